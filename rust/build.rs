@@ -1,6 +1,7 @@
 use std::env;
 
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(locally)");
     if env::var("DFX_NETWORK").unwrap_or_else(|_| "".to_string()) != "ic" {
         println!("cargo:rustc-cfg=locally");
     }
@@ -0,0 +1,173 @@
+//! A stub of the Bitcoin canister serving the Bitcoin API calls of the test canister from an in-memory regtest chain.
+//! The blocks are only added by the integration tests, with `receive` to fund an address and with `mine` to confirm the sent transactions.
+
+use bitcoin::{
+    consensus::deserialize,
+    hashes::{sha256d, Hash},
+    Address, Network, Transaction,
+};
+use ic_cdk::{
+    api::{
+        call::{msg_cycles_accept128, msg_cycles_available128},
+        management_canister::bitcoin::{
+            GetCurrentFeePercentilesRequest, GetUtxosRequest, GetUtxosResponse,
+            MillisatoshiPerByte, Outpoint as OutPoint, Satoshi, SendTransactionRequest, Utxo,
+            UtxoFilter,
+        },
+    },
+    query, update,
+};
+use std::{cell::RefCell, collections::BTreeMap};
+
+/// The regtest chain, whose UTXOs are indexed by address.
+#[derive(Default)]
+struct Chain {
+    tip_height: u32,
+    utxos: BTreeMap<String, Vec<Utxo>>,
+    mempool: Vec<Transaction>,
+    sent_transaction_ids: Vec<String>,
+}
+
+impl Chain {
+    /// Adds a UTXO of `value` satoshis paid to `address` at the tip of the chain.
+    fn add_utxo(&mut self, address: String, outpoint: OutPoint, value: Satoshi) {
+        let height = self.tip_height;
+        self.utxos.entry(address).or_default().push(Utxo {
+            outpoint,
+            value,
+            height,
+        });
+    }
+
+    /// Removes the UTXO spent by `outpoint`, if any.
+    fn spend_utxo(&mut self, outpoint: &OutPoint) {
+        self.utxos
+            .values_mut()
+            .for_each(|utxos| utxos.retain(|utxo| utxo.outpoint != *outpoint));
+    }
+}
+
+thread_local! {
+    static CHAIN: RefCell<Chain> = RefCell::new(Chain::default());
+}
+
+/// Accepts the cycles attached to a Bitcoin API call like the Bitcoin canister does, so that they're recorded as spent by the library.
+fn accept_cycles() {
+    msg_cycles_accept128(msg_cycles_available128());
+}
+
+#[update]
+fn bitcoin_get_utxos(get_utxos_request: GetUtxosRequest) -> GetUtxosResponse {
+    accept_cycles();
+    let min_confirmations = match get_utxos_request.filter {
+        Some(UtxoFilter::MinConfirmations(min_confirmations)) => min_confirmations,
+        _ => 0,
+    };
+    CHAIN.with(|chain| {
+        let chain = chain.borrow();
+        GetUtxosResponse {
+            utxos: chain
+                .utxos
+                .get(&get_utxos_request.address)
+                .into_iter()
+                .flatten()
+                .filter(|utxo| chain.tip_height - utxo.height + 1 >= min_confirmations)
+                .cloned()
+                .collect(),
+            tip_block_hash: sha256d::Hash::hash(&chain.tip_height.to_le_bytes())
+                .to_byte_array()
+                .to_vec(),
+            tip_height: chain.tip_height,
+            // All the UTXOs fit in a single page.
+            next_page: None,
+        }
+    })
+}
+
+#[update]
+fn bitcoin_get_current_fee_percentiles(
+    _get_current_fee_percentiles_request: GetCurrentFeePercentilesRequest,
+) -> Vec<MillisatoshiPerByte> {
+    accept_cycles();
+    (1..=100).map(|percentile| percentile * 1_000).collect()
+}
+
+#[update]
+fn bitcoin_send_transaction(send_transaction_request: SendTransactionRequest) {
+    accept_cycles();
+    let transaction: Transaction = deserialize(&send_transaction_request.transaction)
+        .unwrap_or_else(|_| ic_cdk::trap("The transaction can't be deserialized."));
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        chain
+            .sent_transaction_ids
+            .push(transaction.txid().to_string());
+        chain.mempool.push(transaction);
+    });
+}
+
+/// Adds a block paying `value` satoshis to `address` and returns the new tip height.
+#[update]
+fn receive(address: String, value: Satoshi) -> u32 {
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        chain.tip_height += 1;
+        let outpoint = OutPoint {
+            txid: sha256d::Hash::hash(address.as_bytes())
+                .to_byte_array()
+                .to_vec(),
+            vout: chain.tip_height,
+        };
+        chain.add_utxo(address, outpoint, value);
+        chain.tip_height
+    })
+}
+
+/// Adds a block including the transactions sent since the last block and returns the new tip height.
+#[update]
+fn mine() -> u32 {
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        chain.tip_height += 1;
+        for transaction in std::mem::take(&mut chain.mempool) {
+            transaction.input.iter().for_each(|input| {
+                chain.spend_utxo(&OutPoint {
+                    txid: input.previous_output.txid.to_byte_array().to_vec(),
+                    vout: input.previous_output.vout,
+                })
+            });
+            let txid = transaction.txid();
+            for (vout, output) in transaction.output.iter().enumerate() {
+                if let Ok(address) = Address::from_script(&output.script_pubkey, Network::Regtest) {
+                    let outpoint = OutPoint {
+                        txid: txid.to_byte_array().to_vec(),
+                        vout: vout as u32,
+                    };
+                    chain.add_utxo(address.to_string(), outpoint, output.value);
+                }
+            }
+        }
+        chain.tip_height
+    })
+}
+
+/// Returns the balance of `address`, including its unconfirmed UTXOs.
+#[query]
+fn get_balance(address: String) -> Satoshi {
+    CHAIN.with(|chain| {
+        chain
+            .borrow()
+            .utxos
+            .get(&address)
+            .into_iter()
+            .flatten()
+            .map(|utxo| utxo.value)
+            .sum()
+    })
+}
+
+/// Returns the IDs of the transactions sent, in order.
+#[query]
+fn get_sent_transaction_ids() -> Vec<String> {
+    CHAIN.with(|chain| chain.borrow().sent_transaction_ids.clone())
+}
@@ -0,0 +1 @@
+//! The integration tests of the library are found in `tests/`, running only with the `integration-tests` feature.
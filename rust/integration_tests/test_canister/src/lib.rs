@@ -0,0 +1,144 @@
+//! A canister embedding a `thread_local` [RefCell]<[BitcoinAgent]<[ManagementCanisterImpl]>> following the patterns of the documentation of the library, driven by the integration tests.
+//! The Bitcoin API calls are made to the Bitcoin canister stub given as init and upgrade argument, while the ECDSA calls are made to the management canister.
+
+use candid::Principal;
+use ic_btc_library::bitcoin::Address;
+use ic_btc_library::{
+    get_balance_from_args, get_current_fee, get_current_fees,
+    get_initialization_parameters_from_args, get_utxos_from_args, multi_transfer_from_args,
+    AddressType, BalanceUpdate, BitcoinAgent, BitcoinAgentState, BitcoinApiTarget, Fee, FeeRequest,
+    ManagementCanisterFactory, ManagementCanisterImpl, MillisatoshiPerByte, Network, Satoshi,
+};
+use ic_cdk::{init, post_upgrade, pre_upgrade, query, storage, update};
+use std::{cell::RefCell, collections::BTreeMap, str::FromStr};
+
+const MIN_CONFIRMATIONS: u32 = 1;
+
+thread_local! {
+    static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterImpl>> =
+        RefCell::new(BitcoinAgent::new(ManagementCanisterImpl::new(Network::Regtest, None), &AddressType::P2pkh, MIN_CONFIRMATIONS).unwrap());
+}
+
+/// Makes the Bitcoin agent call `bitcoin_canister_id` instead of the management canister for the Bitcoin API.
+fn set_bitcoin_canister(bitcoin_canister_id: Principal) {
+    BITCOIN_AGENT.with(|bitcoin_agent| {
+        bitcoin_agent
+            .borrow_mut()
+            .set_bitcoin_api_target(BitcoinApiTarget::Direct {
+                principal: bitcoin_canister_id,
+            })
+    });
+}
+
+#[init]
+fn init(bitcoin_canister_id: Principal) {
+    set_bitcoin_canister(bitcoin_canister_id);
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    BITCOIN_AGENT
+        .with(|bitcoin_agent| storage::stable_save((bitcoin_agent.borrow().get_state(),)).unwrap());
+}
+
+#[post_upgrade]
+fn post_upgrade(bitcoin_canister_id: Principal) {
+    let (old_bitcoin_agent_state,): (BitcoinAgentState,) = storage::stable_restore().unwrap();
+    BITCOIN_AGENT.with(|bitcoin_agent| {
+        *bitcoin_agent.borrow_mut() = BitcoinAgent::from_state(old_bitcoin_agent_state, Network::Regtest).unwrap()
+    });
+    // The Bitcoin API target isn't saved in the state.
+    set_bitcoin_canister(bitcoin_canister_id);
+}
+
+/// Initializes the Bitcoin agent and returns its main address.
+#[update]
+async fn initialize() -> String {
+    let initialization_parameters_args = BITCOIN_AGENT
+        .with(|bitcoin_agent| bitcoin_agent.borrow().get_initialization_parameters_args());
+    let initialization_parameters =
+        get_initialization_parameters_from_args(initialization_parameters_args)
+            .await
+            .unwrap()
+            .result;
+    BITCOIN_AGENT.with(|bitcoin_agent| {
+        let mut bitcoin_agent = bitcoin_agent.borrow_mut();
+        bitcoin_agent.initialize(initialization_parameters);
+        bitcoin_agent.get_main_address().to_string()
+    })
+}
+
+#[query]
+fn get_main_address() -> String {
+    BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address().to_string())
+}
+
+/// Returns the balance of the main address according to the Bitcoin canister.
+#[update]
+async fn get_balance() -> Satoshi {
+    let utxos_args = BITCOIN_AGENT.with(|bitcoin_agent| {
+        let bitcoin_agent = bitcoin_agent.borrow();
+        bitcoin_agent
+            .get_utxos_args(&bitcoin_agent.get_main_address(), MIN_CONFIRMATIONS)
+            .unwrap()
+    });
+    get_balance_from_args(utxos_args).await.unwrap()
+}
+
+/// Updates the UTXOs state of the main address and returns its balance update since the last call.
+#[update]
+async fn update_balance() -> BalanceUpdate {
+    let (main_address, utxos_args) = BITCOIN_AGENT.with(|bitcoin_agent| {
+        let bitcoin_agent = bitcoin_agent.borrow();
+        let main_address = bitcoin_agent.get_main_address();
+        let utxos_args = bitcoin_agent
+            .get_utxos_args(&main_address, MIN_CONFIRMATIONS)
+            .unwrap();
+        (main_address, utxos_args)
+    });
+    let utxos_result = get_utxos_from_args(utxos_args).await.unwrap().result;
+    BITCOIN_AGENT.with(|bitcoin_agent| {
+        let mut bitcoin_agent = bitcoin_agent.borrow_mut();
+        bitcoin_agent.apply_utxos(utxos_result);
+        bitcoin_agent.get_balance_update(&main_address).unwrap()
+    })
+}
+
+#[update]
+async fn get_current_fee() -> MillisatoshiPerByte {
+    get_current_fee!(BITCOIN_AGENT, FeeRequest::Standard).unwrap()
+}
+
+#[update]
+async fn get_current_fees() -> Vec<MillisatoshiPerByte> {
+    get_current_fees!(BITCOIN_AGENT).unwrap().result
+}
+
+/// Transfers `amount` satoshis from the main address to `address`, the change being sent back to the main address, and returns the txid as displayed by block explorers.
+#[update]
+async fn transfer(address: String, amount: Satoshi) -> Result<String, String> {
+    let address = Address::from_str(&address)
+        .and_then(|address| address.require_network(Network::Regtest.into()))
+        .map_err(|error| error.to_string())?;
+    let multi_transfer_args = BITCOIN_AGENT.with(|bitcoin_agent| {
+        let bitcoin_agent = bitcoin_agent.borrow();
+        bitcoin_agent.get_multi_transfer_args(
+            &BTreeMap::from([(address, amount)]),
+            &bitcoin_agent.get_main_address(),
+            Fee::Standard,
+            MIN_CONFIRMATIONS,
+            false,
+        )
+    });
+    let multi_transfer_result = multi_transfer_from_args(multi_transfer_args)
+        .await
+        .map_err(|error| format!("{:?}", error))?;
+    BITCOIN_AGENT
+        .with(|bitcoin_agent| {
+            bitcoin_agent
+                .borrow_mut()
+                .apply_multi_transfer_result(&multi_transfer_result)
+        })
+        .map_err(|error| format!("{:?}", error))?;
+    Ok(multi_transfer_result.transaction_info.id.to_string())
+}
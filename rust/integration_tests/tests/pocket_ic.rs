@@ -0,0 +1,260 @@
+//! Integration tests driving `test_canister`, which embeds a `BitcoinAgent<ManagementCanisterImpl>`, with PocketIC.
+//! The Bitcoin API calls of the test canister are served by `bitcoin_canister_stub`, while its ECDSA calls are served by the threshold ECDSA keys of PocketIC.
+//! The Wasm modules are read from `TEST_CANISTER_WASM` and `BITCOIN_CANISTER_STUB_WASM`, defaulting to the ones built in the `target` directory, see `README.adoc`.
+
+#![cfg(feature = "integration-tests")]
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Principal};
+use pocket_ic::{PocketIc, PocketIcBuilder, WasmResult};
+use std::{env, fs, path::PathBuf};
+
+/// The cycles given to the canisters, covering the threshold signatures.
+const INITIAL_CYCLES: u128 = 100_000_000_000_000;
+
+/// A regtest address not managed by the test canister.
+const RECIPIENT_ADDRESS: &str = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn";
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+struct BalanceUpdate {
+    added_balance: u64,
+    removed_balance: u64,
+}
+
+/// The test canister and the Bitcoin canister stub it calls, installed on a PocketIC instance.
+struct TestEnv {
+    pic: PocketIc,
+    test_canister_id: Principal,
+    bitcoin_canister_id: Principal,
+}
+
+/// Returns the Wasm module at the path in `env_var` if set, the one of `crate_name` built for `wasm32-unknown-unknown` in release otherwise.
+fn get_wasm(env_var: &str, crate_name: &str) -> Vec<u8> {
+    let path = env::var_os(env_var).map(PathBuf::from).unwrap_or_else(|| {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("target/wasm32-unknown-unknown/release")
+            .join(format!("{}.wasm", crate_name))
+    });
+    fs::read(&path).unwrap_or_else(|error| {
+        panic!(
+            "Can't read the Wasm module {}, build it first: {}",
+            path.display(),
+            error
+        )
+    })
+}
+
+impl TestEnv {
+    /// Installs the Bitcoin canister stub and the test canister on an application subnet, the threshold ECDSA keys being held by the II subnet.
+    fn new() -> Self {
+        let pic = PocketIcBuilder::new()
+            .with_application_subnet()
+            .with_ii_subnet()
+            .build();
+
+        let bitcoin_canister_id = pic.create_canister();
+        pic.add_cycles(bitcoin_canister_id, INITIAL_CYCLES);
+        pic.install_canister(
+            bitcoin_canister_id,
+            get_wasm("BITCOIN_CANISTER_STUB_WASM", "bitcoin_canister_stub"),
+            encode_args(()).unwrap(),
+            None,
+        );
+
+        let test_canister_id = pic.create_canister();
+        pic.add_cycles(test_canister_id, INITIAL_CYCLES);
+        pic.install_canister(
+            test_canister_id,
+            get_test_canister_wasm(),
+            encode_one(bitcoin_canister_id).unwrap(),
+            None,
+        );
+
+        Self {
+            pic,
+            test_canister_id,
+            bitcoin_canister_id,
+        }
+    }
+
+    /// Returns the decoded reply of the update call to `method` of `canister_id`, panicking if the call is rejected.
+    fn update<T: CandidType + for<'a> Deserialize<'a>>(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> T {
+        match self
+            .pic
+            .update_call(canister_id, Principal::anonymous(), method, arg)
+            .unwrap()
+        {
+            WasmResult::Reply(reply) => decode_one(&reply).unwrap(),
+            WasmResult::Reject(message) => panic!("{} was rejected: {}", method, message),
+        }
+    }
+
+    /// Returns the decoded reply of the query call to `method` of `canister_id`, panicking if the call is rejected.
+    fn query<T: CandidType + for<'a> Deserialize<'a>>(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> T {
+        match self
+            .pic
+            .query_call(canister_id, Principal::anonymous(), method, arg)
+            .unwrap()
+        {
+            WasmResult::Reply(reply) => decode_one(&reply).unwrap(),
+            WasmResult::Reject(message) => panic!("{} was rejected: {}", method, message),
+        }
+    }
+
+    fn initialize(&self) -> String {
+        self.update(
+            self.test_canister_id,
+            "initialize",
+            encode_args(()).unwrap(),
+        )
+    }
+
+    fn update_balance(&self) -> BalanceUpdate {
+        self.update(
+            self.test_canister_id,
+            "update_balance",
+            encode_args(()).unwrap(),
+        )
+    }
+
+    /// Pays `value` satoshis to `address` in a new block of the Bitcoin canister stub.
+    fn receive(&self, address: &str, value: u64) {
+        self.update::<u32>(
+            self.bitcoin_canister_id,
+            "receive",
+            encode_args((address, value)).unwrap(),
+        );
+    }
+
+    /// Confirms the transactions sent to the Bitcoin canister stub in a new block.
+    fn mine(&self) {
+        self.update::<u32>(self.bitcoin_canister_id, "mine", encode_args(()).unwrap());
+    }
+
+    /// Returns the balance of `address` according to the Bitcoin canister stub.
+    fn get_balance(&self, address: &str) -> u64 {
+        self.query(
+            self.bitcoin_canister_id,
+            "get_balance",
+            encode_one(address).unwrap(),
+        )
+    }
+}
+
+fn get_test_canister_wasm() -> Vec<u8> {
+    get_wasm("TEST_CANISTER_WASM", "test_canister")
+}
+
+/// Check that the initialized Bitcoin agent receives bitcoins and transfers them, the transaction being signed with the threshold ECDSA key of PocketIC.
+#[test]
+fn check_initialize_receive_and_transfer() {
+    let test_env = TestEnv::new();
+
+    let main_address = test_env.initialize();
+    // Initializing the Bitcoin agent again keeps its main address.
+    assert_eq!(test_env.initialize(), main_address);
+
+    let received_amount = 100_000_000;
+    test_env.receive(&main_address, received_amount);
+    assert_eq!(
+        test_env.update_balance(),
+        BalanceUpdate {
+            added_balance: received_amount,
+            removed_balance: 0
+        }
+    );
+
+    // The fee macros only borrow the Bitcoin agent to get the arguments of their calls.
+    let current_fees: Vec<u64> = test_env.update(
+        test_env.test_canister_id,
+        "get_current_fees",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(current_fees.len(), 100);
+    let current_fee: u64 = test_env.update(
+        test_env.test_canister_id,
+        "get_current_fee",
+        encode_args(()).unwrap(),
+    );
+    assert!((current_fees[0]..=current_fees[99]).contains(&current_fee));
+
+    let sent_amount = 10_000_000;
+    let transfer_result: Result<String, String> = test_env.update(
+        test_env.test_canister_id,
+        "transfer",
+        encode_args((RECIPIENT_ADDRESS, sent_amount)).unwrap(),
+    );
+    let txid = transfer_result.unwrap();
+    let sent_transaction_ids: Vec<String> = test_env.query(
+        test_env.bitcoin_canister_id,
+        "get_sent_transaction_ids",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(sent_transaction_ids, vec![txid]);
+
+    test_env.mine();
+    assert_eq!(test_env.get_balance(RECIPIENT_ADDRESS), sent_amount);
+    let change = test_env.get_balance(&main_address);
+    assert!(change < received_amount - sent_amount);
+    let balance: u64 = test_env.update(
+        test_env.test_canister_id,
+        "get_balance",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(balance, change);
+}
+
+/// Check that the Bitcoin agent state saved in `pre_upgrade` is restored in `post_upgrade`, including the seen UTXOs state of the main address.
+#[test]
+fn check_upgrade() {
+    let test_env = TestEnv::new();
+
+    let main_address = test_env.initialize();
+    test_env.receive(&main_address, 50_000);
+    assert_eq!(test_env.update_balance().added_balance, 50_000);
+
+    test_env
+        .pic
+        .upgrade_canister(
+            test_env.test_canister_id,
+            get_test_canister_wasm(),
+            encode_one(test_env.bitcoin_canister_id).unwrap(),
+            None,
+        )
+        .unwrap();
+
+    let main_address_after_upgrade: String = test_env.query(
+        test_env.test_canister_id,
+        "get_main_address",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(main_address_after_upgrade, main_address);
+    // The restored Bitcoin agent is already initialized.
+    assert_eq!(test_env.initialize(), main_address);
+    // The UTXO received before the upgrade was already seen.
+    assert_eq!(
+        test_env.update_balance(),
+        BalanceUpdate {
+            added_balance: 0,
+            removed_balance: 0
+        }
+    );
+
+    test_env.receive(&main_address, 20_000);
+    assert_eq!(
+        test_env.update_balance(),
+        BalanceUpdate {
+            added_balance: 20_000,
+            removed_balance: 0
+        }
+    );
+}
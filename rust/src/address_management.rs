@@ -1,21 +1,24 @@
 use crate::{
     bip32_extended_derivation::extended_bip32_derivation, types::BitcoinAddressError,
-    AddAddressWithParametersError, BitcoinAgent, EcdsaPubKey, ManagementCanister, UtxosState,
+    upgrade_management::get_address_using_primitives, AddAddressWithParametersError, BitcoinAgent,
+    DerivationPath, EcdsaPubKey, KeyRotation, ManagementCanister, UtxosState,
     MIN_CONFIRMATIONS_UPPER_BOUND,
 };
 use bitcoin::{
+    address::Payload,
     blockdata::{opcodes, script::Builder},
     hashes,
     hashes::Hash,
-    util,
-    util::address::Payload,
+    key,
+    secp256k1::{Secp256k1, XOnlyPublicKey},
     Address, AddressType, Network, PublicKey, ScriptHash,
 };
+use std::collections::BTreeMap;
 
 /// Returns the public key from a given Bitcoin ECDSA public key.
 pub(crate) fn get_btc_public_key_from_ecdsa_public_key(
     ecdsa_public_key: &EcdsaPubKey,
-) -> Result<PublicKey, bitcoin::util::key::Error> {
+) -> Result<PublicKey, key::Error> {
     PublicKey::from_slice(&ecdsa_public_key.public_key)
 }
 
@@ -31,9 +34,13 @@ pub(crate) fn add_address_with_parameters(
     if min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
         return Err(AddAddressWithParametersError::MinConfirmationsTooHigh);
     }
-    if derivation_path.len() > 255 {
-        return Err(AddAddressWithParametersError::DerivationPathTooLong);
-    }
+    // The derivation path of the signatures is the one of the ECDSA public key followed by `derivation_path`.
+    bitcoin_agent
+        .management_canister
+        .get_ecdsa_public_key()
+        .derivation_path
+        .join(derivation_path)
+        .map_err(AddAddressWithParametersError::InvalidDerivationPath)?;
     let address = add_address_from_extended_path(
         bitcoin_agent,
         derivation_path,
@@ -59,12 +66,14 @@ pub(crate) fn derive_ecdsa_public_key_and_address_from_extended_path(
     let child_ecdsa_public_key = EcdsaPubKey {
         public_key: child_public_key,
         chain_code: child_chain_code,
-        derivation_path: ecdsa_public_key
-            .derivation_path
-            .iter()
-            .cloned()
-            .chain(derivation_path.iter().cloned())
-            .collect(),
+        derivation_path: DerivationPath::new_unchecked(
+            ecdsa_public_key
+                .derivation_path
+                .iter()
+                .chain(derivation_path.iter())
+                .cloned()
+                .collect(),
+        ),
     };
     let address = get_address(network, address_type, &child_ecdsa_public_key).unwrap();
 
@@ -104,7 +113,8 @@ pub(crate) fn remove_address(
     bitcoin_agent: &mut BitcoinAgent<impl ManagementCanister>,
     address: &Address,
 ) -> bool {
-    let address_can_be_removed = bitcoin_agent.ecdsa_pub_key_addresses.contains_key(address)
+    // Watch-only addresses are only tracked by their UTXOs state.
+    let address_can_be_removed = bitcoin_agent.utxos_state_addresses.contains_key(address)
         && *address != bitcoin_agent.get_main_address();
     if address_can_be_removed {
         bitcoin_agent.ecdsa_pub_key_addresses.remove(address);
@@ -120,11 +130,68 @@ pub(crate) fn list_addresses(
     bitcoin_agent.ecdsa_pub_key_addresses.keys().collect()
 }
 
+/// Returns the watch-only addresses according to given BitcoinAgent, which are tracked without being managed.
+pub(crate) fn list_watch_only_addresses(
+    bitcoin_agent: &BitcoinAgent<impl ManagementCanister>,
+) -> Vec<&Address> {
+    bitcoin_agent
+        .utxos_state_addresses
+        .keys()
+        .filter(|address| !bitcoin_agent.ecdsa_pub_key_addresses.contains_key(address))
+        .collect()
+}
+
+/// Replaces the ECDSA public key of the given BitcoinAgent with `ecdsa_public_key`, deriving every managed address again with its derivation path and address type.
+/// The previous addresses stay tracked as watch-only addresses and the rotation is recorded in the Bitcoin agent.
+pub(crate) fn apply_rekey(
+    bitcoin_agent: &mut BitcoinAgent<impl ManagementCanister>,
+    ecdsa_public_key: EcdsaPubKey,
+) {
+    let previous_ecdsa_pub_key = bitcoin_agent.management_canister.get_ecdsa_public_key();
+    if ecdsa_public_key == previous_ecdsa_pub_key {
+        return;
+    }
+    bitcoin_agent
+        .management_canister
+        .set_ecdsa_public_key(ecdsa_public_key.clone());
+    let network = bitcoin_agent.management_canister.get_network();
+    let previous_ecdsa_pub_key_addresses =
+        std::mem::take(&mut bitcoin_agent.ecdsa_pub_key_addresses);
+    let mut rekeyed_addresses = BTreeMap::default();
+    for (previous_address, previous_address_ecdsa_pub_key) in previous_ecdsa_pub_key_addresses {
+        let min_confirmations =
+            bitcoin_agent.utxos_state_addresses[&previous_address].min_confirmations;
+        let (address_ecdsa_pub_key, address) =
+            derive_ecdsa_public_key_and_address_from_extended_path(
+                &previous_address_ecdsa_pub_key.derivation_path
+                    [previous_ecdsa_pub_key.derivation_path.len()..],
+                &get_types_address_type(&previous_address.address_type().unwrap()),
+                &network,
+                &ecdsa_public_key,
+            );
+        bitcoin_agent
+            .ecdsa_pub_key_addresses
+            .insert(address.clone(), address_ecdsa_pub_key);
+        bitcoin_agent
+            .utxos_state_addresses
+            .entry(address.clone())
+            .or_insert_with(|| UtxosState::new(min_confirmations));
+        rekeyed_addresses.insert(
+            get_address_using_primitives(&previous_address),
+            get_address_using_primitives(&address),
+        );
+    }
+    bitcoin_agent.key_rotations.push(KeyRotation {
+        previous_ecdsa_pub_key,
+        rekeyed_addresses,
+    });
+}
+
 /// Returns the P2PKH address from a given network and public key.
 pub(crate) fn get_p2pkh_address(
     network: &Network,
     ecdsa_public_key: &EcdsaPubKey,
-) -> Result<Address, util::key::Error> {
+) -> Result<Address, key::Error> {
     Ok(Address::p2pkh(
         &get_btc_public_key_from_ecdsa_public_key(ecdsa_public_key)?,
         *network,
@@ -135,11 +202,11 @@ pub(crate) fn get_p2pkh_address(
 pub(crate) fn get_p2sh_address(
     network: &Network,
     script_hash: &[u8],
-) -> Result<Address, hashes::error::Error> {
-    Ok(Address {
-        network: *network,
-        payload: Payload::ScriptHash(ScriptHash::from_slice(script_hash)?),
-    })
+) -> Result<Address, hashes::Error> {
+    Ok(Address::new(
+        *network,
+        Payload::ScriptHash(ScriptHash::from_slice(script_hash)?),
+    ))
 }
 
 /// Returns the P2SH address from a given network and public key.
@@ -150,12 +217,12 @@ pub(crate) fn get_p2sh_address_for_pub_key(
     let public_key = get_btc_public_key_from_ecdsa_public_key(ecdsa_public_key)?;
     let public_key_hash = public_key.pubkey_hash();
     let script = Builder::new()
-        .push_slice(&public_key_hash[..])
+        .push_slice(public_key_hash.to_byte_array())
         .push_opcode(opcodes::all::OP_CHECKSIG)
         .into_script();
     Ok(get_p2sh_address(
         network,
-        &script.script_hash().to_ascii_lowercase(),
+        &script.script_hash().to_byte_array().to_ascii_lowercase(),
     )?)
 }
 
@@ -170,6 +237,20 @@ pub(crate) fn get_p2wpkh_address(
     )?)
 }
 
+/// Returns the P2TR address from a given network and public key, the public key being tweaked without any script as per BIP-86.
+pub(crate) fn get_p2tr_address(
+    network: &Network,
+    ecdsa_public_key: &EcdsaPubKey,
+) -> Result<Address, BitcoinAddressError> {
+    let public_key = get_btc_public_key_from_ecdsa_public_key(ecdsa_public_key)?;
+    Ok(Address::p2tr(
+        &Secp256k1::verification_only(),
+        XOnlyPublicKey::from(public_key.inner),
+        None,
+        *network,
+    ))
+}
+
 /// Returns the Bitcoin address from a given network, address type and ECDSA public key.
 fn get_address(
     network: &Network,
@@ -180,6 +261,7 @@ fn get_address(
         AddressType::P2pkh => Ok(get_p2pkh_address(network, ecdsa_public_key)?),
         AddressType::P2sh => get_p2sh_address_for_pub_key(network, ecdsa_public_key),
         AddressType::P2wpkh => get_p2wpkh_address(network, ecdsa_public_key),
+        AddressType::P2tr => get_p2tr_address(network, ecdsa_public_key),
         // TODO (ER-2639): Add more address types (especially P2wsh)
         // Other cases can't happen see BitcoinAgent::new
         _ => panic!(),
@@ -205,19 +287,40 @@ pub(crate) fn get_bitcoin_address_type(address_type: &crate::AddressType) -> Add
         crate::AddressType::P2pkh => AddressType::P2pkh,
         crate::AddressType::P2sh => AddressType::P2sh,
         crate::AddressType::P2wpkh => AddressType::P2wpkh,
+        crate::AddressType::P2tr => AddressType::P2tr,
+    }
+}
+
+/// Returns the `crate::AddressType` associated with a given `bitcoin::AddressType`.
+pub(crate) fn get_types_address_type(address_type: &AddressType) -> crate::AddressType {
+    match address_type {
+        AddressType::P2pkh => crate::AddressType::P2pkh,
+        AddressType::P2sh => crate::AddressType::P2sh,
+        AddressType::P2wpkh => crate::AddressType::P2wpkh,
+        AddressType::P2tr => crate::AddressType::P2tr,
+        // Other cases can't happen as managed addresses have one of the address types above
+        _ => panic!(),
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::{agent, canister_mock::ManagementCanisterMock};
-    use bitcoin::{
-        secp256k1::{Secp256k1, SecretKey},
-        util::bip32::{ChainCode, ChildNumber, ExtendedPrivKey},
-        PrivateKey,
+    pub(crate) use crate::canister_mock::get_btc_private_key;
+    use crate::{
+        agent,
+        bip32_extended_derivation::derive_child_private_key,
+        canister_mock::{self, get_balance_update, ManagementCanisterMock},
+        ecdsa::get_key_name_from_network,
+        types::{
+            from_bitcoin_network_to_management_canister_network,
+            from_bitcoin_network_to_types_network, from_types_network_to_bitcoin_network,
+        },
+        DerivationPathError, Fee, MultiTransferError, MAX_DERIVATION_PATH_ELEMENT_LENGTH,
+        MAX_DERIVATION_PATH_LENGTH,
     };
-    use std::{cell::RefCell, collections::HashSet, str::FromStr};
+    use bitcoin::{secp256k1::Secp256k1, PrivateKey};
+    use std::{collections::HashSet, str::FromStr};
 
     /// Returns the parsed `AddressType` based on a generated address of given `address_type`.
     fn get_parsed_address_type_from_generated_address(
@@ -234,6 +337,7 @@ pub mod tests {
             crate::AddressType::P2pkh,
             crate::AddressType::P2sh,
             crate::AddressType::P2wpkh,
+            crate::AddressType::P2tr,
         ] {
             assert_eq!(
                 get_parsed_address_type_from_generated_address(address_type),
@@ -242,6 +346,47 @@ pub mod tests {
         }
     }
 
+    /// Check that the addresses derived on the signet have the prefixes of the testnet, and that they are restored as signet addresses.
+    #[test]
+    fn check_signet_addresses() {
+        assert_eq!(
+            from_types_network_to_bitcoin_network(crate::Network::Signet),
+            Network::Signet
+        );
+        assert_eq!(
+            from_bitcoin_network_to_types_network(Network::Signet),
+            crate::Network::Signet
+        );
+        assert_eq!(
+            from_bitcoin_network_to_management_canister_network(Network::Signet),
+            Err(crate::UnsupportedNetwork(crate::Network::Signet))
+        );
+        assert_eq!(get_key_name_from_network(Network::Signet), "test_key_1");
+
+        for (address_type, prefixes) in [
+            // P2PKH addresses start with either `m` or `n`.
+            (crate::AddressType::P2pkh, vec!["m", "n"]),
+            (crate::AddressType::P2sh, vec!["2"]),
+            (crate::AddressType::P2wpkh, vec!["tb1q"]),
+            (crate::AddressType::P2tr, vec!["tb1p"]),
+        ] {
+            let bitcoin_agent = agent::tests::new_mock(&crate::Network::Signet, &address_type);
+            let main_address = bitcoin_agent.get_main_address();
+            assert_eq!(main_address.network, Network::Signet);
+            assert!(prefixes
+                .iter()
+                .any(|prefix| main_address.to_string().starts_with(prefix)));
+
+            let state = bitcoin_agent.get_state();
+            assert_eq!(state.network, crate::Network::Signet);
+            let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+                BitcoinAgent::from_state(state, crate::Network::Signet).unwrap();
+            let restored_main_address = restored_bitcoin_agent.get_main_address();
+            assert_eq!(restored_main_address, main_address);
+            assert_eq!(restored_main_address.network, Network::Signet);
+        }
+    }
+
     /// Returns `bitcoin_agent` addresses as a `Vec<Address>`
     fn list_addresses(bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>) -> Vec<Address> {
         bitcoin_agent
@@ -261,6 +406,56 @@ pub mod tests {
         to_hashset(v0) == to_hashset(v1)
     }
 
+    /// Check that derivation paths exceeding the limits of the management canister are rejected, also when adding an address.
+    #[test]
+    fn check_derivation_path_limits() {
+        assert!(DerivationPath::new(vec![vec![0]; MAX_DERIVATION_PATH_LENGTH]).is_ok());
+        assert_eq!(
+            DerivationPath::new(vec![vec![0]; MAX_DERIVATION_PATH_LENGTH + 1]),
+            Err(DerivationPathError::TooLong)
+        );
+
+        assert!(DerivationPath::new(vec![vec![0; MAX_DERIVATION_PATH_ELEMENT_LENGTH]]).is_ok());
+        assert_eq!(
+            DerivationPath::new(vec![
+                vec![0],
+                vec![0; MAX_DERIVATION_PATH_ELEMENT_LENGTH + 1]
+            ]),
+            Err(DerivationPathError::ElementTooLong { index: 1 })
+        );
+
+        // Only 4-byte elements are BIP-32 indexes.
+        for element in [
+            vec![0x7f, 0xff, 0xff, 0xff],
+            vec![0x80],
+            vec![0x80, 0, 0, 0, 0],
+        ] {
+            assert!(DerivationPath::new(vec![element]).is_ok());
+        }
+        assert_eq!(
+            DerivationPath::new(vec![vec![0], vec![0], vec![0x80, 0, 0, 0]]),
+            Err(DerivationPathError::HardenedElement { index: 2 })
+        );
+
+        let bitcoin_agent =
+            &mut agent::tests::new_mock(&crate::Network::Regtest, &crate::AddressType::P2pkh);
+        assert_eq!(
+            bitcoin_agent.add_address(&vec![vec![0]; MAX_DERIVATION_PATH_LENGTH + 1]),
+            Err(DerivationPathError::TooLong)
+        );
+        assert_eq!(
+            bitcoin_agent.add_address_with_parameters(
+                &[vec![0x80, 0, 0, 0]],
+                &crate::AddressType::P2wpkh,
+                0
+            ),
+            Err(AddAddressWithParametersError::InvalidDerivationPath(
+                DerivationPathError::HardenedElement { index: 0 }
+            ))
+        );
+        assert_eq!(list_addresses(bitcoin_agent).len(), 1);
+    }
+
     /// Check that `add_address`, `remove_address` and `list_addresses` respectively add, remove and list managed addresses.
     #[test]
     fn check_managed_addresses() {
@@ -284,17 +479,82 @@ pub mod tests {
         ));
     }
 
-    // A private key in WIF (wallet import format). This is only for testing purposes.
-    const BTC_PRIVATE_KEY_WIF: &str = "L2C1QgyKqNgfV7BpEPAm6PVn2xW8zpXq6MojSbWdH18nGQF2wGsT";
+    /// Check that `apply_rekey` derives the managed addresses from the new key and keeps the previous ones as watch-only addresses.
+    #[tokio::test]
+    async fn check_apply_rekey() {
+        let bitcoin_agent =
+            &mut agent::tests::new_mock(&crate::Network::Regtest, &crate::AddressType::P2pkh);
+        let min_confirmations = 0;
+        let previous_main_address = bitcoin_agent.get_main_address();
+        let previous_address = bitcoin_agent.add_address(&[vec![0]]).unwrap();
+        get_balance_update(bitcoin_agent, &previous_main_address, min_confirmations);
+
+        let rekey_args = bitcoin_agent.get_rekey_args();
+        assert!(rekey_args.ecdsa_public_key.public_key.is_empty());
+        let new_key = get_btc_ecdsa_public_key_from_public_key(
+            &PrivateKey::from_slice(&[3; 32], Network::Regtest)
+                .unwrap()
+                .public_key(&Secp256k1::new()),
+        );
+        bitcoin_agent.apply_rekey(new_key.clone());
+        // Applying the current key again doesn't rotate it.
+        bitcoin_agent.apply_rekey(new_key);
 
-    thread_local! {
-        static BTC_PRIVATE_KEY: RefCell<PrivateKey> =
-            RefCell::new(PrivateKey::from_wif(BTC_PRIVATE_KEY_WIF).unwrap());
-    }
+        let main_address = bitcoin_agent.get_main_address();
+        assert_ne!(main_address, previous_main_address);
+        let address = bitcoin_agent.add_address(&[vec![0]]).unwrap();
+        assert!(contains_same_addresses(
+            &list_addresses(bitcoin_agent),
+            &[main_address.clone(), address.clone()]
+        ));
+        let watch_only_addresses: Vec<Address> = bitcoin_agent
+            .list_watch_only_addresses()
+            .into_iter()
+            .cloned()
+            .collect();
+        assert!(contains_same_addresses(
+            &watch_only_addresses,
+            &[previous_main_address.clone(), previous_address.clone()]
+        ));
 
-    /// Returns the Bitcoin private key.
-    pub(crate) fn get_btc_private_key() -> PrivateKey {
-        BTC_PRIVATE_KEY.with(|private_key| *private_key.borrow())
+        let key_rotations = bitcoin_agent.get_key_rotations();
+        assert_eq!(key_rotations.len(), 1);
+        assert_eq!(
+            key_rotations[0].previous_ecdsa_pub_key,
+            get_btc_ecdsa_public_key()
+        );
+        assert_eq!(
+            key_rotations[0].rekeyed_addresses[&get_address_using_primitives(&previous_address)],
+            get_address_using_primitives(&address)
+        );
+
+        // The balance of the previous main address is still readable but its UTXOs aren't spent anymore.
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, &previous_main_address, min_confirmations),
+            canister_mock::get_init_balance()
+        );
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &BTreeMap::from([(previous_main_address.clone(), 100_000)]),
+            &main_address,
+            Fee::Constant(10_000),
+            min_confirmations,
+            false,
+        );
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::InsufficientBalance)
+        ));
+
+        // The key rotations and the watch-only addresses survive an upgrade.
+        let bitcoin_agent = BitcoinAgent::<ManagementCanisterMock>::from_state(
+            bitcoin_agent.get_state(),
+            crate::Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(bitcoin_agent.get_key_rotations().len(), 1);
+        assert_eq!(bitcoin_agent.list_watch_only_addresses().len(), 2);
     }
 
     /// Returns the Bitcoin public key.
@@ -307,7 +567,7 @@ pub mod tests {
         EcdsaPubKey {
             public_key: public_key.to_bytes(),
             chain_code: vec![],
-            derivation_path: vec![],
+            derivation_path: DerivationPath::default(),
         }
     }
 
@@ -316,48 +576,6 @@ pub mod tests {
         get_btc_ecdsa_public_key_from_public_key(&get_btc_public_key())
     }
 
-    /// Returns the `ChildNumber` (u31) associated with a given vector of at most four `u8`s.
-    /// Assuming that the most significant bit of the first byte is zero, classifying `child_bytes` as an unhardened derivation path.
-    pub(crate) fn get_child_number(child_bytes: &[u8]) -> ChildNumber {
-        let mut index = (child_bytes[0] as u32) << 24;
-        if child_bytes.len() > 1 {
-            index |= (child_bytes[1] as u32) << 16;
-            if child_bytes.len() > 2 {
-                index |= (child_bytes[2] as u32) << 8;
-                if child_bytes.len() > 3 {
-                    index |= child_bytes[3] as u32;
-                }
-            }
-        }
-        ChildNumber::Normal { index }
-    }
-
-    /// Returns the private key of the derived child from the given private key, chain code and unhardened derivation path.
-    pub(crate) fn derive_child_private_key(
-        private_key: &[u8],
-        chain_code: &[u8],
-        derivation_path: &[Vec<u8>],
-    ) -> Vec<u8> {
-        let child_number_vec: Vec<ChildNumber> = derivation_path
-            .iter()
-            .map(|child_bytes| get_child_number(child_bytes))
-            .collect();
-        let parent_extended_private_key = ExtendedPrivKey {
-            network: Network::Bitcoin, // The network isn't taken into account when deriving a child private key.
-            depth: 0,
-            parent_fingerprint: Default::default(),
-            child_number: ChildNumber::Normal { index: 0 },
-            private_key: SecretKey::from_slice(private_key).unwrap(),
-            chain_code: ChainCode::from(&*chain_code),
-        };
-        parent_extended_private_key
-            .derive_priv(&Secp256k1::new(), &child_number_vec)
-            .unwrap()
-            .private_key
-            .secret_bytes()
-            .to_vec()
-    }
-
     /// Check that the keys and address of the derived child match those expected from the given keys, chain code and derivation path.
     fn test_derive_ecdsa_keys_and_address_from_extended_path(
         private_key: &str,
@@ -392,7 +610,7 @@ pub mod tests {
             &EcdsaPubKey {
                 public_key: PublicKey::from_str(expected_public_key).unwrap().to_bytes(),
                 chain_code: chain_code.to_vec(),
-                derivation_path: vec![],
+                derivation_path: DerivationPath::default(),
             },
         );
         assert_eq!(
@@ -440,4 +658,36 @@ pub mod tests {
             "1KbzFs186EhWeDjzQHqWab3Le5rmGGsGn",
         );
     }
+
+    /// Check that the private key derived along a path with a 6-byte element matches the derived public key.
+    #[test]
+    fn check_derive_child_private_key_long_element() {
+        let private_key = get_btc_private_key().to_bytes();
+        let derivation_path = [vec![0, 0, 0, 1], vec![8, 0, 2, 8, 0, 2]];
+        for chain_code in [
+            vec![],
+            hex::decode("d84e7baa7130e741f75c23062e514cba7d3acc4dbeb3b269cb12f37d3d57aae0")
+                .unwrap(),
+        ] {
+            let (ecdsa_public_key, _) = derive_ecdsa_public_key_and_address_from_extended_path(
+                &derivation_path,
+                &crate::AddressType::P2pkh,
+                &Network::Regtest,
+                &EcdsaPubKey {
+                    public_key: get_btc_public_key().to_bytes(),
+                    chain_code: chain_code.clone(),
+                    derivation_path: DerivationPath::default(),
+                },
+            );
+            let child_private_key =
+                derive_child_private_key(&private_key, &chain_code, &derivation_path);
+            assert_eq!(
+                PrivateKey::from_slice(&child_private_key, Network::Regtest)
+                    .unwrap()
+                    .public_key(&Secp256k1::new())
+                    .to_bytes(),
+                ecdsa_public_key.public_key
+            );
+        }
+    }
 }
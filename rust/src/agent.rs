@@ -1,26 +1,69 @@
+#[cfg(feature = "stable-memory")]
+use crate::stable_management::{self, StableStorage};
 use crate::{
     address_management,
     address_management::get_main_address,
-    canister_common::ManagementCanister,
-    ecdsa::{get_btc_ecdsa_public_key, get_key_name_from_network},
+    block_management::get_block_headers,
+    canister_common::{
+        call_with_retries, ManagementCanister, ManagementCanisterFactory,
+        GET_BLOCK_HEADERS_COST_CYCLES,
+    },
+    ecdsa::{get_btc_ecdsa_public_key, sign_with_ecdsa},
+    fee_cache::FeeCache,
+    logging::{LogLevel, LogSink, LOG_TARGET_STATE},
+    message_signing::sign_message,
+    metrics,
+    payout_queue::PayoutQueue,
+    polling,
+    polling::Poller,
+    rate_limit::RateLimiter,
+    time_source::TimeSource,
     transaction_management,
-    transaction_management::{get_current_fee, get_current_fees},
-    types::{from_bitcoin_network_to_types_network, GetUtxosResponse},
+    transaction_management::{
+        get_current_fees, get_fee_from_percentiles, get_fee_suggestions_from_percentiles,
+        IcTransferCalls, TransactionBuilder,
+    },
+    types::{
+        from_bitcoin_network_to_types_network, get_target_blocks_percentile,
+        GetBlockHeadersResponse, GetUtxosResponse,
+    },
     upgrade_management,
     upgrade_management::get_address,
     utxo_management,
-    utxo_management::{get_balance_from_utxos, get_utxos},
-    AddAddressWithParametersError, AddressNotTracked, AddressType, BalanceUpdate,
-    BitcoinAgentState, CurrentFeeArgs, CurrentFeesArgs, DerivationPathTooLong, EcdsaPubKey, Fee,
-    FeeRequest, GetCurrentFeeError, GetUtxosError, InitializationParametersArgs,
-    ManagementCanisterReject, MillisatoshiPerByte, MinConfirmationsTooHigh, MultiTransferArgs,
-    MultiTransferError, MultiTransferResult, OutPoint, Satoshi, Utxo, UtxosArgs, UtxosResult,
-    UtxosState, UtxosUpdate, MIN_CONFIRMATIONS_UPPER_BOUND,
+    utxo_management::{
+        get_balance, get_balance_from_utxos, get_utxos, validate_get_utxos_response,
+    },
+    AddAddressWithParametersError, AddressNotTracked, AddressType, AddressUsingPrimitives,
+    ApplyMultiTransferResultError, BalanceUpdate, BitcoinAgentBuilder, BitcoinAgentState,
+    BitcoinApiTarget, BlockHeadersArgs, CurrentFeeArgs, CurrentFeesArgs, CyclesCostConfig,
+    DerivationPath, DerivationPathError, EcdsaPubKey, Fee, FeeRequest, FeeSmoothing,
+    FeeSuggestions, FeeSuggestionsArgs, GetCurrentFeeError, GetUtxosError,
+    InitializationParametersArgs, IntegrityIssue, InvalidCyclesCostConfig, InvalidPercentile,
+    KeyRotation, ManagementCanisterReject, MemoryStats, MetricsSnapshot, MillisatoshiPerByte,
+    MinConfirmationsTooHigh, MultiTransferArgs, MultiTransferError, MultiTransferResult, Network,
+    OutPoint, PollBudget, QueueId, RateLimited, RateLimitedOp, RetryConfig, RetryPolicy, Satoshi,
+    SelectionScope, SignMessageArgs, SignMessageError, SignedMessage, StandardFeePercentileTooHigh,
+    StateImportError, StateRestoreError, StateRestoreOptions, Utxo, UtxosArgs, UtxosResult,
+    UtxosState, UtxosUpdate, WithCost, DEFAULT_FALLBACK_FEE_PER_BYTE, DEFAULT_MAX_INPUTS,
+    DEFAULT_MAX_TX_WEIGHT, DEFAULT_TARGET_BLOCKS_PERCENTILES,
+};
+#[cfg(any(test, feature = "mock"))]
+use crate::{
+    canister_common::GET_BALANCE_COST_CYCLES,
+    canister_mock::{self, ManagementCanisterMock},
+    ManagementCanisterMethod,
 };
-#[cfg(test)]
-use crate::{canister_mock::ManagementCanisterMock, transaction_management::evaluate_fee_request};
 use bitcoin::{hashes, Address};
-use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "stable-memory")]
+use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+#[cfg(feature = "stable-memory")]
+use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::Arc,
+};
 
 #[derive(Clone)]
 pub struct BitcoinAgent<C: ManagementCanister> {
@@ -29,25 +72,135 @@ pub struct BitcoinAgent<C: ManagementCanister> {
     pub(crate) ecdsa_pub_key_addresses: BTreeMap<Address, EcdsaPubKey>,
     pub(crate) min_confirmations: u32,
     pub(crate) utxos_state_addresses: BTreeMap<Address, UtxosState>,
+    pub(crate) payout_queue: PayoutQueue,
+    pub(crate) fee_cache: FeeCache,
+    pub(crate) standard_fee_percentile: u8,
+    pub(crate) target_blocks_percentiles: BTreeMap<u8, u8>,
+    pub(crate) max_fee_per_byte: Option<MillisatoshiPerByte>,
+    pub(crate) sign_retry_config: RetryConfig,
+    pub(crate) sign_concurrency: u32,
+    pub(crate) key_rotations: Vec<KeyRotation>,
+    /// Counts the calls of the rate limited operations, behind a `RefCell` as the arguments structures are returned from a shared reference.
+    pub(crate) rate_limiter: RefCell<RateLimiter>,
+    /// Keeps track of the planned UTXOs refreshes, behind a `RefCell` for the same reason, which isn't saved in the state.
+    pub(crate) poller: RefCell<Poller>,
+    /// How the free functions retry the calls made with the arguments structures, which isn't saved in the state.
+    pub(crate) retry_policy: RetryPolicy,
+    /// Whether the UTXOs of zero value returned by `bitcoin_get_utxos` are accepted, which isn't saved in the state either.
+    pub(crate) allow_zero_value_utxos: bool,
+    /// Receives the log messages of the Bitcoin agent and of the transfers made with its arguments structures, which isn't saved in the state either.
+    pub(crate) log_sink: Arc<dyn LogSink>,
+    /// Provides the time of the cached fees and of the transfers made with its arguments structures, which isn't saved in the state either.
+    pub(crate) time_source: Arc<dyn TimeSource>,
+    /// The maximum number of cycles accepted for any of the costs set with `set_cycles_cost_config`, which isn't saved in the state either.
+    pub(crate) cycles_cost_ceiling: u128,
+    /// The stable memory the Bitcoin agent is written through to, `None` if the Bitcoin agent is only saved with `get_state`.
+    #[cfg(feature = "stable-memory")]
+    pub(crate) stable_storage: Option<Rc<RefCell<StableStorage>>>,
 }
 
+/// A Bitcoin agent whose management canister is boxed, e.g. to hold Bitcoin agents of different networks or management canisters behind the same type.
+/// It's obtained with `BitcoinAgent::into_boxed`.
+pub type BoxedBitcoinAgent = BitcoinAgent<Box<dyn ManagementCanister>>;
+
 impl<C: ManagementCanister> BitcoinAgent<C> {
     /// Creates a new Bitcoin agent using the given management canister.
+    /// The other settings are configured with their setters afterwards, or at once with `BitcoinAgentBuilder`.
     pub fn new(
         management_canister: C,
         main_address_type: &AddressType,
         min_confirmations: u32,
     ) -> Result<Self, MinConfirmationsTooHigh> {
-        if min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
-            return Err(MinConfirmationsTooHigh);
+        // The minimum number of confirmations is the only setting of the builder which may be invalid here.
+        BitcoinAgentBuilder::new(management_canister)
+            .main_address_type(*main_address_type)
+            .min_confirmations(min_confirmations)
+            .build()
+            .map_err(|_| MinConfirmationsTooHigh)
+    }
+
+    /// Creates a new Bitcoin agent using the given management canister, whose state is written through to the `STABLE_MEMORY_IDS` virtual memories of `memory_manager` on every mutation.
+    /// Hence upgrades don't have to save its state, the Bitcoin agent being re-opened with `from_stable` in `post_upgrade`.
+    /// Any state of a Bitcoin agent held by these virtual memories is overwritten.
+    #[cfg(feature = "stable-memory")]
+    pub fn new_stable(
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+        management_canister: C,
+        main_address_type: &AddressType,
+        min_confirmations: u32,
+    ) -> Result<Self, MinConfirmationsTooHigh> {
+        let mut bitcoin_agent =
+            Self::new(management_canister, main_address_type, min_confirmations)?;
+        stable_management::attach_stable_storage(memory_manager, &mut bitcoin_agent);
+        Ok(bitcoin_agent)
+    }
+
+    /// Returns the Bitcoin agent with its management canister boxed, e.g. after restoring it with `from_state` which requires a concrete management canister.
+    pub fn into_boxed(self) -> BoxedBitcoinAgent
+    where
+        C: 'static,
+    {
+        BoxedBitcoinAgent {
+            management_canister: Box::new(self.management_canister),
+            main_address_type: self.main_address_type,
+            ecdsa_pub_key_addresses: self.ecdsa_pub_key_addresses,
+            min_confirmations: self.min_confirmations,
+            utxos_state_addresses: self.utxos_state_addresses,
+            payout_queue: self.payout_queue,
+            fee_cache: self.fee_cache,
+            standard_fee_percentile: self.standard_fee_percentile,
+            target_blocks_percentiles: self.target_blocks_percentiles,
+            max_fee_per_byte: self.max_fee_per_byte,
+            sign_retry_config: self.sign_retry_config,
+            sign_concurrency: self.sign_concurrency,
+            key_rotations: self.key_rotations,
+            rate_limiter: self.rate_limiter,
+            poller: self.poller,
+            retry_policy: self.retry_policy,
+            allow_zero_value_utxos: self.allow_zero_value_utxos,
+            log_sink: self.log_sink,
+            time_source: self.time_source,
+            cycles_cost_ceiling: self.cycles_cost_ceiling,
+            #[cfg(feature = "stable-memory")]
+            stable_storage: self.stable_storage,
         }
-        Ok(Self {
-            management_canister,
-            main_address_type: *main_address_type,
-            ecdsa_pub_key_addresses: BTreeMap::default(),
-            utxos_state_addresses: BTreeMap::default(),
-            min_confirmations,
-        })
+    }
+
+    /// Returns the Bitcoin agent written through to the stable memory by a Bitcoin agent created with `new_stable` or `import_state`, e.g. in `post_upgrade`.
+    /// Returns an error if the stable memory doesn't hold any Bitcoin agent, if its state is corrupted or if it's the one of another network than `expected_network`.
+    #[cfg(feature = "stable-memory")]
+    pub fn from_stable(
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+        expected_network: Network,
+    ) -> Result<Self, StateRestoreError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        stable_management::from_stable(memory_manager, expected_network)
+    }
+
+    /// Returns the associated Bitcoin agent with the given `bitcoin_agent_state`, whose state is then written through to the stable memory like the ones created with `new_stable`.
+    /// It's used once to migrate a Bitcoin agent saved with `get_state` in `pre_upgrade` to the stable memory, the network of the state being checked like with `from_state`.
+    #[cfg(feature = "stable-memory")]
+    pub fn import_state(
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+        bitcoin_agent_state: impl Into<BitcoinAgentState>,
+        expected_network: Network,
+    ) -> Result<Self, StateRestoreError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        stable_management::import_state(
+            memory_manager,
+            bitcoin_agent_state.into(),
+            expected_network,
+        )
+    }
+
+    /// Returns the inconsistencies of the managed addresses with the ECDSA public key of the Bitcoin agent, e.g. after restoring a state of another canister or whose ECDSA key name changed.
+    /// Each address is re-derived from the ECDSA public key of the Bitcoin agent at the derivation path of its ECDSA public key, watch-only addresses being skipped.
+    pub fn verify_state_integrity(&self) -> Vec<IntegrityIssue> {
+        upgrade_management::verify_state_integrity(self)
     }
 
     /// Returns the Bitcoin agent state.
@@ -55,9 +208,96 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         upgrade_management::get_state(self)
     }
 
+    /// Writes the Bitcoin agent state encoded with Candid to `writer`, e.g. to the stable memory in `pre_upgrade` with `ic_cdk::api::stable::StableWriter`.
+    /// The bytes are the ones of `get_state().encode()`, but the state is encoded from references to the Bitcoin agent instead of a clone of its UTXOs states, lowering the peak memory of `pre_upgrade`.
+    pub fn write_state<W: io::Write>(&self, writer: W) -> Result<(), io::Error> {
+        upgrade_management::write_state(self, writer)
+    }
+
+    /// Returns the Bitcoin agent associated with the state encoded with Candid read from `reader`, e.g. written by `write_state` or `get_state().encode()`.
+    /// States of previous versions are migrated, and the decoded state is validated like with `from_state`, including its network against `expected_network`.
+    pub fn read_state<R: io::Read>(reader: R, expected_network: Network) -> Result<Self, io::Error>
+    where
+        C: ManagementCanisterFactory,
+    {
+        upgrade_management::read_state(reader, expected_network)
+    }
+
+    /// Returns the memory used by the Bitcoin agent, which grows with its addresses and their cached UTXOs.
+    pub fn memory_footprint(&self) -> MemoryStats {
+        upgrade_management::get_memory_stats(self)
+    }
+
     /// Returns the associated Bitcoin agent with the given `bitcoin_agent_state`, assuming that it wasn't modified since its obtention with `get_state`.
-    pub fn from_state(bitcoin_agent_state: BitcoinAgentState) -> Self {
-        upgrade_management::from_state(bitcoin_agent_state)
+    /// States of previous versions, e.g. `BitcoinAgentStateV0`, are migrated to the current version.
+    /// Returns an error if the state is corrupted, e.g. if one of its addresses can't be parsed, instead of trapping.
+    /// Returns `StateRestoreError::NetworkMismatch` if the state was saved on another network than `expected_network`, e.g. the one the canister is configured for.
+    pub fn from_state(
+        bitcoin_agent_state: impl Into<BitcoinAgentState>,
+        expected_network: Network,
+    ) -> Result<Self, StateRestoreError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        upgrade_management::from_state(
+            bitcoin_agent_state.into(),
+            StateRestoreOptions::new(expected_network),
+        )
+    }
+
+    /// Returns the associated Bitcoin agent with the given `bitcoin_agent_state` like `from_state`, restored according to `state_restore_options`.
+    /// E.g. with `allow_network_change`, a state saved on the testnet is restored as a regtest Bitcoin agent to test it locally, in which case its addresses are relabelled with the regtest network.
+    pub fn from_state_with_options(
+        bitcoin_agent_state: impl Into<BitcoinAgentState>,
+        state_restore_options: StateRestoreOptions,
+    ) -> Result<Self, StateRestoreError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        upgrade_management::from_state(bitcoin_agent_state.into(), state_restore_options)
+    }
+
+    /// Returns the associated Bitcoin agent with the given `bitcoin_agent_state` like `from_state`, but with empty UTXOs states, e.g. if the cached UTXOs of the state are stale or suspected to be corrupted.
+    /// The ECDSA public keys of the addresses and their minimum numbers of confirmations are kept, and the next `apply_utxos` of each address rebuilds its UTXOs state.
+    /// The first `get_balance_update` of each address then returns its whole balance as added.
+    pub fn from_state_addresses_only(
+        bitcoin_agent_state: impl Into<BitcoinAgentState>,
+        expected_network: Network,
+    ) -> Result<Self, StateRestoreError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        upgrade_management::from_state_addresses_only(bitcoin_agent_state.into(), expected_network)
+    }
+
+    /// Returns the associated Bitcoin agent with the given `bitcoin_agent_state` without validating it.
+    /// Panics if the state is corrupted.
+    #[deprecated(
+        note = "Use `from_state`, which returns an error on corrupted states instead of panicking."
+    )]
+    pub fn from_state_unchecked(bitcoin_agent_state: impl Into<BitcoinAgentState>) -> Self
+    where
+        C: ManagementCanisterFactory,
+    {
+        upgrade_management::from_state_unchecked(bitcoin_agent_state.into())
+    }
+
+    /// Returns the state of the Bitcoin agent as self-describing bytes, e.g. for disaster recovery, unlike `get_state` which leaves the encoding to the caller.
+    /// The bytes start with a magic, the version of the state and a SHA-256 checksum, followed by the state encoded with Candid.
+    pub fn export_state_bytes(&self) -> Vec<u8> {
+        upgrade_management::export_state_bytes(self)
+    }
+
+    /// Returns the Bitcoin agent associated with the given bytes exported with `export_state_bytes`.
+    /// Fails if the bytes are corrupted, the magic, the version and the checksum being verified before decoding the state, or if the decoded state can't be restored, e.g. as it's the one of another network than `expected_network`.
+    pub fn import_state_bytes(
+        bytes: &[u8],
+        expected_network: Network,
+    ) -> Result<Self, StateImportError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        upgrade_management::import_state_bytes(bytes, expected_network)
     }
 
     /// Adds an address based on the provided derivation path and address type to the list of managed addresses.
@@ -69,12 +309,14 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         address_type: &AddressType,
         min_confirmations: u32,
     ) -> Result<Address, AddAddressWithParametersError> {
-        address_management::add_address_with_parameters(
+        let address = address_management::add_address_with_parameters(
             self,
             derivation_path,
             address_type,
             min_confirmations,
-        )
+        )?;
+        self.write_through_address(&address);
+        Ok(address)
     }
 
     /// Adds an address to the agent with the provided derivation path.
@@ -82,14 +324,16 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
     pub fn add_address(
         &mut self,
         derivation_path: &[Vec<u8>],
-    ) -> Result<Address, DerivationPathTooLong> {
+    ) -> Result<Address, DerivationPathError> {
         let address_type = self.main_address_type;
         match self.add_address_with_parameters(
             derivation_path,
             &address_type,
             self.min_confirmations,
         ) {
-            Err(AddAddressWithParametersError::DerivationPathTooLong) => Err(DerivationPathTooLong),
+            Err(AddAddressWithParametersError::InvalidDerivationPath(derivation_path_error)) => {
+                Err(derivation_path_error)
+            }
             Ok(address) => Ok(address),
             // Other case AddAddressWithParameters::MinConfirmationsTooHigh can't happen see BitcoinAgent::new
             _ => panic!(),
@@ -100,7 +344,9 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
     /// The address is removed if it is already managed and if it is different from the main address.
     /// Returns true if the removal was successful, false otherwise.
     pub fn remove_address(&mut self, address: &Address) -> bool {
-        address_management::remove_address(self, address)
+        let removed = address_management::remove_address(self, address);
+        self.write_through_address(address);
+        removed
     }
 
     /// Returns the managed addresses according to given BitcoinAgent.
@@ -108,6 +354,11 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         address_management::list_addresses(self)
     }
 
+    /// Returns the addresses derived from previous ECDSA public keys, whose UTXOs are still tracked but aren't spent anymore.
+    pub fn list_watch_only_addresses(&self) -> Vec<&Address> {
+        address_management::list_watch_only_addresses(self)
+    }
+
     // TODO(ER-2587): Add support for address management, test spending UTXOs received on addresses of all supported types (relying on ER-2593).
 
     /// Returns the P2SH address from a given script hash.
@@ -120,6 +371,82 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         address_management::get_main_address(&self.management_canister, &self.main_address_type)
     }
 
+    /// Returns the network of the Bitcoin agent, without cloning its state like `get_state`.
+    ///
+    /// ```
+    /// # #[cfg(feature = "mock")]
+    /// # fn main() {
+    /// use ic_btc_library::{canister_mock, AddressType, Network};
+    ///
+    /// let agent = canister_mock::new_mock(&Network::Regtest, &AddressType::P2wpkh);
+    /// assert_eq!(agent.network(), Network::Regtest);
+    /// assert_eq!(agent.main_address_type(), AddressType::P2wpkh);
+    /// assert_eq!(agent.min_confirmations(), 0);
+    /// # }
+    /// # #[cfg(not(feature = "mock"))]
+    /// # fn main() {}
+    /// ```
+    pub fn network(&self) -> Network {
+        from_bitcoin_network_to_types_network(self.management_canister.get_network())
+    }
+
+    /// Returns the minimum number of confirmations used by default for the added addresses.
+    pub fn min_confirmations(&self) -> u32 {
+        self.min_confirmations
+    }
+
+    /// Returns the type of the main address of the Bitcoin agent and of the addresses added without type.
+    pub fn main_address_type(&self) -> AddressType {
+        self.main_address_type
+    }
+
+    /// Returns the number of managed addresses, watch-only addresses excluded, without collecting them like `list_addresses`.
+    ///
+    /// ```
+    /// # #[cfg(feature = "mock")]
+    /// # fn main() {
+    /// use ic_btc_library::{canister_mock, AddressType, Network};
+    ///
+    /// let mut agent = canister_mock::new_mock(&Network::Regtest, &AddressType::P2pkh);
+    /// let managed_address_count = agent.managed_address_count();
+    /// agent.add_address(&[vec![1]]).unwrap();
+    /// assert_eq!(agent.managed_address_count(), managed_address_count + 1);
+    /// # }
+    /// # #[cfg(not(feature = "mock"))]
+    /// # fn main() {}
+    /// ```
+    pub fn managed_address_count(&self) -> usize {
+        self.ecdsa_pub_key_addresses.len()
+    }
+
+    /// Returns the compressed ECDSA public key of the Bitcoin agent, from which its addresses are derived.
+    ///
+    /// ```
+    /// # #[cfg(feature = "mock")]
+    /// # fn main() {
+    /// use ic_btc_library::{canister_mock, AddressType, Network};
+    ///
+    /// let agent = canister_mock::new_mock(&Network::Regtest, &AddressType::P2pkh);
+    /// assert_eq!(agent.ecdsa_public_key().len(), 33);
+    /// # }
+    /// # #[cfg(not(feature = "mock"))]
+    /// # fn main() {}
+    /// ```
+    pub fn ecdsa_public_key(&self) -> Vec<u8> {
+        self.management_canister.get_ecdsa_public_key().public_key
+    }
+
+    /// Returns the minimum number of confirmations of the UTXOs of the managed `address`, the one given when adding it.
+    pub fn get_address_min_confirmations(
+        &self,
+        address: &Address,
+    ) -> Result<u32, AddressNotTracked> {
+        self.utxos_state_addresses
+            .get(address)
+            .map(|utxos_state| utxos_state.min_confirmations)
+            .ok_or(AddressNotTracked)
+    }
+
     /// Returns the difference between the current UTXO state and the last seen state for this address.
     /// The last seen state for an address is updated to the current state by calling `update_state` or implicitly when invoking `get_utxos_update`.
     /// If there are no changes to the UTXO set since the last call, the returned `UtxosUpdate` will be identical.
@@ -130,7 +457,9 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
     /// Updates the state of the `BitcoinAgent` for the given `address`.
     /// This function doesn't invoke a Bitcoin integration API function.
     pub fn update_state(&mut self, address: &Address) -> Result<(), AddressNotTracked> {
-        utxo_management::update_state(self, address)
+        utxo_management::update_state(self, address)?;
+        self.write_through_address(address);
+        Ok(())
     }
 
     /// Returns the difference in the set of UTXOs of an address controlled by the `BitcoinAgent` between the current state and the seen state when the function was last called, considering only UTXOs with the number of confirmations specified when adding the given address.
@@ -140,7 +469,9 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         &mut self,
         address: &Address,
     ) -> Result<UtxosUpdate, AddressNotTracked> {
-        utxo_management::get_utxos_update(self, address)
+        let utxos_update = utxo_management::get_utxos_update(self, address)?;
+        self.write_through_address(address);
+        Ok(utxos_update)
     }
 
     /// Returns the difference between the current balance state and the last seen state for this address.
@@ -154,68 +485,510 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
     }
 
     /// Returns the difference in the balance of an address controlled by the `BitcoinAgent` between the current state and the seen state when the function was last called, considering only transactions with the specified number of confirmations.
-    /// The returned `BalanceUpdate` contains the information on how much balance was added and subtracted in total. If the function is called for the first time, the current balance of the address is returned.
+    /// The returned `BalanceUpdate` contains the information on how much balance was added and subtracted in total, and the outpoints of the UTXOs composing it. If the function is called for the first time, the current balance of the address is returned.
     /// It is equivalent to calling `get_utxos_update` and summing up the balances in the returned UTXOs.
     pub fn get_balance_update(
         &mut self,
         address: &Address,
     ) -> Result<BalanceUpdate, AddressNotTracked> {
-        utxo_management::get_balance_update(self, address)
+        let balance_update = utxo_management::get_balance_update(self, address)?;
+        self.write_through_address(address);
+        Ok(balance_update)
     }
 
     // ---
     // Usage pattern to update the utxos state of the agent (eg. with thread_local agents):
-    // let args = AGENT.with(|s| s.borrow().get_utxos_args(address));
-    // let result = get_utxos_from_args(args).await.unwrap();
+    // let args = AGENT.with(|s| s.borrow().get_utxos_args(address)).unwrap();
+    // let result = get_utxos_from_args(args).await.unwrap().result;
     // let utxos = AGENT.with(|s| s.borrow_mut().apply_utxos(result));
 
-    pub fn get_utxos_args(&self, address: &Address, min_confirmations: u32) -> UtxosArgs {
-        UtxosArgs {
+    /// Fails if the rate limit of `RateLimitedOp::GetUtxos` is reached, see `set_rate_limit`.
+    pub fn get_utxos_args(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+    ) -> Result<UtxosArgs, RateLimited> {
+        self.count_rate_limited_call(RateLimitedOp::GetUtxos)?;
+        Ok(UtxosArgs {
             network: self.management_canister.get_network(),
             address: address.clone(),
             min_confirmations,
+            cycles_cost_config: self.management_canister.get_cycles_cost_config(),
+            retry_policy: self.retry_policy,
+            bitcoin_api_target: self.get_bitcoin_api_target(),
             utxos_state: self
                 .utxos_state_addresses
                 .get(address)
                 .unwrap_or(&UtxosState::new(min_confirmations))
                 .clone(),
-        }
+            allow_zero_value_utxos: self.allow_zero_value_utxos,
+        })
     }
 
     pub fn apply_utxos(&mut self, utxos_result: UtxosResult) -> UtxosUpdate {
-        let mut utxos_state_address = self
+        let utxos_state_address = self
             .utxos_state_addresses
             .get_mut(&utxos_result.address)
             .unwrap();
         utxos_state_address.unseen_state = utxos_result.utxos;
-        UtxosUpdate::from_state(
+        let utxos_update = UtxosUpdate::from_state(
             &utxos_state_address.seen_state,
             &utxos_state_address.unseen_state,
-        )
+        );
+        self.fee_cache.apply_tip_height(utxos_result.tip_height);
+        self.log_sink.log(
+            LogLevel::Info,
+            LOG_TARGET_STATE,
+            &format!(
+                "Applied {} UTXOs of {} at the tip height {}.",
+                utxos_state_address.unseen_state.len(),
+                utxos_result.address,
+                utxos_result.tip_height
+            ),
+        );
+        self.write_through_address(&utxos_result.address);
+        self.write_through_state();
+        utxos_update
+    }
+
+    /// Returns the arguments to refresh the UTXOs of at most `poll_budget.max_calls` managed addresses, e.g. from a timer driving the periodic refreshes of a canister.
+    /// The addresses are planned in turn, skipping the ones planned within the last `poll_budget.skip_recent_invocations` calls, the ones expecting an incoming payment being planned first, see `expect_incoming`.
+    /// The plan stops early if the rate limit of `RateLimitedOp::GetUtxos` is reached.
+    /// The planned refreshes aren't saved in the state, hence the turns start over after restoring.
+    pub fn next_poll_plan(&self, poll_budget: PollBudget) -> Vec<UtxosArgs> {
+        polling::next_poll_plan(self, poll_budget)
     }
 
-    pub fn get_current_fees_args(&self) -> CurrentFeesArgs {
-        CurrentFeesArgs {
+    /// Applies the results of the calls made with the arguments returned by `next_poll_plan` and returns the UTXOs updates of the refreshed addresses.
+    /// The failed calls and the results of the addresses that aren't managed anymore are skipped.
+    pub fn apply_poll_results(
+        &mut self,
+        poll_results: Vec<Result<UtxosResult, GetUtxosError>>,
+    ) -> Vec<(Address, UtxosUpdate)> {
+        polling::apply_poll_results(self, poll_results)
+    }
+
+    /// Makes `next_poll_plan` plan the given managed address ahead of the other ones until UTXOs are added to it.
+    /// The expected incoming payments aren't saved in the state, hence they have to be expected again after restoring.
+    pub fn expect_incoming(&mut self, address: &Address) -> Result<(), AddressNotTracked> {
+        polling::expect_incoming(self, address)
+    }
+
+    /// Stops expecting an incoming payment to the given address.
+    /// Returns true if a payment was expected, false otherwise.
+    pub fn cancel_expected_incoming(&mut self, address: &Address) -> bool {
+        polling::cancel_expected_incoming(self, address)
+    }
+
+    /// Fails if the rate limit of `RateLimitedOp::GetCurrentFees` is reached, see `set_rate_limit`.
+    pub fn get_current_fees_args(&self) -> Result<CurrentFeesArgs, RateLimited> {
+        self.count_rate_limited_call(RateLimitedOp::GetCurrentFees)?;
+        Ok(CurrentFeesArgs {
             network: self.management_canister.get_network(),
+            cycles_cost_config: self.management_canister.get_cycles_cost_config(),
+            retry_policy: self.retry_policy,
+            bitcoin_api_target: self.get_bitcoin_api_target(),
+        })
+    }
+
+    /// Returns the arguments to get fee suggestions with a single retrieval of the fee percentiles.
+    /// The economical and priority suggestions use the percentiles of the largest and smallest numbers of blocks of the target blocks percentiles, and the standard suggestion uses the standard fee percentile.
+    pub fn get_fee_suggestions_args(&self) -> FeeSuggestionsArgs {
+        let get_percentile = |target_blocks| {
+            match self.resolve_fee_request(FeeRequest::TargetBlocks(target_blocks)) {
+                FeeRequest::Percentile(percentile) => percentile,
+                // Without target blocks percentiles, the default ones are used.
+                _ => get_target_blocks_percentile(
+                    target_blocks,
+                    &BTreeMap::from(DEFAULT_TARGET_BLOCKS_PERCENTILES),
+                )
+                .unwrap(),
+            }
+        };
+        FeeSuggestionsArgs {
+            network: self.management_canister.get_network(),
+            economical_percentile: get_percentile(u8::MAX),
+            standard_percentile: self.standard_fee_percentile,
+            priority_percentile: get_percentile(0),
+            cycles_cost_config: self.management_canister.get_cycles_cost_config(),
+            retry_policy: self.retry_policy,
+            bitcoin_api_target: self.get_bitcoin_api_target(),
         }
     }
 
     pub fn get_current_fee_args(&self, fee_request: FeeRequest) -> CurrentFeeArgs {
         CurrentFeeArgs {
             network: self.management_canister.get_network(),
-            fee_request,
+            fee_request: self.resolve_fee_request(fee_request),
+            clamp_percentile: false,
+            cycles_cost_config: self.management_canister.get_cycles_cost_config(),
+            retry_policy: self.retry_policy,
+            bitcoin_api_target: self.get_bitcoin_api_target(),
         }
     }
 
+    /// Returns the arguments to retrieve the block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`.
+    /// Retrieving a single header is a cheap way to learn the tip height, e.g. from a heartbeat.
+    pub fn get_block_headers_args(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> BlockHeadersArgs {
+        BlockHeadersArgs {
+            network: self.management_canister.get_network(),
+            start_height,
+            end_height,
+            payment: GET_BLOCK_HEADERS_COST_CYCLES,
+            retry_policy: self.retry_policy,
+            bitcoin_api_target: self.get_bitcoin_api_target(),
+        }
+    }
+
+    /// Records the tip height of the block headers retrieved with `get_block_headers_from_args`, which makes the cached fees stale if the tip advanced too much.
+    pub fn apply_block_headers(&mut self, get_block_headers_response: &GetBlockHeadersResponse) {
+        self.fee_cache
+            .apply_tip_height(get_block_headers_response.tip_height);
+        self.log_sink.log(
+            LogLevel::Debug,
+            LOG_TARGET_STATE,
+            &format!(
+                "Applied the tip height {}.",
+                get_block_headers_response.tip_height
+            ),
+        );
+        self.write_through_state();
+    }
+
+    /// Caches the given fee percentiles, retrieved with `get_current_fees_from_args` when the tip height was `tip_height`.
+    /// The cached fees are used by the transfers until they are stale.
+    pub fn apply_current_fees(&mut self, fees: Vec<MillisatoshiPerByte>, tip_height: u32) {
+        self.log_sink.log(
+            LogLevel::Debug,
+            LOG_TARGET_STATE,
+            &format!(
+                "Cached {} fee percentiles retrieved at the tip height {}.",
+                fees.len(),
+                tip_height
+            ),
+        );
+        self.fee_cache
+            .apply_current_fees(fees, tip_height, self.time_source.now());
+        self.write_through_state();
+    }
+
+    /// Returns the cached fee in millisatoshis/byte associated with the given `FeeRequest`.
+    /// Returns `None` if no fees are cached, if they are stale or if the fee request is invalid.
+    pub fn cached_fee(&self, fee_request: FeeRequest) -> Option<MillisatoshiPerByte> {
+        self.fee_cache.get_fee(
+            self.resolve_fee_request(fee_request),
+            self.time_source.now(),
+        )
+    }
+
+    /// Sets the number of blocks the tip may advance, as seen by `apply_utxos` and `apply_multi_transfer_result`, before the cached fees are stale.
+    pub fn set_fee_cache_max_age(&mut self, max_age: u32) {
+        self.fee_cache.max_age = max_age;
+        self.write_through_state();
+    }
+
+    /// Sets the nanoseconds after which the cached fees are stale whatever the tip height, according to the time source of the Bitcoin agent, `None` by default for the cached fees not to expire with time.
+    /// The time to live of the cached fees isn't saved in the state, hence it has to be set again after restoring the Bitcoin agent.
+    pub fn set_fee_cache_ttl(&mut self, ttl: Option<u64>) {
+        self.fee_cache.ttl = ttl;
+    }
+
+    /// Returns the nanoseconds after which the cached fees are stale whatever the tip height, `None` if they don't expire with time.
+    pub fn get_fee_cache_ttl(&self) -> Option<u64> {
+        self.fee_cache.ttl
+    }
+
+    /// Sets the fee smoothing, `None` disabling it.
+    /// When enabled, the transfers use the smoothed fee percentiles instead of the cached ones.
+    pub fn set_fee_smoothing(&mut self, fee_smoothing: Option<FeeSmoothing>) {
+        self.fee_cache.set_fee_smoothing(fee_smoothing);
+        self.write_through_state();
+    }
+
+    /// Returns the fee smoothing, `None` if fee smoothing is disabled.
+    pub fn get_fee_smoothing(&self) -> Option<FeeSmoothing> {
+        self.fee_cache.fee_smoothing
+    }
+
+    /// Returns the fee in millisatoshis/byte associated with the given `FeeRequest` smoothed over the last fee percentiles applied with `apply_current_fees`.
+    /// Returns `None` if fee smoothing is disabled, if no fee percentiles were applied since or if the fee request is invalid.
+    pub fn smoothed_fee(&self, fee_request: FeeRequest) -> Option<MillisatoshiPerByte> {
+        self.fee_cache
+            .get_smoothed_fee(self.resolve_fee_request(fee_request))
+    }
+
+    /// Sets the percentile used for `Fee::Standard` and `FeeRequest::Standard`, which has to be between 0 and 100.
+    pub fn set_standard_fee_percentile(
+        &mut self,
+        standard_fee_percentile: u8,
+    ) -> Result<(), StandardFeePercentileTooHigh> {
+        if standard_fee_percentile > 100 {
+            return Err(StandardFeePercentileTooHigh);
+        }
+        self.standard_fee_percentile = standard_fee_percentile;
+        self.write_through_state();
+        Ok(())
+    }
+
+    /// Returns the percentile used for `Fee::Standard` and `FeeRequest::Standard`.
+    pub fn get_standard_fee_percentile(&self) -> u8 {
+        self.standard_fee_percentile
+    }
+
+    /// Sets the percentiles associated with confirming a transaction within a given number of blocks, used for `Fee::TargetBlocks` and `FeeRequest::TargetBlocks`.
+    /// For instance, with the default `{1: 90, 3: 60, 6: 40}`, confirming within 1 or 2 blocks uses the 90th percentile, within 3 to 5 blocks the 60th percentile and within 6 blocks or more the 40th percentile.
+    pub fn set_target_blocks_percentiles(
+        &mut self,
+        target_blocks_percentiles: BTreeMap<u8, u8>,
+    ) -> Result<(), InvalidPercentile> {
+        if target_blocks_percentiles
+            .values()
+            .any(|percentile| *percentile > 100)
+        {
+            return Err(InvalidPercentile);
+        }
+        self.target_blocks_percentiles = target_blocks_percentiles;
+        self.write_through_state();
+        Ok(())
+    }
+
+    /// Returns the percentiles associated with confirming a transaction within a given number of blocks.
+    pub fn get_target_blocks_percentiles(&self) -> &BTreeMap<u8, u8> {
+        &self.target_blocks_percentiles
+    }
+
+    /// Sets the maximum fee in millisatoshis/byte of the transfers using percentile fees, including `Fee::StandardTimes`, `None` removing the cap.
+    /// Constant fees and fees per byte aren't capped.
+    pub fn set_max_fee_per_byte(&mut self, max_fee_per_byte: Option<MillisatoshiPerByte>) {
+        self.max_fee_per_byte = max_fee_per_byte;
+        self.write_through_state();
+    }
+
+    /// Returns the maximum fee in millisatoshis/byte of the transfers using percentile fees, `None` if there isn't any.
+    pub fn get_max_fee_per_byte(&self) -> Option<MillisatoshiPerByte> {
+        self.max_fee_per_byte
+    }
+
+    /// Sets how the signatures of the inputs of the transfers are retried when `sign_with_ecdsa` is rejected because of a transient error.
+    pub fn set_sign_retry_config(&mut self, sign_retry_config: RetryConfig) {
+        self.sign_retry_config = sign_retry_config;
+        self.write_through_state();
+    }
+
+    /// Returns how the signatures of the inputs of the transfers are retried.
+    pub fn get_sign_retry_config(&self) -> RetryConfig {
+        self.sign_retry_config
+    }
+
+    /// Sets the maximum number of inputs of the transfers signed concurrently, `DEFAULT_SIGN_CONCURRENCY` by default.
+    pub fn set_sign_concurrency(&mut self, sign_concurrency: u32) {
+        self.sign_concurrency = sign_concurrency;
+        self.write_through_state();
+    }
+
+    /// Returns the maximum number of inputs of the transfers signed concurrently.
+    pub fn get_sign_concurrency(&self) -> u32 {
+        self.sign_concurrency
+    }
+
+    /// Sets the cycles attached to the paid calls to the management canister, `CyclesCostConfig::for_network` by default, e.g. when the pricing of the Bitcoin API changes.
+    /// The arguments structures returned afterwards carry the new values, which are saved in the state.
+    /// Returns an error, keeping the current values, if any of the costs is zero or exceeds the ceiling set with `set_cycles_cost_ceiling`.
+    pub fn set_cycles_cost_config(
+        &mut self,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<(), InvalidCyclesCostConfig> {
+        cycles_cost_config.validate(self.cycles_cost_ceiling)?;
+        self.management_canister
+            .set_cycles_cost_config(cycles_cost_config);
+        self.write_through_state();
+        Ok(())
+    }
+
+    /// Sets the cycles attached to the paid calls to the management canister from a Candid-encoded `CyclesCostConfig`, e.g. received by an admin endpoint of the canister, like `set_cycles_cost_config`.
+    pub fn set_cycles_cost_config_from_candid(
+        &mut self,
+        candid: &[u8],
+    ) -> Result<(), InvalidCyclesCostConfig> {
+        let cycles_cost_config = candid::decode_one(candid)
+            .map_err(|error| InvalidCyclesCostConfig::InvalidCandid(error.to_string()))?;
+        self.set_cycles_cost_config(cycles_cost_config)
+    }
+
+    /// Sets the maximum number of cycles accepted for any of the costs set with `set_cycles_cost_config`, `DEFAULT_CYCLES_COST_CEILING` by default.
+    /// The ceiling isn't saved in the state, hence it has to be set again after restoring the Bitcoin agent.
+    pub fn set_cycles_cost_ceiling(&mut self, cycles_cost_ceiling: u128) {
+        self.cycles_cost_ceiling = cycles_cost_ceiling;
+    }
+
+    /// Returns the maximum number of cycles accepted for any of the costs set with `set_cycles_cost_config`.
+    pub fn get_cycles_cost_ceiling(&self) -> u128 {
+        self.cycles_cost_ceiling
+    }
+
+    /// Returns the cycles attached to the paid calls to the management canister.
+    pub fn get_cycles_cost_config(&self) -> CyclesCostConfig {
+        self.management_canister.get_cycles_cost_config()
+    }
+
+    /// Sets how the calls to `bitcoin_get_utxos` and `bitcoin_get_current_fee_percentiles` made with the arguments structures returned afterwards are retried, `DEFAULT_RETRY_POLICY` by default.
+    /// The retry policy isn't saved in the state, hence it has to be set again after restoring the Bitcoin agent.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Returns how the calls made with the arguments structures are retried.
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    /// Sets whether the UTXOs of zero value returned by `bitcoin_get_utxos` are accepted by the calls made with the UTXOs arguments returned afterwards, which reject them as an invalid response by default.
+    /// The setting isn't saved in the state, hence it has to be set again after restoring the Bitcoin agent.
+    pub fn set_allow_zero_value_utxos(&mut self, allow_zero_value_utxos: bool) {
+        self.allow_zero_value_utxos = allow_zero_value_utxos;
+    }
+
+    /// Returns whether the UTXOs of zero value returned by `bitcoin_get_utxos` are accepted.
+    pub fn get_allow_zero_value_utxos(&self) -> bool {
+        self.allow_zero_value_utxos
+    }
+
+    /// Limits the arguments structures of `op` returned by the Bitcoin agent to `max_calls_per_window` per window of `window_len` blocks, e.g. to bound the cycles spent by a canister whose callers trigger the calls.
+    /// The first window starts at the latest tip height known by the Bitcoin agent, and a new one starts once the tip height applied with `apply_utxos`, `apply_block_headers` or `apply_current_fees` reaches its end.
+    /// The calls made in the current window are saved in the state, so that an upgrade doesn't reset the limit.
+    pub fn set_rate_limit(
+        &mut self,
+        op: RateLimitedOp,
+        max_calls_per_window: u32,
+        window_len: u32,
+    ) {
+        self.rate_limiter.get_mut().set_rate_limit(
+            op,
+            max_calls_per_window,
+            window_len,
+            self.fee_cache.get_tip_height(),
+        );
+        self.write_through_state();
+    }
+
+    /// Removes the rate limit of `op` set with `set_rate_limit`.
+    /// Returns true if `op` was rate limited, false otherwise.
+    pub fn remove_rate_limit(&mut self, op: RateLimitedOp) -> bool {
+        let was_rate_limited = self.rate_limiter.get_mut().remove_rate_limit(op);
+        self.write_through_state();
+        was_rate_limited
+    }
+
+    /// Sets the canister serving the calls to the Bitcoin API made with the arguments structures returned afterwards, the management canister by default.
+    /// The target isn't saved in the state either, hence it has to be set again after restoring the Bitcoin agent.
+    pub fn set_bitcoin_api_target(&mut self, bitcoin_api_target: BitcoinApiTarget) {
+        self.management_canister
+            .set_bitcoin_api_target(bitcoin_api_target);
+    }
+
+    /// Returns the canister serving the calls to the Bitcoin API.
+    pub fn get_bitcoin_api_target(&self) -> BitcoinApiTarget {
+        self.management_canister.get_bitcoin_api_target()
+    }
+
+    /// Sets the sink receiving the log messages of the Bitcoin agent and of the transfers made with the arguments returned afterwards, e.g. `PrintLogSink` while debugging, `NoopLogSink` by default.
+    /// The log sink isn't saved in the state, hence it has to be set again after restoring the Bitcoin agent.
+    pub fn set_log_sink(&mut self, log_sink: Arc<dyn LogSink>) {
+        self.log_sink = log_sink;
+    }
+
+    /// Sets the time source of the cached fees and of the transfers made with the arguments returned afterwards, the one of the management canister by default, e.g. the logical clock of the management canister mock.
+    /// The time source isn't saved in the state, hence it has to be set again after restoring the Bitcoin agent if it differs from the one of the management canister.
+    pub fn set_time_source(&mut self, time_source: Arc<dyn TimeSource>) {
+        self.time_source = time_source;
+    }
+
+    /// Returns the number of calls made by the library to each method, including the ones made by the free functions, with the cycles they spent and their rejections by rejection code.
+    /// The metrics are shared by the Bitcoin agents of the canister and aren't saved in the state, hence they're reset by an upgrade unless restored with `restore_metrics`.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        metrics::get_metrics_snapshot()
+    }
+
+    /// Resets the metrics returned by `metrics`.
+    pub fn reset_metrics(&self) {
+        metrics::set_metrics_snapshot(MetricsSnapshot::default());
+    }
+
+    /// Restores the metrics returned by `metrics` before an upgrade, e.g. saved in the stable memory in a `pre_upgrade` hook, the calls made since then being discarded.
+    pub fn restore_metrics(&self, metrics_snapshot: MetricsSnapshot) {
+        metrics::set_metrics_snapshot(metrics_snapshot);
+    }
+
+    /// Returns the fee request where the standard and target blocks fee requests are replaced with the percentiles configured for this Bitcoin agent.
+    fn resolve_fee_request(&self, fee_request: FeeRequest) -> FeeRequest {
+        fee_request.resolve(
+            self.standard_fee_percentile,
+            &self.target_blocks_percentiles,
+        )
+    }
+
     pub fn get_initialization_parameters_args(&self) -> InitializationParametersArgs {
         InitializationParametersArgs {
-            key_name: get_key_name_from_network(self.management_canister.get_network()),
+            key_name: self.management_canister.get_ecdsa_key_name(),
             ecdsa_public_key: self.management_canister.get_ecdsa_public_key(),
         }
     }
 
-    /// Initializes the Bitcoin agent by setting its ECDSA public key.
-    pub fn initialize(&mut self, ecdsa_public_key: EcdsaPubKey) {
+    /// Returns arguments to retrieve the current ECDSA public key of this canister with `get_initialization_parameters_from_args`, e.g. after the rotation of the threshold key.
+    /// The retrieved key is then given to `apply_rekey`.
+    pub fn get_rekey_args(&self) -> InitializationParametersArgs {
+        InitializationParametersArgs {
+            key_name: self.management_canister.get_ecdsa_key_name(),
+            ecdsa_public_key: EcdsaPubKey {
+                public_key: vec![],
+                chain_code: vec![],
+                derivation_path: DerivationPath::default(),
+            },
+        }
+    }
+
+    /// Replaces the ECDSA public key of the Bitcoin agent with `new_key`, deriving every managed address again at its derivation path.
+    /// The addresses derived from the previous key are kept as watch-only addresses, so that their balances remain readable, and the rotation is recorded in the state.
+    /// Nothing is done if `new_key` is the current ECDSA public key.
+    pub fn apply_rekey(&mut self, new_key: EcdsaPubKey) {
+        address_management::apply_rekey(self, new_key);
+        self.log_sink.log(
+            LogLevel::Info,
+            LOG_TARGET_STATE,
+            &format!(
+                "Applied the ECDSA public key rotation, {} rotations having been applied.",
+                self.key_rotations.len()
+            ),
+        );
+        self.write_through_all();
+    }
+
+    /// Returns the rotations of the ECDSA public key applied with `apply_rekey`, from the oldest to the most recent one.
+    pub fn get_key_rotations(&self) -> &[KeyRotation] {
+        &self.key_rotations
+    }
+
+    /// Returns whether the Bitcoin agent is initialized, i.e. it has an ECDSA public key and manages addresses, e.g. after `initialize` or `from_state`.
+    pub fn is_initialized(&self) -> bool {
+        !self
+            .management_canister
+            .get_ecdsa_public_key()
+            .public_key
+            .is_empty()
+            && !self.ecdsa_pub_key_addresses.is_empty()
+            && !self.utxos_state_addresses.is_empty()
+    }
+
+    /// Initializes the Bitcoin agent by setting its ECDSA public key, returning whether it wasn't initialized yet.
+    /// Nothing is done if the Bitcoin agent is already initialized, e.g. restored with `from_state`, so that its derived addresses and their UTXOs states are kept.
+    pub fn initialize(&mut self, ecdsa_public_key: EcdsaPubKey) -> bool {
+        if self.is_initialized() {
+            return false;
+        }
         self.management_canister
             .set_ecdsa_public_key(ecdsa_public_key);
         let main_address = get_main_address(&self.management_canister, &self.main_address_type);
@@ -225,6 +998,8 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         )]);
         self.utxos_state_addresses =
             BTreeMap::from([(main_address, UtxosState::new(self.min_confirmations))]);
+        self.write_through_all();
+        true
     }
 
     /// Returns arguments to send a transaction, transferring the specified Bitcoin amounts to the provided addresses.
@@ -234,6 +1009,7 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
     /// Further note that the set of UTXO is restricted to those in the updated state: If new UTXOs are discovered when calling `peek_utxos_update` (or `peek_balance_update`), these UTXOs will not be spent in any transaction until they are made available by calling `update_state`.
     /// On the other hand, the library is free to choose UTXOs of any managed address when constructing transactions.
     /// Also note that the library verifies if the final fee is at least 1 sat/B.
+    /// The payouts below the dust threshold of 546 satoshis are rejected with `MultiTransferError::DustOutput`.
     pub fn get_multi_transfer_args(
         &self,
         payouts: &BTreeMap<Address, Satoshi>,
@@ -242,85 +1018,267 @@ impl<C: ManagementCanister> BitcoinAgent<C> {
         min_confirmations: u32,
         replaceable: bool,
     ) -> MultiTransferArgs {
+        // Only the addresses that can be spent from are carried, instead of the whole state.
+        let (ecdsa_pub_key_addresses, utxos_state_addresses) =
+            transaction_management::get_spendable_addresses(
+                &self.ecdsa_pub_key_addresses,
+                &self.utxos_state_addresses,
+            );
         MultiTransferArgs {
-            key_name: get_key_name_from_network(self.management_canister.get_network()),
-            ecdsa_pub_key_addresses: self.ecdsa_pub_key_addresses.clone(),
-            utxos_state_addresses: self.utxos_state_addresses.clone(),
+            key_name: self.management_canister.get_ecdsa_key_name(),
+            ecdsa_pub_key_addresses,
+            utxos_state_addresses,
             payouts: payouts.clone(),
             change_address: change_address.clone(),
             fee,
+            standard_fee_percentile: self.standard_fee_percentile,
+            target_blocks_percentiles: self.target_blocks_percentiles.clone(),
+            clamp_percentile: false,
+            max_fee_per_byte: self.max_fee_per_byte,
+            sign_retry_config: self.sign_retry_config,
+            sign_concurrency: self.sign_concurrency,
             min_confirmations,
             replaceable,
             network: from_bitcoin_network_to_types_network(self.management_canister.get_network()),
+            queued_payout_ids: vec![],
+            inputs: vec![],
+            script_payouts: vec![],
+            allow_nonstandard: false,
+            max_fee: None,
+            max_tx_weight: DEFAULT_MAX_TX_WEIGHT,
+            max_inputs: DEFAULT_MAX_INPUTS,
+            selection_scope: SelectionScope::Any,
+            current_fees: self.fee_cache.get_fees_to_use(self.time_source.now()),
+            fallback_fee_per_byte: Some(DEFAULT_FALLBACK_FEE_PER_BYTE),
+            cycles_cost_config: self.management_canister.get_cycles_cost_config(),
+            bitcoin_api_target: self.get_bitcoin_api_target(),
+            log_sink: self.log_sink.clone(),
+            time_source: self.time_source.clone(),
+        }
+    }
+
+    /// Returns arguments to sign `message` with the key of the managed `address`, proving the control of the address to off-chain verifiers.
+    pub fn get_sign_message_args(
+        &self,
+        address: &Address,
+        message: &[u8],
+    ) -> Result<SignMessageArgs, AddressNotTracked> {
+        let ecdsa_pub_key = self
+            .ecdsa_pub_key_addresses
+            .get(address)
+            .ok_or(AddressNotTracked)?;
+        Ok(SignMessageArgs {
+            key_name: self.management_canister.get_ecdsa_key_name(),
+            address: address.clone(),
+            ecdsa_pub_key: ecdsa_pub_key.clone(),
+            message: message.to_vec(),
+            cycles_cost_config: self.management_canister.get_cycles_cost_config(),
+        })
+    }
+
+    /// Returns a builder of a transaction spending UTXOs of the managed addresses, whose inputs and outputs are chosen by the caller.
+    pub fn transaction_builder(&self) -> TransactionBuilder {
+        TransactionBuilder::new(self.get_multi_transfer_args(
+            &BTreeMap::default(),
+            &self.get_main_address(),
+            Fee::Standard,
+            self.min_confirmations,
+            false,
+        ))
+    }
+
+    /// Queues a payout of `amount` satoshis to `address` to be sent with the next flush of the payout queue.
+    /// Returns the identifier of the queued payout, which can be used to cancel it with `cancel_queued`.
+    pub fn queue_payout(&mut self, address: &Address, amount: Satoshi) -> QueueId {
+        let queue_id = self.payout_queue.queue_payout(address, amount);
+        self.write_through_state();
+        queue_id
+    }
+
+    /// Removes the queued payout with the given identifier from the payout queue.
+    /// Returns true if the payout was queued, false otherwise.
+    pub fn cancel_queued(&mut self, queue_id: QueueId) -> bool {
+        let cancelled = self.payout_queue.cancel_queued(queue_id);
+        self.write_through_state();
+        cancelled
+    }
+
+    /// Returns the total amount of the payouts waiting in the payout queue.
+    pub fn queued_total(&self) -> Satoshi {
+        self.payout_queue.queued_total()
+    }
+
+    /// Returns arguments to send a single transaction paying out every queued payout, sending back the change to `change_address`.
+    /// Queued payouts to the same address are merged into a single output.
+    /// The queued payouts are only removed from the payout queue when the result of the transfer is applied with `apply_multi_transfer_result`, hence if sending the transaction fails, they are part of the next flush.
+    pub fn get_flush_args(&self, fee: Fee, change_address: &Address) -> MultiTransferArgs {
+        let (payouts, queued_payout_ids) = self.payout_queue.get_payouts();
+        MultiTransferArgs {
+            queued_payout_ids,
+            ..self.get_multi_transfer_args(
+                &payouts,
+                change_address,
+                fee,
+                self.min_confirmations,
+                false,
+            )
         }
     }
 
     /// Caches the spent and generated outputs to build valid future transactions even with `min_confirmations = 0`.
-    pub fn apply_multi_transfer_result(&mut self, multi_transfer_result: &MultiTransferResult) {
-        // Cache the spent outputs to not use them for future transactions.
-        multi_transfer_result
+    /// The flushed payouts are removed from the payout queue.
+    /// Fails without modifying the Bitcoin agent if an address of `multi_transfer_result` is malformed or if the spent UTXOs belong to an address it doesn't manage.
+    pub fn apply_multi_transfer_result(
+        &mut self,
+        multi_transfer_result: &MultiTransferResult,
+    ) -> Result<(), ApplyMultiTransferResultError> {
+        let parse_address = |address_using_primitives: &AddressUsingPrimitives| {
+            get_address(address_using_primitives.clone()).map_err(|_| {
+                ApplyMultiTransferResultError::InvalidAddress(address_using_primitives.0.clone())
+            })
+        };
+        let spent_utxos_addresses = multi_transfer_result
             .transaction_info
             .utxos_addresses
-            .clone()
-            .into_iter()
-            .for_each(|(address_using_primitives, utxos)| {
-                let address = get_address(address_using_primitives);
-                utxos.iter().for_each(|utxo| {
-                    self.utxos_state_addresses
-                        .get_mut(&address)
-                        .unwrap()
-                        .spent_state
-                        .push(utxo.outpoint.clone())
-                })
-            });
-        // Cache the generated outputs to be able to use them for future transactions.
-        multi_transfer_result
-            .generated_utxos_addresses
-            .clone()
-            .into_iter()
-            .for_each(|(address_using_primitives, mut utxos)| {
-                let address = get_address(address_using_primitives);
-                if self.utxos_state_addresses.get(&address).is_none() {
-                    self.utxos_state_addresses
-                        .insert(address.clone(), UtxosState::new(0));
+            .iter()
+            .map(|(address_using_primitives, utxos)| {
+                let address = parse_address(address_using_primitives)?;
+                if !self.utxos_state_addresses.contains_key(&address) {
+                    return Err(ApplyMultiTransferResultError::AddressNotTracked(
+                        address_using_primitives.0.clone(),
+                    ));
                 }
-                let utxos_state_address = self.utxos_state_addresses.get_mut(&address).unwrap();
-                utxos_state_address.generated_state.append(&mut utxos);
+                Ok((address, utxos))
             })
+            .collect::<Result<Vec<_>, _>>()?;
+        let generated_utxos_addresses = multi_transfer_result
+            .generated_utxos_addresses
+            .iter()
+            .map(|(address_using_primitives, utxos)| {
+                Ok((parse_address(address_using_primitives)?, utxos))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.payout_queue
+            .remove_queued(&multi_transfer_result.queued_payout_ids);
+        self.fee_cache
+            .apply_tip_height(multi_transfer_result.height);
+        // Cache the spent outputs to not use them for future transactions.
+        for (address, utxos) in &spent_utxos_addresses {
+            let utxos_state_address = self.utxos_state_addresses.get_mut(address).unwrap();
+            utxos
+                .iter()
+                .for_each(|utxo| utxos_state_address.spent_state.push(utxo.outpoint.clone()));
+        }
+        // Cache the generated outputs to be able to use them for future transactions.
+        for (address, utxos) in &generated_utxos_addresses {
+            let utxos_state_address = self
+                .utxos_state_addresses
+                .entry(address.clone())
+                .or_insert_with(|| UtxosState::new(0));
+            utxos_state_address
+                .generated_state
+                .extend(utxos.iter().cloned());
+        }
+        self.log_sink.log(
+            LogLevel::Info,
+            LOG_TARGET_STATE,
+            &format!(
+                "Applied the transaction {} spending UTXOs of {} addresses and generating UTXOs for {} addresses.",
+                multi_transfer_result.transaction_info.id,
+                spent_utxos_addresses.len(),
+                generated_utxos_addresses.len()
+            ),
+        );
+        for (address, _) in spent_utxos_addresses
+            .iter()
+            .chain(generated_utxos_addresses.iter())
+        {
+            self.write_through_address(address);
+        }
+        self.write_through_state();
+        Ok(())
+    }
+
+    /// Writes the ECDSA public key and the UTXOs state of `address` through to the stable memory if the Bitcoin agent is stable.
+    #[cfg_attr(not(feature = "stable-memory"), allow(unused_variables))]
+    fn write_through_address(&self, address: &Address) {
+        #[cfg(feature = "stable-memory")]
+        stable_management::write_address(self, address);
+    }
+
+    /// Writes the state other than the managed addresses through to the stable memory if the Bitcoin agent is stable.
+    /// Counts a call of `op` if it's rate limited, failing if its rate limit is reached.
+    fn count_rate_limited_call(&self, op: RateLimitedOp) -> Result<(), RateLimited> {
+        if !self.rate_limiter.borrow().is_rate_limited(op) {
+            return Ok(());
+        }
+        let result = self
+            .rate_limiter
+            .borrow_mut()
+            .count_call(op, self.fee_cache.get_tip_height());
+        if let Err(rate_limited) = &result {
+            self.log_sink.log(
+                LogLevel::Warn,
+                LOG_TARGET_STATE,
+                &format!(
+                    "{:?} is rate limited until the tip height {}.",
+                    op, rate_limited.retry_after
+                ),
+            );
+        }
+        self.write_through_state();
+        result
+    }
+
+    fn write_through_state(&self) {
+        #[cfg(feature = "stable-memory")]
+        stable_management::write_state(self);
+    }
+
+    /// Writes the whole state through to the stable memory if the Bitcoin agent is stable, e.g. when all the managed addresses change.
+    fn write_through_all(&self) {
+        #[cfg(feature = "stable-memory")]
+        stable_management::write_all(self);
     }
 }
 
 pub async fn multi_transfer_from_args(
     multi_transfer_args: MultiTransferArgs,
 ) -> Result<MultiTransferResult, MultiTransferError> {
-    // When running `cargo test`, `multi_transfer` requires an additional argument that is `BitcoinAgent<ManagementCanisterMock>`.
-    // This pattern satisfies the compiler for building and testing.
-    #[cfg(test)]
-    unreachable!();
-    #[cfg(not(test))]
-    transaction_management::multi_transfer(multi_transfer_args).await
+    transaction_management::multi_transfer(multi_transfer_args, &IcTransferCalls).await
 }
 
+/// Returns the ECDSA public key of this canister, retrieving it if it isn't known yet.
+/// No cycles are spent as retrieving the ECDSA public key is free.
 pub async fn get_initialization_parameters_from_args(
     initialization_parameters_args: InitializationParametersArgs,
-) -> Result<EcdsaPubKey, ManagementCanisterReject> {
-    if initialization_parameters_args
+) -> Result<WithCost<EcdsaPubKey>, ManagementCanisterReject> {
+    let ecdsa_public_key = if initialization_parameters_args
         .ecdsa_public_key
         .public_key
         .is_empty()
     {
-        get_btc_ecdsa_public_key(&initialization_parameters_args.key_name).await
+        get_btc_ecdsa_public_key(&initialization_parameters_args.key_name).await?
     } else {
-        Ok(initialization_parameters_args.ecdsa_public_key)
-    }
+        initialization_parameters_args.ecdsa_public_key
+    };
+    Ok(WithCost {
+        result: ecdsa_public_key,
+        cycles_spent: 0,
+        attempts: 1,
+    })
 }
 
 /// Modify the provided `GetUtxosResponse` to remove spent UTXOs and add generated UTXOs if using `min_confirmations = 0`.
+/// The response is validated first, hence only the duplicates introduced by merging the generated UTXOs are removed.
 fn get_utxos_from_args_common(
     address: &Address,
     get_utxos_response: GetUtxosResponse,
     utxos_state: UtxosState,
+    allow_zero_value_utxos: bool,
 ) -> Result<UtxosResult, GetUtxosError> {
+    validate_get_utxos_response(&get_utxos_response, allow_zero_value_utxos)?;
     let utxos = if utxos_state.min_confirmations == 0 {
         let mut utxos: Vec<Utxo> = get_utxos_response.utxos;
         utxos.append(&mut utxos_state.generated_state.clone());
@@ -332,17 +1290,20 @@ fn get_utxos_from_args_common(
         });
         // Remove any duplicated UTXOs with a possible different height, keeping the UTXO with the heighest height.
         // Likewise if a UTXO was generated at height `n` thanks to a sent transaction, if the transaction is confirmed, the UTXO return by this function won't have its height still be `n` but the actual one.
-        let mut utxos_occurrences: HashMap<OutPoint, Utxo> = HashMap::default();
+        // The UTXOs keep their order, so that the transactions built from them don't depend on the iteration order of a `HashMap`.
+        let mut utxos_indices: HashMap<OutPoint, usize> = HashMap::default();
+        let mut unique_utxos: Vec<Utxo> = vec![];
         utxos.into_iter().for_each(|utxo| {
-            if let Some(utxo_occurrence) = utxos_occurrences.get(&utxo.outpoint) {
-                if utxo.height > utxo_occurrence.height {
-                    utxos_occurrences.insert(utxo.outpoint.clone(), utxo);
+            if let Some(&utxo_index) = utxos_indices.get(&utxo.outpoint) {
+                if utxo.height > unique_utxos[utxo_index].height {
+                    unique_utxos[utxo_index] = utxo;
                 }
             } else {
-                utxos_occurrences.insert(utxo.outpoint.clone(), utxo);
+                utxos_indices.insert(utxo.outpoint.clone(), unique_utxos.len());
+                unique_utxos.push(utxo);
             }
         });
-        utxos_occurrences.values().cloned().collect()
+        unique_utxos
     } else {
         get_utxos_response.utxos
     };
@@ -354,70 +1315,272 @@ fn get_utxos_from_args_common(
     })
 }
 
-pub async fn get_utxos_from_args(utxos_args: UtxosArgs) -> Result<UtxosResult, GetUtxosError> {
-    get_utxos_from_args_common(
-        &utxos_args.address,
-        get_utxos(
-            utxos_args.network,
-            &utxos_args.address,
-            utxos_args.min_confirmations,
-        )
-        .await?,
-        utxos_args.utxos_state,
+/// Returns the UTXOs of the given Bitcoin `address` according to `min_confirmations` and the cycles spent to retrieve them.
+/// The rejected calls are retried according to the retry policy of `utxos_args`.
+pub async fn get_utxos_from_args(
+    utxos_args: UtxosArgs,
+) -> Result<WithCost<UtxosResult>, GetUtxosError> {
+    let get_utxos_response = call_with_retries(
+        &utxos_args.retry_policy,
+        GetUtxosError::get_rejection_code,
+        || {
+            get_utxos(
+                utxos_args.network,
+                &utxos_args.address,
+                utxos_args.min_confirmations,
+                utxos_args.cycles_cost_config,
+                &utxos_args.bitcoin_api_target,
+            )
+        },
     )
+    .await?;
+    Ok(WithCost {
+        result: get_utxos_from_args_common(
+            &utxos_args.address,
+            get_utxos_response.result,
+            utxos_args.utxos_state,
+            utxos_args.allow_zero_value_utxos,
+        )?,
+        cycles_spent: get_utxos_response.cycles_spent,
+        attempts: get_utxos_response.attempts,
+    })
 }
 
 /// Returns the balance of the given Bitcoin `address` according to `min_confirmations`.
+/// If the address has too many UTXOs for `bitcoin_get_utxos` to return them, the balance is retrieved with `bitcoin_get_balance` instead.
 pub async fn get_balance_from_args(utxos_args: UtxosArgs) -> Result<Satoshi, GetUtxosError> {
-    Ok(get_balance_from_utxos(
-        &get_utxos_from_args(utxos_args).await?.utxos,
-    ))
+    let network = utxos_args.network;
+    let address = utxos_args.address.clone();
+    let min_confirmations = utxos_args.min_confirmations;
+    let retry_policy = utxos_args.retry_policy;
+    let bitcoin_api_target = utxos_args.bitcoin_api_target.clone();
+    match get_utxos_from_args(utxos_args).await {
+        Ok(utxos_result) => Ok(get_balance_from_utxos(&utxos_result.result.utxos)),
+        Err(GetUtxosError::ResponseTooLarge { .. }) => Ok(call_with_retries(
+            &retry_policy,
+            GetUtxosError::get_rejection_code,
+            || get_balance(network, &address, min_confirmations, &bitcoin_api_target),
+        )
+        .await?
+        .result),
+        Err(error) => Err(error),
+    }
 }
 
-/// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions.
+/// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions and the cycles spent to retrieve them.
 pub async fn get_current_fees_from_args(
     current_fees_args: CurrentFeesArgs,
-) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject> {
-    get_current_fees(current_fees_args.network).await
+) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject> {
+    get_current_fees_with_retries(
+        current_fees_args.network,
+        current_fees_args.cycles_cost_config,
+        &current_fees_args.retry_policy,
+        &current_fees_args.bitcoin_api_target,
+    )
+    .await
+}
+
+/// Returns the tip height and the block headers of `block_headers_args` and the cycles spent to retrieve them.
+/// The rejected calls are retried according to the retry policy of `block_headers_args`.
+pub async fn get_block_headers_from_args(
+    block_headers_args: BlockHeadersArgs,
+) -> Result<WithCost<GetBlockHeadersResponse>, ManagementCanisterReject> {
+    call_with_retries(
+        &block_headers_args.retry_policy,
+        |ManagementCanisterReject(rejection_code, _): &ManagementCanisterReject| {
+            Some(*rejection_code)
+        },
+        || {
+            get_block_headers(
+                block_headers_args.network,
+                block_headers_args.start_height,
+                block_headers_args.end_height,
+                block_headers_args.payment,
+                &block_headers_args.bitcoin_api_target,
+            )
+        },
+    )
+    .await
 }
 
 /// Returns the fee as a percentile in millisatoshis/byte over the last 10,000 transactions.
 pub async fn get_current_fee_from_args(
     current_fee_args: CurrentFeeArgs,
 ) -> Result<MillisatoshiPerByte, GetCurrentFeeError> {
-    get_current_fee(current_fee_args.fee_request, current_fee_args.network).await
+    let fees = get_current_fees_with_retries(
+        current_fee_args.network,
+        current_fee_args.cycles_cost_config,
+        &current_fee_args.retry_policy,
+        &current_fee_args.bitcoin_api_target,
+    )
+    .await?
+    .result;
+    get_fee_from_percentiles(
+        current_fee_args.fee_request,
+        &fees,
+        current_fee_args.clamp_percentile,
+    )
 }
 
-#[cfg(test)]
+/// Returns economical, standard and priority fee suggestions in millisatoshis/byte, retrieving the fee percentiles only once.
+pub async fn get_fee_suggestions_from_args(
+    fee_suggestions_args: FeeSuggestionsArgs,
+) -> Result<FeeSuggestions, GetCurrentFeeError> {
+    let fees = get_current_fees_with_retries(
+        fee_suggestions_args.network,
+        fee_suggestions_args.cycles_cost_config,
+        &fee_suggestions_args.retry_policy,
+        &fee_suggestions_args.bitcoin_api_target,
+    )
+    .await?
+    .result;
+    get_fee_suggestions_from_percentiles(&fee_suggestions_args, &fees)
+}
+
+/// Returns the fee percentiles and the cycles spent to retrieve them from `bitcoin_api_target`, the rejected calls being retried according to `retry_policy`.
+async fn get_current_fees_with_retries(
+    network: bitcoin::Network,
+    cycles_cost_config: CyclesCostConfig,
+    retry_policy: &RetryPolicy,
+    bitcoin_api_target: &BitcoinApiTarget,
+) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject> {
+    call_with_retries(
+        retry_policy,
+        |ManagementCanisterReject(rejection_code, _): &ManagementCanisterReject| {
+            Some(*rejection_code)
+        },
+        || get_current_fees(network, cycles_cost_config, bitcoin_api_target),
+    )
+    .await
+}
+
+/// Returns the signature of the message of `sign_message_args` proving the control of its address and the cycles spent to obtain it.
+/// P2PKH addresses use the legacy `signmessage` format and P2WPKH addresses the BIP-322 simple format, the signature being checked with `verify_signed_message`.
+pub async fn sign_message_from_args(
+    sign_message_args: SignMessageArgs,
+) -> Result<WithCost<SignedMessage>, SignMessageError> {
+    let cycles_cost_config = sign_message_args.cycles_cost_config;
+    sign_message(
+        sign_message_args,
+        |key_name, derivation_path, message_hash| {
+            sign_with_ecdsa(key_name, derivation_path, message_hash, cycles_cost_config)
+        },
+    )
+    .await
+}
+
+#[cfg(any(test, feature = "mock"))]
 impl BitcoinAgent<ManagementCanisterMock> {
-    /// Simulates UTXOs retrieval from the Bitcoin network during tests.
+    /// Returns the management canister mock of the Bitcoin agent, e.g. to get its tip height.
+    pub fn get_management_canister_mock(&self) -> &ManagementCanisterMock {
+        &self.management_canister
+    }
+
+    /// Returns the management canister mock of the Bitcoin agent mutably, e.g. to fund an address or to mine blocks.
+    pub fn get_management_canister_mock_mut(&mut self) -> &mut ManagementCanisterMock {
+        &mut self.management_canister
+    }
+
+    /// Simulates UTXOs retrieval from the Bitcoin network during tests, all the attached cycles being spent.
+    /// The rejections planned with `reject_next_calls` are retried according to the retry policy of `utxos_args`.
     pub fn get_utxos_from_args_test(
         &self,
         utxos_args: UtxosArgs,
-    ) -> Result<UtxosResult, GetUtxosError> {
-        get_utxos_from_args_common(
+    ) -> Result<WithCost<UtxosResult>, GetUtxosError> {
+        let attempts = self
+            .management_canister
+            .internal_reject_oversized_page(&utxos_args.address, utxos_args.min_confirmations)
+            .and_then(|()| {
+                self.management_canister.internal_reject_with_retries(
+                    ManagementCanisterMethod::BitcoinGetUtxos,
+                    utxos_args.cycles_cost_config.get_utxos,
+                    &utxos_args.retry_policy,
+                )
+            })
+            .map_err(GetUtxosError::from)?;
+        let utxos_pages = self.management_canister.get_utxos_pages(
             &utxos_args.address,
-            self.management_canister
-                .internal_get_utxos(&utxos_args.address, utxos_args.min_confirmations),
-            utxos_args.utxos_state,
-        )
+            utxos_args.min_confirmations,
+            None,
+        );
+        // Like `utxo_management::get_utxos`, each page is paid for.
+        let cycles_spent =
+            utxos_pages.get_pages_count() as u128 * utxos_args.cycles_cost_config.get_utxos;
+        Ok(WithCost {
+            result: get_utxos_from_args_common(
+                &utxos_args.address,
+                utxos_pages.into_response(),
+                utxos_args.utxos_state,
+                utxos_args.allow_zero_value_utxos,
+            )?,
+            cycles_spent,
+            attempts,
+        })
     }
 
     /// Simulates balance retrieval from the Bitcoin network during tests.
+    /// Like `get_balance_from_args`, the balance of an address whose UTXOs exceed the size limit set with `set_max_response_utxos` is retrieved with `bitcoin_get_balance`.
     pub fn get_balance_from_args_test(
         &self,
         utxos_args: UtxosArgs,
     ) -> Result<Satoshi, GetUtxosError> {
-        let utxos = self.get_utxos_from_args_test(utxos_args).unwrap().utxos;
-        Ok(get_balance_from_utxos(&utxos))
+        let address = utxos_args.address.clone();
+        let min_confirmations = utxos_args.min_confirmations;
+        match self.get_utxos_from_args_test(utxos_args) {
+            Ok(utxos_result) => Ok(get_balance_from_utxos(&utxos_result.result.utxos)),
+            Err(GetUtxosError::ResponseTooLarge { .. }) => {
+                self.management_canister.internal_reject(
+                    ManagementCanisterMethod::BitcoinGetBalance,
+                    GET_BALANCE_COST_CYCLES,
+                )?;
+                Ok(get_balance_from_utxos(
+                    &self
+                        .management_canister
+                        .internal_get_utxos(&address, min_confirmations)
+                        .utxos,
+                ))
+            }
+            Err(error) => Err(error),
+        }
     }
 
-    /// Simulates current fees retrieval from the Bitcoin network during tests.
+    /// Simulates current fees retrieval from the Bitcoin network during tests, all the attached cycles being spent.
+    /// The rejections planned with `reject_next_calls` are retried according to the retry policy of `current_fees_args`.
     pub fn get_current_fees_from_args_test(
         &self,
-        _current_fees_args: CurrentFeesArgs,
-    ) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject> {
-        Ok(self.management_canister.internal_get_current_fees())
+        current_fees_args: CurrentFeesArgs,
+    ) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject> {
+        let attempts = self.management_canister.internal_reject_with_retries(
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            current_fees_args.cycles_cost_config.get_fees,
+            &current_fees_args.retry_policy,
+        )?;
+        Ok(WithCost {
+            result: self.management_canister.internal_get_current_fees(),
+            cycles_spent: current_fees_args.cycles_cost_config.get_fees,
+            attempts,
+        })
+    }
+
+    /// Simulates block headers retrieval from the Bitcoin network during tests, all the attached cycles being spent.
+    /// The rejections planned with `reject_next_calls` are retried according to the retry policy of `block_headers_args`.
+    pub fn get_block_headers_from_args_test(
+        &self,
+        block_headers_args: BlockHeadersArgs,
+    ) -> Result<WithCost<GetBlockHeadersResponse>, ManagementCanisterReject> {
+        let attempts = self.management_canister.internal_reject_with_retries(
+            ManagementCanisterMethod::BitcoinGetBlockHeaders,
+            block_headers_args.payment,
+            &block_headers_args.retry_policy,
+        )?;
+        Ok(WithCost {
+            result: self.management_canister.internal_get_block_headers(
+                block_headers_args.start_height,
+                block_headers_args.end_height,
+            )?,
+            cycles_spent: block_headers_args.payment,
+            attempts,
+        })
     }
 
     /// Simulates current fee retrieval from the Bitcoin network during tests.
@@ -425,70 +1588,98 @@ impl BitcoinAgent<ManagementCanisterMock> {
         &self,
         current_fee_args: CurrentFeeArgs,
     ) -> Result<MillisatoshiPerByte, GetCurrentFeeError> {
-        let percentile = evaluate_fee_request(current_fee_args.fee_request)?;
-        Ok(self.management_canister.internal_get_current_fees()[percentile])
+        let fees = self
+            .get_current_fees_from_args_test(CurrentFeesArgs {
+                network: current_fee_args.network,
+                cycles_cost_config: current_fee_args.cycles_cost_config,
+                retry_policy: current_fee_args.retry_policy,
+                bitcoin_api_target: current_fee_args.bitcoin_api_target.clone(),
+            })?
+            .result;
+        get_fee_from_percentiles(
+            current_fee_args.fee_request,
+            &fees,
+            current_fee_args.clamp_percentile,
+        )
+    }
+
+    /// Simulates fee suggestions retrieval from the Bitcoin network during tests.
+    pub fn get_fee_suggestions_from_args_test(
+        &self,
+        fee_suggestions_args: FeeSuggestionsArgs,
+    ) -> Result<FeeSuggestions, GetCurrentFeeError> {
+        let fees = self
+            .get_current_fees_from_args_test(CurrentFeesArgs {
+                network: fee_suggestions_args.network,
+                cycles_cost_config: fee_suggestions_args.cycles_cost_config,
+                retry_policy: fee_suggestions_args.retry_policy,
+                bitcoin_api_target: fee_suggestions_args.bitcoin_api_target.clone(),
+            })?
+            .result;
+        get_fee_suggestions_from_percentiles(&fee_suggestions_args, &fees)
     }
 
     /// Simulates initialization parameters retrieval from the management canister during tests.
     pub fn get_initialization_parameters_from_args_test(
         &self,
         initialization_parameters_args: InitializationParametersArgs,
-    ) -> Result<EcdsaPubKey, ManagementCanisterReject> {
-        Ok(
-            if initialization_parameters_args
+    ) -> Result<WithCost<EcdsaPubKey>, ManagementCanisterReject> {
+        Ok(WithCost {
+            result: if initialization_parameters_args
                 .ecdsa_public_key
                 .public_key
                 .is_empty()
             {
-                address_management::tests::get_btc_ecdsa_public_key()
+                canister_mock::get_mock_ecdsa_public_key()
             } else {
                 initialization_parameters_args.ecdsa_public_key
             },
+            cycles_spent: 0,
+            attempts: 1,
+        })
+    }
+
+    /// Simulates signing a message with the management canister during tests, all the attached cycles being spent.
+    pub async fn sign_message_from_args_test(
+        &self,
+        sign_message_args: SignMessageArgs,
+    ) -> Result<WithCost<SignedMessage>, SignMessageError> {
+        let management_canister = &self.management_canister;
+        let cycles_cost_config = sign_message_args.cycles_cost_config;
+        sign_message(
+            sign_message_args,
+            |_key_name, derivation_path: DerivationPath, message_hash: Vec<u8>| async move {
+                management_canister.internal_reject_sign(
+                    ManagementCanisterMethod::SignWithEcdsa,
+                    &derivation_path,
+                    cycles_cost_config.sign_with_ecdsa,
+                )?;
+                Ok::<_, ManagementCanisterReject>(WithCost {
+                    result: management_canister
+                        .internal_sign_with_ecdsa_compact(&derivation_path, &message_hash),
+                    cycles_spent: cycles_cost_config.sign_with_ecdsa,
+                    attempts: 1,
+                })
+            },
         )
+        .await
     }
 
     /// Simulates making a multi_transfer on the Bitcoin network during tests.
     pub async fn multi_transfer_from_args_test(
-        &mut self,
+        &self,
         multi_transfer_args: MultiTransferArgs,
     ) -> Result<MultiTransferResult, MultiTransferError> {
-        // When running `cargo build`, `multi_transfer` doesn't require an additional argument that is `BitcoinAgent<ManagementCanisterMock>`.
-        // This pattern satisfies the compiler for building and testing.
-        #[cfg(not(test))]
-        unreachable!();
-        #[cfg(test)]
-        transaction_management::multi_transfer(multi_transfer_args, self).await
+        transaction_management::multi_transfer(multi_transfer_args, &self.management_canister).await
     }
 }
 
-/// Creates a new instance of the Bitcoin agent using the management canister mock.
 #[cfg(test)]
 pub mod tests {
-    use crate::{
-        address_management::tests::get_btc_ecdsa_public_key, canister_mock::ManagementCanisterMock,
-        AddressType, BitcoinAgent, Network,
-    };
+    pub use crate::canister_mock::new_mock;
+    use crate::{canister_mock::ManagementCanisterMock, AddressType, BitcoinAgent, Network};
     use std::cell::RefCell;
 
-    pub fn new_mock(
-        network: &Network,
-        main_address_type: &AddressType,
-    ) -> BitcoinAgent<ManagementCanisterMock> {
-        let ecdsa_public_key = get_btc_ecdsa_public_key();
-        let mut bitcoin_agent = BitcoinAgent::new(
-            ManagementCanisterMock::new_using_ecdsa_public_key_test(
-                *network,
-                ecdsa_public_key.clone(),
-                *main_address_type,
-            ),
-            main_address_type,
-            0,
-        )
-        .unwrap();
-        bitcoin_agent.initialize(ecdsa_public_key);
-        bitcoin_agent
-    }
-
     // Thread local agent to verify library usage pattern.
     thread_local! {
         pub(crate) static MOCK_AGENT: RefCell<BitcoinAgent<ManagementCanisterMock>> = RefCell::new(new_mock(&Network::Regtest, &AddressType::P2pkh));
@@ -0,0 +1,414 @@
+use crate::{
+    canister_common::DEFAULT_CYCLES_COST_CEILING,
+    fee_cache::FeeCache,
+    logging::{LogSink, NoopLogSink},
+    payout_queue::PayoutQueue,
+    time_source::TimeSource,
+    AddressType, BitcoinAgent, BitcoinApiTarget, BuildError, CyclesCostConfig, FeeSmoothing,
+    ManagementCanister, ManagementCanisterFactory, MillisatoshiPerByte, Network, RetryConfig,
+    RetryPolicy, DEFAULT_FEE_CACHE_MAX_AGE, DEFAULT_RETRY_CONFIG, DEFAULT_RETRY_POLICY,
+    DEFAULT_SIGN_CONCURRENCY, DEFAULT_STANDARD_FEE_PERCENTILE, DEFAULT_TARGET_BLOCKS_PERCENTILES,
+    MIN_CONFIRMATIONS_UPPER_BOUND,
+};
+use std::{cell::RefCell, collections::BTreeMap, sync::Arc};
+
+/// Where the management canister of the Bitcoin agent built by a `BitcoinAgentBuilder` comes from.
+enum ManagementCanisterSource<C> {
+    /// The management canister given to `BitcoinAgentBuilder::new`.
+    Given(C),
+    /// The management canister created by `build` for `network`, `create` being `ManagementCanisterFactory::new`.
+    Created {
+        network: Network,
+        create: fn(Network, Option<String>) -> C,
+    },
+}
+
+/// Builds a Bitcoin agent, its settings being validated at once by `build` instead of by each setter of the Bitcoin agent.
+/// The settings which aren't set keep the defaults of `BitcoinAgent::new`.
+pub struct BitcoinAgentBuilder<C: ManagementCanister> {
+    management_canister_source: ManagementCanisterSource<C>,
+    ecdsa_key_name: Option<String>,
+    main_address_type: AddressType,
+    min_confirmations: u32,
+    fee_cache_max_age: u32,
+    fee_cache_ttl: Option<u64>,
+    fee_smoothing: Option<FeeSmoothing>,
+    standard_fee_percentile: u8,
+    target_blocks_percentiles: BTreeMap<u8, u8>,
+    max_fee_per_byte: Option<MillisatoshiPerByte>,
+    sign_retry_config: RetryConfig,
+    sign_concurrency: u32,
+    cycles_cost_config: Option<CyclesCostConfig>,
+    cycles_cost_ceiling: u128,
+    retry_policy: RetryPolicy,
+    allow_zero_value_utxos: bool,
+    bitcoin_api_target: Option<BitcoinApiTarget>,
+    log_sink: Option<Arc<dyn LogSink>>,
+    time_source: Option<Arc<dyn TimeSource>>,
+}
+
+impl<C: ManagementCanister> BitcoinAgentBuilder<C> {
+    /// Creates a builder of a Bitcoin agent using the given management canister, whose main address type is P2PKH and whose UTXOs don't require any confirmation.
+    pub fn new(management_canister: C) -> Self {
+        Self::from_source(ManagementCanisterSource::Given(management_canister))
+    }
+
+    /// Creates a builder of a Bitcoin agent whose management canister is created for `network` by `build`, e.g. to set its ECDSA key name with `ecdsa_key_name`.
+    pub fn for_network(network: Network) -> Self
+    where
+        C: ManagementCanisterFactory,
+    {
+        Self::from_source(ManagementCanisterSource::Created {
+            network,
+            create: C::new,
+        })
+    }
+
+    fn from_source(management_canister_source: ManagementCanisterSource<C>) -> Self {
+        Self {
+            management_canister_source,
+            ecdsa_key_name: None,
+            main_address_type: AddressType::P2pkh,
+            min_confirmations: 0,
+            fee_cache_max_age: DEFAULT_FEE_CACHE_MAX_AGE,
+            fee_cache_ttl: None,
+            fee_smoothing: None,
+            standard_fee_percentile: DEFAULT_STANDARD_FEE_PERCENTILE,
+            target_blocks_percentiles: BTreeMap::from(DEFAULT_TARGET_BLOCKS_PERCENTILES),
+            max_fee_per_byte: None,
+            sign_retry_config: DEFAULT_RETRY_CONFIG,
+            sign_concurrency: DEFAULT_SIGN_CONCURRENCY,
+            cycles_cost_config: None,
+            cycles_cost_ceiling: DEFAULT_CYCLES_COST_CEILING,
+            retry_policy: DEFAULT_RETRY_POLICY,
+            allow_zero_value_utxos: false,
+            bitcoin_api_target: None,
+            log_sink: None,
+            time_source: None,
+        }
+    }
+
+    /// Sets the name of the threshold ECDSA key of the management canister created by `build`, the one associated with the network by default.
+    /// It can't be set if the management canister is given to `new`, as its key name is already set.
+    pub fn ecdsa_key_name(mut self, ecdsa_key_name: &str) -> Self {
+        self.ecdsa_key_name = Some(ecdsa_key_name.to_string());
+        self
+    }
+
+    /// Sets the type of the main address, like `BitcoinAgent::new`.
+    pub fn main_address_type(mut self, main_address_type: AddressType) -> Self {
+        self.main_address_type = main_address_type;
+        self
+    }
+
+    /// Sets the minimum number of confirmations of the UTXOs of the main address and of the addresses added without parameters, like `BitcoinAgent::new`.
+    pub fn min_confirmations(mut self, min_confirmations: u32) -> Self {
+        self.min_confirmations = min_confirmations;
+        self
+    }
+
+    /// Sets the number of blocks the tip may advance before the cached fees are stale, like `BitcoinAgent::set_fee_cache_max_age`.
+    pub fn fee_cache_max_age(mut self, fee_cache_max_age: u32) -> Self {
+        self.fee_cache_max_age = fee_cache_max_age;
+        self
+    }
+
+    /// Sets the nanoseconds after which the cached fees are stale whatever the tip height, like `BitcoinAgent::set_fee_cache_ttl`.
+    pub fn fee_cache_ttl(mut self, fee_cache_ttl: Option<u64>) -> Self {
+        self.fee_cache_ttl = fee_cache_ttl;
+        self
+    }
+
+    /// Sets the fee smoothing, like `BitcoinAgent::set_fee_smoothing`.
+    pub fn fee_smoothing(mut self, fee_smoothing: Option<FeeSmoothing>) -> Self {
+        self.fee_smoothing = fee_smoothing;
+        self
+    }
+
+    /// Sets the percentile used for `Fee::Standard` and `FeeRequest::Standard`, like `BitcoinAgent::set_standard_fee_percentile`.
+    pub fn standard_fee_percentile(mut self, standard_fee_percentile: u8) -> Self {
+        self.standard_fee_percentile = standard_fee_percentile;
+        self
+    }
+
+    /// Sets the percentiles associated with confirming a transaction within a given number of blocks, like `BitcoinAgent::set_target_blocks_percentiles`.
+    pub fn target_blocks_percentiles(
+        mut self,
+        target_blocks_percentiles: BTreeMap<u8, u8>,
+    ) -> Self {
+        self.target_blocks_percentiles = target_blocks_percentiles;
+        self
+    }
+
+    /// Sets the maximum fee in millisatoshis/byte of the transfers using percentile fees, like `BitcoinAgent::set_max_fee_per_byte`.
+    pub fn max_fee_per_byte(mut self, max_fee_per_byte: Option<MillisatoshiPerByte>) -> Self {
+        self.max_fee_per_byte = max_fee_per_byte;
+        self
+    }
+
+    /// Sets how the signatures of the inputs of the transfers are retried, like `BitcoinAgent::set_sign_retry_config`.
+    pub fn sign_retry_config(mut self, sign_retry_config: RetryConfig) -> Self {
+        self.sign_retry_config = sign_retry_config;
+        self
+    }
+
+    /// Sets the maximum number of inputs of the transfers signed concurrently, like `BitcoinAgent::set_sign_concurrency`.
+    pub fn sign_concurrency(mut self, sign_concurrency: u32) -> Self {
+        self.sign_concurrency = sign_concurrency;
+        self
+    }
+
+    /// Sets the cycles attached to the paid calls to the management canister, like `BitcoinAgent::set_cycles_cost_config`.
+    pub fn cycles_cost_config(mut self, cycles_cost_config: CyclesCostConfig) -> Self {
+        self.cycles_cost_config = Some(cycles_cost_config);
+        self
+    }
+
+    /// Sets the maximum number of cycles accepted for any of the costs set with `cycles_cost_config`, like `BitcoinAgent::set_cycles_cost_ceiling`.
+    pub fn cycles_cost_ceiling(mut self, cycles_cost_ceiling: u128) -> Self {
+        self.cycles_cost_ceiling = cycles_cost_ceiling;
+        self
+    }
+
+    /// Sets how the calls made with the arguments structures are retried, like `BitcoinAgent::set_retry_policy`.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets whether the UTXOs of zero value returned by `bitcoin_get_utxos` are accepted, like `BitcoinAgent::set_allow_zero_value_utxos`.
+    pub fn allow_zero_value_utxos(mut self, allow_zero_value_utxos: bool) -> Self {
+        self.allow_zero_value_utxos = allow_zero_value_utxos;
+        self
+    }
+
+    /// Sets the canister serving the calls to the Bitcoin API, like `BitcoinAgent::set_bitcoin_api_target`.
+    pub fn bitcoin_api_target(mut self, bitcoin_api_target: BitcoinApiTarget) -> Self {
+        self.bitcoin_api_target = Some(bitcoin_api_target);
+        self
+    }
+
+    /// Sets the sink receiving the log messages of the Bitcoin agent, like `BitcoinAgent::set_log_sink`.
+    pub fn log_sink(mut self, log_sink: Arc<dyn LogSink>) -> Self {
+        self.log_sink = Some(log_sink);
+        self
+    }
+
+    /// Sets the time source of the cached fees and of the transfers, like `BitcoinAgent::set_time_source`.
+    pub fn time_source(mut self, time_source: Arc<dyn TimeSource>) -> Self {
+        self.time_source = Some(time_source);
+        self
+    }
+
+    /// Checks the settings of the builder, before creating any management canister.
+    fn validate(&self) -> Result<(), BuildError> {
+        if self.min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
+            return Err(BuildError::MinConfirmationsTooHigh);
+        }
+        if let Some(ecdsa_key_name) = &self.ecdsa_key_name {
+            if let ManagementCanisterSource::Given(_) = self.management_canister_source {
+                return Err(BuildError::ConflictingOptions(String::from(
+                    "the ECDSA key name can't be set for a given management canister",
+                )));
+            }
+            if !is_valid_ecdsa_key_name(ecdsa_key_name) {
+                return Err(BuildError::InvalidEcdsaKeyName(ecdsa_key_name.clone()));
+            }
+        }
+        if self.standard_fee_percentile > 100 {
+            return Err(BuildError::StandardFeePercentileTooHigh);
+        }
+        if self
+            .target_blocks_percentiles
+            .values()
+            .any(|percentile| *percentile > 100)
+        {
+            return Err(BuildError::InvalidPercentile);
+        }
+        if let Some(cycles_cost_config) = &self.cycles_cost_config {
+            cycles_cost_config
+                .validate(self.cycles_cost_ceiling)
+                .map_err(BuildError::InvalidCyclesCostConfig)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the Bitcoin agent configured with the settings of the builder, or the first invalid setting.
+    pub fn build(self) -> Result<BitcoinAgent<C>, BuildError> {
+        self.validate()?;
+        let mut management_canister = match self.management_canister_source {
+            ManagementCanisterSource::Given(management_canister) => management_canister,
+            ManagementCanisterSource::Created { network, create } => {
+                create(network, self.ecdsa_key_name)
+            }
+        };
+        if let Some(cycles_cost_config) = self.cycles_cost_config {
+            management_canister.set_cycles_cost_config(cycles_cost_config);
+        }
+        if let Some(bitcoin_api_target) = self.bitcoin_api_target {
+            management_canister.set_bitcoin_api_target(bitcoin_api_target);
+        }
+        let time_source = self
+            .time_source
+            .unwrap_or_else(|| management_canister.get_time_source());
+        let mut fee_cache = FeeCache::new(self.fee_cache_max_age);
+        fee_cache.ttl = self.fee_cache_ttl;
+        fee_cache.set_fee_smoothing(self.fee_smoothing);
+        Ok(BitcoinAgent {
+            management_canister,
+            main_address_type: self.main_address_type,
+            ecdsa_pub_key_addresses: BTreeMap::default(),
+            utxos_state_addresses: BTreeMap::default(),
+            min_confirmations: self.min_confirmations,
+            payout_queue: PayoutQueue::default(),
+            fee_cache,
+            standard_fee_percentile: self.standard_fee_percentile,
+            target_blocks_percentiles: self.target_blocks_percentiles,
+            max_fee_per_byte: self.max_fee_per_byte,
+            sign_retry_config: self.sign_retry_config,
+            sign_concurrency: self.sign_concurrency,
+            key_rotations: vec![],
+            rate_limiter: RefCell::default(),
+            poller: RefCell::default(),
+            retry_policy: self.retry_policy,
+            allow_zero_value_utxos: self.allow_zero_value_utxos,
+            log_sink: self.log_sink.unwrap_or_else(|| Arc::new(NoopLogSink)),
+            time_source,
+            cycles_cost_ceiling: self.cycles_cost_ceiling,
+            #[cfg(feature = "stable-memory")]
+            stable_storage: None,
+        })
+    }
+}
+
+/// Returns true if `ecdsa_key_name` can name a threshold ECDSA key, e.g. `key_1`, `test_key_1` or `dfx_test_key`.
+fn is_valid_ecdsa_key_name(ecdsa_key_name: &str) -> bool {
+    !ecdsa_key_name.is_empty()
+        && ecdsa_key_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canister_mock::ManagementCanisterMock;
+
+    /// Check that the invalid settings are reported by `build`.
+    #[test]
+    fn check_build_errors() {
+        let builder =
+            || BitcoinAgentBuilder::<ManagementCanisterMock>::for_network(Network::Regtest);
+        assert!(matches!(
+            builder()
+                .min_confirmations(MIN_CONFIRMATIONS_UPPER_BOUND + 1)
+                .build(),
+            Err(BuildError::MinConfirmationsTooHigh)
+        ));
+        for ecdsa_key_name in ["", "key 1", "key_1\n", "clé"] {
+            assert!(matches!(
+                builder().ecdsa_key_name(ecdsa_key_name).build(),
+                Err(BuildError::InvalidEcdsaKeyName(invalid_ecdsa_key_name)) if invalid_ecdsa_key_name == ecdsa_key_name
+            ));
+        }
+        assert!(matches!(
+            builder().standard_fee_percentile(101).build(),
+            Err(BuildError::StandardFeePercentileTooHigh)
+        ));
+        assert!(matches!(
+            builder()
+                .target_blocks_percentiles(BTreeMap::from([(1, 90), (3, 101)]))
+                .build(),
+            Err(BuildError::InvalidPercentile)
+        ));
+        let cycles_cost_config = CyclesCostConfig {
+            send_tx: 2_000_000_000,
+            ..CyclesCostConfig::for_network(Network::Regtest.into())
+        };
+        assert!(matches!(
+            builder()
+                .cycles_cost_config(cycles_cost_config)
+                .cycles_cost_ceiling(1_000_000_000)
+                .build(),
+            Err(BuildError::InvalidCyclesCostConfig(
+                crate::InvalidCyclesCostConfig::AboveCeiling { .. }
+            ))
+        ));
+        assert!(matches!(
+            BitcoinAgentBuilder::new(ManagementCanisterMock::new(Network::Regtest, None))
+                .ecdsa_key_name("key_1")
+                .build(),
+            Err(BuildError::ConflictingOptions(_))
+        ));
+        // The old constructor reports the minimum number of confirmations the same way.
+        assert!(BitcoinAgent::new(
+            ManagementCanisterMock::new(Network::Regtest, None),
+            &AddressType::P2pkh,
+            MIN_CONFIRMATIONS_UPPER_BOUND + 1
+        )
+        .is_err());
+    }
+
+    /// Check that the settings of the builder reach the state of the built Bitcoin agent.
+    #[test]
+    fn check_build_settings() {
+        let cycles_cost_config = CyclesCostConfig {
+            send_tx: 2_000_000_000,
+            ..CyclesCostConfig::for_network(Network::Testnet.into())
+        };
+        let bitcoin_agent =
+            BitcoinAgentBuilder::<ManagementCanisterMock>::for_network(Network::Testnet)
+                .ecdsa_key_name("key_1")
+                .main_address_type(AddressType::P2wpkh)
+                .min_confirmations(6)
+                .fee_cache_max_age(3)
+                .fee_cache_ttl(Some(60_000_000_000))
+                .fee_smoothing(Some(FeeSmoothing { window: 4 }))
+                .standard_fee_percentile(75)
+                .target_blocks_percentiles(BTreeMap::from([(2, 80)]))
+                .max_fee_per_byte(Some(100_000))
+                .sign_retry_config(RetryConfig { max_attempts: 5 })
+                .sign_concurrency(4)
+                .cycles_cost_config(cycles_cost_config)
+                .allow_zero_value_utxos(true)
+                .build()
+                .unwrap();
+
+        assert_eq!(bitcoin_agent.network(), Network::Testnet);
+        assert_eq!(bitcoin_agent.main_address_type(), AddressType::P2wpkh);
+        assert_eq!(bitcoin_agent.min_confirmations(), 6);
+        let bitcoin_agent_state = bitcoin_agent.get_state();
+        assert_eq!(bitcoin_agent_state.ecdsa_key_name, "key_1");
+        assert_eq!(bitcoin_agent_state.fee_cache_max_age, 3);
+        assert_eq!(
+            bitcoin_agent_state.fee_smoothing,
+            Some(FeeSmoothing { window: 4 })
+        );
+        assert_eq!(bitcoin_agent_state.standard_fee_percentile, 75);
+        assert_eq!(
+            bitcoin_agent_state.target_blocks_percentiles,
+            BTreeMap::from([(2, 80)])
+        );
+        assert_eq!(bitcoin_agent_state.max_fee_per_byte, Some(100_000));
+        assert_eq!(
+            bitcoin_agent_state.sign_retry_config,
+            RetryConfig { max_attempts: 5 }
+        );
+        assert_eq!(bitcoin_agent_state.sign_concurrency, 4);
+        assert_eq!(bitcoin_agent_state.cycles_cost_config, cycles_cost_config);
+        // The settings which aren't saved in the state are set as well.
+        assert_eq!(bitcoin_agent.get_fee_cache_ttl(), Some(60_000_000_000));
+        assert!(bitcoin_agent.get_allow_zero_value_utxos());
+        assert_eq!(bitcoin_agent.get_standard_fee_percentile(), 75);
+
+        // The Bitcoin agent built from a given management canister keeps its key name.
+        let bitcoin_agent = BitcoinAgentBuilder::new(ManagementCanisterMock::new(
+            Network::Regtest,
+            Some(String::from("dfx_test_key")),
+        ))
+        .build()
+        .unwrap();
+        assert_eq!(bitcoin_agent.get_state().ecdsa_key_name, "dfx_test_key");
+        assert_eq!(bitcoin_agent.min_confirmations(), 0);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{
+    AgentRegistryError, BitcoinAgent, BitcoinAgentState, ManagementCanister,
+    ManagementCanisterFactory, Network,
+};
+use bitcoin::Address;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Owns Bitcoin agents by name, e.g. one per business unit, enforcing that no address is managed by multiple of them.
+/// Their states are saved with `get_states` and restored with `from_states` at once, e.g. in `pre_upgrade` and `post_upgrade`.
+pub struct AgentRegistry<C: ManagementCanister> {
+    bitcoin_agents: BTreeMap<String, BitcoinAgent<C>>,
+}
+
+impl<C: ManagementCanister> Default for AgentRegistry<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: ManagementCanister> AgentRegistry<C> {
+    /// Creates a registry without any Bitcoin agent.
+    pub fn new() -> Self {
+        Self {
+            bitcoin_agents: BTreeMap::default(),
+        }
+    }
+
+    /// Inserts `bitcoin_agent` under `name`, returning the Bitcoin agent previously inserted under this name if any.
+    /// Fails if one of the addresses of `bitcoin_agent`, including the watch-only ones, is managed by another Bitcoin agent of the registry.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        bitcoin_agent: BitcoinAgent<C>,
+    ) -> Result<Option<BitcoinAgent<C>>, AgentRegistryError> {
+        self.check_addresses(name, bitcoin_agent.utxos_state_addresses.keys())?;
+        Ok(self.bitcoin_agents.insert(name.to_string(), bitcoin_agent))
+    }
+
+    /// Removes the Bitcoin agent inserted under `name` from the registry.
+    pub fn remove(&mut self, name: &str) -> Option<BitcoinAgent<C>> {
+        self.bitcoin_agents.remove(name)
+    }
+
+    /// Returns the names of the Bitcoin agents of the registry.
+    pub fn names(&self) -> Vec<&String> {
+        self.bitcoin_agents.keys().collect()
+    }
+
+    /// Returns the result of `f` called with the Bitcoin agent named `name`.
+    /// As `f` isn't async, the Bitcoin agent can't be borrowed across an `await`, like with the `get_current_fee!` macro.
+    pub fn with_agent<R>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&BitcoinAgent<C>) -> R,
+    ) -> Result<R, AgentRegistryError> {
+        self.bitcoin_agents
+            .get(name)
+            .map(f)
+            .ok_or_else(|| AgentRegistryError::UnknownAgent(name.to_string()))
+    }
+
+    /// Returns the result of `f` called with the Bitcoin agent named `name`, which `f` may modify.
+    /// Fails if `f` added an address managed by another Bitcoin agent of the registry, in which case the added addresses are removed again.
+    pub fn with_agent_mut<R>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut BitcoinAgent<C>) -> R,
+    ) -> Result<R, AgentRegistryError> {
+        let bitcoin_agent = self
+            .bitcoin_agents
+            .get_mut(name)
+            .ok_or_else(|| AgentRegistryError::UnknownAgent(name.to_string()))?;
+        let previous_addresses: BTreeSet<Address> = bitcoin_agent
+            .utxos_state_addresses
+            .keys()
+            .cloned()
+            .collect();
+        let result = f(bitcoin_agent);
+        let added_addresses: Vec<Address> = bitcoin_agent
+            .utxos_state_addresses
+            .keys()
+            .filter(|address| !previous_addresses.contains(address))
+            .cloned()
+            .collect();
+        if let Err(error) = self.check_addresses(name, added_addresses.iter()) {
+            let bitcoin_agent = self.bitcoin_agents.get_mut(name).unwrap();
+            for address in &added_addresses {
+                bitcoin_agent.remove_address(address);
+            }
+            return Err(error);
+        }
+        Ok(result)
+    }
+
+    /// Returns the states of the Bitcoin agents of the registry by name.
+    pub fn get_states(&self) -> BTreeMap<String, BitcoinAgentState> {
+        self.bitcoin_agents
+            .iter()
+            .map(|(name, bitcoin_agent)| (name.clone(), bitcoin_agent.get_state()))
+            .collect()
+    }
+
+    /// Returns the registry of the Bitcoin agents associated with the given states by name.
+    /// Fails if a state can't be restored, e.g. as it's the one of another network than `expected_network`, or if an address is managed by multiple Bitcoin agents.
+    pub fn from_states(
+        bitcoin_agent_states: BTreeMap<String, BitcoinAgentState>,
+        expected_network: Network,
+    ) -> Result<Self, AgentRegistryError>
+    where
+        C: ManagementCanisterFactory,
+    {
+        let mut agent_registry = Self::new();
+        for (name, bitcoin_agent_state) in bitcoin_agent_states {
+            let bitcoin_agent = BitcoinAgent::from_state(bitcoin_agent_state, expected_network)
+                .map_err(|error| AgentRegistryError::InvalidState {
+                    agent: name.clone(),
+                    error,
+                })?;
+            agent_registry.insert(&name, bitcoin_agent)?;
+        }
+        Ok(agent_registry)
+    }
+
+    /// Checks that none of `addresses` is managed by a Bitcoin agent of the registry other than the one named `name`.
+    fn check_addresses<'a>(
+        &self,
+        name: &str,
+        addresses: impl Iterator<Item = &'a Address>,
+    ) -> Result<(), AgentRegistryError> {
+        let other_bitcoin_agents: Vec<(&String, &BitcoinAgent<C>)> = self
+            .bitcoin_agents
+            .iter()
+            .filter(|(other_name, _)| *other_name != name)
+            .collect();
+        for address in addresses {
+            if let Some((other_name, _)) = other_bitcoin_agents.iter().find(|(_, bitcoin_agent)| {
+                bitcoin_agent.utxos_state_addresses.contains_key(address)
+            }) {
+                return Err(AgentRegistryError::DuplicateAddress {
+                    address: address.to_string(),
+                    agent: other_name.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agent, canister_mock::ManagementCanisterMock, AddressType, StateRestoreError};
+
+    /// Returns a registry of two Bitcoin agents whose main addresses are of different types.
+    fn get_agent_registry() -> AgentRegistry<ManagementCanisterMock> {
+        let mut agent_registry = AgentRegistry::new();
+        agent_registry
+            .insert(
+                "treasury",
+                agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh),
+            )
+            .unwrap();
+        agent_registry
+            .insert(
+                "payroll",
+                agent::tests::new_mock(&Network::Regtest, &AddressType::P2wpkh),
+            )
+            .unwrap();
+        agent_registry
+    }
+
+    /// Check that an address can't be managed by multiple Bitcoin agents of a registry.
+    #[test]
+    fn check_duplicate_address_rejection() {
+        let mut agent_registry = get_agent_registry();
+        let address = agent_registry
+            .with_agent_mut("treasury", |bitcoin_agent| {
+                bitcoin_agent.add_address(&[vec![1]]).unwrap()
+            })
+            .unwrap();
+
+        // A Bitcoin agent managing the same main address is rejected.
+        let duplicate_address_error = AgentRegistryError::DuplicateAddress {
+            address: agent_registry
+                .with_agent("treasury", |bitcoin_agent| bitcoin_agent.get_main_address())
+                .unwrap()
+                .to_string(),
+            agent: String::from("treasury"),
+        };
+        assert_eq!(
+            agent_registry
+                .insert(
+                    "marketing",
+                    agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh),
+                )
+                .err()
+                .unwrap(),
+            duplicate_address_error
+        );
+        // A removed Bitcoin agent can be inserted again.
+        let treasury = agent_registry.remove("treasury").unwrap();
+        let treasury_state = treasury.get_state();
+        assert!(agent_registry
+            .insert("treasury", treasury)
+            .unwrap()
+            .is_none());
+
+        // An address added by another Bitcoin agent is removed again.
+        assert_eq!(
+            agent_registry
+                .with_agent_mut("payroll", |bitcoin_agent| {
+                    bitcoin_agent
+                        .add_address_with_parameters(&[vec![1]], &AddressType::P2pkh, 0)
+                        .unwrap()
+                })
+                .err()
+                .unwrap(),
+            AgentRegistryError::DuplicateAddress {
+                address: address.to_string(),
+                agent: String::from("treasury"),
+            }
+        );
+        assert_eq!(
+            agent_registry
+                .with_agent("payroll", |bitcoin_agent| bitcoin_agent
+                    .list_addresses()
+                    .len())
+                .unwrap(),
+            1
+        );
+
+        // States whose Bitcoin agents manage the same address aren't restored.
+        let mut bitcoin_agent_states = agent_registry.get_states();
+        bitcoin_agent_states.insert(String::from("marketing"), treasury_state);
+        assert!(matches!(
+            AgentRegistry::<ManagementCanisterMock>::from_states(
+                bitcoin_agent_states,
+                Network::Regtest
+            ),
+            Err(AgentRegistryError::DuplicateAddress { .. })
+        ));
+        assert_eq!(
+            agent_registry
+                .with_agent("marketing", |_| ())
+                .err()
+                .unwrap(),
+            AgentRegistryError::UnknownAgent(String::from("marketing"))
+        );
+    }
+
+    /// Check that the registry is restored from the states of its Bitcoin agents.
+    #[test]
+    fn check_registry_round_trip() {
+        let mut agent_registry = get_agent_registry();
+        agent_registry
+            .with_agent_mut("payroll", |bitcoin_agent| {
+                bitcoin_agent.add_address(&[vec![2]]).unwrap();
+                bitcoin_agent.queue_payout(&bitcoin_agent.get_main_address(), 10_000);
+            })
+            .unwrap();
+        let bitcoin_agent_states = agent_registry.get_states();
+
+        let restored_agent_registry = AgentRegistry::<ManagementCanisterMock>::from_states(
+            bitcoin_agent_states.clone(),
+            Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(restored_agent_registry.names(), vec!["payroll", "treasury"]);
+        assert_eq!(restored_agent_registry.get_states(), bitcoin_agent_states);
+        assert_eq!(
+            restored_agent_registry
+                .with_agent("payroll", |bitcoin_agent| bitcoin_agent.queued_total())
+                .unwrap(),
+            10_000
+        );
+
+        // The states aren't restored on another network than theirs.
+        assert!(matches!(
+            AgentRegistry::<ManagementCanisterMock>::from_states(
+                bitcoin_agent_states,
+                Network::Testnet
+            ),
+            Err(AgentRegistryError::InvalidState {
+                error: StateRestoreError::NetworkMismatch { .. },
+                ..
+            })
+        ));
+    }
+}
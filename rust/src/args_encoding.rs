@@ -0,0 +1,303 @@
+//! Candid encoding of the arguments structures and of their results, e.g. for a coordinator canister to build the arguments with its Bitcoin agent and to forward them to a worker canister calling the `*_from_args` functions.
+//! The structures holding addresses are encoded through layouts describing them with `AddressUsingPrimitives`, and fail to be decoded with a `BitcoinAddressError` if an address can't be parsed.
+
+use crate::{
+    types::{
+        from_bitcoin_network_to_types_network, from_types_network_to_bitcoin_network,
+        BitcoinAddressError,
+    },
+    upgrade_management::{get_address, get_address_using_primitives},
+    AddressUsingPrimitives, BitcoinApiTarget, CurrentFeesArgs, CyclesCostConfig, EcdsaPubKey, Fee,
+    IcTimeSource, MillisatoshiPerByte, MultiTransferArgs, Network, NoopLogSink, OutPoint, QueueId,
+    RetryConfig, RetryPolicy, Satoshi, ScriptPayout, SelectionScope, Utxo, UtxosArgs, UtxosResult,
+    UtxosState, DEFAULT_RETRY_POLICY,
+};
+use bitcoin::Address;
+use candid::{
+    types::{Serializer as CandidSerializer, Type},
+    CandidType, Deserialize,
+};
+use serde::{de, Deserializer};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Implements `CandidType` and `Deserialize` for `$args` through `$layout`, which `$args` is converted to and parsed from, failing to be decoded if it can't be parsed.
+macro_rules! impl_candid_through_layout {
+    ($args:ty, $layout:ty) => {
+        impl CandidType for $args {
+            fn _ty() -> Type {
+                <$layout>::ty()
+            }
+
+            fn idl_serialize<S: CandidSerializer>(&self, serializer: S) -> Result<(), S::Error> {
+                <$layout>::from(self).idl_serialize(serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $args {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                <$args>::try_from(<$layout>::deserialize(deserializer)?).map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+/// Returns the retry policy retrying the transient rejections up to `max_attempts` attempts.
+/// As functions can't be encoded, only the maximum number of attempts of a retry policy is, its `retry_on` being `is_transient_rejection` once decoded.
+fn get_retry_policy(retry_config: RetryConfig) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: retry_config.max_attempts,
+        ..DEFAULT_RETRY_POLICY
+    }
+}
+
+/// Returns the retry configuration encoding `retry_policy`, see `get_retry_policy`.
+fn get_retry_config(retry_policy: &RetryPolicy) -> RetryConfig {
+    RetryConfig {
+        max_attempts: retry_policy.max_attempts,
+    }
+}
+
+/// Returns the map keyed by the `AddressUsingPrimitives` of the addresses of `map`.
+fn get_map_using_primitives<T: Clone>(
+    map: &BTreeMap<Address, T>,
+) -> BTreeMap<AddressUsingPrimitives, T> {
+    map.iter()
+        .map(|(address, value)| (get_address_using_primitives(address), value.clone()))
+        .collect()
+}
+
+/// Returns the map keyed by the addresses described by the keys of `map`.
+fn get_map_using_addresses<T>(
+    map: BTreeMap<AddressUsingPrimitives, T>,
+) -> Result<BTreeMap<Address, T>, BitcoinAddressError> {
+    map.into_iter()
+        .map(|(address_using_primitives, value)| {
+            get_address(address_using_primitives)
+                .map(|address| (address, value))
+                .map_err(BitcoinAddressError::from)
+        })
+        .collect()
+}
+
+/// The Candid layout of `UtxosArgs`.
+#[derive(CandidType, Deserialize)]
+struct UtxosArgsLayout {
+    network: Network,
+    address: AddressUsingPrimitives,
+    min_confirmations: u32,
+    utxos_state: UtxosState,
+    cycles_cost_config: CyclesCostConfig,
+    retry_config: RetryConfig,
+    bitcoin_api_target: BitcoinApiTarget,
+    allow_zero_value_utxos: bool,
+}
+
+impl From<&UtxosArgs> for UtxosArgsLayout {
+    fn from(utxos_args: &UtxosArgs) -> Self {
+        UtxosArgsLayout {
+            network: from_bitcoin_network_to_types_network(utxos_args.network),
+            address: get_address_using_primitives(&utxos_args.address),
+            min_confirmations: utxos_args.min_confirmations,
+            utxos_state: utxos_args.utxos_state.clone(),
+            cycles_cost_config: utxos_args.cycles_cost_config,
+            retry_config: get_retry_config(&utxos_args.retry_policy),
+            bitcoin_api_target: utxos_args.bitcoin_api_target.clone(),
+            allow_zero_value_utxos: utxos_args.allow_zero_value_utxos,
+        }
+    }
+}
+
+impl TryFrom<UtxosArgsLayout> for UtxosArgs {
+    type Error = BitcoinAddressError;
+
+    fn try_from(layout: UtxosArgsLayout) -> Result<Self, Self::Error> {
+        Ok(UtxosArgs {
+            network: from_types_network_to_bitcoin_network(layout.network),
+            address: get_address(layout.address)?,
+            min_confirmations: layout.min_confirmations,
+            utxos_state: layout.utxos_state,
+            cycles_cost_config: layout.cycles_cost_config,
+            retry_policy: get_retry_policy(layout.retry_config),
+            bitcoin_api_target: layout.bitcoin_api_target,
+            allow_zero_value_utxos: layout.allow_zero_value_utxos,
+        })
+    }
+}
+
+impl_candid_through_layout!(UtxosArgs, UtxosArgsLayout);
+
+/// The Candid layout of `UtxosResult`.
+#[derive(CandidType, Deserialize)]
+struct UtxosResultLayout {
+    address: AddressUsingPrimitives,
+    utxos: Vec<Utxo>,
+    tip_height: u32,
+}
+
+impl From<&UtxosResult> for UtxosResultLayout {
+    fn from(utxos_result: &UtxosResult) -> Self {
+        UtxosResultLayout {
+            address: get_address_using_primitives(&utxos_result.address),
+            utxos: utxos_result.utxos.clone(),
+            tip_height: utxos_result.tip_height,
+        }
+    }
+}
+
+impl TryFrom<UtxosResultLayout> for UtxosResult {
+    type Error = BitcoinAddressError;
+
+    fn try_from(layout: UtxosResultLayout) -> Result<Self, Self::Error> {
+        Ok(UtxosResult {
+            address: get_address(layout.address)?,
+            utxos: layout.utxos,
+            tip_height: layout.tip_height,
+        })
+    }
+}
+
+impl_candid_through_layout!(UtxosResult, UtxosResultLayout);
+
+/// The Candid layout of `CurrentFeesArgs`.
+#[derive(CandidType, Deserialize)]
+struct CurrentFeesArgsLayout {
+    network: Network,
+    cycles_cost_config: CyclesCostConfig,
+    retry_config: RetryConfig,
+    bitcoin_api_target: BitcoinApiTarget,
+}
+
+impl From<&CurrentFeesArgs> for CurrentFeesArgsLayout {
+    fn from(current_fees_args: &CurrentFeesArgs) -> Self {
+        CurrentFeesArgsLayout {
+            network: from_bitcoin_network_to_types_network(current_fees_args.network),
+            cycles_cost_config: current_fees_args.cycles_cost_config,
+            retry_config: get_retry_config(&current_fees_args.retry_policy),
+            bitcoin_api_target: current_fees_args.bitcoin_api_target.clone(),
+        }
+    }
+}
+
+impl From<CurrentFeesArgsLayout> for CurrentFeesArgs {
+    fn from(layout: CurrentFeesArgsLayout) -> Self {
+        CurrentFeesArgs {
+            network: from_types_network_to_bitcoin_network(layout.network),
+            cycles_cost_config: layout.cycles_cost_config,
+            retry_policy: get_retry_policy(layout.retry_config),
+            bitcoin_api_target: layout.bitcoin_api_target,
+        }
+    }
+}
+
+impl_candid_through_layout!(CurrentFeesArgs, CurrentFeesArgsLayout);
+
+/// The Candid layout of `MultiTransferArgs`.
+/// The log sink and the time source aren't encoded, the decoded arguments using `NoopLogSink` and `IcTimeSource`, which can be replaced before calling `multi_transfer_from_args`.
+#[derive(CandidType, Deserialize)]
+struct MultiTransferArgsLayout {
+    key_name: String,
+    ecdsa_pub_key_addresses: BTreeMap<AddressUsingPrimitives, EcdsaPubKey>,
+    utxos_state_addresses: BTreeMap<AddressUsingPrimitives, UtxosState>,
+    payouts: BTreeMap<AddressUsingPrimitives, Satoshi>,
+    change_address: AddressUsingPrimitives,
+    fee: Fee,
+    standard_fee_percentile: u8,
+    target_blocks_percentiles: BTreeMap<u8, u8>,
+    clamp_percentile: bool,
+    max_fee_per_byte: Option<MillisatoshiPerByte>,
+    sign_retry_config: RetryConfig,
+    sign_concurrency: u32,
+    min_confirmations: u32,
+    replaceable: bool,
+    network: Network,
+    queued_payout_ids: Vec<QueueId>,
+    inputs: Vec<OutPoint>,
+    script_payouts: Vec<ScriptPayout>,
+    allow_nonstandard: bool,
+    max_fee: Option<Satoshi>,
+    max_tx_weight: u64,
+    max_inputs: u32,
+    selection_scope: SelectionScope,
+    current_fees: Option<Vec<MillisatoshiPerByte>>,
+    fallback_fee_per_byte: Option<MillisatoshiPerByte>,
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: BitcoinApiTarget,
+}
+
+impl From<&MultiTransferArgs> for MultiTransferArgsLayout {
+    fn from(multi_transfer_args: &MultiTransferArgs) -> Self {
+        MultiTransferArgsLayout {
+            key_name: multi_transfer_args.key_name.clone(),
+            ecdsa_pub_key_addresses: get_map_using_primitives(
+                &multi_transfer_args.ecdsa_pub_key_addresses,
+            ),
+            utxos_state_addresses: get_map_using_primitives(
+                &multi_transfer_args.utxos_state_addresses,
+            ),
+            payouts: get_map_using_primitives(&multi_transfer_args.payouts),
+            change_address: get_address_using_primitives(&multi_transfer_args.change_address),
+            fee: multi_transfer_args.fee,
+            standard_fee_percentile: multi_transfer_args.standard_fee_percentile,
+            target_blocks_percentiles: multi_transfer_args.target_blocks_percentiles.clone(),
+            clamp_percentile: multi_transfer_args.clamp_percentile,
+            max_fee_per_byte: multi_transfer_args.max_fee_per_byte,
+            sign_retry_config: multi_transfer_args.sign_retry_config,
+            sign_concurrency: multi_transfer_args.sign_concurrency,
+            min_confirmations: multi_transfer_args.min_confirmations,
+            replaceable: multi_transfer_args.replaceable,
+            network: multi_transfer_args.network,
+            queued_payout_ids: multi_transfer_args.queued_payout_ids.clone(),
+            inputs: multi_transfer_args.inputs.clone(),
+            script_payouts: multi_transfer_args.script_payouts.clone(),
+            allow_nonstandard: multi_transfer_args.allow_nonstandard,
+            max_fee: multi_transfer_args.max_fee,
+            max_tx_weight: multi_transfer_args.max_tx_weight,
+            max_inputs: multi_transfer_args.max_inputs,
+            selection_scope: multi_transfer_args.selection_scope,
+            current_fees: multi_transfer_args.current_fees.clone(),
+            fallback_fee_per_byte: multi_transfer_args.fallback_fee_per_byte,
+            cycles_cost_config: multi_transfer_args.cycles_cost_config,
+            bitcoin_api_target: multi_transfer_args.bitcoin_api_target.clone(),
+        }
+    }
+}
+
+impl TryFrom<MultiTransferArgsLayout> for MultiTransferArgs {
+    type Error = BitcoinAddressError;
+
+    fn try_from(layout: MultiTransferArgsLayout) -> Result<Self, Self::Error> {
+        Ok(MultiTransferArgs {
+            key_name: layout.key_name,
+            ecdsa_pub_key_addresses: get_map_using_addresses(layout.ecdsa_pub_key_addresses)?,
+            utxos_state_addresses: get_map_using_addresses(layout.utxos_state_addresses)?,
+            payouts: get_map_using_addresses(layout.payouts)?,
+            change_address: get_address(layout.change_address)?,
+            fee: layout.fee,
+            standard_fee_percentile: layout.standard_fee_percentile,
+            target_blocks_percentiles: layout.target_blocks_percentiles,
+            clamp_percentile: layout.clamp_percentile,
+            max_fee_per_byte: layout.max_fee_per_byte,
+            sign_retry_config: layout.sign_retry_config,
+            sign_concurrency: layout.sign_concurrency,
+            min_confirmations: layout.min_confirmations,
+            replaceable: layout.replaceable,
+            network: layout.network,
+            queued_payout_ids: layout.queued_payout_ids,
+            inputs: layout.inputs,
+            script_payouts: layout.script_payouts,
+            allow_nonstandard: layout.allow_nonstandard,
+            max_fee: layout.max_fee,
+            max_tx_weight: layout.max_tx_weight,
+            max_inputs: layout.max_inputs,
+            selection_scope: layout.selection_scope,
+            current_fees: layout.current_fees,
+            fallback_fee_per_byte: layout.fallback_fee_per_byte,
+            cycles_cost_config: layout.cycles_cost_config,
+            bitcoin_api_target: layout.bitcoin_api_target,
+            log_sink: Arc::new(NoopLogSink),
+            time_source: Arc::new(IcTimeSource),
+        })
+    }
+}
+
+impl_candid_through_layout!(MultiTransferArgs, MultiTransferArgsLayout);
@@ -0,0 +1,341 @@
+#[cfg(any(test, feature = "mock"))]
+use crate::canister_mock::ManagementCanisterMock;
+use crate::{
+    get_current_fees_from_args, get_utxos_from_args,
+    logging::{LogLevel, LOG_TARGET_STATE},
+    multi_transfer_from_args,
+    transaction_management::get_fee_from_percentiles,
+    utxo_management::get_balance_from_utxos,
+    BitcoinAgent, Fee, FeeRequest, GetCurrentFeeError, GetUtxosError, ManagementCanister,
+    ManagementCanisterReject, MillisatoshiPerByte, MultiTransferError, MultiTransferResult,
+    Satoshi, UtxosArgs, UtxosResult, UtxosUpdate, WithCost,
+};
+use bitcoin::Address;
+use std::collections::BTreeMap;
+
+/// Owns a Bitcoin agent and composes its arguments structures, the free functions making the calls and the application of their results into single async methods, e.g. `balance(&address).await`.
+///
+/// **The methods hold a mutable borrow of the Bitcoin agent across the `await`s**, hence they must only be used when the canister guarantees an exclusive access to it for the whole call, e.g. with a message queue processing one call at a time.
+/// **They must not be called on a Bitcoin agent shared behind a `RefCell`**, e.g. in a `thread_local`, as another message borrowing it while the call is pending would panic. Such Bitcoin agents are used with the arguments structures or with the macros like `get_utxos!` instead.
+pub struct AsyncBitcoinAgent<C: ManagementCanister> {
+    bitcoin_agent: BitcoinAgent<C>,
+}
+
+impl<C: ManagementCanister> AsyncBitcoinAgent<C> {
+    /// Wraps the given Bitcoin agent.
+    pub fn new(bitcoin_agent: BitcoinAgent<C>) -> Self {
+        Self { bitcoin_agent }
+    }
+
+    /// Returns the wrapped Bitcoin agent, e.g. to get its state.
+    pub fn bitcoin_agent(&self) -> &BitcoinAgent<C> {
+        &self.bitcoin_agent
+    }
+
+    /// Returns the wrapped Bitcoin agent mutably, e.g. to add addresses.
+    pub fn bitcoin_agent_mut(&mut self) -> &mut BitcoinAgent<C> {
+        &mut self.bitcoin_agent
+    }
+
+    /// Returns the wrapped Bitcoin agent, consuming the wrapper.
+    pub fn into_inner(self) -> BitcoinAgent<C> {
+        self.bitcoin_agent
+    }
+
+    /// Refreshes the UTXOs of the managed `address` according to the minimum number of confirmations given when adding it and returns the difference with the UTXOs seen last, like `apply_utxos`.
+    /// The refreshed UTXOs are then seen with `update_state`, so that `transfer` may spend them.
+    pub async fn refresh_utxos(&mut self, address: &Address) -> Result<UtxosUpdate, GetUtxosError> {
+        let utxos_args = self.get_refresh_utxos_args(address)?;
+        let utxos_result = get_utxos_from_args(utxos_args).await;
+        self.apply_refreshed_utxos(utxos_result)
+    }
+
+    /// Refreshes the UTXOs of the managed `address` like `refresh_utxos` and returns its balance.
+    pub async fn balance(&mut self, address: &Address) -> Result<Satoshi, GetUtxosError> {
+        self.refresh_utxos(address).await?;
+        Ok(self.get_balance(address))
+    }
+
+    /// Transfers bitcoins from the managed addresses to `payouts` like `get_multi_transfer_args` and `multi_transfer_from_args` do, and applies the result to the Bitcoin agent.
+    pub async fn transfer(
+        &mut self,
+        payouts: &BTreeMap<Address, Satoshi>,
+        change_address: &Address,
+        fee: Fee,
+        min_confirmations: u32,
+        replaceable: bool,
+    ) -> Result<MultiTransferResult, MultiTransferError> {
+        let multi_transfer_args = self.bitcoin_agent.get_multi_transfer_args(
+            payouts,
+            change_address,
+            fee,
+            min_confirmations,
+            replaceable,
+        );
+        let multi_transfer_result = multi_transfer_from_args(multi_transfer_args).await?;
+        self.apply_transfer(&multi_transfer_result);
+        Ok(multi_transfer_result)
+    }
+
+    /// Returns the fee in millisatoshis/byte associated with `fee_request`, from the cached fees if they aren't stale.
+    /// Otherwise, the fee percentiles are retrieved and cached with `apply_current_fees`.
+    pub async fn current_fee(
+        &mut self,
+        fee_request: FeeRequest,
+    ) -> Result<MillisatoshiPerByte, GetCurrentFeeError> {
+        if let Some(cached_fee) = self.bitcoin_agent.cached_fee(fee_request) {
+            return Ok(cached_fee);
+        }
+        let current_fees_args = self
+            .bitcoin_agent
+            .get_current_fees_args()
+            .map_err(ManagementCanisterReject::from)?;
+        let fees = get_current_fees_from_args(current_fees_args).await?;
+        self.apply_fees(fee_request, fees)
+    }
+
+    /// Returns the arguments to refresh the UTXOs of the managed `address`.
+    fn get_refresh_utxos_args(&self, address: &Address) -> Result<UtxosArgs, GetUtxosError> {
+        let min_confirmations = self.bitcoin_agent.get_address_min_confirmations(address)?;
+        self.bitcoin_agent
+            .get_utxos_args(address, min_confirmations)
+            .map_err(|rate_limited| ManagementCanisterReject::from(rate_limited).into())
+    }
+
+    fn apply_refreshed_utxos(
+        &mut self,
+        utxos_result: Result<WithCost<UtxosResult>, GetUtxosError>,
+    ) -> Result<UtxosUpdate, GetUtxosError> {
+        let utxos_result = utxos_result?.result;
+        let address = utxos_result.address.clone();
+        let utxos_update = self.bitcoin_agent.apply_utxos(utxos_result);
+        self.bitcoin_agent.update_state(&address)?;
+        Ok(utxos_update)
+    }
+
+    /// Returns the balance of the UTXOs of the managed `address` applied last.
+    fn get_balance(&self, address: &Address) -> Satoshi {
+        get_balance_from_utxos(&self.bitcoin_agent.utxos_state_addresses[address].unseen_state)
+    }
+
+    fn apply_transfer(&mut self, multi_transfer_result: &MultiTransferResult) {
+        // The Bitcoin agent was exclusively borrowed during the transfer, hence the spending addresses are still managed and the result can be applied.
+        // As the transaction is sent anyway, a result which can't be applied is only logged, the UTXOs being corrected by their next refresh.
+        if let Err(error) = self
+            .bitcoin_agent
+            .apply_multi_transfer_result(multi_transfer_result)
+        {
+            self.bitcoin_agent.log_sink.log(
+                LogLevel::Error,
+                LOG_TARGET_STATE,
+                &format!(
+                    "Failed to apply the result of the transaction {}: {}",
+                    multi_transfer_result.transaction_info.id, error
+                ),
+            );
+        }
+    }
+
+    fn apply_fees(
+        &mut self,
+        fee_request: FeeRequest,
+        fees: WithCost<Vec<MillisatoshiPerByte>>,
+    ) -> Result<MillisatoshiPerByte, GetCurrentFeeError> {
+        let current_fee_args = self.bitcoin_agent.get_current_fee_args(fee_request);
+        let fee = get_fee_from_percentiles(
+            current_fee_args.fee_request,
+            &fees.result,
+            current_fee_args.clamp_percentile,
+        );
+        let tip_height = self.bitcoin_agent.fee_cache.get_tip_height();
+        self.bitcoin_agent
+            .apply_current_fees(fees.result, tip_height);
+        fee
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+impl AsyncBitcoinAgent<ManagementCanisterMock> {
+    /// Simulates `refresh_utxos` during tests.
+    pub fn refresh_utxos_test(&mut self, address: &Address) -> Result<UtxosUpdate, GetUtxosError> {
+        let utxos_args = self.get_refresh_utxos_args(address)?;
+        let utxos_result = self.bitcoin_agent.get_utxos_from_args_test(utxos_args);
+        self.apply_refreshed_utxos(utxos_result)
+    }
+
+    /// Simulates `balance` during tests.
+    pub fn balance_test(&mut self, address: &Address) -> Result<Satoshi, GetUtxosError> {
+        self.refresh_utxos_test(address)?;
+        Ok(self.get_balance(address))
+    }
+
+    /// Simulates `transfer` during tests.
+    pub async fn transfer_test(
+        &mut self,
+        payouts: &BTreeMap<Address, Satoshi>,
+        change_address: &Address,
+        fee: Fee,
+        min_confirmations: u32,
+        replaceable: bool,
+    ) -> Result<MultiTransferResult, MultiTransferError> {
+        let multi_transfer_args = self.bitcoin_agent.get_multi_transfer_args(
+            payouts,
+            change_address,
+            fee,
+            min_confirmations,
+            replaceable,
+        );
+        let multi_transfer_result = self
+            .bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await?;
+        self.apply_transfer(&multi_transfer_result);
+        Ok(multi_transfer_result)
+    }
+
+    /// Simulates `current_fee` during tests.
+    pub fn current_fee_test(
+        &mut self,
+        fee_request: FeeRequest,
+    ) -> Result<MillisatoshiPerByte, GetCurrentFeeError> {
+        if let Some(cached_fee) = self.bitcoin_agent.cached_fee(fee_request) {
+            return Ok(cached_fee);
+        }
+        let current_fees_args = self
+            .bitcoin_agent
+            .get_current_fees_args()
+            .map_err(ManagementCanisterReject::from)?;
+        let fees = self
+            .bitcoin_agent
+            .get_current_fees_from_args_test(current_fees_args)?;
+        self.apply_fees(fee_request, fees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent,
+        canister_mock::{get_init_balance, get_init_utxos_update},
+        AddressType, Network,
+    };
+    use std::str::FromStr;
+
+    /// Check that `refresh_utxos` and `balance` apply the refreshed UTXOs of the managed addresses only.
+    #[test]
+    fn check_refresh_utxos_and_balance() {
+        let mut async_bitcoin_agent = AsyncBitcoinAgent::new(agent::tests::new_mock(
+            &Network::Regtest,
+            &AddressType::P2pkh,
+        ));
+        let main_address = async_bitcoin_agent.bitcoin_agent().get_main_address();
+        assert_eq!(
+            async_bitcoin_agent.refresh_utxos_test(&main_address),
+            Ok(get_init_utxos_update())
+        );
+        assert_eq!(
+            async_bitcoin_agent.balance_test(&main_address),
+            Ok(get_init_balance())
+        );
+
+        let derived_address = async_bitcoin_agent
+            .bitcoin_agent_mut()
+            .add_address(&[vec![0]])
+            .unwrap();
+        async_bitcoin_agent
+            .bitcoin_agent_mut()
+            .get_management_canister_mock_mut()
+            .fund_address(&derived_address, 10_000);
+        assert_eq!(
+            async_bitcoin_agent.balance_test(&derived_address),
+            Ok(10_000)
+        );
+        // The refreshed UTXOs are seen, hence available to the transfers.
+        assert_eq!(
+            async_bitcoin_agent
+                .bitcoin_agent()
+                .peek_utxos_update(&derived_address),
+            Ok(UtxosUpdate::new())
+        );
+
+        let unmanaged_address = Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        assert_eq!(
+            async_bitcoin_agent.balance_test(&unmanaged_address),
+            Err(GetUtxosError::AddressNotTracked)
+        );
+    }
+
+    /// Check that `transfer` spends the UTXOs refreshed by `refresh_utxos` and `balance` and applies its result, so that the spent UTXOs aren't spent again.
+    #[tokio::test]
+    async fn check_transfer() {
+        let mut async_bitcoin_agent = AsyncBitcoinAgent::new(agent::tests::new_mock(
+            &Network::Testnet,
+            &AddressType::P2pkh,
+        ));
+        let main_address = async_bitcoin_agent.bitcoin_agent().get_main_address();
+        async_bitcoin_agent
+            .refresh_utxos_test(&main_address)
+            .unwrap();
+
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        let multi_transfer_result = async_bitcoin_agent
+            .transfer_test(&payouts, &main_address, Fee::Constant(10_000), 0, false)
+            .await
+            .unwrap();
+        assert_eq!(multi_transfer_result.transaction_info.fee, 10_000);
+        assert_eq!(
+            async_bitcoin_agent.balance_test(&main_address),
+            Ok(get_init_balance() - 25_000 - 10_000)
+        );
+
+        // The refreshed change of the first transfer is spent by the next one.
+        async_bitcoin_agent
+            .transfer_test(&payouts, &main_address, Fee::Constant(10_000), 0, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            async_bitcoin_agent.balance_test(&main_address),
+            Ok(get_init_balance() - 2 * (25_000 + 10_000))
+        );
+    }
+
+    /// Check that `current_fee` caches the fee percentiles it retrieves.
+    #[test]
+    fn check_current_fee() {
+        let mut async_bitcoin_agent = AsyncBitcoinAgent::new(agent::tests::new_mock(
+            &Network::Regtest,
+            &AddressType::P2pkh,
+        ));
+        assert_eq!(
+            async_bitcoin_agent.current_fee_test(FeeRequest::Standard),
+            Ok(50_000)
+        );
+        assert_eq!(
+            async_bitcoin_agent
+                .bitcoin_agent()
+                .cached_fee(FeeRequest::Standard),
+            Some(50_000)
+        );
+
+        // The cached fees are used as the management canister doesn't provide any anymore.
+        async_bitcoin_agent
+            .bitcoin_agent_mut()
+            .get_management_canister_mock_mut()
+            .clear_fee_percentiles();
+        assert_eq!(
+            async_bitcoin_agent.current_fee_test(FeeRequest::Median),
+            Ok(50_000)
+        );
+        assert!(async_bitcoin_agent
+            .current_fee_test(FeeRequest::Percentile(101))
+            .is_err());
+    }
+}
@@ -1,7 +1,105 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+};
+
+/// The maximum number of parent extended public keys kept by the derivation cache.
+const DERIVATION_CACHE_CAPACITY: usize = 64;
+
+/// An extended public key given by its public key, its chain code and its derivation path from them.
+type DerivationCacheKey = (Vec<u8>, Vec<u8>, Vec<Vec<u8>>);
+
+/// An extended public key given by its public key and its chain code.
+type ExtendedPublicKey = (Vec<u8>, Vec<u8>);
+
+/// A least recently used cache of derived extended public keys.
+struct DerivationCache {
+    capacity: usize,
+    last_use: u64,
+    entries: HashMap<DerivationCacheKey, (ExtendedPublicKey, u64)>,
+    // The keys of the entries by their last use, the first one being the least recently used.
+    uses: BTreeMap<u64, DerivationCacheKey>,
+}
+
+impl DerivationCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            last_use: 0,
+            entries: HashMap::default(),
+            uses: BTreeMap::default(),
+        }
+    }
+
+    /// Returns the cached public key and chain code of the given key, marking it as the most recently used one.
+    fn get(&mut self, key: &DerivationCacheKey) -> Option<ExtendedPublicKey> {
+        self.last_use += 1;
+        let (extended_public_key, last_use) = self.entries.get_mut(key)?;
+        let key = self.uses.remove(last_use).unwrap();
+        *last_use = self.last_use;
+        self.uses.insert(self.last_use, key);
+        Some(extended_public_key.clone())
+    }
+
+    /// Caches the public key and chain code of the given key, evicting the least recently used entry if the cache is full.
+    fn insert(&mut self, key: DerivationCacheKey, extended_public_key: ExtendedPublicKey) {
+        if self.entries.len() == self.capacity {
+            if let Some(&least_recent_use) = self.uses.keys().next() {
+                let evicted_key = self.uses.remove(&least_recent_use).unwrap();
+                self.entries.remove(&evicted_key);
+            }
+        }
+        self.last_use += 1;
+        self.uses.insert(self.last_use, key.clone());
+        self.entries
+            .insert(key, (extended_public_key, self.last_use));
+    }
+}
+
+thread_local! {
+    // The cache only speeds up derivations, it's therefore not part of `BitcoinAgentState`.
+    static DERIVATION_CACHE: RefCell<DerivationCache> =
+        RefCell::new(DerivationCache::new(DERIVATION_CACHE_CAPACITY));
+}
+
+#[cfg(test)]
+thread_local! {
+    // The number of child derivations computed, each one requiring a HMAC-SHA512 and an elliptic curve multiplication.
+    static CHILD_DERIVATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Returns the public key and chain code derived from the given public key and chain code along `path`.
+/// The parent extended public key of the derived one is cached, so that sibling paths only derive their last element.
 pub(crate) fn extended_bip32_derivation(
     public_key: &[u8],
     chain_code: &[u8],
     path: &[Vec<u8>],
+) -> (Vec<u8>, Vec<u8>) {
+    if path.len() < 2 {
+        return uncached_extended_bip32_derivation(public_key, chain_code, path);
+    }
+    let (parent_path, child_path) = path.split_at(path.len() - 1);
+    let key = (
+        public_key.to_vec(),
+        chain_code.to_vec(),
+        parent_path.to_vec(),
+    );
+    let cached_parent = DERIVATION_CACHE.with(|cache| cache.borrow_mut().get(&key));
+    let (parent_public_key, parent_chain_code) = cached_parent.unwrap_or_else(|| {
+        let parent = uncached_extended_bip32_derivation(public_key, chain_code, parent_path);
+        DERIVATION_CACHE.with(|cache| cache.borrow_mut().insert(key, parent.clone()));
+        parent
+    });
+    uncached_extended_bip32_derivation(&parent_public_key, &parent_chain_code, child_path)
+}
+
+/// Returns the public key and chain code derived from the given public key and chain code along `path`, deriving every element of `path`.
+fn uncached_extended_bip32_derivation(
+    public_key: &[u8],
+    chain_code: &[u8],
+    path: &[Vec<u8>],
 ) -> (Vec<u8>, Vec<u8>) {
     fn secp256k1_decode_point(bytes: &[u8]) -> Option<k256::ProjectivePoint> {
         use k256::elliptic_curve::sec1::FromEncodedPoint;
@@ -76,6 +174,9 @@ pub(crate) fn extended_bip32_derivation(
 
     for idx in path {
         let (new_public_key, new_chain_code) = ckdpub(&public_key, &chain_code, idx);
+        #[cfg(test)]
+        CHILD_DERIVATIONS
+            .with(|child_derivations| child_derivations.set(child_derivations.get() + 1));
 
         public_key = new_public_key;
         chain_code = new_chain_code;
@@ -84,6 +185,39 @@ pub(crate) fn extended_bip32_derivation(
     (public_key, chain_code)
 }
 
+/// Returns the private key of the derived child from the given private key, chain code and derivation path, matching the public key derived by `extended_bip32_derivation`.
+/// Each element of the derivation path, whatever its length, adds the left half of the HMAC-SHA512 of the parent public key and the element to the parent private key.
+#[cfg(any(test, feature = "bitcoind-rpc", feature = "mock"))]
+pub(crate) fn derive_child_private_key(
+    private_key: &[u8],
+    chain_code: &[u8],
+    derivation_path: &[Vec<u8>],
+) -> Vec<u8> {
+    use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha512;
+
+    let secp256k1 = Secp256k1::new();
+    let mut private_key = SecretKey::from_slice(private_key).unwrap();
+    // An empty chain code is considered as zeros, like for the public key derivation.
+    let mut chain_code = if chain_code.is_empty() {
+        vec![0; 32]
+    } else {
+        chain_code.to_vec()
+    };
+    for element in derivation_path {
+        let mut hmac = Hmac::<Sha512>::new_from_slice(&chain_code).unwrap();
+        hmac.update(&PublicKey::from_secret_key(&secp256k1, &private_key).serialize());
+        hmac.update(element);
+        let hmac_output = hmac.finalize().into_bytes();
+        private_key = private_key
+            .add_tweak(&Scalar::from_be_bytes(hmac_output[..32].try_into().unwrap()).unwrap())
+            .unwrap();
+        chain_code = hmac_output[32..].to_vec();
+    }
+    private_key.secret_bytes().to_vec()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,8 +232,11 @@ mod tests {
         let index1 = vec![1, 2, 3, 4, 5];
         let index2 = vec![8, 0, 2, 8, 0, 2];
 
-        let (derived_1_pk, derived_1_cc) =
-            extended_bip32_derivation(&master_public_key, &master_chain_key, &[index1.clone()]);
+        let (derived_1_pk, derived_1_cc) = extended_bip32_derivation(
+            &master_public_key,
+            &master_chain_key,
+            std::slice::from_ref(&index1),
+        );
 
         assert_eq!(
             hex::encode(&derived_1_pk),
@@ -110,8 +247,11 @@ mod tests {
             "0811cb2a510b05fedcfb7ba49a5ceb4d48d9ed1210b6a85839e36c53105d3308"
         );
 
-        let (derived_2_pk, derived_2_cc) =
-            extended_bip32_derivation(&master_public_key, &master_chain_key, &[index2.clone()]);
+        let (derived_2_pk, derived_2_cc) = extended_bip32_derivation(
+            &master_public_key,
+            &master_chain_key,
+            std::slice::from_ref(&index2),
+        );
 
         assert_eq!(
             hex::encode(&derived_2_pk),
@@ -134,4 +274,60 @@ mod tests {
             "53ab3ab4ba311976dfae6e7f38fe2131dd5cb72ceff178b06a19b8ad92d1f2d3"
         );
     }
+
+    /// Check that deriving sibling addresses with the cache returns the same keys as without it while computing fewer child derivations.
+    #[test]
+    fn check_derivation_cache() {
+        let master_public_key =
+            hex::decode("038cc78aa6040c5f269351939a05aad3a31f86902d0b8cf3085244bb58b6d4337a")
+                .unwrap();
+        let master_chain_key = vec![];
+        let sibling_paths: Vec<Vec<Vec<u8>>> = (0..1_000u32)
+            .map(|index| {
+                vec![
+                    vec![0, 0, 0, 1],
+                    vec![0, 0, 0, 2],
+                    index.to_be_bytes().to_vec(),
+                ]
+            })
+            .collect();
+
+        let child_derivations =
+            || CHILD_DERIVATIONS.with(|child_derivations| child_derivations.get());
+        let initial_child_derivations = child_derivations();
+        let uncached_keys: Vec<(Vec<u8>, Vec<u8>)> = sibling_paths
+            .iter()
+            .map(|path| {
+                uncached_extended_bip32_derivation(&master_public_key, &master_chain_key, path)
+            })
+            .collect();
+        let uncached_child_derivations = child_derivations() - initial_child_derivations;
+        assert_eq!(uncached_child_derivations, 3 * 1_000);
+
+        let initial_child_derivations = child_derivations();
+        let cached_keys: Vec<(Vec<u8>, Vec<u8>)> = sibling_paths
+            .iter()
+            .map(|path| extended_bip32_derivation(&master_public_key, &master_chain_key, path))
+            .collect();
+        let cached_child_derivations = child_derivations() - initial_child_derivations;
+        // The common parent is only derived once, roughly dividing the instruction count by the path length.
+        assert_eq!(cached_child_derivations, 2 + 1_000);
+
+        assert_eq!(cached_keys, uncached_keys);
+    }
+
+    /// Check that the derivation cache evicts the least recently used entry once full.
+    #[test]
+    fn check_derivation_cache_eviction() {
+        let key = |index: u8| (vec![index], vec![], vec![]);
+        let extended_public_key = |index: u8| (vec![index], vec![index]);
+        let mut cache = DerivationCache::new(2);
+        cache.insert(key(0), extended_public_key(0));
+        cache.insert(key(1), extended_public_key(1));
+        assert_eq!(cache.get(&key(0)), Some(extended_public_key(0)));
+        cache.insert(key(2), extended_public_key(2));
+        assert_eq!(cache.get(&key(1)), None);
+        assert_eq!(cache.get(&key(0)), Some(extended_public_key(0)));
+        assert_eq!(cache.get(&key(2)), Some(extended_public_key(2)));
+    }
 }
@@ -0,0 +1,135 @@
+use crate::{
+    canister_common::{call_with_payment, get_cycles_spent},
+    types::{from_bitcoin_network_to_management_canister_network, GetBlockHeadersRequest},
+    BitcoinApiTarget, GetBlockHeadersResponse, ManagementCanisterMethod, ManagementCanisterReject,
+    WithCost,
+};
+use bitcoin::Network;
+
+/// Returns the tip height and the block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`, and the cycles spent to retrieve them from `bitcoin_api_target`.
+/// It's a much cheaper way than `get_utxos` to retrieve the tip height.
+pub(crate) async fn get_block_headers(
+    network: Network,
+    start_height: u32,
+    end_height: Option<u32>,
+    payment: u128,
+    bitcoin_api_target: &BitcoinApiTarget,
+) -> Result<WithCost<GetBlockHeadersResponse>, ManagementCanisterReject> {
+    let res: Result<(GetBlockHeadersResponse,), _> = call_with_payment(
+        bitcoin_api_target.get_principal(),
+        ManagementCanisterMethod::BitcoinGetBlockHeaders,
+        (GetBlockHeadersRequest {
+            start_height,
+            end_height,
+            network: from_bitcoin_network_to_management_canister_network(network)?,
+        },),
+        payment,
+    )
+    .await;
+
+    match res {
+        // Return the block headers to the caller.
+        Ok((get_block_headers_response,)) => Ok(WithCost {
+            result: get_block_headers_response,
+            cycles_spent: get_cycles_spent(
+                ManagementCanisterMethod::BitcoinGetBlockHeaders,
+                payment,
+            ),
+            attempts: 1,
+        }),
+
+        // The call to `get_block_headers` was rejected for a given reason (e.g., the start height exceeds the tip height).
+        Err((rejection_code, message)) => Err(ManagementCanisterReject(rejection_code, message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent, canister_mock::BLOCK_HEADER_LENGTH, AddressType, FeeRequest,
+        ManagementCanisterMethod, Network, GET_BLOCK_HEADERS_COST_CYCLES,
+    };
+    use bitcoin::{block::Header as BlockHeader, consensus::deserialize};
+    use ic_cdk::api::call::RejectionCode;
+
+    /// Check that the block headers are synthetic 80-byte headers chained up to the tip height of the mock.
+    #[test]
+    fn check_get_block_headers() {
+        let bitcoin_agent = agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let tip_height = bitcoin_agent.management_canister.chain().tip_height;
+
+        let block_headers_args = bitcoin_agent.get_block_headers_args(tip_height - 2, None);
+        assert_eq!(block_headers_args.payment, GET_BLOCK_HEADERS_COST_CYCLES);
+        let block_headers = bitcoin_agent
+            .get_block_headers_from_args_test(block_headers_args)
+            .unwrap();
+        assert_eq!(block_headers.cycles_spent, GET_BLOCK_HEADERS_COST_CYCLES);
+        assert_eq!(block_headers.result.tip_height, tip_height);
+        assert_eq!(block_headers.result.block_headers.len(), 3);
+        let block_headers: Vec<BlockHeader> = block_headers
+            .result
+            .block_headers
+            .iter()
+            .map(|block_header| {
+                assert_eq!(block_header.len(), BLOCK_HEADER_LENGTH);
+                deserialize(block_header).unwrap()
+            })
+            .collect();
+        for pair in block_headers.windows(2) {
+            assert_eq!(pair[1].prev_blockhash, pair[0].block_hash());
+        }
+
+        // The headers of the same heights are the same whatever the requested range.
+        let block_header = bitcoin_agent
+            .get_block_headers_from_args_test(
+                bitcoin_agent.get_block_headers_args(tip_height, Some(tip_height)),
+            )
+            .unwrap()
+            .result
+            .block_headers;
+        assert_eq!(
+            deserialize::<BlockHeader>(&block_header[0]).unwrap(),
+            block_headers[2]
+        );
+
+        for (start_height, end_height) in [(tip_height + 1, None), (2, Some(1))] {
+            assert_eq!(
+                bitcoin_agent
+                    .get_block_headers_from_args_test(
+                        bitcoin_agent.get_block_headers_args(start_height, end_height)
+                    )
+                    .unwrap_err()
+                    .0,
+                RejectionCode::CanisterReject
+            );
+        }
+    }
+
+    /// Check that applying the block headers makes the cached fees stale once the tip advanced too much.
+    #[test]
+    fn check_apply_block_headers() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let tip_height = bitcoin_agent.management_canister.chain().tip_height;
+        bitcoin_agent.set_fee_cache_max_age(6);
+        bitcoin_agent.apply_current_fees((1_000..100_000).step_by(1_000).collect(), tip_height);
+        assert!(bitcoin_agent.cached_fee(FeeRequest::Median).is_some());
+
+        bitcoin_agent.management_canister.chain().tip_height += 6;
+        let block_headers = bitcoin_agent
+            .get_block_headers_from_args_test(bitcoin_agent.get_block_headers_args(0, Some(0)))
+            .unwrap()
+            .result;
+        bitcoin_agent.apply_block_headers(&block_headers);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Median), None);
+
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::BitcoinGetBlockHeaders,
+            RejectionCode::SysTransient,
+            "busy",
+            1,
+        );
+        assert!(bitcoin_agent
+            .get_block_headers_from_args_test(bitcoin_agent.get_block_headers_args(0, None))
+            .is_err());
+    }
+}
@@ -1,28 +1,191 @@
 use crate::{
-    types::GetUtxosResponse, EcdsaPubKey, GetUtxosError, ManagementCanisterReject,
-    MillisatoshiPerByte,
+    metrics,
+    types::{GetBlockHeadersResponse, GetUtxosResponse},
+    BitcoinApiTarget, CyclesCostConfig, CyclesReconciliation, DerivationPath, EcdsaPubKey,
+    GetUtxosError, IcTimeSource, ManagementCanisterMethod, ManagementCanisterReject,
+    MillisatoshiPerByte, RetryPolicy, TimeSource, WithCost,
 };
 use async_trait::async_trait;
 use bitcoin::{Address, Network};
+use candid::{
+    utils::{decode_args, encode_args, ArgumentDecoder, ArgumentEncoder},
+    Principal,
+};
+use ic_cdk::api::call::{call_raw128, msg_cycles_refunded128, CallResult, RejectionCode};
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    sync::Arc,
+};
 
-const MILLION: u64 = 1_000_000; // One million
-const BILLION: u64 = 1_000_000_000; // One billion
+const MILLION: u128 = 1_000_000; // One million
+const BILLION: u128 = 1_000_000_000; // One billion
 
 // Fees for the various Bitcoin endpoints.
-pub(crate) const GET_UTXOS_COST_CYCLES: u64 = 100 * MILLION;
-pub(crate) const GET_CURRENT_FEE_PERCENTILES_COST_CYCLES: u64 = 100 * MILLION;
-pub(crate) const SEND_TRANSACTION_BASE_COST_CYCLES: u64 = 5 * BILLION;
-pub(crate) const SEND_TRANSACTION_COST_CYCLES_PER_BYTE: u64 = 20 * MILLION;
-pub(crate) const SIGN_WITH_ECDSA_COST_CYCLES: u64 = 10 * BILLION;
+pub(crate) const GET_UTXOS_COST_CYCLES: u128 = 100 * MILLION;
+pub(crate) const GET_CURRENT_FEE_PERCENTILES_COST_CYCLES: u128 = 100 * MILLION;
+pub const GET_BLOCK_HEADERS_COST_CYCLES: u128 = 10 * MILLION;
+pub const GET_BALANCE_COST_CYCLES: u128 = 10 * MILLION;
+pub(crate) const SEND_TRANSACTION_BASE_COST_CYCLES: u128 = 5 * BILLION;
+pub(crate) const SEND_TRANSACTION_COST_CYCLES_PER_BYTE: u128 = 20 * MILLION;
+// Fees for the threshold signatures, depending on the size of the subnet holding the key.
+pub(crate) const SIGN_13_NODE_COST_CYCLES: u128 = 10 * BILLION;
+pub(crate) const SIGN_34_NODE_COST_CYCLES: u128 = 26_153_846_153;
+/// The default maximum number of cycles accepted for any of the costs of a `CyclesCostConfig`.
+pub const DEFAULT_CYCLES_COST_CEILING: u128 = 1_000 * BILLION;
 
+/// The layer making the inter-canister calls of the library, e.g. to the management canister, with Candid-encoded arguments and replies.
+/// The calls are made with the CDK unless another caller is set with `set_canister_caller`, e.g. to check the requests made off-replica.
 #[async_trait]
-pub trait ManagementCanister {
-    /// Creates a new instance of the management canister.
-    fn new(network: crate::Network) -> Self;
+pub trait CanisterCaller: Send + Sync {
+    /// Calls `method` of the canister `canister_id` with the Candid-encoded `args`, attaching `payment` cycles to the call, and returns the Candid-encoded reply.
+    async fn call(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        args: Vec<u8>,
+        payment: u128,
+    ) -> CallResult<Vec<u8>>;
 
-    /// Creates a new instance of the management canister using the given ECDSA public key.
-    fn new_using_ecdsa_public_key(network: crate::Network, ecdsa_public_key: EcdsaPubKey) -> Self;
+    /// Returns the cycles refunded by the last call.
+    fn get_cycles_refunded(&self) -> u128;
+}
+
+/// The canister caller making the calls with the CDK.
+struct CdkCanisterCaller;
+
+#[async_trait]
+impl CanisterCaller for CdkCanisterCaller {
+    async fn call(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        args: Vec<u8>,
+        payment: u128,
+    ) -> CallResult<Vec<u8>> {
+        call_raw128(canister_id, method, args, payment).await
+    }
+
+    fn get_cycles_refunded(&self) -> u128 {
+        msg_cycles_refunded128()
+    }
+}
+
+thread_local! {
+    static CYCLES_RECONCILIATION_HOOK: Cell<Option<fn(CyclesReconciliation)>> = Cell::new(None);
+    static CANISTER_CALLER: RefCell<Arc<dyn CanisterCaller>> = RefCell::new(Arc::new(CdkCanisterCaller));
+}
+
+/// Makes the inter-canister calls of the library go through `canister_caller`.
+/// The calls are made with the CDK again if `canister_caller` is `None`.
+pub fn set_canister_caller(canister_caller: Option<Arc<dyn CanisterCaller>>) {
+    let canister_caller =
+        canister_caller.unwrap_or_else(|| Arc::new(CdkCanisterCaller) as Arc<dyn CanisterCaller>);
+    CANISTER_CALLER.with(|caller| *caller.borrow_mut() = canister_caller);
+}
+
+fn get_canister_caller() -> Arc<dyn CanisterCaller> {
+    CANISTER_CALLER.with(|caller| caller.borrow().clone())
+}
+
+/// Calls `method` of the canister `canister_id` with `args` through the canister caller, attaching `payment` cycles to the call.
+/// Like the CDK, the arguments failing to be encoded is a bug and the reply failing to be decoded is reported as a rejection.
+pub(crate) async fn call<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    canister_id: Principal,
+    method: &str,
+    args: T,
+    payment: u128,
+) -> CallResult<R> {
+    let args = encode_args(args).expect("Failed to encode the arguments.");
+    let reply = get_canister_caller()
+        .call(canister_id, method, args, payment)
+        .await?;
+    decode_args(&reply).map_err(|error| {
+        (
+            RejectionCode::CanisterError,
+            format!("Failed to decode the reply: {}", error),
+        )
+    })
+}
+
+/// Calls `method` of the canister `canister_id`, e.g. the management canister, with `args`, attaching `payment` cycles to the call.
+/// The rejections are recorded in the metrics, the successful calls being recorded when their spent cycles are reconciled.
+pub(crate) async fn call_with_payment<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    canister_id: Principal,
+    method: ManagementCanisterMethod,
+    args: T,
+    payment: u128,
+) -> CallResult<R> {
+    let res = call(canister_id, method.name(), args, payment).await;
+    if let Err((rejection_code, _)) = &res {
+        metrics::record_rejection(method, *rejection_code);
+    }
+    res
+}
+
+/// Makes `hook` be called with the cycles attached to each paid call to the management canister and the ones actually spent by it, e.g. to adjust a `CyclesCostConfig`.
+/// The hook is removed if `hook` is `None`.
+pub fn set_cycles_reconciliation_hook(hook: Option<fn(CyclesReconciliation)>) {
+    CYCLES_RECONCILIATION_HOOK.with(|reconciliation_hook| reconciliation_hook.set(hook));
+}
+
+/// Awaits the calls returned by `call` until one succeeds or its rejection, whose code is returned by `get_rejection_code`, isn't retried according to `retry_policy`.
+/// Returns the result of the last call, whose `attempts` are the number of calls.
+pub(crate) async fn call_with_retries<T, E, CallFun, Fut>(
+    retry_policy: &RetryPolicy,
+    get_rejection_code: fn(&E) -> Option<RejectionCode>,
+    call: CallFun,
+) -> Result<WithCost<T>, E>
+where
+    CallFun: Fn() -> Fut,
+    Fut: Future<Output = Result<WithCost<T>, E>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match call().await {
+            Ok(with_cost) => {
+                return Ok(WithCost {
+                    attempts,
+                    ..with_cost
+                })
+            }
+            Err(error)
+                if get_rejection_code(&error).is_some_and(|rejection_code| {
+                    retry_policy.should_retry(rejection_code, attempts)
+                }) => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Returns the cycles spent by the last call to `method`, `payment` cycles having been attached to it.
+pub(crate) fn get_cycles_spent(method: ManagementCanisterMethod, payment: u128) -> u128 {
+    reconcile_cycles(method, payment, get_canister_caller().get_cycles_refunded())
+}
 
+/// Returns the cycles spent by a call to `method` given the attached and refunded cycles, recording them in the metrics and reporting them to the reconciliation hook if any.
+fn reconcile_cycles(
+    method: ManagementCanisterMethod,
+    cycles_attached: u128,
+    cycles_refunded: u128,
+) -> u128 {
+    let cycles_spent = cycles_attached.saturating_sub(cycles_refunded);
+    metrics::record_call(method, cycles_spent);
+    if let Some(hook) = CYCLES_RECONCILIATION_HOOK.with(|hook| hook.get()) {
+        hook(CyclesReconciliation {
+            method,
+            cycles_attached,
+            cycles_spent,
+        });
+    }
+    cycles_spent
+}
+
+/// The operations of the management canister used by the Bitcoin agent.
+/// The trait is object-safe, the constructors being in `ManagementCanisterFactory`, hence a `Box<dyn ManagementCanister>` can be used, e.g. to hold Bitcoin agents of different networks behind the same type.
+#[async_trait]
+pub trait ManagementCanister: Send + Sync {
     /// Initializes the management canister by initializing its ECDSA public key.
     fn set_ecdsa_public_key(&mut self, ecdsa_public_key: EcdsaPubKey);
 
@@ -32,6 +195,26 @@ pub trait ManagementCanister {
     /// Returns the ECDSA public key of this canister.
     fn get_ecdsa_public_key(&self) -> EcdsaPubKey;
 
+    /// Returns the name of the threshold ECDSA key used by this canister.
+    fn get_ecdsa_key_name(&self) -> String;
+
+    /// Returns the cycles attached to the paid calls to the management canister.
+    fn get_cycles_cost_config(&self) -> CyclesCostConfig;
+
+    /// Sets the cycles attached to the paid calls to the management canister.
+    fn set_cycles_cost_config(&mut self, cycles_cost_config: CyclesCostConfig);
+
+    /// Returns the canister serving the calls to the Bitcoin API.
+    fn get_bitcoin_api_target(&self) -> BitcoinApiTarget;
+
+    /// Sets the canister serving the calls to the Bitcoin API.
+    fn set_bitcoin_api_target(&mut self, bitcoin_api_target: BitcoinApiTarget);
+
+    /// Returns the time source of the Bitcoin agents using the management canister, the time of the IC by default.
+    fn get_time_source(&self) -> Arc<dyn TimeSource> {
+        Arc::new(IcTimeSource)
+    }
+
     /// Returns the UTXOs of the given Bitcoin `address` according to `min_confirmations`.
     async fn get_utxos(
         &self,
@@ -42,17 +225,760 @@ pub trait ManagementCanister {
     /// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions.
     async fn get_current_fees(&self) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject>;
 
+    /// Returns the tip height and the block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`.
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> Result<GetBlockHeadersResponse, ManagementCanisterReject>;
+
     /// Returns the signature of the given `message_hash` associated with the ECDSA public key of this canister at the given derivation path.
     async fn sign_with_ecdsa(
         &self,
-        derivation_path: &[Vec<u8>],
+        derivation_path: &DerivationPath,
         message_hash: &[u8],
     ) -> Result<Vec<u8>, ManagementCanisterReject>;
 
+    /// Returns the BIP-340 signature of the given `message` associated with the Schnorr public key of this canister at the given derivation path, tweaked as per BIP-86.
+    async fn sign_with_schnorr(
+        &self,
+        derivation_path: &DerivationPath,
+        message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject>;
+
     /// Sends the given transaction to the network the management canister interacts with.
     async fn send_transaction(
-        &mut self,
+        &self,
         transaction: Vec<u8>,
         network: Network,
     ) -> Result<(), ManagementCanisterReject>;
 }
+
+/// Creates the management canisters, e.g. when restoring a Bitcoin agent from its state.
+pub trait ManagementCanisterFactory: ManagementCanister + Sized {
+    /// Creates a new instance of the management canister.
+    /// The ECDSA key name defaults to the one associated with `network` if `ecdsa_key_name` is `None`.
+    fn new(network: crate::Network, ecdsa_key_name: Option<String>) -> Self;
+
+    /// Creates a new instance of the management canister using the given ECDSA public key.
+    /// The ECDSA key name defaults to the one associated with `network` if `ecdsa_key_name` is `None`.
+    fn new_using_ecdsa_public_key(
+        network: crate::Network,
+        ecdsa_public_key: EcdsaPubKey,
+        ecdsa_key_name: Option<String>,
+    ) -> Self;
+}
+
+/// Forwards the operations to the boxed management canister, e.g. a `Box<dyn ManagementCanister>`.
+#[async_trait]
+impl<T: ManagementCanister + ?Sized> ManagementCanister for Box<T> {
+    fn set_ecdsa_public_key(&mut self, ecdsa_public_key: EcdsaPubKey) {
+        (**self).set_ecdsa_public_key(ecdsa_public_key)
+    }
+
+    fn get_network(&self) -> Network {
+        (**self).get_network()
+    }
+
+    fn get_ecdsa_public_key(&self) -> EcdsaPubKey {
+        (**self).get_ecdsa_public_key()
+    }
+
+    fn get_ecdsa_key_name(&self) -> String {
+        (**self).get_ecdsa_key_name()
+    }
+
+    fn get_cycles_cost_config(&self) -> CyclesCostConfig {
+        (**self).get_cycles_cost_config()
+    }
+
+    fn set_cycles_cost_config(&mut self, cycles_cost_config: CyclesCostConfig) {
+        (**self).set_cycles_cost_config(cycles_cost_config)
+    }
+
+    fn get_bitcoin_api_target(&self) -> BitcoinApiTarget {
+        (**self).get_bitcoin_api_target()
+    }
+
+    fn set_bitcoin_api_target(&mut self, bitcoin_api_target: BitcoinApiTarget) {
+        (**self).set_bitcoin_api_target(bitcoin_api_target)
+    }
+
+    fn get_time_source(&self) -> Arc<dyn TimeSource> {
+        (**self).get_time_source()
+    }
+
+    async fn get_utxos(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+    ) -> Result<GetUtxosResponse, GetUtxosError> {
+        (**self).get_utxos(address, min_confirmations).await
+    }
+
+    async fn get_current_fees(&self) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject> {
+        (**self).get_current_fees().await
+    }
+
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> Result<GetBlockHeadersResponse, ManagementCanisterReject> {
+        (**self).get_block_headers(start_height, end_height).await
+    }
+
+    async fn sign_with_ecdsa(
+        &self,
+        derivation_path: &DerivationPath,
+        message_hash: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        (**self)
+            .sign_with_ecdsa(derivation_path, message_hash)
+            .await
+    }
+
+    async fn sign_with_schnorr(
+        &self,
+        derivation_path: &DerivationPath,
+        message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        (**self).sign_with_schnorr(derivation_path, message).await
+    }
+
+    async fn send_transaction(
+        &self,
+        transaction: Vec<u8>,
+        network: Network,
+    ) -> Result<(), ManagementCanisterReject> {
+        (**self).send_transaction(transaction, network).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent,
+        canister_mock::ManagementCanisterMock,
+        get_current_fees_from_args, get_initialization_parameters_from_args, get_utxos_from_args,
+        sign_message_from_args,
+        transaction_management::{get_current_fees, send_transaction},
+        types::{
+            from_bitcoin_network_to_management_canister_network, EcdsaCurve, EcdsaKeyId,
+            SignWithECDSA, SignWithECDSAReply,
+        },
+        AddressType, BitcoinAgent, BoxedBitcoinAgent, CurrentFeesArgs, Fee,
+        InitializationParametersArgs, InvalidCyclesCostConfig, ManagementCanisterImpl,
+        MultiTransferArgs, UtxosArgs, UtxosResult,
+    };
+    use candid::{decode_one, encode_one};
+    use ic_cdk::api::management_canister::bitcoin::{
+        self as management_canister_bitcoin, GetCurrentFeePercentilesRequest, GetUtxosRequest,
+        SendTransactionRequest, UtxoFilter::MinConfirmations,
+    };
+    use std::{collections::BTreeMap, str::FromStr, sync::Mutex};
+
+    thread_local! {
+        static RECONCILIATIONS: RefCell<Vec<CyclesReconciliation>> = const { RefCell::new(vec![]) };
+    }
+
+    /// A call made through a canister caller: the called canister, the method, the Candid-encoded arguments and the attached cycles.
+    type RecordedCall = (Principal, String, Vec<u8>, u128);
+
+    /// A canister caller recording the calls instead of making them, replying to the methods with a reply and rejecting the calls to the other ones.
+    #[derive(Default)]
+    struct RecordingCanisterCaller {
+        replies: BTreeMap<&'static str, Vec<u8>>,
+        cycles_refunded: u128,
+        calls: Mutex<Vec<RecordedCall>>,
+    }
+
+    impl RecordingCanisterCaller {
+        fn get_calls(&self) -> Vec<RecordedCall> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        /// Returns the called canisters, methods and attached cycles of the recorded calls.
+        fn get_paid_calls(&self) -> Vec<(Principal, String, u128)> {
+            self.get_calls()
+                .into_iter()
+                .map(|(canister_id, method, _, payment)| (canister_id, method, payment))
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl CanisterCaller for RecordingCanisterCaller {
+        async fn call(
+            &self,
+            canister_id: Principal,
+            method: &str,
+            args: Vec<u8>,
+            payment: u128,
+        ) -> CallResult<Vec<u8>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((canister_id, method.to_string(), args, payment));
+            self.replies.get(method).cloned().ok_or((
+                RejectionCode::CanisterReject,
+                String::from("The call was recorded."),
+            ))
+        }
+
+        fn get_cycles_refunded(&self) -> u128 {
+            self.cycles_refunded
+        }
+    }
+
+    /// Makes the calls go through a new `RecordingCanisterCaller` rejecting all of them.
+    fn record_calls() -> Arc<RecordingCanisterCaller> {
+        let canister_caller = Arc::new(RecordingCanisterCaller::default());
+        set_canister_caller(Some(canister_caller.clone()));
+        canister_caller
+    }
+
+    fn record_reconciliation(reconciliation: CyclesReconciliation) {
+        RECONCILIATIONS.with(|reconciliations| reconciliations.borrow_mut().push(reconciliation));
+    }
+
+    /// Check that the reconciliation hook receives the attached and spent cycles of the calls.
+    #[test]
+    fn check_cycles_reconciliation_hook() {
+        assert_eq!(
+            reconcile_cycles(ManagementCanisterMethod::SignWithEcdsa, 10, 3),
+            7
+        );
+        set_cycles_reconciliation_hook(Some(record_reconciliation));
+        let cycles_attached = SIGN_34_NODE_COST_CYCLES;
+        assert_eq!(
+            reconcile_cycles(
+                ManagementCanisterMethod::SignWithEcdsa,
+                cycles_attached,
+                cycles_attached - SIGN_13_NODE_COST_CYCLES
+            ),
+            SIGN_13_NODE_COST_CYCLES
+        );
+        // The spent cycles are never negative, whatever the refunded cycles.
+        assert_eq!(
+            reconcile_cycles(ManagementCanisterMethod::BitcoinGetUtxos, 5, 8),
+            0
+        );
+        set_cycles_reconciliation_hook(None);
+        reconcile_cycles(ManagementCanisterMethod::BitcoinGetUtxos, 5, 0);
+
+        assert_eq!(
+            RECONCILIATIONS.with(|reconciliations| reconciliations.borrow().clone()),
+            vec![
+                CyclesReconciliation {
+                    method: ManagementCanisterMethod::SignWithEcdsa,
+                    cycles_attached,
+                    cycles_spent: SIGN_13_NODE_COST_CYCLES,
+                },
+                CyclesReconciliation {
+                    method: ManagementCanisterMethod::BitcoinGetUtxos,
+                    cycles_attached: 5,
+                    cycles_spent: 0,
+                },
+            ]
+        );
+    }
+
+    /// Check that the cycles set with `set_cycles_cost_config` are attached to the paid calls made with the arguments structures, and are restored from the state.
+    #[tokio::test]
+    async fn check_configured_cycles_attached() {
+        let mut bitcoin_agent =
+            agent::tests::new_mock(&crate::Network::Mainnet, &AddressType::P2pkh);
+        let cycles_cost_config = CyclesCostConfig {
+            get_utxos: 1,
+            get_fees: 2,
+            send_tx: 3,
+            send_tx_per_byte: 4,
+            sign_with_ecdsa: 5,
+            sign_with_schnorr: 6,
+        };
+        bitcoin_agent
+            .set_cycles_cost_config(cycles_cost_config)
+            .unwrap();
+        let bitcoin_agent: BitcoinAgent<crate::canister_mock::ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), crate::Network::Mainnet).unwrap();
+        assert_eq!(bitcoin_agent.get_cycles_cost_config(), cycles_cost_config);
+
+        let canister_caller = record_calls();
+        let main_address = &bitcoin_agent.get_main_address();
+        assert!(
+            get_utxos_from_args(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+                .await
+                .is_err()
+        );
+        assert!(
+            get_current_fees_from_args(bitcoin_agent.get_current_fees_args().unwrap())
+                .await
+                .is_err()
+        );
+        assert!(sign_message_from_args(
+            bitcoin_agent
+                .get_sign_message_args(main_address, b"message")
+                .unwrap()
+        )
+        .await
+        .is_err());
+        assert!(send_transaction(
+            vec![0; 10],
+            Network::Bitcoin,
+            cycles_cost_config,
+            &BitcoinApiTarget::ManagementCanister
+        )
+        .await
+        .is_err());
+        set_canister_caller(None);
+
+        let management_canister = Principal::management_canister();
+        assert_eq!(
+            canister_caller.get_paid_calls(),
+            [
+                (ManagementCanisterMethod::BitcoinGetUtxos, 1),
+                (ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles, 2),
+                (ManagementCanisterMethod::SignWithEcdsa, 5),
+                (ManagementCanisterMethod::BitcoinSendTransaction, 3 + 10 * 4),
+            ]
+            .into_iter()
+            .map(|(method, payment)| (management_canister, method.name().to_string(), payment))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    /// Check that the cycles costs set from a Candid blob are validated, saved in the state and attached to the calls made with the arguments structures returned afterwards.
+    #[test]
+    fn check_cycles_cost_config_updates() {
+        let mut bitcoin_agent =
+            agent::tests::new_mock(&crate::Network::Mainnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let default_cycles_cost_config = CyclesCostConfig::for_network(Network::Bitcoin);
+        let cycles_cost_config = CyclesCostConfig {
+            get_utxos: 2 * default_cycles_cost_config.get_utxos,
+            sign_with_ecdsa: 2 * default_cycles_cost_config.sign_with_ecdsa,
+            ..default_cycles_cost_config
+        };
+
+        bitcoin_agent
+            .set_cycles_cost_config_from_candid(&candid::encode_one(cycles_cost_config).unwrap())
+            .unwrap();
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_args(main_address, 0)
+                .unwrap()
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+        let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), crate::Network::Mainnet).unwrap();
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_args(main_address, 0)
+                .unwrap()
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+
+        // Invalid costs are rejected, the current ones being kept.
+        assert_eq!(
+            bitcoin_agent.set_cycles_cost_config(CyclesCostConfig {
+                get_fees: 0,
+                ..cycles_cost_config
+            }),
+            Err(InvalidCyclesCostConfig::ZeroCost {
+                cost: "get_fees".to_string()
+            })
+        );
+        assert_eq!(
+            bitcoin_agent.set_cycles_cost_config(CyclesCostConfig {
+                send_tx: DEFAULT_CYCLES_COST_CEILING + 1,
+                ..cycles_cost_config
+            }),
+            Err(InvalidCyclesCostConfig::AboveCeiling {
+                cost: "send_tx".to_string(),
+                cycles: DEFAULT_CYCLES_COST_CEILING + 1,
+                ceiling: DEFAULT_CYCLES_COST_CEILING
+            })
+        );
+        assert!(matches!(
+            bitcoin_agent.set_cycles_cost_config_from_candid(b"not Candid"),
+            Err(InvalidCyclesCostConfig::InvalidCandid(_))
+        ));
+        assert_eq!(bitcoin_agent.get_cycles_cost_config(), cycles_cost_config);
+
+        // A lower ceiling rejects the costs exceeding it.
+        bitcoin_agent.set_cycles_cost_ceiling(cycles_cost_config.sign_with_ecdsa - 1);
+        assert!(matches!(
+            bitcoin_agent.set_cycles_cost_config(cycles_cost_config),
+            Err(InvalidCyclesCostConfig::AboveCeiling { cost, .. }) if cost == "sign_with_ecdsa"
+        ));
+        bitcoin_agent
+            .set_cycles_cost_config(default_cycles_cost_config)
+            .unwrap();
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_args(main_address, 0)
+                .unwrap()
+                .cycles_cost_config,
+            default_cycles_cost_config
+        );
+    }
+
+    /// Check that the Bitcoin API calls made with the arguments structures go to the configured target while the signatures are still requested from the management canister.
+    #[tokio::test]
+    async fn check_bitcoin_api_target() {
+        let mut bitcoin_agent =
+            agent::tests::new_mock(&crate::Network::Regtest, &AddressType::P2pkh);
+        assert_eq!(
+            bitcoin_agent.get_bitcoin_api_target(),
+            BitcoinApiTarget::ManagementCanister
+        );
+        let bitcoin_canister = Principal::from_text("ghsi2-tqaaa-aaaan-aaaca-cai").unwrap();
+        let bitcoin_api_target = BitcoinApiTarget::Direct {
+            principal: bitcoin_canister,
+        };
+        bitcoin_agent.set_bitcoin_api_target(bitcoin_api_target.clone());
+        assert_eq!(bitcoin_agent.get_bitcoin_api_target(), bitcoin_api_target);
+
+        let canister_caller = record_calls();
+        let main_address = &bitcoin_agent.get_main_address();
+        assert!(
+            get_utxos_from_args(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+                .await
+                .is_err()
+        );
+        assert!(
+            get_current_fees_from_args(bitcoin_agent.get_current_fees_args().unwrap())
+                .await
+                .is_err()
+        );
+        assert!(sign_message_from_args(
+            bitcoin_agent
+                .get_sign_message_args(main_address, b"message")
+                .unwrap()
+        )
+        .await
+        .is_err());
+        assert!(send_transaction(
+            vec![0; 10],
+            Network::Regtest,
+            bitcoin_agent.get_cycles_cost_config(),
+            &bitcoin_api_target
+        )
+        .await
+        .is_err());
+        set_canister_caller(None);
+
+        assert_eq!(
+            canister_caller
+                .get_paid_calls()
+                .into_iter()
+                .map(|(canister_id, method, _)| (canister_id, method))
+                .collect::<Vec<_>>(),
+            [
+                (bitcoin_canister, ManagementCanisterMethod::BitcoinGetUtxos),
+                (
+                    bitcoin_canister,
+                    ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles
+                ),
+                (
+                    Principal::management_canister(),
+                    ManagementCanisterMethod::SignWithEcdsa
+                ),
+                (
+                    bitcoin_canister,
+                    ManagementCanisterMethod::BitcoinSendTransaction
+                ),
+            ]
+            .into_iter()
+            .map(|(canister_id, method)| (canister_id, method.name().to_string()))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    /// Check that the arguments structures and their results round trip through Candid, e.g. to be forwarded from a coordinator canister to a worker canister, the decoded arguments making the same calls with the same results.
+    #[tokio::test]
+    async fn check_args_candid_encoding() {
+        let mut bitcoin_agent =
+            agent::tests::new_mock(&crate::Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.set_bitcoin_api_target(BitcoinApiTarget::Direct {
+            principal: Principal::from_text("ghsi2-tqaaa-aaaan-aaaca-cai").unwrap(),
+        });
+        let main_address = &bitcoin_agent.get_main_address();
+        let get_utxos_response = management_canister_bitcoin::GetUtxosResponse {
+            utxos: vec![management_canister_bitcoin::Utxo {
+                outpoint: management_canister_bitcoin::Outpoint {
+                    txid: vec![1; 32],
+                    vout: 0,
+                },
+                value: 100_000,
+                height: 1,
+            }],
+            tip_block_hash: vec![0; 32],
+            tip_height: 1,
+            next_page: None,
+        };
+        let fees: Vec<MillisatoshiPerByte> = vec![1_000, 2_000];
+        let canister_caller = Arc::new(RecordingCanisterCaller {
+            replies: BTreeMap::from([
+                (
+                    ManagementCanisterMethod::BitcoinGetUtxos.name(),
+                    encode_args((get_utxos_response,)).unwrap(),
+                ),
+                (
+                    ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles.name(),
+                    encode_args((fees.clone(),)).unwrap(),
+                ),
+            ]),
+            ..Default::default()
+        });
+        set_canister_caller(Some(canister_caller.clone()));
+
+        let utxos_args = bitcoin_agent.get_utxos_args(main_address, 0).unwrap();
+        let decoded_utxos_args: UtxosArgs = decode_one(&encode_one(&utxos_args).unwrap()).unwrap();
+        assert_eq!(decoded_utxos_args, utxos_args);
+        let utxos_result = get_utxos_from_args(utxos_args).await.unwrap();
+        assert_eq!(
+            get_utxos_from_args(decoded_utxos_args).await.unwrap(),
+            utxos_result
+        );
+        let decoded_utxos_result: WithCost<UtxosResult> =
+            decode_one(&encode_one(&utxos_result).unwrap()).unwrap();
+        assert_eq!(decoded_utxos_result, utxos_result);
+
+        let current_fees_args = bitcoin_agent.get_current_fees_args().unwrap();
+        let decoded_current_fees_args: CurrentFeesArgs =
+            decode_one(&encode_one(&current_fees_args).unwrap()).unwrap();
+        assert_eq!(decoded_current_fees_args, current_fees_args);
+        assert_eq!(
+            get_current_fees_from_args(current_fees_args)
+                .await
+                .unwrap()
+                .result,
+            fees
+        );
+        assert_eq!(
+            get_current_fees_from_args(decoded_current_fees_args)
+                .await
+                .unwrap()
+                .result,
+            fees
+        );
+        set_canister_caller(None);
+
+        let calls = canister_caller.get_calls();
+        assert_eq!(calls.len(), 4);
+        assert_eq!(calls[0], calls[1]);
+        assert_eq!(calls[2], calls[3]);
+
+        let initialization_parameters_args = bitcoin_agent.get_initialization_parameters_args();
+        let decoded_initialization_parameters_args: InitializationParametersArgs =
+            decode_one(&encode_one(&initialization_parameters_args).unwrap()).unwrap();
+        assert_eq!(
+            decoded_initialization_parameters_args,
+            initialization_parameters_args
+        );
+        assert_eq!(
+            get_initialization_parameters_from_args(decoded_initialization_parameters_args)
+                .await
+                .unwrap(),
+            get_initialization_parameters_from_args(initialization_parameters_args)
+                .await
+                .unwrap()
+        );
+
+        // The log sink and the time source aren't encoded, hence they're replaced to compare the decoded arguments.
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &BTreeMap::from([(main_address.clone(), 10_000)]),
+            main_address,
+            Fee::Constant(1_000),
+            0,
+            false,
+        );
+        let decoded_multi_transfer_args: MultiTransferArgs =
+            decode_one(&encode_one(&multi_transfer_args).unwrap()).unwrap();
+        assert_eq!(
+            MultiTransferArgs {
+                log_sink: multi_transfer_args.log_sink.clone(),
+                time_source: multi_transfer_args.time_source.clone(),
+                ..decoded_multi_transfer_args
+            },
+            multi_transfer_args
+        );
+    }
+
+    /// Check the methods, the Candid-encoded requests and the cycles of the calls made by `ManagementCanisterImpl`, and the decoding of their replies.
+    #[tokio::test]
+    async fn check_management_canister_impl_calls() {
+        let mut management_canister = ManagementCanisterImpl::new(crate::Network::Regtest, None);
+        let cycles_cost_config = CyclesCostConfig {
+            get_utxos: 1,
+            get_fees: 2,
+            send_tx: 3,
+            send_tx_per_byte: 4,
+            sign_with_ecdsa: 5,
+            sign_with_schnorr: 6,
+        };
+        management_canister.set_cycles_cost_config(cycles_cost_config);
+        let fees: Vec<MillisatoshiPerByte> = vec![1_000, 2_000];
+        let signature = vec![1; 64];
+        let canister_caller = Arc::new(RecordingCanisterCaller {
+            replies: BTreeMap::from([
+                (
+                    ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles.name(),
+                    encode_args((fees.clone(),)).unwrap(),
+                ),
+                (
+                    ManagementCanisterMethod::SignWithEcdsa.name(),
+                    encode_args((SignWithECDSAReply {
+                        signature: signature.clone(),
+                    },))
+                    .unwrap(),
+                ),
+                (
+                    ManagementCanisterMethod::BitcoinSendTransaction.name(),
+                    encode_args(()).unwrap(),
+                ),
+            ]),
+            cycles_refunded: 1,
+            ..Default::default()
+        });
+        set_canister_caller(Some(canister_caller.clone()));
+
+        let address = Address::from_str("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn")
+            .unwrap()
+            .assume_checked();
+        assert_eq!(
+            management_canister.get_utxos(&address, 2).await,
+            Err(GetUtxosError::ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                String::from("The call was recorded.")
+            ))
+        );
+        assert_eq!(management_canister.get_current_fees().await, Ok(fees));
+        let derivation_path = DerivationPath::new(vec![vec![0, 1]]).unwrap();
+        let message_hash = vec![7; 32];
+        assert_eq!(
+            management_canister
+                .sign_with_ecdsa(&derivation_path, &message_hash)
+                .await,
+            Ok(signature)
+        );
+        let transaction = vec![0; 10];
+        assert_eq!(
+            management_canister
+                .send_transaction(transaction.clone(), Network::Regtest)
+                .await,
+            Ok(())
+        );
+        // The spent cycles are the attached ones minus the refunded ones.
+        let bitcoin_api_target = BitcoinApiTarget::ManagementCanister;
+        assert_eq!(
+            get_current_fees(Network::Regtest, cycles_cost_config, &bitcoin_api_target)
+                .await
+                .unwrap()
+                .cycles_spent,
+            2 - 1
+        );
+        // The signet isn't served by the Bitcoin API of the IC, hence the call isn't made.
+        let signet_management_canister = ManagementCanisterImpl::new(crate::Network::Signet, None);
+        assert_eq!(
+            signet_management_canister.get_current_fees().await,
+            Err(ManagementCanisterReject::from(crate::UnsupportedNetwork(
+                crate::Network::Signet
+            )))
+        );
+        set_canister_caller(None);
+
+        let management_canister_id = Principal::management_canister();
+        let network =
+            from_bitcoin_network_to_management_canister_network(Network::Regtest).unwrap();
+        let get_current_fees_call = (
+            management_canister_id,
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles
+                .name()
+                .to_string(),
+            encode_args((GetCurrentFeePercentilesRequest { network },)).unwrap(),
+            2,
+        );
+        assert_eq!(
+            canister_caller.get_calls(),
+            vec![
+                (
+                    management_canister_id,
+                    ManagementCanisterMethod::BitcoinGetUtxos.name().to_string(),
+                    encode_args((GetUtxosRequest {
+                        address: address.to_string(),
+                        network,
+                        filter: Some(MinConfirmations(2)),
+                    },))
+                    .unwrap(),
+                    1
+                ),
+                get_current_fees_call.clone(),
+                (
+                    management_canister_id,
+                    ManagementCanisterMethod::SignWithEcdsa.name().to_string(),
+                    encode_args((SignWithECDSA {
+                        message_hash,
+                        derivation_path: derivation_path.into(),
+                        key_id: EcdsaKeyId {
+                            curve: EcdsaCurve::Secp256k1,
+                            name: management_canister.get_ecdsa_key_name(),
+                        },
+                    },))
+                    .unwrap(),
+                    5
+                ),
+                (
+                    management_canister_id,
+                    ManagementCanisterMethod::BitcoinSendTransaction
+                        .name()
+                        .to_string(),
+                    encode_args((SendTransactionRequest {
+                        transaction,
+                        network,
+                    },))
+                    .unwrap(),
+                    3 + 10 * 4
+                ),
+                get_current_fees_call,
+            ]
+        );
+    }
+
+    /// Check that Bitcoin agents of different networks are held behind the same type by boxing their management canisters, the calls being forwarded to the boxed ones.
+    #[test]
+    fn check_boxed_management_canister() {
+        let regtest_bitcoin_agent =
+            agent::tests::new_mock(&crate::Network::Regtest, &AddressType::P2pkh);
+        let regtest_main_address = regtest_bitcoin_agent.get_main_address();
+        let mainnet_management_canister: Box<dyn ManagementCanister> =
+            Box::new(ManagementCanisterMock::new(crate::Network::Mainnet, None));
+        let bitcoin_agents: Vec<BoxedBitcoinAgent> = vec![
+            BitcoinAgent::<ManagementCanisterMock>::from_state(
+                regtest_bitcoin_agent.get_state(),
+                crate::Network::Regtest,
+            )
+            .unwrap()
+            .into_boxed(),
+            BitcoinAgent::new(mainnet_management_canister, &AddressType::P2pkh, 0).unwrap(),
+        ];
+
+        assert_eq!(
+            bitcoin_agents
+                .iter()
+                .map(|bitcoin_agent| bitcoin_agent.network())
+                .collect::<Vec<_>>(),
+            vec![crate::Network::Regtest, crate::Network::Mainnet]
+        );
+        assert_eq!(bitcoin_agents[0].get_main_address(), regtest_main_address);
+        assert_eq!(
+            bitcoin_agents[1].get_cycles_cost_config(),
+            CyclesCostConfig::for_network(Network::Bitcoin)
+        );
+    }
+}
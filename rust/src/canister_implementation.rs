@@ -1,10 +1,14 @@
 use crate::{
-    canister_common::ManagementCanister,
+    block_management,
+    canister_common::{
+        ManagementCanister, ManagementCanisterFactory, GET_BLOCK_HEADERS_COST_CYCLES,
+    },
     ecdsa,
-    ecdsa::get_key_name_from_network,
-    transaction_management,
-    types::{from_types_network_to_bitcoin_network, GetUtxosResponse},
-    utxo_management, EcdsaPubKey, GetUtxosError, ManagementCanisterReject, MillisatoshiPerByte,
+    ecdsa::get_key_name,
+    schnorr, transaction_management,
+    types::{from_types_network_to_bitcoin_network, GetBlockHeadersResponse, GetUtxosResponse},
+    utxo_management, BitcoinApiTarget, CyclesCostConfig, DerivationPath, EcdsaPubKey,
+    GetUtxosError, ManagementCanisterReject, MillisatoshiPerByte, NetworkOverride,
 };
 use async_trait::async_trait;
 use bitcoin::{Address, Network};
@@ -14,30 +18,60 @@ use bitcoin::{Address, Network};
 pub struct ManagementCanisterImpl {
     network: Network,
     ecdsa_public_key: EcdsaPubKey,
+    ecdsa_key_name: String,
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: BitcoinApiTarget,
 }
 
-#[async_trait]
-impl ManagementCanister for ManagementCanisterImpl {
+impl ManagementCanisterImpl {
+    /// Creates a new instance of the real management canister interacting with the network of `network_override` if any, with `network` otherwise.
+    /// E.g. `NetworkOverride(Network::Regtest)` lets a canister configured for the testnet be tested locally against a regtest node.
+    pub fn new_with_network_override(
+        network: crate::Network,
+        ecdsa_key_name: Option<String>,
+        network_override: Option<NetworkOverride>,
+    ) -> Self {
+        let network = match network_override {
+            Some(NetworkOverride(network)) => network,
+            None => network,
+        };
+        Self::new(network, ecdsa_key_name)
+    }
+}
+
+impl ManagementCanisterFactory for ManagementCanisterImpl {
     /// Creates a new instance of the real management canister.
-    fn new(network: crate::Network) -> Self {
+    fn new(network: crate::Network, ecdsa_key_name: Option<String>) -> Self {
         Self::new_using_ecdsa_public_key(
             network,
             EcdsaPubKey {
                 public_key: vec![],
                 chain_code: vec![],
-                derivation_path: vec![],
+                derivation_path: DerivationPath::default(),
             },
+            ecdsa_key_name,
         )
     }
 
     /// Creates a new instance of the real management canister using the given ECDSA public key.
-    fn new_using_ecdsa_public_key(network: crate::Network, ecdsa_public_key: EcdsaPubKey) -> Self {
+    fn new_using_ecdsa_public_key(
+        network: crate::Network,
+        ecdsa_public_key: EcdsaPubKey,
+        ecdsa_key_name: Option<String>,
+    ) -> Self {
+        let network = from_types_network_to_bitcoin_network(network);
         Self {
-            network: from_types_network_to_bitcoin_network(network),
+            network,
             ecdsa_public_key,
+            ecdsa_key_name: get_key_name(network, ecdsa_key_name),
+            cycles_cost_config: CyclesCostConfig::for_network(network),
+            bitcoin_api_target: BitcoinApiTarget::default(),
         }
     }
+}
 
+#[async_trait]
+impl ManagementCanister for ManagementCanisterImpl {
     /// Initializes the management canister by initializing its ECDSA public key.
     fn set_ecdsa_public_key(&mut self, ecdsa_public_key: EcdsaPubKey) {
         self.ecdsa_public_key = ecdsa_public_key;
@@ -53,6 +87,31 @@ impl ManagementCanister for ManagementCanisterImpl {
         self.ecdsa_public_key.clone()
     }
 
+    /// Returns the name of the threshold ECDSA key used by this canister.
+    fn get_ecdsa_key_name(&self) -> String {
+        self.ecdsa_key_name.clone()
+    }
+
+    /// Returns the cycles attached to the paid calls to the management canister, which default to the ones of the network.
+    fn get_cycles_cost_config(&self) -> CyclesCostConfig {
+        self.cycles_cost_config
+    }
+
+    /// Sets the cycles attached to the paid calls to the management canister, e.g. when the key is held by another subnet than the default one.
+    fn set_cycles_cost_config(&mut self, cycles_cost_config: CyclesCostConfig) {
+        self.cycles_cost_config = cycles_cost_config;
+    }
+
+    /// Returns the canister serving the calls to the Bitcoin API, the management canister by default.
+    fn get_bitcoin_api_target(&self) -> BitcoinApiTarget {
+        self.bitcoin_api_target.clone()
+    }
+
+    /// Sets the canister serving the calls to the Bitcoin API, e.g. to call a Bitcoin canister directly.
+    fn set_bitcoin_api_target(&mut self, bitcoin_api_target: BitcoinApiTarget) {
+        self.bitcoin_api_target = bitcoin_api_target;
+    }
+
     /// Returns the UTXOs of the given Bitcoin `address` according to `min_confirmations`.
     /// This getter always return the same value until a block, with transactions concerning the address, is mined.
     async fn get_utxos(
@@ -60,34 +119,90 @@ impl ManagementCanister for ManagementCanisterImpl {
         address: &Address,
         min_confirmations: u32,
     ) -> Result<GetUtxosResponse, GetUtxosError> {
-        utxo_management::get_utxos(self.network, address, min_confirmations).await
+        utxo_management::get_utxos(
+            self.network,
+            address,
+            min_confirmations,
+            self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+        .await
+        .map(|get_utxos_response| get_utxos_response.result)
     }
 
     /// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions.
     async fn get_current_fees(&self) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject> {
-        transaction_management::get_current_fees(self.get_network()).await
+        transaction_management::get_current_fees(
+            self.get_network(),
+            self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+        .await
+        .map(|fees| fees.result)
+    }
+
+    /// Returns the tip height and the block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`.
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> Result<GetBlockHeadersResponse, ManagementCanisterReject> {
+        block_management::get_block_headers(
+            self.network,
+            start_height,
+            end_height,
+            GET_BLOCK_HEADERS_COST_CYCLES,
+            &self.bitcoin_api_target,
+        )
+        .await
+        .map(|block_headers| block_headers.result)
     }
 
     /// Returns the signature of the given `message_hash` associated with the ECDSA public key of this canister at the given derivation path.
     async fn sign_with_ecdsa(
         &self,
-        derivation_path: &[Vec<u8>],
+        derivation_path: &DerivationPath,
         message_hash: &[u8],
     ) -> Result<Vec<u8>, ManagementCanisterReject> {
         ecdsa::sign_with_ecdsa(
-            get_key_name_from_network(self.network),
-            derivation_path.to_vec(),
+            self.get_ecdsa_key_name(),
+            derivation_path.clone(),
             message_hash.to_vec(),
+            self.cycles_cost_config,
         )
         .await
+        .map(|signature| signature.result)
+    }
+
+    /// Returns the BIP-340 signature of the given `message` associated with the Schnorr public key of this canister at the given derivation path, tweaked as per BIP-86.
+    async fn sign_with_schnorr(
+        &self,
+        derivation_path: &DerivationPath,
+        message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        schnorr::sign_with_schnorr(
+            self.get_ecdsa_key_name(),
+            derivation_path.clone(),
+            message.to_vec(),
+            self.cycles_cost_config,
+        )
+        .await
+        .map(|signature| signature.result)
     }
 
     /// Sends the given transaction to the network the management canister interacts with.
     async fn send_transaction(
-        &mut self,
+        &self,
         transaction: Vec<u8>,
         network: Network,
     ) -> Result<(), ManagementCanisterReject> {
-        transaction_management::send_transaction(transaction, network).await
+        transaction_management::send_transaction(
+            transaction,
+            network,
+            self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+        .await
+        .map(|sent| sent.result)
     }
 }
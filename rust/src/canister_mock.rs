@@ -1,52 +1,476 @@
 use crate::{
-    address_management::{get_main_address, tests::derive_child_private_key},
-    canister_common::ManagementCanister,
-    types::{from_types_network_to_bitcoin_network, GetUtxosResponse},
-    utxo_management::has_utxo_min_confirmations,
-    AddressType, BalanceUpdate, BitcoinAgent, EcdsaPubKey, Fee, GetUtxosError,
-    ManagementCanisterReject, MillisatoshiPerByte, OutPoint, Satoshi, TransactionInfo, Utxo,
-    UtxosUpdate, MIN_CONFIRMATIONS_UPPER_BOUND,
+    address_management::get_main_address,
+    bip32_extended_derivation::derive_child_private_key,
+    canister_common::{CanisterCaller, ManagementCanister, ManagementCanisterFactory},
+    ecdsa::get_key_name,
+    logging::format_derivation_path,
+    metrics,
+    transaction_management::{mock_signer, TransferCalls},
+    types::{
+        from_bitcoin_network_to_types_network, from_types_network_to_bitcoin_network,
+        GetBalanceRequest, GetBlockHeadersRequest, GetBlockHeadersResponse, GetUtxosResponse,
+        SignWithECDSA, SignWithECDSAReply, SignWithSchnorr, SignWithSchnorrReply,
+    },
+    upgrade_management::get_address,
+    utxo_management::{has_utxo_min_confirmations, UtxosPages},
+    AddressType, BitcoinAgent, BitcoinApiTarget, CyclesCostConfig, DerivationPath, EcdsaPubKey,
+    GetUtxosError, ManagementCanisterMethod, ManagementCanisterReject, MillisatoshiPerByte,
+    MultiTransferArgs, MultiTransferError, OutPoint, RetryPolicy, Satoshi, TimeSource, Utxo,
+    WithCost, DEFAULT_RETRY_POLICY, MIN_CONFIRMATIONS_UPPER_BOUND,
 };
+#[cfg(test)]
+use crate::{BalanceUpdate, Fee, TransactionInfo, UtxosUpdate};
 use async_trait::async_trait;
 use bitcoin::{
-    psbt::serialize::Deserialize,
-    secp256k1::{Message, Secp256k1, SecretKey},
-    Address, Network, Transaction,
+    absolute::LockTime,
+    block::{Header as BlockHeader, Version},
+    blockdata::script::{Builder, PushBytesBuf},
+    consensus::{deserialize, serialize},
+    hash_types::TxMerkleNode,
+    hashes::{sha256, Hash},
+    key::TapTweak,
+    secp256k1::{KeyPair, Message, Secp256k1, SecretKey},
+    Address, BlockHash, CompactTarget, Network, PrivateKey, Sequence, Transaction, TxIn, TxOut,
+    Txid, Witness,
 };
-use std::collections::BTreeMap;
+use candid::{
+    utils::{decode_args, encode_args},
+    CandidType, Deserialize, Principal,
+};
+use ic_cdk::api::{
+    call::{CallResult, RejectionCode},
+    management_canister::bitcoin::{
+        self as management_canister_bitcoin, GetCurrentFeePercentilesRequest, GetUtxosRequest,
+        SendTransactionRequest, UtxoFilter,
+    },
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+#[cfg(test)]
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+/// The length in bytes of a consensus encoded block header.
+#[cfg(test)]
+pub(crate) const BLOCK_HEADER_LENGTH: usize = 80;
+
+/// The time of a new mock chain, 2022-01-01T00:00:00Z in nanoseconds since the UNIX epoch, so that the tests are deterministic.
+pub const MOCK_START_TIME: u64 = 1_640_995_200_000_000_000;
+
+// A private key in WIF (wallet import format). This is only for testing purposes.
+const BTC_PRIVATE_KEY_WIF: &str = "L2C1QgyKqNgfV7BpEPAm6PVn2xW8zpXq6MojSbWdH18nGQF2wGsT";
+
+/// Returns the Bitcoin private key signing for the mock.
+pub(crate) fn get_btc_private_key() -> PrivateKey {
+    PrivateKey::from_wif(BTC_PRIVATE_KEY_WIF).unwrap()
+}
+
+/// Returns the ECDSA public key matching the signatures of the mock.
+pub fn get_mock_ecdsa_public_key() -> EcdsaPubKey {
+    EcdsaPubKey {
+        public_key: get_btc_private_key()
+            .public_key(&Secp256k1::new())
+            .to_bytes(),
+        chain_code: vec![],
+        derivation_path: DerivationPath::default(),
+    }
+}
+
+/// Creates a new instance of the Bitcoin agent using the management canister mock, initialized with the ECDSA public key of the mock.
+/// Its main address is funded with a UTXO of 250,000 satoshis with `MIN_CONFIRMATIONS_UPPER_BOUND` confirmations.
+pub fn new_mock(
+    network: &crate::Network,
+    main_address_type: &AddressType,
+) -> BitcoinAgent<ManagementCanisterMock> {
+    let ecdsa_public_key = get_mock_ecdsa_public_key();
+    let mut bitcoin_agent = BitcoinAgent::new(
+        ManagementCanisterMock::new_using_ecdsa_public_key_test(
+            *network,
+            ecdsa_public_key.clone(),
+            *main_address_type,
+        ),
+        main_address_type,
+        0,
+    )
+    .unwrap();
+    bitcoin_agent.initialize(ecdsa_public_key);
+    bitcoin_agent
+}
+
+/// Creates a new instance of the Bitcoin agent using a management canister mock simulating `chain`, e.g. the one of another Bitcoin agent created with `new_mock`.
+/// It's initialized with the ECDSA public key of the mock, so that the main address type has to differ from the one of the other Bitcoin agent for their main addresses to differ.
+pub fn new_mock_sharing(
+    chain: Arc<Mutex<MockChain>>,
+    network: &crate::Network,
+    main_address_type: &AddressType,
+) -> BitcoinAgent<ManagementCanisterMock> {
+    let ecdsa_public_key = get_mock_ecdsa_public_key();
+    let mut bitcoin_agent = BitcoinAgent::new(
+        ManagementCanisterMock::new_sharing(chain, *network, ecdsa_public_key.clone(), None),
+        main_address_type,
+        0,
+    )
+    .unwrap();
+    bitcoin_agent.initialize(ecdsa_public_key);
+    bitcoin_agent
+}
+
+/// Reads the logical clock of a mock chain, the time source of the Bitcoin agents using the management canister mock.
+struct MockTimeSource(Arc<Mutex<MockChain>>);
+
+impl TimeSource for MockTimeSource {
+    fn now(&self) -> u64 {
+        self.0.lock().unwrap().time
+    }
+}
+
+/// A block mined by `mine_block`, kept to undo it when the block is invalidated.
+struct MinedBlock {
+    /// The tip height once the block was mined.
+    tip_height: u32,
+    transactions: Vec<Transaction>,
+    /// The UTXOs spent by the transactions of the block along with their address.
+    spent_utxos: Vec<(Address, Utxo)>,
+}
+
+/// The simulated chain of the mock, shared by the mocks created with `ManagementCanisterMock::new_sharing`, e.g. to test Bitcoin agents paying each other.
+/// It is shared behind a mutex rather than a `RefCell` as the management canisters are `Send + Sync`.
+pub struct MockChain {
+    pub(crate) utxos_addresses: BTreeMap<Address, Vec<Utxo>>,
+    pub(crate) tip_height: u32,
+    /// The sent transactions not mined yet.
+    pending_transactions: Vec<Transaction>,
+    /// The blocks mined by `mine_block`, in order.
+    mined_blocks: Vec<MinedBlock>,
+    fee_percentiles: Vec<MillisatoshiPerByte>,
+    /// The fee percentiles returned from a given tip height on, scheduled with `schedule_fee_change`.
+    scheduled_fee_changes: BTreeMap<u32, Vec<MillisatoshiPerByte>>,
+    /// The number of txids returned by `generate_txid`.
+    generated_txids_count: u64,
+    /// The logical clock of the mock chain in nanoseconds since the UNIX epoch, which only advances with `advance_time` and `mine_block`.
+    time: u64,
+    /// The nanoseconds the clock advances by when a block is mined.
+    block_interval: u64,
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self {
+            utxos_addresses: BTreeMap::default(),
+            tip_height: MIN_CONFIRMATIONS_UPPER_BOUND,
+            pending_transactions: vec![],
+            mined_blocks: vec![],
+            fee_percentiles: (1_000..100_000).step_by(1_000).collect(),
+            scheduled_fee_changes: BTreeMap::default(),
+            generated_txids_count: 0,
+            time: MOCK_START_TIME,
+            block_interval: 0,
+        }
+    }
+}
+
+impl MockChain {
+    /// Returns the UTXOs of `address` with at least `min_confirmations` confirmations.
+    /// Like a node, with `min_confirmations = 0`, the unconfirmed outputs of the pending transactions are included and the outputs they spend are excluded.
+    /// The unconfirmed outputs have the tip height, the one `mine_block` confirms them at, so that they are unchanged once mined.
+    fn get_utxos(
+        &self,
+        network: Network,
+        address: &Address,
+        min_confirmations: u32,
+    ) -> GetUtxosResponse {
+        let mut utxos: Vec<Utxo> = self
+            .utxos_addresses
+            .get(address)
+            .unwrap_or(&vec![])
+            .iter()
+            .filter(|utxo| has_utxo_min_confirmations(utxo, self.tip_height, min_confirmations))
+            .cloned()
+            .collect();
+        if min_confirmations == 0 {
+            let spent_outpoints: HashSet<OutPoint> = self
+                .pending_transactions
+                .iter()
+                .flat_map(|transaction| {
+                    transaction
+                        .input
+                        .iter()
+                        .map(|input| get_outpoint_ic_type(input.previous_output))
+                })
+                .collect();
+            utxos.retain(|utxo| !spent_outpoints.contains(&utxo.outpoint));
+            for transaction in self.pending_transactions.iter() {
+                let txid = transaction.txid().to_byte_array().to_vec();
+                for (vout, output) in transaction.output.iter().enumerate() {
+                    let outpoint = OutPoint {
+                        txid: txid.clone(),
+                        vout: vout as u32,
+                    };
+                    if Address::from_script(&output.script_pubkey, network)
+                        .ok()
+                        .as_ref()
+                        == Some(address)
+                        && !spent_outpoints.contains(&outpoint)
+                    {
+                        utxos.push(Utxo {
+                            outpoint,
+                            value: output.value,
+                            height: self.tip_height,
+                        });
+                    }
+                }
+            }
+        }
+        GetUtxosResponse {
+            utxos,
+            tip_height: self.tip_height,
+        }
+    }
+
+    /// Applies the fee changes scheduled up to the tip height, the last one taking effect.
+    fn apply_scheduled_fee_changes(&mut self) {
+        let later_fee_changes = self.scheduled_fee_changes.split_off(&(self.tip_height + 1));
+        let due_fee_changes = std::mem::replace(&mut self.scheduled_fee_changes, later_fee_changes);
+        if let Some((_, fee_percentiles)) = due_fee_changes.into_iter().next_back() {
+            self.fee_percentiles = fee_percentiles;
+        }
+    }
+
+    /// Returns a new txid, the SHA-256 hash of the number of txids generated so far.
+    fn generate_txid(&mut self) -> Vec<u8> {
+        let txid = sha256::Hash::hash(&self.generated_txids_count.to_be_bytes())
+            .to_byte_array()
+            .to_vec();
+        self.generated_txids_count += 1;
+        txid
+    }
+
+    /// Sends `value` satoshis to `address` with a transaction spending a synthetic coinbase input, which stays pending until `mine_block` confirms it, and returns its txid.
+    fn send_to_address(&mut self, address: &Address, value: Satoshi) -> Txid {
+        // The coinbase script holds a generated txid, like the height of BIP 34, so that the txids differ.
+        let transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: bitcoin::OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_slice(PushBytesBuf::try_from(self.generate_txid()).unwrap())
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let txid = transaction.txid();
+        self.pending_transactions.push(transaction);
+        txid
+    }
+
+    /// Pays `value` satoshis to `address` with a new UTXO confirmed at the tip height, and returns its outpoint.
+    fn fund_address(&mut self, address: &Address, value: Satoshi) -> OutPoint {
+        let outpoint = OutPoint {
+            txid: self.generate_txid(),
+            vout: 0,
+        };
+        self.utxos_addresses
+            .entry(address.clone())
+            .or_default()
+            .push(Utxo {
+                outpoint: outpoint.clone(),
+                value,
+                height: self.tip_height,
+            });
+        outpoint
+    }
+
+    /// Mines the pending transactions in a block, their generated UTXOs being confirmed at the current tip height, which is then incremented.
+    fn mine_block(&mut self, network: Network) {
+        let pending_transactions = std::mem::take(&mut self.pending_transactions);
+        let mut spent_utxos = vec![];
+        pending_transactions.iter().for_each(|transaction| {
+            // Consumes UTXOs from the given transaction inputs.
+            transaction.input.iter().for_each(|input| {
+                let outpoint_ic_type = get_outpoint_ic_type(input.previous_output);
+                self.utxos_addresses
+                    .iter_mut()
+                    .for_each(|(address, address_utxos)| {
+                        address_utxos.retain(|utxo| {
+                            if utxo.outpoint == outpoint_ic_type {
+                                spent_utxos.push((address.clone(), utxo.clone()));
+                                false
+                            } else {
+                                true
+                            }
+                        });
+                    });
+            });
+            let tx_id = transaction.txid().to_byte_array().to_vec();
+            // Generates UTXOs from the given transaction outputs.
+            transaction
+                .output
+                .iter()
+                .enumerate()
+                .for_each(|(outputs_index, output)| {
+                    let address = Address::from_script(&output.script_pubkey, network).unwrap();
+                    let new_utxo = Utxo {
+                        outpoint: OutPoint {
+                            txid: tx_id.clone(),
+                            vout: outputs_index as u32,
+                        },
+                        value: output.value,
+                        height: self.tip_height,
+                    };
+                    self.utxos_addresses
+                        .entry(address)
+                        .or_default()
+                        .push(new_utxo);
+                });
+        });
+        self.tip_height += 1;
+        self.time += self.block_interval;
+        self.apply_scheduled_fee_changes();
+        self.mined_blocks.push(MinedBlock {
+            tip_height: self.tip_height,
+            transactions: pending_transactions,
+            spent_utxos,
+        });
+    }
+
+    /// Rewinds the chain by `depth` blocks, the transactions mined in these blocks returning to the pending transactions.
+    fn invalidate_blocks(&mut self, depth: u32) {
+        assert!(
+            depth <= self.tip_height,
+            "Can't invalidate {} blocks with the tip height {}.",
+            depth,
+            self.tip_height
+        );
+        let tip_height = self.tip_height - depth;
+        let mut orphaned_transactions = vec![];
+        while self
+            .mined_blocks
+            .last()
+            .is_some_and(|mined_block| mined_block.tip_height > tip_height)
+        {
+            let mined_block = self.mined_blocks.pop().unwrap();
+            // The spent UTXOs are restored first, as some of them may have been generated by a transaction of the same block.
+            for (address, utxo) in mined_block.spent_utxos {
+                self.utxos_addresses.entry(address).or_default().push(utxo);
+            }
+            let txids: BTreeSet<Vec<u8>> = mined_block
+                .transactions
+                .iter()
+                .map(|transaction| transaction.txid().to_byte_array().to_vec())
+                .collect();
+            self.utxos_addresses
+                .values_mut()
+                .for_each(|utxos| utxos.retain(|utxo| !txids.contains(&utxo.outpoint.txid)));
+            orphaned_transactions.splice(0..0, mined_block.transactions);
+        }
+        self.utxos_addresses
+            .values_mut()
+            .for_each(|utxos| utxos.retain(|utxo| utxo.height <= tip_height));
+        self.tip_height = tip_height;
+        orphaned_transactions.append(&mut self.pending_transactions);
+        self.pending_transactions = orphaned_transactions;
+    }
+}
+
+/// The operations of the mock made to fail with `ManagementCanisterMock::inject_failure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockOp {
+    GetUtxos,
+    GetFees,
+    /// The ECDSA and Schnorr signatures.
+    Sign,
+    SendTransaction,
+}
+
+impl MockOp {
+    /// Returns the management canister methods simulated by the operation.
+    fn get_methods(self) -> &'static [ManagementCanisterMethod] {
+        match self {
+            MockOp::GetUtxos => &[ManagementCanisterMethod::BitcoinGetUtxos],
+            MockOp::GetFees => &[ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles],
+            MockOp::Sign => &[
+                ManagementCanisterMethod::SignWithEcdsa,
+                ManagementCanisterMethod::SignWithSchnorr,
+            ],
+            MockOp::SendTransaction => &[ManagementCanisterMethod::BitcoinSendTransaction],
+        }
+    }
+}
 
 /// The management canister mock is used to perform unit tests against the library.
+/// With the `mock` feature, it is also available to the tests of downstream canisters, e.g. to simulate chain reorganizations with `invalidate_blocks` and `fork_and_replace`.
 pub struct ManagementCanisterMock {
-    pub(crate) utxos_addresses: BTreeMap<Address, Vec<Utxo>>,
     network: Network,
     ecdsa_public_key: EcdsaPubKey,
-    pub(crate) tip_height: u32,
-    pending_transactions: Vec<Transaction>,
+    ecdsa_key_name: String,
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: BitcoinApiTarget,
+    /// The simulated chain, possibly shared with other mocks.
+    chain: Arc<Mutex<MockChain>>,
+    /// Makes `multi_transfer` compute actual ECDSA signatures with a high S value instead of rubber-stamping them.
+    pub(crate) high_s_signatures: bool,
+    /// The simulated latency of each signature call made by `multi_transfer`.
+    #[cfg(test)]
+    pub(crate) sign_latency: Duration,
+    #[cfg(test)]
+    pending_signatures: AtomicU32,
+    /// The maximum number of signature calls that were pending at the same time.
+    #[cfg(test)]
+    pub(crate) max_pending_signatures: AtomicU32,
+    /// The rejections planned with `reject_next_call`, shared with the canister callers of the mock.
+    rejections: Arc<Mutex<BTreeMap<ManagementCanisterMethod, VecDeque<ManagementCanisterReject>>>>,
+    /// The number of remaining rejections of the signatures with given derivation paths, planned with `fail_sign_for_paths`.
+    sign_path_failures: Arc<Mutex<BTreeMap<Vec<Vec<u8>>, u32>>>,
+    /// The number of UTXOs per page returned by `get_utxos`, all the UTXOs being returned in a single page if `None`.
+    page_size: Option<usize>,
+    /// The maximum number of UTXOs of a page returned by `get_utxos`, larger pages being rejected like the responses exceeding the size limit of the inter-canister responses, unlimited if `None`.
+    max_response_utxos: Option<usize>,
 }
 
-#[async_trait]
-impl ManagementCanister for ManagementCanisterMock {
+impl ManagementCanisterFactory for ManagementCanisterMock {
     /// Creates a new instance of the management canister mock.
-    fn new(network: crate::Network) -> Self {
+    fn new(network: crate::Network, ecdsa_key_name: Option<String>) -> Self {
         Self::new_using_ecdsa_public_key(
             network,
             EcdsaPubKey {
                 public_key: vec![],
                 chain_code: vec![],
-                derivation_path: vec![],
+                derivation_path: DerivationPath::default(),
             },
+            ecdsa_key_name,
         )
     }
 
     /// Creates a new instance of the management canister mock using a given ECDSA public key.
-    fn new_using_ecdsa_public_key(network: crate::Network, ecdsa_public_key: EcdsaPubKey) -> Self {
-        ManagementCanisterMock::new_using_ecdsa_public_key_test(
+    fn new_using_ecdsa_public_key(
+        network: crate::Network,
+        ecdsa_public_key: EcdsaPubKey,
+        ecdsa_key_name: Option<String>,
+    ) -> Self {
+        let mut management_canister = ManagementCanisterMock::new_using_ecdsa_public_key_test(
             network,
             ecdsa_public_key,
             AddressType::P2pkh,
-        )
+        );
+        if let Some(ecdsa_key_name) = ecdsa_key_name {
+            management_canister.ecdsa_key_name = ecdsa_key_name;
+        }
+        management_canister
     }
+}
 
+#[async_trait]
+impl ManagementCanister for ManagementCanisterMock {
     /// Initializes the management canister by initializing its ECDSA public key.
     fn set_ecdsa_public_key(&mut self, ecdsa_public_key: EcdsaPubKey) {
         self.ecdsa_public_key = ecdsa_public_key;
@@ -62,14 +486,55 @@ impl ManagementCanister for ManagementCanisterMock {
         self.ecdsa_public_key.clone()
     }
 
-    /// Returns the mock UTXOs of the canister address according to `min_confirmations`.
-    /// Note: `address` is ignored for simplicity purpose.
+    /// Returns the name of the threshold ECDSA key used by this canister.
+    fn get_ecdsa_key_name(&self) -> String {
+        self.ecdsa_key_name.clone()
+    }
+
+    /// Returns the cycles attached to the paid calls to the management canister.
+    fn get_cycles_cost_config(&self) -> CyclesCostConfig {
+        self.cycles_cost_config
+    }
+
+    /// Sets the cycles attached to the paid calls to the management canister.
+    fn set_cycles_cost_config(&mut self, cycles_cost_config: CyclesCostConfig) {
+        self.cycles_cost_config = cycles_cost_config;
+    }
+
+    /// Returns the canister serving the calls to the Bitcoin API.
+    fn get_bitcoin_api_target(&self) -> BitcoinApiTarget {
+        self.bitcoin_api_target.clone()
+    }
+
+    /// Sets the canister serving the calls to the Bitcoin API.
+    /// Note: the mock serves the calls itself whatever the target.
+    fn set_bitcoin_api_target(&mut self, bitcoin_api_target: BitcoinApiTarget) {
+        self.bitcoin_api_target = bitcoin_api_target;
+    }
+
+    /// Returns the logical clock of the mock chain, see `advance_time`.
+    fn get_time_source(&self) -> Arc<dyn TimeSource> {
+        Arc::new(MockTimeSource(self.get_chain()))
+    }
+
+    /// Returns the UTXOs of the given Bitcoin `address` according to `min_confirmations`, assembled from their pages.
     async fn get_utxos(
         &self,
-        _address: &Address,
-        _min_confirmations: u32,
+        address: &Address,
+        min_confirmations: u32,
     ) -> Result<GetUtxosResponse, GetUtxosError> {
-        unreachable!()
+        if min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
+            return Err(GetUtxosError::MinConfirmationsTooHigh);
+        }
+        self.internal_reject_oversized_page(address, min_confirmations)
+            .and_then(|()| {
+                self.internal_reject(
+                    ManagementCanisterMethod::BitcoinGetUtxos,
+                    self.cycles_cost_config.get_utxos,
+                )
+            })
+            .map_err(GetUtxosError::from)?;
+        Ok(self.internal_get_utxos(address, min_confirmations))
     }
 
     /// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions.
@@ -77,18 +542,36 @@ impl ManagementCanister for ManagementCanisterMock {
         unreachable!()
     }
 
+    /// Returns the tip height and the block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`.
+    async fn get_block_headers(
+        &self,
+        _start_height: u32,
+        _end_height: Option<u32>,
+    ) -> Result<GetBlockHeadersResponse, ManagementCanisterReject> {
+        unreachable!()
+    }
+
     /// Returns the DER signature of the given `message_hash` associated with the ECDSA public key of this canister at the given derivation path.
     async fn sign_with_ecdsa(
         &self,
-        _derivation_path: &[Vec<u8>],
+        _derivation_path: &DerivationPath,
         _message_hash: &[u8],
     ) -> Result<Vec<u8>, ManagementCanisterReject> {
         unreachable!()
     }
 
+    /// Returns the BIP-340 signature of the given `message` associated with the Schnorr public key of this canister at the given derivation path, tweaked as per BIP-86.
+    async fn sign_with_schnorr(
+        &self,
+        _derivation_path: &DerivationPath,
+        _message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        unreachable!()
+    }
+
     /// Sends the given transaction to the network the management canister interacts with.
     async fn send_transaction(
-        &mut self,
+        &self,
         _transaction: Vec<u8>,
         _network: Network,
     ) -> Result<(), ManagementCanisterReject> {
@@ -96,6 +579,108 @@ impl ManagementCanister for ManagementCanisterMock {
     }
 }
 
+#[async_trait]
+impl TransferCalls for ManagementCanisterMock {
+    /// Simulates the retrieval of the tip height by `multi_transfer`, all the attached cycles being spent.
+    async fn get_tip_height(
+        &self,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<u32>, MultiTransferError> {
+        let cycles_spent = multi_transfer_args.cycles_cost_config.get_utxos;
+        self.internal_reject(ManagementCanisterMethod::BitcoinGetUtxos, cycles_spent)
+            .map_err(|reject| {
+                MultiTransferError::from_reject(ManagementCanisterMethod::BitcoinGetUtxos, reject)
+            })?;
+        Ok(WithCost {
+            result: self
+                .internal_get_utxos(&multi_transfer_args.change_address, 0)
+                .tip_height,
+            cycles_spent,
+            attempts: 1,
+        })
+    }
+
+    /// Simulates the retrieval of the current fees by `multi_transfer`, the rejections being retried according to `DEFAULT_RETRY_POLICY`.
+    async fn get_current_fees(
+        &self,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject> {
+        let cycles_spent = multi_transfer_args.cycles_cost_config.get_fees;
+        let attempts = self.internal_reject_with_retries(
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            cycles_spent,
+            &DEFAULT_RETRY_POLICY,
+        )?;
+        Ok(WithCost {
+            result: self.internal_get_current_fees(),
+            cycles_spent,
+            attempts,
+        })
+    }
+
+    /// Simulates the ECDSA signatures of `multi_transfer`, which are rubber-stamped unless `high_s_signatures` is set.
+    async fn sign_with_ecdsa(
+        &self,
+        key_name: String,
+        derivation_path: DerivationPath,
+        message_hash: Vec<u8>,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
+        self.internal_sign_latency().await;
+        self.internal_reject_sign(
+            ManagementCanisterMethod::SignWithEcdsa,
+            &derivation_path,
+            cycles_cost_config.sign_with_ecdsa,
+        )?;
+        let signature = if self.high_s_signatures {
+            self.internal_sign_with_ecdsa_high_s(&derivation_path, &message_hash)
+        } else {
+            mock_signer(key_name, derivation_path, message_hash)
+                .await?
+                .result
+        };
+        Ok(WithCost {
+            result: signature,
+            cycles_spent: cycles_cost_config.sign_with_ecdsa,
+            attempts: 1,
+        })
+    }
+
+    /// Simulates the Schnorr signatures of `multi_transfer`, which are actually computed to check Taproot key path spends.
+    async fn sign_with_schnorr(
+        &self,
+        _key_name: String,
+        derivation_path: DerivationPath,
+        message: Vec<u8>,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
+        self.internal_sign_latency().await;
+        self.internal_reject_sign(
+            ManagementCanisterMethod::SignWithSchnorr,
+            &derivation_path,
+            cycles_cost_config.sign_with_schnorr,
+        )?;
+        Ok(WithCost {
+            result: self.internal_sign_with_schnorr(&derivation_path, &message),
+            cycles_spent: cycles_cost_config.sign_with_schnorr,
+            attempts: 1,
+        })
+    }
+
+    /// Simulates the broadcast of the transaction of `multi_transfer`, which is pending until mined.
+    async fn send_transaction(
+        &self,
+        transaction: Vec<u8>,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<()>, ManagementCanisterReject> {
+        self.internal_send_transaction(
+            transaction,
+            from_types_network_to_bitcoin_network(multi_transfer_args.network),
+            &multi_transfer_args.cycles_cost_config,
+        )
+    }
+}
+
 impl ManagementCanisterMock {
     /// Creates a new instance of the management canister mock using a given ECDSA public key.
     pub(crate) fn new_using_ecdsa_public_key_test(
@@ -103,44 +688,254 @@ impl ManagementCanisterMock {
         ecdsa_public_key: EcdsaPubKey,
         address_type: AddressType,
     ) -> Self {
-        let mut management_canister = Self {
-            utxos_addresses: BTreeMap::default(),
-            network: from_types_network_to_bitcoin_network(network),
-            ecdsa_public_key: ecdsa_public_key.clone(),
-            tip_height: MIN_CONFIRMATIONS_UPPER_BOUND,
-            pending_transactions: vec![],
-        };
+        let management_canister = Self::new_with_chain(
+            network,
+            ecdsa_public_key.clone(),
+            Arc::new(Mutex::new(MockChain::default())),
+        );
         if !ecdsa_public_key.public_key.is_empty() {
             let main_address = get_main_address(&management_canister, &address_type);
-            management_canister.utxos_addresses =
+            management_canister.chain().utxos_addresses =
                 BTreeMap::from([(main_address, get_init_utxos())]);
         }
         management_canister
     }
 
+    /// Creates a new instance of the management canister mock simulating the given chain.
+    fn new_with_chain(
+        network: crate::Network,
+        ecdsa_public_key: EcdsaPubKey,
+        chain: Arc<Mutex<MockChain>>,
+    ) -> Self {
+        let network = from_types_network_to_bitcoin_network(network);
+        Self {
+            network,
+            ecdsa_public_key,
+            ecdsa_key_name: get_key_name(network, None),
+            cycles_cost_config: CyclesCostConfig::for_network(network),
+            bitcoin_api_target: BitcoinApiTarget::default(),
+            chain,
+            high_s_signatures: false,
+            #[cfg(test)]
+            sign_latency: Duration::ZERO,
+            #[cfg(test)]
+            pending_signatures: AtomicU32::default(),
+            #[cfg(test)]
+            max_pending_signatures: AtomicU32::default(),
+            rejections: Arc::default(),
+            sign_path_failures: Arc::default(),
+            page_size: None,
+            max_response_utxos: None,
+        }
+    }
+
+    /// Creates a new instance of the management canister mock using a given ECDSA public key and simulating the same chain as the mock `chain` was returned by, e.g. with `get_chain`.
+    /// The mocks sharing a chain see the transactions sent by each other, which are mined by `mine_block` whatever the mock it's called with.
+    pub fn new_sharing(
+        chain: Arc<Mutex<MockChain>>,
+        network: crate::Network,
+        ecdsa_public_key: EcdsaPubKey,
+        ecdsa_key_name: Option<String>,
+    ) -> Self {
+        let mut management_canister = Self::new_with_chain(network, ecdsa_public_key, chain);
+        if let Some(ecdsa_key_name) = ecdsa_key_name {
+            management_canister.ecdsa_key_name = ecdsa_key_name;
+        }
+        management_canister
+    }
+
+    /// Returns the simulated chain, to be shared with another mock with `new_sharing`.
+    pub fn get_chain(&self) -> Arc<Mutex<MockChain>> {
+        self.chain.clone()
+    }
+
+    /// Locks the simulated chain.
+    pub(crate) fn chain(&self) -> MutexGuard<'_, MockChain> {
+        self.chain.lock().unwrap()
+    }
+
+    /// Returns the UTXOs of `address` with at least `min_confirmations` confirmations, assembled from their pages.
     pub(crate) fn internal_get_utxos(
         &self,
         address: &Address,
         min_confirmations: u32,
     ) -> GetUtxosResponse {
-        let utxos = self
-            .utxos_addresses
-            .get(address)
-            .unwrap_or(&vec![])
-            .iter()
-            .filter(|utxo| has_utxo_min_confirmations(utxo, self.tip_height, min_confirmations))
-            .cloned()
-            .collect();
-        GetUtxosResponse {
-            utxos,
-            tip_height: self.tip_height,
+        self.get_utxos_pages(address, min_confirmations, None)
+            .into_response()
+    }
+
+    /// Sets the number of UTXOs per page returned by `get_utxos`, all the UTXOs being returned in a single page if `None`, e.g. to test the retrieval of several pages.
+    pub fn set_page_size(&mut self, page_size: Option<usize>) {
+        assert_ne!(page_size, Some(0), "The pages can't be empty.");
+        self.page_size = page_size;
+    }
+
+    /// Makes the pages of more than `max_response_utxos` UTXOs returned by `get_utxos` be rejected like the responses exceeding the size limit of the inter-canister responses, `None` removing the limit.
+    pub fn set_max_response_utxos(&mut self, max_response_utxos: Option<usize>) {
+        self.max_response_utxos = max_response_utxos;
+    }
+
+    /// Simulates the rejection of a call to `bitcoin_get_utxos` for `address` whose first page, the largest one, holds more UTXOs than set with `set_max_response_utxos`.
+    pub(crate) fn internal_reject_oversized_page(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+    ) -> Result<(), ManagementCanisterReject> {
+        self.check_page_size(address, min_confirmations, None)
+            .inspect_err(|rejection| {
+                metrics::record_rejection(ManagementCanisterMethod::BitcoinGetUtxos, rejection.0)
+            })
+    }
+
+    /// Returns the rejection of the page of the UTXOs of `address` starting at `page`, or of the first page if `None`, if it holds more UTXOs than set with `set_max_response_utxos`.
+    fn check_page_size(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+        page: Option<Vec<u8>>,
+    ) -> Result<(), ManagementCanisterReject> {
+        let page_len = self
+            .get_utxos_page(address, min_confirmations, page)
+            .0
+            .utxos
+            .len();
+        match self.max_response_utxos {
+            Some(max_response_utxos) if page_len > max_response_utxos => {
+                Err(ManagementCanisterReject(
+                    RejectionCode::CanisterError,
+                    format!(
+                        "Canister violated contract: ic0.msg_reply_data_append: application payload size of {} UTXOs cannot be larger than {} UTXOs.",
+                        page_len, max_response_utxos
+                    ),
+                ))
+            }
+            _ => Ok(()),
         }
     }
 
+    /// Returns the page of the UTXOs of `address` with at least `min_confirmations` confirmations starting at the offset encoded in `page`, or the first page if `None`, along with the token of the next page if any.
+    /// Like the tokens of the Bitcoin canister, the token of the next page encodes `min_confirmations` too.
+    fn get_utxos_page(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+        page: Option<Vec<u8>>,
+    ) -> (GetUtxosResponse, Option<Vec<u8>>) {
+        let GetUtxosResponse { utxos, tip_height } =
+            self.chain()
+                .get_utxos(self.network, address, min_confirmations);
+        let offset = page.map_or(0, |page| {
+            decode_page_token(&page).expect("Invalid page token.").0
+        });
+        let end = self
+            .page_size
+            .map_or(utxos.len(), |page_size| utxos.len().min(offset + page_size));
+        let next_page = (end < utxos.len()).then(|| {
+            [
+                &(end as u64).to_be_bytes()[..],
+                &min_confirmations.to_be_bytes(),
+            ]
+            .concat()
+        });
+        (
+            GetUtxosResponse {
+                utxos: utxos[offset..end].to_vec(),
+                tip_height,
+            },
+            next_page,
+        )
+    }
+
+    /// Retrieves the pages of the UTXOs of `address` with at least `min_confirmations` confirmations like `utxo_management::get_utxos`, at most `max_pages` of them if set.
+    pub(crate) fn get_utxos_pages(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+        max_pages: Option<usize>,
+    ) -> UtxosPages<Vec<u8>> {
+        let mut utxos_pages = UtxosPages::new(max_pages);
+        while !utxos_pages.is_complete() {
+            let page = utxos_pages.take_next_page();
+            let (get_utxos_response, next_page) =
+                self.get_utxos_page(address, min_confirmations, page);
+            utxos_pages.push_page(
+                get_utxos_response.utxos,
+                get_utxos_response.tip_height,
+                next_page,
+            );
+        }
+        utxos_pages
+    }
+
     pub(crate) fn internal_get_current_fees(&self) -> Vec<MillisatoshiPerByte> {
-        (1_000..100_000).step_by(1_000).collect()
+        self.chain().fee_percentiles.clone()
+    }
+
+    /// Returns the synthetic block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`.
+    /// Each header of the mock chain, from the genesis one to the one at `tip_height`, references the previous one.
+    pub(crate) fn internal_get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> Result<GetBlockHeadersResponse, ManagementCanisterReject> {
+        let tip_height = self.chain().tip_height;
+        let end_height = end_height.unwrap_or(tip_height);
+        if start_height > end_height || end_height > tip_height {
+            return Err(ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                format!(
+                    "Invalid block headers range {}..={} for the tip height {}.",
+                    start_height, end_height, tip_height
+                ),
+            ));
+        }
+        let mut prev_blockhash = BlockHash::all_zeros();
+        let mut block_headers = vec![];
+        for height in 0..=end_height {
+            let block_header = BlockHeader {
+                version: Version::from_consensus(4),
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1_231_006_505 + height * 600,
+                bits: CompactTarget::from_consensus(0x207f_ffff),
+                nonce: height,
+            };
+            prev_blockhash = block_header.block_hash();
+            if height >= start_height {
+                block_headers.push(serialize(&block_header));
+            }
+        }
+        Ok(GetBlockHeadersResponse {
+            tip_height,
+            block_headers,
+        })
+    }
+
+    /// Makes the mock return the given fee percentiles, until a fee change scheduled with `schedule_fee_change` takes effect.
+    pub fn set_fee_percentiles(&mut self, fee_percentiles: Vec<MillisatoshiPerByte>) {
+        self.chain().fee_percentiles = fee_percentiles;
     }
 
+    /// Makes the mock return no fee percentiles, as if there wasn't any transaction yet.
+    pub fn clear_fee_percentiles(&mut self) {
+        self.chain().fee_percentiles.clear();
+    }
+
+    /// Makes the mock return the given fee percentiles once its tip height reaches `at_tip_height`, e.g. to script a fee spike over the blocks mined with `mine_block`.
+    /// The fee change takes effect right away if the tip height already reached `at_tip_height`.
+    pub fn schedule_fee_change(
+        &mut self,
+        at_tip_height: u32,
+        fee_percentiles: Vec<MillisatoshiPerByte>,
+    ) {
+        let mut chain = self.chain();
+        chain
+            .scheduled_fee_changes
+            .insert(at_tip_height, fee_percentiles);
+        chain.apply_scheduled_fee_changes();
+    }
+
+    #[cfg(test)]
     pub(crate) fn internal_sign_with_ecdsa(
         &self,
         private_key: &[u8],
@@ -158,57 +953,591 @@ impl ManagementCanisterMock {
             .to_vec()
     }
 
-    pub(crate) fn internal_send_transaction(&mut self, transaction: Vec<u8>, _network: Network) {
-        self.pending_transactions
-            .push(Transaction::deserialize(&transaction).unwrap());
+    /// Returns the test private key derived at the given derivation path.
+    fn get_child_private_key(&self, derivation_path: &[Vec<u8>]) -> Vec<u8> {
+        let private_key = get_btc_private_key().to_bytes();
+        if derivation_path.is_empty() {
+            return private_key;
+        }
+        derive_child_private_key(
+            &private_key,
+            &self.ecdsa_public_key.chain_code,
+            derivation_path,
+        )
+    }
+
+    /// Returns the 64-byte compact signature of the given `message_hash`, like `sign_with_ecdsa`, with the test private key derived at the given derivation path.
+    pub(crate) fn internal_sign_with_ecdsa_compact(
+        &self,
+        derivation_path: &[Vec<u8>],
+        message_hash: &[u8],
+    ) -> Vec<u8> {
+        Secp256k1::new()
+            .sign_ecdsa(
+                &Message::from_slice(message_hash).unwrap(),
+                &SecretKey::from_slice(&self.get_child_private_key(derivation_path)).unwrap(),
+            )
+            .serialize_compact()
+            .to_vec()
+    }
+
+    /// Returns the 64-byte compact signature of the given `message_hash` with the test private key derived at the given derivation path, replacing S with its high form.
+    pub(crate) fn internal_sign_with_ecdsa_high_s(
+        &self,
+        derivation_path: &[Vec<u8>],
+        message_hash: &[u8],
+    ) -> Vec<u8> {
+        let mut signature = self.internal_sign_with_ecdsa_compact(derivation_path, message_hash);
+        // The signatures computed by libsecp256k1 have a low S value, the curve order minus S is its high form.
+        let s = SecretKey::from_slice(&signature[32..]).unwrap().negate();
+        signature[32..].copy_from_slice(&s.secret_bytes());
+        signature
+    }
+
+    /// Returns the BIP-340 signature of the given `message` with the test private key derived at the given derivation path and tweaked as per BIP-86.
+    pub(crate) fn internal_sign_with_schnorr(
+        &self,
+        derivation_path: &[Vec<u8>],
+        message: &[u8],
+    ) -> Vec<u8> {
+        let secp256k1 = Secp256k1::new();
+        let key_pair =
+            KeyPair::from_seckey_slice(&secp256k1, &self.get_child_private_key(derivation_path))
+                .unwrap()
+                .tap_tweak(&secp256k1, None)
+                .to_inner();
+        secp256k1.sign_schnorr_no_aux_rand(&Message::from_slice(message).unwrap(), &key_pair)[..]
+            .to_vec()
+    }
+
+    /// Makes the next call to `method` be rejected with the given rejection code and message.
+    pub(crate) fn reject_next_call(
+        &mut self,
+        method: ManagementCanisterMethod,
+        rejection_code: RejectionCode,
+        message: &str,
+    ) {
+        self.rejections
+            .lock()
+            .unwrap()
+            .entry(method)
+            .or_default()
+            .push_back(ManagementCanisterReject(
+                rejection_code,
+                message.to_string(),
+            ));
+    }
+
+    /// Makes the next `calls` calls to `method` be rejected with the given rejection code and message, the following calls succeeding.
+    pub(crate) fn reject_next_calls(
+        &mut self,
+        method: ManagementCanisterMethod,
+        rejection_code: RejectionCode,
+        message: &str,
+        calls: u32,
+    ) {
+        for _ in 0..calls {
+            self.reject_next_call(method, rejection_code, message);
+        }
+    }
+
+    /// Makes the next `count` invocations of `op` fail with the given rejection, the following ones succeeding again, e.g. to test retry policies.
+    /// For `MockOp::Sign`, the next `count` ECDSA signatures and the next `count` Schnorr signatures fail.
+    /// The simulated calls report the rejection with the same error types as the calls to the management canister.
+    pub fn inject_failure(
+        &mut self,
+        op: MockOp,
+        count: u32,
+        (rejection_code, message): (RejectionCode, String),
+    ) {
+        for method in op.get_methods() {
+            self.reject_next_calls(*method, rejection_code, &message, count);
+        }
+    }
+
+    /// Makes the next `times` ECDSA and Schnorr signatures with each of the given derivation paths be rejected with a transient rejection, the signatures with other derivation paths being unaffected, e.g. to make some inputs of a transaction fail to be signed.
+    pub fn fail_sign_for_paths(&mut self, paths: Vec<Vec<Vec<u8>>>, times: u32) {
+        let mut sign_path_failures = self.sign_path_failures.lock().unwrap();
+        for path in paths {
+            if times == 0 {
+                sign_path_failures.remove(&path);
+            } else {
+                sign_path_failures.insert(path, times);
+            }
+        }
+    }
+
+    /// Simulates a signature call to `method` with the key derived at `derivation_path`, returning the rejection planned with `fail_sign_for_paths` for this derivation path if any, the one planned with `reject_next_call` otherwise.
+    pub(crate) fn internal_reject_sign(
+        &self,
+        method: ManagementCanisterMethod,
+        derivation_path: &[Vec<u8>],
+        payment: u128,
+    ) -> Result<(), ManagementCanisterReject> {
+        let result = self.take_sign_rejection(method, derivation_path);
+        record_simulated_call(method, payment, &result);
+        result
+    }
+
+    /// Simulates a call to `method` with `payment` cycles attached, returning the rejection planned with `reject_next_call` if any.
+    /// The call is recorded in the metrics, all the attached cycles being spent if it succeeds.
+    pub(crate) fn internal_reject(
+        &self,
+        method: ManagementCanisterMethod,
+        payment: u128,
+    ) -> Result<(), ManagementCanisterReject> {
+        let result = self.take_rejection(method);
+        record_simulated_call(method, payment, &result);
+        result
+    }
+
+    /// Returns the rejection planned with `fail_sign_for_paths` for the signature with the key derived at `derivation_path` if any, the one planned with `reject_next_call` for `method` otherwise, without recording the call in the metrics.
+    fn take_sign_rejection(
+        &self,
+        method: ManagementCanisterMethod,
+        derivation_path: &[Vec<u8>],
+    ) -> Result<(), ManagementCanisterReject> {
+        let mut sign_path_failures = self.sign_path_failures.lock().unwrap();
+        match sign_path_failures.get_mut(derivation_path) {
+            Some(remaining_failures) => {
+                *remaining_failures -= 1;
+                if *remaining_failures == 0 {
+                    sign_path_failures.remove(derivation_path);
+                }
+                Err(ManagementCanisterReject(
+                    RejectionCode::SysTransient,
+                    format!(
+                        "Signing with the derivation path {} failed.",
+                        format_derivation_path(&DerivationPath::new_unchecked(
+                            derivation_path.to_vec()
+                        ))
+                    ),
+                ))
+            }
+            None => self.take_rejection(method),
+        }
+    }
+
+    /// Returns the rejection planned with `reject_next_call` for the next call to `method` if any, without recording the call in the metrics.
+    fn take_rejection(
+        &self,
+        method: ManagementCanisterMethod,
+    ) -> Result<(), ManagementCanisterReject> {
+        match self
+            .rejections
+            .lock()
+            .unwrap()
+            .get_mut(&method)
+            .and_then(|rejections| rejections.pop_front())
+        {
+            Some(rejection) => Err(rejection),
+            None => Ok(()),
+        }
+    }
+
+    /// Simulates calls to `method` with `payment` cycles attached retried according to `retry_policy` while they're rejected with the rejections planned with `reject_next_call`.
+    /// Returns the number of calls, or the rejection of the last one if it isn't retried.
+    pub(crate) fn internal_reject_with_retries(
+        &self,
+        method: ManagementCanisterMethod,
+        payment: u128,
+        retry_policy: &RetryPolicy,
+    ) -> Result<u32, ManagementCanisterReject> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.internal_reject(method, payment) {
+                Err(ManagementCanisterReject(rejection_code, _))
+                    if retry_policy.should_retry(rejection_code, attempts) => {}
+                Err(rejection) => return Err(rejection),
+                Ok(()) => return Ok(attempts),
+            }
+        }
+    }
+
+    /// Simulates the latency of a signature call, keeping track of the number of pending signature calls.
+    #[cfg(test)]
+    pub(crate) async fn internal_sign_latency(&self) {
+        let pending_signatures = self.pending_signatures.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_pending_signatures
+            .fetch_max(pending_signatures, Ordering::SeqCst);
+        if !self.sign_latency.is_zero() {
+            tokio::time::sleep(self.sign_latency).await;
+        }
+        self.pending_signatures.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// The latency of the signature calls is only simulated by the tests of the library, which depend on `tokio`.
+    #[cfg(not(test))]
+    pub(crate) async fn internal_sign_latency(&self) {}
+
+    /// Simulates sending a transaction, all the attached cycles being spent.
+    pub(crate) fn internal_send_transaction(
+        &self,
+        transaction: Vec<u8>,
+        _network: Network,
+        cycles_cost_config: &CyclesCostConfig,
+    ) -> Result<WithCost<()>, ManagementCanisterReject> {
+        let cycles_spent = cycles_cost_config.get_send_transaction_cost_cycles(transaction.len());
+        self.internal_reject(
+            ManagementCanisterMethod::BitcoinSendTransaction,
+            cycles_spent,
+        )?;
+        self.chain()
+            .pending_transactions
+            .push(deserialize(&transaction).unwrap());
+        Ok(WithCost {
+            result: (),
+            cycles_spent,
+            attempts: 1,
+        })
+    }
+
+    /// Returns a canister caller serving the calls of the library with the mock, to be set with `set_canister_caller`, e.g. to run the `_from_args` functions and the macros of the library against the mock.
+    /// The canister caller simulates the same chain as the mock and shares the failures injected in it, and its calls spend all the attached cycles.
+    pub fn get_canister_caller(&self) -> Arc<dyn CanisterCaller> {
+        Arc::new(MockCanisterCaller(ManagementCanisterMock {
+            ecdsa_public_key: self.ecdsa_public_key.clone(),
+            ecdsa_key_name: self.ecdsa_key_name.clone(),
+            bitcoin_api_target: self.bitcoin_api_target.clone(),
+            cycles_cost_config: self.cycles_cost_config,
+            high_s_signatures: self.high_s_signatures,
+            rejections: self.rejections.clone(),
+            sign_path_failures: self.sign_path_failures.clone(),
+            page_size: self.page_size,
+            max_response_utxos: self.max_response_utxos,
+            ..Self::new_with_chain(
+                from_bitcoin_network_to_types_network(self.network),
+                EcdsaPubKey {
+                    public_key: vec![],
+                    chain_code: vec![],
+                    derivation_path: DerivationPath::default(),
+                },
+                self.chain.clone(),
+            )
+        }))
+    }
+
+    /// Simulates a call to `method` with the Candid-encoded `args`, returning its Candid-encoded reply.
+    /// Unlike the other simulated calls, the call isn't recorded in the metrics, as the library records the calls made through the canister caller itself.
+    fn internal_call(
+        &self,
+        method: &str,
+        args: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        match method {
+            "bitcoin_get_utxos" => {
+                let get_utxos_request: GetUtxosRequest = decode_call_args(args)?;
+                self.take_rejection(ManagementCanisterMethod::BitcoinGetUtxos)?;
+                let address = self.parse_address(&get_utxos_request.address)?;
+                let (min_confirmations, page) = match get_utxos_request.filter {
+                    None => (0, None),
+                    Some(UtxoFilter::MinConfirmations(min_confirmations)) => {
+                        (min_confirmations, None)
+                    }
+                    Some(UtxoFilter::Page(page)) => {
+                        let (_, min_confirmations) =
+                            decode_page_token(&page).ok_or(ManagementCanisterReject(
+                                RejectionCode::CanisterReject,
+                                String::from("Invalid page token."),
+                            ))?;
+                        (min_confirmations, Some(page))
+                    }
+                };
+                self.check_page_size(&address, min_confirmations, page.clone())?;
+                let (get_utxos_response, next_page) =
+                    self.get_utxos_page(&address, min_confirmations, page);
+                Ok(encode_reply(
+                    management_canister_bitcoin::GetUtxosResponse {
+                        utxos: get_utxos_response
+                            .utxos
+                            .into_iter()
+                            .map(management_canister_bitcoin::Utxo::from)
+                            .collect(),
+                        tip_block_hash: vec![],
+                        tip_height: get_utxos_response.tip_height,
+                        next_page,
+                    },
+                ))
+            }
+            "bitcoin_get_balance" => {
+                let get_balance_request: GetBalanceRequest = decode_call_args(args)?;
+                self.take_rejection(ManagementCanisterMethod::BitcoinGetBalance)?;
+                let address = self.parse_address(&get_balance_request.address)?;
+                let balance: Satoshi = self
+                    .chain()
+                    .get_utxos(
+                        self.network,
+                        &address,
+                        get_balance_request.min_confirmations.unwrap_or(0),
+                    )
+                    .utxos
+                    .iter()
+                    .map(|utxo| utxo.value)
+                    .sum();
+                Ok(encode_reply(balance))
+            }
+            "bitcoin_get_current_fee_percentiles" => {
+                let _: GetCurrentFeePercentilesRequest = decode_call_args(args)?;
+                self.take_rejection(ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles)?;
+                Ok(encode_reply(self.internal_get_current_fees()))
+            }
+            "bitcoin_get_block_headers" => {
+                let get_block_headers_request: GetBlockHeadersRequest = decode_call_args(args)?;
+                self.take_rejection(ManagementCanisterMethod::BitcoinGetBlockHeaders)?;
+                Ok(encode_reply(self.internal_get_block_headers(
+                    get_block_headers_request.start_height,
+                    get_block_headers_request.end_height,
+                )?))
+            }
+            "sign_with_ecdsa" => {
+                let sign_with_ecdsa: SignWithECDSA = decode_call_args(args)?;
+                self.take_sign_rejection(
+                    ManagementCanisterMethod::SignWithEcdsa,
+                    &sign_with_ecdsa.derivation_path,
+                )?;
+                let signature = if self.high_s_signatures {
+                    self.internal_sign_with_ecdsa_high_s(
+                        &sign_with_ecdsa.derivation_path,
+                        &sign_with_ecdsa.message_hash,
+                    )
+                } else {
+                    self.internal_sign_with_ecdsa_compact(
+                        &sign_with_ecdsa.derivation_path,
+                        &sign_with_ecdsa.message_hash,
+                    )
+                };
+                Ok(encode_reply(SignWithECDSAReply { signature }))
+            }
+            "sign_with_schnorr" => {
+                let sign_with_schnorr: SignWithSchnorr = decode_call_args(args)?;
+                self.take_sign_rejection(
+                    ManagementCanisterMethod::SignWithSchnorr,
+                    &sign_with_schnorr.derivation_path,
+                )?;
+                Ok(encode_reply(SignWithSchnorrReply {
+                    signature: self.internal_sign_with_schnorr(
+                        &sign_with_schnorr.derivation_path,
+                        &sign_with_schnorr.message,
+                    ),
+                }))
+            }
+            "bitcoin_send_transaction" => {
+                let send_transaction_request: SendTransactionRequest = decode_call_args(args)?;
+                self.take_rejection(ManagementCanisterMethod::BitcoinSendTransaction)?;
+                let transaction =
+                    deserialize(&send_transaction_request.transaction).map_err(|error| {
+                        ManagementCanisterReject(
+                            RejectionCode::CanisterReject,
+                            format!("Cannot decode transaction: {}", error),
+                        )
+                    })?;
+                self.chain().pending_transactions.push(transaction);
+                Ok(encode_reply(()))
+            }
+            method => Err(ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                format!("The method {} isn't simulated by the mock.", method),
+            )),
+        }
+    }
+
+    /// Returns the address of the network of the mock encoded in `address`, the call being rejected if it can't be parsed.
+    fn parse_address(&self, address: &str) -> Result<Address, ManagementCanisterReject> {
+        // The network is the one of the mock rather than the one of the prefix, shared by the testnet and the regtest.
+        get_address((
+            address.to_string(),
+            from_bitcoin_network_to_types_network(self.network),
+        ))
+        .map_err(|_| {
+            ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                format!("Invalid address {}.", address),
+            )
+        })
+    }
+
+    /// Returns the sent transactions not mined yet, in the order they were sent.
+    pub fn get_pending_transactions(&self) -> Vec<Transaction> {
+        self.chain().pending_transactions.clone()
+    }
+
+    /// Returns the time of the logical clock of the mock chain in nanoseconds since the UNIX epoch, `MOCK_START_TIME` for a new mock chain.
+    pub fn now(&self) -> u64 {
+        self.chain().time
+    }
+
+    /// Advances the logical clock of the mock chain by `nanos` nanoseconds, e.g. to expire the cached fees without sleeping.
+    pub fn advance_time(&mut self, nanos: u64) {
+        self.chain().time += nanos;
+    }
+
+    /// Makes every block mined by `mine_block` advance the logical clock of the mock chain by `nanos` nanoseconds, 0 by default.
+    pub fn set_block_interval(&mut self, nanos: u64) {
+        self.chain().block_interval = nanos;
+    }
+
+    /// Returns the height of the tip of the mock chain.
+    pub fn get_tip_height(&self) -> u32 {
+        self.chain().tip_height
+    }
+
+    /// Moves the tip of the mock chain to `tip_height` without mining blocks, e.g. to give the UTXOs as many confirmations as needed.
+    /// The fee changes scheduled up to `tip_height` take effect.
+    pub fn set_tip_height(&mut self, tip_height: u32) {
+        let mut chain = self.chain();
+        chain.tip_height = tip_height;
+        chain.apply_scheduled_fee_changes();
+    }
+
+    /// Mines `count` blocks with `mine_block`, the pending transactions being included in the first one.
+    pub fn mine_blocks(&mut self, count: u32) {
+        for _ in 0..count {
+            mine_block(self);
+        }
+    }
+
+    /// Returns a new txid, the SHA-256 hash of the number of txids generated so far, so that the generated txids are unique and identical from a run to another.
+    pub fn generate_txid(&mut self) -> Vec<u8> {
+        self.chain().generate_txid()
+    }
+
+    /// Pays `value` satoshis to `address` with a new UTXO confirmed at the tip height, and returns its outpoint.
+    ///
+    /// ```
+    /// use ic_btc_library::{canister_mock, AddressType, Fee, Network};
+    /// use std::collections::BTreeMap;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let mut agent = canister_mock::new_mock(&Network::Regtest, &AddressType::P2pkh);
+    /// let main_address = agent.get_main_address();
+    /// let outpoint = agent
+    ///     .get_management_canister_mock_mut()
+    ///     .fund_address(&main_address, 1_000_000);
+    ///
+    /// // Refresh the UTXOs of the main address.
+    /// let utxos_args = agent.get_utxos_args(&main_address, 1).unwrap();
+    /// let utxos_result = agent.get_utxos_from_args_test(utxos_args).unwrap().result;
+    /// agent.apply_utxos(utxos_result);
+    /// let balance_update = agent.get_balance_update(&main_address).unwrap();
+    /// assert_eq!(balance_update.added_balance, 1_250_000);
+    ///
+    /// // Spend the funded UTXO.
+    /// let payee = agent.add_address(&[vec![1]]).unwrap();
+    /// let payouts = BTreeMap::from([(payee.clone(), 1_100_000)]);
+    /// let multi_transfer_args =
+    ///     agent.get_multi_transfer_args(&payouts, &main_address, Fee::Constant(10_000), 1, false);
+    /// let multi_transfer_result = agent
+    ///     .multi_transfer_from_args_test(multi_transfer_args)
+    ///     .await
+    ///     .unwrap();
+    /// agent.apply_multi_transfer_result(&multi_transfer_result).unwrap();
+    /// let spent_outpoints: Vec<_> = multi_transfer_result
+    ///     .transaction_info
+    ///     .utxos_addresses
+    ///     .values()
+    ///     .flatten()
+    ///     .map(|utxo| utxo.outpoint.clone())
+    ///     .collect();
+    /// assert!(spent_outpoints.contains(&outpoint));
+    ///
+    /// // The payee receives the payment once it's mined.
+    /// agent.get_management_canister_mock_mut().mine_blocks(1);
+    /// let utxos_args = agent.get_utxos_args(&payee, 1).unwrap();
+    /// assert_eq!(agent.get_balance_from_args_test(utxos_args).unwrap(), 1_100_000);
+    /// # }
+    /// ```
+    pub fn fund_address(&mut self, address: &Address, value: Satoshi) -> OutPoint {
+        self.chain().fund_address(address, value)
+    }
+
+    /// Sends `value` satoshis to `address` with a real transaction spending a synthetic coinbase input, and returns its txid.
+    /// Unlike with `fund_address`, the transaction is pending until `mine_block` confirms it, hence its output goes through the same script and outpoint bookkeeping as the transactions sent by the Bitcoin agents.
+    pub fn send_to_address(&mut self, address: &Address, value: Satoshi) -> Txid {
+        self.chain().send_to_address(address, value)
+    }
+
+    /// Rewinds the mock chain by `depth` blocks, as if they were orphaned by a reorganization.
+    /// The transactions mined in these blocks return to the pending transactions, ahead of the ones sent since, the UTXOs they spent are restored and the ones they generated are removed, as are the other UTXOs confirmed in these blocks.
+    pub fn invalidate_blocks(&mut self, depth: u32) {
+        self.chain().invalidate_blocks(depth);
+    }
+
+    /// Replaces the last `depth` blocks of the mock chain with `depth` blocks, at least one, the first one including `alternative_transactions`, e.g. double spending transactions of the replaced blocks.
+    /// The transactions of the replaced blocks are dropped, while the pending transactions stay pending.
+    pub fn fork_and_replace(&mut self, depth: u32, alternative_transactions: Vec<Transaction>) {
+        let network = self.network;
+        let mut chain = self.chain();
+        let pending_transactions = std::mem::take(&mut chain.pending_transactions);
+        chain.invalidate_blocks(depth);
+        chain.pending_transactions = alternative_transactions;
+        for _ in 0..depth.max(1) {
+            chain.mine_block(network);
+        }
+        chain.pending_transactions = pending_transactions;
     }
 }
 
+#[cfg(test)]
 pub(crate) fn get_utxos(
     bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>,
     address: &Address,
     min_confirmations: u32,
 ) -> Vec<Utxo> {
-    let get_utxos_args = bitcoin_agent.get_utxos_args(address, min_confirmations);
+    let get_utxos_args = bitcoin_agent
+        .get_utxos_args(address, min_confirmations)
+        .unwrap();
     bitcoin_agent
         .get_utxos_from_args_test(get_utxos_args)
         .unwrap()
+        .result
         .utxos
 }
 
+#[cfg(test)]
 pub(crate) fn get_balance(
     bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>,
     address: &Address,
     min_confirmations: u32,
 ) -> Satoshi {
-    let get_utxos_args = bitcoin_agent.get_utxos_args(address, min_confirmations);
+    let get_utxos_args = bitcoin_agent
+        .get_utxos_args(address, min_confirmations)
+        .unwrap();
     bitcoin_agent
         .get_balance_from_args_test(get_utxos_args)
         .unwrap()
 }
 
+#[cfg(test)]
 pub(crate) fn get_balance_update(
     bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
     address: &Address,
     min_confirmations: u32,
 ) -> BalanceUpdate {
-    let get_utxos_args = bitcoin_agent.get_utxos_args(address, min_confirmations);
+    let get_utxos_args = bitcoin_agent
+        .get_utxos_args(address, min_confirmations)
+        .unwrap();
     let get_utxos_result = bitcoin_agent
         .get_utxos_from_args_test(get_utxos_args)
-        .unwrap();
+        .unwrap()
+        .result;
     bitcoin_agent.apply_utxos(get_utxos_result);
     bitcoin_agent.get_balance_update(address).unwrap()
 }
 
+#[cfg(test)]
 pub(crate) fn get_current_fees(
     bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>,
 ) -> Vec<MillisatoshiPerByte> {
-    let get_current_fees_args = bitcoin_agent.get_current_fees_args();
+    let get_current_fees_args = bitcoin_agent.get_current_fees_args().unwrap();
     bitcoin_agent
         .get_current_fees_from_args_test(get_current_fees_args)
         .unwrap()
+        .result
 }
 
+#[cfg(test)]
 pub(crate) async fn multi_transfer(
     bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
     payouts: &BTreeMap<Address, Satoshi>,
@@ -228,10 +1557,73 @@ pub(crate) async fn multi_transfer(
         .multi_transfer_from_args_test(multi_transfer_args)
         .await
         .unwrap();
-    bitcoin_agent.apply_multi_transfer_result(&multi_transfer_result);
+    bitcoin_agent
+        .apply_multi_transfer_result(&multi_transfer_result)
+        .unwrap();
     multi_transfer_result.transaction_info
 }
 
+/// Records a simulated call to `method` with `payment` cycles attached in the metrics, all the attached cycles being spent if it succeeds.
+fn record_simulated_call(
+    method: ManagementCanisterMethod,
+    payment: u128,
+    result: &Result<(), ManagementCanisterReject>,
+) {
+    match result {
+        Err(rejection) => metrics::record_rejection(method, rejection.0),
+        Ok(()) => metrics::record_call(method, payment),
+    }
+}
+
+/// Returns the offset and the minimum number of confirmations encoded in the token of a page of UTXOs returned by the mock.
+fn decode_page_token(page: &[u8]) -> Option<(usize, u32)> {
+    let offset = u64::from_be_bytes(page.get(..8)?.try_into().ok()?);
+    let min_confirmations = u32::from_be_bytes(page.get(8..)?.try_into().ok()?);
+    Some((offset as usize, min_confirmations))
+}
+
+/// Returns the arguments decoded from the Candid-encoded `args` of a call to the mock, the call being rejected if they can't be decoded.
+fn decode_call_args<T: CandidType + for<'a> Deserialize<'a>>(
+    args: &[u8],
+) -> Result<T, ManagementCanisterReject> {
+    decode_args::<(T,)>(args)
+        .map(|(args,)| args)
+        .map_err(|error| {
+            ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                format!("Failed to decode the arguments: {}", error),
+            )
+        })
+}
+
+/// Returns the Candid-encoded `reply` of a call to the mock.
+fn encode_reply<T: CandidType>(reply: T) -> Vec<u8> {
+    encode_args((reply,)).expect("Failed to encode the reply.")
+}
+
+/// The canister caller serving the calls of the library with a management canister mock, see `ManagementCanisterMock::get_canister_caller`.
+struct MockCanisterCaller(ManagementCanisterMock);
+
+#[async_trait]
+impl CanisterCaller for MockCanisterCaller {
+    async fn call(
+        &self,
+        _canister_id: Principal,
+        method: &str,
+        args: Vec<u8>,
+        _payment: u128,
+    ) -> CallResult<Vec<u8>> {
+        self.0
+            .internal_call(method, &args)
+            .map_err(|ManagementCanisterReject(rejection_code, message)| (rejection_code, message))
+    }
+
+    /// The simulated calls spend all the attached cycles.
+    fn get_cycles_refunded(&self) -> u128 {
+        0
+    }
+}
+
 /// Gets some hard-coded UTXOs to be used by the mock.
 pub(crate) fn get_init_utxos() -> Vec<Utxo> {
     vec![Utxo {
@@ -245,11 +1637,13 @@ pub(crate) fn get_init_utxos() -> Vec<Utxo> {
 }
 
 /// Gets the initial balance to be used by the mock.
+#[cfg(test)]
 pub(crate) fn get_init_balance() -> Satoshi {
     get_init_utxos().iter().map(|utxo| utxo.value).sum()
 }
 
 /// Gets the initial UTXOs update to be used by the mock.
+#[cfg(test)]
 pub(crate) fn get_init_utxos_update() -> UtxosUpdate {
     UtxosUpdate {
         added_utxos: get_init_utxos(),
@@ -258,65 +1652,22 @@ pub(crate) fn get_init_utxos_update() -> UtxosUpdate {
 }
 
 /// Gets the initial balance update to be used by the mock.
+#[cfg(test)]
 pub(crate) fn get_init_balance_update() -> BalanceUpdate {
     BalanceUpdate::from(get_init_utxos_update())
 }
 
-/// Returns an `ic_btc_types::OutPoint` from a given `bitcoin::OutPoint`.
+/// Returns an `OutPoint` from a given `bitcoin::OutPoint`.
 pub(crate) fn get_outpoint_ic_type(outpoint_btc_type: bitcoin::OutPoint) -> OutPoint {
     OutPoint {
-        txid: outpoint_btc_type.txid.to_vec(),
+        txid: outpoint_btc_type.txid.to_byte_array().to_vec(),
         vout: outpoint_btc_type.vout,
     }
 }
 
-pub(crate) fn mine_block(management_canister_mock: &mut ManagementCanisterMock) {
-    management_canister_mock
-        .pending_transactions
-        .iter()
-        .for_each(|transaction| {
-            // Consumes UTXOs from the given transaction inputs.
-            transaction.input.iter().for_each(|input| {
-                management_canister_mock
-                    .utxos_addresses
-                    .clone()
-                    .keys()
-                    .for_each(|address| {
-                        let address_utxos = &mut management_canister_mock
-                            .utxos_addresses
-                            .get_mut(address)
-                            .unwrap();
-                        let outpoint_ic_type = get_outpoint_ic_type(input.previous_output);
-                        address_utxos.retain(|utxo| utxo.outpoint != outpoint_ic_type);
-                    });
-            });
-            let tx_id = transaction.txid().to_vec();
-            // Generates UTXOs from the given transaction outputs.
-            transaction
-                .output
-                .iter()
-                .enumerate()
-                .for_each(|(outputs_index, output)| {
-                    let address = Address::from_script(
-                        &output.script_pubkey,
-                        management_canister_mock.network,
-                    )
-                    .unwrap();
-                    let new_utxo = Utxo {
-                        outpoint: OutPoint {
-                            txid: tx_id.clone(),
-                            vout: outputs_index as u32,
-                        },
-                        value: output.value,
-                        height: management_canister_mock.tip_height,
-                    };
-                    management_canister_mock
-                        .utxos_addresses
-                        .entry(address)
-                        .or_insert_with(Vec::new)
-                        .push(new_utxo);
-                });
-        });
-    management_canister_mock.pending_transactions.clear();
-    management_canister_mock.tip_height += 1;
+/// Mines the pending transactions in a block, their generated UTXOs being confirmed at the current tip height, which is then incremented.
+/// The transactions sent by the mocks sharing the chain of `management_canister_mock` are mined too.
+pub fn mine_block(management_canister_mock: &mut ManagementCanisterMock) {
+    let network = management_canister_mock.network;
+    management_canister_mock.chain().mine_block(network);
 }
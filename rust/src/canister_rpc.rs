@@ -0,0 +1,517 @@
+use crate::{
+    bip32_extended_derivation::derive_child_private_key,
+    canister_common::ManagementCanister,
+    ecdsa::get_key_name,
+    types::{from_types_network_to_bitcoin_network, GetBlockHeadersResponse, GetUtxosResponse},
+    utxo_management::has_utxo_min_confirmations,
+    BitcoinApiTarget, CyclesCostConfig, DerivationPath, EcdsaPubKey, GetUtxosError,
+    ManagementCanisterReject, MillisatoshiPerByte, OutPoint, Utxo, MIN_CONFIRMATIONS_UPPER_BOUND,
+};
+use async_trait::async_trait;
+use bitcoin::{
+    consensus::serialize,
+    hashes::Hash,
+    key::TapTweak,
+    secp256k1::{KeyPair, Message, PublicKey, Secp256k1, SecretKey},
+    Address, Network,
+};
+use bitcoincore_rpc::{json::ScanTxOutRequest, Auth, Client, RpcApi};
+use ic_cdk::api::call::RejectionCode;
+use std::sync::Arc;
+
+/// The confirmation targets, in blocks, whose fee estimates fill the fee percentiles from the lowest to the highest fee.
+const FEE_ESTIMATES_CONFIRMATION_TARGETS: [u16; 10] = [1008, 504, 144, 48, 24, 12, 6, 3, 2, 1];
+
+/// The number of fee percentiles returned by `get_current_fees`.
+const FEE_PERCENTILES: usize = 100;
+
+/// Signs the messages of a `ManagementCanisterRpc` off-chain, in place of the threshold signatures of the management canister.
+pub trait LocalSigner: Send + Sync {
+    /// Returns the ECDSA public key and chain code from which the keys are derived.
+    fn get_ecdsa_public_key(&self) -> EcdsaPubKey;
+
+    /// Returns the 64-byte compact ECDSA signature of `message_hash` with the key derived at `derivation_path`, like the management canister.
+    fn sign_with_ecdsa(
+        &self,
+        derivation_path: &DerivationPath,
+        message_hash: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject>;
+
+    /// Returns the BIP-340 signature of `message` with the key derived at `derivation_path` and tweaked as per BIP-86.
+    fn sign_with_schnorr(
+        &self,
+        derivation_path: &DerivationPath,
+        message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject>;
+}
+
+/// Signs with a local private key, the keys being derived along the derivation paths like the ones of the threshold ECDSA key.
+pub struct LocalKeySigner {
+    private_key: SecretKey,
+    chain_code: Vec<u8>,
+}
+
+impl LocalKeySigner {
+    /// Creates a signer deriving its keys from `private_key` and `chain_code`.
+    pub fn new(private_key: SecretKey, chain_code: Vec<u8>) -> Self {
+        Self {
+            private_key,
+            chain_code,
+        }
+    }
+
+    /// Returns the private key derived at `derivation_path`.
+    fn get_child_private_key(&self, derivation_path: &DerivationPath) -> SecretKey {
+        SecretKey::from_slice(&derive_child_private_key(
+            &self.private_key.secret_bytes(),
+            &self.chain_code,
+            derivation_path,
+        ))
+        .unwrap()
+    }
+}
+
+impl LocalSigner for LocalKeySigner {
+    fn get_ecdsa_public_key(&self) -> EcdsaPubKey {
+        EcdsaPubKey {
+            public_key: PublicKey::from_secret_key(&Secp256k1::new(), &self.private_key)
+                .serialize()
+                .to_vec(),
+            chain_code: self.chain_code.clone(),
+            derivation_path: DerivationPath::default(),
+        }
+    }
+
+    fn sign_with_ecdsa(
+        &self,
+        derivation_path: &DerivationPath,
+        message_hash: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        let message = get_message(message_hash)?;
+        Ok(Secp256k1::new()
+            .sign_ecdsa(&message, &self.get_child_private_key(derivation_path))
+            .serialize_compact()
+            .to_vec())
+    }
+
+    fn sign_with_schnorr(
+        &self,
+        derivation_path: &DerivationPath,
+        message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        let message = get_message(message)?;
+        let secp256k1 = Secp256k1::new();
+        let key_pair = KeyPair::from_seckey_slice(
+            &secp256k1,
+            &self.get_child_private_key(derivation_path).secret_bytes(),
+        )
+        .unwrap()
+        .tap_tweak(&secp256k1, None)
+        .to_inner();
+        Ok(secp256k1.sign_schnorr_no_aux_rand(&message, &key_pair)[..].to_vec())
+    }
+}
+
+/// Returns the message to sign, rejecting it like the management canister if it isn't 32-byte long.
+fn get_message(message: &[u8]) -> Result<Message, ManagementCanisterReject> {
+    Message::from_slice(message)
+        .map_err(|error| ManagementCanisterReject(RejectionCode::CanisterReject, error.to_string()))
+}
+
+/// Returns the rejection of a failed RPC call.
+fn get_reject(error: bitcoincore_rpc::Error) -> ManagementCanisterReject {
+    ManagementCanisterReject(RejectionCode::CanisterError, error.to_string())
+}
+
+/// The management canister served by the RPC interface of a Bitcoin Core node, e.g. a local regtest bitcoind, to run the library off-chain.
+/// The signatures are computed by a `LocalSigner` and no cycles are spent.
+/// It can't be restored from a state, not being a `ManagementCanisterFactory`, as it requires the URL of the node.
+pub struct ManagementCanisterRpc {
+    client: Client,
+    network: Network,
+    ecdsa_public_key: EcdsaPubKey,
+    ecdsa_key_name: String,
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: BitcoinApiTarget,
+    signer: Arc<dyn LocalSigner>,
+}
+
+impl ManagementCanisterRpc {
+    /// Creates a management canister served by the node of the RPC interface at `url`, signing with `signer`.
+    /// Like the other management canisters, its ECDSA public key is set by initializing the Bitcoin agent, with the one of `signer` here.
+    pub fn new(
+        network: crate::Network,
+        url: &str,
+        auth: Auth,
+        signer: Arc<dyn LocalSigner>,
+    ) -> Result<Self, bitcoincore_rpc::Error> {
+        let network = from_types_network_to_bitcoin_network(network);
+        Ok(Self {
+            client: Client::new(url, auth)?,
+            network,
+            ecdsa_public_key: EcdsaPubKey {
+                public_key: vec![],
+                chain_code: vec![],
+                derivation_path: DerivationPath::default(),
+            },
+            ecdsa_key_name: get_key_name(network, None),
+            cycles_cost_config: CyclesCostConfig::for_network(network),
+            bitcoin_api_target: BitcoinApiTarget::default(),
+            signer,
+        })
+    }
+
+    /// Returns the RPC client of the node, e.g. to mine blocks on regtest with `generate_to_address`.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the height of the tip of the node.
+    fn get_tip_height(&self) -> Result<u32, ManagementCanisterReject> {
+        self.client
+            .get_block_count()
+            .map(|block_count| block_count as u32)
+            .map_err(get_reject)
+    }
+}
+
+#[async_trait]
+impl ManagementCanister for ManagementCanisterRpc {
+    /// Initializes the management canister by initializing its ECDSA public key.
+    /// Note: the signatures are still computed by the local signer.
+    fn set_ecdsa_public_key(&mut self, ecdsa_public_key: EcdsaPubKey) {
+        self.ecdsa_public_key = ecdsa_public_key;
+    }
+
+    /// Returns the network the management canister interacts with.
+    fn get_network(&self) -> Network {
+        self.network
+    }
+
+    /// Returns the ECDSA public key of this canister.
+    fn get_ecdsa_public_key(&self) -> EcdsaPubKey {
+        self.ecdsa_public_key.clone()
+    }
+
+    /// Returns the name of the threshold ECDSA key used by this canister.
+    fn get_ecdsa_key_name(&self) -> String {
+        self.ecdsa_key_name.clone()
+    }
+
+    /// Returns the cycles attached to the paid calls to the management canister.
+    fn get_cycles_cost_config(&self) -> CyclesCostConfig {
+        self.cycles_cost_config
+    }
+
+    /// Sets the cycles attached to the paid calls to the management canister.
+    fn set_cycles_cost_config(&mut self, cycles_cost_config: CyclesCostConfig) {
+        self.cycles_cost_config = cycles_cost_config;
+    }
+
+    /// Returns the canister serving the calls to the Bitcoin API.
+    fn get_bitcoin_api_target(&self) -> BitcoinApiTarget {
+        self.bitcoin_api_target.clone()
+    }
+
+    /// Sets the canister serving the calls to the Bitcoin API.
+    /// Note: the calls are served by the node whatever the target.
+    fn set_bitcoin_api_target(&mut self, bitcoin_api_target: BitcoinApiTarget) {
+        self.bitcoin_api_target = bitcoin_api_target;
+    }
+
+    /// Returns the UTXOs of the given Bitcoin `address` according to `min_confirmations`, scanned with `scantxoutset`.
+    /// Note: the unconfirmed UTXOs aren't in the UTXO set scanned by the node.
+    async fn get_utxos(
+        &self,
+        address: &Address,
+        min_confirmations: u32,
+    ) -> Result<GetUtxosResponse, GetUtxosError> {
+        if min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
+            return Err(GetUtxosError::MinConfirmationsTooHigh);
+        }
+        let scan_result = self
+            .client
+            .scan_tx_out_set_blocking(&[ScanTxOutRequest::Single(format!("addr({})", address))])
+            .map_err(|error| {
+                GetUtxosError::ManagementCanisterReject(
+                    RejectionCode::CanisterError,
+                    error.to_string(),
+                )
+            })?;
+        let tip_height = match scan_result.height {
+            Some(height) => height as u32,
+            None => self.get_tip_height().map_err(
+                |ManagementCanisterReject(rejection_code, message)| {
+                    GetUtxosError::ManagementCanisterReject(rejection_code, message)
+                },
+            )?,
+        };
+        let utxos = scan_result
+            .unspents
+            .into_iter()
+            .map(|unspent| Utxo {
+                outpoint: OutPoint {
+                    txid: unspent.txid.to_byte_array().to_vec(),
+                    vout: unspent.vout,
+                },
+                value: unspent.amount.to_sat(),
+                height: unspent.height as u32,
+            })
+            .filter(|utxo| has_utxo_min_confirmations(utxo, tip_height, min_confirmations))
+            .collect();
+        Ok(GetUtxosResponse { utxos, tip_height })
+    }
+
+    /// Returns fees as percentiles in millisatoshis/byte estimated with `estimatesmartfee`, from the slowest confirmation target to the fastest one.
+    /// Returns no percentiles if the node can't estimate any fee, e.g. on a fresh regtest network, like the management canister without any transaction.
+    async fn get_current_fees(&self) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject> {
+        let mut fee_estimates = vec![];
+        for confirmation_target in FEE_ESTIMATES_CONFIRMATION_TARGETS {
+            // A fee rate in satoshis per kilo virtual byte is a fee rate in millisatoshis per virtual byte.
+            if let Some(fee_rate) = self
+                .client
+                .estimate_smart_fee(confirmation_target, None)
+                .map_err(get_reject)?
+                .fee_rate
+            {
+                fee_estimates.push(fee_rate.to_sat());
+            }
+        }
+        if fee_estimates.is_empty() {
+            return Ok(vec![]);
+        }
+        fee_estimates.sort_unstable();
+        Ok((0..FEE_PERCENTILES)
+            .map(|percentile| fee_estimates[percentile * fee_estimates.len() / FEE_PERCENTILES])
+            .collect())
+    }
+
+    /// Returns the tip height and the block headers from `start_height` to `end_height`, or to the tip if `end_height` is `None`.
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> Result<GetBlockHeadersResponse, ManagementCanisterReject> {
+        let tip_height = self.get_tip_height()?;
+        let end_height = end_height.unwrap_or(tip_height);
+        if start_height > end_height || end_height > tip_height {
+            return Err(ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                format!(
+                    "The heights {}..={} aren't within the tip height {}.",
+                    start_height, end_height, tip_height
+                ),
+            ));
+        }
+        let block_headers = (start_height..=end_height)
+            .map(|height| {
+                let block_hash = self
+                    .client
+                    .get_block_hash(height as u64)
+                    .map_err(get_reject)?;
+                self.client
+                    .get_block_header(&block_hash)
+                    .map(|block_header| serialize(&block_header))
+                    .map_err(get_reject)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(GetBlockHeadersResponse {
+            tip_height,
+            block_headers,
+        })
+    }
+
+    /// Returns the signature of the given `message_hash` computed by the local signer at the given derivation path.
+    async fn sign_with_ecdsa(
+        &self,
+        derivation_path: &DerivationPath,
+        message_hash: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        self.signer.sign_with_ecdsa(derivation_path, message_hash)
+    }
+
+    /// Returns the BIP-340 signature of the given `message` computed by the local signer at the given derivation path, tweaked as per BIP-86.
+    async fn sign_with_schnorr(
+        &self,
+        derivation_path: &DerivationPath,
+        message: &[u8],
+    ) -> Result<Vec<u8>, ManagementCanisterReject> {
+        self.signer.sign_with_schnorr(derivation_path, message)
+    }
+
+    /// Sends the given transaction to the network of the node with `sendrawtransaction`.
+    async fn send_transaction(
+        &self,
+        transaction: Vec<u8>,
+        _network: Network,
+    ) -> Result<(), ManagementCanisterReject> {
+        self.client
+            .send_raw_transaction(&transaction)
+            .map(|_| ())
+            .map_err(get_reject)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address_management::tests::get_btc_private_key, AddressType, BitcoinAgent, UtxosResult,
+    };
+    use bitcoin::{
+        absolute::LockTime,
+        blockdata::script::{Builder, PushBytes},
+        secp256k1::ecdsa::Signature,
+        sighash::{EcdsaSighashType, SighashCache},
+        Transaction, TxIn, TxOut, Txid,
+    };
+    use std::env;
+
+    /// Check that the local signer signs with the keys derived like the public keys of the addresses.
+    #[test]
+    fn check_local_key_signer() {
+        let signer = LocalKeySigner::new(get_btc_private_key().inner, vec![1; 32]);
+        let root = signer.get_ecdsa_public_key();
+        let derivation_path = DerivationPath::new(vec![vec![0, 0, 0, 1]]).unwrap();
+        let message_hash = [2; 32];
+        let signature = signer
+            .sign_with_ecdsa(&derivation_path, &message_hash)
+            .unwrap();
+        assert_eq!(signature.len(), 64);
+        assert_eq!(
+            crate::ecdsa::verify_signature(
+                &crate::ecdsa::derive_public_key(&root, &derivation_path),
+                &message_hash,
+                &Signature::from_compact(&signature).unwrap().serialize_der()
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            signer
+                .sign_with_ecdsa(&derivation_path, &[2; 31])
+                .unwrap_err()
+                .0,
+            RejectionCode::CanisterReject
+        );
+    }
+
+    /// Check that the UTXOs of a regtest address are retrieved from the node, and that a transaction spending one of them is broadcast and mined.
+    /// Run with `cargo test --features bitcoind-rpc -- --ignored` against the bitcoind set up in Section 5 of the documentation.
+    #[tokio::test]
+    #[ignore = "requires a regtest bitcoind whose RPC URL is BITCOIND_RPC_URL"]
+    async fn check_regtest_node() {
+        let url = env::var("BITCOIND_RPC_URL").expect("BITCOIND_RPC_URL isn't set.");
+        let auth = Auth::UserPass(
+            env::var("BITCOIND_RPC_USER").unwrap_or_else(|_| String::from("btc-library")),
+            env::var("BITCOIND_RPC_PASSWORD")
+                .unwrap_or_else(|_| String::from("Wjh4u6SAjT4UMJKxPmoZ0AN2r9qbE-ksXQ5I2_-Hm4w=")),
+        );
+        let signer = Arc::new(LocalKeySigner::new(get_btc_private_key().inner, vec![]));
+        let ecdsa_public_key = signer.get_ecdsa_public_key();
+        let management_canister =
+            ManagementCanisterRpc::new(crate::Network::Regtest, &url, auth, signer).unwrap();
+        let mut bitcoin_agent =
+            BitcoinAgent::new(management_canister, &AddressType::P2pkh, 1).unwrap();
+        assert!(bitcoin_agent.initialize(ecdsa_public_key.clone()));
+        let main_address = bitcoin_agent.get_main_address();
+        let management_canister = &bitcoin_agent.management_canister;
+        let utxos_count = management_canister
+            .get_utxos(&main_address, 1)
+            .await
+            .unwrap()
+            .utxos
+            .len();
+
+        // The coinbase outputs can be spent once they have 100 confirmations.
+        management_canister
+            .client()
+            .generate_to_address(101, &main_address)
+            .unwrap();
+        let get_utxos_response = management_canister
+            .get_utxos(&main_address, 1)
+            .await
+            .unwrap();
+        assert_eq!(get_utxos_response.utxos.len(), utxos_count + 101);
+        let block_headers = management_canister
+            .get_block_headers(get_utxos_response.tip_height, None)
+            .await
+            .unwrap();
+        assert_eq!(block_headers.tip_height, get_utxos_response.tip_height);
+        assert_eq!(block_headers.block_headers.len(), 1);
+
+        // Spend the oldest UTXO back to the main address.
+        let utxo = get_utxos_response
+            .utxos
+            .iter()
+            .min_by_key(|utxo| utxo.height)
+            .unwrap()
+            .clone();
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: bitcoin::OutPoint {
+                    txid: Txid::from_slice(&utxo.outpoint.txid).unwrap(),
+                    vout: utxo.outpoint.vout,
+                },
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: utxo.value - 10_000,
+                script_pubkey: main_address.script_pubkey(),
+            }],
+        };
+        let sighash = SighashCache::new(&transaction)
+            .legacy_signature_hash(
+                0,
+                &main_address.script_pubkey(),
+                EcdsaSighashType::All.to_u32(),
+            )
+            .unwrap();
+        let signature = management_canister
+            .sign_with_ecdsa(&DerivationPath::default(), sighash.as_byte_array())
+            .await
+            .unwrap();
+        let mut der_signature = Signature::from_compact(&signature)
+            .unwrap()
+            .serialize_der()
+            .to_vec();
+        der_signature.push(EcdsaSighashType::All as u8);
+        transaction.input[0].script_sig = Builder::new()
+            .push_slice(<&PushBytes>::try_from(der_signature.as_slice()).unwrap())
+            .push_slice(<&PushBytes>::try_from(ecdsa_public_key.public_key.as_slice()).unwrap())
+            .into_script();
+        management_canister
+            .send_transaction(serialize(&transaction), Network::Regtest)
+            .await
+            .unwrap();
+        management_canister
+            .client()
+            .generate_to_address(1, &main_address)
+            .unwrap();
+
+        let utxos = management_canister
+            .get_utxos(&main_address, 1)
+            .await
+            .unwrap()
+            .utxos;
+        assert!(!utxos.contains(&utxo));
+        assert!(utxos
+            .iter()
+            .any(|utxo| utxo.outpoint.txid == transaction.txid().to_byte_array().to_vec()));
+        let tip_height = get_utxos_response.tip_height + 1;
+        let balance: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+        bitcoin_agent.apply_utxos(UtxosResult {
+            address: main_address.clone(),
+            utxos,
+            tip_height,
+        });
+        assert_eq!(
+            bitcoin_agent
+                .get_balance_update(&main_address)
+                .unwrap()
+                .added_balance,
+            balance
+        );
+    }
+}
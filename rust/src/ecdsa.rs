@@ -1,25 +1,34 @@
 use crate::{
-    canister_common::SIGN_WITH_ECDSA_COST_CYCLES,
+    bip32_extended_derivation::extended_bip32_derivation,
+    canister_common::{call, call_with_payment, get_cycles_spent},
     types::{
         ECDSAPublicKey, ECDSAPublicKeyReply, EcdsaCurve, EcdsaKeyId, SignWithECDSA,
         SignWithECDSAReply,
     },
-    EcdsaPubKey, ManagementCanisterReject,
+    CyclesCostConfig, DerivationPath, EcdsaPubKey, ManagementCanisterMethod,
+    ManagementCanisterReject, SignatureError, WithCost,
+};
+use bitcoin::{
+    secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1},
+    Network,
 };
-use bitcoin::Network;
 use candid::Principal;
-use ic_cdk::{api::call::call_with_payment, call};
 
 /// Returns the key name associated with a given Bitcoin network.
 pub(crate) fn get_key_name_from_network(network: Network) -> String {
     String::from(match network {
         // A special test key with dfx is used for local development.
         Network::Regtest => "dfx_test_key",
-        // A test ECDSA key is used on the IC.
+        // A test ECDSA key is used on the IC, the signet being only served off-chain.
         _ => "test_key_1",
     })
 }
 
+/// Returns `ecdsa_key_name` if any, the key name associated with the given Bitcoin network otherwise.
+pub(crate) fn get_key_name(network: Network, ecdsa_key_name: Option<String>) -> String {
+    ecdsa_key_name.unwrap_or_else(|| get_key_name_from_network(network))
+}
+
 /// Returns the Bitcoin ECDSA public key of this canister.
 pub(crate) async fn get_btc_ecdsa_public_key(
     key_name: &String,
@@ -28,7 +37,7 @@ pub(crate) async fn get_btc_ecdsa_public_key(
     Ok(EcdsaPubKey {
         public_key: ecdsa_public_key_reply.public_key,
         chain_code: ecdsa_public_key_reply.chain_code,
-        derivation_path: vec![],
+        derivation_path: DerivationPath::default(),
     })
 }
 
@@ -50,6 +59,7 @@ pub(crate) async fn ecdsa_public_key(
                 name: key_name.to_string(),
             },
         },),
+        0,
     )
     .await;
 
@@ -62,32 +72,125 @@ pub(crate) async fn ecdsa_public_key(
     }
 }
 
-/// Returns the signature of the given `message_hash` associated with the ECDSA public key of this canister at the given derivation path.
+/// Returns the signature of the given `message_hash` associated with the ECDSA public key of this canister at the given derivation path and the cycles spent to obtain it.
 pub(crate) async fn sign_with_ecdsa(
     key_name: String,
-    derivation_path: Vec<Vec<u8>>,
+    derivation_path: DerivationPath,
     message_hash: Vec<u8>,
-) -> Result<Vec<u8>, ManagementCanisterReject> {
+    cycles_cost_config: CyclesCostConfig,
+) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
     let res: Result<(SignWithECDSAReply,), _> = call_with_payment(
         Principal::management_canister(),
-        "sign_with_ecdsa",
+        ManagementCanisterMethod::SignWithEcdsa,
         (SignWithECDSA {
             message_hash,
-            derivation_path,
+            derivation_path: derivation_path.into(),
             key_id: EcdsaKeyId {
                 curve: EcdsaCurve::Secp256k1,
                 name: key_name,
             },
         },),
-        SIGN_WITH_ECDSA_COST_CYCLES,
+        cycles_cost_config.sign_with_ecdsa,
     )
     .await;
 
     match res {
         // Return the signature to the caller.
-        Ok(data) => Ok(data.0.signature),
+        Ok(data) => Ok(WithCost {
+            result: data.0.signature,
+            cycles_spent: get_cycles_spent(
+                ManagementCanisterMethod::SignWithEcdsa,
+                cycles_cost_config.sign_with_ecdsa,
+            ),
+            attempts: 1,
+        }),
 
         // The call to `sign_with_ecdsa` was rejected for a given reason (e.g., not enough cycles were attached to the call).
         Err((rejection_code, message)) => Err(ManagementCanisterReject(rejection_code, message)),
     }
 }
+
+/// Returns the public key derived from the `root` ECDSA public key at the given derivation path, like the management canister does.
+pub fn derive_public_key(root: &EcdsaPubKey, derivation_path: &[Vec<u8>]) -> Vec<u8> {
+    extended_bip32_derivation(&root.public_key, &root.chain_code, derivation_path).0
+}
+
+/// Returns true if `der_signature` is a valid signature of `message_hash` for the SEC1-encoded `public_key`, as computed when signing transactions.
+/// The DER encoding doesn't have to be strict and high-S signatures are normalized, as the library normalizes them before embedding them.
+pub fn verify_signature(
+    public_key: &[u8],
+    message_hash: &[u8],
+    der_signature: &[u8],
+) -> Result<bool, SignatureError> {
+    let public_key =
+        PublicKey::from_slice(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    let message =
+        Message::from_slice(message_hash).map_err(|_| SignatureError::InvalidMessageHash)?;
+    let mut signature =
+        Signature::from_der_lax(der_signature).map_err(|_| SignatureError::InvalidSignature)?;
+    signature.normalize_s();
+    Ok(Secp256k1::verification_only()
+        .verify_ecdsa(&message, &signature, &public_key)
+        .is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address_management::tests::{get_btc_ecdsa_public_key, get_btc_private_key},
+        canister_common::ManagementCanisterFactory,
+        canister_mock::ManagementCanisterMock,
+    };
+
+    /// Check that the signatures of the mock are verified with the public keys derived at the same derivation paths.
+    #[test]
+    fn check_verify_signature() {
+        let management_canister = ManagementCanisterMock::new(crate::Network::Regtest, None);
+        let private_key = get_btc_private_key().to_bytes();
+        let root = EcdsaPubKey {
+            chain_code: vec![1; 32],
+            ..get_btc_ecdsa_public_key()
+        };
+        let message_hash = [2; 32];
+        for derivation_path in [
+            vec![],
+            vec![vec![0, 0, 0, 1]],
+            vec![vec![0, 0, 0, 1], vec![0, 0, 0, 2]],
+            vec![vec![0x7f, 0xff, 0xff, 0xff]],
+        ] {
+            let der_signature = management_canister.internal_sign_with_ecdsa(
+                &private_key,
+                &root.chain_code,
+                &derivation_path,
+                &message_hash,
+            );
+            let public_key = derive_public_key(&root, &derivation_path);
+            assert_eq!(
+                verify_signature(&public_key, &message_hash, &der_signature),
+                Ok(true)
+            );
+            assert_eq!(
+                verify_signature(&public_key, &[3; 32], &der_signature),
+                Ok(false)
+            );
+            assert_eq!(
+                verify_signature(&root.public_key, &message_hash, &der_signature),
+                Ok(derivation_path.is_empty())
+            );
+        }
+
+        assert_eq!(
+            verify_signature(&[2; 32], &message_hash, &[]),
+            Err(SignatureError::InvalidPublicKey)
+        );
+        assert_eq!(
+            verify_signature(&root.public_key, &[2; 31], &[]),
+            Err(SignatureError::InvalidMessageHash)
+        );
+        assert_eq!(
+            verify_signature(&root.public_key, &message_hash, &[0x30, 0x00]),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+}
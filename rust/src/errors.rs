@@ -0,0 +1,1159 @@
+//! Human-readable messages of the errors of the library, which also implement `std::error::Error` so that they can be propagated with `?`, e.g. into a `Box<dyn Error>`.
+//! Every error also converts into `BtcLibError` and back.
+
+use crate::{
+    types::BitcoinAddressError, AddAddressWithParametersError, AddressNotTracked, AddressType,
+    AgentRegistryError, ApplyMultiTransferResultError, BtcLibError, BuildError,
+    DerivationPathError, ErrorDetail, GetCurrentFeeError, GetUtxosError, InputSigningFailure,
+    IntegrityIssue, InvalidCyclesCostConfig, InvalidPercentile, ManagementCanisterReject,
+    MinConfirmationsTooHigh, MultiTransferError, NetChangeOverflow, Network, ParseAddressTypeError,
+    ParseNetworkError, RateLimited, RegtestUnavailable, SignMessageError, SignatureError,
+    StandardFeePercentileTooHigh, StateImportError, StateRestoreError, UnsupportedNetwork,
+    MAX_DERIVATION_PATH_ELEMENT_LENGTH, MAX_DERIVATION_PATH_LENGTH, MIN_CONFIRMATIONS_UPPER_BOUND,
+};
+use std::{error::Error, fmt};
+
+impl fmt::Display for BitcoinAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitcoinAddressError::Hashes(message) => {
+                write!(f, "invalid hash of the address: {}", message)
+            }
+            BitcoinAddressError::UtilKey(message) => {
+                write!(f, "invalid public key of the address: {}", message)
+            }
+            BitcoinAddressError::UtilAddress(message) => write!(f, "invalid address: {}", message),
+        }
+    }
+}
+
+impl Error for BitcoinAddressError {}
+
+impl fmt::Display for DerivationPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DerivationPathError::TooLong => write!(
+                f,
+                "the derivation path has more than {} elements",
+                MAX_DERIVATION_PATH_LENGTH
+            ),
+            DerivationPathError::ElementTooLong { index } => write!(
+                f,
+                "the element {} of the derivation path has more than {} bytes",
+                index, MAX_DERIVATION_PATH_ELEMENT_LENGTH
+            ),
+            DerivationPathError::HardenedElement { index } => write!(
+                f,
+                "the element {} of the derivation path is a hardened BIP-32 index",
+                index
+            ),
+        }
+    }
+}
+
+impl Error for DerivationPathError {}
+
+impl fmt::Display for AddressNotTracked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the address isn't managed by the Bitcoin agent")
+    }
+}
+
+impl Error for AddressNotTracked {}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited until the tip height {}", self.retry_after)
+    }
+}
+
+impl Error for RateLimited {}
+
+impl fmt::Display for UnsupportedNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the Bitcoin API of the IC doesn't serve the {:?} network",
+            self.0
+        )
+    }
+}
+
+impl Error for UnsupportedNetwork {}
+
+impl fmt::Display for RegtestUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the regtest network isn't available on the IC")
+    }
+}
+
+impl Error for RegtestUnavailable {}
+
+impl fmt::Display for MinConfirmationsTooHigh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the minimum number of confirmations exceeds {}",
+            MIN_CONFIRMATIONS_UPPER_BOUND
+        )
+    }
+}
+
+impl Error for MinConfirmationsTooHigh {}
+
+impl fmt::Display for StandardFeePercentileTooHigh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the standard fee percentile exceeds 100")
+    }
+}
+
+impl Error for StandardFeePercentileTooHigh {}
+
+impl fmt::Display for InvalidPercentile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a percentile exceeds 100")
+    }
+}
+
+impl Error for InvalidPercentile {}
+
+impl fmt::Display for AddAddressWithParametersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddAddressWithParametersError::InvalidDerivationPath(_) => {
+                write!(f, "invalid derivation path")
+            }
+            AddAddressWithParametersError::MinConfirmationsTooHigh => {
+                write!(f, "{}", MinConfirmationsTooHigh)
+            }
+        }
+    }
+}
+
+impl Error for AddAddressWithParametersError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AddAddressWithParametersError::InvalidDerivationPath(error) => Some(error),
+            AddAddressWithParametersError::MinConfirmationsTooHigh => None,
+        }
+    }
+}
+
+impl fmt::Display for StateRestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateRestoreError::UnknownVersion(version) => {
+                write!(f, "unknown version {} of the state", version)
+            }
+            StateRestoreError::InvalidAddress(address) => {
+                write!(f, "the address {} can't be parsed", address)
+            }
+            StateRestoreError::AddressNetworkMismatch(address) => write!(
+                f,
+                "the address {} doesn't belong to the network of the state",
+                address
+            ),
+            StateRestoreError::NetworkMismatch { expected, found } => write!(
+                f,
+                "the state belongs to the {:?} network instead of the {:?} network",
+                found, expected
+            ),
+            StateRestoreError::DuplicateAddress(address) => write!(
+                f,
+                "the address {} is associated with multiple entries",
+                address
+            ),
+            StateRestoreError::MinConfirmationsTooHigh => write!(f, "{}", MinConfirmationsTooHigh),
+            StateRestoreError::InvalidEcdsaPubKey => {
+                write!(f, "the ECDSA public key of the state is invalid")
+            }
+            StateRestoreError::EcdsaPubKeyMismatch(address) => write!(
+                f,
+                "the ECDSA public key of the address {} doesn't match the one of the state",
+                address
+            ),
+            StateRestoreError::InvalidCompactedAddress(address) => write!(
+                f,
+                "the address {} is marked as compacted but its UTXOs states aren't",
+                address
+            ),
+            StateRestoreError::DecodeFailed(message) => {
+                write!(f, "the state can't be decoded: {}", message)
+            }
+            StateRestoreError::IntegrityIssue(_) => {
+                write!(f, "the restored Bitcoin agent has an integrity issue")
+            }
+            #[cfg(feature = "stable-memory")]
+            StateRestoreError::NoStableState => {
+                write!(f, "the stable memory doesn't hold any Bitcoin agent")
+            }
+        }
+    }
+}
+
+impl Error for StateRestoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StateRestoreError::IntegrityIssue(integrity_issue) => Some(integrity_issue),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IntegrityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityIssue::InvalidEcdsaPubKey => {
+                write!(f, "the ECDSA public key of the Bitcoin agent is invalid")
+            }
+            IntegrityIssue::DerivationPathMismatch(address) => write!(
+                f,
+                "the derivation path of the address {} doesn't extend the one of the Bitcoin agent",
+                address
+            ),
+            IntegrityIssue::EcdsaPubKeyMismatch(address) => write!(
+                f,
+                "the ECDSA public key of the address {} isn't derived from the one of the Bitcoin agent",
+                address
+            ),
+            IntegrityIssue::AddressMismatch(address) => write!(
+                f,
+                "the address {} isn't the one derived at its derivation path",
+                address
+            ),
+            IntegrityIssue::MissingUtxosState(address) => {
+                write!(f, "the address {} has no UTXOs state", address)
+            }
+        }
+    }
+}
+
+impl Error for IntegrityIssue {}
+
+impl fmt::Display for StateImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateImportError::Truncated => write!(f, "the exported state is truncated"),
+            StateImportError::InvalidMagic => {
+                write!(f, "the bytes don't hold an exported state")
+            }
+            StateImportError::UnknownVersion(version) => {
+                write!(f, "unknown version {} of the exported state", version)
+            }
+            StateImportError::ChecksumMismatch => {
+                write!(f, "the checksum of the exported state doesn't match")
+            }
+            StateImportError::DecodeFailed(message) => {
+                write!(f, "the exported state can't be decoded: {}", message)
+            }
+            StateImportError::InvalidState(_) => {
+                write!(f, "the exported state can't be restored")
+            }
+        }
+    }
+}
+
+impl Error for StateImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StateImportError::InvalidState(state_restore_error) => Some(state_restore_error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AgentRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentRegistryError::UnknownAgent(agent) => {
+                write!(f, "no Bitcoin agent is named {}", agent)
+            }
+            AgentRegistryError::DuplicateAddress { address, agent } => write!(
+                f,
+                "the address {} is already managed by the Bitcoin agent {}",
+                address, agent
+            ),
+            AgentRegistryError::InvalidState { agent, .. } => write!(
+                f,
+                "the state of the Bitcoin agent {} can't be restored",
+                agent
+            ),
+        }
+    }
+}
+
+impl Error for AgentRegistryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AgentRegistryError::InvalidState { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApplyMultiTransferResultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyMultiTransferResultError::InvalidAddress(address) => {
+                write!(f, "the address {} can't be parsed", address)
+            }
+            ApplyMultiTransferResultError::AddressNotTracked(address) => write!(
+                f,
+                "the address {} isn't managed by the Bitcoin agent",
+                address
+            ),
+        }
+    }
+}
+
+impl Error for ApplyMultiTransferResultError {}
+
+impl fmt::Display for GetUtxosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetUtxosError::MinConfirmationsTooHigh => write!(f, "{}", MinConfirmationsTooHigh),
+            GetUtxosError::ManagementCanisterReject(rejection_code, message) => {
+                write!(
+                    f,
+                    "{}",
+                    ManagementCanisterReject(*rejection_code, message.clone())
+                )
+            }
+            GetUtxosError::InvalidResponse { reason } => {
+                write!(f, "invalid response of bitcoin_get_utxos: {}", reason)
+            }
+            GetUtxosError::ResponseTooLarge { message } => write!(
+                f,
+                "the UTXOs of the address exceed the size limit of the responses: {}",
+                message
+            ),
+            GetUtxosError::AddressNotTracked => write!(f, "{}", AddressNotTracked),
+        }
+    }
+}
+
+impl Error for GetUtxosError {}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::InvalidPublicKey => write!(f, "invalid public key"),
+            SignatureError::InvalidMessageHash => write!(f, "invalid message hash"),
+            SignatureError::InvalidSignature => write!(f, "invalid DER signature"),
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+impl fmt::Display for ManagementCanisterReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the management canister rejected the call with {:?}: {}",
+            self.0, self.1
+        )
+    }
+}
+
+impl Error for ManagementCanisterReject {}
+
+impl fmt::Display for InvalidCyclesCostConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidCyclesCostConfig::ZeroCost { cost } => {
+                write!(f, "no cycles would be attached for {}", cost)
+            }
+            InvalidCyclesCostConfig::AboveCeiling {
+                cost,
+                cycles,
+                ceiling,
+            } => write!(
+                f,
+                "the {} cycles of {} exceed the ceiling of {} cycles",
+                cycles, cost, ceiling
+            ),
+            InvalidCyclesCostConfig::InvalidCandid(message) => {
+                write!(f, "invalid Candid cycles cost config: {}", message)
+            }
+        }
+    }
+}
+
+impl Error for InvalidCyclesCostConfig {}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MinConfirmationsTooHigh => write!(f, "{}", MinConfirmationsTooHigh),
+            BuildError::InvalidEcdsaKeyName(ecdsa_key_name) => {
+                write!(f, "invalid ECDSA key name {:?}", ecdsa_key_name)
+            }
+            BuildError::StandardFeePercentileTooHigh => {
+                write!(f, "{}", StandardFeePercentileTooHigh)
+            }
+            BuildError::InvalidPercentile => write!(f, "{}", InvalidPercentile),
+            BuildError::InvalidCyclesCostConfig(_) => write!(f, "invalid cycles cost config"),
+            BuildError::ConflictingOptions(reason) => {
+                write!(f, "conflicting options: {}", reason)
+            }
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::InvalidCyclesCostConfig(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GetCurrentFeeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetCurrentFeeError::InvalidPercentile {
+                requested,
+                available,
+            } => write!(
+                f,
+                "the percentile {} exceeds 100, {} fee percentiles being available",
+                requested, available
+            ),
+            GetCurrentFeeError::NoFeeData => {
+                write!(f, "the management canister doesn't have any fee data")
+            }
+            GetCurrentFeeError::ManagementCanisterReject(rejection_code, message) => {
+                write!(
+                    f,
+                    "{}",
+                    ManagementCanisterReject(*rejection_code, message.clone())
+                )
+            }
+        }
+    }
+}
+
+impl Error for GetCurrentFeeError {}
+
+impl fmt::Display for SignMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignMessageError::UnsupportedAddressType => write!(
+                f,
+                "only messages of P2PKH and P2WPKH addresses can be signed"
+            ),
+            SignMessageError::InvalidDerivationPath(_) => {
+                write!(f, "invalid derivation path of the address")
+            }
+            SignMessageError::ManagementCanisterReject(rejection_code, message) => {
+                write!(
+                    f,
+                    "{}",
+                    ManagementCanisterReject(*rejection_code, message.clone())
+                )
+            }
+        }
+    }
+}
+
+impl Error for SignMessageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SignMessageError::InvalidDerivationPath(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InputSigningFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rejected the signature of the input {} with {:?}: {}",
+            self.method.name(),
+            self.input,
+            self.rejection_code,
+            self.message
+        )
+    }
+}
+
+impl fmt::Display for MultiTransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultiTransferError::FeeTooLow => write!(f, "the fee is too low"),
+            MultiTransferError::FeeTooHigh { fee, max_fee } => write!(
+                f,
+                "the fee of {} satoshis exceeds the maximum fee of {} satoshis",
+                fee, max_fee
+            ),
+            MultiTransferError::InvalidFeeMultiplier => {
+                write!(f, "the denominator of the fee multiplier is 0")
+            }
+            MultiTransferError::InvalidPercentile {
+                requested,
+                available,
+            } => write!(
+                f,
+                "{}",
+                GetCurrentFeeError::InvalidPercentile {
+                    requested: *requested,
+                    available: *available,
+                }
+            ),
+            MultiTransferError::NoFeeData => write!(
+                f,
+                "the management canister doesn't have any fee data and there is no fallback fee"
+            ),
+            MultiTransferError::InsufficientBalance => {
+                write!(f, "the balance doesn't cover the payouts and the fee")
+            }
+            MultiTransferError::MinConfirmationsTooHigh => write!(f, "{}", MinConfirmationsTooHigh),
+            MultiTransferError::InputNotSpendable => write!(f, "an input isn't spendable"),
+            MultiTransferError::DuplicateInput => write!(f, "an input is given several times"),
+            MultiTransferError::DustOutput => write!(f, "an output is dust"),
+            MultiTransferError::ScriptTooLong => write!(f, "a script payout is too long"),
+            MultiTransferError::NonStandardScript => {
+                write!(f, "a script payout isn't standard")
+            }
+            MultiTransferError::SingleAddressSelectionFailed => {
+                write!(f, "no single address covers the transaction")
+            }
+            MultiTransferError::TransactionTooLarge { weight, inputs } => write!(
+                f,
+                "the transaction of weight {} with {} inputs is too large",
+                weight, inputs
+            ),
+            MultiTransferError::InvalidDerivationPath(_) => {
+                write!(f, "invalid derivation path of a spent address")
+            }
+            MultiTransferError::ManagementCanisterReject {
+                method,
+                rejection_code,
+                message,
+            } => write!(
+                f,
+                "the management canister rejected the call to {} with {:?}: {}",
+                method.name(),
+                rejection_code,
+                message
+            ),
+            MultiTransferError::SigningFailed { failed_inputs } => {
+                write!(f, "the signature of {} inputs failed", failed_inputs.len())?;
+                for failed_input in failed_inputs {
+                    write!(f, "; {}", failed_input)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for MultiTransferError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MultiTransferError::InvalidDerivationPath(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown network \"{}\", expected one of: {}",
+            self.0,
+            join_names(Network::ALL)
+        )
+    }
+}
+
+impl Error for ParseNetworkError {}
+
+impl fmt::Display for ParseAddressTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown address type \"{}\", expected one of: {}",
+            self.0,
+            join_names(AddressType::ALL)
+        )
+    }
+}
+
+impl Error for ParseAddressTypeError {}
+
+impl fmt::Display for NetChangeOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the net change of the balance doesn't fit in an i64")
+    }
+}
+
+impl Error for NetChangeOverflow {}
+
+/// Returns the displayed names of the given values separated by commas.
+fn join_names<T: fmt::Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Implements the conversions of the errors into `BtcLibError` and back, each error being kept as the `ErrorDetail` variant.
+macro_rules! impl_btc_lib_error_conversions {
+    ($($error:ty => $detail:ident),* $(,)?) => {
+        $(
+            impl From<$error> for BtcLibError {
+                fn from(error: $error) -> Self {
+                    BtcLibError::new(ErrorDetail::$detail(error))
+                }
+            }
+
+            impl TryFrom<BtcLibError> for $error {
+                type Error = BtcLibError;
+
+                /// Fails with the given error if its original error is of another type.
+                fn try_from(error: BtcLibError) -> Result<Self, Self::Error> {
+                    match error.into_detail() {
+                        ErrorDetail::$detail(error) => Ok(error),
+                        detail => Err(BtcLibError::new(detail)),
+                    }
+                }
+            }
+        )*
+
+        impl ErrorDetail {
+            /// Returns the original error.
+            fn as_error(&self) -> &(dyn Error + 'static) {
+                match self {
+                    $(ErrorDetail::$detail(error) => error,)*
+                }
+            }
+        }
+    };
+}
+
+impl_btc_lib_error_conversions!(
+    BitcoinAddressError => BitcoinAddress,
+    DerivationPathError => DerivationPath,
+    AddressNotTracked => AddressNotTracked,
+    RateLimited => RateLimited,
+    UnsupportedNetwork => UnsupportedNetwork,
+    RegtestUnavailable => RegtestUnavailable,
+    MinConfirmationsTooHigh => MinConfirmationsTooHigh,
+    StandardFeePercentileTooHigh => StandardFeePercentileTooHigh,
+    InvalidPercentile => InvalidPercentile,
+    AddAddressWithParametersError => AddAddressWithParameters,
+    StateRestoreError => StateRestore,
+    IntegrityIssue => IntegrityIssue,
+    StateImportError => StateImport,
+    AgentRegistryError => AgentRegistry,
+    ApplyMultiTransferResultError => ApplyMultiTransferResult,
+    GetUtxosError => GetUtxos,
+    SignatureError => Signature,
+    ManagementCanisterReject => ManagementCanisterReject,
+    InvalidCyclesCostConfig => InvalidCyclesCostConfig,
+    BuildError => Build,
+    GetCurrentFeeError => GetCurrentFee,
+    SignMessageError => SignMessage,
+    MultiTransferError => MultiTransfer,
+    ParseNetworkError => ParseNetwork,
+    ParseAddressTypeError => ParseAddressType,
+    NetChangeOverflow => NetChangeOverflow,
+);
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_error())
+    }
+}
+
+impl Error for ErrorDetail {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.as_error().source()
+    }
+}
+
+impl fmt::Display for BtcLibError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.detail())
+    }
+}
+
+impl Error for BtcLibError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.detail().source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManagementCanisterMethod;
+    use candid::{decode_one, encode_one, types::TypeInner, CandidType, Deserialize, TypeEnv};
+    use candid_parser::{check_prog, IDLArgs, IDLProg};
+    use ic_cdk::api::call::RejectionCode;
+    use std::fmt::Debug;
+
+    /// Check the messages of representative errors and that the wrapped errors are their sources.
+    #[test]
+    fn check_error_messages() {
+        assert_eq!(
+            ManagementCanisterReject(RejectionCode::SysTransient, String::from("Busy."))
+                .to_string(),
+            "the management canister rejected the call with SysTransient: Busy."
+        );
+        assert_eq!(
+            GetUtxosError::InvalidResponse {
+                reason: String::from("duplicated outpoint")
+            }
+            .to_string(),
+            "invalid response of bitcoin_get_utxos: duplicated outpoint"
+        );
+        assert_eq!(
+            AddressNotTracked.to_string(),
+            "the address isn't managed by the Bitcoin agent"
+        );
+        assert_eq!(
+            MinConfirmationsTooHigh.to_string(),
+            "the minimum number of confirmations exceeds 6"
+        );
+        assert_eq!(
+            RateLimited { retry_after: 42 }.to_string(),
+            "rate limited until the tip height 42"
+        );
+        assert_eq!(
+            MultiTransferError::TransactionTooLarge {
+                weight: 500_000,
+                inputs: 1_000
+            }
+            .to_string(),
+            "the transaction of weight 500000 with 1000 inputs is too large"
+        );
+        assert_eq!(
+            MultiTransferError::SigningFailed {
+                failed_inputs: vec![InputSigningFailure {
+                    input: 3,
+                    method: ManagementCanisterMethod::SignWithEcdsa,
+                    rejection_code: RejectionCode::SysTransient,
+                    message: String::from("Busy."),
+                }]
+            }
+            .to_string(),
+            "the signature of 1 inputs failed; sign_with_ecdsa rejected the signature of the input 3 with SysTransient: Busy."
+        );
+        assert_eq!(
+            StateRestoreError::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet
+            }
+            .to_string(),
+            "the state belongs to the Testnet network instead of the Mainnet network"
+        );
+
+        let error =
+            MultiTransferError::InvalidDerivationPath(DerivationPathError::ElementTooLong {
+                index: 2,
+            });
+        assert_eq!(
+            error.source().unwrap().to_string(),
+            format!(
+                "the element 2 of the derivation path has more than {} bytes",
+                MAX_DERIVATION_PATH_ELEMENT_LENGTH
+            )
+        );
+        let error = StateImportError::InvalidState(StateRestoreError::IntegrityIssue(
+            IntegrityIssue::MissingUtxosState(String::from("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")),
+        ));
+        assert_eq!(
+            error.source().unwrap().source().unwrap().to_string(),
+            "the address mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76 has no UTXOs state"
+        );
+        assert!(GetUtxosError::AddressNotTracked.source().is_none());
+
+        // The errors are propagated with `?` into a `Box<dyn Error>`.
+        fn add_address() -> Result<(), Box<dyn Error>> {
+            Err(AddAddressWithParametersError::MinConfirmationsTooHigh)?
+        }
+        assert_eq!(
+            add_address().unwrap_err().to_string(),
+            "the minimum number of confirmations exceeds 6"
+        );
+    }
+
+    /// Candid-encodes each value, checking that it decodes back to itself and that it matches the type `name` of `fixtures/errors.did`.
+    fn check_candid_values<T>(env: &TypeEnv, name: &str, values: Vec<T>)
+    where
+        T: CandidType + for<'de> Deserialize<'de> + PartialEq + Debug,
+    {
+        for value in values {
+            let bytes = encode_one(&value).unwrap();
+            assert_eq!(decode_one::<T>(&bytes).unwrap(), value);
+            if let Err(error) = IDLArgs::from_bytes_with_types(
+                &bytes,
+                env,
+                &[TypeInner::Var(String::from(name)).into()],
+            ) {
+                panic!("{:?} doesn't match the type {}: {}", value, name, error);
+            }
+        }
+    }
+
+    /// Check that each variant of the errors is Candid-encoded with the types of `fixtures/errors.did`.
+    #[test]
+    fn check_candid_encoding() {
+        let prog: IDLProg = include_str!("../fixtures/errors.did").parse().unwrap();
+        let mut env = TypeEnv::new();
+        check_prog(&mut env, &prog).unwrap();
+        let address = || String::from("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76");
+        let message = || String::from("Busy.");
+
+        check_candid_values(
+            &env,
+            "ManagementCanisterReject",
+            vec![ManagementCanisterReject(
+                RejectionCode::SysTransient,
+                message(),
+            )],
+        );
+        check_candid_values(
+            &env,
+            "BitcoinAddressError",
+            vec![
+                BitcoinAddressError::Hashes(message()),
+                BitcoinAddressError::UtilKey(message()),
+                BitcoinAddressError::UtilAddress(message()),
+            ],
+        );
+        check_candid_values(
+            &env,
+            "DerivationPathError",
+            vec![
+                DerivationPathError::TooLong,
+                DerivationPathError::ElementTooLong { index: 1 },
+                DerivationPathError::HardenedElement { index: 2 },
+            ],
+        );
+        check_candid_values(
+            &env,
+            "AddAddressWithParametersError",
+            vec![
+                AddAddressWithParametersError::InvalidDerivationPath(DerivationPathError::TooLong),
+                AddAddressWithParametersError::MinConfirmationsTooHigh,
+            ],
+        );
+        let integrity_issues = || {
+            vec![
+                IntegrityIssue::InvalidEcdsaPubKey,
+                IntegrityIssue::DerivationPathMismatch(address()),
+                IntegrityIssue::EcdsaPubKeyMismatch(address()),
+                IntegrityIssue::AddressMismatch(address()),
+                IntegrityIssue::MissingUtxosState(address()),
+            ]
+        };
+        check_candid_values(&env, "IntegrityIssue", integrity_issues());
+        let state_restore_errors = || {
+            let mut errors = vec![
+                StateRestoreError::UnknownVersion(42),
+                StateRestoreError::InvalidAddress(address()),
+                StateRestoreError::AddressNetworkMismatch(address()),
+                StateRestoreError::NetworkMismatch {
+                    expected: Network::Mainnet,
+                    found: Network::Testnet,
+                },
+                StateRestoreError::DuplicateAddress(address()),
+                StateRestoreError::MinConfirmationsTooHigh,
+                StateRestoreError::InvalidEcdsaPubKey,
+                StateRestoreError::EcdsaPubKeyMismatch(address()),
+                StateRestoreError::InvalidCompactedAddress(address()),
+                StateRestoreError::DecodeFailed(message()),
+            ];
+            errors.extend(
+                integrity_issues()
+                    .into_iter()
+                    .map(StateRestoreError::IntegrityIssue),
+            );
+            #[cfg(feature = "stable-memory")]
+            errors.push(StateRestoreError::NoStableState);
+            errors
+        };
+        check_candid_values(&env, "StateRestoreError", state_restore_errors());
+        let mut state_import_errors = vec![
+            StateImportError::Truncated,
+            StateImportError::InvalidMagic,
+            StateImportError::UnknownVersion(42),
+            StateImportError::ChecksumMismatch,
+            StateImportError::DecodeFailed(message()),
+        ];
+        state_import_errors.extend(
+            state_restore_errors()
+                .into_iter()
+                .map(StateImportError::InvalidState),
+        );
+        check_candid_values(&env, "StateImportError", state_import_errors);
+        check_candid_values(
+            &env,
+            "AgentRegistryError",
+            vec![
+                AgentRegistryError::UnknownAgent(String::from("payouts")),
+                AgentRegistryError::DuplicateAddress {
+                    address: address(),
+                    agent: String::from("payouts"),
+                },
+                AgentRegistryError::InvalidState {
+                    agent: String::from("payouts"),
+                    error: StateRestoreError::UnknownVersion(42),
+                },
+            ],
+        );
+        check_candid_values(
+            &env,
+            "ApplyMultiTransferResultError",
+            vec![
+                ApplyMultiTransferResultError::InvalidAddress(address()),
+                ApplyMultiTransferResultError::AddressNotTracked(address()),
+            ],
+        );
+        check_candid_values(
+            &env,
+            "GetUtxosError",
+            vec![
+                GetUtxosError::MinConfirmationsTooHigh,
+                GetUtxosError::ManagementCanisterReject(RejectionCode::CanisterReject, message()),
+                GetUtxosError::InvalidResponse { reason: message() },
+                GetUtxosError::ResponseTooLarge { message: message() },
+                GetUtxosError::AddressNotTracked,
+            ],
+        );
+        check_candid_values(
+            &env,
+            "SignatureError",
+            vec![
+                SignatureError::InvalidPublicKey,
+                SignatureError::InvalidMessageHash,
+                SignatureError::InvalidSignature,
+            ],
+        );
+        let invalid_cycles_cost_configs = || {
+            vec![
+                InvalidCyclesCostConfig::ZeroCost {
+                    cost: String::from("get_utxos"),
+                },
+                InvalidCyclesCostConfig::AboveCeiling {
+                    cost: String::from("get_utxos"),
+                    cycles: 20_000_000_000,
+                    ceiling: 10_000_000_000,
+                },
+                InvalidCyclesCostConfig::InvalidCandid(message()),
+            ]
+        };
+        check_candid_values(
+            &env,
+            "InvalidCyclesCostConfig",
+            invalid_cycles_cost_configs(),
+        );
+        let mut build_errors = vec![
+            BuildError::MinConfirmationsTooHigh,
+            BuildError::InvalidEcdsaKeyName(String::from("key 1")),
+            BuildError::StandardFeePercentileTooHigh,
+            BuildError::InvalidPercentile,
+            BuildError::ConflictingOptions(message()),
+        ];
+        build_errors.extend(
+            invalid_cycles_cost_configs()
+                .into_iter()
+                .map(BuildError::InvalidCyclesCostConfig),
+        );
+        check_candid_values(&env, "BuildError", build_errors);
+        check_candid_values(
+            &env,
+            "GetCurrentFeeError",
+            vec![
+                GetCurrentFeeError::InvalidPercentile {
+                    requested: 101,
+                    available: 100,
+                },
+                GetCurrentFeeError::NoFeeData,
+                GetCurrentFeeError::ManagementCanisterReject(RejectionCode::SysFatal, message()),
+            ],
+        );
+        check_candid_values(
+            &env,
+            "SignMessageError",
+            vec![
+                SignMessageError::UnsupportedAddressType,
+                SignMessageError::InvalidDerivationPath(DerivationPathError::TooLong),
+                SignMessageError::ManagementCanisterReject(RejectionCode::Unknown, message()),
+            ],
+        );
+        check_candid_values(
+            &env,
+            "MultiTransferError",
+            vec![
+                MultiTransferError::FeeTooLow,
+                MultiTransferError::FeeTooHigh {
+                    fee: 20_000,
+                    max_fee: 10_000,
+                },
+                MultiTransferError::InvalidFeeMultiplier,
+                MultiTransferError::InvalidPercentile {
+                    requested: 101,
+                    available: 100,
+                },
+                MultiTransferError::NoFeeData,
+                MultiTransferError::InsufficientBalance,
+                MultiTransferError::MinConfirmationsTooHigh,
+                MultiTransferError::InputNotSpendable,
+                MultiTransferError::DuplicateInput,
+                MultiTransferError::DustOutput,
+                MultiTransferError::ScriptTooLong,
+                MultiTransferError::NonStandardScript,
+                MultiTransferError::SingleAddressSelectionFailed,
+                MultiTransferError::TransactionTooLarge {
+                    weight: 500_000,
+                    inputs: 1_000,
+                },
+                MultiTransferError::InvalidDerivationPath(DerivationPathError::TooLong),
+                MultiTransferError::ManagementCanisterReject {
+                    method: ManagementCanisterMethod::BitcoinSendTransaction,
+                    rejection_code: RejectionCode::DestinationInvalid,
+                    message: message(),
+                },
+                MultiTransferError::SigningFailed {
+                    failed_inputs: vec![InputSigningFailure {
+                        input: 3,
+                        method: ManagementCanisterMethod::SignWithSchnorr,
+                        rejection_code: RejectionCode::CanisterError,
+                        message: message(),
+                    }],
+                },
+            ],
+        );
+    }
+
+    /// Converts the error into `BtcLibError` and back, checking that it is kept as is.
+    fn check_round_trip<T>(error: impl Fn() -> T)
+    where
+        T: Into<BtcLibError> + TryFrom<BtcLibError, Error = BtcLibError> + PartialEq + Debug,
+        T: fmt::Display,
+    {
+        let btc_lib_error: BtcLibError = error().into();
+        assert_eq!(btc_lib_error.to_string(), error().to_string());
+        assert_eq!(T::try_from(btc_lib_error), Ok(error()));
+    }
+
+    /// Check that every error is converted into `BtcLibError` of the expected category and back without losing any detail.
+    #[test]
+    fn check_btc_lib_error_conversions() {
+        let address = || String::from("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76");
+        check_round_trip(|| BitcoinAddressError::UtilAddress(String::from("invalid")));
+        check_round_trip(|| DerivationPathError::HardenedElement { index: 2 });
+        check_round_trip(|| AddressNotTracked);
+        check_round_trip(|| RateLimited { retry_after: 42 });
+        check_round_trip(|| UnsupportedNetwork(Network::Signet));
+        check_round_trip(|| RegtestUnavailable);
+        check_round_trip(|| MinConfirmationsTooHigh);
+        check_round_trip(|| StandardFeePercentileTooHigh);
+        check_round_trip(|| InvalidPercentile);
+        check_round_trip(|| AddAddressWithParametersError::MinConfirmationsTooHigh);
+        check_round_trip(|| StateRestoreError::DuplicateAddress(address()));
+        check_round_trip(|| IntegrityIssue::AddressMismatch(address()));
+        check_round_trip(|| StateImportError::ChecksumMismatch);
+        check_round_trip(|| AgentRegistryError::DuplicateAddress {
+            address: address(),
+            agent: String::from("payouts"),
+        });
+        check_round_trip(|| ApplyMultiTransferResultError::AddressNotTracked(address()));
+        check_round_trip(|| GetUtxosError::ResponseTooLarge {
+            message: String::from("Too large."),
+        });
+        check_round_trip(|| SignatureError::InvalidSignature);
+        check_round_trip(|| {
+            ManagementCanisterReject(RejectionCode::SysTransient, String::from("Busy."))
+        });
+        check_round_trip(|| InvalidCyclesCostConfig::InvalidCandid(String::from("invalid")));
+        check_round_trip(|| BuildError::InvalidEcdsaKeyName(String::from("key 1")));
+        check_round_trip(|| GetCurrentFeeError::NoFeeData);
+        check_round_trip(|| SignMessageError::UnsupportedAddressType);
+        check_round_trip(|| NetChangeOverflow);
+        check_round_trip(|| MultiTransferError::SigningFailed {
+            failed_inputs: vec![InputSigningFailure {
+                input: 3,
+                method: ManagementCanisterMethod::SignWithEcdsa,
+                rejection_code: RejectionCode::SysTransient,
+                message: String::from("Busy."),
+            }],
+        });
+
+        assert_eq!(
+            BtcLibError::from(GetUtxosError::ManagementCanisterReject(
+                RejectionCode::SysTransient,
+                String::from("Busy.")
+            )),
+            BtcLibError::Rejected(ErrorDetail::GetUtxos(
+                GetUtxosError::ManagementCanisterReject(
+                    RejectionCode::SysTransient,
+                    String::from("Busy.")
+                )
+            ))
+        );
+        assert_eq!(
+            BtcLibError::from(GetUtxosError::AddressNotTracked),
+            BtcLibError::NotTracked(ErrorDetail::GetUtxos(GetUtxosError::AddressNotTracked))
+        );
+        assert_eq!(
+            BtcLibError::from(GetCurrentFeeError::InvalidPercentile {
+                requested: 101,
+                available: 100
+            }),
+            BtcLibError::InvalidInput(ErrorDetail::GetCurrentFee(
+                GetCurrentFeeError::InvalidPercentile {
+                    requested: 101,
+                    available: 100
+                }
+            ))
+        );
+        assert_eq!(
+            BtcLibError::from(MultiTransferError::InsufficientBalance),
+            BtcLibError::InsufficientFunds(ErrorDetail::MultiTransfer(
+                MultiTransferError::InsufficientBalance
+            ))
+        );
+        assert_eq!(
+            BtcLibError::from(IntegrityIssue::InvalidEcdsaPubKey),
+            BtcLibError::Internal(ErrorDetail::IntegrityIssue(
+                IntegrityIssue::InvalidEcdsaPubKey
+            ))
+        );
+
+        // An error of another type isn't converted back, and is returned as is.
+        assert_eq!(
+            MultiTransferError::try_from(BtcLibError::from(GetCurrentFeeError::NoFeeData)),
+            Err(BtcLibError::Internal(ErrorDetail::GetCurrentFee(
+                GetCurrentFeeError::NoFeeData
+            )))
+        );
+
+        // The errors of several operations are propagated with `?` into a single `Result`.
+        fn transfer() -> Result<u64, BtcLibError> {
+            let fee: Result<u64, GetCurrentFeeError> = Err(GetCurrentFeeError::NoFeeData);
+            let transfer: Result<u64, MultiTransferError> = Ok(fee?);
+            Ok(transfer?)
+        }
+        assert_eq!(
+            transfer().unwrap_err().to_string(),
+            GetCurrentFeeError::NoFeeData.to_string()
+        );
+        let error = BtcLibError::from(MultiTransferError::InvalidDerivationPath(
+            DerivationPathError::TooLong,
+        ));
+        assert_eq!(
+            error.source().unwrap().to_string(),
+            DerivationPathError::TooLong.to_string()
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use crate::{
+    transaction_management::get_fee_from_percentiles, FeeRequest, FeeSmoothing, MillisatoshiPerByte,
+};
+use std::collections::VecDeque;
+
+/// Caches the last retrieved fee percentiles with the tip height and the time at which they were retrieved.
+/// When fee smoothing is enabled, the last retrieved fee percentiles are also kept to smooth them.
+#[derive(Clone)]
+pub(crate) struct FeeCache {
+    fees: Option<(Vec<MillisatoshiPerByte>, u32, u64)>,
+    tip_height: u32,
+    pub(crate) max_age: u32,
+    /// The nanoseconds after which the cached fees are stale, whatever the tip height, `None` if they don't expire with time.
+    pub(crate) ttl: Option<u64>,
+    pub(crate) fee_smoothing: Option<FeeSmoothing>,
+    pub(crate) fee_history: VecDeque<Vec<MillisatoshiPerByte>>,
+}
+
+impl FeeCache {
+    /// Creates an empty fee cache whose fees are stale once the tip advanced by `max_age` blocks.
+    pub(crate) fn new(max_age: u32) -> Self {
+        Self {
+            fees: None,
+            tip_height: 0,
+            max_age,
+            ttl: None,
+            fee_smoothing: None,
+            fee_history: VecDeque::default(),
+        }
+    }
+
+    /// Creates an empty fee cache from the persisted fee smoothing and fee percentiles kept to smooth them.
+    pub(crate) fn from_state(
+        max_age: u32,
+        fee_smoothing: Option<FeeSmoothing>,
+        fee_history: Vec<Vec<MillisatoshiPerByte>>,
+    ) -> Self {
+        Self {
+            fee_smoothing,
+            fee_history: fee_history.into(),
+            ..Self::new(max_age)
+        }
+    }
+
+    /// Caches the given fee percentiles retrieved at `tip_height` and at the time `now`.
+    pub(crate) fn apply_current_fees(
+        &mut self,
+        fees: Vec<MillisatoshiPerByte>,
+        tip_height: u32,
+        now: u64,
+    ) {
+        if let Some(fee_smoothing) = self.fee_smoothing {
+            self.fee_history.push_back(fees.clone());
+            self.forget_old_fees(fee_smoothing.window);
+        }
+        self.fees = Some((fees, tip_height, now));
+        self.apply_tip_height(tip_height);
+    }
+
+    /// Records the latest known tip height, which makes the cached fees stale if the tip advanced too much.
+    pub(crate) fn apply_tip_height(&mut self, tip_height: u32) {
+        self.tip_height = self.tip_height.max(tip_height);
+    }
+
+    /// Returns the latest known tip height.
+    pub(crate) fn get_tip_height(&self) -> u32 {
+        self.tip_height
+    }
+
+    /// Returns the cached fee percentiles if they aren't stale at the time `now`.
+    pub(crate) fn get_fees(&self, now: u64) -> Option<&[MillisatoshiPerByte]> {
+        self.fees
+            .as_ref()
+            .filter(|(_, fees_tip_height, fees_time)| {
+                self.tip_height < fees_tip_height.saturating_add(self.max_age)
+                    && self
+                        .ttl
+                        .is_none_or(|ttl| now < fees_time.saturating_add(ttl))
+            })
+            .map(|(fees, _, _)| fees.as_slice())
+    }
+
+    /// Returns the cached fee associated with the given `FeeRequest` if the cached fees aren't stale at the time `now`.
+    pub(crate) fn get_fee(&self, fee_request: FeeRequest, now: u64) -> Option<MillisatoshiPerByte> {
+        self.get_fees(now)
+            .and_then(|fees| get_fee_from_percentiles(fee_request, fees, false).ok())
+    }
+
+    /// Returns the fee percentiles used for percentile fees if the cached fees aren't stale at the time `now`, smoothed if fee smoothing is enabled.
+    pub(crate) fn get_fees_to_use(&self, now: u64) -> Option<Vec<MillisatoshiPerByte>> {
+        self.get_fees(now).map(|fees| match self.fee_smoothing {
+            Some(_) => self.get_smoothed_fees().unwrap_or_else(|| fees.to_vec()),
+            None => fees.to_vec(),
+        })
+    }
+
+    /// Sets the fee smoothing, `None` disabling it.
+    /// The kept fee percentiles exceeding the new window are forgotten.
+    pub(crate) fn set_fee_smoothing(&mut self, fee_smoothing: Option<FeeSmoothing>) {
+        self.fee_smoothing = fee_smoothing;
+        self.forget_old_fees(fee_smoothing.map_or(0, |fee_smoothing| fee_smoothing.window));
+    }
+
+    /// Only keeps the last `window` fee percentiles.
+    fn forget_old_fees(&mut self, window: u8) {
+        while self.fee_history.len() > window as usize {
+            self.fee_history.pop_front();
+        }
+    }
+
+    /// Returns the 0th to 100th fee percentiles, each of them being the exponential moving average of this percentile over the kept fee percentiles.
+    /// The smoothing factor is `2 / (window + 1)` and only integer arithmetic is used, hence the result is deterministic.
+    /// Returns `None` if fee smoothing is disabled or if no fee percentiles with fee data were kept.
+    pub(crate) fn get_smoothed_fees(&self) -> Option<Vec<MillisatoshiPerByte>> {
+        let window = self.fee_smoothing?.window as u128;
+        let denominator = window + 1;
+        (0..=100)
+            .map(|percentile| {
+                self.fee_history
+                    .iter()
+                    .filter_map(|fees| {
+                        get_fee_from_percentiles(FeeRequest::Percentile(percentile), fees, false)
+                            .ok()
+                    })
+                    .map(|fee| fee as u128)
+                    .reduce(|average, fee| {
+                        // Rounded half up exponential moving average.
+                        (2 * fee + window.saturating_sub(1) * average + denominator / 2)
+                            / denominator
+                    })
+                    .map(|average| average as MillisatoshiPerByte)
+            })
+            .collect()
+    }
+
+    /// Returns the smoothed fee associated with the given `FeeRequest`.
+    pub(crate) fn get_smoothed_fee(&self, fee_request: FeeRequest) -> Option<MillisatoshiPerByte> {
+        self.get_smoothed_fees()
+            .and_then(|fees| get_fee_from_percentiles(fee_request, &fees, false).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent,
+        canister_mock::{get_balance_update, mine_block, ManagementCanisterMock, MOCK_START_TIME},
+        AddressType, BitcoinAgent, Fee, FeeRequest, FeeSmoothing, MillisatoshiPerByte, Network,
+    };
+    use bitcoin::Address;
+    use std::{collections::BTreeMap, str::FromStr};
+
+    /// Check that cached fees are used for transfers and become stale once the tip advanced by the configured number of blocks.
+    #[tokio::test]
+    async fn check_fee_cache() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), None);
+
+        let current_fees = bitcoin_agent
+            .get_current_fees_from_args_test(bitcoin_agent.get_current_fees_args().unwrap())
+            .unwrap()
+            .result;
+        let tip_height = bitcoin_agent.management_canister.chain().tip_height;
+        bitcoin_agent.apply_current_fees(current_fees, tip_height);
+        bitcoin_agent.set_fee_cache_max_age(2);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), Some(50_000));
+
+        // The transfer relies on the cached fees as the management canister doesn't provide any.
+        bitcoin_agent.management_canister.clear_fee_percentiles();
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            10_000,
+        )]);
+        let mut multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &payouts,
+            main_address,
+            Fee::Standard,
+            min_confirmations,
+            false,
+        );
+        multi_transfer_args.fallback_fee_per_byte = None;
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        bitcoin_agent
+            .apply_multi_transfer_result(&multi_transfer_result)
+            .unwrap();
+
+        // The fees are still fresh one block later.
+        mine_block(&mut bitcoin_agent.management_canister);
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), Some(50_000));
+
+        // The fees are stale two blocks later.
+        mine_block(&mut bitcoin_agent.management_canister);
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), None);
+        assert!(bitcoin_agent
+            .get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Standard,
+                min_confirmations,
+                false
+            )
+            .current_fees
+            .is_none());
+    }
+
+    /// Check that smoothed fees lag behind a fee spike and that the fee smoothing is part of the Bitcoin agent state.
+    #[test]
+    fn check_fee_smoothing() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        // With a window of 3 blocks, the smoothing factor is 1/2.
+        bitcoin_agent.set_fee_smoothing(Some(FeeSmoothing { window: 3 }));
+        assert_eq!(bitcoin_agent.smoothed_fee(FeeRequest::Standard), None);
+
+        let base_fees = bitcoin_agent
+            .management_canister
+            .internal_get_current_fees();
+        let spike_fees: Vec<MillisatoshiPerByte> = base_fees.iter().map(|fee| fee * 10).collect();
+        /// Applies the given fees returned by the mock and mines a block.
+        fn apply_mock_fees(
+            bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
+            fees: &[MillisatoshiPerByte],
+        ) {
+            bitcoin_agent
+                .management_canister
+                .set_fee_percentiles(fees.to_vec());
+            let current_fees = bitcoin_agent
+                .get_current_fees_from_args_test(bitcoin_agent.get_current_fees_args().unwrap())
+                .unwrap()
+                .result;
+            let tip_height = bitcoin_agent.management_canister.chain().tip_height;
+            bitcoin_agent.apply_current_fees(current_fees, tip_height);
+            mine_block(&mut bitcoin_agent.management_canister);
+        }
+
+        for _ in 0..3 {
+            apply_mock_fees(bitcoin_agent, &base_fees);
+        }
+        assert_eq!(
+            bitcoin_agent.smoothed_fee(FeeRequest::Standard),
+            Some(50_000)
+        );
+
+        apply_mock_fees(bitcoin_agent, &spike_fees);
+        assert_eq!(
+            bitcoin_agent.cached_fee(FeeRequest::Standard),
+            Some(500_000)
+        );
+        assert_eq!(
+            bitcoin_agent.smoothed_fee(FeeRequest::Standard),
+            Some(275_000)
+        );
+        // The transfers use the smoothed fees.
+        let current_fees = bitcoin_agent
+            .get_multi_transfer_args(&BTreeMap::default(), main_address, Fee::Standard, 0, false)
+            .current_fees
+            .unwrap();
+        assert_eq!(current_fees.len(), 101);
+        assert_eq!(current_fees[50], 275_000);
+
+        apply_mock_fees(bitcoin_agent, &base_fees);
+        assert_eq!(
+            bitcoin_agent.smoothed_fee(FeeRequest::Standard),
+            Some(162_500)
+        );
+
+        // The fee smoothing and the fee percentiles kept to smooth them are part of the Bitcoin agent state.
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Testnet).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent.get_fee_smoothing(),
+            Some(FeeSmoothing { window: 3 })
+        );
+        assert_eq!(
+            restored_bitcoin_agent.smoothed_fee(FeeRequest::Standard),
+            Some(162_500)
+        );
+
+        // The spike is forgotten once it's out of the window.
+        apply_mock_fees(bitcoin_agent, &base_fees);
+        apply_mock_fees(bitcoin_agent, &base_fees);
+        assert_eq!(
+            bitcoin_agent.smoothed_fee(FeeRequest::Standard),
+            Some(50_000)
+        );
+
+        bitcoin_agent.set_fee_smoothing(None);
+        assert_eq!(bitcoin_agent.smoothed_fee(FeeRequest::Standard), None);
+    }
+
+    /// Check that with a time to live, the cached fees are stale once the logical clock of the mock advanced across it, even if the tip didn't advance, the mined blocks advancing the clock by the block interval.
+    #[test]
+    fn check_fee_cache_ttl() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let minute = 60_000_000_000;
+        bitcoin_agent.set_fee_cache_ttl(Some(10 * minute));
+        assert_eq!(bitcoin_agent.get_fee_cache_ttl(), Some(10 * minute));
+        /// Caches the fees returned by the mock.
+        fn apply_mock_fees(bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>) {
+            let current_fees = bitcoin_agent
+                .get_current_fees_from_args_test(bitcoin_agent.get_current_fees_args().unwrap())
+                .unwrap()
+                .result;
+            let tip_height = bitcoin_agent.management_canister.chain().tip_height;
+            bitcoin_agent.apply_current_fees(current_fees, tip_height);
+        }
+
+        apply_mock_fees(bitcoin_agent);
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .advance_time(10 * minute - 1);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), Some(50_000));
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .advance_time(1);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), None);
+        assert!(bitcoin_agent
+            .get_multi_transfer_args(&BTreeMap::default(), main_address, Fee::Standard, 0, false)
+            .current_fees
+            .is_none());
+
+        apply_mock_fees(bitcoin_agent);
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .set_block_interval(5 * minute);
+        mine_block(&mut bitcoin_agent.management_canister);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), Some(50_000));
+        mine_block(&mut bitcoin_agent.management_canister);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), None);
+        assert_eq!(
+            bitcoin_agent.get_management_canister_mock().now(),
+            MOCK_START_TIME + 20 * minute
+        );
+
+        // Without a time to live, only the tip height makes the cached fees stale.
+        bitcoin_agent.set_fee_cache_ttl(None);
+        assert_eq!(bitcoin_agent.cached_fee(FeeRequest::Standard), Some(50_000));
+    }
+}
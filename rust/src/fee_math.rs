@@ -0,0 +1,155 @@
+//! Conversions between fees in satoshis and fee rates in millisatoshis/byte with explicit rounding.
+
+use crate::{MillisatoshiPerByte, Satoshi};
+
+/// The minimum fee rate relayed by default by nodes, which is 1 sat/B.
+pub const MIN_RELAY_FEE_PER_BYTE: MillisatoshiPerByte = 1_000;
+
+/// Returns the fee in satoshis paying `rate` millisatoshis/byte for a transaction of `vsize` bytes.
+/// The fee is rounded up, hence the transaction pays at least `rate`.
+pub fn fee_for_vsize(rate: MillisatoshiPerByte, vsize: u64) -> Satoshi {
+    let fee = rate as u128 * vsize as u128;
+    let mut satoshis = fee / 1_000;
+    if satoshis * 1_000 < fee {
+        satoshis += 1;
+    }
+    satoshis.min(Satoshi::MAX as u128) as Satoshi
+}
+
+/// Returns the fee rate in millisatoshis/byte paid by a transaction of `vsize` bytes with a fee of `fee` satoshis.
+/// The rate is rounded down, hence the transaction pays at least the returned rate.
+/// Returns 0 if `vsize` is 0.
+pub fn rate_for_fee(fee: Satoshi, vsize: u64) -> MillisatoshiPerByte {
+    (fee as u128 * 1_000)
+        .checked_div(vsize as u128)
+        .map_or(0, |rate| {
+            rate.min(MillisatoshiPerByte::MAX as u128) as MillisatoshiPerByte
+        })
+}
+
+/// Returns the fee rate in millisatoshis/byte associated with the given fee rate in satoshis/kilobyte, as used by Bitcoin Core.
+/// Both units are equal, hence no rounding is involved.
+pub fn rate_from_sat_per_kvb(sat_per_kvb: u64) -> MillisatoshiPerByte {
+    sat_per_kvb
+}
+
+/// Returns the fee rate in satoshis/kilobyte, as used by Bitcoin Core, associated with the given fee rate in millisatoshis/byte.
+/// Both units are equal, hence no rounding is involved.
+pub fn rate_to_sat_per_kvb(rate: MillisatoshiPerByte) -> u64 {
+    rate
+}
+
+/// Returns the fee rate in millisatoshis/byte associated with the given fee rate in satoshis/byte.
+pub fn rate_from_sat_per_vbyte(sat_per_vbyte: u64) -> MillisatoshiPerByte {
+    sat_per_vbyte.saturating_mul(1_000)
+}
+
+/// Returns the fee rate in satoshis/byte associated with the given fee rate in millisatoshis/byte.
+/// The rate is rounded up, hence paying the returned rate pays at least `rate`.
+pub fn rate_to_sat_per_vbyte(rate: MillisatoshiPerByte) -> u64 {
+    fee_for_vsize(rate, 1)
+}
+
+/// Returns the fee rate `rate` multiplied by `numerator / denominator`, rounded down.
+/// Returns `None` if `denominator` is 0.
+pub fn scale_rate(
+    rate: MillisatoshiPerByte,
+    numerator: u32,
+    denominator: u32,
+) -> Option<MillisatoshiPerByte> {
+    (rate as u128 * numerator as u128)
+        .checked_div(denominator as u128)
+        .map(|rate| rate.min(MillisatoshiPerByte::MAX as u128) as MillisatoshiPerByte)
+}
+
+/// Returns whether a transaction of `vsize` bytes with a fee of `fee` satoshis pays at least the minimum relay fee rate.
+pub fn is_relayable_fee(fee: Satoshi, vsize: u64) -> bool {
+    fee >= fee_for_vsize(MIN_RELAY_FEE_PER_BYTE, vsize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that `fee_for_vsize` rounds up at the satoshi boundaries.
+    #[test]
+    fn check_fee_for_vsize() {
+        assert_eq!(fee_for_vsize(0, 250), 0);
+        assert_eq!(fee_for_vsize(1_000, 0), 0);
+        assert_eq!(fee_for_vsize(1, 1), 1);
+        assert_eq!(fee_for_vsize(999, 1), 1);
+        assert_eq!(fee_for_vsize(1_000, 1), 1);
+        assert_eq!(fee_for_vsize(1_001, 1), 2);
+        assert_eq!(fee_for_vsize(1_500, 2), 3);
+        assert_eq!(fee_for_vsize(1_500, 3), 5);
+        assert_eq!(fee_for_vsize(1_000, 225), 225);
+        assert_eq!(fee_for_vsize(1_001, 225), 226);
+        assert_eq!(
+            fee_for_vsize(MillisatoshiPerByte::MAX, u64::MAX),
+            Satoshi::MAX
+        );
+    }
+
+    /// Check that `rate_for_fee` rounds down at the millisatoshi boundaries.
+    #[test]
+    fn check_rate_for_fee() {
+        assert_eq!(rate_for_fee(0, 250), 0);
+        assert_eq!(rate_for_fee(250, 0), 0);
+        assert_eq!(rate_for_fee(225, 225), 1_000);
+        assert_eq!(rate_for_fee(224, 225), 995);
+        assert_eq!(rate_for_fee(1, 3), 333);
+        assert_eq!(rate_for_fee(2, 3), 666);
+        assert_eq!(rate_for_fee(Satoshi::MAX, 1), MillisatoshiPerByte::MAX);
+    }
+
+    /// Check that `scale_rate` rounds down and rejects a zero denominator.
+    #[test]
+    fn check_scale_rate() {
+        assert_eq!(scale_rate(50_000, 5, 4), Some(62_500));
+        assert_eq!(scale_rate(1_001, 5, 4), Some(1_251));
+        assert_eq!(scale_rate(1_003, 1, 2), Some(501));
+        assert_eq!(scale_rate(1_000, 0, 1), Some(0));
+        assert_eq!(scale_rate(1_000, 1, 0), None);
+        assert_eq!(
+            scale_rate(MillisatoshiPerByte::MAX, u32::MAX, 1),
+            Some(MillisatoshiPerByte::MAX)
+        );
+    }
+
+    /// Check the fee rate unit conversions.
+    #[test]
+    fn check_rate_conversions() {
+        assert_eq!(rate_from_sat_per_kvb(1_000), 1_000);
+        assert_eq!(rate_to_sat_per_kvb(2_500), 2_500);
+        assert_eq!(rate_from_sat_per_vbyte(3), 3_000);
+        assert_eq!(rate_from_sat_per_vbyte(u64::MAX), MillisatoshiPerByte::MAX);
+        assert_eq!(rate_to_sat_per_vbyte(3_000), 3);
+        assert_eq!(rate_to_sat_per_vbyte(3_001), 4);
+        assert_eq!(rate_to_sat_per_vbyte(1), 1);
+    }
+
+    /// Check that the fee given by `fee_for_vsize` always pays at least the requested rate, by at most one satoshi too much, and hence respects the minimum relay fee rate.
+    #[test]
+    fn check_fee_for_vsize_invariants() {
+        for vsize in 1..=1_000 {
+            for rate in (0..=5_000).step_by(7) {
+                let fee = fee_for_vsize(rate, vsize);
+                assert!(fee as u128 * 1_000 >= rate as u128 * vsize as u128);
+                assert!(fee == 0 || (fee as u128 - 1) * 1_000 < rate as u128 * vsize as u128);
+                assert!(rate_for_fee(fee, vsize) >= rate);
+                assert_eq!(
+                    is_relayable_fee(fee, vsize),
+                    rate >= MIN_RELAY_FEE_PER_BYTE || fee >= vsize
+                );
+            }
+            assert!(is_relayable_fee(
+                fee_for_vsize(MIN_RELAY_FEE_PER_BYTE, vsize),
+                vsize
+            ));
+            assert!(!is_relayable_fee(
+                fee_for_vsize(MIN_RELAY_FEE_PER_BYTE, vsize) - 1,
+                vsize
+            ));
+        }
+    }
+}
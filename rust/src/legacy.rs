@@ -0,0 +1,29 @@
+//! Layouts of the Bitcoin agent state saved by early revisions of the library, which predate `BitcoinAgentStateV0`.
+//! They're decoded by `BitcoinAgentState::from_legacy_v0` and as a last resort by `BitcoinAgentState::decode`.
+
+use crate::{AddressType, AddressUsingPrimitives, EcdsaPubKey, Network, OutPoint, Utxo};
+use candid::{CandidType, Deserialize};
+use std::collections::BTreeMap;
+
+/// The layout of the UTXOs state saved by early revisions.
+/// The spent and generated UTXOs states were added later on, hence they're missing from the oldest states.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct LegacyUtxosStateV0 {
+    pub seen_state: Vec<Utxo>,
+    pub unseen_state: Vec<Utxo>,
+    pub min_confirmations: u32,
+    pub spent_state: Option<Vec<OutPoint>>,
+    pub generated_state: Option<Vec<Utxo>>,
+}
+
+/// The layout of the Bitcoin agent state saved by early revisions, before the payout queue, the fee and the signing settings were saved.
+/// These settings are restored with their default values.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct LegacyBitcoinAgentStateV0 {
+    pub network: Network,
+    pub main_address_type: AddressType,
+    pub ecdsa_pub_key_addresses: BTreeMap<AddressUsingPrimitives, EcdsaPubKey>,
+    pub utxos_state_addresses: BTreeMap<AddressUsingPrimitives, LegacyUtxosStateV0>,
+    pub min_confirmations: u32,
+    pub ecdsa_pub_key: EcdsaPubKey,
+}
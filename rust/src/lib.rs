@@ -15,13 +15,12 @@
 //! As mentioned above, the [BitcoinAgent] is stateful. Therefore, it is important to store and load the agent’s state properly in the canister’s life cycle management. This aspect is discussed in detail in [Section 3](#3-life-cycle-management).
 //!
 //! It's an established and secure practice to encapsulate global state within a [Cell]/[RefCell] and this practice should be followed with respect to the [BitcoinAgent].
-//! In order to ensure the integrity of a [RefCell]<[BitcoinAgent]>, for instance, getting the balance of an address has to use `get_balance!` or the equivalent lines of code detailed in [Section 4](#4-best-practices-for-the-management-of-global-state).
+//! In order to ensure the integrity of a [RefCell]<[BitcoinAgent]>, for instance, getting the balance of an address has to use [refresh_and_get_balance_update!] or the equivalent lines of code detailed in [Section 4](#4-best-practices-for-the-management-of-global-state).
 //!
 //! While working on the Internet Computer does not require more configuration, working locally does. The additional instructions are provided in [Section 5](#5-testing-locally).
 
 //! # 0. Disclaimer
 
-
 //! While the library is reasonably well tested, it is still under development, therefore use it *at your own risk*.
 //!
 //! This library is *work in progress* and is *not production-grade* yet. At least the following aspects of the library need further consideration before using it in production use cases:
@@ -46,11 +45,12 @@
 //! cd example_rust/
 //! ```
 
-//! Add the most recent version of the `ic-btc-library` and its dependency to your `src/example_rust_backend/Cargo.toml` dependencies.
+//! Add the most recent version of the `ic-btc-library` to your `src/example_rust_backend/Cargo.toml` dependencies.
+//! The `bitcoin` crate used by its API is re-exported as `ic_btc_library::bitcoin`, so it does not need to be added as well.
+//! The library is built with `ic-cdk` 0.13 and `candid` 0.10, which the canister has to use too for types like `Principal` to match.
 
 //! ```toml
 //! ic-btc-library = { git = "https://github.com/Benjamin-Loison/Internet-Computer-Bitcoin-Library/" }
-//! bitcoin = "0.28.1"
 //! ```
 
 //! Replace the content of `src/example_rust_backend/src/lib.rs` with the sample code from [Section 2](#2-sample-code).
@@ -64,6 +64,9 @@
 //!     "main": () -> (text, nat64, text, variant { Ok : text; Err : null });
 //! }
 //! ```
+//!
+//! The errors of the library can be returned by the endpoints too, their Candid types being given in `fixtures/errors.did`.
+//! They all convert into `BtcLibError`, e.g. to return a single error type from a flow calling several operations.
 
 //! Install `ic-cdk-optimizer` to optimize the output WASM module.
 
@@ -110,15 +113,17 @@
 //! # 2. Sample Code
 
 //! The following code shows how to create a [BitcoinAgent] instance, add a managed address derived from the canister’s public key and get its current balance.
-//! ```ignore
+//! ```
 //! use ic_cdk::print;
-//! # use ic_btc_library::{AddressType, Network, BitcoinAgent, ManagementCanister, ManagementCanisterMock, Satoshi, Fee};
+//! # #[cfg(feature = "mock")]
+//! # use ic_btc_library::{AddressType, Network, BitcoinAgent, ManagementCanisterFactory, canister_mock::ManagementCanisterMock, Satoshi, Fee};
 //! # /*
-//! use ic_cdk_macros::update;
-//! use ic_btc_library::{AddressType, Network, BitcoinAgent, ManagementCanister, ManagementCanisterImpl, Satoshi, Fee, get_balance_from_args, get_initialization_parameters_from_args, multi_transfer_from_args, get_utxos_from_args};
+//! use ic_cdk::update;
+//! use ic_btc_library::{AddressType, Network, BitcoinAgent, ManagementCanisterFactory, ManagementCanisterImpl, Satoshi, Fee, get_balance_from_args, get_initialization_parameters_from_args, multi_transfer_from_args, get_utxos_from_args};
 //! # */
 //! use std::collections::BTreeMap;
 //!
+//! # #[cfg(feature = "mock")]
 //! # #[tokio::main]
 //! # async fn main() {
 //! # /*
@@ -131,33 +136,35 @@
 //!     # let num_confirmations = 1;
 //!
 //!     let mut agent = BitcoinAgent::new(
-//!         // Choose the Bitcoin network your `BitcoinAgent` will use: mainnet, testnet, or regtest.
+//!         // Choose the Bitcoin network your `BitcoinAgent` will use: mainnet, testnet, or regtest, e.g. parsed from the init arguments of the canister.
+//!         // The threshold ECDSA key name is derived from the network unless one is given, such as `Some(String::from("key_1"))`.
 //!         # /*
-//!         ManagementCanisterImpl::new(Network::Regtest),
+//!         ManagementCanisterImpl::new("regtest".parse::<Network>().unwrap(), None),
 //!         # */
-//!         # ManagementCanisterMock::new(Network::Regtest),
-//!         &AddressType::P2pkh,
+//!         # ManagementCanisterMock::new("regtest".parse::<Network>().unwrap(), None),
+//!         &"p2pkh".parse::<AddressType>().unwrap(),
 //!         num_confirmations,
 //!     ).unwrap();
+//!     // Alternatively, `BitcoinAgentBuilder` configures the other settings, such as the fee caps, and validates them at once with `build`.
 //!
 //!     // Initializes the Bitcoin agent.
 //!     let get_initialization_parameters_args = agent.get_initialization_parameters_args();
 //!     # /*
-//!     let initialization_parameters = get_initialization_parameters_from_args(get_initialization_parameters_args).await.unwrap();
+//!     let initialization_parameters = get_initialization_parameters_from_args(get_initialization_parameters_args).await.unwrap().result;
 //!     # */
-//!     # let initialization_parameters = agent.get_initialization_parameters_from_args_test(get_initialization_parameters_args).unwrap();
+//!     # let initialization_parameters = agent.get_initialization_parameters_from_args_test(get_initialization_parameters_args).unwrap().result;
 //!     agent.initialize(initialization_parameters);
 //!
 //!     // Print the address of the main account and its balance:
 //!     let main_address = agent.get_main_address();
 //!     # /*
 //!     print(&format!("Main account address: {}", main_address));
-//!     let get_utxos_args = agent.get_utxos_args(&main_address, num_confirmations);
+//!     let get_utxos_args = agent.get_utxos_args(&main_address, num_confirmations).unwrap();
 //!     let balance = get_balance_from_args(get_utxos_args).await.unwrap();
 //!     print(&format!("Main account balance: {}", balance));
 //!     # */
 //!     # println!("Main account address: {}", main_address);
-//!     # let get_utxos_args = agent.get_utxos_args(&main_address, num_confirmations);
+//!     # let get_utxos_args = agent.get_utxos_args(&main_address, num_confirmations).unwrap();
 //!     # let balance = agent.get_balance_from_args_test(get_utxos_args).unwrap();
 //!     # println!("Main account balance: {}", balance);
 //!
@@ -173,11 +180,11 @@
 //!     let amount: Satoshi = 1_000_000;
 //!     let payouts = BTreeMap::from([(new_address.clone(), amount)]);
 //!
-//!     let get_utxos_args = agent.get_utxos_args(&main_address, num_confirmations);
+//!     let get_utxos_args = agent.get_utxos_args(&main_address, num_confirmations).unwrap();
 //!     # /*
-//!     let get_utxos_result = get_utxos_from_args(get_utxos_args).await.unwrap();
+//!     let get_utxos_result = get_utxos_from_args(get_utxos_args).await.unwrap().result;
 //!     # */
-//!     # let get_utxos_result = agent.get_utxos_from_args_test(get_utxos_args).unwrap();
+//!     # let get_utxos_result = agent.get_utxos_from_args_test(get_utxos_args).unwrap().result;
 //!     agent.apply_utxos(get_utxos_result);
 //!     agent.get_balance_update(&main_address).unwrap();
 //!
@@ -186,8 +193,9 @@
 //!     let multi_transfer_result = multi_transfer_from_args(multi_transfer_args).await;
 //!     # let multi_transfer_result = agent.multi_transfer_from_args_test(multi_transfer_args).await;
 //!     let multi_transfer_result = if let Ok(multi_transfer_result) = multi_transfer_result {
-//!         agent.apply_multi_transfer_result(&multi_transfer_result);
-//!         Ok(multi_transfer_result.transaction_info.id)
+//!         agent.apply_multi_transfer_result(&multi_transfer_result).unwrap();
+//!         // The txid is displayed as by block explorers.
+//!         Ok(multi_transfer_result.transaction_info.id.to_string())
 //!     } else {
 //!         Err(())
 //!     };
@@ -199,13 +207,17 @@
 //!     (main_address.to_string(), balance, new_address.to_string(), multi_transfer_result)
 //!     # */
 //! }
+//! # #[cfg(not(feature = "mock"))]
+//! # fn main() {}
 //! ```
 
 //! Given a [BitcoinAgent] instance, it is possible to get updates for a particular address using the function [`get_balance_update`](BitcoinAgent::get_balance_update):
 
-//! ```ignore
-//! # use ic_btc_library::{AddressType, BitcoinAgent, ManagementCanister, ManagementCanisterMock, tests::new_mock, Network};
+//! ```
+//! # #[cfg(feature = "mock")]
+//! # use ic_btc_library::{AddressType, canister_mock::new_mock, Network};
 //! #
+//! # #[cfg(feature = "mock")]
 //! # fn main() {
 //! # let mut agent = new_mock(&Network::Regtest, &AddressType::P2pkh);
 //! # let address = agent.get_main_address();
@@ -215,15 +227,21 @@
 //!     // ...
 //! }
 //! # }
+//! # #[cfg(not(feature = "mock"))]
+//! # fn main() {}
 //! ```
 
+//! The balance update also gives its `net_change`, the added balance minus the removed one, and the numbers of added and removed UTXOs.
+//!
 //! Note that the [`get_balance_update`](BitcoinAgent::get_balance_update) call changes the state of the agent. If the function is called again before any other balance change is recorded, the return value will indicate no balance changes, i.e., `balance_update.added_balance == 0`.
 //! In a more complex example, asynchronous actions may be triggered based on the update. If these actions fail, the library state should not change in order to avoid inconsistencies.
 //! This case can be handled using [`peek_balance_update`](BitcoinAgent::peek_balance_update) and [`update_state`](BitcoinAgent::update_state) as follows.
 
-//! ```ignore
-//! # use ic_btc_library::{AddressType, BitcoinAgent, ManagementCanister, ManagementCanisterMock, tests::new_mock, Network};
+//! ```
+//! # #[cfg(feature = "mock")]
+//! # use ic_btc_library::{AddressType, canister_mock::new_mock, Network};
 //! #
+//! # #[cfg(feature = "mock")]
 //! # fn main() {
 //! # let mut agent = new_mock(&Network::Regtest, &AddressType::P2pkh);
 //! # let address = agent.get_main_address();
@@ -239,6 +257,8 @@
 //! }
 //! // Access to the address can be made available again here.
 //! # }
+//! # #[cfg(not(feature = "mock"))]
+//! # fn main() {}
 //! ```
 
 //! Calling [`peek_balance_update`](BitcoinAgent::peek_balance_update) followed by [`update_state`](BitcoinAgent::update_state) is equivalent to calling [`get_balance_update`](BitcoinAgent::get_balance_update).
@@ -256,19 +276,20 @@
 //!
 //! As far as initialization is concerned, the canister developer must ensure that [`initialize`](BitcoinAgent::initialize) is called before any [BitcoinAgent] is used. The canister developer has multiple options such as:
 //! - Initializing the [BitcoinAgent]s by adding a custom endpoint that needs to be called once. This endpoint can then be removed in a canister upgrade.
-//! - Calling [BitcoinAgent::initialize] in every function before using the agent. Note that it is okay to call the function multiple times as the initialization will only happen on the first invocation.
+//! - Calling [BitcoinAgent::initialize] in every function before using the agent. Note that it is okay to call the function multiple times as the initialization will only happen on the first invocation, [BitcoinAgent::initialize] returning `false` and keeping the managed addresses once [BitcoinAgent::is_initialized], including after [BitcoinAgent::from_state].
+//! - Calling the macro [initialize!] at the beginning of every `update` method, which retrieves the ECDSA public key and initializes a `thread_local` [RefCell]<[BitcoinAgent]> without holding its borrow across the `await`, nothing being done once it is initialized.
 //!
 //! As far as storing and restoring state is concerned, the following sample code shows how to manage a single [BitcoinAgent] instance.
 
 //! ```
 //! use ic_cdk::storage;
 //! use std::cell::RefCell;
-//! use ic_btc_library::{BitcoinAgentState, AddressType, Network, BitcoinAgent, ManagementCanister, ManagementCanisterImpl};
-//! use ic_cdk_macros::{post_upgrade, pre_upgrade};
+//! use ic_btc_library::{BitcoinAgentState, AddressType, Network, BitcoinAgent, ManagementCanisterFactory, ManagementCanisterImpl};
+//! use ic_cdk::{post_upgrade, pre_upgrade};
 //!
 //! thread_local! {
 //!     static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterImpl>> =
-//!         RefCell::new(BitcoinAgent::new(ManagementCanisterImpl::new(Network::Regtest), &AddressType::P2pkh, 0).unwrap());
+//!         RefCell::new(BitcoinAgent::new(ManagementCanisterImpl::new(Network::Regtest, None), &AddressType::P2pkh, 0).unwrap());
 //! }
 //!
 //! #[pre_upgrade]
@@ -281,7 +302,7 @@
 //! fn post_upgrade() {
 //!     let (old_bitcoin_agent_state,): (BitcoinAgentState,) = storage::stable_restore().unwrap();
 //!     BITCOIN_AGENT.with(|bitcoin_agent| {
-//!         *bitcoin_agent.borrow_mut() = BitcoinAgent::from_state(old_bitcoin_agent_state)
+//!         *bitcoin_agent.borrow_mut() = BitcoinAgent::from_state(old_bitcoin_agent_state, Network::Regtest).unwrap()
 //!     });
 //! }
 //! ```
@@ -290,30 +311,52 @@
 
 //! Furthermore the canister developer must enforce that no address is managed by multiple [BitcoinAgent]s.
 
+//! For large states, [BitcoinAgent::write_state] writes the same bytes as the encoded `get_state` without cloning the UTXOs states, e.g. to a `StableWriter` in `pre_upgrade`, and [BitcoinAgent::read_state] restores the Bitcoin agent from them in `post_upgrade`. To write a state to the raw stable memory APIs or to an `ic-stable-structures` memory, [upgrade_management::save_state_chunked] streams its encoding by pages, read back with [upgrade_management::load_state_chunked].
+
+//! States saved by early revisions of the library, which only hold the addresses, their UTXOs states and the ECDSA public key, are decoded with [BitcoinAgentState::from_legacy_v0], the other settings getting their default values. [BitcoinAgentState::decode] falls back to this layout when no more recent one matches.
+
+//! With the `stable-memory` feature, a Bitcoin agent created with [BitcoinAgent::new_stable] writes its state through to the stable memory on every mutation, so that `pre_upgrade` doesn't have to save it and `post_upgrade` re-opens it with [BitcoinAgent::from_stable]. A Bitcoin agent saved with `get_state` is migrated once with [BitcoinAgent::import_state].
+
+//! The states are restored on the network the canister is configured for, passed to [BitcoinAgent::from_state] and the other restoring functions, to guard against restoring a state saved on another network by mistake, e.g. a testnet state in a canister configured for the mainnet, which fails with [StateRestoreError::NetworkMismatch] instead.
+
+//! A state saved on another network, e.g. on the testnet, is restored as a regtest Bitcoin agent to test it locally with [BitcoinAgent::from_state_with_options] and [StateRestoreOptions::allow_network_change], which relabels its addresses with the regtest network. Similarly, [ManagementCanisterImpl::new_with_network_override] lets a canister configured for another network interact with a local regtest node.
+
+//! With the `serde` feature, the state and the transfer results implement `serde::Serialize`, and the state can be backed up off-chain in CBOR with [BitcoinAgentState::to_cbor] and restored with [BitcoinAgentState::from_cbor].
+
 //! # 4. Best practices for the management of global state
 
 //! In order to ensure the integrity of a [RefCell]<[BitcoinAgent]>, for instance, getting the balance of an address has to be done as follows:
 
-//! ```ignore
+//! ```
 //! # use std::cell::RefCell;
-//! # use ic_btc_library::{AddressType, BitcoinAgent, ManagementCanister, ManagementCanisterMock, get_utxos_from_args, tests::new_mock, Network};
+//! # #[cfg(feature = "mock")]
+//! # use ic_btc_library::{AddressType, BitcoinAgent, canister_mock::{new_mock, ManagementCanisterMock}, Network};
 //! #
+//! # #[cfg(feature = "mock")]
 //! # thread_local! {
 //! #     static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterMock>> =
 //! #        RefCell::new(new_mock(&Network::Regtest, &AddressType::P2pkh));
 //! # }
 //! #
+//! # #[cfg(feature = "mock")]
 //! # fn main() {
 //! # let address = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
-//! let get_utxos_args = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_utxos_args(&address, 0));
+//! let get_utxos_args = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_utxos_args(&address, 0).unwrap());
 //! # let balance = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_balance_from_args_test(get_utxos_args).unwrap());
 //! # /*
 //! let balance = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_balance_from_args(get_utxos_args).await.unwrap());
 //! # */
 //! # }
+//! # #[cfg(not(feature = "mock"))]
+//! # fn main() {}
 //! ```
 
-//! Note that the macro `get_balance!` can be used instead, which is equivalent to the lines of code above.
+//! Note that the macro [refresh_and_get_balance_update!] can be used instead, which refreshes the UTXOs of the address and returns its balance update without holding the borrow across the `await`.
+//! Similarly, the macro [get_utxos!] applies the refreshed UTXOs to the [BitcoinAgent] and the macro [multi_transfer!] applies the result of the transfer to it, so that the spent UTXOs aren't spent again.
+//! Canisters guaranteeing an exclusive access to their Bitcoin agent for the whole duration of a call, e.g. with a message queue, may use an [AsyncBitcoinAgent] instead, whose async methods hold the borrow across the `await`s. It must never be shared behind a [RefCell].
+//! The macros [get_current_fee!] and [get_current_fees!] also retrieve the current fees without holding the borrow across the `await`.
+//! The tip height can be retrieved much more cheaply than with `get_utxos_from_args`, e.g. from a heartbeat, by getting a single block header with [BitcoinAgent::get_block_headers_args] and [get_block_headers_from_args], the result being applied with [BitcoinAgent::apply_block_headers].
+//! [UtxosArgs], [CurrentFeesArgs], [MultiTransferArgs], [InitializationParametersArgs] and their results are Candid-encodable, so that a canister can build the arguments with its Bitcoin agent and forward them to another canister calling the `*_from_args` functions, the results being applied back with the Bitcoin agent. The log sink and the time source of [MultiTransferArgs] aren't encoded, and the retry policies only retry the transient rejections once decoded.
 
 //! # 5. Testing locally
 
@@ -415,47 +458,118 @@
 //!
 //! If successful, querying the balance of the canister should return the updated balance.
 
+//! # Testing against bitcoind without a replica
+
+//! With the `bitcoind-rpc` feature, a `ManagementCanisterRpc` serves the calls of the library with the RPC interface of the bitcoind set up above, signing with a `LocalKeySigner`, so that a wallet can be tested from `cargo test` without deploying a canister.
+//! The ignored integration test runs against it with:
+
+//! ```bash
+//! BITCOIND_RPC_URL=http://127.0.0.1:18443 cargo test --features bitcoind-rpc -- --ignored
+//! ```
+
+//! # Testing with the management canister mock
+
+//! With the `mock` feature, the `canister_mock::ManagementCanisterMock` used by the tests of the library is available to the tests of downstream canisters, a Bitcoin agent using it being created with `canister_mock::new_mock` and reached with `BitcoinAgent::get_management_canister_mock_mut`. The `*_from_args_test` methods of such a Bitcoin agent simulate the calls of the `*_from_args` functions, e.g. to fund an address with `fund_address`, refresh its UTXOs and spend them. The documentation examples use them and run with `cargo test --features mock`. The `mock` feature doesn't change the behaviour of the library and is meant to be enabled for the `[dev-dependencies]` only. Several mocks simulate the same chain when created with `ManagementCanisterMock::new_sharing`, or with `canister_mock::new_mock_sharing` for a Bitcoin agent, given the chain of another mock, e.g. to test Bitcoin agents paying each other.
+
+//! Besides mining the sent transactions with `mine_block` or `mine_blocks`, and moving the tip with `set_tip_height`, the mock simulates chain reorganizations: `invalidate_blocks` orphans the last blocks, their transactions becoming pending again, and `fork_and_replace` replaces them with blocks including alternative transactions. Its fee percentiles are scripted over the mined blocks with `schedule_fee_change`, `inject_failure` makes its next calls fail to test the handling of rejections, and `set_page_size` makes it return the UTXOs by pages to test their retrieval. The library has no randomized component and the mock signs deterministically, the txids of the UTXOs funded with `fund_address` being generated from a counter by `generate_txid`, so that a scenario broadcasts the same transactions from a run to another.
+
 pub mod address_management;
 mod agent;
+mod agent_builder;
+mod agent_registry;
+mod args_encoding;
+mod async_agent;
 mod bip32_extended_derivation;
+mod block_management;
 mod canister_common;
 mod canister_implementation;
-#[cfg(test)]
+#[cfg(any(test, feature = "mock"))]
 pub mod canister_mock;
-mod ecdsa;
+#[cfg(feature = "bitcoind-rpc")]
+mod canister_rpc;
+pub mod ecdsa;
+mod errors;
+mod fee_cache;
+pub mod fee_math;
+pub mod legacy;
+mod logging;
+mod macros;
+mod message_signing;
+mod metrics;
+mod payout_queue;
+mod polling;
+mod rate_limit;
+mod schnorr;
+#[cfg(feature = "stable-memory")]
+mod stable_management;
+mod time_source;
 mod transaction_management;
 mod types;
-mod upgrade_management;
+pub mod upgrade_management;
 mod utxo_management;
 
-pub use ic_btc_types::{MillisatoshiPerByte, OutPoint, Satoshi, Utxo};
+/// The version of the `bitcoin` crate used by the API of the library, to use its types without depending on it directly.
+///
+/// ```
+/// use ic_btc_library::bitcoin::Address;
+/// use std::str::FromStr;
+///
+/// let address = Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+///     .unwrap()
+///     .require_network(ic_btc_library::bitcoin::Network::Testnet)
+///     .unwrap();
+/// assert_eq!(address.to_string(), "mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76");
+/// ```
+pub use bitcoin;
 pub use types::{
-    AddAddressWithParametersError, AddressNotTracked, AddressType, AddressUsingPrimitives,
-    BalanceUpdate, BitcoinAgentState, CurrentFeeArgs, CurrentFeesArgs, DerivationPathTooLong,
-    ECDSAPublicKeyReply, EcdsaPubKey, Fee, FeeRequest, GetCurrentFeeError, GetUtxosError,
-    InitializationParametersArgs, InvalidPercentile, ManagementCanisterReject,
-    MinConfirmationsTooHigh, MultiTransferArgs, MultiTransferError, MultiTransferResult, Network,
-    TransactionID, TransactionInfo, UtxosArgs, UtxosResult, UtxosState, UtxosUpdate,
-    MIN_CONFIRMATIONS_UPPER_BOUND,
+    is_transient_rejection, AddAddressWithParametersError, AddressNotTracked, AddressType,
+    AddressUsingPrimitives, AgentRegistryError, ApplyMultiTransferResultError, BalanceUpdate,
+    BitcoinAgentState, BitcoinAgentStateV0, BitcoinAgentStateV1, BitcoinApiTarget,
+    BlockHeadersArgs, BtcLibError, BuildError, CallPreview, CurrentFeeArgs, CurrentFeesArgs,
+    CyclesCostConfig, CyclesReconciliation, DerivationPath, DerivationPathError,
+    ECDSAPublicKeyReply, EcdsaPubKey, ErrorDetail, Fee, FeeRequest, FeeSmoothing, FeeSuggestions,
+    FeeSuggestionsArgs, GetBlockHeadersResponse, GetCurrentFeeError, GetUtxosError,
+    InitializationParametersArgs, InputSigningFailure, IntegrityIssue, InvalidCyclesCostConfig,
+    InvalidPercentile, KeyRotation, ManagementCanisterMethod, ManagementCanisterReject,
+    MemoryStats, MethodMetrics, MetricsSnapshot, MillisatoshiPerByte, MinConfirmationsTooHigh,
+    MultiTransferArgs, MultiTransferError, MultiTransferResult, NetChangeOverflow, Network,
+    NetworkOverride, OutPoint, ParseAddressTypeError, ParseNetworkError, PayoutQueueState,
+    PollBudget, QueueId, RateLimitState, RateLimited, RateLimitedOp, RegtestUnavailable,
+    RetryConfig, RetryPolicy, Satoshi, ScriptPayout, SelectionScope, SignMessageArgs,
+    SignMessageError, SignatureError, SignedMessage, SignedMessageFormat,
+    StandardFeePercentileTooHigh, StateDiff, StateImportError, StateRestoreError,
+    StateRestoreOptions, TransactionID, TransactionInfo, UnsupportedNetwork, Utxo, UtxosArgs,
+    UtxosResult, UtxosState, UtxosUpdate, WithCost, BITCOIN_AGENT_STATE_VERSION,
+    DEFAULT_FALLBACK_FEE_PER_BYTE, DEFAULT_FEE_CACHE_MAX_AGE, DEFAULT_MAX_INPUTS,
+    DEFAULT_MAX_TX_WEIGHT, DEFAULT_RETRY_CONFIG, DEFAULT_RETRY_POLICY, DEFAULT_SIGN_CONCURRENCY,
+    DEFAULT_STANDARD_FEE_PERCENTILE, DEFAULT_TARGET_BLOCKS_PERCENTILES,
+    MAX_DERIVATION_PATH_ELEMENT_LENGTH, MAX_DERIVATION_PATH_LENGTH, MIN_CONFIRMATIONS_UPPER_BOUND,
 };
 
 pub use agent::{
-    get_balance_from_args, get_current_fee_from_args, get_current_fees_from_args,
+    get_balance_from_args, get_block_headers_from_args, get_current_fee_from_args,
+    get_current_fees_from_args, get_fee_suggestions_from_args,
     get_initialization_parameters_from_args, get_utxos_from_args, multi_transfer_from_args,
-    BitcoinAgent,
+    sign_message_from_args, BitcoinAgent, BoxedBitcoinAgent,
+};
+pub use agent_builder::BitcoinAgentBuilder;
+pub use agent_registry::AgentRegistry;
+pub use async_agent::AsyncBitcoinAgent;
+pub use canister_common::{
+    set_canister_caller, set_cycles_reconciliation_hook, CanisterCaller, ManagementCanister,
+    ManagementCanisterFactory, DEFAULT_CYCLES_COST_CEILING, GET_BALANCE_COST_CYCLES,
+    GET_BLOCK_HEADERS_COST_CYCLES,
 };
-pub use canister_common::ManagementCanister;
 pub use canister_implementation::ManagementCanisterImpl;
-
-/*
-    To run documentation tests:
-    1. uncomment the `use` line below.
-    2. comment `#[cfg(test)]` above, above the second `use` of agent.rs, in agent.rs above `impl BitcoinAgent<ManagementCanisterMock>`, in agent.rs above `pub mod tests` and in address_management.rs above `pub mod tests`.
-    3. remove the four `ignore` documentation test attribute above.
-    4. add `hex = "0.4.3"` to Cargo.toml `[dependencies]`
-*/
-/*pub use {
-    agent::tests,
-    canister_mock::ManagementCanisterMock,
-    std::cell::{Cell, RefCell},
-};*/
+#[cfg(feature = "bitcoind-rpc")]
+pub use canister_rpc::{LocalKeySigner, LocalSigner, ManagementCanisterRpc};
+pub use logging::{
+    LogLevel, LogSink, NoopLogSink, PrintLogSink, LOG_TARGET_BROADCAST, LOG_TARGET_FEE,
+    LOG_TARGET_SIGN, LOG_TARGET_STATE, LOG_TARGET_UTXO_SELECTION,
+};
+pub use message_signing::verify_signed_message;
+#[cfg(feature = "stable-memory")]
+pub use stable_management::STABLE_MEMORY_IDS;
+pub use time_source::{IcTimeSource, TimeSource};
+pub use transaction_management::{median_fee, OutputDestination, TransactionBuilder};
+pub use utxo_management::{outpoint_from_explorer_hex, outpoint_to_explorer_string, utxo_txid_hex};
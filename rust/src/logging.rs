@@ -0,0 +1,135 @@
+use crate::DerivationPath;
+use std::fmt;
+
+// The targets of the log messages.
+pub const LOG_TARGET_UTXO_SELECTION: &str = "utxo_selection";
+pub const LOG_TARGET_FEE: &str = "fee";
+pub const LOG_TARGET_SIGN: &str = "sign";
+pub const LOG_TARGET_BROADCAST: &str = "broadcast";
+pub const LOG_TARGET_STATE: &str = "state";
+
+/// The severity of a log message.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Receives the log messages of the library, `target` being one of the `LOG_TARGET_*` constants.
+/// The log sink of a Bitcoin agent is set with `BitcoinAgent::set_log_sink` and is never saved in the state.
+pub trait LogSink: Send + Sync {
+    fn log(&self, level: LogLevel, target: &str, msg: &str);
+}
+
+impl fmt::Debug for dyn LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogSink")
+    }
+}
+
+/// Discards the log messages, the default log sink.
+pub struct NoopLogSink;
+
+impl LogSink for NoopLogSink {
+    fn log(&self, _level: LogLevel, _target: &str, _msg: &str) {}
+}
+
+/// Prints the log messages with `ic_cdk::print`, hence to the replica logs.
+pub struct PrintLogSink;
+
+impl LogSink for PrintLogSink {
+    fn log(&self, level: LogLevel, target: &str, msg: &str) {
+        ic_cdk::print(format!("[{:?}] {}: {}", level, target, msg));
+    }
+}
+
+/// Returns the derivation path as its hexadecimal elements separated by slashes, e.g. to log it.
+pub(crate) fn format_derivation_path(derivation_path: &DerivationPath) -> String {
+    derivation_path
+        .iter()
+        .map(|element| {
+            element
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        agent,
+        canister_mock::{self, get_balance_update},
+        AddressType, Fee, Network,
+    };
+    use bitcoin::Address;
+    use std::{
+        collections::BTreeMap,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+
+    /// Records the targets of the log messages.
+    #[derive(Default)]
+    pub(crate) struct RecordingLogSink {
+        pub(crate) targets: Mutex<Vec<String>>,
+    }
+
+    impl LogSink for RecordingLogSink {
+        fn log(&self, _level: LogLevel, target: &str, _msg: &str) {
+            self.targets.lock().unwrap().push(target.to_string());
+        }
+    }
+
+    /// Check that the derivation paths are logged as their hexadecimal elements.
+    #[test]
+    fn check_format_derivation_path() {
+        assert_eq!(format_derivation_path(&DerivationPath::default()), "");
+        assert_eq!(
+            format_derivation_path(&DerivationPath::new(vec![vec![0, 1], vec![255]]).unwrap()),
+            "0001/ff"
+        );
+    }
+
+    /// Check that a transfer logs the UTXO selection, the fee, the signature of its single input, its broadcast and the state mutation applying it, in this order.
+    #[tokio::test]
+    async fn check_transfer_logs() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, 0);
+        let log_sink = Arc::new(RecordingLogSink::default());
+        bitcoin_agent.set_log_sink(log_sink.clone());
+
+        canister_mock::multi_transfer(
+            bitcoin_agent,
+            &BTreeMap::from([(
+                Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                    .unwrap()
+                    .assume_checked(),
+                25_000,
+            )]),
+            main_address,
+            Fee::Constant(10_000),
+            0,
+            false,
+        )
+        .await;
+
+        assert_eq!(
+            *log_sink.targets.lock().unwrap(),
+            vec![
+                LOG_TARGET_UTXO_SELECTION,
+                LOG_TARGET_FEE,
+                LOG_TARGET_SIGN,
+                LOG_TARGET_BROADCAST,
+                LOG_TARGET_BROADCAST,
+                LOG_TARGET_STATE,
+            ]
+        );
+    }
+}
@@ -0,0 +1,610 @@
+/// Initializes the `thread_local` [RefCell]<[BitcoinAgent]> `$bitcoin_agent` like [initialize] with the ECDSA public key retrieved by [get_initialization_parameters_from_args], nothing being done if it's already initialized.
+/// The [BitcoinAgent] is only borrowed to get the arguments of the call and then mutably to initialize it, hence the borrow isn't held across the `await`.
+/// It is equivalent to:
+/// ```ignore
+/// if !BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().is_initialized()) {
+///     let initialization_parameters_args = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_initialization_parameters_args());
+///     let ecdsa_public_key = get_initialization_parameters_from_args(initialization_parameters_args).await?.result;
+///     BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow_mut().initialize(ecdsa_public_key));
+/// }
+/// Ok(())
+/// ```
+/// For instance, it can be called at the beginning of every `update` method, only the first call initializing the [BitcoinAgent]:
+/// ```
+/// # use std::cell::RefCell;
+/// # #[cfg(feature = "mock")]
+/// # use ic_btc_library::{AddressType, BitcoinAgent, canister_mock::{get_mock_ecdsa_public_key, ManagementCanisterMock}, ManagementCanisterFactory, Network};
+/// #
+/// # #[cfg(feature = "mock")]
+/// # thread_local! {
+/// #     static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterMock>> = RefCell::new(
+/// #         BitcoinAgent::new(
+/// #             ManagementCanisterMock::new_using_ecdsa_public_key(Network::Regtest, get_mock_ecdsa_public_key(), None),
+/// #             &AddressType::P2pkh,
+/// #             0,
+/// #         )
+/// #         .unwrap(),
+/// #     );
+/// # }
+/// #
+/// # #[cfg(feature = "mock")]
+/// # fn main() {
+/// assert!(!BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().is_initialized()));
+/// futures::executor::block_on(async { ic_btc_library::initialize!(BITCOIN_AGENT) }).unwrap();
+/// assert!(BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().is_initialized()));
+/// let main_address = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+/// let address = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow_mut().add_address(&[vec![1]])).unwrap();
+///
+/// // Initializing the Bitcoin agent again keeps its managed addresses.
+/// futures::executor::block_on(async { ic_btc_library::initialize!(BITCOIN_AGENT) }).unwrap();
+/// BITCOIN_AGENT.with(|bitcoin_agent| {
+///     let bitcoin_agent = bitcoin_agent.borrow();
+///     assert_eq!(bitcoin_agent.get_main_address(), main_address);
+///     assert!(bitcoin_agent.list_addresses().contains(&&address));
+/// });
+/// # }
+/// # #[cfg(not(feature = "mock"))]
+/// # fn main() {}
+/// ```
+///
+/// [RefCell]: std::cell::RefCell
+/// [BitcoinAgent]: crate::BitcoinAgent
+/// [initialize]: crate::BitcoinAgent::initialize
+/// [get_initialization_parameters_from_args]: crate::get_initialization_parameters_from_args
+#[macro_export]
+macro_rules! initialize {
+    ($bitcoin_agent:expr) => {{
+        let initialization_parameters_args = $bitcoin_agent.with(|bitcoin_agent| {
+            let bitcoin_agent = bitcoin_agent.borrow();
+            (!bitcoin_agent.is_initialized())
+                .then(|| bitcoin_agent.get_initialization_parameters_args())
+        });
+        match initialization_parameters_args {
+            Some(initialization_parameters_args) => {
+                match $crate::get_initialization_parameters_from_args(
+                    initialization_parameters_args,
+                )
+                .await
+                {
+                    // Another message may have initialized the Bitcoin agent during the call, in which case it's kept.
+                    Ok(ecdsa_public_key) => {
+                        $bitcoin_agent.with(|bitcoin_agent| {
+                            bitcoin_agent
+                                .borrow_mut()
+                                .initialize(ecdsa_public_key.result)
+                        });
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            None => Ok::<(), $crate::ManagementCanisterReject>(()),
+        }
+    }};
+}
+
+/// Returns the fee as a percentile in millisatoshis/byte over the last 10,000 transactions using the `thread_local` [RefCell]<[BitcoinAgent]> `$bitcoin_agent`.
+/// The [BitcoinAgent] is only borrowed to get the arguments of the call, hence the borrow isn't held across the `await`.
+/// It is equivalent to:
+/// ```ignore
+/// let current_fee_args = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_current_fee_args(fee_request));
+/// get_current_fee_from_args(current_fee_args).await
+/// ```
+///
+/// [RefCell]: std::cell::RefCell
+/// [BitcoinAgent]: crate::BitcoinAgent
+#[macro_export]
+macro_rules! get_current_fee {
+    ($bitcoin_agent:expr, $fee_request:expr) => {{
+        let current_fee_args = $bitcoin_agent
+            .with(|bitcoin_agent| bitcoin_agent.borrow().get_current_fee_args($fee_request));
+        $crate::get_current_fee_from_args(current_fee_args).await
+    }};
+}
+
+/// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions using the `thread_local` [RefCell]<[BitcoinAgent]> `$bitcoin_agent`.
+/// The [BitcoinAgent] is only borrowed to get the arguments of the call, hence the borrow isn't held across the `await`.
+/// If the fees are rate limited, the [RateLimited] error is returned as a [ManagementCanisterReject] without making the call.
+/// It is equivalent to:
+/// ```ignore
+/// match BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_current_fees_args()) {
+///     Ok(current_fees_args) => get_current_fees_from_args(current_fees_args).await,
+///     Err(rate_limited) => Err(rate_limited.into()),
+/// }
+/// ```
+///
+/// [RefCell]: std::cell::RefCell
+/// [BitcoinAgent]: crate::BitcoinAgent
+/// [RateLimited]: crate::RateLimited
+/// [ManagementCanisterReject]: crate::ManagementCanisterReject
+#[macro_export]
+macro_rules! get_current_fees {
+    ($bitcoin_agent:expr) => {{
+        let current_fees_args =
+            $bitcoin_agent.with(|bitcoin_agent| bitcoin_agent.borrow().get_current_fees_args());
+        match current_fees_args {
+            Ok(current_fees_args) => $crate::get_current_fees_from_args(current_fees_args).await,
+            Err(rate_limited) => Err($crate::ManagementCanisterReject::from(rate_limited)),
+        }
+    }};
+}
+
+/// Returns the UTXOs of `$address` according to `$min_confirmations` using the `thread_local` [RefCell]<[BitcoinAgent]> `$bitcoin_agent`, after applying them to the [BitcoinAgent] with [apply_utxos].
+/// The [BitcoinAgent] is only borrowed to get the arguments of the call and then mutably to apply its result, hence the borrow isn't held across the `await`.
+/// If `$address` isn't managed, or stops being managed during the call, [GetUtxosError::AddressNotTracked] is returned.
+/// It is equivalent to:
+/// ```ignore
+/// let utxos_args = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_utxos_args(address, min_confirmations))?;
+/// let utxos_result = get_utxos_from_args(utxos_args).await?.result;
+/// let utxos = utxos_result.utxos.clone();
+/// BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow_mut().apply_utxos(utxos_result));
+/// Ok(utxos)
+/// ```
+/// For instance, the future of the following call can be awaited by an `update` method, as it doesn't hold a borrow, the calls being served here by the management canister mock:
+/// ```
+/// # use std::cell::RefCell;
+/// # #[cfg(feature = "mock")]
+/// # use ic_btc_library::{AddressType, BitcoinAgent, canister_mock::{new_mock, ManagementCanisterMock}, Network};
+/// #
+/// # #[cfg(feature = "mock")]
+/// # thread_local! {
+/// #     static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterMock>> =
+/// #        RefCell::new(new_mock(&Network::Regtest, &AddressType::P2pkh));
+/// # }
+/// #
+/// # fn assert_send<T: Send>(future: T) -> T {
+/// #     future
+/// # }
+/// #
+/// # #[cfg(feature = "mock")]
+/// # fn main() {
+/// # ic_btc_library::set_canister_caller(Some(
+/// #     BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_management_canister_mock().get_canister_caller()),
+/// # ));
+/// let address = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+/// let utxos = futures::executor::block_on(assert_send(async {
+///     ic_btc_library::get_utxos!(BITCOIN_AGENT, &address, 1)
+/// }))
+/// .unwrap();
+/// assert_eq!(utxos.iter().map(|utxo| utxo.value).sum::<u64>(), 250_000);
+/// // The UTXOs have been applied to the Bitcoin agent.
+/// let utxos_update = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().peek_utxos_update(&address)).unwrap();
+/// assert_eq!(utxos_update.added_utxos, utxos);
+/// # }
+/// # #[cfg(not(feature = "mock"))]
+/// # fn main() {}
+/// ```
+///
+/// [RefCell]: std::cell::RefCell
+/// [BitcoinAgent]: crate::BitcoinAgent
+/// [apply_utxos]: crate::BitcoinAgent::apply_utxos
+/// [GetUtxosError::AddressNotTracked]: crate::GetUtxosError::AddressNotTracked
+#[macro_export]
+macro_rules! get_utxos {
+    ($bitcoin_agent:expr, $address:expr, $min_confirmations:expr) => {{
+        let address = $address;
+        let utxos_args = $bitcoin_agent.with(|bitcoin_agent| -> Result<_, $crate::GetUtxosError> {
+            let bitcoin_agent = bitcoin_agent.borrow();
+            bitcoin_agent.get_address_min_confirmations(address)?;
+            bitcoin_agent
+                .get_utxos_args(address, $min_confirmations)
+                .map_err(|rate_limited| {
+                    $crate::GetUtxosError::from($crate::ManagementCanisterReject::from(
+                        rate_limited,
+                    ))
+                })
+        });
+        match utxos_args {
+            Ok(utxos_args) => match $crate::get_utxos_from_args(utxos_args).await {
+                Ok(utxos_result) => {
+                    $bitcoin_agent.with(|bitcoin_agent| -> Result<_, $crate::GetUtxosError> {
+                        let mut bitcoin_agent = bitcoin_agent.borrow_mut();
+                        bitcoin_agent.get_address_min_confirmations(address)?;
+                        let utxos = utxos_result.result.utxos.clone();
+                        bitcoin_agent.apply_utxos(utxos_result.result);
+                        Ok(utxos)
+                    })
+                }
+                Err(error) => Err(error),
+            },
+            Err(error) => Err(error),
+        }
+    }};
+}
+
+/// Refreshes the UTXOs of the managed `$address` according to its minimum number of confirmations using the `thread_local` [RefCell]<[BitcoinAgent]> `$bitcoin_agent`, and returns its balance update as [get_balance_update] does.
+/// The [BitcoinAgent] is only borrowed to get the arguments of the call and then mutably to apply its result, hence the borrow isn't held across the `await`.
+/// If `$address` isn't managed, or stops being managed during the call, [GetUtxosError::AddressNotTracked] is returned.
+/// It is equivalent to:
+/// ```ignore
+/// let utxos_args = BITCOIN_AGENT.with(|bitcoin_agent| {
+///     let bitcoin_agent = bitcoin_agent.borrow();
+///     bitcoin_agent.get_utxos_args(address, bitcoin_agent.get_address_min_confirmations(address)?)
+/// })?;
+/// let utxos_result = get_utxos_from_args(utxos_args).await?.result;
+/// BITCOIN_AGENT.with(|bitcoin_agent| {
+///     let mut bitcoin_agent = bitcoin_agent.borrow_mut();
+///     bitcoin_agent.apply_utxos(utxos_result);
+///     bitcoin_agent.get_balance_update(address)
+/// })
+/// ```
+/// For instance, the future of the following call can be awaited by an `update` method, as it doesn't hold a borrow, the calls being served here by the management canister mock:
+/// ```
+/// # use std::cell::RefCell;
+/// # #[cfg(feature = "mock")]
+/// # use ic_btc_library::{AddressType, BitcoinAgent, canister_mock::{new_mock, ManagementCanisterMock}, Network};
+/// #
+/// # #[cfg(feature = "mock")]
+/// # thread_local! {
+/// #     static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterMock>> =
+/// #        RefCell::new(new_mock(&Network::Regtest, &AddressType::P2pkh));
+/// # }
+/// #
+/// # fn assert_send<T: Send>(future: T) -> T {
+/// #     future
+/// # }
+/// #
+/// # #[cfg(feature = "mock")]
+/// # fn main() {
+/// # ic_btc_library::set_canister_caller(Some(
+/// #     BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_management_canister_mock().get_canister_caller()),
+/// # ));
+/// let address = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+/// let refresh = || {
+///     futures::executor::block_on(assert_send(async {
+///         ic_btc_library::refresh_and_get_balance_update!(BITCOIN_AGENT, &address)
+///     }))
+///     .unwrap()
+/// };
+/// let balance_update = refresh();
+/// assert_eq!((balance_update.added_balance, balance_update.removed_balance), (250_000, 0));
+///
+/// // The balance update has been applied to the Bitcoin agent.
+/// let balance_update = refresh();
+/// assert_eq!((balance_update.added_balance, balance_update.removed_balance), (0, 0));
+/// # }
+/// # #[cfg(not(feature = "mock"))]
+/// # fn main() {}
+/// ```
+///
+/// [RefCell]: std::cell::RefCell
+/// [BitcoinAgent]: crate::BitcoinAgent
+/// [get_balance_update]: crate::BitcoinAgent::get_balance_update
+/// [GetUtxosError::AddressNotTracked]: crate::GetUtxosError::AddressNotTracked
+#[macro_export]
+macro_rules! refresh_and_get_balance_update {
+    ($bitcoin_agent:expr, $address:expr) => {{
+        let address = $address;
+        let utxos_args = $bitcoin_agent.with(|bitcoin_agent| -> Result<_, $crate::GetUtxosError> {
+            let bitcoin_agent = bitcoin_agent.borrow();
+            let min_confirmations = bitcoin_agent.get_address_min_confirmations(address)?;
+            bitcoin_agent
+                .get_utxos_args(address, min_confirmations)
+                .map_err(|rate_limited| {
+                    $crate::GetUtxosError::from($crate::ManagementCanisterReject::from(
+                        rate_limited,
+                    ))
+                })
+        });
+        match utxos_args {
+            Ok(utxos_args) => match $crate::get_utxos_from_args(utxos_args).await {
+                Ok(utxos_result) => {
+                    $bitcoin_agent.with(|bitcoin_agent| -> Result<_, $crate::GetUtxosError> {
+                        let mut bitcoin_agent = bitcoin_agent.borrow_mut();
+                        bitcoin_agent.get_address_min_confirmations(address)?;
+                        bitcoin_agent.apply_utxos(utxos_result.result);
+                        Ok(bitcoin_agent.get_balance_update(address)?)
+                    })
+                }
+                Err(error) => Err(error),
+            },
+            Err(error) => Err(error),
+        }
+    }};
+}
+
+/// Transfers bitcoins from the managed addresses to `$payouts` like [multi_transfer_from_args] using the `thread_local` [RefCell]<[BitcoinAgent]> `$bitcoin_agent`, and applies the result to the [BitcoinAgent] with [apply_multi_transfer_result] so that the spent UTXOs aren't spent again.
+/// The [BitcoinAgent] is only borrowed to get the arguments of the transfer and then mutably to apply its result, hence the borrow isn't held across the `await`s.
+/// As the transaction is already sent when its result is applied, the [MultiTransferResult] is returned along with the result of [apply_multi_transfer_result].
+/// A result which can't be applied, e.g. as a spending address stopped being managed during the transfer, leaves the [BitcoinAgent] unchanged, the UTXOs being corrected by their next refresh.
+/// It is equivalent to:
+/// ```ignore
+/// let multi_transfer_args = BITCOIN_AGENT.with(|bitcoin_agent| {
+///     bitcoin_agent.borrow().get_multi_transfer_args(payouts, change_address, fee, min_confirmations, replaceable)
+/// });
+/// let multi_transfer_result = multi_transfer_from_args(multi_transfer_args).await?;
+/// let apply_result = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow_mut().apply_multi_transfer_result(&multi_transfer_result));
+/// Ok((multi_transfer_result, apply_result))
+/// ```
+/// For instance, the future of the following transfer can be awaited by an `update` method, as it doesn't hold a borrow, the calls being served here by the management canister mock:
+/// ```
+/// # use std::cell::RefCell;
+/// # #[cfg(feature = "mock")]
+/// # use ic_btc_library::{AddressType, BitcoinAgent, canister_mock::{new_mock, ManagementCanisterMock}, Network};
+/// #
+/// # #[cfg(feature = "mock")]
+/// # thread_local! {
+/// #     static BITCOIN_AGENT: RefCell<BitcoinAgent<ManagementCanisterMock>> =
+/// #        RefCell::new(new_mock(&Network::Regtest, &AddressType::P2pkh));
+/// # }
+/// #
+/// # fn assert_send<T: Send>(future: T) -> T {
+/// #     future
+/// # }
+/// #
+/// # #[cfg(feature = "mock")]
+/// # fn main() {
+/// # let canister_caller = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_management_canister_mock().get_canister_caller());
+/// # ic_btc_library::set_canister_caller(Some(canister_caller));
+/// let address = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+/// let payouts = std::collections::BTreeMap::from([(address.clone(), 10_000)]);
+/// // Makes the UTXOs of the address available to the transfer.
+/// futures::executor::block_on(async { ic_btc_library::refresh_and_get_balance_update!(BITCOIN_AGENT, &address) }).unwrap();
+/// let (multi_transfer_result, apply_result) = futures::executor::block_on(assert_send(async {
+///     ic_btc_library::multi_transfer!(BITCOIN_AGENT, &payouts, &address, ic_btc_library::Fee::Standard, 1, true)
+/// }))
+/// .unwrap();
+/// assert_eq!(apply_result, Ok(()));
+/// let pending_transactions = BITCOIN_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_management_canister_mock().get_pending_transactions());
+/// assert_eq!(pending_transactions.len(), 1);
+/// assert_eq!(pending_transactions[0].txid().to_string(), multi_transfer_result.transaction_info.id.to_string());
+/// # }
+/// # #[cfg(not(feature = "mock"))]
+/// # fn main() {}
+/// ```
+///
+/// [RefCell]: std::cell::RefCell
+/// [BitcoinAgent]: crate::BitcoinAgent
+/// [multi_transfer_from_args]: crate::multi_transfer_from_args
+/// [MultiTransferResult]: crate::MultiTransferResult
+/// [apply_multi_transfer_result]: crate::BitcoinAgent::apply_multi_transfer_result
+#[macro_export]
+macro_rules! multi_transfer {
+    ($bitcoin_agent:expr, $payouts:expr, $change_address:expr, $fee:expr, $min_confirmations:expr, $replaceable:expr) => {{
+        let multi_transfer_args = $bitcoin_agent.with(|bitcoin_agent| {
+            bitcoin_agent.borrow().get_multi_transfer_args(
+                $payouts,
+                $change_address,
+                $fee,
+                $min_confirmations,
+                $replaceable,
+            )
+        });
+        match $crate::multi_transfer_from_args(multi_transfer_args).await {
+            Ok(multi_transfer_result) => {
+                let apply_result = $bitcoin_agent.with(|bitcoin_agent| {
+                    bitcoin_agent
+                        .borrow_mut()
+                        .apply_multi_transfer_result(&multi_transfer_result)
+                });
+                Ok((multi_transfer_result, apply_result))
+            }
+            Err(error) => Err(error),
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent::tests::MOCK_AGENT,
+        canister_mock::{get_init_balance_update, get_init_utxos},
+        set_canister_caller, ApplyMultiTransferResultError, BalanceUpdate, CanisterCaller, Fee,
+        FeeRequest, GetUtxosError, TransactionID, UtxosUpdate,
+    };
+    use async_trait::async_trait;
+    use bitcoin::{Address, Txid};
+    use candid::Principal;
+    use ic_cdk::api::call::CallResult;
+    use std::{collections::BTreeMap, sync::Arc};
+
+    /// Requires `future` to be `Send`, which isn't the case if it holds a `Ref` of the Bitcoin agent across an `await`.
+    fn assert_send<T: Send>(_future: T) {}
+
+    /// Check that the initialization macro doesn't hold a borrow of the Bitcoin agent across an `await` and that it keeps an initialized Bitcoin agent unchanged.
+    #[tokio::test]
+    async fn check_initialize_macro() {
+        assert_send(async { initialize!(MOCK_AGENT) });
+
+        let main_address =
+            MOCK_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+        let address = MOCK_AGENT
+            .with(|bitcoin_agent| bitcoin_agent.borrow_mut().add_address(&[vec![1]]))
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(initialize!(MOCK_AGENT), Ok(()));
+            MOCK_AGENT.with(|bitcoin_agent| {
+                let bitcoin_agent = bitcoin_agent.borrow();
+                assert!(bitcoin_agent.is_initialized());
+                assert_eq!(bitcoin_agent.get_main_address(), main_address);
+                assert!(bitcoin_agent.list_addresses().contains(&&address));
+            });
+        }
+    }
+
+    /// Check that the fee macros compile and that their futures don't hold a borrow of the Bitcoin agent across an `await`.
+    #[test]
+    fn check_fee_macros() {
+        assert_send(async { get_current_fee!(MOCK_AGENT, FeeRequest::Standard) });
+        assert_send(async { get_current_fees!(MOCK_AGENT) });
+    }
+
+    /// Check that the UTXOs and transfer macros compile and that their futures don't hold a borrow of the Bitcoin agent across an `await`.
+    #[test]
+    fn check_utxos_and_transfer_macros() {
+        let address = MOCK_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+        let payouts = BTreeMap::from([(address.clone(), 10_000)]);
+        assert_send(async { get_utxos!(MOCK_AGENT, &address, 1) });
+        assert_send(async { refresh_and_get_balance_update!(MOCK_AGENT, &address) });
+        assert_send(async {
+            multi_transfer!(MOCK_AGENT, &payouts, &address, Fee::Standard, 1, false)
+        });
+    }
+
+    /// Makes the calls of the library be served by the management canister mock of `MOCK_AGENT`.
+    fn serve_calls_with_mock() {
+        set_canister_caller(Some(MOCK_AGENT.with(|bitcoin_agent| {
+            bitcoin_agent
+                .borrow()
+                .get_management_canister_mock()
+                .get_canister_caller()
+        })));
+    }
+
+    /// Check that the UTXOs macros apply the UTXOs returned by the management canister mock to the Bitcoin agent.
+    #[tokio::test]
+    async fn check_utxos_macros_apply_utxos() {
+        serve_calls_with_mock();
+        let address = MOCK_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+
+        assert_eq!(get_utxos!(MOCK_AGENT, &address, 1), Ok(get_init_utxos()));
+        assert_eq!(
+            MOCK_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().peek_utxos_update(&address)),
+            Ok(UtxosUpdate {
+                added_utxos: get_init_utxos(),
+                removed_utxos: vec![],
+            })
+        );
+
+        assert_eq!(
+            refresh_and_get_balance_update!(MOCK_AGENT, &address),
+            Ok(get_init_balance_update())
+        );
+        // The balance update has been consumed by the Bitcoin agent.
+        assert_eq!(
+            refresh_and_get_balance_update!(MOCK_AGENT, &address),
+            Ok(BalanceUpdate::default())
+        );
+
+        let unmanaged_address = MOCK_AGENT
+            .with(|bitcoin_agent| bitcoin_agent.borrow_mut().add_address(&[vec![1]]))
+            .unwrap();
+        MOCK_AGENT.with(|bitcoin_agent| {
+            bitcoin_agent
+                .borrow_mut()
+                .remove_address(&unmanaged_address)
+        });
+        assert!(matches!(
+            get_utxos!(MOCK_AGENT, &unmanaged_address, 1),
+            Err(GetUtxosError::AddressNotTracked)
+        ));
+        set_canister_caller(None);
+    }
+
+    /// Check that the transfer macro sends the transaction to the management canister mock and applies its result to the Bitcoin agent.
+    #[tokio::test]
+    async fn check_multi_transfer_macro_applies_result() {
+        serve_calls_with_mock();
+        let address = MOCK_AGENT.with(|bitcoin_agent| bitcoin_agent.borrow().get_main_address());
+        let payouts = BTreeMap::from([(address.clone(), 10_000)]);
+        // Makes the UTXO of the address available to the transfer.
+        refresh_and_get_balance_update!(MOCK_AGENT, &address).unwrap();
+
+        let (multi_transfer_result, apply_result) =
+            multi_transfer!(MOCK_AGENT, &payouts, &address, Fee::Standard, 1, false).unwrap();
+        assert_eq!(apply_result, Ok(()));
+        MOCK_AGENT.with(|bitcoin_agent| {
+            let bitcoin_agent = bitcoin_agent.borrow();
+            let pending_transactions = bitcoin_agent
+                .get_management_canister_mock()
+                .get_pending_transactions();
+            assert_eq!(pending_transactions.len(), 1);
+            assert_eq!(
+                TransactionID::from(pending_transactions[0].txid()),
+                multi_transfer_result.transaction_info.id
+            );
+            let spent_outpoints: Vec<_> = get_init_utxos()
+                .into_iter()
+                .map(|utxo| utxo.outpoint)
+                .collect();
+            assert_eq!(
+                bitcoin_agent.utxos_state_addresses[&address].spent_state,
+                spent_outpoints
+            );
+        });
+
+        set_canister_caller(None);
+    }
+
+    /// The canister caller removing `address` from `MOCK_AGENT` when the transaction is sent, as another message may do during the transfer.
+    struct RemovingAddressCanisterCaller {
+        canister_caller: Arc<dyn CanisterCaller>,
+        address: Address,
+    }
+
+    #[async_trait]
+    impl CanisterCaller for RemovingAddressCanisterCaller {
+        async fn call(
+            &self,
+            canister_id: Principal,
+            method: &str,
+            args: Vec<u8>,
+            payment: u128,
+        ) -> CallResult<Vec<u8>> {
+            if method == "bitcoin_send_transaction" {
+                MOCK_AGENT.with(|bitcoin_agent| {
+                    assert!(bitcoin_agent.borrow_mut().remove_address(&self.address))
+                });
+            }
+            self.canister_caller
+                .call(canister_id, method, args, payment)
+                .await
+        }
+
+        fn get_cycles_refunded(&self) -> u128 {
+            self.canister_caller.get_cycles_refunded()
+        }
+    }
+
+    /// Check that the transfer macro returns the error of applying its result if a spending address stops being managed during the transfer, the transaction being sent nonetheless.
+    #[tokio::test]
+    async fn check_multi_transfer_macro_returns_apply_error() {
+        let (main_address, address) = MOCK_AGENT.with(|bitcoin_agent| {
+            let mut bitcoin_agent = bitcoin_agent.borrow_mut();
+            let address = bitcoin_agent.add_address(&[vec![1]]).unwrap();
+            bitcoin_agent
+                .get_management_canister_mock_mut()
+                .fund_address(&address, 100_000);
+            (bitcoin_agent.get_main_address(), address)
+        });
+        let canister_caller = MOCK_AGENT.with(|bitcoin_agent| {
+            bitcoin_agent
+                .borrow()
+                .get_management_canister_mock()
+                .get_canister_caller()
+        });
+        set_canister_caller(Some(Arc::new(RemovingAddressCanisterCaller {
+            canister_caller,
+            address: address.clone(),
+        })));
+        // Spending both the UTXO of the main address and the one of `address`.
+        let payouts = BTreeMap::from([(main_address.clone(), 300_000)]);
+        for address in [&main_address, &address] {
+            refresh_and_get_balance_update!(MOCK_AGENT, address).unwrap();
+        }
+
+        let (multi_transfer_result, apply_result) =
+            multi_transfer!(MOCK_AGENT, &payouts, &main_address, Fee::Standard, 0, false).unwrap();
+        assert_eq!(
+            apply_result,
+            Err(ApplyMultiTransferResultError::AddressNotTracked(
+                address.to_string()
+            ))
+        );
+        MOCK_AGENT.with(|bitcoin_agent| {
+            let bitcoin_agent = bitcoin_agent.borrow();
+            assert_eq!(
+                bitcoin_agent
+                    .get_management_canister_mock()
+                    .get_pending_transactions()[0]
+                    .txid(),
+                Txid::from(multi_transfer_result.transaction_info.id)
+            );
+            // The Bitcoin agent is left unchanged.
+            assert!(bitcoin_agent.utxos_state_addresses[&main_address]
+                .spent_state
+                .is_empty());
+        });
+        set_canister_caller(None);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::{
+    address_management::get_btc_public_key_from_ecdsa_public_key, DerivationPath,
+    ManagementCanisterReject, SignMessageArgs, SignMessageError, SignedMessage,
+    SignedMessageFormat, WithCost,
+};
+use bitcoin::{
+    absolute::LockTime,
+    blockdata::{opcodes, script::Builder},
+    consensus::{deserialize, encode::VarInt, serialize, Encodable},
+    hashes::{sha256, sha256d, Hash, HashEngine},
+    secp256k1::{
+        ecdsa::{RecoverableSignature, RecoveryId, Signature},
+        Message, Secp256k1,
+    },
+    sighash::{EcdsaSighashType, SegwitV0Sighash, SighashCache},
+    sign_message::MessageSignature,
+    Address, AddressType, OutPoint, PublicKey, Script, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Witness,
+};
+use std::future::Future;
+
+// The prefix of the messages signed with the legacy `signmessage` format, starting with its length.
+const LEGACY_MESSAGE_PREFIX: &[u8] = b"\x18Bitcoin Signed Message:\n";
+
+// The tag of the BIP-322 message hash.
+const BIP322_TAG: &[u8] = b"BIP0322-signed-message";
+
+/// Returns the hash of the given message signed with the legacy `signmessage` format.
+fn get_legacy_message_hash(message: &[u8]) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(LEGACY_MESSAGE_PREFIX);
+    VarInt(message.len() as u64)
+        .consensus_encode(&mut engine)
+        .unwrap();
+    engine.input(message);
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Returns the BIP-322 tagged hash of the given message.
+fn get_bip322_message_hash(message: &[u8]) -> sha256::Hash {
+    let tag_hash = sha256::Hash::hash(BIP322_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash.as_byte_array());
+    engine.input(tag_hash.as_byte_array());
+    engine.input(message);
+    sha256::Hash::from_engine(engine)
+}
+
+/// Returns the BIP-322 virtual transaction paying to `script_pubkey` and committing to the given message.
+fn get_bip322_to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(0)
+                .push_slice(get_bip322_message_hash(message).to_byte_array())
+                .into_script(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: script_pubkey.to_owned(),
+        }],
+    }
+}
+
+/// Returns the BIP-322 virtual transaction spending `to_spend` with the given witness.
+fn get_bip322_to_sign(to_spend: &Transaction, witness: Witness) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.txid(),
+                vout: 0,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .into_script(),
+        }],
+    }
+}
+
+/// Returns the signature hash of the P2WPKH input of the BIP-322 `to_sign` transaction.
+fn get_bip322_sighash(public_key: &PublicKey, to_sign: &Transaction) -> SegwitV0Sighash {
+    SighashCache::new(to_sign)
+        .segwit_signature_hash(
+            0,
+            &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+            0,
+            EcdsaSighashType::All,
+        )
+        .unwrap()
+}
+
+/// Returns the 65-byte legacy signature of `message_hash` from its 64-byte compact signature.
+/// The header byte lets the verifier recover the compressed public key, hence the recovery identifier is the one recovering `public_key`.
+fn get_legacy_signature(
+    public_key: &PublicKey,
+    message_hash: sha256d::Hash,
+    signature: &[u8],
+) -> Vec<u8> {
+    let secp256k1 = Secp256k1::verification_only();
+    (0..4)
+        .map(|recovery_id| {
+            MessageSignature::new(
+                RecoverableSignature::from_compact(
+                    signature,
+                    RecoveryId::from_i32(recovery_id).unwrap(),
+                )
+                .unwrap(),
+                true,
+            )
+        })
+        .find(|message_signature| {
+            message_signature
+                .recover_pubkey(&secp256k1, message_hash)
+                .is_ok_and(|recovered_public_key| recovered_public_key == *public_key)
+        })
+        .unwrap()
+        .serialize()
+        .to_vec()
+}
+
+/// Signs the message of `sign_message_args` with `signer` at the derivation path of its address.
+/// P2PKH addresses use the legacy `signmessage` format and P2WPKH addresses the BIP-322 simple format, the other address types aren't supported.
+/// Returns the signed message and the cycles spent by `signer`.
+pub(crate) async fn sign_message<SignFun, Fut>(
+    sign_message_args: SignMessageArgs,
+    signer: SignFun,
+) -> Result<WithCost<SignedMessage>, SignMessageError>
+where
+    SignFun: Fn(String, DerivationPath, Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<WithCost<Vec<u8>>, ManagementCanisterReject>>,
+{
+    let public_key =
+        get_btc_public_key_from_ecdsa_public_key(&sign_message_args.ecdsa_pub_key).unwrap();
+    let address = &sign_message_args.address;
+    let message = &sign_message_args.message;
+    let format = match address.address_type() {
+        Some(AddressType::P2pkh) => SignedMessageFormat::Legacy,
+        Some(AddressType::P2wpkh) => SignedMessageFormat::Bip322Simple,
+        _ => return Err(SignMessageError::UnsupportedAddressType),
+    };
+    sign_message_args
+        .ecdsa_pub_key
+        .derivation_path
+        .validate()
+        .map_err(SignMessageError::InvalidDerivationPath)?;
+    let legacy_message_hash = get_legacy_message_hash(message);
+    let message_hash = match format {
+        SignedMessageFormat::Legacy => legacy_message_hash.to_byte_array().to_vec(),
+        SignedMessageFormat::Bip322Simple => {
+            let to_spend = get_bip322_to_spend(&address.script_pubkey(), message);
+            get_bip322_sighash(&public_key, &get_bip322_to_sign(&to_spend, Witness::new()))
+                .to_byte_array()
+                .to_vec()
+        }
+    };
+
+    let signature = signer(
+        sign_message_args.key_name,
+        sign_message_args.ecdsa_pub_key.derivation_path,
+        message_hash,
+    )
+    .await?;
+
+    Ok(signature.map(|signature| SignedMessage {
+        format,
+        signature: match format {
+            SignedMessageFormat::Legacy => {
+                get_legacy_signature(&public_key, legacy_message_hash, &signature)
+            }
+            SignedMessageFormat::Bip322Simple => {
+                let mut der_signature = Signature::from_compact(&signature)
+                    .unwrap()
+                    .serialize_der()
+                    .to_vec();
+                der_signature.push(EcdsaSighashType::All.to_u32() as u8);
+                serialize(&Witness::from_slice(&[
+                    der_signature,
+                    public_key.to_bytes(),
+                ]))
+            }
+        },
+    }))
+}
+
+/// Returns true if the legacy `signature` of `message` was made with the key of the P2PKH `address`.
+fn verify_legacy_signature(address: &Address, message: &[u8], signature: &[u8]) -> bool {
+    MessageSignature::from_slice(signature)
+        .and_then(|message_signature| {
+            message_signature.recover_pubkey(
+                &Secp256k1::verification_only(),
+                get_legacy_message_hash(message),
+            )
+        })
+        .is_ok_and(|public_key| Address::p2pkh(&public_key, address.network) == *address)
+}
+
+/// Returns true if the BIP-322 simple `signature` of `message` was made with the key of the P2WPKH `address`, `None` if `signature` is malformed.
+fn verify_bip322_simple_signature(
+    address: &Address,
+    message: &[u8],
+    signature: &[u8],
+) -> Option<bool> {
+    let witness: Witness = deserialize(signature).ok()?;
+    let (signature, public_key) = match witness.to_vec().as_slice() {
+        [signature, public_key] => (signature.clone(), public_key.clone()),
+        _ => return None,
+    };
+    let (sighash_type, der_signature) = signature.split_last()?;
+    if *sighash_type != EcdsaSighashType::All.to_u32() as u8 {
+        return None;
+    }
+    let public_key = PublicKey::from_slice(&public_key).ok()?;
+    if Address::p2wpkh(&public_key, address.network).ok()? != *address {
+        return Some(false);
+    }
+    let to_spend = get_bip322_to_spend(&address.script_pubkey(), message);
+    let sighash = get_bip322_sighash(&public_key, &get_bip322_to_sign(&to_spend, witness));
+    Some(
+        Secp256k1::verification_only()
+            .verify_ecdsa(
+                &Message::from_slice(sighash.as_byte_array()).unwrap(),
+                &Signature::from_der(der_signature).ok()?,
+                &public_key.inner,
+            )
+            .is_ok(),
+    )
+}
+
+/// Returns true if `signed_message` is a valid signature of `message` made with the key of `address`.
+/// The format of `signed_message` has to be the one used by `sign_message_from_args` for the type of `address`.
+pub fn verify_signed_message(
+    address: &Address,
+    message: &[u8],
+    signed_message: &SignedMessage,
+) -> bool {
+    match (address.address_type(), signed_message.format) {
+        (Some(AddressType::P2pkh), SignedMessageFormat::Legacy) => {
+            verify_legacy_signature(address, message, &signed_message.signature)
+        }
+        (Some(AddressType::P2wpkh), SignedMessageFormat::Bip322Simple) => {
+            verify_bip322_simple_signature(address, message, &signed_message.signature)
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agent, AddressType, Network};
+    use std::str::FromStr;
+
+    /// Check that the BIP-322 message hash and `to_spend` transaction match the test vectors of BIP-322.
+    #[test]
+    fn check_bip322_test_vectors() {
+        let address = Address::from_str("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l")
+            .unwrap()
+            .assume_checked();
+        for (message, message_hash, to_spend_txid) in [
+            (
+                "",
+                "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1",
+                "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7",
+            ),
+            (
+                "Hello World",
+                "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a",
+                "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b",
+            ),
+        ] {
+            assert_eq!(
+                get_bip322_message_hash(message.as_bytes())
+                    .to_byte_array()
+                    .to_vec(),
+                hex::decode(message_hash).unwrap()
+            );
+            assert_eq!(
+                get_bip322_to_spend(&address.script_pubkey(), message.as_bytes())
+                    .txid()
+                    .to_string(),
+                to_spend_txid
+            );
+        }
+    }
+
+    /// Check that messages signed for the main address of each supported address type are verified, and only for this message and address.
+    #[tokio::test]
+    async fn check_sign_message() {
+        let message = b"Prove control of this address";
+        for (address_type, format, other_address) in [
+            (
+                AddressType::P2pkh,
+                SignedMessageFormat::Legacy,
+                "mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76",
+            ),
+            (
+                AddressType::P2wpkh,
+                SignedMessageFormat::Bip322Simple,
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            ),
+        ] {
+            let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &address_type);
+            let main_address = &bitcoin_agent.get_main_address();
+
+            let sign_message_args = bitcoin_agent
+                .get_sign_message_args(main_address, message)
+                .unwrap();
+            let signed_message = bitcoin_agent
+                .sign_message_from_args_test(sign_message_args)
+                .await
+                .unwrap()
+                .result;
+            assert_eq!(signed_message.format, format);
+            assert!(verify_signed_message(
+                main_address,
+                message,
+                &signed_message
+            ));
+
+            assert!(!verify_signed_message(
+                main_address,
+                b"Another message",
+                &signed_message
+            ));
+            assert!(!verify_signed_message(
+                &Address::from_str(other_address).unwrap().assume_checked(),
+                message,
+                &signed_message
+            ));
+        }
+
+        // The other address types aren't supported.
+        for address_type in [AddressType::P2sh, AddressType::P2tr] {
+            let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &address_type);
+            let sign_message_args = bitcoin_agent
+                .get_sign_message_args(&bitcoin_agent.get_main_address(), message)
+                .unwrap();
+            assert!(matches!(
+                bitcoin_agent
+                    .sign_message_from_args_test(sign_message_args)
+                    .await,
+                Err(SignMessageError::UnsupportedAddressType)
+            ));
+        }
+    }
+}
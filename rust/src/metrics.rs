@@ -0,0 +1,164 @@
+use crate::{ManagementCanisterMethod, MethodMetrics, MetricsSnapshot};
+use ic_cdk::api::call::RejectionCode;
+use std::{cell::RefCell, collections::BTreeMap};
+
+thread_local! {
+    // The metrics are kept globally, the free functions making the calls not having access to the Bitcoin agent.
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+/// Counts the calls made by the library to the management canister, or to the canister serving the Bitcoin API, with the cycles they spent and their rejections.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    methods: BTreeMap<ManagementCanisterMethod, MethodMetrics>,
+}
+
+impl Metrics {
+    /// Records a successful call to `method` which spent `cycles_spent` cycles.
+    fn record_call(&mut self, method: ManagementCanisterMethod, cycles_spent: u128) {
+        let method_metrics = self.methods.entry(method).or_default();
+        method_metrics.calls += 1;
+        method_metrics.cycles_spent += cycles_spent;
+    }
+
+    /// Records a call to `method` rejected with `rejection_code`.
+    fn record_rejection(
+        &mut self,
+        method: ManagementCanisterMethod,
+        rejection_code: RejectionCode,
+    ) {
+        let method_metrics = self.methods.entry(method).or_default();
+        method_metrics.calls += 1;
+        match method_metrics
+            .rejections
+            .iter_mut()
+            .find(|(code, _)| *code == rejection_code)
+        {
+            Some((_, rejections)) => *rejections += 1,
+            None => method_metrics.rejections.push((rejection_code, 1)),
+        }
+    }
+}
+
+/// Records a successful call to `method` which spent `cycles_spent` cycles.
+pub(crate) fn record_call(method: ManagementCanisterMethod, cycles_spent: u128) {
+    METRICS.with(|metrics| metrics.borrow_mut().record_call(method, cycles_spent));
+}
+
+/// Records a call to `method` rejected with `rejection_code`.
+pub(crate) fn record_rejection(method: ManagementCanisterMethod, rejection_code: RejectionCode) {
+    METRICS.with(|metrics| {
+        metrics
+            .borrow_mut()
+            .record_rejection(method, rejection_code)
+    });
+}
+
+/// Returns the metrics recorded since the last reset.
+pub(crate) fn get_metrics_snapshot() -> MetricsSnapshot {
+    METRICS.with(|metrics| MetricsSnapshot {
+        methods: metrics.borrow().methods.clone(),
+    })
+}
+
+/// Replaces the recorded metrics with `metrics_snapshot`, e.g. with an empty snapshot to reset them or with the snapshot saved before an upgrade.
+pub(crate) fn set_metrics_snapshot(metrics_snapshot: MetricsSnapshot) {
+    METRICS.with(|metrics| metrics.borrow_mut().methods = metrics_snapshot.methods);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent,
+        canister_mock::{self, get_balance_update},
+        AddressType, Fee, ManagementCanisterMethod, MetricsSnapshot, Network,
+    };
+    use bitcoin::Address;
+    use ic_cdk::api::call::RejectionCode;
+    use std::{collections::BTreeMap, str::FromStr};
+
+    /// Check that a mock transfer records the expected calls, cycles and rejections, and that the metrics can be reset and restored.
+    #[tokio::test]
+    async fn check_metrics() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        bitcoin_agent.reset_metrics();
+        assert_eq!(bitcoin_agent.metrics(), MetricsSnapshot::default());
+        let cycles_cost_config = bitcoin_agent.get_cycles_cost_config();
+        let main_address = &bitcoin_agent.get_main_address();
+
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            RejectionCode::SysTransient,
+            "busy",
+            1,
+        );
+        assert!(bitcoin_agent
+            .get_current_fees_from_args_test(bitcoin_agent.get_current_fees_args().unwrap())
+            .is_err());
+        get_balance_update(bitcoin_agent, main_address, 0);
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        let transaction_info = canister_mock::multi_transfer(
+            bitcoin_agent,
+            &payouts,
+            main_address,
+            Fee::Standard,
+            0,
+            false,
+        )
+        .await;
+        let inputs = transaction_info
+            .utxos_addresses
+            .values()
+            .map(Vec::len)
+            .sum::<usize>() as u64;
+
+        let metrics = bitcoin_agent.metrics();
+        // The balance update and the tip height retrieval of the transfer.
+        let get_utxos_metrics = metrics.get(ManagementCanisterMethod::BitcoinGetUtxos);
+        assert_eq!(get_utxos_metrics.calls, 2);
+        assert_eq!(
+            get_utxos_metrics.cycles_spent,
+            2 * cycles_cost_config.get_utxos
+        );
+        // The rejected fees retrieval and the one of the transfer.
+        let get_fees_metrics =
+            metrics.get(ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles);
+        assert_eq!(get_fees_metrics.calls, 2);
+        assert_eq!(get_fees_metrics.cycles_spent, cycles_cost_config.get_fees);
+        assert_eq!(
+            get_fees_metrics.get_rejections(RejectionCode::SysTransient),
+            1
+        );
+        assert_eq!(
+            get_fees_metrics.get_rejections(RejectionCode::CanisterReject),
+            0
+        );
+        let sign_metrics = metrics.get(ManagementCanisterMethod::SignWithEcdsa);
+        assert_eq!(sign_metrics.calls, inputs);
+        assert_eq!(
+            sign_metrics.cycles_spent,
+            inputs as u128 * cycles_cost_config.sign_with_ecdsa
+        );
+        assert!(sign_metrics.rejections.is_empty());
+        assert_eq!(
+            metrics
+                .get(ManagementCanisterMethod::BitcoinSendTransaction)
+                .calls,
+            1
+        );
+        assert_eq!(
+            metrics.get(ManagementCanisterMethod::SignWithSchnorr).calls,
+            0
+        );
+
+        bitcoin_agent.reset_metrics();
+        assert_eq!(bitcoin_agent.metrics(), MetricsSnapshot::default());
+        bitcoin_agent.restore_metrics(metrics.clone());
+        assert_eq!(bitcoin_agent.metrics(), metrics);
+    }
+}
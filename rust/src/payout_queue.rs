@@ -0,0 +1,184 @@
+use crate::{
+    upgrade_management::{get_address, get_address_using_primitives},
+    PayoutQueueState, QueueId, Satoshi,
+};
+use bitcoin::Address;
+use std::collections::BTreeMap;
+
+/// Accumulates payouts to send them later in a single transaction.
+#[derive(Clone, Default)]
+pub(crate) struct PayoutQueue {
+    next_queue_id: QueueId,
+    queued_payouts: BTreeMap<QueueId, (Address, Satoshi)>,
+}
+
+impl PayoutQueue {
+    /// Queues a payout of `amount` satoshis to `address` and returns its identifier.
+    pub(crate) fn queue_payout(&mut self, address: &Address, amount: Satoshi) -> QueueId {
+        let queue_id = self.next_queue_id;
+        self.queued_payouts
+            .insert(queue_id, (address.clone(), amount));
+        self.next_queue_id += 1;
+        queue_id
+    }
+
+    /// Removes the queued payout with the given identifier.
+    /// Returns true if the payout was queued, false otherwise.
+    pub(crate) fn cancel_queued(&mut self, queue_id: QueueId) -> bool {
+        self.queued_payouts.remove(&queue_id).is_some()
+    }
+
+    /// Returns the total amount of the queued payouts.
+    pub(crate) fn queued_total(&self) -> Satoshi {
+        self.queued_payouts.values().map(|(_, amount)| amount).sum()
+    }
+
+    /// Returns the queued payouts, merging the amounts of payouts to the same address, and the identifiers of these payouts.
+    pub(crate) fn get_payouts(&self) -> (BTreeMap<Address, Satoshi>, Vec<QueueId>) {
+        let mut payouts: BTreeMap<Address, Satoshi> = BTreeMap::default();
+        self.queued_payouts.values().for_each(|(address, amount)| {
+            *payouts.entry(address.clone()).or_default() += amount;
+        });
+        (payouts, self.queued_payouts.keys().cloned().collect())
+    }
+
+    /// Removes the given payouts from the queue, ignoring the identifiers that aren't queued anymore.
+    pub(crate) fn remove_queued(&mut self, queue_ids: &[QueueId]) {
+        queue_ids.iter().for_each(|queue_id| {
+            self.queued_payouts.remove(queue_id);
+        });
+    }
+
+    /// Returns the payout queue state.
+    pub(crate) fn get_state(&self) -> PayoutQueueState {
+        PayoutQueueState {
+            next_queue_id: self.next_queue_id,
+            queued_payouts: self
+                .queued_payouts
+                .iter()
+                .map(|(queue_id, (address, amount))| {
+                    (*queue_id, (get_address_using_primitives(address), *amount))
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the payout queue associated with the given `payout_queue_state`.
+    pub(crate) fn from_state(payout_queue_state: PayoutQueueState) -> Self {
+        Self {
+            next_queue_id: payout_queue_state.next_queue_id,
+            queued_payouts: payout_queue_state
+                .queued_payouts
+                .into_iter()
+                .map(|(queue_id, (address_using_primitives, amount))| {
+                    (
+                        queue_id,
+                        (
+                            get_address(address_using_primitives)
+                                .expect("Malformed state address."),
+                            amount,
+                        ),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent,
+        canister_mock::{
+            self, get_balance_update, get_init_balance, mine_block, ManagementCanisterMock,
+        },
+        AddressType, BitcoinAgent, Fee, ManagementCanisterMethod, Network, Satoshi,
+    };
+    use bitcoin::Address;
+    use ic_cdk::api::call::RejectionCode;
+    use std::str::FromStr;
+
+    /// Check that queued payouts are sent in a single transaction and only removed from the queue once the transaction result is applied.
+    #[tokio::test]
+    async fn check_flush_payout_queue() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let min_confirmations = 0;
+        let fee_amount = 10_000;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        let address_0 = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        let address_1 = &Address::from_str("mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt")
+            .unwrap()
+            .assume_checked();
+        let queued_payouts: [(&Address, Satoshi); 5] = [
+            (address_0, 10_000),
+            (address_1, 15_000),
+            (address_0, 20_000),
+            (address_1, 25_000),
+            (address_0, 30_000),
+        ];
+        let queue_ids: Vec<_> = queued_payouts
+            .iter()
+            .map(|(address, amount)| bitcoin_agent.queue_payout(address, *amount))
+            .collect();
+        assert_eq!(bitcoin_agent.queued_total(), 100_000);
+
+        // A cancelled payout isn't part of the flush.
+        let cancelled_queue_id = bitcoin_agent.queue_payout(address_1, 5_000);
+        assert!(bitcoin_agent.cancel_queued(cancelled_queue_id));
+        assert!(!bitcoin_agent.cancel_queued(cancelled_queue_id));
+        assert_eq!(bitcoin_agent.queued_total(), 100_000);
+
+        // The queue is part of the Bitcoin agent state.
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Testnet).unwrap();
+        assert_eq!(restored_bitcoin_agent.queued_total(), 100_000);
+
+        let flush_args = bitcoin_agent.get_flush_args(Fee::Constant(fee_amount), main_address);
+        assert_eq!(flush_args.payouts.len(), 2);
+        assert_eq!(flush_args.payouts[address_0], 60_000);
+        assert_eq!(flush_args.payouts[address_1], 40_000);
+        assert_eq!(flush_args.queued_payout_ids, queue_ids);
+
+        // A failed broadcast keeps the payouts queued.
+        bitcoin_agent.management_canister.reject_next_call(
+            ManagementCanisterMethod::BitcoinSendTransaction,
+            RejectionCode::SysTransient,
+            "Simulated send_transaction failure.",
+        );
+        assert!(bitcoin_agent
+            .multi_transfer_from_args_test(flush_args)
+            .await
+            .is_err());
+        assert_eq!(bitcoin_agent.queued_total(), 100_000);
+
+        let flush_args = bitcoin_agent.get_flush_args(Fee::Constant(fee_amount), main_address);
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(flush_args)
+            .await
+            .unwrap();
+        assert_eq!(bitcoin_agent.queued_total(), 100_000);
+        bitcoin_agent
+            .apply_multi_transfer_result(&multi_transfer_result)
+            .unwrap();
+        assert_eq!(bitcoin_agent.queued_total(), 0);
+
+        mine_block(&mut bitcoin_agent.management_canister);
+
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, main_address, min_confirmations),
+            get_init_balance() - 100_000 - fee_amount
+        );
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, address_0, min_confirmations),
+            60_000
+        );
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, address_1, min_confirmations),
+            40_000
+        );
+    }
+}
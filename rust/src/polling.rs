@@ -0,0 +1,345 @@
+use crate::{
+    canister_common::ManagementCanister,
+    logging::{LogLevel, LOG_TARGET_STATE},
+    AddressNotTracked, BitcoinAgent, GetUtxosError, PollBudget, UtxosArgs, UtxosResult,
+    UtxosUpdate,
+};
+use bitcoin::Address;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Keeps track of the periodic refreshes of the UTXOs of the managed addresses planned with `next_poll_plan`.
+#[derive(Clone, Default)]
+pub(crate) struct Poller {
+    /// The number of plans made so far.
+    invocations: u64,
+    /// The invocation during which each address was last planned.
+    last_polled: BTreeMap<Address, u64>,
+    /// The last address planned in the round-robin order, the next plan starting after it.
+    cursor: Option<Address>,
+    /// The addresses waiting for an incoming payment, planned ahead of the other ones.
+    expected_incoming: BTreeSet<Address>,
+}
+
+impl Poller {
+    /// Returns true if `address` was planned within the last `skip_recent_invocations` invocations before `invocation`.
+    fn was_polled_recently(
+        &self,
+        address: &Address,
+        invocation: u64,
+        skip_recent_invocations: u32,
+    ) -> bool {
+        self.last_polled
+            .get(address)
+            .is_some_and(|last_polled| invocation - last_polled <= skip_recent_invocations as u64)
+    }
+}
+
+/// Returns the arguments to refresh the UTXOs of at most `poll_budget.max_calls` managed addresses of the given BitcoinAgent.
+/// The addresses are planned in a round-robin order, skipping the ones planned within the last `poll_budget.skip_recent_invocations` invocations, the addresses expecting an incoming payment being planned first.
+/// The plan stops early if the rate limit of `RateLimitedOp::GetUtxos` is reached.
+pub(crate) fn next_poll_plan(
+    bitcoin_agent: &BitcoinAgent<impl ManagementCanister>,
+    poll_budget: PollBudget,
+) -> Vec<UtxosArgs> {
+    let mut poller = bitcoin_agent.poller.borrow_mut();
+    // Forgets the addresses that aren't managed anymore.
+    poller
+        .last_polled
+        .retain(|address, _| bitcoin_agent.ecdsa_pub_key_addresses.contains_key(address));
+    poller
+        .expected_incoming
+        .retain(|address| bitcoin_agent.ecdsa_pub_key_addresses.contains_key(address));
+    poller.invocations += 1;
+    let invocation = poller.invocations;
+
+    // The managed addresses are sorted, hence the round-robin order starts with the ones after the cursor.
+    let addresses = bitcoin_agent.list_addresses();
+    let split = poller.cursor.as_ref().map_or(0, |cursor| {
+        addresses.partition_point(|address| *address <= cursor)
+    });
+    let mut candidates: Vec<&Address> = addresses[split..]
+        .iter()
+        .chain(&addresses[..split])
+        .copied()
+        .filter(|address| {
+            !poller.was_polled_recently(address, invocation, poll_budget.skip_recent_invocations)
+        })
+        .collect();
+    // The sort is stable, hence the round-robin order is kept among the addresses expecting an incoming payment and among the other ones.
+    candidates.sort_by_key(|address| !poller.expected_incoming.contains(*address));
+
+    let mut poll_plan = vec![];
+    for address in candidates.into_iter().take(poll_budget.max_calls as usize) {
+        let min_confirmations = bitcoin_agent.utxos_state_addresses[address].min_confirmations;
+        match bitcoin_agent.get_utxos_args(address, min_confirmations) {
+            Ok(utxos_args) => poll_plan.push(utxos_args),
+            Err(_) => break,
+        }
+        poller.last_polled.insert(address.clone(), invocation);
+        // Planning an address ahead of its turn doesn't move the cursor, so that the other addresses keep their turn.
+        if !poller.expected_incoming.contains(address) {
+            poller.cursor = Some(address.clone());
+        }
+    }
+    poll_plan
+}
+
+/// Applies the results of the calls made with the arguments returned by `next_poll_plan` and returns the UTXOs updates of the refreshed addresses.
+/// The failed calls and the results of addresses that aren't managed anymore are skipped.
+/// An address stops expecting an incoming payment once UTXOs are added to it.
+pub(crate) fn apply_poll_results(
+    bitcoin_agent: &mut BitcoinAgent<impl ManagementCanister>,
+    poll_results: Vec<Result<UtxosResult, GetUtxosError>>,
+) -> Vec<(Address, UtxosUpdate)> {
+    let mut utxos_updates = vec![];
+    for poll_result in poll_results {
+        let utxos_result = match poll_result {
+            Ok(utxos_result) => utxos_result,
+            Err(get_utxos_error) => {
+                bitcoin_agent.log_sink.log(
+                    LogLevel::Warn,
+                    LOG_TARGET_STATE,
+                    &format!("Skipped a failed poll: {:?}.", get_utxos_error),
+                );
+                continue;
+            }
+        };
+        let address = utxos_result.address.clone();
+        if !bitcoin_agent.ecdsa_pub_key_addresses.contains_key(&address) {
+            continue;
+        }
+        let utxos_update = bitcoin_agent.apply_utxos(utxos_result);
+        if !utxos_update.added_utxos.is_empty() {
+            bitcoin_agent
+                .poller
+                .get_mut()
+                .expected_incoming
+                .remove(&address);
+        }
+        utxos_updates.push((address, utxos_update));
+    }
+    utxos_updates
+}
+
+/// Makes `next_poll_plan` plan the given managed address ahead of the other ones until UTXOs are added to it.
+pub(crate) fn expect_incoming(
+    bitcoin_agent: &mut BitcoinAgent<impl ManagementCanister>,
+    address: &Address,
+) -> Result<(), AddressNotTracked> {
+    if !bitcoin_agent.ecdsa_pub_key_addresses.contains_key(address) {
+        return Err(AddressNotTracked);
+    }
+    bitcoin_agent
+        .poller
+        .get_mut()
+        .expected_incoming
+        .insert(address.clone());
+    Ok(())
+}
+
+/// Stops expecting an incoming payment to the given address.
+/// Returns true if a payment was expected, false otherwise.
+pub(crate) fn cancel_expected_incoming(
+    bitcoin_agent: &mut BitcoinAgent<impl ManagementCanister>,
+    address: &Address,
+) -> bool {
+    bitcoin_agent
+        .poller
+        .get_mut()
+        .expected_incoming
+        .remove(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent, canister_mock::ManagementCanisterMock, AddressType, BitcoinAgent, GetUtxosError,
+        Network, PollBudget, RateLimitedOp, UtxosArgs, UtxosResult,
+    };
+    use bitcoin::Address;
+    use std::{collections::BTreeMap, str::FromStr};
+
+    /// Creates a Bitcoin agent managing 12 addresses, its main address included.
+    fn new_mock_with_addresses() -> BitcoinAgent<ManagementCanisterMock> {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        for index in 0..11 {
+            bitcoin_agent.add_address(&[vec![index]]).unwrap();
+        }
+        assert_eq!(bitcoin_agent.managed_address_count(), 12);
+        bitcoin_agent
+    }
+
+    fn get_planned_addresses(poll_plan: &[UtxosArgs]) -> Vec<Address> {
+        poll_plan
+            .iter()
+            .map(|utxos_args| utxos_args.address.clone())
+            .collect()
+    }
+
+    /// Makes the calls planned by `poll_plan` with the management canister mock.
+    fn poll(
+        bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>,
+        poll_plan: Vec<UtxosArgs>,
+    ) -> Vec<Result<UtxosResult, GetUtxosError>> {
+        poll_plan
+            .into_iter()
+            .map(|utxos_args| {
+                bitcoin_agent
+                    .get_utxos_from_args_test(utxos_args)
+                    .map(|utxos_result| utxos_result.result)
+            })
+            .collect()
+    }
+
+    /// Check that with a small budget every managed address is planned in turn, each one being planned as often as the other ones.
+    #[test]
+    fn check_poll_plan_fairness() {
+        let bitcoin_agent = new_mock_with_addresses();
+        let poll_budget = PollBudget {
+            max_calls: 5,
+            skip_recent_invocations: 0,
+        };
+
+        let mut poll_counts: BTreeMap<Address, u32> = BTreeMap::default();
+        let mut previous_planned_addresses = vec![];
+        for _ in 0..12 {
+            let planned_addresses =
+                get_planned_addresses(&bitcoin_agent.next_poll_plan(poll_budget));
+            assert_eq!(planned_addresses.len(), 5);
+            // The addresses of consecutive plans differ as there are more addresses than the budget of two plans.
+            assert!(planned_addresses
+                .iter()
+                .all(|address| !previous_planned_addresses.contains(address)));
+            planned_addresses.iter().for_each(|address| {
+                *poll_counts.entry(address.clone()).or_default() += 1;
+            });
+            previous_planned_addresses = planned_addresses;
+        }
+        // The 60 planned calls are evenly spread over the 12 addresses.
+        assert_eq!(poll_counts.len(), 12);
+        assert!(poll_counts.values().all(|poll_count| *poll_count == 5));
+    }
+
+    /// Check that the addresses planned within the last `skip_recent_invocations` invocations aren't planned again, even if the budget allows it.
+    #[test]
+    fn check_poll_plan_skips_recent_addresses() {
+        let bitcoin_agent = new_mock_with_addresses();
+        let poll_budget = PollBudget {
+            max_calls: 100,
+            skip_recent_invocations: 2,
+        };
+        assert_eq!(bitcoin_agent.next_poll_plan(poll_budget).len(), 12);
+        assert!(bitcoin_agent.next_poll_plan(poll_budget).is_empty());
+        assert!(bitcoin_agent.next_poll_plan(poll_budget).is_empty());
+        assert_eq!(bitcoin_agent.next_poll_plan(poll_budget).len(), 12);
+
+        let poll_budget = PollBudget {
+            max_calls: 4,
+            skip_recent_invocations: 2,
+        };
+        let bitcoin_agent = new_mock_with_addresses();
+        let mut planned_addresses = vec![];
+        for _ in 0..3 {
+            planned_addresses.extend(get_planned_addresses(
+                &bitcoin_agent.next_poll_plan(poll_budget),
+            ));
+        }
+        planned_addresses.sort();
+        planned_addresses.dedup();
+        assert_eq!(planned_addresses.len(), 12);
+    }
+
+    /// Check that the addresses expecting an incoming payment are planned first until UTXOs are added to them, without taking the turn of the other addresses.
+    #[test]
+    fn check_poll_plan_expected_incoming() {
+        let mut bitcoin_agent = new_mock_with_addresses();
+        let poll_budget = PollBudget {
+            max_calls: 3,
+            skip_recent_invocations: 0,
+        };
+        let addresses: Vec<Address> = bitcoin_agent
+            .list_addresses()
+            .into_iter()
+            .cloned()
+            .collect();
+        let expected_address = addresses[10].clone();
+        bitcoin_agent.expect_incoming(&expected_address).unwrap();
+        assert!(bitcoin_agent
+            .expect_incoming(
+                &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                    .unwrap()
+                    .assume_checked()
+            )
+            .is_err());
+
+        let poll_plan = bitcoin_agent.next_poll_plan(poll_budget);
+        assert_eq!(
+            get_planned_addresses(&poll_plan),
+            vec![
+                expected_address.clone(),
+                addresses[0].clone(),
+                addresses[1].clone()
+            ]
+        );
+        // No UTXOs were added to the expected address, hence it's still expected.
+        let poll_results = poll(&bitcoin_agent, poll_plan);
+        bitcoin_agent.apply_poll_results(poll_results);
+        assert_eq!(
+            get_planned_addresses(&bitcoin_agent.next_poll_plan(poll_budget)),
+            vec![
+                expected_address.clone(),
+                addresses[2].clone(),
+                addresses[3].clone()
+            ]
+        );
+
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .fund_address(&expected_address, 10_000);
+        let poll_plan = bitcoin_agent.next_poll_plan(poll_budget);
+        let poll_results = poll(&bitcoin_agent, poll_plan);
+        let utxos_updates = bitcoin_agent.apply_poll_results(poll_results);
+        let (_, utxos_update) = utxos_updates
+            .iter()
+            .find(|(address, _)| *address == expected_address)
+            .unwrap();
+        assert_eq!(utxos_update.added_utxos.len(), 1);
+        assert_eq!(utxos_update.added_utxos[0].value, 10_000);
+
+        // The payment arrived, hence the expected address waits for its turn again.
+        assert!(!bitcoin_agent.cancel_expected_incoming(&expected_address));
+        assert_eq!(
+            get_planned_addresses(&bitcoin_agent.next_poll_plan(poll_budget)),
+            addresses[6..9].to_vec()
+        );
+    }
+
+    /// Check that the failed calls and the results of removed addresses are skipped and that the plan stops once the rate limit is reached.
+    #[test]
+    fn check_apply_poll_results() {
+        let mut bitcoin_agent = new_mock_with_addresses();
+        let poll_budget = PollBudget {
+            max_calls: 3,
+            skip_recent_invocations: 0,
+        };
+        let poll_plan = bitcoin_agent.next_poll_plan(poll_budget);
+        let planned_addresses = get_planned_addresses(&poll_plan);
+        let mut poll_results = poll(&bitcoin_agent, poll_plan);
+        poll_results.push(Err(GetUtxosError::MinConfirmationsTooHigh));
+        let removed_address = planned_addresses
+            .iter()
+            .find(|address| **address != bitcoin_agent.get_main_address())
+            .unwrap();
+        assert!(bitcoin_agent.remove_address(removed_address));
+
+        let utxos_updates = bitcoin_agent.apply_poll_results(poll_results);
+        assert_eq!(utxos_updates.len(), 2);
+        assert!(utxos_updates
+            .iter()
+            .all(|(address, _)| address != removed_address));
+
+        bitcoin_agent.set_rate_limit(RateLimitedOp::GetUtxos, 2, 1);
+        assert_eq!(bitcoin_agent.next_poll_plan(poll_budget).len(), 2);
+        assert!(bitcoin_agent.next_poll_plan(poll_budget).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{RateLimitState, RateLimited, RateLimitedOp};
+use std::collections::BTreeMap;
+
+/// Limits the number of calls of the rate limited operations per window of blocks.
+#[derive(Clone, Default)]
+pub(crate) struct RateLimiter {
+    rate_limits: BTreeMap<RateLimitedOp, RateLimitState>,
+}
+
+impl RateLimiter {
+    /// Limits `op` to `max_calls_per_window` calls per window of `window_len` blocks, the first window starting at `tip_height`.
+    pub(crate) fn set_rate_limit(
+        &mut self,
+        op: RateLimitedOp,
+        max_calls_per_window: u32,
+        window_len: u32,
+        tip_height: u32,
+    ) {
+        self.rate_limits.insert(
+            op,
+            RateLimitState {
+                max_calls_per_window,
+                window_len,
+                window_start: tip_height,
+                calls_in_window: 0,
+            },
+        );
+    }
+
+    /// Removes the rate limit of `op`.
+    /// Returns true if `op` was rate limited, false otherwise.
+    pub(crate) fn remove_rate_limit(&mut self, op: RateLimitedOp) -> bool {
+        self.rate_limits.remove(&op).is_some()
+    }
+
+    pub(crate) fn is_rate_limited(&self, op: RateLimitedOp) -> bool {
+        self.rate_limits.contains_key(&op)
+    }
+
+    /// Counts a call of `op` at `tip_height`, a new window starting if the current one is over.
+    /// Fails without counting the call if the rate limit of `op` is reached in the current window.
+    pub(crate) fn count_call(
+        &mut self,
+        op: RateLimitedOp,
+        tip_height: u32,
+    ) -> Result<(), RateLimited> {
+        let rate_limit = match self.rate_limits.get_mut(&op) {
+            Some(rate_limit) => rate_limit,
+            None => return Ok(()),
+        };
+        let window_end = rate_limit
+            .window_start
+            .saturating_add(rate_limit.window_len);
+        if tip_height >= window_end {
+            rate_limit.window_start = tip_height;
+            rate_limit.calls_in_window = 0;
+        } else if rate_limit.calls_in_window >= rate_limit.max_calls_per_window {
+            return Err(RateLimited {
+                retry_after: window_end,
+            });
+        }
+        rate_limit.calls_in_window += 1;
+        Ok(())
+    }
+
+    /// Returns the rate limits along with the calls made in their current windows, `None` if there are none.
+    pub(crate) fn get_state(&self) -> Option<BTreeMap<RateLimitedOp, RateLimitState>> {
+        (!self.rate_limits.is_empty()).then(|| self.rate_limits.clone())
+    }
+
+    pub(crate) fn from_state(rate_limits: Option<BTreeMap<RateLimitedOp, RateLimitState>>) -> Self {
+        Self {
+            rate_limits: rate_limits.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agent, canister_mock::get_current_fees, AddressType, BitcoinAgent, GetBlockHeadersResponse,
+        Network, RateLimited, RateLimitedOp,
+    };
+
+    /// Applies the tip height `tip_height` to the Bitcoin agent, as if it was returned by `get_block_headers_from_args`.
+    fn apply_tip_height<C: crate::ManagementCanister>(
+        bitcoin_agent: &mut BitcoinAgent<C>,
+        tip_height: u32,
+    ) {
+        bitcoin_agent.apply_block_headers(&GetBlockHeadersResponse {
+            tip_height,
+            block_headers: vec![],
+        });
+    }
+
+    /// Check that the arguments of a rate limited operation are refused once its limit is reached until the tip height reaches the end of the window, the other operations not being limited.
+    #[test]
+    fn check_rate_limit() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        apply_tip_height(&mut bitcoin_agent, 100);
+        bitcoin_agent.set_rate_limit(RateLimitedOp::GetUtxos, 2, 3);
+
+        for _ in 0..2 {
+            assert!(bitcoin_agent.get_utxos_args(main_address, 0).is_ok());
+        }
+        assert_eq!(
+            bitcoin_agent.get_utxos_args(main_address, 0).unwrap_err(),
+            RateLimited { retry_after: 103 }
+        );
+        // The fees aren't limited.
+        for _ in 0..3 {
+            assert!(!get_current_fees(&bitcoin_agent).is_empty());
+        }
+
+        apply_tip_height(&mut bitcoin_agent, 102);
+        assert!(bitcoin_agent.get_utxos_args(main_address, 0).is_err());
+        apply_tip_height(&mut bitcoin_agent, 103);
+        for _ in 0..2 {
+            assert!(bitcoin_agent.get_utxos_args(main_address, 0).is_ok());
+        }
+        assert_eq!(
+            bitcoin_agent.get_utxos_args(main_address, 0).unwrap_err(),
+            RateLimited { retry_after: 106 }
+        );
+
+        bitcoin_agent.remove_rate_limit(RateLimitedOp::GetUtxos);
+        assert!(bitcoin_agent.get_utxos_args(main_address, 0).is_ok());
+    }
+
+    /// Check that the calls made in the current window are restored from the state, so that an upgrade doesn't reset the window.
+    #[test]
+    fn check_rate_limit_state() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        assert_eq!(bitcoin_agent.get_state().rate_limits, None);
+        apply_tip_height(&mut bitcoin_agent, 100);
+        bitcoin_agent.set_rate_limit(RateLimitedOp::GetCurrentFees, 1, 6);
+        assert!(bitcoin_agent.get_current_fees_args().is_ok());
+
+        let mut restored_bitcoin_agent: BitcoinAgent<crate::canister_mock::ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Regtest).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent.get_state(),
+            bitcoin_agent.get_state()
+        );
+        // The tip height isn't saved in the state, hence the window lasts until the tip height is applied again.
+        assert_eq!(
+            restored_bitcoin_agent.get_current_fees_args().unwrap_err(),
+            RateLimited { retry_after: 106 }
+        );
+        apply_tip_height(&mut restored_bitcoin_agent, 106);
+        assert!(restored_bitcoin_agent.get_current_fees_args().is_ok());
+    }
+}
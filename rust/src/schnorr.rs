@@ -0,0 +1,51 @@
+use crate::{
+    canister_common::{call_with_payment, get_cycles_spent},
+    types::{
+        SchnorrAlgorithm, SchnorrKeyId, SignWithBip341Aux, SignWithSchnorr, SignWithSchnorrAux,
+        SignWithSchnorrReply,
+    },
+    CyclesCostConfig, DerivationPath, ManagementCanisterMethod, ManagementCanisterReject, WithCost,
+};
+use candid::Principal;
+
+/// Returns the BIP-340 signature of the given `message` associated with the Schnorr public key of this canister at the given derivation path and the cycles spent to obtain it.
+/// The key is tweaked without any script as per BIP-86, hence the signature is valid for a Taproot key path spend.
+pub(crate) async fn sign_with_schnorr(
+    key_name: String,
+    derivation_path: DerivationPath,
+    message: Vec<u8>,
+    cycles_cost_config: CyclesCostConfig,
+) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
+    let res: Result<(SignWithSchnorrReply,), _> = call_with_payment(
+        Principal::management_canister(),
+        ManagementCanisterMethod::SignWithSchnorr,
+        (SignWithSchnorr {
+            message,
+            derivation_path: derivation_path.into(),
+            key_id: SchnorrKeyId {
+                algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+                name: key_name,
+            },
+            aux: Some(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+                merkle_root_hash: vec![],
+            })),
+        },),
+        cycles_cost_config.sign_with_schnorr,
+    )
+    .await;
+
+    match res {
+        // Return the signature to the caller.
+        Ok(data) => Ok(WithCost {
+            result: data.0.signature,
+            cycles_spent: get_cycles_spent(
+                ManagementCanisterMethod::SignWithSchnorr,
+                cycles_cost_config.sign_with_schnorr,
+            ),
+            attempts: 1,
+        }),
+
+        // The call to `sign_with_schnorr` was rejected for a given reason (e.g., not enough cycles were attached to the call).
+        Err((rejection_code, message)) => Err(ManagementCanisterReject(rejection_code, message)),
+    }
+}
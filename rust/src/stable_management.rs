@@ -0,0 +1,376 @@
+use crate::{
+    upgrade_management,
+    upgrade_management::{get_address_using_primitives, get_state_without_addresses},
+    AddressUsingPrimitives, BitcoinAgent, BitcoinAgentState, BitcoinAgentStateV1, EcdsaPubKey,
+    ManagementCanister, ManagementCanisterFactory, StateRestoreError, StateRestoreOptions,
+    UtxosState,
+};
+use bitcoin::{
+    hashes::{sha256, Hash},
+    Address,
+};
+use candid::CandidType;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::de::DeserializeOwned;
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+/// The virtual memories of the memory manager holding the state of a stable Bitcoin agent, which the canister mustn't use otherwise.
+/// They respectively hold the ECDSA public keys of the managed addresses, their UTXOs states and the rest of the state.
+pub const STABLE_MEMORY_IDS: [MemoryId; 3] = [MemoryId::new(0), MemoryId::new(1), MemoryId::new(2)];
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Key of an address in the stable maps, the SHA-256 hash of the address so that the keys have a fixed size whatever the address type.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct AddressKey([u8; 32]);
+
+impl AddressKey {
+    fn new(address: &Address) -> Self {
+        Self(sha256::Hash::hash(address.to_string().as_bytes()).to_byte_array())
+    }
+}
+
+impl Storable for AddressKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.as_ref().try_into().unwrap())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+}
+
+/// Value of the stable structures, encoded with Candid.
+struct StableValue<T>(T);
+
+impl<T: CandidType + DeserializeOwned> Storable for StableValue<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(candid::decode_one(bytes.as_ref()).unwrap())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Value of the cell holding the state without its managed addresses, encoded with Candid.
+/// States written with the layout of the version 1, which aren't decoded as the current layout, are decoded with the former and migrated, and the ones of the version 2 are migrated as well.
+struct StableState(Option<BitcoinAgentState>);
+
+impl Storable for StableState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match candid::decode_one::<Option<BitcoinAgentState>>(bytes.as_ref()) {
+            Ok(Some(state)) => Self(Some(upgrade_management::migrate_v2_to_v3(state))),
+            _ => Self(
+                candid::decode_one::<Option<BitcoinAgentStateV1>>(bytes.as_ref())
+                    .unwrap()
+                    .map(BitcoinAgentState::from),
+            ),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The state of a Bitcoin agent written through to the stable memory on every mutation, so that upgrades don't have to save it.
+pub(crate) struct StableStorage {
+    ecdsa_pub_key_addresses:
+        StableBTreeMap<AddressKey, StableValue<(AddressUsingPrimitives, EcdsaPubKey)>, Memory>,
+    utxos_state_addresses:
+        StableBTreeMap<AddressKey, StableValue<(AddressUsingPrimitives, UtxosState)>, Memory>,
+    /// The state without its managed addresses, `None` if no Bitcoin agent was written yet.
+    state: StableCell<StableState, Memory>,
+}
+
+impl StableStorage {
+    /// Opens the stable structures held by the `STABLE_MEMORY_IDS` virtual memories.
+    fn open(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> Self {
+        let [ecdsa_pub_key_addresses_memory_id, utxos_state_addresses_memory_id, state_memory_id] =
+            STABLE_MEMORY_IDS;
+        Self {
+            ecdsa_pub_key_addresses: StableBTreeMap::init(
+                memory_manager.get(ecdsa_pub_key_addresses_memory_id),
+            ),
+            utxos_state_addresses: StableBTreeMap::init(
+                memory_manager.get(utxos_state_addresses_memory_id),
+            ),
+            state: StableCell::init(memory_manager.get(state_memory_id), StableState(None))
+                .unwrap(),
+        }
+    }
+
+    /// Returns the Bitcoin agent state held by the stable structures, `None` if no Bitcoin agent was written yet.
+    fn read(&self) -> Option<BitcoinAgentState> {
+        let StableState(state) = self.state.get();
+        let state = state.clone()?;
+        Some(BitcoinAgentState {
+            ecdsa_pub_key_addresses: self
+                .ecdsa_pub_key_addresses
+                .iter()
+                .map(|(_, StableValue(entry))| entry)
+                .collect(),
+            utxos_state_addresses: self
+                .utxos_state_addresses
+                .iter()
+                .map(|(_, StableValue(entry))| entry)
+                .collect(),
+            ..state
+        })
+    }
+
+    /// Writes the Bitcoin agent state without its managed addresses.
+    fn write_state(&mut self, state: BitcoinAgentState) {
+        self.state.set(StableState(Some(state))).unwrap();
+    }
+
+    /// Writes the ECDSA public key and the UTXOs state of `address`, removing the ones which aren't managed anymore.
+    fn write_address(
+        &mut self,
+        address: &Address,
+        ecdsa_pub_key: Option<&EcdsaPubKey>,
+        utxos_state: Option<&UtxosState>,
+    ) {
+        let address_key = AddressKey::new(address);
+        let address_using_primitives = get_address_using_primitives(address);
+        match ecdsa_pub_key {
+            Some(ecdsa_pub_key) => self.ecdsa_pub_key_addresses.insert(
+                address_key,
+                StableValue((address_using_primitives.clone(), ecdsa_pub_key.clone())),
+            ),
+            None => self.ecdsa_pub_key_addresses.remove(&address_key),
+        };
+        match utxos_state {
+            Some(utxos_state) => self.utxos_state_addresses.insert(
+                address_key,
+                StableValue((address_using_primitives, utxos_state.clone())),
+            ),
+            None => self.utxos_state_addresses.remove(&address_key),
+        };
+    }
+
+    /// Replaces every entry of the stable structures with the ones of the given Bitcoin agent.
+    fn write_all<C: ManagementCanister>(&mut self, bitcoin_agent: &BitcoinAgent<C>) {
+        let address_keys: Vec<AddressKey> = self
+            .ecdsa_pub_key_addresses
+            .keys()
+            .chain(self.utxos_state_addresses.keys())
+            .collect();
+        for address_key in address_keys {
+            self.ecdsa_pub_key_addresses.remove(&address_key);
+            self.utxos_state_addresses.remove(&address_key);
+        }
+        for address in bitcoin_agent
+            .ecdsa_pub_key_addresses
+            .keys()
+            .chain(bitcoin_agent.utxos_state_addresses.keys())
+        {
+            self.write_address(
+                address,
+                bitcoin_agent.ecdsa_pub_key_addresses.get(address),
+                bitcoin_agent.utxos_state_addresses.get(address),
+            );
+        }
+        self.write_state(get_state_without_addresses(bitcoin_agent));
+    }
+}
+
+/// Returns the Bitcoin agent associated with `bitcoin_agent_state` written through to the stable memory, e.g. to migrate a Bitcoin agent saved in `pre_upgrade` once.
+pub(crate) fn import_state<C: ManagementCanisterFactory>(
+    memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    bitcoin_agent_state: BitcoinAgentState,
+    expected_network: crate::Network,
+) -> Result<BitcoinAgent<C>, StateRestoreError> {
+    let mut bitcoin_agent = upgrade_management::from_state(
+        bitcoin_agent_state,
+        StateRestoreOptions::new(expected_network),
+    )?;
+    attach_stable_storage(memory_manager, &mut bitcoin_agent);
+    Ok(bitcoin_agent)
+}
+
+/// Returns the Bitcoin agent held by the stable memory, e.g. in `post_upgrade`.
+pub(crate) fn from_stable<C: ManagementCanisterFactory>(
+    memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    expected_network: crate::Network,
+) -> Result<BitcoinAgent<C>, StateRestoreError> {
+    let stable_storage = StableStorage::open(memory_manager);
+    let bitcoin_agent_state = stable_storage
+        .read()
+        .ok_or(StateRestoreError::NoStableState)?;
+    let mut bitcoin_agent = upgrade_management::from_state(
+        bitcoin_agent_state,
+        StateRestoreOptions::new(expected_network),
+    )?;
+    bitcoin_agent.stable_storage = Some(Rc::new(RefCell::new(stable_storage)));
+    Ok(bitcoin_agent)
+}
+
+/// Writes the whole Bitcoin agent to the stable memory, overwriting any state held by the `STABLE_MEMORY_IDS` virtual memories, and then writes the Bitcoin agent through to it.
+pub(crate) fn attach_stable_storage<C: ManagementCanister>(
+    memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    bitcoin_agent: &mut BitcoinAgent<C>,
+) {
+    let mut stable_storage = StableStorage::open(memory_manager);
+    stable_storage.write_all(bitcoin_agent);
+    bitcoin_agent.stable_storage = Some(Rc::new(RefCell::new(stable_storage)));
+}
+
+/// Writes the ECDSA public key and the UTXOs state of `address` through to the stable memory if the Bitcoin agent is stable.
+pub(crate) fn write_address<C: ManagementCanister>(
+    bitcoin_agent: &BitcoinAgent<C>,
+    address: &Address,
+) {
+    if let Some(stable_storage) = &bitcoin_agent.stable_storage {
+        stable_storage.borrow_mut().write_address(
+            address,
+            bitcoin_agent.ecdsa_pub_key_addresses.get(address),
+            bitcoin_agent.utxos_state_addresses.get(address),
+        );
+    }
+}
+
+/// Writes the Bitcoin agent state without its managed addresses through to the stable memory if the Bitcoin agent is stable.
+pub(crate) fn write_state<C: ManagementCanister>(bitcoin_agent: &BitcoinAgent<C>) {
+    if let Some(stable_storage) = &bitcoin_agent.stable_storage {
+        stable_storage
+            .borrow_mut()
+            .write_state(get_state_without_addresses(bitcoin_agent));
+    }
+}
+
+/// Writes the whole Bitcoin agent through to the stable memory if the Bitcoin agent is stable.
+pub(crate) fn write_all<C: ManagementCanister>(bitcoin_agent: &BitcoinAgent<C>) {
+    if let Some(stable_storage) = &bitcoin_agent.stable_storage {
+        stable_storage.borrow_mut().write_all(bitcoin_agent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address_management::tests::{
+            get_btc_ecdsa_public_key, get_btc_ecdsa_public_key_from_public_key,
+        },
+        agent,
+        canister_mock::{get_init_utxos, ManagementCanisterMock},
+        AddressType, FeeSmoothing, Network, UtxosResult,
+    };
+    use bitcoin::{secp256k1::Secp256k1, PrivateKey};
+
+    /// Returns a new stable Bitcoin agent using the management canister mock.
+    fn new_stable_mock(
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    ) -> BitcoinAgent<ManagementCanisterMock> {
+        let ecdsa_public_key = get_btc_ecdsa_public_key();
+        let mut bitcoin_agent = BitcoinAgent::new_stable(
+            memory_manager,
+            ManagementCanisterMock::new_using_ecdsa_public_key_test(
+                Network::Regtest,
+                ecdsa_public_key.clone(),
+                AddressType::P2pkh,
+            ),
+            &AddressType::P2pkh,
+            0,
+        )
+        .unwrap();
+        bitcoin_agent.initialize(ecdsa_public_key);
+        bitcoin_agent
+    }
+
+    /// Check that the mutations of a stable Bitcoin agent are restored after simulating an upgrade by dropping and re-opening the stable structures.
+    #[test]
+    fn check_stable_upgrade() {
+        let memory = DefaultMemoryImpl::default();
+        let mut bitcoin_agent = new_stable_mock(&MemoryManager::init(memory.clone()));
+
+        let main_address = bitcoin_agent.get_main_address();
+        let added_address = bitcoin_agent.add_address(&[vec![1]]).unwrap();
+        let removed_address = bitcoin_agent.add_address(&[vec![2]]).unwrap();
+        assert!(bitcoin_agent.remove_address(&removed_address));
+        bitcoin_agent.apply_utxos(UtxosResult {
+            address: main_address.clone(),
+            utxos: get_init_utxos(),
+            tip_height: 10,
+        });
+        bitcoin_agent.update_state(&main_address).unwrap();
+        bitcoin_agent.queue_payout(&added_address, 10_000);
+        bitcoin_agent.set_fee_smoothing(Some(FeeSmoothing { window: 3 }));
+        bitcoin_agent.set_max_fee_per_byte(Some(100_000));
+        let state = bitcoin_agent.get_state();
+        drop(bitcoin_agent);
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_stable(&MemoryManager::init(memory.clone()), Network::Regtest)
+                .unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert_eq!(restored_bitcoin_agent.managed_address_count(), 2);
+        assert_eq!(restored_bitcoin_agent.queued_total(), 10_000);
+
+        // The restored Bitcoin agent keeps writing through to the stable memory, including when all of its addresses are rewritten.
+        let mut bitcoin_agent = restored_bitcoin_agent;
+        bitcoin_agent.apply_rekey(get_btc_ecdsa_public_key_from_public_key(
+            &PrivateKey::from_slice(&[3; 32], bitcoin::Network::Regtest)
+                .unwrap()
+                .public_key(&Secp256k1::new()),
+        ));
+        bitcoin_agent.add_address(&[vec![3]]).unwrap();
+        let state = bitcoin_agent.get_state();
+        drop(bitcoin_agent);
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_stable(&MemoryManager::init(memory), Network::Regtest).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert_eq!(restored_bitcoin_agent.get_key_rotations().len(), 1);
+    }
+
+    /// Check that a heap Bitcoin agent state is imported into the stable memory once and restored after an upgrade.
+    #[test]
+    fn check_stable_import() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.add_address(&[vec![1]]).unwrap();
+        bitcoin_agent.queue_payout(&bitcoin_agent.get_main_address(), 10_000);
+        let state = bitcoin_agent.get_state();
+
+        let memory = DefaultMemoryImpl::default();
+        let imported_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::import_state(
+                &MemoryManager::init(memory.clone()),
+                state.clone(),
+                Network::Regtest,
+            )
+            .unwrap();
+        assert_eq!(imported_bitcoin_agent.get_state(), state);
+        drop(imported_bitcoin_agent);
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_stable(&MemoryManager::init(memory), Network::Regtest).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+
+        // An empty stable memory doesn't hold any Bitcoin agent.
+        assert_eq!(
+            BitcoinAgent::<ManagementCanisterMock>::from_stable(
+                &MemoryManager::init(DefaultMemoryImpl::default()),
+                Network::Regtest
+            )
+            .err()
+            .unwrap(),
+            StateRestoreError::NoStableState
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+/// Provides the current time in nanoseconds since the UNIX epoch, e.g. to timestamp the transactions or to expire the cached fees.
+/// The time source of a Bitcoin agent defaults to the one of its management canister, is set with `BitcoinAgent::set_time_source` and is never saved in the state.
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+impl fmt::Debug for dyn TimeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TimeSource")
+    }
+}
+
+/// Returns the time of the IC with `ic_cdk::api::time`, the default time source.
+/// As `ic_cdk::api::time` can only be called from a canister, the system time is returned instead in the tests of the library.
+pub struct IcTimeSource;
+
+impl TimeSource for IcTimeSource {
+    fn now(&self) -> u64 {
+        if cfg!(test) {
+            use std::time::{SystemTime, UNIX_EPOCH};
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        } else {
+            ic_cdk::api::time()
+        }
+    }
+}
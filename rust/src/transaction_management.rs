@@ -1,32 +1,53 @@
+#[cfg(test)]
+use crate::CurrentFeeArgs;
 use crate::{
-    canister_common::{
-        GET_CURRENT_FEE_PERCENTILES_COST_CYCLES, SEND_TRANSACTION_BASE_COST_CYCLES,
-        SEND_TRANSACTION_COST_CYCLES_PER_BYTE,
+    canister_common::{call_with_payment, get_cycles_spent, SIGN_13_NODE_COST_CYCLES},
+    fee_math::{fee_for_vsize, is_relayable_fee, scale_rate},
+    logging::{
+        format_derivation_path, LogLevel, LogSink, NoopLogSink, LOG_TARGET_BROADCAST,
+        LOG_TARGET_FEE, LOG_TARGET_SIGN, LOG_TARGET_UTXO_SELECTION,
     },
-    ecdsa::sign_with_ecdsa,
     types::{
-        from_bitcoin_network_to_ic_btc_types_network, from_types_network_to_bitcoin_network,
-        BuiltTransaction,
+        from_bitcoin_network_to_management_canister_network, from_types_network_to_bitcoin_network,
+        get_target_blocks_percentile, BuiltTransaction,
     },
     upgrade_management::get_address_using_primitives,
-    utxo_management::{get_utxos, has_utxo_min_confirmations},
-    AddressUsingPrimitives, EcdsaPubKey, Fee, FeeRequest, GetCurrentFeeError,
-    ManagementCanisterReject, MillisatoshiPerByte, MultiTransferArgs, MultiTransferError,
-    MultiTransferResult, Satoshi, TransactionInfo, Utxo, MIN_CONFIRMATIONS_UPPER_BOUND,
+    utxo_management::{has_utxo_min_confirmations, outpoint_to_explorer_string},
+    AddressUsingPrimitives, BitcoinApiTarget, CallPreview, CyclesCostConfig, DerivationPath,
+    EcdsaPubKey, Fee, FeeRequest, FeeSuggestions, FeeSuggestionsArgs, GetCurrentFeeError,
+    InputSigningFailure, ManagementCanisterMethod, ManagementCanisterReject, MillisatoshiPerByte,
+    MultiTransferArgs, MultiTransferError, MultiTransferResult, RetryConfig, Satoshi, ScriptPayout,
+    SelectionScope, TransactionInfo, Utxo, UtxosState, WithCost, DEFAULT_RETRY_CONFIG,
+    DEFAULT_TARGET_BLOCKS_PERCENTILES, MIN_CONFIRMATIONS_UPPER_BOUND,
 };
-#[cfg(test)]
-use crate::{canister_mock::ManagementCanisterMock, BitcoinAgent};
+use crate::{
+    ecdsa::sign_with_ecdsa, schnorr::sign_with_schnorr, utxo_management::get_utxos, GetUtxosError,
+};
+use async_trait::async_trait;
 use bitcoin::{
-    blockdata::script::Builder, hashes::Hash, psbt::serialize::Serialize, Address, AddressType,
-    EcdsaSighashType, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid, Witness,
+    absolute::LockTime,
+    blockdata::script::{Builder, PushBytes},
+    consensus::serialize,
+    hashes::Hash,
+    secp256k1::ecdsa::Signature,
+    sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType},
+    Address, AddressType, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Txid, Witness,
+};
+use candid::Principal;
+use futures::stream::{self, StreamExt};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::bitcoin::{
+    GetCurrentFeePercentilesRequest, SendTransactionRequest,
 };
-use ic_btc_types::{GetCurrentFeePercentilesRequest, SendTransactionRequest};
-use ic_cdk::{api::call::call_with_payment, export::Principal};
 use std::{collections::BTreeMap, future::Future};
 
 // The signature hash type that is always used.
 const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
 
+// The signature hash type that is always used for Taproot key path spends, committing to the whole transaction like `SIG_HASH_TYPE`.
+const SCHNORR_SIG_HASH_TYPE: TapSighashType = TapSighashType::Default;
+
 // Dust is the amount below which spending the `TxOut` would cost more in fee than the amount of the `TxOut`.
 // Here we calculate the dust threshold by calculating the minimum number of bytes to spend an additional `TxOut`.
 //
@@ -56,70 +77,144 @@ const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
 // This calculation is done assuming that we add this dust `TxOut` and redeem `TxIn` in already existing transaction (so we don't have to count number of bytes of other transaction fields).
 const DUST_THRESHOLD: Satoshi = 546;
 
-/// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions.
+// The maximum size of a script in bytes, any output with a larger script is unspendable.
+// (source: https://github.com/bitcoin/bitcoin/blob/26ec2f2d6bb12525044b6d09422b42715fc09319/src/script/script.h)
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+// The maximum size in bytes of an `OP_RETURN` script relayed by default.
+// (source: https://github.com/bitcoin/bitcoin/blob/26ec2f2d6bb12525044b6d09422b42715fc09319/src/script/standard.h)
+const MAX_OP_RETURN_RELAY: usize = 83;
+
+/// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions and the cycles spent to retrieve them from `bitcoin_api_target`.
 pub(crate) async fn get_current_fees(
     network: Network,
-) -> Result<Vec<MillisatoshiPerByte>, ManagementCanisterReject> {
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: &BitcoinApiTarget,
+) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject> {
     let res: Result<(Vec<MillisatoshiPerByte>,), _> = call_with_payment(
-        Principal::management_canister(),
-        "bitcoin_get_current_fee_percentiles",
+        bitcoin_api_target.get_principal(),
+        ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
         (GetCurrentFeePercentilesRequest {
-            network: from_bitcoin_network_to_ic_btc_types_network(network),
+            network: from_bitcoin_network_to_management_canister_network(network)?,
         },),
-        GET_CURRENT_FEE_PERCENTILES_COST_CYCLES,
+        cycles_cost_config.get_fees,
     )
     .await;
 
     match res {
         // Return the fees to the caller.
-        Ok(data) => Ok(data.0),
+        Ok(data) => Ok(WithCost {
+            result: data.0,
+            cycles_spent: get_cycles_spent(
+                ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+                cycles_cost_config.get_fees,
+            ),
+            attempts: 1,
+        }),
 
         // The call to `get_current_fees` was rejected for a given reason (e.g., not enough cycles were attached to the call).
         Err((rejection_code, message)) => Err(ManagementCanisterReject(rejection_code, message)),
     }
 }
 
-/// Returns the percentile associated with the given `FeeRequest`.
-pub(crate) fn evaluate_fee_request(fee_request: FeeRequest) -> Result<usize, GetCurrentFeeError> {
+/// Returns the position of the percentile associated with the given `FeeRequest` in a fee percentiles vector of length `fees_len`, in hundredths of an index.
+/// The 0th percentile is the first fee and the 100th percentile is the last fee whatever the length of the vector.
+/// A percentile exceeding the 100th percentile is an error unless `clamp_percentile` is true, in which case the 100th percentile is used.
+pub(crate) fn evaluate_fee_request(
+    fee_request: FeeRequest,
+    fees_len: usize,
+    clamp_percentile: bool,
+) -> Result<u64, GetCurrentFeeError> {
+    // The management canister doesn't have any transaction to compute current fees, e.g. right after genesis.
+    if fees_len == 0 {
+        return Err(GetCurrentFeeError::NoFeeData);
+    }
     let percentile = match fee_request {
         FeeRequest::Slow => 25,
-        FeeRequest::Standard => 50,
+        FeeRequest::Standard | FeeRequest::Median => 50,
         FeeRequest::Fast => 75,
         FeeRequest::Percentile(percentile) => percentile,
-    } as usize;
-    if percentile >= 99 {
-        return Err(GetCurrentFeeError::InvalidPercentile);
+        FeeRequest::TargetBlocks(target_blocks) => get_target_blocks_percentile(
+            target_blocks,
+            &BTreeMap::from(DEFAULT_TARGET_BLOCKS_PERCENTILES),
+        )
+        .unwrap(),
+    };
+    let percentile = match percentile {
+        0..=100 => percentile,
+        _ if clamp_percentile => 100,
+        _ => {
+            return Err(GetCurrentFeeError::InvalidPercentile {
+                requested: percentile,
+                available: fees_len as u32,
+            })
+        }
+    } as u64;
+    Ok(percentile * (fees_len as u64 - 1))
+}
+
+/// Returns the fee at the given position of `fees`, in hundredths of an index.
+/// Between two fees, the fee is linearly interpolated and rounded half up to a whole number of millisatoshis/byte.
+fn interpolate_fee(fees: &[MillisatoshiPerByte], position: u64) -> MillisatoshiPerByte {
+    let index = (position / 100) as usize;
+    let hundredths = (position % 100) as i128;
+    if hundredths == 0 {
+        return fees[index];
     }
-    Ok(percentile)
+    let lower_fee = fees[index] as i128;
+    let upper_fee = fees[index + 1] as i128;
+    ((lower_fee * 100 + (upper_fee - lower_fee) * hundredths + 50) / 100) as MillisatoshiPerByte
 }
 
-/// Returns the fee as a percentile in millisatoshis/byte over the last 10,000 transactions.
-pub(crate) async fn get_current_fee(
+/// Returns the fee in millisatoshis/byte associated with the given `FeeRequest` among the given fee percentiles.
+pub(crate) fn get_fee_from_percentiles(
     fee_request: FeeRequest,
-    network: Network,
+    fees: &[MillisatoshiPerByte],
+    clamp_percentile: bool,
 ) -> Result<MillisatoshiPerByte, GetCurrentFeeError> {
-    let percentile = evaluate_fee_request(fee_request)?;
-    let fees = get_current_fees(network).await?;
-    // A given percentile between 0 and 99 is invalid if the management canister doesn't have enough transactions to compute current fees.
-    if percentile > fees.len() {
-        return Err(GetCurrentFeeError::InvalidPercentile);
-    }
-    Ok(fees[percentile])
+    let position = evaluate_fee_request(fee_request, fees.len(), clamp_percentile)?;
+    Ok(interpolate_fee(fees, position))
+}
+
+/// Returns the fee suggestions associated with the percentiles of `fee_suggestions_args` among the given fee percentiles.
+/// A suggestion lower than the previous one is raised to it, hence the suggestions are non-decreasing.
+pub(crate) fn get_fee_suggestions_from_percentiles(
+    fee_suggestions_args: &FeeSuggestionsArgs,
+    fees: &[MillisatoshiPerByte],
+) -> Result<FeeSuggestions, GetCurrentFeeError> {
+    let get_fee =
+        |percentile| get_fee_from_percentiles(FeeRequest::Percentile(percentile), fees, false);
+    let economical = get_fee(fee_suggestions_args.economical_percentile)?;
+    let standard = get_fee(fee_suggestions_args.standard_percentile)?.max(economical);
+    let priority = get_fee(fee_suggestions_args.priority_percentile)?.max(standard);
+    Ok(FeeSuggestions {
+        economical,
+        standard,
+        priority,
+    })
+}
+
+/// Returns the median of the given fees in millisatoshis/byte, `None` if there isn't any fee.
+/// For an even number of fees, the median is the average of the two middle fees rounded half up.
+pub fn median_fee(fees: &[MillisatoshiPerByte]) -> Option<MillisatoshiPerByte> {
+    get_fee_from_percentiles(FeeRequest::Median, fees, false).ok()
 }
 
-/// Sends the given transaction to the network the management canister interacts with.
+/// Sends the given transaction to the network `bitcoin_api_target` interacts with and returns the cycles spent to send it.
 pub(crate) async fn send_transaction(
     transaction: Vec<u8>,
     network: Network,
-) -> Result<(), ManagementCanisterReject> {
-    let transaction_cost_cycles = SEND_TRANSACTION_BASE_COST_CYCLES
-        + (transaction.len() as u64) * SEND_TRANSACTION_COST_CYCLES_PER_BYTE;
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: &BitcoinApiTarget,
+) -> Result<WithCost<()>, ManagementCanisterReject> {
+    let transaction_cost_cycles =
+        cycles_cost_config.get_send_transaction_cost_cycles(transaction.len());
     let res: Result<(), _> = call_with_payment(
-        Principal::management_canister(),
-        "bitcoin_send_transaction",
+        bitcoin_api_target.get_principal(),
+        ManagementCanisterMethod::BitcoinSendTransaction,
         (SendTransactionRequest {
             transaction,
-            network: from_bitcoin_network_to_ic_btc_types_network(network),
+            network: from_bitcoin_network_to_management_canister_network(network)?,
         },),
         transaction_cost_cycles,
     )
@@ -127,7 +222,14 @@ pub(crate) async fn send_transaction(
 
     match res {
         // Return to the caller.
-        Ok(()) => Ok(()),
+        Ok(()) => Ok(WithCost {
+            result: (),
+            cycles_spent: get_cycles_spent(
+                ManagementCanisterMethod::BitcoinSendTransaction,
+                transaction_cost_cycles,
+            ),
+            attempts: 1,
+        }),
 
         // The call to `send_transaction` was rejected for a given reason (e.g., not enough cycles were attached to the call).
         Err((rejection_code, message)) => Err(ManagementCanisterReject(rejection_code, message)),
@@ -140,51 +242,112 @@ pub(crate) async fn send_transaction(
 /// Note that `min_confirmations` = 0 implies that unconfirmed outputs may be used to create a transaction.
 /// Further note that the set of UTXO is restricted to those in the updated state: If new UTXOs are discovered when calling `peek_utxos_update` (or `peek_balance_update`), these UTXOs will not be spent in any transaction until they are made available by calling `update_state`.
 /// On the other hand, the library is free to choose UTXOs of any managed address when constructing transactions.
+/// The UTXOs are selected within `max_inputs` and `max_tx_weight`, the largest ones being preferred if the first ones exceed these limits.
+/// The payouts below `DUST_THRESHOLD` are rejected with `MultiTransferError::DustOutput`, whereas a change below it is left to the fee.
+/// A fee above `max_fee` is rejected with `MultiTransferError::FeeTooHigh`.
+/// The calls to the management canister are made through `transfer_calls`.
 pub(crate) async fn multi_transfer(
     multi_transfer_args: MultiTransferArgs,
-    #[cfg(test)] bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
+    transfer_calls: &impl TransferCalls,
 ) -> Result<MultiTransferResult, MultiTransferError> {
     if multi_transfer_args.min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
         return Err(MultiTransferError::MinConfirmationsTooHigh);
     }
+    validate_outputs(&multi_transfer_args)?;
     // Retrieves Bitcoin blockchain tip height.
-    #[cfg(test)]
-    let tip_height = get_tip_height(&multi_transfer_args, bitcoin_agent).await;
-    #[cfg(not(test))]
-    let tip_height = get_tip_height(&multi_transfer_args).await;
+    let tip_height = transfer_calls.get_tip_height(&multi_transfer_args).await?;
+    let mut cycles_spent = tip_height.cycles_spent;
+    let tip_height = tip_height.result;
 
     let utxos_addresses = get_utxos_addresses(&multi_transfer_args, tip_height);
 
-    let built_transaction = get_built_transaction(&multi_transfer_args, &utxos_addresses).await?;
+    let candidate_utxos = get_candidate_utxos(&multi_transfer_args, &utxos_addresses)?;
+
+    let built_transaction =
+        get_built_transaction(&multi_transfer_args, &candidate_utxos, transfer_calls).await?;
+    cycles_spent += built_transaction.cycles_spent;
+    let built_transaction = built_transaction.result;
+    let log_sink = multi_transfer_args.log_sink.as_ref();
+    log_sink.log(
+        LogLevel::Debug,
+        LOG_TARGET_UTXO_SELECTION,
+        &format!(
+            "Selected {} UTXOs out of {} candidates.",
+            built_transaction.spending_addresses.len(),
+            candidate_utxos.len()
+        ),
+    );
+    log_sink.log(
+        LogLevel::Info,
+        LOG_TARGET_FEE,
+        &format!(
+            "Computed a fee of {} satoshis for a signed transaction of {} bytes.",
+            built_transaction.fee, built_transaction.mock_signed_transaction_size
+        ),
+    );
 
-    if built_transaction.fee < built_transaction.mock_signed_transaction_size as u64 {
+    if !is_relayable_fee(
+        built_transaction.fee,
+        built_transaction.mock_signed_transaction_size,
+    ) {
         return Err(MultiTransferError::FeeTooLow);
     }
+    validate_fee(&multi_transfer_args, built_transaction.fee)?;
 
-    #[cfg(test)]
-    let sign_fun = mock_signer;
-    #[cfg(not(test))]
-    let sign_fun = sign_with_ecdsa;
+    let cycles_cost_config = multi_transfer_args.cycles_cost_config;
+    let sign_fun = |key_name, derivation_path, message_hash| {
+        transfer_calls.sign_with_ecdsa(key_name, derivation_path, message_hash, cycles_cost_config)
+    };
+    let schnorr_sign_fun = |key_name, derivation_path, message| {
+        transfer_calls.sign_with_schnorr(key_name, derivation_path, message, cycles_cost_config)
+    };
 
     // Sign the transaction.
-    let signed_transaction = sign_transaction(
+    let (signed_transaction, sign_attempts, sign_cycles_spent) = sign_transaction(
         multi_transfer_args.key_name.clone(),
-        &get_spending_addresses(&built_transaction),
-        &built_transaction.spending_ecdsa_pub_keys,
-        built_transaction.transaction,
+        &built_transaction,
         sign_fun,
+        schnorr_sign_fun,
+        &multi_transfer_args.sign_retry_config,
+        multi_transfer_args.sign_concurrency,
+        log_sink,
     )
     .await?;
+    cycles_spent += sign_cycles_spent;
 
     // Send the transaction to the Bitcoin network.
-    let signed_transaction_bytes = signed_transaction.serialize();
-    let network = from_types_network_to_bitcoin_network(multi_transfer_args.network);
-    #[cfg(test)]
-    bitcoin_agent
-        .management_canister
-        .internal_send_transaction(signed_transaction_bytes, network);
-    #[cfg(not(test))]
-    send_transaction(signed_transaction_bytes, network).await?;
+    let txid = signed_transaction.txid();
+    log_sink.log(
+        LogLevel::Info,
+        LOG_TARGET_BROADCAST,
+        &format!("Broadcasting the transaction {}.", txid),
+    );
+    let send_result = transfer_calls
+        .send_transaction(serialize(&signed_transaction), &multi_transfer_args)
+        .await;
+    match &send_result {
+        Ok(_) => log_sink.log(
+            LogLevel::Info,
+            LOG_TARGET_BROADCAST,
+            &format!("Broadcasted the transaction {}.", txid),
+        ),
+        Err(ManagementCanisterReject(rejection_code, message)) => log_sink.log(
+            LogLevel::Warn,
+            LOG_TARGET_BROADCAST,
+            &format!(
+                "The broadcast of the transaction {} was rejected: {:?} {}.",
+                txid, rejection_code, message
+            ),
+        ),
+    }
+    cycles_spent += send_result
+        .map_err(|reject| {
+            MultiTransferError::from_reject(
+                ManagementCanisterMethod::BitcoinSendTransaction,
+                reject,
+            )
+        })?
+        .cycles_spent;
 
     let spending_utxos_addresses = built_transaction
         .spending_utxos_addresses
@@ -192,13 +355,12 @@ pub(crate) async fn multi_transfer(
         .map(|(address, uxtos)| (get_address_using_primitives(&address), uxtos))
         .collect();
 
-    let txid = signed_transaction.txid();
     let transaction_info = TransactionInfo {
-        id: txid.to_string(),
+        id: txid.into(),
         utxos_addresses: spending_utxos_addresses,
         fee: built_transaction.fee,
         size: signed_transaction.size() as u32,
-        timestamp: time(),
+        timestamp: multi_transfer_args.time_source.now(),
     };
 
     let generated_utxos_addresses =
@@ -208,29 +370,209 @@ pub(crate) async fn multi_transfer(
         transaction_info,
         generated_utxos_addresses,
         height: tip_height,
+        script_payout_vouts: get_script_payout_vouts(&multi_transfer_args),
+        queued_payout_ids: multi_transfer_args.queued_payout_ids,
+        sign_attempts,
+        cycles_spent,
     })
 }
 
-/// Returns the Bitcoin blockchain tip height.
-async fn get_tip_height(
+/// The calls to the management canister made by `multi_transfer`, e.g. simulated by `ManagementCanisterMock` in tests.
+#[async_trait]
+pub(crate) trait TransferCalls: Sync {
+    /// Returns the Bitcoin blockchain tip height and the cycles spent to retrieve it.
+    /// Fails with the rejection of the call to `bitcoin_get_utxos` made to retrieve it.
+    async fn get_tip_height(
+        &self,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<u32>, MultiTransferError>;
+
+    /// Returns fees as percentiles in millisatoshis/byte over the last 10,000 transactions.
+    async fn get_current_fees(
+        &self,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject>;
+
+    /// Returns the DER signature of `message_hash` with the ECDSA key named `key_name` at `derivation_path`.
+    async fn sign_with_ecdsa(
+        &self,
+        key_name: String,
+        derivation_path: DerivationPath,
+        message_hash: Vec<u8>,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject>;
+
+    /// Returns the BIP-340 signature of `message` with the Schnorr key named `key_name` at `derivation_path`.
+    async fn sign_with_schnorr(
+        &self,
+        key_name: String,
+        derivation_path: DerivationPath,
+        message: Vec<u8>,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject>;
+
+    /// Sends the signed `transaction` to the network of `multi_transfer_args`.
+    async fn send_transaction(
+        &self,
+        transaction: Vec<u8>,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<()>, ManagementCanisterReject>;
+}
+
+/// The calls to the management canister made by `multi_transfer_from_args`, served by the IC.
+pub(crate) struct IcTransferCalls;
+
+#[async_trait]
+impl TransferCalls for IcTransferCalls {
+    async fn get_tip_height(
+        &self,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<u32>, MultiTransferError> {
+        get_utxos(
+            from_types_network_to_bitcoin_network(multi_transfer_args.network),
+            &multi_transfer_args.change_address,
+            0,
+            multi_transfer_args.cycles_cost_config,
+            &multi_transfer_args.bitcoin_api_target,
+        )
+        .await
+        .map(|get_utxos_response| {
+            get_utxos_response.map(|get_utxos_response| get_utxos_response.tip_height)
+        })
+        .map_err(get_tip_height_error)
+    }
+
+    async fn get_current_fees(
+        &self,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<Vec<MillisatoshiPerByte>>, ManagementCanisterReject> {
+        get_current_fees(
+            from_types_network_to_bitcoin_network(multi_transfer_args.network),
+            multi_transfer_args.cycles_cost_config,
+            &multi_transfer_args.bitcoin_api_target,
+        )
+        .await
+    }
+
+    async fn sign_with_ecdsa(
+        &self,
+        key_name: String,
+        derivation_path: DerivationPath,
+        message_hash: Vec<u8>,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
+        sign_with_ecdsa(key_name, derivation_path, message_hash, cycles_cost_config).await
+    }
+
+    async fn sign_with_schnorr(
+        &self,
+        key_name: String,
+        derivation_path: DerivationPath,
+        message: Vec<u8>,
+        cycles_cost_config: CyclesCostConfig,
+    ) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
+        sign_with_schnorr(key_name, derivation_path, message, cycles_cost_config).await
+    }
+
+    async fn send_transaction(
+        &self,
+        transaction: Vec<u8>,
+        multi_transfer_args: &MultiTransferArgs,
+    ) -> Result<WithCost<()>, ManagementCanisterReject> {
+        send_transaction(
+            transaction,
+            from_types_network_to_bitcoin_network(multi_transfer_args.network),
+            multi_transfer_args.cycles_cost_config,
+            &multi_transfer_args.bitcoin_api_target,
+        )
+        .await
+    }
+}
+
+/// Returns the error of `multi_transfer` associated with the failed retrieval of the UTXOs of the change address made to get the tip height.
+fn get_tip_height_error(get_utxos_error: GetUtxosError) -> MultiTransferError {
+    let reject = match get_utxos_error {
+        GetUtxosError::ManagementCanisterReject(rejection_code, message) => {
+            ManagementCanisterReject(rejection_code, message)
+        }
+        get_utxos_error => {
+            ManagementCanisterReject(RejectionCode::CanisterError, get_utxos_error.to_string())
+        }
+    };
+    MultiTransferError::from_reject(ManagementCanisterMethod::BitcoinGetUtxos, reject)
+}
+
+/// Returns the UTXOs of `utxos_state` that weren't previously spent in a transaction.
+fn get_unspent_utxos(utxos_state: &UtxosState) -> Vec<Utxo> {
+    utxos_state
+        .seen_state
+        .iter()
+        .filter(|utxo| !utxos_state.spent_state.contains(&utxo.outpoint))
+        .cloned()
+        .collect()
+}
+
+/// Returns the UTXOs of the managed addresses that weren't previously spent in a transaction.
+fn get_unspent_utxos_addresses(
     multi_transfer_args: &MultiTransferArgs,
-    #[cfg(test)] bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
-) -> u32 {
-    #[cfg(test)]
-    let tip_height = bitcoin_agent
-        .management_canister
-        .internal_get_utxos(&multi_transfer_args.change_address, 0)
-        .tip_height;
-    #[cfg(not(test))]
-    let tip_height = get_utxos(
-        from_types_network_to_bitcoin_network(multi_transfer_args.network),
-        &multi_transfer_args.change_address,
-        0,
+) -> BTreeMap<Address, Vec<Utxo>> {
+    multi_transfer_args
+        .utxos_state_addresses
+        .iter()
+        .filter(|(address, _)| {
+            is_spendable_address(address, &multi_transfer_args.ecdsa_pub_key_addresses)
+        })
+        .map(|(address, utxos_state)| (address.clone(), get_unspent_utxos(utxos_state)))
+        .collect()
+}
+
+/// Returns whether the UTXOs of `address` can be spent in a transaction.
+fn is_spendable_address(
+    address: &Address,
+    ecdsa_pub_key_addresses: &BTreeMap<Address, EcdsaPubKey>,
+) -> bool {
+    // Watch-only addresses can't be spent as they aren't associated with an ECDSA public key.
+    ecdsa_pub_key_addresses.contains_key(address)
+        // Filter our addresses to only keep the P2PKH and P2TR ones.
+        && matches!(
+            address.address_type(),
+            Some(AddressType::P2pkh | AddressType::P2tr)
+        )
+}
+
+/// Returns the ECDSA public keys and the UTXOs states of the managed addresses having unspent UTXOs that can be spent in a transaction.
+/// The returned UTXOs states only keep the unspent UTXOs, so that the arguments of a transfer don't carry the whole state of the Bitcoin agent.
+pub(crate) fn get_spendable_addresses(
+    ecdsa_pub_key_addresses: &BTreeMap<Address, EcdsaPubKey>,
+    utxos_state_addresses: &BTreeMap<Address, UtxosState>,
+) -> (
+    BTreeMap<Address, EcdsaPubKey>,
+    BTreeMap<Address, UtxosState>,
+) {
+    let spendable_utxos_state_addresses: BTreeMap<Address, UtxosState> = utxos_state_addresses
+        .iter()
+        .filter(|(address, _)| is_spendable_address(address, ecdsa_pub_key_addresses))
+        .filter_map(|(address, utxos_state)| {
+            let unspent_utxos = get_unspent_utxos(utxos_state);
+            (!unspent_utxos.is_empty()).then(|| {
+                (
+                    address.clone(),
+                    UtxosState {
+                        seen_state: unspent_utxos,
+                        ..UtxosState::new(utxos_state.min_confirmations)
+                    },
+                )
+            })
+        })
+        .collect();
+    let spendable_ecdsa_pub_key_addresses = spendable_utxos_state_addresses
+        .keys()
+        .map(|address| (address.clone(), ecdsa_pub_key_addresses[address].clone()))
+        .collect();
+    (
+        spendable_ecdsa_pub_key_addresses,
+        spendable_utxos_state_addresses,
     )
-    .await
-    .unwrap()
-    .tip_height;
-    tip_height
 }
 
 /// Returns the UTXOs associated with their addresses that may be used to build the transaction.
@@ -238,62 +580,248 @@ fn get_utxos_addresses(
     multi_transfer_args: &MultiTransferArgs,
     tip_height: u32,
 ) -> BTreeMap<Address, Vec<Utxo>> {
-    let mut utxos_addresses: BTreeMap<Address, Vec<Utxo>> = multi_transfer_args
-        .utxos_state_addresses
-        .iter()
-        .map(|(address, utxos_state)| (address.clone(), utxos_state.seen_state.clone()))
-        .collect();
-
-    utxos_addresses.retain(|address, utxos| {
-        // Filter UTXOs, keeping those with enough confirmations and that weren't previously spent in a transaction.
-        let spent_txos_address = &multi_transfer_args.utxos_state_addresses[address].spent_state;
+    let mut utxos_addresses = get_unspent_utxos_addresses(multi_transfer_args);
+    // Filter UTXOs, keeping those with enough confirmations.
+    utxos_addresses.values_mut().for_each(|utxos| {
         utxos.retain(|utxo| {
             has_utxo_min_confirmations(utxo, tip_height, multi_transfer_args.min_confirmations)
-                && !spent_txos_address.contains(&utxo.outpoint)
-        });
-        // Filter our addresses to only keep the P2PKH ones.
-        address.address_type() == Some(AddressType::P2pkh)
+        })
     });
     utxos_addresses
 }
 
-/// Returns the final unsigned transaction.
-async fn get_built_transaction(
+/// Returns the UTXO spent by `outpoint` with its address.
+/// Fails if `outpoint` isn't one of the given UTXOs or if it is already part of `inputs`.
+fn find_input(
+    utxos_addresses: &BTreeMap<Address, Vec<Utxo>>,
+    inputs: &[crate::OutPoint],
+    outpoint: &crate::OutPoint,
+) -> Result<(Address, Utxo), MultiTransferError> {
+    if inputs.contains(outpoint) {
+        return Err(MultiTransferError::DuplicateInput);
+    }
+    utxos_addresses
+        .iter()
+        .find_map(|(address, utxos)| {
+            utxos
+                .iter()
+                .find(|utxo| &utxo.outpoint == outpoint)
+                .map(|utxo| (address.clone(), utxo.clone()))
+        })
+        .ok_or(MultiTransferError::InputNotSpendable)
+}
+
+/// Returns the UTXOs, associated with their addresses, from which the inputs of the transaction are selected.
+/// If the inputs are given in `multi_transfer_args`, only their UTXOs are returned, in the same order.
+fn get_candidate_utxos(
     multi_transfer_args: &MultiTransferArgs,
     utxos_addresses: &BTreeMap<Address, Vec<Utxo>>,
-) -> Result<BuiltTransaction, MultiTransferError> {
+) -> Result<Vec<(Address, Utxo)>, MultiTransferError> {
+    if multi_transfer_args.inputs.is_empty() {
+        return Ok(utxos_addresses
+            .iter()
+            .flat_map(|(address, utxos)| utxos.iter().map(|utxo| (address.clone(), utxo.clone())))
+            .collect());
+    }
+    multi_transfer_args
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(index, outpoint)| {
+            find_input(
+                utxos_addresses,
+                &multi_transfer_args.inputs[..index],
+                outpoint,
+            )
+        })
+        .collect()
+}
+
+/// Returns whether `script` is one of the standard output scripts relayed by nodes.
+fn is_standard_script(script: &Script) -> bool {
+    script.is_p2pk()
+        || script.is_p2pkh()
+        || script.is_p2sh()
+        || script.is_v0_p2wpkh()
+        || script.is_v0_p2wsh()
+        || script.is_v1_p2tr()
+        || (script.is_op_return() && script.len() <= MAX_OP_RETURN_RELAY)
+}
+
+/// Checks that the given script payout is spendable, standard unless `allow_nonstandard` and not dust.
+fn validate_script_payout(
+    script_payout: &ScriptPayout,
+    allow_nonstandard: bool,
+) -> Result<(), MultiTransferError> {
+    if script_payout.script_pubkey.len() > MAX_SCRIPT_SIZE {
+        return Err(MultiTransferError::ScriptTooLong);
+    }
+    let script = ScriptBuf::from(script_payout.script_pubkey.clone());
+    if !allow_nonstandard && !is_standard_script(&script) {
+        return Err(MultiTransferError::NonStandardScript);
+    }
+    // `OP_RETURN` outputs are never spent, so they can't be dust.
+    if !script.is_op_return() && is_dust(script_payout.amount) {
+        return Err(MultiTransferError::DustOutput);
+    }
+    Ok(())
+}
+
+/// Restricts the candidate UTXOs according to the selection scope, given the `required_amount` the spent UTXOs have to cover.
+fn get_scoped_candidate_utxos(
+    multi_transfer_args: &MultiTransferArgs,
+    candidate_utxos: &[(Address, Utxo)],
+    required_amount: Satoshi,
+) -> Result<Vec<(Address, Utxo)>, MultiTransferError> {
+    let selection_scope = multi_transfer_args.selection_scope;
+    if selection_scope == SelectionScope::Any {
+        return Ok(candidate_utxos.to_vec());
+    }
+    // The given inputs are all spent, so they can only be checked.
+    if !multi_transfer_args.inputs.is_empty() {
+        let is_single_address = candidate_utxos
+            .iter()
+            .all(|(address, _)| address == &candidate_utxos[0].0);
+        if selection_scope == SelectionScope::SingleAddressOnly && !is_single_address {
+            return Err(MultiTransferError::SingleAddressSelectionFailed);
+        }
+        return Ok(candidate_utxos.to_vec());
+    }
+    // Find the first address whose UTXOs cover the required amount.
+    let mut balances: BTreeMap<&Address, Satoshi> = BTreeMap::default();
+    let covering_address = candidate_utxos.iter().find_map(|(address, utxo)| {
+        let balance = balances.entry(address).or_default();
+        *balance += utxo.value;
+        (*balance >= required_amount).then_some(address)
+    });
+    match (covering_address, selection_scope) {
+        (Some(covering_address), _) => Ok(candidate_utxos
+            .iter()
+            .filter(|(address, _)| address == covering_address)
+            .cloned()
+            .collect()),
+        (None, SelectionScope::SingleAddressOnly) => {
+            Err(MultiTransferError::SingleAddressSelectionFailed)
+        }
+        // Mixing addresses is the last resort.
+        (None, _) => Ok(candidate_utxos.to_vec()),
+    }
+}
+
+/// Returns whether an output of `amount` satoshis is dust, the change being left to the fee in that case.
+fn is_dust(amount: Satoshi) -> bool {
+    amount < DUST_THRESHOLD
+}
+
+/// Checks that none of the payouts is dust and that the script payouts are valid.
+fn validate_outputs(multi_transfer_args: &MultiTransferArgs) -> Result<(), MultiTransferError> {
+    if multi_transfer_args
+        .payouts
+        .values()
+        .any(|amount| is_dust(*amount))
+    {
+        return Err(MultiTransferError::DustOutput);
+    }
+    multi_transfer_args
+        .script_payouts
+        .iter()
+        .try_for_each(|script_payout| {
+            validate_script_payout(script_payout, multi_transfer_args.allow_nonstandard)
+        })
+}
+
+/// Checks that `fee` doesn't exceed the maximum fee of the transaction, if any.
+fn validate_fee(
+    multi_transfer_args: &MultiTransferArgs,
+    fee: Satoshi,
+) -> Result<(), MultiTransferError> {
+    match multi_transfer_args.max_fee {
+        Some(max_fee) if fee > max_fee => Err(MultiTransferError::FeeTooHigh { fee, max_fee }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the final unsigned transaction and the cycles spent to retrieve the fees if needed.
+async fn get_built_transaction(
+    multi_transfer_args: &MultiTransferArgs,
+    candidate_utxos: &[(Address, Utxo)],
+    transfer_calls: &impl TransferCalls,
+) -> Result<WithCost<BuiltTransaction>, MultiTransferError> {
+    let mut cycles_spent = 0;
     match multi_transfer_args.fee {
-        Fee::Constant(fee) => build_transaction_with_fee(
-            &multi_transfer_args.ecdsa_pub_key_addresses,
-            utxos_addresses,
-            &multi_transfer_args.change_address,
-            &multi_transfer_args.payouts,
-            fee,
-            multi_transfer_args.replaceable,
-        ),
+        Fee::Constant(fee) => {
+            let mut built_transaction =
+                build_transaction_with_fee(multi_transfer_args, candidate_utxos, fee)?;
+            built_transaction.mock_signed_transaction_size =
+                get_mock_signed_transaction_size(multi_transfer_args, &built_transaction).await?;
+            Ok(WithCost {
+                result: built_transaction,
+                cycles_spent,
+                attempts: 1,
+            })
+        }
         _ => {
             let fee_per_byte = match multi_transfer_args.fee {
                 Fee::PerByte(fee_per_byte) => fee_per_byte,
                 // This case can't happen see above
                 Fee::Constant(_) => panic!(),
                 fee_percentile => {
-                    get_current_fee(
-                        FeeRequest::from(fee_percentile),
-                        from_types_network_to_bitcoin_network(multi_transfer_args.network),
-                    )
-                    .await?
+                    let fee_request = FeeRequest::from(fee_percentile).resolve(
+                        multi_transfer_args.standard_fee_percentile,
+                        &multi_transfer_args.target_blocks_percentiles,
+                    );
+                    let fee_result = match &multi_transfer_args.current_fees {
+                        // The given fees don't need to be retrieved again.
+                        Some(current_fees) => get_fee_from_percentiles(
+                            fee_request,
+                            current_fees,
+                            multi_transfer_args.clamp_percentile,
+                        ),
+                        None => {
+                            let current_fees =
+                                transfer_calls.get_current_fees(multi_transfer_args).await;
+                            current_fees.map_err(GetCurrentFeeError::from).and_then(
+                                |current_fees| {
+                                    cycles_spent += current_fees.cycles_spent;
+                                    get_fee_from_percentiles(
+                                        fee_request,
+                                        &current_fees.result,
+                                        multi_transfer_args.clamp_percentile,
+                                    )
+                                },
+                            )
+                        }
+                    };
+                    let fee_per_byte = match (fee_result, multi_transfer_args.fallback_fee_per_byte)
+                    {
+                        // Without fee data, e.g. right after genesis, the fallback fee is used.
+                        (Err(GetCurrentFeeError::NoFeeData), Some(fallback_fee_per_byte)) => {
+                            fallback_fee_per_byte
+                        }
+                        (fee_result, _) => fee_result?,
+                    };
+                    let fee_per_byte = match fee_percentile {
+                        Fee::StandardTimes {
+                            numerator,
+                            denominator,
+                        } => scale_rate(fee_per_byte, numerator, denominator)
+                            .ok_or(MultiTransferError::InvalidFeeMultiplier)?,
+                        _ => fee_per_byte,
+                    };
+                    // The cap applies after the multiplier, hence it may be lower than the standard fee.
+                    multi_transfer_args
+                        .max_fee_per_byte
+                        .map_or(fee_per_byte, |max_fee_per_byte| {
+                            fee_per_byte.min(max_fee_per_byte)
+                        })
                 }
             };
-            build_transaction(
-                multi_transfer_args.key_name.clone(),
-                &multi_transfer_args.ecdsa_pub_key_addresses,
-                utxos_addresses,
-                &multi_transfer_args.change_address,
-                &multi_transfer_args.payouts,
-                fee_per_byte,
-                multi_transfer_args.replaceable,
-            )
-            .await
+            Ok(WithCost {
+                result: build_transaction(multi_transfer_args, candidate_utxos, fee_per_byte)
+                    .await?,
+                cycles_spent,
+                attempts: 1,
+            })
         }
     }
 }
@@ -313,7 +841,7 @@ fn get_generated_utxos_addresses(
         .for_each(|(address, value)| {
             let utxo = Utxo {
                 outpoint: crate::OutPoint {
-                    txid: txid.to_vec(),
+                    txid: txid.to_byte_array().to_vec(),
                     vout,
                 },
                 value: *value,
@@ -325,14 +853,36 @@ fn get_generated_utxos_addresses(
                 .push(utxo);
             vout += 1;
         });
+    let network = from_types_network_to_bitcoin_network(multi_transfer_args.network);
+    multi_transfer_args
+        .script_payouts
+        .iter()
+        .for_each(|script_payout| {
+            // Only the script payouts paying to an address are tracked.
+            let script = ScriptBuf::from(script_payout.script_pubkey.clone());
+            if let Ok(address) = Address::from_script(&script, network) {
+                generated_utxos_addresses
+                    .entry(get_address_using_primitives(&address))
+                    .or_insert_with(Vec::new)
+                    .push(Utxo {
+                        outpoint: crate::OutPoint {
+                            txid: txid.to_byte_array().to_vec(),
+                            vout,
+                        },
+                        value: script_payout.amount,
+                        height: tip_height,
+                    });
+            }
+            vout += 1;
+        });
     let total_spent: Satoshi = transaction_info
         .utxos_addresses
-        .iter()
-        .map(|(_, utxos)| utxos.iter().map(|utxo| utxo.value).sum::<Satoshi>())
+        .values()
+        .map(|utxos| utxos.iter().map(|utxo| utxo.value).sum::<Satoshi>())
         .sum();
-    let total_amount: Satoshi = multi_transfer_args.payouts.values().sum();
+    let total_amount = get_total_amount(multi_transfer_args);
     let change_amount = total_spent - total_amount - transaction_info.fee;
-    if change_amount > DUST_THRESHOLD {
+    if !is_dust(change_amount) {
         generated_utxos_addresses
             .entry(get_address_using_primitives(
                 &multi_transfer_args.change_address,
@@ -340,7 +890,7 @@ fn get_generated_utxos_addresses(
             .or_insert_with(Vec::new)
             .push(Utxo {
                 outpoint: crate::OutPoint {
-                    txid: txid.to_vec(),
+                    txid: txid.to_byte_array().to_vec(),
                     vout,
                 },
                 value: change_amount,
@@ -350,28 +900,106 @@ fn get_generated_utxos_addresses(
     generated_utxos_addresses
 }
 
-pub(crate) fn time() -> u64 {
-    if cfg!(test) {
-        use std::time::{SystemTime, UNIX_EPOCH};
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64
-    } else {
-        ic_cdk::api::time()
+/// Returns the total amount of the address and script payouts.
+fn get_total_amount(multi_transfer_args: &MultiTransferArgs) -> Satoshi {
+    multi_transfer_args.payouts.values().sum::<Satoshi>()
+        + multi_transfer_args
+            .script_payouts
+            .iter()
+            .map(|script_payout| script_payout.amount)
+            .sum::<Satoshi>()
+}
+
+/// Returns the indices of the outputs paying the script payouts, which follow the address payouts.
+fn get_script_payout_vouts(multi_transfer_args: &MultiTransferArgs) -> Vec<u32> {
+    let first_vout = multi_transfer_args.payouts.len() as u32;
+    (first_vout..first_vout + multi_transfer_args.script_payouts.len() as u32).collect()
+}
+
+impl MultiTransferArgs {
+    /// Returns the previews of the calls made by `multi_transfer_from_args` with these arguments, in this order, e.g. to have the transfer approved before making it.
+    /// Only the inputs given with a `TransactionBuilder` have the previews of their signatures, the UTXOs selected automatically being only known once the selection runs.
+    /// The cycles of `bitcoin_send_transaction` are the base ones, the ones per byte depending on the size of the signed transaction.
+    pub fn preview(&self) -> Vec<CallPreview> {
+        let network = from_types_network_to_bitcoin_network(self.network);
+        // The tip height is retrieved with the UTXOs of the change address.
+        let mut call_previews = vec![CallPreview::get_utxos(
+            network,
+            &self.change_address,
+            0,
+            &self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )];
+        if self.fee.is_percentile() && self.current_fees.is_none() {
+            call_previews.push(CallPreview::get_current_fee_percentiles(
+                network,
+                &self.cycles_cost_config,
+                &self.bitcoin_api_target,
+            ));
+        }
+        let unspent_utxos_addresses = get_unspent_utxos_addresses(self);
+        for outpoint in &self.inputs {
+            if let Ok((address, utxo)) = find_input(&unspent_utxos_addresses, &[], outpoint) {
+                let (method, cycles) = match address.address_type() {
+                    Some(AddressType::P2tr) => (
+                        ManagementCanisterMethod::SignWithSchnorr,
+                        self.cycles_cost_config.sign_with_schnorr,
+                    ),
+                    _ => (
+                        ManagementCanisterMethod::SignWithEcdsa,
+                        self.cycles_cost_config.sign_with_ecdsa,
+                    ),
+                };
+                call_previews.push(CallPreview {
+                    method,
+                    principal: Principal::management_canister(),
+                    cycles,
+                    decoded_args_summary: format!(
+                        "key_name: {}, derivation_path: {}, input: {}, value: {}, address: {}",
+                        self.key_name,
+                        format_derivation_path(
+                            &self.ecdsa_pub_key_addresses[&address].derivation_path
+                        ),
+                        outpoint_to_explorer_string(&utxo.outpoint),
+                        utxo.value,
+                        address
+                    ),
+                });
+            }
+        }
+        let inputs = if self.inputs.is_empty() {
+            "selected automatically".to_string()
+        } else {
+            self.inputs
+                .iter()
+                .map(outpoint_to_explorer_string)
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        call_previews.push(CallPreview {
+            method: ManagementCanisterMethod::BitcoinSendTransaction,
+            principal: self.bitcoin_api_target.get_principal(),
+            cycles: self.cycles_cost_config.send_tx,
+            decoded_args_summary: format!(
+                "network: {}, payouts: {}, total_amount: {}, change_address: {}, fee: {:?}, inputs: {}",
+                network,
+                self.payouts.len() + self.script_payouts.len(),
+                get_total_amount(self),
+                self.change_address,
+                self.fee,
+                inputs
+            ),
+        });
+        call_previews
     }
 }
 
 // Builds a transaction to send the given `amount` of satoshis to the
 // destination address.
 async fn build_transaction(
-    key_name: String,
-    ecdsa_pub_key_addresses: &BTreeMap<Address, EcdsaPubKey>,
-    utxos_addresses: &BTreeMap<Address, Vec<Utxo>>,
-    change_address: &Address,
-    payouts: &BTreeMap<Address, Satoshi>,
+    multi_transfer_args: &MultiTransferArgs,
+    candidate_utxos: &[(Address, Utxo)],
     fee_per_byte: MillisatoshiPerByte,
-    replaceable: bool,
 ) -> Result<BuiltTransaction, MultiTransferError> {
     // We have a chicken-and-egg problem where we need to know the size
     // of the transaction in order to compute its proper fee, but we need
@@ -383,108 +1011,235 @@ async fn build_transaction(
     // rebuild the transaction, until the fee is set to the correct amount.
     let mut total_fee = 0;
     loop {
-        let mut built_transaction = build_transaction_with_fee(
-            ecdsa_pub_key_addresses,
-            utxos_addresses,
-            change_address,
-            payouts,
-            total_fee,
-            replaceable,
-        )?;
-
-        // Sign the transaction. In this case, we only care about the size
-        // of the signed transaction, so we use a mock signer here for efficiency.
-        let signed_transaction = sign_transaction(
-            key_name.clone(),
-            &get_spending_addresses(&built_transaction),
-            &built_transaction.spending_ecdsa_pub_keys,
-            built_transaction.transaction.clone(),
-            mock_signer,
-        )
-        .await?;
+        let mut built_transaction =
+            build_transaction_with_fee(multi_transfer_args, candidate_utxos, total_fee)?;
 
-        let signed_tx_bytes_len = signed_transaction.serialize().len() as u64;
+        let signed_tx_bytes_len =
+            get_mock_signed_transaction_size(multi_transfer_args, &built_transaction).await?;
 
-        if (signed_tx_bytes_len * fee_per_byte) / 1000 == total_fee {
+        let fee = fee_for_vsize(fee_per_byte, signed_tx_bytes_len);
+        if fee == total_fee {
             built_transaction.mock_signed_transaction_size = signed_tx_bytes_len;
             return Ok(built_transaction);
         } else {
-            total_fee = (signed_tx_bytes_len * fee_per_byte) / 1000;
+            total_fee = fee;
         }
     }
 }
 
-/// Builds a transaction that sends the given `payouts` amounts of satoshis to the given `payouts` addresses.
-/// Sends back the change to `change_address`.
-fn build_transaction_with_fee(
-    ecdsa_pub_key_addresses: &BTreeMap<Address, EcdsaPubKey>,
-    utxos_addresses: &BTreeMap<Address, Vec<Utxo>>,
-    change_address: &Address,
-    payouts: &BTreeMap<Address, Satoshi>,
-    fee: Satoshi,
-    replaceable: bool,
-) -> Result<BuiltTransaction, MultiTransferError> {
-    // TODO (FI-313): Add smarter coin selection
-    // Select which UTXOs to spend. For now, we naively spend the first available UTXOs.
-    let mut spending_utxos_addresses = BTreeMap::default();
-    let mut spending_ecdsa_pub_keys = vec![];
-    let mut inputs: Vec<TxIn> = vec![];
+/// Returns the size of the built transaction once signed.
+/// Fails if the signed transaction exceeds the weight or inputs limits of `multi_transfer_args`.
+async fn get_mock_signed_transaction_size(
+    multi_transfer_args: &MultiTransferArgs,
+    built_transaction: &BuiltTransaction,
+) -> Result<u64, MultiTransferError> {
+    // Sign the transaction. In this case, we only care about the size
+    // of the signed transaction, so we use a mock signer here for efficiency.
+    let (signed_transaction, _, _) = sign_transaction(
+        multi_transfer_args.key_name.clone(),
+        built_transaction,
+        mock_signer,
+        mock_signer,
+        &DEFAULT_RETRY_CONFIG,
+        multi_transfer_args.sign_concurrency,
+        // The mock signatures of the size estimation aren't logged.
+        &NoopLogSink,
+    )
+    .await?;
+
+    let weight = signed_transaction.weight().to_wu();
+    let inputs = signed_transaction.input.len() as u32;
+    if weight > multi_transfer_args.max_tx_weight || inputs > multi_transfer_args.max_inputs {
+        return Err(MultiTransferError::TransactionTooLarge { weight, inputs });
+    }
+    Ok(serialize(&signed_transaction).len() as u64)
+}
+
+/// Returns an upper bound of the weight of an input spending a UTXO of `address` once signed.
+fn get_max_input_weight(address: &Address) -> u64 {
+    // The outpoint, the script length and the sequence take 41 bytes.
+    match address.address_type() {
+        // The witness holds a 64 bytes Schnorr signature, the signature hash type being the default one.
+        Some(AddressType::P2tr) => 4 * 41 + 66,
+        // The script signature pushes a DER signature of at most 72 bytes with its signature hash type and a compressed public key.
+        _ => 4 * (41 + 107),
+    }
+}
+
+/// Returns the first `candidate_utxos` covering `required_amount`, the transaction weighing `base_weight` without its inputs.
+/// If `limited`, fails as soon as the inputs or weight limits of `multi_transfer_args` are reached.
+fn select_first_utxos<'a>(
+    multi_transfer_args: &MultiTransferArgs,
+    candidate_utxos: impl IntoIterator<Item = &'a (Address, Utxo)>,
+    required_amount: Satoshi,
+    base_weight: u64,
+    limited: bool,
+) -> Option<Vec<&'a (Address, Utxo)>> {
+    let mut selected_utxos = vec![];
     let mut total_spent = 0;
-    let total_amount: Satoshi = payouts.values().sum();
-    'select_utxos: for (address, utxos) in utxos_addresses.iter() {
-        for utxo in utxos.iter() {
-            total_spent += utxo.value;
-            spending_utxos_addresses
-                .entry(address.clone())
-                .or_insert_with(Vec::new)
-                .push(utxo.clone());
-            spending_ecdsa_pub_keys.push(ecdsa_pub_key_addresses[address].clone());
-            inputs.push(TxIn {
-                previous_output: OutPoint {
-                    txid: Txid::from_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
-                    vout: utxo.outpoint.vout,
-                },
-                sequence: if replaceable {
-                    // If `replaceable`, then enable Replace-By-Fee according to BIP 125.
-                    0x00000000
-                } else {
-                    0xffffffff
-                },
-                witness: Witness::new(),
-                script_sig: Script::new(),
-            });
-            if total_spent >= total_amount + fee {
-                break 'select_utxos;
-            }
+    let mut weight = base_weight;
+    for candidate_utxo in candidate_utxos {
+        weight += get_max_input_weight(&candidate_utxo.0);
+        if limited
+            && (selected_utxos.len() as u32 >= multi_transfer_args.max_inputs
+                || weight > multi_transfer_args.max_tx_weight)
+        {
+            return None;
+        }
+        selected_utxos.push(candidate_utxo);
+        total_spent += candidate_utxo.1.value;
+        if total_spent >= required_amount {
+            return Some(selected_utxos);
         }
     }
+    None
+}
 
-    if total_spent < total_amount + fee {
-        return Err(MultiTransferError::InsufficientBalance);
+/// Selects the `candidate_utxos` to spend to cover `required_amount`, the transaction weighing `base_weight` without its inputs.
+/// If the inputs are given in `multi_transfer_args`, all of them are selected.
+/// Otherwise, the first candidates are selected within the inputs and weight limits, the largest ones being selected instead if the first ones exceed them.
+/// If no selection fits within the limits, the fewest candidates covering the amount are selected, the transaction being then rejected as too large.
+fn select_utxos<'a>(
+    multi_transfer_args: &MultiTransferArgs,
+    candidate_utxos: &'a [(Address, Utxo)],
+    required_amount: Satoshi,
+    base_weight: u64,
+) -> Result<Vec<&'a (Address, Utxo)>, MultiTransferError> {
+    if !multi_transfer_args.inputs.is_empty() {
+        let selected_utxos: Vec<_> = candidate_utxos.iter().collect();
+        let total_spent: Satoshi = selected_utxos.iter().map(|(_, utxo)| utxo.value).sum();
+        return if total_spent >= required_amount {
+            Ok(selected_utxos)
+        } else {
+            Err(MultiTransferError::InsufficientBalance)
+        };
     }
+    let mut largest_utxos: Vec<_> = candidate_utxos.iter().collect();
+    largest_utxos.sort_by_key(|(_, utxo)| std::cmp::Reverse(utxo.value));
+    select_first_utxos(
+        multi_transfer_args,
+        candidate_utxos,
+        required_amount,
+        base_weight,
+        true,
+    )
+    .or_else(|| {
+        select_first_utxos(
+            multi_transfer_args,
+            largest_utxos.iter().copied(),
+            required_amount,
+            base_weight,
+            true,
+        )
+    })
+    .or_else(|| {
+        select_first_utxos(
+            multi_transfer_args,
+            largest_utxos.iter().copied(),
+            required_amount,
+            base_weight,
+            false,
+        )
+    })
+    .ok_or(MultiTransferError::InsufficientBalance)
+}
 
-    let mut outputs: Vec<TxOut> = payouts
+/// Builds a transaction that sends the given `payouts` amounts of satoshis to the given `payouts` addresses, followed by the script payouts.
+/// Sends back the change to `change_address`.
+/// If the inputs are given in `multi_transfer_args`, all of them are spent, otherwise the `candidate_utxos` are selected with `select_utxos`.
+fn build_transaction_with_fee(
+    multi_transfer_args: &MultiTransferArgs,
+    candidate_utxos: &[(Address, Utxo)],
+    fee: Satoshi,
+) -> Result<BuiltTransaction, MultiTransferError> {
+    let mut outputs: Vec<TxOut> = multi_transfer_args
+        .payouts
         .iter()
         .map(|(address, amount)| TxOut {
             script_pubkey: address.script_pubkey(),
             value: *amount,
         })
         .collect();
+    outputs.extend(
+        multi_transfer_args
+            .script_payouts
+            .iter()
+            .map(|script_payout| TxOut {
+                script_pubkey: ScriptBuf::from(script_payout.script_pubkey.clone()),
+                value: script_payout.amount,
+            }),
+    );
+    let change_output = TxOut {
+        script_pubkey: multi_transfer_args.change_address.script_pubkey(),
+        value: 0,
+    };
+
+    // TODO (FI-313): Add smarter coin selection
+    let mut spending_utxos_addresses = BTreeMap::default();
+    let mut spending_addresses = vec![];
+    let mut spending_values = vec![];
+    let mut spending_ecdsa_pub_keys = vec![];
+    let mut inputs: Vec<TxIn> = vec![];
+    let mut total_spent = 0;
+    let total_amount = get_total_amount(multi_transfer_args);
+    let candidate_utxos =
+        &get_scoped_candidate_utxos(multi_transfer_args, candidate_utxos, total_amount + fee)?;
+    // The change output, the segregated witness marker and flag and the largest inputs count are accounted for the weight of the transaction without its inputs.
+    let base_weight = Transaction {
+        input: vec![],
+        output: [outputs.as_slice(), std::slice::from_ref(&change_output)].concat(),
+        lock_time: LockTime::ZERO,
+        version: 2,
+    }
+    .weight()
+    .to_wu()
+        + 2
+        + 4 * 8;
+    let selected_utxos = select_utxos(
+        multi_transfer_args,
+        candidate_utxos,
+        total_amount + fee,
+        base_weight,
+    )?;
+    for (address, utxo) in selected_utxos {
+        total_spent += utxo.value;
+        spending_utxos_addresses
+            .entry(address.clone())
+            .or_insert_with(Vec::new)
+            .push(utxo.clone());
+        spending_addresses.push(address.clone());
+        spending_values.push(utxo.value);
+        spending_ecdsa_pub_keys.push(multi_transfer_args.ecdsa_pub_key_addresses[address].clone());
+        inputs.push(TxIn {
+            previous_output: OutPoint {
+                txid: Txid::from_slice(&utxo.outpoint.txid).unwrap(),
+                vout: utxo.outpoint.vout,
+            },
+            sequence: if multi_transfer_args.replaceable {
+                // If `replaceable`, then enable Replace-By-Fee according to BIP 125.
+                Sequence::ZERO
+            } else {
+                Sequence::MAX
+            },
+            witness: Witness::new(),
+            script_sig: ScriptBuf::new(),
+        });
+    }
 
     let remaining_amount = total_spent - total_amount - fee;
 
-    // Assume that any amount below this threshold is dust.
-    if remaining_amount > DUST_THRESHOLD {
+    // The change is left to the fee if it's dust.
+    if !is_dust(remaining_amount) {
         outputs.push(TxOut {
-            script_pubkey: change_address.script_pubkey(),
             value: remaining_amount,
+            ..change_output
         });
     }
 
     let transaction = Transaction {
         input: inputs,
         output: outputs,
-        lock_time: 0,
+        lock_time: LockTime::ZERO,
         version: 2,
     };
 
@@ -492,62 +1247,239 @@ fn build_transaction_with_fee(
         transaction,
         mock_signed_transaction_size: 0,
         spending_utxos_addresses,
+        spending_addresses,
+        spending_values,
         spending_ecdsa_pub_keys,
         fee,
     })
 }
 
 /// Sign a Bitcoin transaction given the addresses of the funds and the change address.
+/// P2TR inputs are signed with `schnorr_signer` for a key path spend, the other ones with `ecdsa_signer`.
+/// At most `concurrency` inputs are signed concurrently, the signature of another input being requested as soon as a pending one is obtained.
+/// Returns the signed transaction, the number of calls to the signers, the rejected calls being retried according to `retry_config`, and the cycles spent by the calls.
+/// Fails with all the inputs whose signature was rejected, the signatures of the other inputs being requested anyway.
+/// Each signature request is logged to `log_sink` with the derivation path of its input.
 ///
 /// Constraint:
 /// * All the inputs are referencing outpoints that are owned by managed supported addresses.
-async fn sign_transaction<SignFun, Fut>(
+async fn sign_transaction<EcdsaSignFun, EcdsaFut, SchnorrSignFun, SchnorrFut>(
     key_name: String,
-    addresses: &[Address],
-    ecdsa_pub_keys: &[EcdsaPubKey],
-    mut transaction: Transaction,
-    signer: SignFun,
-) -> Result<Transaction, ManagementCanisterReject>
+    built_transaction: &BuiltTransaction,
+    ecdsa_signer: EcdsaSignFun,
+    schnorr_signer: SchnorrSignFun,
+    retry_config: &RetryConfig,
+    concurrency: u32,
+    log_sink: &dyn LogSink,
+) -> Result<(Transaction, u32, u128), MultiTransferError>
 where
-    SignFun: Fn(String, Vec<Vec<u8>>, Vec<u8>) -> Fut,
-    Fut: Future<Output = Result<Vec<u8>, ManagementCanisterReject>>,
+    EcdsaSignFun: Fn(String, DerivationPath, Vec<u8>) -> EcdsaFut,
+    EcdsaFut: Future<Output = Result<WithCost<Vec<u8>>, ManagementCanisterReject>>,
+    SchnorrSignFun: Fn(String, DerivationPath, Vec<u8>) -> SchnorrFut,
+    SchnorrFut: Future<Output = Result<WithCost<Vec<u8>>, ManagementCanisterReject>>,
 {
-    let txclone = transaction.clone();
-    for (index, input) in transaction.input.iter_mut().enumerate() {
-        let address = &addresses[index];
-        let sighash =
-            txclone.signature_hash(index, &address.script_pubkey(), SIG_HASH_TYPE.to_u32());
-
-        let ecdsa_pub_key = &ecdsa_pub_keys[index];
-        let signature = signer(
-            key_name.clone(),
-            ecdsa_pub_key.derivation_path.clone(),
-            sighash.to_vec(),
-        )
-        .await?;
+    // The derivation paths read from the state are checked before spending any cycles.
+    for ecdsa_pub_key in &built_transaction.spending_ecdsa_pub_keys {
+        ecdsa_pub_key
+            .derivation_path
+            .validate()
+            .map_err(MultiTransferError::InvalidDerivationPath)?;
+    }
+    let unsigned_transaction = &built_transaction.transaction;
+    let mut transaction = unsigned_transaction.clone();
+    // Taproot signature hashes commit to all the spent outputs.
+    let prevouts: Vec<TxOut> = built_transaction
+        .spending_addresses
+        .iter()
+        .zip(&built_transaction.spending_values)
+        .map(|(address, value)| TxOut {
+            script_pubkey: address.script_pubkey(),
+            value: *value,
+        })
+        .collect();
+    // The signature hashes are computed upfront to sign the inputs concurrently.
+    let mut sighash_cache = SighashCache::new(unsigned_transaction);
+    let sighashes: Vec<(ManagementCanisterMethod, Vec<u8>)> = built_transaction
+        .spending_addresses
+        .iter()
+        .enumerate()
+        .map(|(index, address)| {
+            if address.address_type() == Some(AddressType::P2tr) {
+                let sighash = sighash_cache
+                    .taproot_key_spend_signature_hash(
+                        index,
+                        &Prevouts::All(prevouts.as_slice()),
+                        SCHNORR_SIG_HASH_TYPE,
+                    )
+                    .unwrap();
+                (
+                    ManagementCanisterMethod::SignWithSchnorr,
+                    sighash.to_byte_array().to_vec(),
+                )
+            } else {
+                let sighash = sighash_cache
+                    .legacy_signature_hash(index, &address.script_pubkey(), SIG_HASH_TYPE.to_u32())
+                    .unwrap();
+                (
+                    ManagementCanisterMethod::SignWithEcdsa,
+                    sighash.to_byte_array().to_vec(),
+                )
+            }
+        })
+        .collect();
+
+    // At most `concurrency` signature calls are pending at a time, the signatures being yielded in the order of the inputs.
+    let signatures: Vec<_> = stream::iter(sighashes.into_iter().enumerate().map(
+        |(index, (method, sighash))| {
+            let (ecdsa_signer, schnorr_signer, key_name) =
+                (&ecdsa_signer, &schnorr_signer, &key_name);
+            let derivation_path = &built_transaction.spending_ecdsa_pub_keys[index].derivation_path;
+            async move {
+                log_sink.log(
+                    LogLevel::Debug,
+                    LOG_TARGET_SIGN,
+                    &format!(
+                        "Requesting the signature of the input {} with {:?} at the derivation path {}.",
+                        index,
+                        method,
+                        format_derivation_path(derivation_path)
+                    ),
+                );
+                let (signature, attempts) = match method {
+                    ManagementCanisterMethod::SignWithSchnorr => {
+                        sign_with_retries(
+                            schnorr_signer,
+                            key_name,
+                            derivation_path,
+                            &sighash,
+                            retry_config,
+                        )
+                        .await
+                    }
+                    _ => {
+                        sign_with_retries(
+                            ecdsa_signer,
+                            key_name,
+                            derivation_path,
+                            &sighash,
+                            retry_config,
+                        )
+                        .await
+                    }
+                };
+                (method, signature, attempts)
+            }
+        },
+    ))
+    .buffered(concurrency.max(1) as usize)
+    .collect()
+    .await;
+
+    let mut attempts = 0;
+    let mut cycles_spent = 0;
+    let mut failed_inputs = vec![];
+    for (index, (method, signature, input_attempts)) in signatures.into_iter().enumerate() {
+        attempts += input_attempts;
+        let signature = match signature {
+            Ok(signature) => signature,
+            Err(ManagementCanisterReject(rejection_code, message)) => {
+                failed_inputs.push(InputSigningFailure {
+                    input: index as u32,
+                    method,
+                    rejection_code,
+                    message,
+                });
+                continue;
+            }
+        };
+        cycles_spent += signature.cycles_spent;
+        let input = &mut transaction.input[index];
+
+        if method == ManagementCanisterMethod::SignWithSchnorr {
+            // With the default signature hash type, the 64-byte signature is the whole witness.
+            input.witness = Witness::from_slice(&[signature.result]);
+            continue;
+        }
 
-        // Convert signature to DER.
-        let der_signature = sec1_to_der(signature);
+        // Convert signature to DER with a low S value.
+        let der_signature = sec1_to_low_s_der(signature.result);
 
         let mut sig_with_hashtype = der_signature;
         sig_with_hashtype.push(SIG_HASH_TYPE.to_u32() as u8);
         input.script_sig = Builder::new()
-            .push_slice(sig_with_hashtype.as_slice())
-            .push_slice(&ecdsa_pub_key.public_key)
+            .push_slice(<&PushBytes>::try_from(sig_with_hashtype.as_slice()).unwrap())
+            .push_slice(
+                <&PushBytes>::try_from(
+                    built_transaction.spending_ecdsa_pub_keys[index]
+                        .public_key
+                        .as_slice(),
+                )
+                .unwrap(),
+            )
             .into_script();
     }
+    if !failed_inputs.is_empty() {
+        return Err(MultiTransferError::SigningFailed { failed_inputs });
+    }
 
-    Ok(transaction)
+    Ok((transaction, attempts, cycles_spent))
 }
 
-// A mock for rubber-stamping ECDSA signatures.
-async fn mock_signer(
-    _key_name: String,
-    _derivation_path: Vec<Vec<u8>>,
-    _message_hash: Vec<u8>,
-) -> Result<Vec<u8>, ManagementCanisterReject> {
-    Ok(vec![255; 64])
-}
+/// Calls `signer` until it succeeds or its rejection isn't retried according to `retry_config`.
+/// Returns the result of the last call and the number of calls.
+async fn sign_with_retries<SignFun, Fut>(
+    signer: &SignFun,
+    key_name: &str,
+    derivation_path: &DerivationPath,
+    message: &[u8],
+    retry_config: &RetryConfig,
+) -> (Result<WithCost<Vec<u8>>, ManagementCanisterReject>, u32)
+where
+    SignFun: Fn(String, DerivationPath, Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<WithCost<Vec<u8>>, ManagementCanisterReject>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match signer(
+            key_name.to_string(),
+            derivation_path.clone(),
+            message.to_vec(),
+        )
+        .await
+        {
+            Err(ManagementCanisterReject(rejection_code, _))
+                if retry_config.should_retry(rejection_code, attempts) => {}
+            signature_result => return (signature_result, attempts),
+        }
+    }
+}
+
+// A mock for rubber-stamping ECDSA signatures, reporting the cycles attached to actual signatures on the 13-node signing subnet as spent.
+pub(crate) async fn mock_signer(
+    _key_name: String,
+    _derivation_path: DerivationPath,
+    _message_hash: Vec<u8>,
+) -> Result<WithCost<Vec<u8>>, ManagementCanisterReject> {
+    Ok(WithCost {
+        result: vec![255; 64],
+        cycles_spent: SIGN_13_NODE_COST_CYCLES,
+        attempts: 1,
+    })
+}
+
+// Converts a SEC1 ECDSA signature to the DER format, normalizing S to the lower half of the curve order.
+// BIP-62 makes transactions with a high S value non-standard, so they wouldn't be relayed.
+fn sec1_to_low_s_der(sec1_signature: Vec<u8>) -> Vec<u8> {
+    match Signature::from_compact(&sec1_signature) {
+        Ok(mut signature) => {
+            signature.normalize_s();
+            signature.serialize_der().to_vec()
+        }
+        // The rubber-stamped signatures of `mock_signer` aren't valid signatures.
+        Err(_) => sec1_to_der(sec1_signature),
+    }
+}
 
 // Converts a SEC1 ECDSA signature to the DER format.
 fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
@@ -583,28 +1515,186 @@ fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
     .collect()
 }
 
-/// Returns the spending addresses from a given built transaction.
-fn get_spending_addresses(built_transaction: &BuiltTransaction) -> Vec<Address> {
-    let mut spending_addresses: Vec<Address> = vec![];
-    built_transaction
-        .spending_utxos_addresses
-        .iter()
-        .for_each(|(address, spending_utxos)| {
-            spending_addresses.append(&mut vec![address.clone(); spending_utxos.len()])
+/// Destination of an output added with `TransactionBuilder::add_output`.
+#[derive(Debug, Clone)]
+pub enum OutputDestination {
+    Address(Address),
+    Script(ScriptBuf),
+}
+
+impl OutputDestination {
+    /// Returns the script locking the output.
+    fn script_pubkey(self) -> ScriptBuf {
+        match self {
+            OutputDestination::Address(address) => address.script_pubkey(),
+            OutputDestination::Script(script) => script,
+        }
+    }
+}
+
+impl From<Address> for OutputDestination {
+    fn from(address: Address) -> Self {
+        OutputDestination::Address(address)
+    }
+}
+
+impl From<&Address> for OutputDestination {
+    fn from(address: &Address) -> Self {
+        OutputDestination::Address(address.clone())
+    }
+}
+
+impl From<ScriptBuf> for OutputDestination {
+    fn from(script: ScriptBuf) -> Self {
+        OutputDestination::Script(script)
+    }
+}
+
+/// Builds the arguments of a transaction whose inputs and outputs are chosen by the caller.
+/// The built arguments are used as the ones returned by `get_multi_transfer_args`, i.e. with `multi_transfer_from_args` and then `apply_multi_transfer_result`.
+#[derive(Debug)]
+pub struct TransactionBuilder {
+    multi_transfer_args: MultiTransferArgs,
+}
+
+impl TransactionBuilder {
+    /// Creates a new transaction builder from the given arguments without any input or output.
+    pub(crate) fn new(multi_transfer_args: MultiTransferArgs) -> Self {
+        Self {
+            multi_transfer_args,
+        }
+    }
+
+    /// Adds an input spending `outpoint`, which has to be an unspent output of a managed address.
+    /// Inputs are spent in the order they are added.
+    pub fn add_input(mut self, outpoint: &crate::OutPoint) -> Result<Self, MultiTransferError> {
+        find_input(
+            &get_unspent_utxos_addresses(&self.multi_transfer_args),
+            &self.multi_transfer_args.inputs,
+            outpoint,
+        )?;
+        self.multi_transfer_args.inputs.push(outpoint.clone());
+        Ok(self)
+    }
+
+    /// Adds an output paying `value` satoshis to the given address or script.
+    /// Outputs are created in the order they are added, before the change output.
+    pub fn add_output(mut self, destination: impl Into<OutputDestination>, value: Satoshi) -> Self {
+        self.multi_transfer_args.script_payouts.push(ScriptPayout {
+            script_pubkey: destination.into().script_pubkey().to_bytes(),
+            amount: value,
         });
-    spending_addresses
+        self
+    }
+
+    /// Sets whether outputs may pay to non-standard scripts, false by default.
+    pub fn allow_nonstandard(mut self, allow_nonstandard: bool) -> Self {
+        self.multi_transfer_args.allow_nonstandard = allow_nonstandard;
+        self
+    }
+
+    /// Sets the fee of the transaction, `Fee::Standard` by default.
+    pub fn fee(mut self, fee: Fee) -> Self {
+        self.multi_transfer_args.fee = fee;
+        self
+    }
+
+    /// Sets the maximum fee in satoshis of the transaction, none by default.
+    pub fn max_fee(mut self, max_fee: Option<Satoshi>) -> Self {
+        self.multi_transfer_args.max_fee = max_fee;
+        self
+    }
+
+    /// Sets the address the change is sent back to, the main address by default.
+    pub fn change_address(mut self, change_address: &Address) -> Self {
+        self.multi_transfer_args.change_address = change_address.clone();
+        self
+    }
+
+    /// Sets the minimum number of confirmations of the spent outputs, the one of the Bitcoin agent by default.
+    pub fn min_confirmations(mut self, min_confirmations: u32) -> Self {
+        self.multi_transfer_args.min_confirmations = min_confirmations;
+        self
+    }
+
+    /// Sets whether the transaction is replaceable using Bitcoin's replace-by-fee (RBF) mechanism, false by default.
+    pub fn replaceable(mut self, replaceable: bool) -> Self {
+        self.multi_transfer_args.replaceable = replaceable;
+        self
+    }
+
+    /// Sets which managed addresses the spent UTXOs may come from, `SelectionScope::Any` by default.
+    pub fn selection_scope(mut self, selection_scope: SelectionScope) -> Self {
+        self.multi_transfer_args.selection_scope = selection_scope;
+        self
+    }
+
+    /// Sets the fee in millisatoshis/byte used when the management canister doesn't have any fee data, `DEFAULT_FALLBACK_FEE_PER_BYTE` by default.
+    pub fn fallback_fee_per_byte(
+        mut self,
+        fallback_fee_per_byte: Option<MillisatoshiPerByte>,
+    ) -> Self {
+        self.multi_transfer_args.fallback_fee_per_byte = fallback_fee_per_byte;
+        self
+    }
+
+    /// Sets the maximum weight of the signed transaction, `DEFAULT_MAX_TX_WEIGHT` by default.
+    pub fn max_tx_weight(mut self, max_tx_weight: u64) -> Self {
+        self.multi_transfer_args.max_tx_weight = max_tx_weight;
+        self
+    }
+
+    /// Sets the maximum number of inputs of the transaction, `DEFAULT_MAX_INPUTS` by default.
+    pub fn max_inputs(mut self, max_inputs: u32) -> Self {
+        self.multi_transfer_args.max_inputs = max_inputs;
+        self
+    }
+
+    /// Returns the arguments to send the built transaction with `multi_transfer_from_args`.
+    /// The outputs and a constant fee are validated like by `multi_transfer_from_args`, the other fees being only known once the transaction is built.
+    pub fn build_args(self) -> Result<MultiTransferArgs, MultiTransferError> {
+        validate_outputs(&self.multi_transfer_args)?;
+        if let Fee::Constant(fee) = self.multi_transfer_args.fee {
+            validate_fee(&self.multi_transfer_args, fee)?;
+        }
+        Ok(self.multi_transfer_args)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        agent, canister_mock,
-        canister_mock::{get_balance_update, get_init_balance, mine_block, ManagementCanisterMock},
-        AddressType, BitcoinAgent, FeeRequest, GetCurrentFeeError, MillisatoshiPerByte, Network,
+        agent,
+        canister_common::{ManagementCanister, SIGN_34_NODE_COST_CYCLES},
+        canister_mock,
+        canister_mock::{
+            get_balance_update, get_init_balance, get_init_utxos, get_outpoint_ic_type, mine_block,
+            ManagementCanisterMock, MockOp, MOCK_START_TIME,
+        },
+        fee_math::rate_for_fee,
+        AddressType, ApplyMultiTransferResultError, BitcoinAgent, DerivationPathError, FeeRequest,
+        GetCurrentFeeError, GetUtxosError, InvalidPercentile, MillisatoshiPerByte, Network,
+        RetryPolicy, StandardFeePercentileTooHigh, TimeSource, TransactionID,
+        DEFAULT_FALLBACK_FEE_PER_BYTE, DEFAULT_RETRY_POLICY, DEFAULT_SIGN_CONCURRENCY,
         MIN_CONFIRMATIONS_UPPER_BOUND,
     };
-    use std::str::FromStr;
+    use bitcoin::{
+        blockdata::script::Instruction,
+        consensus::serialize,
+        secp256k1::{
+            ecdsa::Signature as EcdsaSignature, schnorr::Signature, Message, PublicKey, Secp256k1,
+            XOnlyPublicKey,
+        },
+    };
+    use ic_cdk::api::call::RejectionCode;
+    use std::{
+        collections::BTreeSet,
+        str::FromStr,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+    use tokio::time::Instant;
 
     /// Check that `get_current_fees` returns the correct fees.
     #[test]
@@ -634,17 +1724,571 @@ mod tests {
     fn check_get_current_fee() {
         let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
 
+        // The mock returns 99 percentiles, whose 50th percentile is the middle one.
         let fee_request = FeeRequest::Standard;
         let fee = get_current_fee_test(&bitcoin_agent, fee_request).unwrap();
-        assert_eq!(fee, 51_000);
+        assert_eq!(fee, 50_000);
 
-        let fee_request = FeeRequest::Percentile(98);
-        let fee_result = get_current_fee_test(&bitcoin_agent, fee_request);
-        assert!(fee_result.is_ok());
+        let fee_request = FeeRequest::Percentile(100);
+        let fee = get_current_fee_test(&bitcoin_agent, fee_request).unwrap();
+        assert_eq!(fee, 99_000);
 
-        let fee_request = FeeRequest::Percentile(99);
+        let fee_request = FeeRequest::Percentile(101);
         let fee_result = get_current_fee_test(&bitcoin_agent, fee_request);
         assert!(fee_result.is_err());
+
+        let fee_request = FeeRequest::Median;
+        let fee = get_current_fee_test(&bitcoin_agent, fee_request).unwrap();
+        assert_eq!(fee, 50_000);
+
+        let fee_request = FeeRequest::Percentile(u8::MAX);
+        let fee_result = get_current_fee_test(&bitcoin_agent, fee_request);
+        assert!(matches!(
+            fee_result,
+            Err(GetCurrentFeeError::InvalidPercentile {
+                requested: u8::MAX,
+                available: 99
+            })
+        ));
+    }
+
+    /// Check that the standard fee percentile of each Bitcoin agent is used to resolve standard fees.
+    #[tokio::test]
+    async fn check_standard_fee_percentile() {
+        let mut bitcoin_agents: Vec<_> = [40, 75]
+            .iter()
+            .map(|standard_fee_percentile| {
+                let mut bitcoin_agent =
+                    agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+                bitcoin_agent
+                    .set_standard_fee_percentile(*standard_fee_percentile)
+                    .unwrap();
+                bitcoin_agent
+            })
+            .collect();
+        assert_eq!(
+            bitcoin_agents[0].set_standard_fee_percentile(101),
+            Err(StandardFeePercentileTooHigh)
+        );
+        assert_eq!(bitcoin_agents[0].get_standard_fee_percentile(), 40);
+
+        // Both Bitcoin agents share the same mock fee percentiles.
+        assert_eq!(
+            get_current_fee_test(&bitcoin_agents[0], FeeRequest::Standard).unwrap(),
+            40_200
+        );
+        assert_eq!(
+            get_current_fee_test(&bitcoin_agents[1], FeeRequest::Standard).unwrap(),
+            74_500
+        );
+
+        /// Returns the fee in millisatoshis/byte of a transfer using the given fee.
+        async fn get_fee_per_byte(
+            bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
+            fee: Fee,
+        ) -> MillisatoshiPerByte {
+            let min_confirmations = 0;
+            let main_address = &bitcoin_agent.get_main_address();
+            get_balance_update(bitcoin_agent, main_address, min_confirmations);
+            let payouts = BTreeMap::from([(
+                Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                    .unwrap()
+                    .assume_checked(),
+                10_000,
+            )]);
+            let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                fee,
+                min_confirmations,
+                false,
+            );
+            let transaction_info = bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await
+                .unwrap()
+                .transaction_info;
+            rate_for_fee(transaction_info.fee, transaction_info.size as u64)
+        }
+        let fee_per_byte_0 = get_fee_per_byte(&mut bitcoin_agents[0], Fee::Standard).await;
+        let fee_per_byte_1 = get_fee_per_byte(&mut bitcoin_agents[1], Fee::Standard).await;
+        // The fee ratio is slightly off as the mock signed transaction size may differ from the actual one by a few bytes.
+        assert!(fee_per_byte_0.abs_diff(40_200) < 100);
+        assert!(fee_per_byte_1.abs_diff(74_500) < 100);
+
+        // A custom percentile overrides the standard fee percentile for a single call.
+        let fee_per_byte_2 = get_fee_per_byte(&mut bitcoin_agents[0], Fee::Percentile(75)).await;
+        assert!(fee_per_byte_2.abs_diff(74_500) < 100);
+    }
+
+    /// Check that `Fee::StandardTimes` scales the standard fee, is capped by the maximum fee and is subject to the minimum relay fee.
+    #[tokio::test]
+    async fn check_standard_times_fee() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            10_000,
+        )]);
+        let get_multi_transfer_args =
+            |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>, numerator, denominator| {
+                bitcoin_agent.get_multi_transfer_args(
+                    &payouts,
+                    main_address,
+                    Fee::StandardTimes {
+                        numerator,
+                        denominator,
+                    },
+                    min_confirmations,
+                    false,
+                )
+            };
+
+        // The standard fee of the mock is 50,000 millisatoshis/byte.
+        let transaction_info = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent, 5, 4))
+            .await
+            .unwrap()
+            .transaction_info;
+        assert_eq!(
+            transaction_info.fee,
+            fee_for_vsize(62_500, transaction_info.size as u64)
+        );
+
+        bitcoin_agent.set_max_fee_per_byte(Some(55_000));
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Testnet).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_max_fee_per_byte(), Some(55_000));
+        let transaction_info = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent, 5, 4))
+            .await
+            .unwrap()
+            .transaction_info;
+        assert_eq!(
+            transaction_info.fee,
+            fee_for_vsize(55_000, transaction_info.size as u64)
+        );
+
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent, 1, 0))
+                .await,
+            Err(MultiTransferError::InvalidFeeMultiplier)
+        ));
+        // 500 millisatoshis/byte is below the minimum relay fee.
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent, 1, 100))
+                .await,
+            Err(MultiTransferError::FeeTooLow)
+        ));
+    }
+
+    /// Check that the dust payouts are rejected, that a change of exactly `DUST_THRESHOLD` is kept and that the fee is capped by `max_fee`.
+    #[tokio::test]
+    async fn check_dust_and_max_fee() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        let address = Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        let get_multi_transfer_args =
+            |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>, amount, fee| {
+                bitcoin_agent.get_multi_transfer_args(
+                    &BTreeMap::from([(address.clone(), amount)]),
+                    main_address,
+                    fee,
+                    min_confirmations,
+                    false,
+                )
+            };
+        let balance = get_init_utxos()[0].value;
+
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(
+                    bitcoin_agent,
+                    DUST_THRESHOLD - 1,
+                    Fee::Standard
+                ))
+                .await,
+            Err(MultiTransferError::DustOutput)
+        ));
+
+        let multi_transfer_args = MultiTransferArgs {
+            max_fee: Some(1_000),
+            ..get_multi_transfer_args(bitcoin_agent, 10_000, Fee::Standard)
+        };
+        match bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+        {
+            Err(MultiTransferError::FeeTooHigh { fee, max_fee }) => {
+                assert!(fee > 1_000);
+                assert_eq!(max_fee, 1_000);
+            }
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+        assert!(matches!(
+            bitcoin_agent
+                .transaction_builder()
+                .add_output(&address, 10_000)
+                .fee(Fee::Constant(10_000))
+                .max_fee(Some(5_000))
+                .build_args(),
+            Err(MultiTransferError::FeeTooHigh {
+                fee: 10_000,
+                max_fee: 5_000
+            })
+        ));
+
+        // The change of exactly `DUST_THRESHOLD` satoshis is kept as an output.
+        let fee = 10_000;
+        let multi_transfer_args = MultiTransferArgs {
+            max_fee: Some(fee),
+            ..get_multi_transfer_args(
+                bitcoin_agent,
+                balance - fee - DUST_THRESHOLD,
+                Fee::Constant(fee),
+            )
+        };
+        bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        let transaction = bitcoin_agent.management_canister.get_pending_transactions()[0].clone();
+        assert_eq!(transaction.output.len(), 2);
+        assert!(transaction.output.iter().any(|output| output.script_pubkey
+            == main_address.script_pubkey()
+            && output.value == DUST_THRESHOLD));
+    }
+
+    /// Check that target blocks fee requests are resolved with the target blocks percentiles of the Bitcoin agent.
+    #[test]
+    fn check_target_blocks() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        // With the default target blocks percentiles, 1 block maps to the 90th percentile, 3 blocks to the 60th one and 6 blocks to the 40th one.
+        for (target_blocks, expected_fee) in [
+            (0, 89_200),
+            (1, 89_200),
+            (2, 89_200),
+            (3, 59_800),
+            (6, 40_200),
+            (144, 40_200),
+        ] {
+            assert_eq!(
+                get_current_fee_test(&bitcoin_agent, FeeRequest::TargetBlocks(target_blocks))
+                    .unwrap(),
+                expected_fee
+            );
+        }
+
+        assert_eq!(
+            bitcoin_agent.set_target_blocks_percentiles(BTreeMap::from([(1, 101)])),
+            Err(InvalidPercentile)
+        );
+        bitcoin_agent
+            .set_target_blocks_percentiles(BTreeMap::from([(2, 75), (12, 25)]))
+            .unwrap();
+        for (target_blocks, expected_fee) in [(1, 74_500), (11, 74_500), (12, 25_500)] {
+            assert_eq!(
+                get_current_fee_test(&bitcoin_agent, FeeRequest::TargetBlocks(target_blocks))
+                    .unwrap(),
+                expected_fee
+            );
+        }
+
+        // The target blocks percentiles are part of the Bitcoin agent state.
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Testnet).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent.get_target_blocks_percentiles(),
+            &BTreeMap::from([(2, 75), (12, 25)])
+        );
+
+        // Without target blocks percentiles, the default ones are used.
+        bitcoin_agent
+            .set_target_blocks_percentiles(BTreeMap::default())
+            .unwrap();
+        assert_eq!(
+            get_current_fee_test(&bitcoin_agent, FeeRequest::TargetBlocks(3)).unwrap(),
+            59_800
+        );
+    }
+
+    /// Check that fee suggestions use the percentiles of the Bitcoin agent and are non-decreasing.
+    #[test]
+    fn check_fee_suggestions() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let get_fee_suggestions = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            let fee_suggestions = bitcoin_agent
+                .get_fee_suggestions_from_args_test(bitcoin_agent.get_fee_suggestions_args())
+                .unwrap();
+            assert!(fee_suggestions.economical <= fee_suggestions.standard);
+            assert!(fee_suggestions.standard <= fee_suggestions.priority);
+            fee_suggestions
+        };
+
+        // By default, the economical suggestion is the 40th percentile, the standard one the 50th one and the priority one the 90th one.
+        assert_eq!(
+            get_fee_suggestions(&bitcoin_agent),
+            FeeSuggestions {
+                economical: 40_200,
+                standard: 50_000,
+                priority: 89_200,
+            }
+        );
+
+        bitcoin_agent.set_standard_fee_percentile(75).unwrap();
+        bitcoin_agent
+            .set_target_blocks_percentiles(BTreeMap::from([(1, 100), (3, 75), (144, 0)]))
+            .unwrap();
+        assert_eq!(
+            get_fee_suggestions(&bitcoin_agent),
+            FeeSuggestions {
+                economical: 1_000,
+                standard: 74_500,
+                priority: 99_000,
+            }
+        );
+
+        // Inconsistent percentiles still lead to non-decreasing suggestions.
+        bitcoin_agent.set_standard_fee_percentile(0).unwrap();
+        bitcoin_agent
+            .set_target_blocks_percentiles(BTreeMap::from([(1, 10), (6, 50)]))
+            .unwrap();
+        assert_eq!(
+            get_fee_suggestions(&bitcoin_agent),
+            FeeSuggestions {
+                economical: 50_000,
+                standard: 50_000,
+                priority: 50_000,
+            }
+        );
+
+        bitcoin_agent.management_canister.clear_fee_percentiles();
+        assert!(matches!(
+            bitcoin_agent
+                .get_fee_suggestions_from_args_test(bitcoin_agent.get_fee_suggestions_args()),
+            Err(GetCurrentFeeError::NoFeeData)
+        ));
+    }
+
+    /// Check that `median_fee` returns the median of the given fees.
+    #[test]
+    fn check_median_fee() {
+        assert_eq!(median_fee(&[]), None);
+        assert_eq!(median_fee(&[1_000]), Some(1_000));
+        assert_eq!(median_fee(&[1_000, 2_000]), Some(1_500));
+        assert_eq!(median_fee(&[1_000, 2_000, 5_000]), Some(2_000));
+
+        // Rounds half up.
+        assert_eq!(median_fee(&[1, 2]), Some(2));
+    }
+
+    /// Check that percentiles are interpolated whatever the length of the fee percentiles vector.
+    #[test]
+    fn check_percentile_interpolation() {
+        let get_fees =
+            |percentiles: &[u8], fees: &[MillisatoshiPerByte]| -> Vec<MillisatoshiPerByte> {
+                percentiles
+                    .iter()
+                    .map(|percentile| {
+                        get_fee_from_percentiles(FeeRequest::Percentile(*percentile), fees, false)
+                            .unwrap()
+                    })
+                    .collect()
+            };
+        let percentiles = [0, 50, 100];
+
+        assert_eq!(get_fees(&percentiles, &[5_000]), vec![5_000, 5_000, 5_000]);
+
+        let fees: Vec<MillisatoshiPerByte> = (1_000..=10_000).step_by(1_000).collect();
+        assert_eq!(fees.len(), 10);
+        assert_eq!(get_fees(&percentiles, &fees), vec![1_000, 5_500, 10_000]);
+        assert_eq!(get_fees(&[25, 75], &fees), vec![3_250, 7_750]);
+
+        let fees: Vec<MillisatoshiPerByte> = (1_000..100_000).step_by(1_000).collect();
+        assert_eq!(fees.len(), 99);
+        assert_eq!(get_fees(&percentiles, &fees), vec![1_000, 50_000, 99_000]);
+        assert_eq!(get_fees(&[99], &fees), vec![98_020]);
+
+        let fees: Vec<MillisatoshiPerByte> = (0..=100_000).step_by(1_000).collect();
+        assert_eq!(fees.len(), 101);
+        assert_eq!(get_fees(&percentiles, &fees), vec![0, 50_000, 100_000]);
+        assert_eq!(get_fees(&[37], &fees), vec![37_000]);
+
+        assert!(matches!(
+            get_fee_from_percentiles(FeeRequest::Percentile(101), &fees, false),
+            Err(GetCurrentFeeError::InvalidPercentile { .. })
+        ));
+        assert!(matches!(
+            get_fee_from_percentiles(FeeRequest::Percentile(0), &[], false),
+            Err(GetCurrentFeeError::NoFeeData)
+        ));
+    }
+
+    /// Check that the fees returned by the mock follow the scripted fee percentiles, including empty and single-entry ones and a fee spike over mined blocks.
+    #[test]
+    fn check_scripted_fee_percentiles() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let get_current_fee = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            bitcoin_agent.get_current_fee_from_args_test(
+                bitcoin_agent.get_current_fee_args(FeeRequest::Standard),
+            )
+        };
+
+        bitcoin_agent
+            .management_canister
+            .set_fee_percentiles(vec![]);
+        assert!(matches!(
+            get_current_fee(bitcoin_agent),
+            Err(GetCurrentFeeError::NoFeeData)
+        ));
+
+        bitcoin_agent
+            .management_canister
+            .set_fee_percentiles(vec![7_000]);
+        assert_eq!(get_current_fee(bitcoin_agent), Ok(7_000));
+        assert_eq!(canister_mock::get_current_fees(bitcoin_agent), vec![7_000]);
+
+        // The fees spike for two blocks.
+        let base_fees: Vec<MillisatoshiPerByte> = (1_000..100_000).step_by(1_000).collect();
+        let spike_fees: Vec<MillisatoshiPerByte> = base_fees.iter().map(|fee| fee * 10).collect();
+        bitcoin_agent
+            .management_canister
+            .set_fee_percentiles(base_fees.clone());
+        let tip_height = bitcoin_agent.management_canister.get_tip_height();
+        bitcoin_agent
+            .management_canister
+            .schedule_fee_change(tip_height + 2, spike_fees);
+        bitcoin_agent
+            .management_canister
+            .schedule_fee_change(tip_height + 4, base_fees);
+        let mut current_fees = vec![];
+        for _ in 0..5 {
+            current_fees.push(get_current_fee(bitcoin_agent).unwrap());
+            mine_block(&mut bitcoin_agent.management_canister);
+        }
+        assert_eq!(current_fees, vec![50_000, 50_000, 500_000, 500_000, 50_000]);
+
+        // A fee change scheduled at a reached tip height takes effect right away.
+        let tip_height = bitcoin_agent.management_canister.get_tip_height();
+        bitcoin_agent
+            .management_canister
+            .schedule_fee_change(tip_height, vec![3_000]);
+        assert_eq!(get_current_fee(bitcoin_agent), Ok(3_000));
+    }
+
+    /// Check that percentiles are bounds-checked against the fee percentiles returned by the management canister and clamped if requested.
+    #[tokio::test]
+    async fn check_percentile_bounds() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let get_current_fee_args =
+            |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>, percentile, clamp_percentile| {
+                CurrentFeeArgs {
+                    clamp_percentile,
+                    ..bitcoin_agent.get_current_fee_args(FeeRequest::Percentile(percentile))
+                }
+            };
+
+        for fees_len in [1, 10, 99, 101] {
+            let fees: Vec<MillisatoshiPerByte> = (1..=fees_len).map(|fee| fee * 1_000).collect();
+            bitcoin_agent
+                .management_canister
+                .set_fee_percentiles(fees.clone());
+            for clamp_percentile in [false, true] {
+                assert_eq!(
+                    bitcoin_agent
+                        .get_current_fee_from_args_test(get_current_fee_args(
+                            bitcoin_agent,
+                            100,
+                            clamp_percentile
+                        ))
+                        .unwrap(),
+                    fees_len * 1_000
+                );
+            }
+            assert!(matches!(
+                bitcoin_agent.get_current_fee_from_args_test(get_current_fee_args(
+                    bitcoin_agent,
+                    101,
+                    false
+                )),
+                Err(GetCurrentFeeError::InvalidPercentile { requested: 101, available })
+                    if available as u64 == fees_len
+            ));
+            assert_eq!(
+                bitcoin_agent
+                    .get_current_fee_from_args_test(get_current_fee_args(bitcoin_agent, 101, true))
+                    .unwrap(),
+                fees_len * 1_000
+            );
+        }
+
+        // Without fee percentiles, clamping doesn't help.
+        bitcoin_agent.management_canister.clear_fee_percentiles();
+        for clamp_percentile in [false, true] {
+            assert!(matches!(
+                bitcoin_agent.get_current_fee_from_args_test(get_current_fee_args(
+                    bitcoin_agent,
+                    101,
+                    clamp_percentile
+                )),
+                Err(GetCurrentFeeError::NoFeeData)
+            ));
+        }
+
+        // Transfers report the bounds too.
+        bitcoin_agent
+            .management_canister
+            .set_fee_percentiles((1..=10).map(|fee| fee * 1_000).collect());
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            10_000,
+        )]);
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &payouts,
+            main_address,
+            Fee::Percentile(150),
+            min_confirmations,
+            false,
+        );
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::InvalidPercentile {
+                requested: 150,
+                available: 10
+            })
+        ));
+        let multi_transfer_args = MultiTransferArgs {
+            clamp_percentile: true,
+            ..bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Percentile(150),
+                min_confirmations,
+                false,
+            )
+        };
+        assert!(bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .is_ok());
     }
 
     /// Check that `multi_transfer` sends a transaction transferring the specified Bitcoin amounts to the provided addresses.
@@ -659,11 +2303,15 @@ mod tests {
 
         let payouts: BTreeMap<Address, Satoshi> = BTreeMap::from([
             (
-                Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76").unwrap(),
+                Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                    .unwrap()
+                    .assume_checked(),
                 25_000,
             ),
             (
-                Address::from_str("mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt").unwrap(),
+                Address::from_str("mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt")
+                    .unwrap()
+                    .assume_checked(),
                 50_000,
             ),
         ]);
@@ -715,20 +2363,15 @@ mod tests {
 
         let derived_address = &bitcoin_agent.add_address(&[vec![0]]).unwrap();
 
-        bitcoin_agent.management_canister.utxos_addresses.insert(
-            derived_address.clone(),
-            vec![Utxo {
-                outpoint: ic_btc_types::OutPoint {
-                    txid: vec![0; 32],
-                    vout: 1,
-                },
-                value: 250_000,
-                height: MIN_CONFIRMATIONS_UPPER_BOUND,
-            }],
-        );
+        bitcoin_agent
+            .management_canister
+            .send_to_address(derived_address, 250_000);
+        mine_block(&mut bitcoin_agent.management_canister);
 
         let payouts: BTreeMap<Address, Satoshi> = BTreeMap::from([(
-            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76").unwrap(),
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
             490_000,
         )]);
 
@@ -767,4 +2410,1949 @@ mod tests {
             );
         }
     }
+
+    /// Check that a transaction built with `TransactionBuilder` spends the chosen inputs and creates the chosen outputs in order.
+    #[tokio::test]
+    async fn check_transaction_builder() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        let main_outpoint = &get_init_utxos()[0].outpoint;
+        let derived_address = &bitcoin_agent.add_address(&[vec![0]]).unwrap();
+        let derived_txid = bitcoin_agent
+            .management_canister
+            .send_to_address(derived_address, 250_000);
+        mine_block(&mut bitcoin_agent.management_canister);
+        let derived_outpoint = &get_outpoint_ic_type(bitcoin::OutPoint::new(derived_txid, 0));
+
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        get_balance_update(bitcoin_agent, derived_address, min_confirmations);
+
+        let address_0 = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        let address_1 = &Address::from_str("mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt")
+            .unwrap()
+            .assume_checked();
+
+        // Unknown and duplicate inputs as well as dust outputs are rejected.
+        assert!(matches!(
+            bitcoin_agent
+                .transaction_builder()
+                .add_input(&crate::OutPoint {
+                    txid: vec![1; 32],
+                    vout: 0,
+                }),
+            Err(MultiTransferError::InputNotSpendable)
+        ));
+        assert!(matches!(
+            bitcoin_agent
+                .transaction_builder()
+                .add_input(main_outpoint)
+                .unwrap()
+                .add_input(main_outpoint),
+            Err(MultiTransferError::DuplicateInput)
+        ));
+        assert!(matches!(
+            bitcoin_agent
+                .transaction_builder()
+                .add_output(address_0, DUST_THRESHOLD - 1)
+                .build_args(),
+            Err(MultiTransferError::DustOutput)
+        ));
+
+        // Spends both UTXOs without any change, the derived address one first.
+        let outputs = vec![
+            TxOut {
+                script_pubkey: address_1.script_pubkey(),
+                value: 200_000,
+            },
+            TxOut {
+                script_pubkey: address_0.script_pubkey(),
+                value: 150_000,
+            },
+            TxOut {
+                script_pubkey: address_1.script_pubkey(),
+                value: 140_000,
+            },
+        ];
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_input(derived_outpoint)
+            .unwrap()
+            .add_input(main_outpoint)
+            .unwrap()
+            .add_output(address_1, 200_000)
+            .add_output(address_0.script_pubkey(), 150_000)
+            .add_output(address_1, 140_000)
+            .fee(Fee::Constant(10_000))
+            .build_args()
+            .unwrap();
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+
+        let transaction = bitcoin_agent.management_canister.get_pending_transactions()[0].clone();
+        assert_eq!(
+            TransactionID::from(transaction.txid()),
+            multi_transfer_result.transaction_info.id
+        );
+        assert_eq!(
+            transaction
+                .input
+                .iter()
+                .map(|input| get_outpoint_ic_type(input.previous_output))
+                .collect::<Vec<_>>(),
+            vec![derived_outpoint.clone(), main_outpoint.clone()]
+        );
+        assert_eq!(transaction.output, outputs);
+
+        bitcoin_agent
+            .apply_multi_transfer_result(&multi_transfer_result)
+            .unwrap();
+
+        // The spent UTXOs can't be added again to a transaction.
+        assert!(matches!(
+            bitcoin_agent.transaction_builder().add_input(main_outpoint),
+            Err(MultiTransferError::InputNotSpendable)
+        ));
+
+        mine_block(&mut bitcoin_agent.management_canister);
+
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, main_address, min_confirmations),
+            0
+        );
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, derived_address, min_confirmations),
+            0
+        );
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, address_0, min_confirmations),
+            150_000
+        );
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, address_1, min_confirmations),
+            340_000
+        );
+    }
+
+    /// Check the previews of the calls made with the arguments structures, including the signatures of the inputs of a built transaction.
+    #[tokio::test]
+    async fn check_call_previews() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        let derived_address = &bitcoin_agent.add_address(&[vec![0]]).unwrap();
+        let derived_txid = bitcoin_agent
+            .management_canister
+            .send_to_address(derived_address, 250_000);
+        mine_block(&mut bitcoin_agent.management_canister);
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        get_balance_update(bitcoin_agent, derived_address, min_confirmations);
+        let cycles_cost_config = bitcoin_agent.get_cycles_cost_config();
+        let management_canister = Principal::management_canister();
+
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_args(main_address, 1)
+                .unwrap()
+                .preview(),
+            CallPreview {
+                method: ManagementCanisterMethod::BitcoinGetUtxos,
+                principal: management_canister,
+                cycles: cycles_cost_config.get_utxos,
+                decoded_args_summary: format!(
+                    "address: {}, network: testnet, min_confirmations: 1",
+                    main_address
+                ),
+            }
+        );
+        assert_eq!(
+            bitcoin_agent.get_current_fees_args().unwrap().preview(),
+            CallPreview {
+                method: ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+                principal: management_canister,
+                cycles: cycles_cost_config.get_fees,
+                decoded_args_summary: "network: testnet".to_string(),
+            }
+        );
+
+        // The input of a built transaction is previewed with its signature.
+        let address_0 = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_input(&get_outpoint_ic_type(bitcoin::OutPoint::new(
+                derived_txid,
+                0,
+            )))
+            .unwrap()
+            .add_output(address_0, 200_000)
+            .fee(Fee::Constant(10_000))
+            .build_args()
+            .unwrap();
+        assert_eq!(
+            multi_transfer_args.preview(),
+            vec![
+                CallPreview {
+                    method: ManagementCanisterMethod::BitcoinGetUtxos,
+                    principal: management_canister,
+                    cycles: cycles_cost_config.get_utxos,
+                    decoded_args_summary: format!(
+                        "address: {}, network: testnet, min_confirmations: 0",
+                        main_address
+                    ),
+                },
+                CallPreview {
+                    method: ManagementCanisterMethod::SignWithEcdsa,
+                    principal: management_canister,
+                    cycles: cycles_cost_config.sign_with_ecdsa,
+                    decoded_args_summary: format!(
+                        "key_name: {}, derivation_path: 00, input: {}:0, value: 250000, address: {}",
+                        multi_transfer_args.key_name, derived_txid, derived_address
+                    ),
+                },
+                CallPreview {
+                    method: ManagementCanisterMethod::BitcoinSendTransaction,
+                    principal: management_canister,
+                    cycles: cycles_cost_config.send_tx,
+                    decoded_args_summary: format!(
+                        "network: testnet, payouts: 1, total_amount: 200000, change_address: {}, fee: Constant(10000), inputs: {}:0",
+                        main_address, derived_txid
+                    ),
+                },
+            ]
+        );
+
+        // The fee percentiles are retrieved for a percentile fee, the inputs being selected later.
+        let call_previews = bitcoin_agent
+            .get_multi_transfer_args(
+                &BTreeMap::from([(address_0.clone(), 100_000)]),
+                main_address,
+                Fee::Standard,
+                min_confirmations,
+                false,
+            )
+            .preview();
+        assert_eq!(
+            call_previews
+                .iter()
+                .map(|call_preview| call_preview.method)
+                .collect::<Vec<_>>(),
+            vec![
+                ManagementCanisterMethod::BitcoinGetUtxos,
+                ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+                ManagementCanisterMethod::BitcoinSendTransaction,
+            ]
+        );
+        assert!(call_previews[2]
+            .decoded_args_summary
+            .ends_with("fee: Standard, inputs: selected automatically"));
+    }
+
+    /// Check that script payouts are validated and pay the exact given scripts.
+    #[tokio::test]
+    async fn check_script_payouts() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        // Non-standard scripts are only allowed on request and too long scripts are always rejected.
+        let op_true_script = ScriptBuf::from(vec![0x51]);
+        assert!(matches!(
+            bitcoin_agent
+                .transaction_builder()
+                .add_output(op_true_script.clone(), 10_000)
+                .build_args(),
+            Err(MultiTransferError::NonStandardScript)
+        ));
+        assert!(bitcoin_agent
+            .transaction_builder()
+            .add_output(op_true_script, 10_000)
+            .allow_nonstandard(true)
+            .build_args()
+            .is_ok());
+        assert!(matches!(
+            bitcoin_agent
+                .transaction_builder()
+                .add_output(ScriptBuf::from(vec![0x51; MAX_SCRIPT_SIZE + 1]), 10_000)
+                .allow_nonstandard(true)
+                .build_args(),
+            Err(MultiTransferError::ScriptTooLong)
+        ));
+
+        // P2WSH script of `tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7` (source: https://github.com/bitcoin/bips/blob/master/bip-0173.mediawiki#test-vectors).
+        let p2wsh_script =
+            hex::decode("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262")
+                .unwrap();
+        let script_payout = ScriptPayout {
+            script_pubkey: p2wsh_script.clone(),
+            amount: 50_000,
+        };
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        let multi_transfer_args = MultiTransferArgs {
+            script_payouts: vec![script_payout],
+            ..bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::PerByte(1_000),
+                min_confirmations,
+                false,
+            )
+        };
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        assert_eq!(multi_transfer_result.script_payout_vouts, vec![1]);
+
+        // The fee accounts for the script length.
+        let transaction_info = &multi_transfer_result.transaction_info;
+        assert!(
+            transaction_info.size as u64 <= transaction_info.fee
+                && transaction_info.fee <= transaction_info.size as u64 + 2
+        );
+
+        let transaction = bitcoin_agent.management_canister.get_pending_transactions()[0].clone();
+        let mut expected_output_bytes = 50_000u64.to_le_bytes().to_vec();
+        expected_output_bytes.push(p2wsh_script.len() as u8);
+        expected_output_bytes.extend(p2wsh_script);
+        assert_eq!(serialize(&transaction.output[1]), expected_output_bytes);
+    }
+
+    /// Check that `multi_transfer` refuses to build transactions exceeding the weight or inputs limits.
+    #[tokio::test]
+    async fn check_transaction_limits() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        let derived_address = &bitcoin_agent.add_address(&[vec![0]]).unwrap();
+        bitcoin_agent
+            .management_canister
+            .send_to_address(derived_address, 250_000);
+        mine_block(&mut bitcoin_agent.management_canister);
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        get_balance_update(bitcoin_agent, derived_address, min_confirmations);
+
+        // Spending both UTXOs is required.
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            400_000,
+        )]);
+        let get_multi_transfer_args = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Constant(10_000),
+                min_confirmations,
+                false,
+            )
+        };
+
+        let multi_transfer_args = MultiTransferArgs {
+            max_inputs: 1,
+            ..get_multi_transfer_args(bitcoin_agent)
+        };
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::TransactionTooLarge { inputs: 2, .. })
+        ));
+
+        // A two inputs and two outputs P2PKH transaction weighs about 4 * 374 weight units.
+        let multi_transfer_args = MultiTransferArgs {
+            max_tx_weight: 1_000,
+            ..get_multi_transfer_args(bitcoin_agent)
+        };
+        match bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+        {
+            Err(MultiTransferError::TransactionTooLarge { weight, inputs }) => {
+                assert!((4 * 372..=4 * 376).contains(&weight));
+                assert_eq!(inputs, 2);
+            }
+            _ => panic!("The transaction should exceed the maximum weight."),
+        }
+
+        // The default limits allow the transaction.
+        assert!(bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .is_ok());
+    }
+
+    /// Check that the UTXOs are selected within the inputs and weight limits, the largest ones being preferred when the first ones exceed them.
+    #[test]
+    fn check_select_utxos_limits() {
+        let bitcoin_agent = agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = bitcoin_agent.get_main_address();
+        let get_candidate_utxo = |vout, value| {
+            (
+                main_address.clone(),
+                Utxo {
+                    outpoint: crate::OutPoint {
+                        txid: vec![1; 32],
+                        vout,
+                    },
+                    value,
+                    height: 0,
+                },
+            )
+        };
+        // The first UTXOs only cover the amount with 6 inputs, whereas the last one covers it alone.
+        let candidate_utxos: Vec<_> = (0..5)
+            .map(|vout| get_candidate_utxo(vout, 10_000))
+            .chain([
+                get_candidate_utxo(5, 100_000),
+                get_candidate_utxo(6, 40_000),
+            ])
+            .collect();
+        let get_multi_transfer_args = || {
+            bitcoin_agent.get_multi_transfer_args(
+                &BTreeMap::default(),
+                &main_address,
+                Fee::Standard,
+                0,
+                false,
+            )
+        };
+        let get_selected_vouts = |multi_transfer_args: &MultiTransferArgs, required_amount| {
+            select_utxos(multi_transfer_args, &candidate_utxos, required_amount, 0)
+                .unwrap()
+                .iter()
+                .map(|(_, utxo)| utxo.outpoint.vout)
+                .collect::<Vec<_>>()
+        };
+
+        // The first UTXOs are selected when they fit within the limits.
+        let multi_transfer_args = get_multi_transfer_args();
+        assert_eq!(
+            get_selected_vouts(&multi_transfer_args, 55_000),
+            [0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(get_selected_vouts(&multi_transfer_args, 30_000), [0, 1, 2]);
+
+        // The largest UTXOs are selected when the first ones exceed the inputs limit.
+        let max_inputs_args = MultiTransferArgs {
+            max_inputs: 3,
+            ..get_multi_transfer_args()
+        };
+        assert_eq!(get_selected_vouts(&max_inputs_args, 30_000), [0, 1, 2]);
+        assert_eq!(get_selected_vouts(&max_inputs_args, 55_000), [5]);
+        assert_eq!(get_selected_vouts(&max_inputs_args, 130_000), [5, 6]);
+
+        // Likewise when they exceed the weight limit, which only fits 2 P2PKH inputs.
+        let max_tx_weight_args = MultiTransferArgs {
+            max_tx_weight: 3 * get_max_input_weight(&main_address) - 1,
+            ..get_multi_transfer_args()
+        };
+        assert_eq!(get_selected_vouts(&max_tx_weight_args, 20_000), [0, 1]);
+        assert_eq!(get_selected_vouts(&max_tx_weight_args, 30_000), [5]);
+
+        // Without any selection within the limits, the fewest UTXOs covering the amount are selected.
+        assert_eq!(get_selected_vouts(&max_inputs_args, 160_000), [5, 6, 0, 1]);
+        assert!(matches!(
+            select_utxos(&multi_transfer_args, &candidate_utxos, 200_000, 0),
+            Err(MultiTransferError::InsufficientBalance)
+        ));
+    }
+
+    /// Check that the selection scope restricts the addresses whose UTXOs are spent.
+    #[tokio::test]
+    async fn check_selection_scope() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        let main_outpoint = &get_init_utxos()[0].outpoint;
+        let derived_address = &bitcoin_agent.add_address(&[vec![0]]).unwrap();
+        let derived_txid = bitcoin_agent
+            .management_canister
+            .send_to_address(derived_address, 20_000);
+        mine_block(&mut bitcoin_agent.management_canister);
+        let derived_outpoint = &get_outpoint_ic_type(bitcoin::OutPoint::new(derived_txid, 0));
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        get_balance_update(bitcoin_agent, derived_address, min_confirmations);
+
+        let address = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        let get_spent_outpoints = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            bitcoin_agent
+                .management_canister
+                .get_pending_transactions()
+                .last()
+                .unwrap()
+                .input
+                .iter()
+                .map(|input| get_outpoint_ic_type(input.previous_output))
+                .collect::<BTreeSet<_>>()
+        };
+
+        // The main address covers the transaction on its own.
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_output(address, 100_000)
+            .fee(Fee::Constant(10_000))
+            .selection_scope(SelectionScope::PreferSingleAddress)
+            .build_args()
+            .unwrap();
+        bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_spent_outpoints(bitcoin_agent),
+            BTreeSet::from([main_outpoint.clone()])
+        );
+
+        // No single address covers the transaction.
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_output(address, 255_000)
+            .fee(Fee::Constant(10_000))
+            .selection_scope(SelectionScope::SingleAddressOnly)
+            .build_args()
+            .unwrap();
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::SingleAddressSelectionFailed)
+        ));
+
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_output(address, 255_000)
+            .fee(Fee::Constant(10_000))
+            .selection_scope(SelectionScope::PreferSingleAddress)
+            .build_args()
+            .unwrap();
+        bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_spent_outpoints(bitcoin_agent),
+            BTreeSet::from([main_outpoint.clone(), derived_outpoint.clone()])
+        );
+
+        // Given inputs of several addresses can't be spent with `SelectionScope::SingleAddressOnly`.
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_input(main_outpoint)
+            .unwrap()
+            .add_input(derived_outpoint)
+            .unwrap()
+            .add_output(address, 100_000)
+            .fee(Fee::Constant(10_000))
+            .selection_scope(SelectionScope::SingleAddressOnly)
+            .build_args()
+            .unwrap();
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::SingleAddressSelectionFailed)
+        ));
+    }
+
+    /// Check that the management canister rejections are reported with the rejecting method and classified as retryable or not.
+    #[tokio::test]
+    async fn check_multi_transfer_rejections() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        let rejections = [
+            (
+                ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+                RejectionCode::SysTransient,
+                "Canister is overloaded.",
+                true,
+            ),
+            (
+                ManagementCanisterMethod::SignWithEcdsa,
+                RejectionCode::CanisterReject,
+                "Insufficient cycles attached.",
+                false,
+            ),
+            (
+                ManagementCanisterMethod::BitcoinSendTransaction,
+                RejectionCode::CanisterReject,
+                "Cannot decode transaction.",
+                false,
+            ),
+            (
+                ManagementCanisterMethod::BitcoinSendTransaction,
+                RejectionCode::SysTransient,
+                "Canister is overloaded.",
+                true,
+            ),
+        ];
+        for (method, rejection_code, message, is_retryable) in rejections {
+            bitcoin_agent
+                .management_canister
+                .reject_next_call(method, rejection_code, message);
+            let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Standard,
+                min_confirmations,
+                false,
+            );
+            let multi_transfer_error = bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await
+                .unwrap_err();
+            assert_eq!(multi_transfer_error.is_retryable(), is_retryable);
+            match multi_transfer_error {
+                MultiTransferError::ManagementCanisterReject {
+                    method: rejecting_method,
+                    rejection_code: rejecting_code,
+                    message: rejection_message,
+                } => {
+                    assert_eq!(rejecting_method, method);
+                    assert_eq!(rejecting_code, rejection_code);
+                    assert_eq!(rejection_message, message);
+                }
+                // The rejected signatures are reported along with their inputs.
+                MultiTransferError::SigningFailed { failed_inputs } => assert_eq!(
+                    failed_inputs,
+                    vec![InputSigningFailure {
+                        input: 0,
+                        method,
+                        rejection_code,
+                        message: message.to_string(),
+                    }]
+                ),
+                _ => panic!("The transfer should be rejected by the management canister."),
+            }
+        }
+
+        // Without any rejection, the transfer succeeds.
+        assert!(!MultiTransferError::InsufficientBalance.is_retryable());
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &payouts,
+            main_address,
+            Fee::Standard,
+            min_confirmations,
+            false,
+        );
+        assert!(bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .is_ok());
+    }
+
+    /// Check that the failures injected in the mock are reported with the error types of the calls to the management canister, a rejected broadcast being distinguished from rejected signatures.
+    #[tokio::test]
+    async fn check_injected_failures() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        let rejection = |rejection_code, message: &str| (rejection_code, message.to_string());
+
+        bitcoin_agent.management_canister.inject_failure(
+            MockOp::GetUtxos,
+            1,
+            rejection(RejectionCode::CanisterReject, "Invalid address."),
+        );
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_from_args_test(
+                    bitcoin_agent
+                        .get_utxos_args(main_address, min_confirmations)
+                        .unwrap()
+                )
+                .unwrap_err(),
+            GetUtxosError::ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                String::from("Invalid address.")
+            )
+        );
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        bitcoin_agent.management_canister.inject_failure(
+            MockOp::GetFees,
+            1,
+            rejection(
+                RejectionCode::CanisterReject,
+                "Insufficient cycles attached.",
+            ),
+        );
+        assert_eq!(
+            bitcoin_agent
+                .get_current_fees_from_args_test(bitcoin_agent.get_current_fees_args().unwrap())
+                .unwrap_err(),
+            ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                String::from("Insufficient cycles attached.")
+            )
+        );
+        assert_eq!(
+            canister_mock::get_current_fees(bitcoin_agent),
+            bitcoin_agent
+                .management_canister
+                .internal_get_current_fees()
+        );
+
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        let get_multi_transfer_args = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Standard,
+                min_confirmations,
+                false,
+            )
+        };
+
+        bitcoin_agent.management_canister.inject_failure(
+            MockOp::GetUtxos,
+            1,
+            rejection(RejectionCode::SysTransient, "Too many requests."),
+        );
+        let multi_transfer_error = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap_err();
+        assert!(multi_transfer_error.is_retryable());
+        assert_eq!(
+            multi_transfer_error,
+            MultiTransferError::ManagementCanisterReject {
+                method: ManagementCanisterMethod::BitcoinGetUtxos,
+                rejection_code: RejectionCode::SysTransient,
+                message: String::from("Too many requests."),
+            }
+        );
+
+        bitcoin_agent.management_canister.inject_failure(
+            MockOp::SendTransaction,
+            1,
+            rejection(RejectionCode::CanisterReject, "Cannot decode transaction."),
+        );
+        let multi_transfer_error = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap_err();
+        assert!(!multi_transfer_error.is_retryable());
+        assert_eq!(
+            multi_transfer_error,
+            MultiTransferError::ManagementCanisterReject {
+                method: ManagementCanisterMethod::BitcoinSendTransaction,
+                rejection_code: RejectionCode::CanisterReject,
+                message: String::from("Cannot decode transaction."),
+            }
+        );
+
+        bitcoin_agent.management_canister.inject_failure(
+            MockOp::Sign,
+            1,
+            rejection(
+                RejectionCode::SysTransient,
+                "Signing request queue is full.",
+            ),
+        );
+        let multi_transfer_error = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap_err();
+        assert!(multi_transfer_error.is_retryable());
+        assert_eq!(
+            multi_transfer_error,
+            MultiTransferError::SigningFailed {
+                failed_inputs: vec![InputSigningFailure {
+                    input: 0,
+                    method: ManagementCanisterMethod::SignWithEcdsa,
+                    rejection_code: RejectionCode::SysTransient,
+                    message: String::from("Signing request queue is full."),
+                }]
+            }
+        );
+
+        // Once the injected failures are consumed, the transfer succeeds.
+        assert!(bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .is_ok());
+    }
+
+    /// Check that `apply_multi_transfer_result` rejects a result with malformed addresses without modifying the Bitcoin agent.
+    #[tokio::test]
+    async fn check_malformed_multi_transfer_result() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        let payout_address = Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        bitcoin_agent.queue_payout(&payout_address, 25_000);
+        let multi_transfer_args = bitcoin_agent.get_flush_args(Fee::Standard, main_address);
+        let mut multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        let state = bitcoin_agent.get_state();
+
+        let malformed_addresses = [
+            // Truncated address.
+            String::from("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o7"),
+            // Invalid checksum.
+            String::from("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o77"),
+            String::new(),
+        ];
+        for malformed_address in malformed_addresses {
+            let address_using_primitives = (malformed_address.clone(), Network::Testnet);
+            multi_transfer_result
+                .generated_utxos_addresses
+                .insert(address_using_primitives.clone(), get_init_utxos());
+            assert_eq!(
+                bitcoin_agent.apply_multi_transfer_result(&multi_transfer_result),
+                Err(ApplyMultiTransferResultError::InvalidAddress(
+                    malformed_address.clone()
+                ))
+            );
+            multi_transfer_result
+                .generated_utxos_addresses
+                .remove(&address_using_primitives);
+
+            multi_transfer_result
+                .transaction_info
+                .utxos_addresses
+                .insert(address_using_primitives.clone(), get_init_utxos());
+            assert_eq!(
+                bitcoin_agent.apply_multi_transfer_result(&multi_transfer_result),
+                Err(ApplyMultiTransferResultError::InvalidAddress(
+                    malformed_address
+                ))
+            );
+            multi_transfer_result
+                .transaction_info
+                .utxos_addresses
+                .remove(&address_using_primitives);
+        }
+
+        // UTXOs spent by an address which isn't managed by the Bitcoin agent.
+        let unmanaged_address = get_address_using_primitives(&payout_address);
+        multi_transfer_result
+            .transaction_info
+            .utxos_addresses
+            .insert(unmanaged_address.clone(), get_init_utxos());
+        assert_eq!(
+            bitcoin_agent.apply_multi_transfer_result(&multi_transfer_result),
+            Err(ApplyMultiTransferResultError::AddressNotTracked(
+                unmanaged_address.0.clone()
+            ))
+        );
+        multi_transfer_result
+            .transaction_info
+            .utxos_addresses
+            .remove(&unmanaged_address);
+
+        // The rejected results neither flushed the payout queue nor cached any output.
+        assert_eq!(bitcoin_agent.get_state(), state);
+        assert_eq!(bitcoin_agent.queued_total(), 25_000);
+
+        bitcoin_agent
+            .apply_multi_transfer_result(&multi_transfer_result)
+            .unwrap();
+        assert_eq!(bitcoin_agent.queued_total(), 0);
+    }
+
+    /// Check that an empty fee percentiles vector is reported and that `multi_transfer` then uses the fallback fee.
+    #[tokio::test]
+    async fn check_no_fee_data() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        bitcoin_agent.management_canister.clear_fee_percentiles();
+
+        assert!(matches!(
+            get_current_fee_test(bitcoin_agent, FeeRequest::Standard),
+            Err(GetCurrentFeeError::NoFeeData)
+        ));
+        assert!(matches!(
+            get_current_fee_test(bitcoin_agent, FeeRequest::Median),
+            Err(GetCurrentFeeError::NoFeeData)
+        ));
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        let address = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_output(address, 25_000)
+            .fallback_fee_per_byte(None)
+            .build_args()
+            .unwrap();
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::NoFeeData)
+        ));
+
+        let multi_transfer_args = bitcoin_agent
+            .transaction_builder()
+            .add_output(address, 25_000)
+            .build_args()
+            .unwrap();
+        let transaction_info = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap()
+            .transaction_info;
+        assert_eq!(
+            transaction_info.fee,
+            fee_for_vsize(DEFAULT_FALLBACK_FEE_PER_BYTE, transaction_info.size as u64)
+        );
+    }
+
+    /// Check that the UTXOs and fee percentiles retrievals rejected because of a transient error are retried according to the retry policy of the Bitcoin agent.
+    #[test]
+    fn check_call_retries() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let overloaded =
+            |bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>, method, calls| {
+                bitcoin_agent.management_canister.reject_next_calls(
+                    method,
+                    RejectionCode::SysTransient,
+                    "Canister is overloaded.",
+                    calls,
+                )
+            };
+
+        // By default, a rejected call isn't retried.
+        assert_eq!(bitcoin_agent.get_retry_policy().max_attempts, 1);
+        overloaded(bitcoin_agent, ManagementCanisterMethod::BitcoinGetUtxos, 1);
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+                .err()
+                .unwrap(),
+            GetUtxosError::ManagementCanisterReject(
+                RejectionCode::SysTransient,
+                String::from("Canister is overloaded.")
+            )
+        );
+        let get_utxos_result = bitcoin_agent
+            .get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+            .unwrap();
+        assert_eq!(get_utxos_result.attempts, 1);
+
+        bitcoin_agent.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            ..DEFAULT_RETRY_POLICY
+        });
+        // Two transient rejections followed by a success.
+        overloaded(bitcoin_agent, ManagementCanisterMethod::BitcoinGetUtxos, 2);
+        let get_utxos_result = bitcoin_agent
+            .get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+            .unwrap();
+        assert_eq!(get_utxos_result.attempts, 3);
+        assert_eq!(get_utxos_result.result.utxos, get_init_utxos());
+        overloaded(
+            bitcoin_agent,
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            2,
+        );
+        let current_fees = bitcoin_agent
+            .get_current_fees_from_args_test(bitcoin_agent.get_current_fees_args().unwrap())
+            .unwrap();
+        assert_eq!(current_fees.attempts, 3);
+        overloaded(
+            bitcoin_agent,
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            2,
+        );
+        assert!(bitcoin_agent
+            .get_current_fee_from_args_test(bitcoin_agent.get_current_fee_args(FeeRequest::Fast))
+            .is_ok());
+
+        // Transient rejections exhausting the attempts.
+        overloaded(
+            bitcoin_agent,
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            3,
+        );
+        assert!(bitcoin_agent
+            .get_fee_suggestions_from_args_test(bitcoin_agent.get_fee_suggestions_args())
+            .is_err());
+
+        // A rejection not retried by the retry policy.
+        bitcoin_agent.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            retry_on: |rejection_code| rejection_code == RejectionCode::CanisterError,
+        });
+        overloaded(bitcoin_agent, ManagementCanisterMethod::BitcoinGetUtxos, 1);
+        assert!(bitcoin_agent
+            .get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+            .is_err());
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+                .unwrap()
+                .attempts,
+            1
+        );
+    }
+
+    /// Check that the signatures rejected because of a transient error are retried according to the retry configuration of the Bitcoin agent.
+    #[tokio::test]
+    async fn check_sign_retries() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        let get_multi_transfer_args = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Standard,
+                min_confirmations,
+                false,
+            )
+        };
+
+        // By default, a rejected signature isn't retried.
+        assert_eq!(bitcoin_agent.get_sign_retry_config(), DEFAULT_RETRY_CONFIG);
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::SignWithEcdsa,
+            RejectionCode::SysTransient,
+            "Signing request queue is full.",
+            1,
+        );
+        assert!(bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .is_err());
+
+        bitcoin_agent.set_sign_retry_config(RetryConfig { max_attempts: 3 });
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Testnet).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent.get_sign_retry_config(),
+            RetryConfig { max_attempts: 3 }
+        );
+
+        // Two transient rejections followed by a success.
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::SignWithEcdsa,
+            RejectionCode::SysTransient,
+            "Signing request queue is full.",
+            2,
+        );
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap();
+        assert_eq!(multi_transfer_result.sign_attempts, 3);
+        bitcoin_agent
+            .apply_multi_transfer_result(&multi_transfer_result)
+            .unwrap();
+
+        // Transient rejections exhausting the attempts.
+        mine_block(&mut bitcoin_agent.management_canister);
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::SignWithEcdsa,
+            RejectionCode::SysTransient,
+            "Signing request queue is full.",
+            3,
+        );
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+                .await,
+            Err(MultiTransferError::SigningFailed { failed_inputs })
+                if failed_inputs[0].rejection_code == RejectionCode::SysTransient
+        ));
+
+        // A non-transient rejection isn't retried.
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::SignWithEcdsa,
+            RejectionCode::CanisterReject,
+            "Signing request queue is full.",
+            1,
+        );
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+                .await,
+            Err(MultiTransferError::SigningFailed { failed_inputs })
+                if failed_inputs[0].rejection_code == RejectionCode::CanisterReject
+        ));
+
+        // Without any rejection, each input is signed with a single call.
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap();
+        assert_eq!(
+            multi_transfer_result.sign_attempts as usize,
+            multi_transfer_result
+                .transaction_info
+                .utxos_addresses
+                .values()
+                .map(Vec::len)
+                .sum::<usize>()
+        );
+    }
+
+    /// Check that the signature failures of a single input are reported for this input only and that, once retried, the transaction spends all the inputs and is broadcast once.
+    #[tokio::test]
+    async fn check_partial_sign_failures() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let mut addresses = vec![bitcoin_agent.get_main_address()];
+        let mut outpoints = vec![get_init_utxos()[0].outpoint.clone()];
+        for index in 0..4 {
+            let address = bitcoin_agent.add_address(&[vec![index]]).unwrap();
+            let txid = bitcoin_agent
+                .management_canister
+                .send_to_address(&address, 50_000);
+            outpoints.push(get_outpoint_ic_type(bitcoin::OutPoint::new(txid, 0)));
+            addresses.push(address);
+        }
+        mine_block(&mut bitcoin_agent.management_canister);
+        for address in &addresses {
+            get_balance_update(bitcoin_agent, address, min_confirmations);
+        }
+
+        let get_multi_transfer_args = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            outpoints
+                .iter()
+                .fold(
+                    bitcoin_agent.transaction_builder(),
+                    |transaction_builder, outpoint| {
+                        transaction_builder.add_input(outpoint).unwrap()
+                    },
+                )
+                .add_output(
+                    Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                        .unwrap()
+                        .assume_checked(),
+                    400_000,
+                )
+                .fee(Fee::Constant(10_000))
+                .build_args()
+                .unwrap()
+        };
+
+        // Only the input spent from the failing derivation path is reported.
+        let failing_path = vec![vec![2]];
+        bitcoin_agent
+            .management_canister
+            .fail_sign_for_paths(vec![failing_path.clone()], 2);
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+                .await,
+            Err(MultiTransferError::SigningFailed { failed_inputs })
+                if failed_inputs.len() == 1
+                    && failed_inputs[0].input == 3
+                    && failed_inputs[0].rejection_code == RejectionCode::SysTransient
+        ));
+        assert!(bitcoin_agent
+            .management_canister
+            .get_pending_transactions()
+            .is_empty());
+
+        // The input fails twice and then succeeds.
+        bitcoin_agent
+            .management_canister
+            .fail_sign_for_paths(vec![failing_path], 2);
+        bitcoin_agent.set_sign_retry_config(RetryConfig { max_attempts: 3 });
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap();
+        assert_eq!(multi_transfer_result.sign_attempts, 7);
+
+        let pending_transactions = bitcoin_agent.management_canister.get_pending_transactions();
+        assert_eq!(pending_transactions.len(), 1);
+        let transaction = &pending_transactions[0];
+        assert_eq!(
+            transaction
+                .input
+                .iter()
+                .map(|input| get_outpoint_ic_type(input.previous_output))
+                .collect::<Vec<_>>(),
+            outpoints
+        );
+        assert!(transaction
+            .input
+            .iter()
+            .all(|input| !input.script_sig.is_empty()));
+        assert_eq!(
+            TransactionID::from(transaction.txid()),
+            multi_transfer_result.transaction_info.id
+        );
+    }
+
+    /// Check that `multi_transfer` reports the cycles spent by all its calls to the management canister.
+    #[tokio::test]
+    async fn check_multi_transfer_cycles_spent() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            25_000,
+        )]);
+        // The tip height and the fees are retrieved, then the single input is signed and the transaction is sent.
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &payouts,
+            main_address,
+            Fee::Standard,
+            min_confirmations,
+            false,
+        );
+        let cycles_cost_config = multi_transfer_args.cycles_cost_config;
+        assert_eq!(
+            cycles_cost_config,
+            CyclesCostConfig::for_network(bitcoin::Network::Testnet)
+        );
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        assert_eq!(
+            multi_transfer_result.cycles_spent,
+            cycles_cost_config.get_utxos
+                + cycles_cost_config.get_fees
+                + cycles_cost_config.sign_with_ecdsa
+                + cycles_cost_config.get_send_transaction_cost_cycles(
+                    multi_transfer_result.transaction_info.size as usize
+                )
+        );
+        bitcoin_agent
+            .apply_multi_transfer_result(&multi_transfer_result)
+            .unwrap();
+
+        // A constant fee doesn't require retrieving the fees.
+        // The cycles attached to the calls follow the configuration of the management canister, e.g. for a key held by the 34-node signing subnet.
+        let cycles_cost_config = CyclesCostConfig {
+            sign_with_ecdsa: SIGN_34_NODE_COST_CYCLES,
+            send_tx: 2 * cycles_cost_config.send_tx,
+            ..cycles_cost_config
+        };
+        bitcoin_agent
+            .management_canister
+            .set_cycles_cost_config(cycles_cost_config);
+        // The initial UTXO being spent, the main address is funded again for the second transfer.
+        bitcoin_agent
+            .management_canister
+            .fund_address(main_address, get_init_balance());
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &payouts,
+            main_address,
+            Fee::Constant(10_000),
+            min_confirmations,
+            false,
+        );
+        let multi_transfer_result = bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args)
+            .await
+            .unwrap();
+        assert_eq!(
+            multi_transfer_result.cycles_spent,
+            cycles_cost_config.get_utxos
+                + SIGN_34_NODE_COST_CYCLES
+                + cycles_cost_config.get_send_transaction_cost_cycles(
+                    multi_transfer_result.transaction_info.size as usize
+                )
+        );
+    }
+
+    /// Checks that each input of `transaction` is a Taproot key path spend of the given previous output with a valid signature.
+    fn verify_p2tr_key_spends(transaction: &Transaction, prevouts: &[TxOut]) {
+        let secp256k1 = Secp256k1::verification_only();
+        let mut sighash_cache = SighashCache::new(transaction);
+        for (index, input) in transaction.input.iter().enumerate() {
+            assert!(input.script_sig.is_empty());
+            let witness = input.witness.to_vec();
+            assert_eq!(witness.len(), 1);
+            assert_eq!(witness[0].len(), 64);
+            let sighash = sighash_cache
+                .taproot_key_spend_signature_hash(
+                    index,
+                    &Prevouts::All(prevouts),
+                    SCHNORR_SIG_HASH_TYPE,
+                )
+                .unwrap();
+            // The witness program of a P2TR output is the tweaked public key.
+            let output_key =
+                XOnlyPublicKey::from_slice(&prevouts[index].script_pubkey.as_bytes()[2..]).unwrap();
+            secp256k1
+                .verify_schnorr(
+                    &Signature::from_slice(&witness[0]).unwrap(),
+                    &Message::from_slice(sighash.as_byte_array()).unwrap(),
+                    &output_key,
+                )
+                .unwrap();
+        }
+    }
+
+    /// Check that the UTXOs received by a P2TR main address are spent with valid Taproot key path signatures.
+    #[tokio::test]
+    async fn check_p2tr_spend() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2tr);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        assert_eq!(
+            main_address.address_type(),
+            Some(bitcoin::AddressType::P2tr)
+        );
+
+        let mut outputs = BTreeMap::from([(
+            OutPoint {
+                txid: Txid::from_slice(&[0; 32]).unwrap(),
+                vout: 0,
+            },
+            TxOut {
+                script_pubkey: main_address.script_pubkey(),
+                value: get_init_balance(),
+            },
+        )]);
+        let payouts = BTreeMap::from([(main_address.clone(), 25_000)]);
+        // The second transaction spends the outputs received from the first one.
+        for _ in 0..2 {
+            get_balance_update(bitcoin_agent, main_address, min_confirmations);
+            let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Constant(10_000),
+                min_confirmations,
+                false,
+            );
+            let cycles_cost_config = multi_transfer_args.cycles_cost_config;
+            let multi_transfer_result = bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await
+                .unwrap();
+            assert_eq!(
+                multi_transfer_result.cycles_spent,
+                cycles_cost_config.get_utxos
+                    + multi_transfer_result.sign_attempts as u128
+                        * cycles_cost_config.sign_with_schnorr
+                    + cycles_cost_config.get_send_transaction_cost_cycles(
+                        multi_transfer_result.transaction_info.size as usize
+                    )
+            );
+            bitcoin_agent
+                .apply_multi_transfer_result(&multi_transfer_result)
+                .unwrap();
+
+            let transaction =
+                bitcoin_agent.management_canister.get_pending_transactions()[0].clone();
+            let prevouts: Vec<TxOut> = transaction
+                .input
+                .iter()
+                .map(|input| outputs[&input.previous_output].clone())
+                .collect();
+            verify_p2tr_key_spends(&transaction, &prevouts);
+
+            let txid = transaction.txid();
+            outputs.extend(
+                transaction
+                    .output
+                    .into_iter()
+                    .enumerate()
+                    .map(|(vout, output)| {
+                        (
+                            OutPoint {
+                                txid,
+                                vout: vout as u32,
+                            },
+                            output,
+                        )
+                    }),
+            );
+            mine_block(&mut bitcoin_agent.management_canister);
+        }
+
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, main_address, min_confirmations),
+            get_init_balance() - 2 * 10_000
+        );
+    }
+
+    /// Check that an invalid derivation path read from the state is rejected before signing any input.
+    #[tokio::test]
+    async fn check_invalid_stored_derivation_path() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        bitcoin_agent
+            .ecdsa_pub_key_addresses
+            .get_mut(main_address)
+            .unwrap()
+            .derivation_path = DerivationPath::new_unchecked(vec![vec![0x80, 0, 0, 0]]);
+
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &BTreeMap::from([(main_address.clone(), 25_000)]),
+            main_address,
+            Fee::Constant(10_000),
+            min_confirmations,
+            false,
+        );
+        assert!(matches!(
+            bitcoin_agent
+                .multi_transfer_from_args_test(multi_transfer_args)
+                .await,
+            Err(MultiTransferError::InvalidDerivationPath(
+                DerivationPathError::HardenedElement { index: 0 }
+            ))
+        ));
+        assert!(bitcoin_agent
+            .management_canister
+            .get_pending_transactions()
+            .is_empty());
+    }
+
+    /// Checks that the inputs of `transaction` spend outputs of the P2PKH `address` with strict DER signatures with a low S value.
+    fn verify_p2pkh_spends(transaction: &Transaction, address: &Address) {
+        let secp256k1 = Secp256k1::verification_only();
+        for (index, input) in transaction.input.iter().enumerate() {
+            let pushes: Vec<&[u8]> = input
+                .script_sig
+                .instructions()
+                .map(|instruction| match instruction.unwrap() {
+                    Instruction::PushBytes(bytes) => bytes.as_bytes(),
+                    Instruction::Op(_) => panic!("Unexpected opcode in the script signature."),
+                })
+                .collect();
+            let (sig_with_hashtype, public_key) = (pushes[0], pushes[1]);
+            assert_eq!(
+                Address::p2pkh(
+                    &bitcoin::PublicKey::from_slice(public_key).unwrap(),
+                    address.network
+                ),
+                *address
+            );
+            assert_eq!(
+                *sig_with_hashtype.last().unwrap(),
+                SIG_HASH_TYPE.to_u32() as u8
+            );
+            // `from_der` only accepts strict DER encodings.
+            let signature =
+                EcdsaSignature::from_der(&sig_with_hashtype[..sig_with_hashtype.len() - 1])
+                    .unwrap();
+            let mut normalized_signature = signature;
+            normalized_signature.normalize_s();
+            assert_eq!(normalized_signature, signature);
+
+            let sighash = SighashCache::new(transaction)
+                .legacy_signature_hash(index, &address.script_pubkey(), SIG_HASH_TYPE.to_u32())
+                .unwrap();
+            secp256k1
+                .verify_ecdsa(
+                    &Message::from_slice(sighash.as_byte_array()).unwrap(),
+                    &signature,
+                    &PublicKey::from_slice(public_key).unwrap(),
+                )
+                .unwrap();
+        }
+    }
+
+    /// Check that the high S values returned by the signer are normalized in the sent transaction.
+    #[tokio::test]
+    async fn check_low_s_signatures() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.management_canister.high_s_signatures = true;
+
+        // The mock signer actually returns high S values.
+        let mut high_s_signature = EcdsaSignature::from_compact(
+            &bitcoin_agent
+                .management_canister
+                .internal_sign_with_ecdsa_high_s(&[], &[1; 32]),
+        )
+        .unwrap();
+        let signature = high_s_signature;
+        high_s_signature.normalize_s();
+        assert_ne!(high_s_signature, signature);
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        canister_mock::multi_transfer(
+            bitcoin_agent,
+            &BTreeMap::from([(main_address.clone(), 25_000)]),
+            main_address,
+            Fee::Constant(10_000),
+            min_confirmations,
+            false,
+        )
+        .await;
+
+        verify_p2pkh_spends(
+            &bitcoin_agent.management_canister.get_pending_transactions()[0],
+            main_address,
+        );
+    }
+
+    /// Funds the main address of a new mock with UTXOs of generated txids, makes two chained transfers actually signed and returns the broadcast transactions.
+    async fn get_broadcast_transactions(address_type: AddressType) -> Vec<Vec<u8>> {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &address_type);
+        bitcoin_agent.management_canister.high_s_signatures = true;
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        for value in [30_000, 40_000, 50_000] {
+            bitcoin_agent
+                .management_canister
+                .fund_address(main_address, value);
+        }
+        let payee = &bitcoin_agent.add_address(&[vec![1]]).unwrap();
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        for amount in [250_000, 20_000] {
+            canister_mock::multi_transfer(
+                bitcoin_agent,
+                &BTreeMap::from([(payee.clone(), amount)]),
+                main_address,
+                Fee::Standard,
+                min_confirmations,
+                false,
+            )
+            .await;
+        }
+
+        bitcoin_agent
+            .management_canister
+            .get_pending_transactions()
+            .iter()
+            .map(serialize)
+            .collect()
+    }
+
+    /// Check that the same scenario broadcasts byte-identical transactions from a run to another.
+    #[tokio::test]
+    async fn check_deterministic_transactions() {
+        for address_type in [AddressType::P2pkh, AddressType::P2tr] {
+            let broadcast_transactions = get_broadcast_transactions(address_type).await;
+            assert_eq!(broadcast_transactions.len(), 2);
+            assert_eq!(
+                get_broadcast_transactions(address_type).await,
+                broadcast_transactions
+            );
+        }
+    }
+
+    /// Builds the arguments of a transfer for a new mock managing many addresses, few of which are funded, and returns them with the broadcast transaction.
+    /// If `whole_state`, the arguments carry the ECDSA public keys and the UTXOs states of all the managed addresses, as they used to.
+    async fn get_many_addresses_transfer(whole_state: bool) -> (MultiTransferArgs, Vec<u8>) {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.management_canister.high_s_signatures = true;
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        for index in 0..80 {
+            let address = &bitcoin_agent.add_address(&[vec![index]]).unwrap();
+            if index % 16 == 0 {
+                bitcoin_agent
+                    .management_canister
+                    .fund_address(address, 30_000);
+            }
+            get_balance_update(bitcoin_agent, address, min_confirmations);
+        }
+        let mut multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &BTreeMap::from([(
+                Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                    .unwrap()
+                    .assume_checked(),
+                100_000,
+            )]),
+            main_address,
+            Fee::Constant(10_000),
+            min_confirmations,
+            false,
+        );
+        if whole_state {
+            multi_transfer_args.ecdsa_pub_key_addresses =
+                bitcoin_agent.ecdsa_pub_key_addresses.clone();
+            multi_transfer_args.utxos_state_addresses = bitcoin_agent.utxos_state_addresses.clone();
+        }
+        bitcoin_agent
+            .multi_transfer_from_args_test(multi_transfer_args.clone())
+            .await
+            .unwrap();
+
+        let broadcast_transaction = serialize(
+            bitcoin_agent
+                .management_canister
+                .get_pending_transactions()
+                .last()
+                .unwrap(),
+        );
+        (multi_transfer_args, broadcast_transaction)
+    }
+
+    /// Check that the arguments of a transfer only carry the addresses that can be spent from, the broadcast transaction being the same as with the whole state.
+    #[tokio::test]
+    async fn check_spendable_addresses_args() {
+        let (multi_transfer_args, broadcast_transaction) = get_many_addresses_transfer(false).await;
+        let (whole_state_multi_transfer_args, whole_state_broadcast_transaction) =
+            get_many_addresses_transfer(true).await;
+        assert_eq!(broadcast_transaction, whole_state_broadcast_transaction);
+
+        // Only the 5 funded addresses out of the 81 managed ones are carried.
+        assert_eq!(
+            whole_state_multi_transfer_args.utxos_state_addresses.len(),
+            81
+        );
+        assert_eq!(multi_transfer_args.utxos_state_addresses.len(), 5);
+        assert!(multi_transfer_args
+            .ecdsa_pub_key_addresses
+            .keys()
+            .eq(multi_transfer_args.utxos_state_addresses.keys()));
+        assert!(
+            candid::encode_one(&multi_transfer_args).unwrap().len() * 2
+                < candid::encode_one(&whole_state_multi_transfer_args)
+                    .unwrap()
+                    .len()
+        );
+    }
+
+    /// Check that the UTXOs of an address derived with a 6-byte path element are spent with signatures of its derived key.
+    #[tokio::test]
+    async fn check_long_path_element_spend() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        // The mock actually signs the transaction instead of rubber-stamping the signatures.
+        bitcoin_agent.management_canister.high_s_signatures = true;
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        let derived_address = &bitcoin_agent
+            .add_address(&[vec![8, 0, 2, 8, 0, 2]])
+            .unwrap();
+        bitcoin_agent
+            .management_canister
+            .fund_address(derived_address, get_init_balance());
+        get_balance_update(bitcoin_agent, derived_address, min_confirmations);
+        canister_mock::multi_transfer(
+            bitcoin_agent,
+            &BTreeMap::from([(main_address.clone(), 25_000)]),
+            derived_address,
+            Fee::Constant(10_000),
+            min_confirmations,
+            false,
+        )
+        .await;
+
+        verify_p2pkh_spends(
+            &bitcoin_agent.management_canister.get_pending_transactions()[0],
+            derived_address,
+        );
+    }
+
+    /// Check that the inputs are signed concurrently up to the concurrency limit, the signatures being assembled in the order of the inputs and the rejected signatures being reported together.
+    #[tokio::test(start_paused = true)]
+    async fn check_concurrent_signing() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.management_canister.high_s_signatures = true;
+
+        let min_confirmations = 0;
+        let main_address = &bitcoin_agent.get_main_address();
+        bitcoin_agent
+            .management_canister
+            .chain()
+            .utxos_addresses
+            .insert(
+                main_address.clone(),
+                (0..30)
+                    .map(|vout| Utxo {
+                        outpoint: crate::OutPoint {
+                            txid: vec![1; 32],
+                            vout,
+                        },
+                        value: 10_000,
+                        height: MIN_CONFIRMATIONS_UPPER_BOUND,
+                    })
+                    .collect(),
+            );
+        get_balance_update(bitcoin_agent, main_address, min_confirmations);
+        // The transaction spends the 30 UTXOs.
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            285_000,
+        )]);
+        let get_multi_transfer_args = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+            bitcoin_agent.get_multi_transfer_args(
+                &payouts,
+                main_address,
+                Fee::Constant(10_000),
+                min_confirmations,
+                false,
+            )
+        };
+
+        // Each signature call takes a second.
+        bitcoin_agent.management_canister.sign_latency = Duration::from_secs(1);
+        assert_eq!(
+            bitcoin_agent.get_sign_concurrency(),
+            DEFAULT_SIGN_CONCURRENCY
+        );
+        for (sign_concurrency, elapsed_secs) in [(1, 30), (DEFAULT_SIGN_CONCURRENCY, 4)] {
+            bitcoin_agent.set_sign_concurrency(sign_concurrency);
+            bitcoin_agent
+                .management_canister
+                .max_pending_signatures
+                .store(0, Ordering::SeqCst);
+            let start = Instant::now();
+            let multi_transfer_result = bitcoin_agent
+                .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+                .await
+                .unwrap();
+            assert_eq!(start.elapsed(), Duration::from_secs(elapsed_secs));
+            assert_eq!(
+                bitcoin_agent
+                    .management_canister
+                    .max_pending_signatures
+                    .load(Ordering::SeqCst),
+                sign_concurrency
+            );
+            assert_eq!(multi_transfer_result.sign_attempts, 30);
+            verify_p2pkh_spends(
+                bitcoin_agent
+                    .management_canister
+                    .get_pending_transactions()
+                    .last()
+                    .unwrap(),
+                main_address,
+            );
+        }
+
+        // The signatures of the other inputs are requested anyway.
+        bitcoin_agent.management_canister.sign_latency = Duration::ZERO;
+        bitcoin_agent.management_canister.reject_next_calls(
+            ManagementCanisterMethod::SignWithEcdsa,
+            RejectionCode::SysTransient,
+            "Signing request queue is full.",
+            2,
+        );
+        let multi_transfer_error = bitcoin_agent
+            .multi_transfer_from_args_test(get_multi_transfer_args(bitcoin_agent))
+            .await
+            .unwrap_err();
+        assert!(multi_transfer_error.is_retryable());
+        match multi_transfer_error {
+            MultiTransferError::SigningFailed { failed_inputs } => assert_eq!(
+                failed_inputs
+                    .iter()
+                    .map(|failed_input| failed_input.input)
+                    .collect::<Vec<_>>(),
+                vec![0, 1]
+            ),
+            _ => panic!("The signatures of the first two inputs should be rejected."),
+        }
+    }
+
+    /// Check that a transfer with `min_confirmations = 0` spends the unconfirmed change of a previous one, both transactions being mined in the same block.
+    #[tokio::test]
+    async fn check_chained_zero_confirmation_transfers() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let payee_address = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        let fee_amount = 1_000;
+        get_balance_update(bitcoin_agent, main_address, 0);
+
+        let first_transaction_info = canister_mock::multi_transfer(
+            bitcoin_agent,
+            &BTreeMap::from([(payee_address.clone(), 100_000)]),
+            main_address,
+            Fee::Constant(fee_amount),
+            0,
+            false,
+        )
+        .await;
+        let first_txid = Txid::from(first_transaction_info.id);
+        // The mock only returns the unconfirmed change, the initial UTXO being spent by the pending transaction.
+        let utxos = bitcoin_agent
+            .management_canister
+            .internal_get_utxos(main_address, 0)
+            .utxos;
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].outpoint.txid, first_txid.to_byte_array().to_vec());
+        assert_eq!(utxos[0].value, get_init_balance() - 100_000 - fee_amount);
+
+        get_balance_update(bitcoin_agent, main_address, 0);
+        let second_transaction_info = canister_mock::multi_transfer(
+            bitcoin_agent,
+            &BTreeMap::from([(payee_address.clone(), 50_000)]),
+            main_address,
+            Fee::Constant(fee_amount),
+            0,
+            false,
+        )
+        .await;
+        assert_eq!(
+            second_transaction_info
+                .utxos_addresses
+                .values()
+                .flatten()
+                .map(|utxo| utxo.outpoint.txid.clone())
+                .collect::<Vec<_>>(),
+            vec![first_txid.to_byte_array().to_vec()]
+        );
+        let pending_transactions = bitcoin_agent.management_canister.get_pending_transactions();
+        assert_eq!(pending_transactions.len(), 2);
+        assert_eq!(
+            pending_transactions[1].input[0].previous_output.txid,
+            first_txid
+        );
+
+        let expected_balance = get_init_balance() - 150_000 - 2 * fee_amount;
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent, main_address, 0),
+            expected_balance
+        );
+        let get_payee_balance = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>,
+                                 min_confirmations| {
+            bitcoin_agent
+                .management_canister
+                .internal_get_utxos(payee_address, min_confirmations)
+                .utxos
+                .iter()
+                .map(|utxo| utxo.value)
+                .sum::<Satoshi>()
+        };
+        assert_eq!(get_payee_balance(bitcoin_agent, 0), 150_000);
+        // The confirmed view is unchanged until the transactions are mined.
+        assert_eq!(
+            bitcoin_agent
+                .management_canister
+                .internal_get_utxos(main_address, 1)
+                .utxos,
+            get_init_utxos()
+        );
+        assert_eq!(get_payee_balance(bitcoin_agent, 1), 0);
+
+        mine_block(&mut bitcoin_agent.management_canister);
+        assert!(bitcoin_agent
+            .management_canister
+            .get_pending_transactions()
+            .is_empty());
+        assert_eq!(
+            bitcoin_agent
+                .management_canister
+                .internal_get_utxos(main_address, 1)
+                .utxos
+                .iter()
+                .map(|utxo| utxo.value)
+                .sum::<Satoshi>(),
+            expected_balance
+        );
+        assert_eq!(get_payee_balance(bitcoin_agent, 1), 150_000);
+    }
+
+    /// Check that the transactions are timestamped by the time source of the Bitcoin agent, the logical clock of the management canister mock by default.
+    #[tokio::test]
+    async fn check_transaction_timestamp() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        get_balance_update(bitcoin_agent, main_address, 0);
+        let payouts = BTreeMap::from([(
+            Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+                .unwrap()
+                .assume_checked(),
+            10_000,
+        )]);
+
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .advance_time(1_000);
+        let transaction_info = canister_mock::multi_transfer(
+            bitcoin_agent,
+            &payouts,
+            main_address,
+            Fee::Standard,
+            0,
+            false,
+        )
+        .await;
+        assert_eq!(transaction_info.timestamp, MOCK_START_TIME + 1_000);
+
+        struct FixedTimeSource;
+        impl TimeSource for FixedTimeSource {
+            fn now(&self) -> u64 {
+                42
+            }
+        }
+        bitcoin_agent.set_time_source(Arc::new(FixedTimeSource));
+        // The initial UTXO being spent, the main address is funded again for the second transfer.
+        bitcoin_agent
+            .management_canister
+            .fund_address(main_address, get_init_balance());
+        get_balance_update(bitcoin_agent, main_address, 0);
+        let transaction_info = canister_mock::multi_transfer(
+            bitcoin_agent,
+            &payouts,
+            main_address,
+            Fee::Standard,
+            0,
+            false,
+        )
+        .await;
+        assert_eq!(transaction_info.timestamp, 42);
+    }
 }
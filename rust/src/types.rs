@@ -1,82 +1,293 @@
 //! Types used to support the candid API.
 
-use crate::{OutPoint, Satoshi, Utxo};
-use bitcoin::{hashes, util, Address, Transaction};
-use ic_cdk::{
-    api::call::RejectionCode,
-    export::{
-        candid::{CandidType, Deserialize},
-        serde::Serialize,
-        Principal,
+use crate::{
+    canister_common::{
+        GET_CURRENT_FEE_PERCENTILES_COST_CYCLES, GET_UTXOS_COST_CYCLES,
+        SEND_TRANSACTION_BASE_COST_CYCLES, SEND_TRANSACTION_COST_CYCLES_PER_BYTE,
+        SIGN_13_NODE_COST_CYCLES, SIGN_34_NODE_COST_CYCLES,
     },
+    logging::{format_derivation_path, LogSink},
+    time_source::TimeSource,
+    utxo_management::to_explorer_hex,
+};
+use bitcoin::{address, hashes, hashes::Hash as _, key, Address, Transaction, Txid};
+use candid::{
+    types::{Serializer as CandidSerializer, Type},
+    CandidType, Deserialize, Principal,
+};
+use ic_cdk::api::{
+    call::RejectionCode,
+    management_canister::bitcoin::{self as management_canister_bitcoin, BitcoinNetwork},
+};
+use serde::{de, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+    str::FromStr,
+    sync::Arc,
 };
-use std::collections::{BTreeMap, HashSet};
 
 pub type Millisatoshi = u64;
 
+/// An amount of bitcoins in satoshis.
+pub type Satoshi = u64;
+
+/// A fee rate in millisatoshis per byte, e.g. a fee percentile returned by `bitcoin_get_current_fee_percentiles`.
+pub type MillisatoshiPerByte = u64;
+
+/// The outpoint of a UTXO: the txid of the transaction creating it, in the internal byte order of `bitcoin::Txid`, and the index of the output in this transaction.
+/// It's Candid-encoded like the `Outpoint` of the management canister API of `ic-cdk`, which it converts from and to, and like the outpoints of the states saved with `ic-btc-types`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OutPoint {
+    pub txid: Vec<u8>,
+    pub vout: u32,
+}
+
+impl From<management_canister_bitcoin::Outpoint> for OutPoint {
+    fn from(outpoint: management_canister_bitcoin::Outpoint) -> Self {
+        OutPoint {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+        }
+    }
+}
+
+impl From<OutPoint> for management_canister_bitcoin::Outpoint {
+    fn from(outpoint: OutPoint) -> Self {
+        management_canister_bitcoin::Outpoint {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+        }
+    }
+}
+
+/// An unspent transaction output: its outpoint, its value and the height of the block containing the transaction creating it.
+/// Like `OutPoint`, it converts from and to the `Utxo` of the management canister API of `ic-cdk`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub value: Satoshi,
+    pub height: u32,
+}
+
+impl From<management_canister_bitcoin::Utxo> for Utxo {
+    fn from(utxo: management_canister_bitcoin::Utxo) -> Self {
+        Utxo {
+            outpoint: utxo.outpoint.into(),
+            value: utxo.value,
+            height: utxo.height,
+        }
+    }
+}
+
+impl From<Utxo> for management_canister_bitcoin::Utxo {
+    fn from(utxo: Utxo) -> Self {
+        management_canister_bitcoin::Utxo {
+            outpoint: utxo.outpoint.into(),
+            value: utxo.value,
+            height: utxo.height,
+        }
+    }
+}
+
+/// Converts from and to `bitcoin::Network` and the `BitcoinNetwork` of the management canister API of `ic-cdk`, see the `From` and `TryFrom` implementations.
+/// It's displayed in lowercase, e.g. `mainnet`, and parsed case-insensitively from the same names, e.g. from the init arguments of a canister.
 #[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Eq, Hash, PartialOrd, Ord, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum Network {
     Mainnet,
     Testnet,
+    /// The signet, which isn't served by the Bitcoin API of the IC but by `ManagementCanisterRpc` and the management canister mock.
+    Signet,
     #[cfg(locally)]
     Regtest,
 }
 
 /// Contains the result of a `get_utxos` call.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
 pub struct GetUtxosResponse {
     pub utxos: Vec<Utxo>,
     pub tip_height: u32,
 }
 
+/// Contains the result of a `get_block_headers` call: the 80-byte consensus encoded headers of the requested heights.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct GetBlockHeadersResponse {
+    pub tip_height: u32,
+    pub block_headers: Vec<Vec<u8>>,
+}
+
 /// ECDSA public key and chain code.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct EcdsaPubKey {
     pub public_key: Vec<u8>,
     pub chain_code: Vec<u8>,
-    pub derivation_path: Vec<Vec<u8>>,
+    pub derivation_path: DerivationPath,
+}
+
+/// The maximum number of elements of a derivation path accepted by the management canister.
+pub const MAX_DERIVATION_PATH_LENGTH: usize = 255;
+
+/// The maximum number of bytes of a derivation path element.
+pub const MAX_DERIVATION_PATH_ELEMENT_LENGTH: usize = 255;
+
+/// A derivation path of ECDSA public keys, checked with `DerivationPath::new`.
+/// It's encoded like a `Vec<Vec<u8>>`, so that states storing derivation paths as such remain readable.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[serde(transparent)]
+pub struct DerivationPath(Vec<Vec<u8>>);
+
+impl DerivationPath {
+    /// Returns the derivation path made of the given elements if it's valid and an error otherwise.
+    pub fn new(elements: Vec<Vec<u8>>) -> Result<Self, DerivationPathError> {
+        let derivation_path = Self(elements);
+        derivation_path.validate()?;
+        Ok(derivation_path)
+    }
+
+    /// Returns the derivation path made of the given elements without checking it, like when it's deserialized.
+    pub(crate) fn new_unchecked(elements: Vec<Vec<u8>>) -> Self {
+        Self(elements)
+    }
+
+    /// Checks that the derivation path has at most `MAX_DERIVATION_PATH_LENGTH` elements of at most `MAX_DERIVATION_PATH_ELEMENT_LENGTH` bytes without any hardened BIP-32 index.
+    /// Derivation paths read from a state aren't checked when deserialized.
+    pub fn validate(&self) -> Result<(), DerivationPathError> {
+        if self.0.len() > MAX_DERIVATION_PATH_LENGTH {
+            return Err(DerivationPathError::TooLong);
+        }
+        for (index, element) in self.0.iter().enumerate() {
+            let index = index as u8;
+            if element.len() > MAX_DERIVATION_PATH_ELEMENT_LENGTH {
+                return Err(DerivationPathError::ElementTooLong { index });
+            }
+            // A 4-byte element is a BIP-32 index, which can't be hardened as children are derived from public keys.
+            if element.len() == 4 && element[0] & 0x80 != 0 {
+                return Err(DerivationPathError::HardenedElement { index });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the derivation path followed by the elements of `derivation_path` if it's valid and an error otherwise.
+    pub fn join(&self, derivation_path: &[Vec<u8>]) -> Result<Self, DerivationPathError> {
+        Self::new(
+            self.0
+                .iter()
+                .chain(derivation_path.iter())
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl std::ops::Deref for DerivationPath {
+    type Target = [Vec<u8>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<DerivationPath> for Vec<Vec<u8>> {
+    fn from(derivation_path: DerivationPath) -> Self {
+        derivation_path.0
+    }
 }
 
 /// Address types supported by the `ic-btc-library`.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+/// It's displayed in lowercase, e.g. `p2pkh`, and parsed case-insensitively from the same names.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum AddressType {
     P2pkh,
     P2sh,
     P2wpkh,
+    /// Taproot address spent with the key path, its public key being tweaked without any script as per BIP-86.
+    P2tr,
+}
+
+impl AddressType {
+    /// The address types, in the order of their declaration.
+    pub const ALL: &'static [AddressType] = &[
+        AddressType::P2pkh,
+        AddressType::P2sh,
+        AddressType::P2wpkh,
+        AddressType::P2tr,
+    ];
+}
+
+impl fmt::Display for AddressType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AddressType::P2pkh => "p2pkh",
+            AddressType::P2sh => "p2sh",
+            AddressType::P2wpkh => "p2wpkh",
+            AddressType::P2tr => "p2tr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for AddressType {
+    type Err = ParseAddressTypeError;
+
+    /// Parses the name of the address type as displayed, ignoring the case.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        AddressType::ALL
+            .iter()
+            .copied()
+            .find(|address_type| address_type.to_string().eq_ignore_ascii_case(name))
+            .ok_or_else(|| ParseAddressTypeError(name.to_string()))
+    }
 }
 
-/// Errors when processing an `get_p2*_adddress` request.
-#[derive(Debug, PartialEq)]
+/// Errors when processing an `get_p2*_adddress` request, with the message of the error of the `bitcoin` crate so that they can be Candid-encoded.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum BitcoinAddressError {
-    Hashes(hashes::error::Error),
-    UtilKey(util::key::Error),
-    UtilAddress(util::address::Error),
+    Hashes(String),
+    UtilKey(String),
+    UtilAddress(String),
 }
 
-impl From<hashes::error::Error> for BitcoinAddressError {
-    fn from(bitcoin_hashes_error: hashes::error::Error) -> Self {
-        BitcoinAddressError::Hashes(bitcoin_hashes_error)
+impl From<hashes::Error> for BitcoinAddressError {
+    fn from(bitcoin_hashes_error: hashes::Error) -> Self {
+        BitcoinAddressError::Hashes(bitcoin_hashes_error.to_string())
     }
 }
 
-impl From<util::key::Error> for BitcoinAddressError {
-    fn from(bitcoin_util_key_error: util::key::Error) -> Self {
-        BitcoinAddressError::UtilKey(bitcoin_util_key_error)
+impl From<key::Error> for BitcoinAddressError {
+    fn from(bitcoin_util_key_error: key::Error) -> Self {
+        BitcoinAddressError::UtilKey(bitcoin_util_key_error.to_string())
     }
 }
 
-impl From<util::address::Error> for BitcoinAddressError {
-    fn from(bitcoin_util_address_error: util::address::Error) -> Self {
-        BitcoinAddressError::UtilAddress(bitcoin_util_address_error)
+impl From<address::Error> for BitcoinAddressError {
+    fn from(bitcoin_util_address_error: address::Error) -> Self {
+        BitcoinAddressError::UtilAddress(bitcoin_util_address_error.to_string())
     }
 }
 
-/// Error when processing an `add_address` request.
-#[derive(CandidType, Debug, Deserialize, PartialEq)]
-pub struct DerivationPathTooLong;
+/// Errors when checking a derivation path, `index` being the position of the invalid element.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+#[non_exhaustive]
+pub enum DerivationPathError {
+    /// The derivation path has more than `MAX_DERIVATION_PATH_LENGTH` elements.
+    TooLong,
+    /// The element has more than `MAX_DERIVATION_PATH_ELEMENT_LENGTH` bytes.
+    ElementTooLong { index: u8 },
+    /// The element is a 4-byte BIP-32 index with the hardened bit set.
+    HardenedElement { index: u8 },
+}
 
 /// Contains the information which UTXOs were added and removed since a given moment.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct UtxosUpdate {
     pub added_utxos: Vec<Utxo>,
     pub removed_utxos: Vec<Utxo>,
@@ -125,27 +336,105 @@ impl UtxosUpdate {
 }
 
 /// Arguments used to call get_utxos_from_args in the agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct UtxosArgs {
     pub network: bitcoin::Network,
     pub address: bitcoin::Address,
     pub min_confirmations: u32,
     pub utxos_state: UtxosState,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub retry_policy: RetryPolicy,
+    pub bitcoin_api_target: BitcoinApiTarget,
+    /// Whether the UTXOs of zero value returned by `bitcoin_get_utxos` are accepted, the response being rejected as invalid otherwise.
+    pub allow_zero_value_utxos: bool,
+}
+
+impl UtxosArgs {
+    /// Returns the preview of the call to `bitcoin_get_utxos` made with these arguments, the next pages of UTXOs being retrieved with the same arguments.
+    pub fn preview(&self) -> CallPreview {
+        CallPreview::get_utxos(
+            self.network,
+            &self.address,
+            self.min_confirmations,
+            &self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+    }
+}
+
+/// Arguments used to call get_block_headers_from_args in the agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockHeadersArgs {
+    pub network: bitcoin::Network,
+    pub start_height: u32,
+    /// The height of the last header to retrieve, the tip height if `None`.
+    pub end_height: Option<u32>,
+    /// The cycles attached to the call, `GET_BLOCK_HEADERS_COST_CYCLES` by default.
+    pub payment: u128,
+    pub retry_policy: RetryPolicy,
+    pub bitcoin_api_target: BitcoinApiTarget,
+}
+
+impl BlockHeadersArgs {
+    /// Returns the preview of the call to `bitcoin_get_block_headers` made with these arguments.
+    pub fn preview(&self) -> CallPreview {
+        CallPreview {
+            method: ManagementCanisterMethod::BitcoinGetBlockHeaders,
+            principal: self.bitcoin_api_target.get_principal(),
+            cycles: self.payment,
+            decoded_args_summary: format!(
+                "start_height: {}, end_height: {}, network: {}",
+                self.start_height,
+                self.end_height
+                    .map_or_else(|| "tip".to_string(), |end_height| end_height.to_string()),
+                self.network
+            ),
+        }
+    }
 }
 
 /// Latest utxos retrieved at a given address.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct UtxosResult {
     pub address: bitcoin::Address,
     pub utxos: Vec<Utxo>,
     pub tip_height: u32,
 }
 
+/// The result of calls to the management canister along with the cycles spent by these calls.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct WithCost<T> {
+    pub result: T,
+    /// The cycles attached to the calls minus the refunded ones.
+    pub cycles_spent: u128,
+    /// The number of attempts made to obtain the result, more than one if rejected calls were retried according to a `RetryPolicy`.
+    pub attempts: u32,
+}
+
+impl<T> WithCost<T> {
+    /// Returns the result transformed by `f` along with the same cycles spent.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> WithCost<U> {
+        WithCost {
+            result: f(self.result),
+            cycles_spent: self.cycles_spent,
+            attempts: self.attempts,
+        }
+    }
+}
+
 /// Represents the last seen state and the unseen state UTXOs for a given `min_confirmations`.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct UtxosState {
+    /// The UTXOs made available by the last `update_state`, which are the ones spent by the transactions.
     pub seen_state: Vec<Utxo>,
+    /// The UTXOs returned by the last `get_utxos` call, which become the seen state with the next `update_state`.
     pub unseen_state: Vec<Utxo>,
+    /// The number of confirmations the UTXOs of the seen and unseen states have at least.
     pub min_confirmations: u32,
+    /// The outpoints spent by the transactions sent, which are never part of the UTXOs returned with `min_confirmations = 0`.
     pub spent_state: Vec<OutPoint>,
+    /// The UTXOs generated by the transactions sent, which are added to the UTXOs returned with `min_confirmations = 0`.
     pub generated_state: Vec<Utxo>,
 }
 
@@ -159,16 +448,96 @@ impl UtxosState {
             generated_state: vec![],
         }
     }
+
+    /// Returns true if the seen state is up to date with the unseen state and no spent or generated UTXOs are cached.
+    pub fn is_quiescent(&self) -> bool {
+        self.seen_state == self.unseen_state
+            && self.spent_state.is_empty()
+            && self.generated_state.is_empty()
+    }
+
+    /// Returns the number of UTXOs of the seen state.
+    pub fn len(&self) -> usize {
+        self.seen_state.len()
+    }
+
+    /// Returns true if the seen state has no UTXOs.
+    pub fn is_empty(&self) -> bool {
+        self.seen_state.is_empty()
+    }
+
+    /// Returns the total value of the UTXOs of the seen state.
+    pub fn value(&self) -> Satoshi {
+        get_balance_from_utxos(&self.seen_state)
+    }
 }
 
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct AddressNotTracked;
 
+/// The memory used by a Bitcoin agent, e.g. to alert from a heartbeat when its state approaches the limits of an upgrade.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct MemoryStats {
+    /// The number of managed addresses, including the watch-only ones.
+    pub addresses: u64,
+    /// The number of UTXOs of the seen and unseen states of the addresses.
+    pub utxos_cached: u64,
+    pub spent_entries: u64,
+    pub generated_entries: u64,
+    /// The approximate number of bytes of the heap used by the addresses and their UTXOs.
+    pub approx_bytes: u64,
+}
+
+/// The calls made to a method, the cycles they spent and their rejections.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Default)]
+pub struct MethodMetrics {
+    /// The number of calls, including the rejected ones.
+    pub calls: u64,
+    pub cycles_spent: u128,
+    /// The number of rejected calls by rejection code.
+    pub rejections: Vec<(RejectionCode, u64)>,
+}
+
+impl MethodMetrics {
+    /// Returns the number of calls rejected with `rejection_code`.
+    pub fn get_rejections(&self, rejection_code: RejectionCode) -> u64 {
+        self.rejections
+            .iter()
+            .find(|(code, _)| *code == rejection_code)
+            .map_or(0, |(_, rejections)| *rejections)
+    }
+}
+
+/// The metrics of the calls made by the library, e.g. to be displayed by a dashboard.
+/// It can be saved before an upgrade and restored afterwards with `BitcoinAgent::restore_metrics`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub methods: BTreeMap<ManagementCanisterMethod, MethodMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the metrics of the calls made to `method`.
+    pub fn get(&self, method: ManagementCanisterMethod) -> MethodMetrics {
+        self.methods.get(&method).cloned().unwrap_or_default()
+    }
+}
+
 /// Represents the last seen state and the unseen state balances for a given `min_confirmations`.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct BalanceUpdate {
     pub added_balance: Satoshi,
     pub removed_balance: Satoshi,
+    /// `added_balance` minus `removed_balance`, see `BalanceUpdate::get_net_change`.
+    pub net_change: i64,
+    /// The number of UTXOs added, e.g. by new payments or by payments reaching `min_confirmations`.
+    pub added_utxo_count: u32,
+    /// The number of UTXOs removed, e.g. spent or orphaned by a reorganization.
+    pub removed_utxo_count: u32,
+    /// The outpoints of the added UTXOs composing `added_balance`, e.g. of the deposits, in the order of the UTXOs update.
+    pub added_outpoints: Vec<OutPoint>,
+    /// The outpoints of the removed UTXOs composing `removed_balance`.
+    pub removed_outpoints: Vec<OutPoint>,
 }
 
 impl BalanceUpdate {
@@ -176,10 +545,32 @@ impl BalanceUpdate {
         Self {
             added_balance: 0,
             removed_balance: 0,
+            net_change: 0,
+            added_utxo_count: 0,
+            removed_utxo_count: 0,
+            added_outpoints: vec![],
+            removed_outpoints: vec![],
         }
     }
+
+    /// Returns `added_balance` minus `removed_balance`, failing if it doesn't fit in an `i64`.
+    /// It can't fail for the balances of UTXOs, which can't exceed the 21 million bitcoins that will ever exist.
+    pub fn get_net_change(
+        added_balance: Satoshi,
+        removed_balance: Satoshi,
+    ) -> Result<i64, NetChangeOverflow> {
+        let added_balance = i64::try_from(added_balance).map_err(|_| NetChangeOverflow)?;
+        let removed_balance = i64::try_from(removed_balance).map_err(|_| NetChangeOverflow)?;
+        added_balance
+            .checked_sub(removed_balance)
+            .ok_or(NetChangeOverflow)
+    }
 }
 
+/// Error when the net change of a balance update doesn't fit in an `i64`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct NetChangeOverflow;
+
 impl Default for BalanceUpdate {
     fn default() -> Self {
         Self::new()
@@ -191,59 +582,402 @@ pub(crate) fn get_balance_from_utxos(utxos: &[Utxo]) -> Satoshi {
     utxos.iter().map(|utxo| utxo.value).sum()
 }
 
+/// Returns the outpoints of the UTXOs.
+fn get_outpoints(utxos: &[Utxo]) -> Vec<OutPoint> {
+    utxos.iter().map(|utxo| utxo.outpoint.clone()).collect()
+}
+
 impl From<UtxosUpdate> for BalanceUpdate {
+    /// The net change saturates if it doesn't fit in an `i64`, which can't happen for the UTXOs checked when they are applied.
     fn from(utxos_update: UtxosUpdate) -> Self {
+        let added_balance = get_balance_from_utxos(&utxos_update.added_utxos);
+        let removed_balance = get_balance_from_utxos(&utxos_update.removed_utxos);
+        let net_change = BalanceUpdate::get_net_change(added_balance, removed_balance).unwrap_or(
+            if added_balance > removed_balance {
+                i64::MAX
+            } else {
+                i64::MIN
+            },
+        );
         Self {
-            added_balance: get_balance_from_utxos(&utxos_update.added_utxos),
-            removed_balance: get_balance_from_utxos(&utxos_update.removed_utxos),
+            added_balance,
+            removed_balance,
+            net_change,
+            added_utxo_count: utxos_update.added_utxos.len() as u32,
+            removed_utxo_count: utxos_update.removed_utxos.len() as u32,
+            added_outpoints: get_outpoints(&utxos_update.added_utxos),
+            removed_outpoints: get_outpoints(&utxos_update.removed_utxos),
         }
     }
 }
 
-pub(crate) fn from_types_network_to_bitcoin_network(network: Network) -> bitcoin::Network {
-    match network {
-        Network::Mainnet => bitcoin::Network::Bitcoin,
-        Network::Testnet => bitcoin::Network::Testnet,
+impl Network {
+    /// The available networks, in the order of their declaration.
+    pub const ALL: &'static [Network] = &[
+        Network::Mainnet,
+        Network::Testnet,
+        Network::Signet,
         #[cfg(locally)]
-        Network::Regtest => bitcoin::Network::Regtest,
+        Network::Regtest,
+    ];
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Signet => "signet",
+            #[cfg(locally)]
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    /// Parses the name of the network as displayed, ignoring the case.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Network::ALL
+            .iter()
+            .copied()
+            .find(|network| network.to_string().eq_ignore_ascii_case(name))
+            .ok_or_else(|| ParseNetworkError(name.to_string()))
+    }
+}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Mainnet => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Signet => bitcoin::Network::Signet,
+            #[cfg(locally)]
+            Network::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+}
+
+impl TryFrom<bitcoin::Network> for Network {
+    type Error = RegtestUnavailable;
+
+    /// Fails on the regtest when it isn't available, i.e. when built with `DFX_NETWORK=ic`.
+    fn try_from(network: bitcoin::Network) -> Result<Self, Self::Error> {
+        match network {
+            bitcoin::Network::Bitcoin => Ok(Network::Mainnet),
+            bitcoin::Network::Testnet => Ok(Network::Testnet),
+            bitcoin::Network::Signet => Ok(Network::Signet),
+            #[cfg(locally)]
+            bitcoin::Network::Regtest => Ok(Network::Regtest),
+            #[cfg(not(locally))]
+            bitcoin::Network::Regtest => Err(RegtestUnavailable),
+            // Other cases can't happen as `bitcoin::Network` doesn't have other networks yet despite being non-exhaustive
+            _ => panic!(),
+        }
+    }
+}
+
+impl TryFrom<Network> for BitcoinNetwork {
+    type Error = UnsupportedNetwork;
+
+    /// Fails if the Bitcoin API of the IC doesn't serve the given network, e.g. the signet.
+    fn try_from(network: Network) -> Result<Self, Self::Error> {
+        match network {
+            Network::Mainnet => Ok(BitcoinNetwork::Mainnet),
+            Network::Testnet => Ok(BitcoinNetwork::Testnet),
+            Network::Signet => Err(UnsupportedNetwork(Network::Signet)),
+            #[cfg(locally)]
+            Network::Regtest => Ok(BitcoinNetwork::Regtest),
+        }
+    }
+}
+
+impl TryFrom<BitcoinNetwork> for Network {
+    type Error = RegtestUnavailable;
+
+    /// Fails on the regtest when it isn't available, i.e. when built with `DFX_NETWORK=ic`.
+    fn try_from(network: BitcoinNetwork) -> Result<Self, Self::Error> {
+        match network {
+            BitcoinNetwork::Mainnet => Ok(Network::Mainnet),
+            BitcoinNetwork::Testnet => Ok(Network::Testnet),
+            #[cfg(locally)]
+            BitcoinNetwork::Regtest => Ok(Network::Regtest),
+            #[cfg(not(locally))]
+            BitcoinNetwork::Regtest => Err(RegtestUnavailable),
+        }
     }
 }
 
-pub(crate) fn from_bitcoin_network_to_ic_btc_types_network(
+pub(crate) fn from_types_network_to_bitcoin_network(network: Network) -> bitcoin::Network {
+    network.into()
+}
+
+/// Fails if the Bitcoin API of the IC doesn't serve the given network, e.g. the signet.
+/// Doesn't go through `Network` as the regtest is served even when it isn't available in `Network`.
+pub(crate) fn from_bitcoin_network_to_management_canister_network(
     network: bitcoin::Network,
-) -> ic_btc_types::Network {
+) -> Result<BitcoinNetwork, UnsupportedNetwork> {
     match network {
-        bitcoin::Network::Bitcoin => ic_btc_types::Network::Mainnet,
-        bitcoin::Network::Testnet => ic_btc_types::Network::Testnet,
-        bitcoin::Network::Regtest => ic_btc_types::Network::Regtest,
-        // Other cases can't happen see ManagementCanister::new
+        bitcoin::Network::Bitcoin => Ok(BitcoinNetwork::Mainnet),
+        bitcoin::Network::Testnet => Ok(BitcoinNetwork::Testnet),
+        bitcoin::Network::Regtest => Ok(BitcoinNetwork::Regtest),
+        bitcoin::Network::Signet => Err(UnsupportedNetwork(Network::Signet)),
+        // Other cases can't happen as `bitcoin::Network` doesn't have other networks yet despite being non-exhaustive
         _ => panic!(),
     }
 }
 
 pub(crate) fn from_bitcoin_network_to_types_network(network: bitcoin::Network) -> Network {
-    match network {
-        bitcoin::Network::Bitcoin => Network::Mainnet,
-        bitcoin::Network::Testnet => Network::Testnet,
-        #[cfg(locally)]
-        bitcoin::Network::Regtest => Network::Regtest,
-        // Other cases can't happen see ManagementCanister::new
-        _ => panic!(),
-    }
+    // The regtest can't be unavailable see ManagementCanister::new
+    Network::try_from(network).unwrap()
 }
 
 /// Needs to use `(String, Network)` to describe an address otherwise there is an ambiguity between testnet and regtest because of the same address prefix.
 pub type AddressUsingPrimitives = (String, Network);
 
+/// Identifier of a payout queued with `BitcoinAgent::queue_payout`.
+pub type QueueId = u64;
+
+/// Smooths the fee percentiles applied to a Bitcoin agent with an exponential moving average over the last `window` ones.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FeeSmoothing {
+    pub window: u8,
+}
+
+/// Represents the payouts waiting in the payout queue of a Bitcoin agent.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PayoutQueueState {
+    pub next_queue_id: QueueId,
+    pub queued_payouts: BTreeMap<QueueId, (AddressUsingPrimitives, Satoshi)>,
+}
+
+/// Records a rotation of the ECDSA public key of the Bitcoin agent.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct KeyRotation {
+    pub previous_ecdsa_pub_key: EcdsaPubKey,
+    /// The addresses derived from the previous key, kept as watch-only, associated with the addresses derived from the new key at the same derivation paths.
+    pub rekeyed_addresses: BTreeMap<AddressUsingPrimitives, AddressUsingPrimitives>,
+}
+
+/// The operations whose arguments can be rate limited with `BitcoinAgent::set_rate_limit`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum RateLimitedOp {
+    /// The calls to `bitcoin_get_utxos` whose arguments are returned by `BitcoinAgent::get_utxos_args`.
+    GetUtxos,
+    /// The calls to `bitcoin_get_current_fee_percentiles` whose arguments are returned by `BitcoinAgent::get_current_fees_args`.
+    GetCurrentFees,
+}
+
+/// Bounds the refreshes of the UTXOs of the managed addresses planned by each call of `BitcoinAgent::next_poll_plan`, e.g. from a timer.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PollBudget {
+    /// The maximum number of `get_utxos` calls planned.
+    pub max_calls: u32,
+    /// The number of previous calls of `next_poll_plan` during which a planned address isn't planned again, 0 allowing to plan an address at every call.
+    pub skip_recent_invocations: u32,
+}
+
+/// The rate limit of an operation along with the calls made in its current window.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RateLimitState {
+    pub max_calls_per_window: u32,
+    /// The number of blocks of a window.
+    pub window_len: u32,
+    /// The tip height the current window started at.
+    pub window_start: u32,
+    pub calls_in_window: u32,
+}
+
+/// Error when the arguments of an operation are requested while its rate limit is reached.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct RateLimited {
+    /// The tip height from which the arguments of the operation can be requested again.
+    pub retry_after: u32,
+}
+
+/// Error when the Bitcoin API of the IC is called on a network it doesn't serve, e.g. the signet.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct UnsupportedNetwork(pub Network);
+
+/// Error when parsing a `Network` from an unknown name, which is given.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct ParseNetworkError(pub String);
+
+/// Error when parsing an `AddressType` from an unknown name, which is given.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct ParseAddressTypeError(pub String);
+
+/// Error when converting the regtest of the `bitcoin` crate or of the management canister API to `Network` while it isn't available, i.e. when built with `DFX_NETWORK=ic`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct RegtestUnavailable;
+
+impl From<UnsupportedNetwork> for ManagementCanisterReject {
+    /// Reports the call as rejected by the Bitcoin API without making it.
+    fn from(UnsupportedNetwork(network): UnsupportedNetwork) -> Self {
+        ManagementCanisterReject(
+            RejectionCode::CanisterReject,
+            format!(
+                "The Bitcoin API of the IC doesn't serve the {:?} network.",
+                network
+            ),
+        )
+    }
+}
+
+impl From<ManagementCanisterReject> for GetUtxosError {
+    /// Reports the rejections of the responses exceeding the size limit of the inter-canister responses as `GetUtxosError::ResponseTooLarge`.
+    fn from(ManagementCanisterReject(rejection_code, message): ManagementCanisterReject) -> Self {
+        if is_response_too_large_rejection(&message) {
+            GetUtxosError::ResponseTooLarge { message }
+        } else {
+            GetUtxosError::ManagementCanisterReject(rejection_code, message)
+        }
+    }
+}
+
+impl From<AddressNotTracked> for GetUtxosError {
+    fn from(_: AddressNotTracked) -> Self {
+        GetUtxosError::AddressNotTracked
+    }
+}
+
+impl From<UnsupportedNetwork> for GetUtxosError {
+    fn from(unsupported_network: UnsupportedNetwork) -> Self {
+        let ManagementCanisterReject(rejection_code, message) = unsupported_network.into();
+        GetUtxosError::ManagementCanisterReject(rejection_code, message)
+    }
+}
+
+impl From<RateLimited> for ManagementCanisterReject {
+    /// Reports a rate limited call as a transient rejection made without calling the management canister, e.g. by the fee macros.
+    fn from(rate_limited: RateLimited) -> Self {
+        ManagementCanisterReject(
+            RejectionCode::SysTransient,
+            format!(
+                "Rate limited until the tip height {}.",
+                rate_limited.retry_after
+            ),
+        )
+    }
+}
+
+/// The version of the layout of `BitcoinAgentState`.
+/// It has to be incremented whenever the layout changes, along with a migration from the previous layout.
+pub const BITCOIN_AGENT_STATE_VERSION: u32 = 3;
+
 /// Represents the Bitcoin agent state used for canister upgrades.
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+/// States of previous versions are migrated when decoded with `BitcoinAgentState::decode`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct BitcoinAgentState {
+    pub version: u32,
+    pub network: Network,
+    pub main_address_type: AddressType,
+    pub ecdsa_pub_key_addresses: BTreeMap<AddressUsingPrimitives, EcdsaPubKey>,
+    pub utxos_state_addresses: BTreeMap<AddressUsingPrimitives, UtxosState>,
+    pub min_confirmations: u32,
+    pub ecdsa_pub_key: EcdsaPubKey,
+    pub ecdsa_key_name: String,
+    pub payout_queue: PayoutQueueState,
+    pub fee_cache_max_age: u32,
+    pub standard_fee_percentile: u8,
+    pub target_blocks_percentiles: BTreeMap<u8, u8>,
+    pub max_fee_per_byte: Option<MillisatoshiPerByte>,
+    pub sign_retry_config: RetryConfig,
+    pub fee_smoothing: Option<FeeSmoothing>,
+    pub fee_history: Vec<Vec<MillisatoshiPerByte>>,
+    pub key_rotations: Vec<KeyRotation>,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub sign_concurrency: u32,
+    /// The addresses whose unseen UTXOs state is the same as their non-empty seen one, as after `update_state`.
+    /// Their `unseen_state` is stored empty and is restored as a copy of their `seen_state`, so that quiescent addresses don't store their UTXOs twice.
+    pub unseen_same_as_seen_addresses: BTreeSet<AddressUsingPrimitives>,
+    /// The rate limits set with `BitcoinAgent::set_rate_limit`, `None` if there are none.
+    /// As the field is optional, the states of the version 2 are decoded with the current layout, and the CBOR encoding of a state without rate limits is the one of the version 2 but its version.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub rate_limits: Option<BTreeMap<RateLimitedOp, RateLimitState>>,
+}
+
+/// The layout of the version 1 of the Bitcoin agent state, storing the unseen UTXOs states even when they are the same as the seen ones, migrated to the version 2.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BitcoinAgentStateV1 {
+    pub version: u32,
+    pub network: Network,
+    pub main_address_type: AddressType,
+    pub ecdsa_pub_key_addresses: BTreeMap<AddressUsingPrimitives, EcdsaPubKey>,
+    pub utxos_state_addresses: BTreeMap<AddressUsingPrimitives, UtxosState>,
+    pub min_confirmations: u32,
+    pub ecdsa_pub_key: EcdsaPubKey,
+    pub ecdsa_key_name: String,
+    pub payout_queue: PayoutQueueState,
+    pub fee_cache_max_age: u32,
+    pub standard_fee_percentile: u8,
+    pub target_blocks_percentiles: BTreeMap<u8, u8>,
+    pub max_fee_per_byte: Option<MillisatoshiPerByte>,
+    pub sign_retry_config: RetryConfig,
+    pub fee_smoothing: Option<FeeSmoothing>,
+    pub fee_history: Vec<Vec<MillisatoshiPerByte>>,
+    pub key_rotations: Vec<KeyRotation>,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub sign_concurrency: u32,
+}
+
+/// The layout of the Bitcoin agent state before it was versioned, migrated to the version 1.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BitcoinAgentStateV0 {
     pub network: Network,
     pub main_address_type: AddressType,
     pub ecdsa_pub_key_addresses: BTreeMap<AddressUsingPrimitives, EcdsaPubKey>,
     pub utxos_state_addresses: BTreeMap<AddressUsingPrimitives, UtxosState>,
     pub min_confirmations: u32,
     pub ecdsa_pub_key: EcdsaPubKey,
+    pub ecdsa_key_name: String,
+    pub payout_queue: PayoutQueueState,
+    pub fee_cache_max_age: u32,
+    pub standard_fee_percentile: u8,
+    pub target_blocks_percentiles: BTreeMap<u8, u8>,
+    pub max_fee_per_byte: Option<MillisatoshiPerByte>,
+    pub sign_retry_config: RetryConfig,
+    pub fee_smoothing: Option<FeeSmoothing>,
+    pub fee_history: Vec<Vec<MillisatoshiPerByte>>,
+    pub key_rotations: Vec<KeyRotation>,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub sign_concurrency: u32,
+}
+
+/// The differences between two Bitcoin agent states, e.g. the pre-upgrade and the post-upgrade ones, returned by `BitcoinAgentState::diff`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct StateDiff {
+    /// The addresses of the other state which aren't managed by this one.
+    pub added_addresses: Vec<AddressUsingPrimitives>,
+    /// The addresses of this state which aren't managed by the other one.
+    pub removed_addresses: Vec<AddressUsingPrimitives>,
+    /// The minimum numbers of confirmations of this state and of the other one, for the addresses of both states whose ones differ.
+    pub changed_min_confirmations: BTreeMap<AddressUsingPrimitives, (u32, u32)>,
+    /// The number of UTXOs of the other state minus the one of this state, for the addresses of both states whose unseen UTXOs states differ in size.
+    pub utxo_count_deltas: BTreeMap<AddressUsingPrimitives, i64>,
+    /// The names of the other fields which differ, e.g. `"fee_history"`.
+    pub changed_fields: Vec<String>,
+}
+
+impl StateDiff {
+    /// Returns whether the compared states don't differ.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 /// The upper bound on the minimum number of confirmations supported by the Bitcoin integration.
@@ -252,63 +986,622 @@ pub const MIN_CONFIRMATIONS_UPPER_BOUND: u32 = 6;
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct MinConfirmationsTooHigh;
 
+/// The default percentile used for `Fee::Standard` and `FeeRequest::Standard`.
+pub const DEFAULT_STANDARD_FEE_PERCENTILE: u8 = 50;
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct StandardFeePercentileTooHigh;
+
+/// The default percentiles associated with confirming a transaction within a given number of blocks, used for `Fee::TargetBlocks` and `FeeRequest::TargetBlocks`.
+pub const DEFAULT_TARGET_BLOCKS_PERCENTILES: [(u8, u8); 3] = [(1, 90), (3, 60), (6, 40)];
+
+/// Returns the percentile associated with confirming a transaction within `target_blocks` blocks according to `target_blocks_percentiles`.
+/// The percentile of the largest number of blocks not exceeding `target_blocks` is used, the one of the smallest number of blocks if there isn't any.
+/// Returns `None` if `target_blocks_percentiles` is empty.
+pub(crate) fn get_target_blocks_percentile(
+    target_blocks: u8,
+    target_blocks_percentiles: &BTreeMap<u8, u8>,
+) -> Option<u8> {
+    target_blocks_percentiles
+        .range(..=target_blocks)
+        .next_back()
+        .or_else(|| target_blocks_percentiles.iter().next())
+        .map(|(_, percentile)| *percentile)
+}
+
+/// The default maximum weight of a transaction, which is the largest standard transaction weight.
+pub const DEFAULT_MAX_TX_WEIGHT: u64 = 400_000;
+
+/// The default fee in millisatoshis/byte used when the management canister doesn't have any fee data, i.e. 2 sat/B.
+pub const DEFAULT_FALLBACK_FEE_PER_BYTE: MillisatoshiPerByte = 2_000;
+
+/// The default number of blocks the tip may advance before the fees cached by a Bitcoin agent are stale.
+pub const DEFAULT_FEE_CACHE_MAX_AGE: u32 = 1;
+
+/// The default maximum number of inputs of a transaction, to bound the cycles spent signing it.
+pub const DEFAULT_MAX_INPUTS: u32 = 100;
+
+/// The default maximum number of inputs of a transaction signed concurrently, to bound the instructions spent by an execution round.
+pub const DEFAULT_SIGN_CONCURRENCY: u32 = 8;
+
 /// Error when processing an `add_address_with_parameters` request.
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
 pub enum AddAddressWithParametersError {
-    DerivationPathTooLong,
+    InvalidDerivationPath(DerivationPathError),
     MinConfirmationsTooHigh,
 }
 
-/// Errors when processing a `get_utxos` request.
-#[derive(CandidType, Debug)]
-pub enum GetUtxosError {
+/// Errors when restoring a Bitcoin agent from a corrupted `BitcoinAgentState`.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum StateRestoreError {
+    UnknownVersion(u32),
+    /// The address can't be parsed.
+    InvalidAddress(String),
+    /// The address doesn't belong to the network of the state.
+    AddressNetworkMismatch(String),
+    /// The network of the state isn't the expected one of `StateRestoreOptions`, and `allow_network_change` isn't set.
+    NetworkMismatch {
+        expected: Network,
+        found: Network,
+    },
+    /// The address is associated with multiple entries.
+    DuplicateAddress(String),
     MinConfirmationsTooHigh,
-    ManagementCanisterReject(RejectionCode, String),
+    InvalidEcdsaPubKey,
+    /// The ECDSA public key of the address isn't derived from the one of the state, or doesn't derive the address.
+    EcdsaPubKeyMismatch(String),
+    /// The address is marked as having its unseen UTXOs state the same as its seen one, but it has no UTXOs state or its stored unseen one isn't empty.
+    InvalidCompactedAddress(String),
+    /// The bytes can't be decoded with the layout of a legacy state.
+    DecodeFailed(String),
+    /// The restored Bitcoin agent has an integrity issue, reported when restoring with `verify_integrity`.
+    IntegrityIssue(IntegrityIssue),
+    /// The stable memory doesn't hold any Bitcoin agent.
+    #[cfg(feature = "stable-memory")]
+    NoStableState,
 }
 
-/// Error when processing a request to the management canister.
-#[derive(CandidType, Debug)]
-pub struct ManagementCanisterReject(pub RejectionCode, pub String);
+/// Inconsistencies of the managed addresses of a Bitcoin agent with its ECDSA public key, reported by `verify_state_integrity`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum IntegrityIssue {
+    /// The ECDSA public key of the Bitcoin agent can't be parsed, hence no address can be re-derived.
+    InvalidEcdsaPubKey,
+    /// The derivation path of the ECDSA public key of the address doesn't extend the one of the Bitcoin agent.
+    DerivationPathMismatch(String),
+    /// The ECDSA public key of the address isn't the one derived from the ECDSA public key of the Bitcoin agent at its derivation path.
+    EcdsaPubKeyMismatch(String),
+    /// The address isn't the one derived at its derivation path, e.g. because of its type or network.
+    AddressMismatch(String),
+    /// The address has an ECDSA public key but no UTXOs state.
+    MissingUtxosState(String),
+}
 
-/// Errors when processing a `get_current_fee` request.
-#[derive(CandidType, Debug)]
-pub enum GetCurrentFeeError {
-    InvalidPercentile,
-    ManagementCanisterReject(RejectionCode, String),
+/// Errors when importing a Bitcoin agent from bytes exported with `export_state_bytes`.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum StateImportError {
+    /// The bytes are shorter than the header of an exported state.
+    Truncated,
+    /// The bytes don't start with the magic of an exported state.
+    InvalidMagic,
+    UnknownVersion(u32),
+    /// The checksum doesn't match the header and the encoded state, which are hence corrupted.
+    ChecksumMismatch,
+    /// The encoded state can't be decoded, with the error message of the decoder.
+    DecodeFailed(String),
+    /// The decoded state can't be restored.
+    InvalidState(StateRestoreError),
 }
 
-impl From<ManagementCanisterReject> for GetCurrentFeeError {
-    fn from(ManagementCanisterReject(rejection_code, message): ManagementCanisterReject) -> Self {
-        GetCurrentFeeError::ManagementCanisterReject(rejection_code, message)
+/// Network used instead of the configured one by `ManagementCanisterImpl::new_with_network_override`, e.g. `NetworkOverride(Network::Regtest)` to test locally against a regtest node a canister configured for the testnet.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct NetworkOverride(pub Network);
+
+/// Options of `BitcoinAgent::from_state_with_options`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct StateRestoreOptions {
+    /// Network the restoring canister is configured for, restoring a state of another network failing unless `allow_network_change` is set.
+    pub expected_network: Network,
+    /// Whether a state of another network than `expected_network` is restored on the latter, its addresses being relabelled with it.
+    /// E.g. a state saved on the testnet is restored as a regtest Bitcoin agent to test it locally.
+    pub allow_network_change: bool,
+    /// Whether to fail with the first issue found by `verify_state_integrity` on the restored Bitcoin agent.
+    pub verify_integrity: bool,
+}
+
+impl StateRestoreOptions {
+    /// Returns the options restoring a state of `expected_network`, e.g. the network of the canister restoring the state in `post_upgrade`, without verifying its integrity.
+    pub fn new(expected_network: Network) -> Self {
+        Self {
+            expected_network,
+            allow_network_change: false,
+            verify_integrity: false,
+        }
+    }
+
+    /// Returns the options allowing a state of another network than `expected_network` to be restored on the latter.
+    pub fn allow_network_change(self) -> Self {
+        Self {
+            allow_network_change: true,
+            ..self
+        }
     }
 }
 
-/// Represents the fee request as a percentile in millisatoshis/byte over the last 10,000 transactions.
+/// Errors when inserting or restoring Bitcoin agents in an `AgentRegistry`.
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
-pub enum FeeRequest {
-    Slow,           // 25th percentile
-    Standard,       // 50th percentile
-    Fast,           // 75th percentile
-    Percentile(u8), // custom percentile
+#[non_exhaustive]
+pub enum AgentRegistryError {
+    UnknownAgent(String),
+    /// The address is already managed by the Bitcoin agent named `agent`.
+    DuplicateAddress {
+        address: String,
+        agent: String,
+    },
+    /// The state of the Bitcoin agent named `agent` can't be restored.
+    InvalidState {
+        agent: String,
+        error: StateRestoreError,
+    },
 }
 
-/// Arguments used to call get_current_fees_from_args in the agent.
-pub struct CurrentFeesArgs {
-    pub network: bitcoin::Network,
+/// Errors when applying a malformed `MultiTransferResult`, which is then rejected without modifying the Bitcoin agent.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum ApplyMultiTransferResultError {
+    /// The address can't be parsed.
+    InvalidAddress(String),
+    /// The address of spent UTXOs isn't managed by the Bitcoin agent.
+    AddressNotTracked(String),
 }
 
-/// Arguments used to call get_current_fee_from_args in the agent.
+/// Errors when processing a `get_utxos` request.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum GetUtxosError {
+    MinConfirmationsTooHigh,
+    ManagementCanisterReject(RejectionCode, String),
+    /// The UTXOs returned by `bitcoin_get_utxos` are inconsistent, e.g. with duplicated outpoints, hence aren't returned so as not to corrupt the UTXOs state of the address.
+    InvalidResponse {
+        reason: String,
+    },
+    /// A page of the UTXOs of the address exceeds the size limit of the inter-canister responses, about 2 MiB, `message` being the one of the rejection.
+    /// The UTXOs of such an address can't be retrieved, hence it shouldn't receive more payments, e.g. a derived address being used instead.
+    /// Its balance is still returned by `get_balance_from_args`, which falls back to `bitcoin_get_balance`.
+    ResponseTooLarge {
+        message: String,
+    },
+    /// The address isn't managed by the Bitcoin agent, hence its UTXOs can't be applied.
+    AddressNotTracked,
+}
+
+impl GetUtxosError {
+    /// Returns the rejection code of the management canister if the call was rejected.
+    pub(crate) fn get_rejection_code(&self) -> Option<RejectionCode> {
+        match self {
+            GetUtxosError::ManagementCanisterReject(rejection_code, _) => Some(*rejection_code),
+            GetUtxosError::MinConfirmationsTooHigh
+            | GetUtxosError::InvalidResponse { .. }
+            | GetUtxosError::ResponseTooLarge { .. }
+            | GetUtxosError::AddressNotTracked => None,
+        }
+    }
+}
+
+/// Errors when verifying an ECDSA signature, whose inputs are malformed.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+#[non_exhaustive]
+pub enum SignatureError {
+    InvalidPublicKey,
+    InvalidMessageHash,
+    InvalidSignature,
+}
+
+/// Error when processing a request to the management canister.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct ManagementCanisterReject(pub RejectionCode, pub String);
+
+/// Paid management canister methods, whose rejections are reported by `MultiTransferError` and whose cycles are reported by `CyclesReconciliation`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum ManagementCanisterMethod {
+    BitcoinGetUtxos,
+    BitcoinGetCurrentFeePercentiles,
+    SignWithEcdsa,
+    SignWithSchnorr,
+    BitcoinSendTransaction,
+    BitcoinGetBlockHeaders,
+    BitcoinGetBalance,
+}
+
+impl ManagementCanisterMethod {
+    /// Returns the name of the management canister method.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ManagementCanisterMethod::BitcoinGetUtxos => "bitcoin_get_utxos",
+            ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles => {
+                "bitcoin_get_current_fee_percentiles"
+            }
+            ManagementCanisterMethod::SignWithEcdsa => "sign_with_ecdsa",
+            ManagementCanisterMethod::SignWithSchnorr => "sign_with_schnorr",
+            ManagementCanisterMethod::BitcoinSendTransaction => "bitcoin_send_transaction",
+            ManagementCanisterMethod::BitcoinGetBlockHeaders => "bitcoin_get_block_headers",
+            ManagementCanisterMethod::BitcoinGetBalance => "bitcoin_get_balance",
+        }
+    }
+}
+
+/// The canister serving the calls to the Bitcoin API, i.e. to `bitcoin_get_utxos`, `bitcoin_get_current_fee_percentiles` and `bitcoin_send_transaction`.
+/// The Bitcoin canisters expose these methods with the same names and request types as the management canister, hence only the called principal differs.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub enum BitcoinApiTarget {
+    /// The management canister, which routes the calls to the Bitcoin canister of the network.
+    #[default]
+    ManagementCanister,
+    /// The Bitcoin canister `principal` called directly, e.g. `ghsi2-tqaaa-aaaan-aaaca-cai` on the mainnet or a locally deployed build, whose cycles costs are set with `set_cycles_cost_config`.
+    Direct { principal: Principal },
+}
+
+impl BitcoinApiTarget {
+    /// Returns the principal of the canister called.
+    pub fn get_principal(&self) -> Principal {
+        match self {
+            BitcoinApiTarget::ManagementCanister => Principal::management_canister(),
+            BitcoinApiTarget::Direct { principal } => *principal,
+        }
+    }
+}
+
+/// The cycles attached to the paid calls to the management canister.
+/// The cost of the threshold signatures depends on the size of the subnet holding the key.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CyclesCostConfig {
+    pub get_utxos: u128,
+    pub get_fees: u128,
+    /// The cycles attached to send a transaction, on top of `send_tx_per_byte` cycles per byte of the transaction.
+    pub send_tx: u128,
+    pub send_tx_per_byte: u128,
+    pub sign_with_ecdsa: u128,
+    pub sign_with_schnorr: u128,
+}
+
+impl CyclesCostConfig {
+    /// Returns the default cycles attached on the given network.
+    /// The keys of the mainnet are held by the 34-node signing subnet, the other ones by the 13-node one.
+    pub fn for_network(network: bitcoin::Network) -> Self {
+        let sign_cost_cycles = match network {
+            bitcoin::Network::Bitcoin => SIGN_34_NODE_COST_CYCLES,
+            _ => SIGN_13_NODE_COST_CYCLES,
+        };
+        Self {
+            get_utxos: GET_UTXOS_COST_CYCLES,
+            get_fees: GET_CURRENT_FEE_PERCENTILES_COST_CYCLES,
+            send_tx: SEND_TRANSACTION_BASE_COST_CYCLES,
+            send_tx_per_byte: SEND_TRANSACTION_COST_CYCLES_PER_BYTE,
+            sign_with_ecdsa: sign_cost_cycles,
+            sign_with_schnorr: sign_cost_cycles,
+        }
+    }
+
+    /// Returns the cycles to attach to send a transaction of `transaction_len` bytes.
+    pub fn get_send_transaction_cost_cycles(&self, transaction_len: usize) -> u128 {
+        self.send_tx + transaction_len as u128 * self.send_tx_per_byte
+    }
+
+    /// Returns an error if any of the cycles attached is zero or exceeds `ceiling`, e.g. to reject a mistyped update of the pricing.
+    pub fn validate(&self, ceiling: u128) -> Result<(), InvalidCyclesCostConfig> {
+        [
+            ("get_utxos", self.get_utxos),
+            ("get_fees", self.get_fees),
+            ("send_tx", self.send_tx),
+            ("send_tx_per_byte", self.send_tx_per_byte),
+            ("sign_with_ecdsa", self.sign_with_ecdsa),
+            ("sign_with_schnorr", self.sign_with_schnorr),
+        ]
+        .into_iter()
+        .try_for_each(|(cost, cycles)| {
+            if cycles == 0 {
+                Err(InvalidCyclesCostConfig::ZeroCost {
+                    cost: cost.to_string(),
+                })
+            } else if cycles > ceiling {
+                Err(InvalidCyclesCostConfig::AboveCeiling {
+                    cost: cost.to_string(),
+                    cycles,
+                    ceiling,
+                })
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+/// Errors when updating the cycles attached to the paid calls to the management canister.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[non_exhaustive]
+pub enum InvalidCyclesCostConfig {
+    /// No cycles would be attached for the `cost` field.
+    ZeroCost { cost: String },
+    /// The `cycles` of the `cost` field exceed the `ceiling` set with `set_cycles_cost_ceiling`.
+    AboveCeiling {
+        cost: String,
+        cycles: u128,
+        ceiling: u128,
+    },
+    /// The Candid blob doesn't encode a `CyclesCostConfig`.
+    InvalidCandid(String),
+}
+
+/// Errors when building a Bitcoin agent with `BitcoinAgentBuilder::build`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The minimum number of confirmations exceeds `MIN_CONFIRMATIONS_UPPER_BOUND`.
+    MinConfirmationsTooHigh,
+    /// The ECDSA key name is empty or contains other characters than ASCII alphanumerics, `_` and `-`.
+    InvalidEcdsaKeyName(String),
+    /// The standard fee percentile exceeds 100.
+    StandardFeePercentileTooHigh,
+    /// One of the target blocks percentiles exceeds 100.
+    InvalidPercentile,
+    /// The cycles cost config is rejected as by `BitcoinAgent::set_cycles_cost_config`.
+    InvalidCyclesCostConfig(InvalidCyclesCostConfig),
+    /// Two settings can't be set together, the reason being given.
+    ConflictingOptions(String),
+}
+
+/// The cycles attached to a call to the management canister and the ones actually spent by it, reported to the hook set with `set_cycles_reconciliation_hook`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct CyclesReconciliation {
+    pub method: ManagementCanisterMethod,
+    pub cycles_attached: u128,
+    pub cycles_spent: u128,
+}
+
+/// A human-readable preview of a call made with an arguments structure, e.g. to log it or to return it to an admin endpoint for approval before making it.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct CallPreview {
+    pub method: ManagementCanisterMethod,
+    /// The called canister, i.e. the management canister or the Bitcoin canister of the `BitcoinApiTarget`.
+    pub principal: Principal,
+    /// The cycles attached to the call.
+    pub cycles: u128,
+    /// The decoded arguments of the call, e.g. `address: mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76, network: testnet, min_confirmations: 1`.
+    pub decoded_args_summary: String,
+}
+
+impl CallPreview {
+    /// Returns the preview of a call to `bitcoin_get_utxos` retrieving the UTXOs of `address`.
+    pub(crate) fn get_utxos(
+        network: bitcoin::Network,
+        address: &Address,
+        min_confirmations: u32,
+        cycles_cost_config: &CyclesCostConfig,
+        bitcoin_api_target: &BitcoinApiTarget,
+    ) -> Self {
+        Self {
+            method: ManagementCanisterMethod::BitcoinGetUtxos,
+            principal: bitcoin_api_target.get_principal(),
+            cycles: cycles_cost_config.get_utxos,
+            decoded_args_summary: format!(
+                "address: {}, network: {}, min_confirmations: {}",
+                address, network, min_confirmations
+            ),
+        }
+    }
+
+    /// Returns the preview of a call to `bitcoin_get_current_fee_percentiles`.
+    pub(crate) fn get_current_fee_percentiles(
+        network: bitcoin::Network,
+        cycles_cost_config: &CyclesCostConfig,
+        bitcoin_api_target: &BitcoinApiTarget,
+    ) -> Self {
+        Self {
+            method: ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+            principal: bitcoin_api_target.get_principal(),
+            cycles: cycles_cost_config.get_fees,
+            decoded_args_summary: format!("network: {}", network),
+        }
+    }
+}
+
+/// Errors when processing a `get_current_fee` request.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum GetCurrentFeeError {
+    /// The `requested` percentile exceeds the 100th percentile, `available` being the number of fee percentiles returned by the management canister.
+    InvalidPercentile {
+        requested: u8,
+        available: u32,
+    },
+    NoFeeData,
+    ManagementCanisterReject(RejectionCode, String),
+}
+
+impl From<ManagementCanisterReject> for GetCurrentFeeError {
+    fn from(ManagementCanisterReject(rejection_code, message): ManagementCanisterReject) -> Self {
+        GetCurrentFeeError::ManagementCanisterReject(rejection_code, message)
+    }
+}
+
+/// Represents the fee request as a percentile in millisatoshis/byte over the last 10,000 transactions.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum FeeRequest {
+    Slow,             // 25th percentile
+    Standard,         // standard fee percentile of the Bitcoin agent, 50th by default
+    Fast,             // 75th percentile
+    Median,           // 50th percentile
+    Percentile(u8),   // custom percentile between 0 and 100
+    TargetBlocks(u8), // percentile to confirm within the given number of blocks
+}
+
+impl FeeRequest {
+    /// Returns the fee request where `FeeRequest::Standard` is replaced with the given standard fee percentile and `FeeRequest::TargetBlocks` with the percentile given by `target_blocks_percentiles`.
+    /// `FeeRequest::TargetBlocks` is kept if `target_blocks_percentiles` is empty.
+    pub(crate) fn resolve(
+        self,
+        standard_fee_percentile: u8,
+        target_blocks_percentiles: &BTreeMap<u8, u8>,
+    ) -> Self {
+        match self {
+            FeeRequest::Standard => FeeRequest::Percentile(standard_fee_percentile),
+            FeeRequest::TargetBlocks(target_blocks) => {
+                get_target_blocks_percentile(target_blocks, target_blocks_percentiles)
+                    .map_or(self, FeeRequest::Percentile)
+            }
+            fee_request => fee_request,
+        }
+    }
+}
+
+/// Arguments used to call get_current_fees_from_args in the agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CurrentFeesArgs {
+    pub network: bitcoin::Network,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub retry_policy: RetryPolicy,
+    pub bitcoin_api_target: BitcoinApiTarget,
+}
+
+impl CurrentFeesArgs {
+    /// Returns the preview of the call to `bitcoin_get_current_fee_percentiles` made with these arguments.
+    pub fn preview(&self) -> CallPreview {
+        CallPreview::get_current_fee_percentiles(
+            self.network,
+            &self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+    }
+}
+
+/// Arguments used to call get_current_fee_from_args in the agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CurrentFeeArgs {
     pub network: bitcoin::Network,
     pub fee_request: FeeRequest,
+    /// If true, a percentile exceeding the 100th percentile is clamped to it instead of being an error.
+    pub clamp_percentile: bool,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub retry_policy: RetryPolicy,
+    pub bitcoin_api_target: BitcoinApiTarget,
+}
+
+impl CurrentFeeArgs {
+    /// Returns the preview of the call to `bitcoin_get_current_fee_percentiles` made with these arguments.
+    pub fn preview(&self) -> CallPreview {
+        CallPreview::get_current_fee_percentiles(
+            self.network,
+            &self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+    }
+}
+
+/// Arguments used to call get_fee_suggestions_from_args in the agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FeeSuggestionsArgs {
+    pub network: bitcoin::Network,
+    pub economical_percentile: u8,
+    pub standard_percentile: u8,
+    pub priority_percentile: u8,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub retry_policy: RetryPolicy,
+    pub bitcoin_api_target: BitcoinApiTarget,
+}
+
+impl FeeSuggestionsArgs {
+    /// Returns the preview of the call to `bitcoin_get_current_fee_percentiles` made with these arguments.
+    pub fn preview(&self) -> CallPreview {
+        CallPreview::get_current_fee_percentiles(
+            self.network,
+            &self.cycles_cost_config,
+            &self.bitcoin_api_target,
+        )
+    }
+}
+
+/// Fee suggestions in millisatoshis/byte, e.g. to let the user choose among them in a wallet.
+/// The suggestions are non-decreasing from `economical` to `priority`.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct FeeSuggestions {
+    pub economical: MillisatoshiPerByte,
+    pub standard: MillisatoshiPerByte,
+    pub priority: MillisatoshiPerByte,
 }
 
 /// Arguments used to call get_initialization_parameters_from_args in the agent.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
 pub struct InitializationParametersArgs {
     pub key_name: String,
     pub ecdsa_public_key: EcdsaPubKey,
 }
 
+/// Arguments used to call sign_message_from_args in the agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SignMessageArgs {
+    pub key_name: String,
+    pub address: Address,
+    /// The ECDSA public key of `address`, whose derivation path is the one signing the message.
+    pub ecdsa_pub_key: EcdsaPubKey,
+    pub message: Vec<u8>,
+    pub cycles_cost_config: CyclesCostConfig,
+}
+
+impl SignMessageArgs {
+    /// Returns the preview of the call to `sign_with_ecdsa` made with these arguments.
+    pub fn preview(&self) -> CallPreview {
+        CallPreview {
+            method: ManagementCanisterMethod::SignWithEcdsa,
+            principal: Principal::management_canister(),
+            cycles: self.cycles_cost_config.sign_with_ecdsa,
+            decoded_args_summary: format!(
+                "key_name: {}, derivation_path: {}, address: {}, message_len: {}",
+                self.key_name,
+                format_derivation_path(&self.ecdsa_pub_key.derivation_path),
+                self.address,
+                self.message.len()
+            ),
+        }
+    }
+}
+
+/// The format of a signed message, which depends on the type of the signing address.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum SignedMessageFormat {
+    Legacy,       // `signmessage` format of Bitcoin Core, used for P2PKH addresses
+    Bip322Simple, // BIP-322 simple signature, used for P2WPKH addresses
+}
+
+/// A signature of a message proving the control of an address, which off-chain verifiers can check.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct SignedMessage {
+    pub format: SignedMessageFormat,
+    /// The 65-byte recoverable signature for `SignedMessageFormat::Legacy`, the consensus-encoded witness for `SignedMessageFormat::Bip322Simple`.
+    /// Both are usually exchanged encoded in Base64.
+    pub signature: Vec<u8>,
+}
+
+/// Errors when processing a `sign_message` request.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum SignMessageError {
+    /// Only messages of P2PKH and P2WPKH addresses can be signed.
+    UnsupportedAddressType,
+    /// The derivation path of the address, as read from the state, isn't valid.
+    InvalidDerivationPath(DerivationPathError),
+    ManagementCanisterReject(RejectionCode, String),
+}
+
+impl From<ManagementCanisterReject> for SignMessageError {
+    fn from(ManagementCanisterReject(rejection_code, message): ManagementCanisterReject) -> Self {
+        SignMessageError::ManagementCanisterReject(rejection_code, message)
+    }
+}
+
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct InvalidPercentile;
 
@@ -342,21 +1635,87 @@ pub struct ECDSAPublicKey {
     pub key_id: EcdsaKeyId,
 }
 
-#[derive(CandidType, Serialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Debug)]
 pub struct SignWithECDSA {
     pub message_hash: Vec<u8>,
     pub derivation_path: Vec<Vec<u8>>,
     pub key_id: EcdsaKeyId,
 }
 
-#[derive(CandidType, Debug, Deserialize, PartialEq, Clone, Copy)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct SchnorrKeyId {
+    pub algorithm: SchnorrAlgorithm,
+    pub name: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub enum SchnorrAlgorithm {
+    #[serde(rename = "bip340secp256k1")]
+    Bip340Secp256k1,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct SignWithSchnorr {
+    pub message: Vec<u8>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: SchnorrKeyId,
+    pub aux: Option<SignWithSchnorrAux>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub enum SignWithSchnorrAux {
+    #[serde(rename = "bip341")]
+    Bip341(SignWithBip341Aux),
+}
+
+/// Tweaks the signing key with the given Taproot merkle root, an empty merkle root meaning a BIP-86 tweak without any script.
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct SignWithBip341Aux {
+    pub merkle_root_hash: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SignWithSchnorrReply {
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct GetBlockHeadersRequest {
+    pub start_height: u32,
+    pub end_height: Option<u32>,
+    pub network: BitcoinNetwork,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct GetBalanceRequest {
+    pub address: String,
+    pub network: BitcoinNetwork,
+    pub min_confirmations: Option<u32>,
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
 pub enum Fee {
     Constant(Satoshi),     // constant fee in millisatoshis for the transaction
     PerByte(Millisatoshi), // constant fee ratio in millisatoshis/byte
     Slow,                  // 25th percentile
-    Standard,              // 50th percentile
+    Standard,              // standard fee percentile of the Bitcoin agent, 50th by default
     Fast,                  // 75th percentile
+    Median,                // 50th percentile
     Percentile(u8),        // custom percentile
+    TargetBlocks(u8),      // percentile to confirm within the given number of blocks
+    /// The standard fee multiplied by `numerator / denominator` and rounded down to a whole number of millisatoshis/byte, e.g. `{ numerator: 5, denominator: 4 }` to overpay by 25% during mempool congestion.
+    /// The resulting fee is capped by the maximum fee of the Bitcoin agent and, like any fee, has to be at least 1 sat/B, otherwise the transfer fails with `MultiTransferError::FeeTooLow`.
+    StandardTimes {
+        numerator: u32,
+        denominator: u32,
+    },
+}
+
+impl Fee {
+    /// Returns whether the fee is computed from the fee percentiles.
+    pub(crate) fn is_percentile(&self) -> bool {
+        !matches!(self, Fee::Constant(_) | Fee::PerByte(_))
+    }
 }
 
 impl From<Fee> for FeeRequest {
@@ -365,17 +1724,93 @@ impl From<Fee> for FeeRequest {
             Fee::Slow => FeeRequest::Slow,
             Fee::Standard => FeeRequest::Standard,
             Fee::Fast => FeeRequest::Fast,
+            Fee::Median => FeeRequest::Median,
             Fee::Percentile(percentile) => FeeRequest::Percentile(percentile),
+            Fee::TargetBlocks(target_blocks) => FeeRequest::TargetBlocks(target_blocks),
+            Fee::StandardTimes { .. } => FeeRequest::Standard,
             // Other cases can't happen see multi_transfer
             _ => panic!(),
         }
     }
 }
 
-pub type TransactionID = String;
+/// Identifier of a transaction, i.e. its txid, holding the bytes in the internal order of `bitcoin::Txid` and of the outpoints of the UTXOs.
+/// It's displayed and parsed in hexadecimal in the reversed byte order shown by block explorers, like `bitcoin::Txid`.
+/// It's Candid-encoded as the `blob` of its bytes in the internal order.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct TransactionID([u8; 32]);
 
-#[derive(CandidType, Debug, Deserialize, PartialEq)]
+impl TransactionID {
+    /// Returns the bytes of the txid in the internal order, the one of the outpoints of the UTXOs.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for TransactionID {
+    fn from(bytes: [u8; 32]) -> Self {
+        TransactionID(bytes)
+    }
+}
+
+impl From<Txid> for TransactionID {
+    fn from(txid: Txid) -> Self {
+        TransactionID(txid.to_byte_array())
+    }
+}
+
+impl From<TransactionID> for Txid {
+    fn from(transaction_id: TransactionID) -> Self {
+        Txid::from_byte_array(transaction_id.0)
+    }
+}
+
+impl fmt::Display for TransactionID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", to_explorer_hex(&self.0))
+    }
+}
+
+impl FromStr for TransactionID {
+    type Err = hashes::hex::Error;
+
+    /// Parses the txid as displayed by block explorers.
+    fn from_str(txid: &str) -> Result<Self, Self::Err> {
+        Txid::from_str(txid).map(TransactionID::from)
+    }
+}
+
+impl CandidType for TransactionID {
+    fn _ty() -> Type {
+        Vec::<u8>::ty()
+    }
+
+    fn idl_serialize<S: CandidSerializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.to_vec().idl_serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionID {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        <[u8; 32]>::try_from(bytes)
+            .map(TransactionID)
+            .map_err(|bytes| de::Error::invalid_length(bytes.len(), &"32 bytes"))
+    }
+}
+
+/// Serializes the txid as displayed by block explorers.
+#[cfg(feature = "serde")]
+impl Serialize for TransactionID {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct TransactionInfo {
+    /// The txid of the transaction.
     pub id: TransactionID,
     pub utxos_addresses: BTreeMap<AddressUsingPrimitives, Vec<Utxo>>,
     pub fee: Satoshi,
@@ -383,51 +1818,447 @@ pub struct TransactionInfo {
     pub timestamp: u64,
 }
 
-#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct MultiTransferResult {
     pub transaction_info: TransactionInfo,
     pub generated_utxos_addresses: BTreeMap<AddressUsingPrimitives, Vec<Utxo>>,
     pub height: u32,
+    pub queued_payout_ids: Vec<QueueId>,
+    /// Indices of the outputs of the transaction paying the script payouts.
+    pub script_payout_vouts: Vec<u32>,
+    /// The number of calls to `sign_with_ecdsa`, including the retried ones.
+    pub sign_attempts: u32,
+    /// The cycles spent by all the calls to the management canister made by the transfer.
+    pub cycles_spent: u128,
+}
+
+/// Configures how calls to the management canister rejected because of a transient error are retried.
+/// As a canister can't wait, a call is retried right away, the await of each call already deferring the next attempt to a later execution round.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RetryConfig {
+    /// The maximum number of calls, including the first one, at least one call being made.
+    pub max_attempts: u32,
+}
+
+impl RetryConfig {
+    /// Returns true if a call rejected with `rejection_code` after `attempts` calls has to be retried.
+    pub(crate) fn should_retry(&self, rejection_code: RejectionCode, attempts: u32) -> bool {
+        rejection_code == RejectionCode::SysTransient && attempts < self.max_attempts
+    }
+}
+
+/// The default retry configuration, which doesn't retry.
+pub const DEFAULT_RETRY_CONFIG: RetryConfig = RetryConfig { max_attempts: 1 };
+
+/// Configures how the calls to `bitcoin_get_utxos` and `bitcoin_get_current_fee_percentiles` made by the free functions, e.g. `get_utxos_from_args`, are retried within the same message.
+/// As it holds a function, it isn't saved in the Bitcoin agent state and has to be set again after restoring the Bitcoin agent.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one, at least one attempt being made.
+    pub max_attempts: u32,
+    /// Returns true if a call rejected with the given rejection code has to be retried, e.g. `is_transient_rejection`.
+    pub retry_on: fn(RejectionCode) -> bool,
+}
+
+/// Compares `retry_on` by address, hence the policies are equal if they hold the same function.
+impl PartialEq for RetryPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_attempts == other.max_attempts && self.retry_on as usize == other.retry_on as usize
+    }
+}
+
+impl Eq for RetryPolicy {}
+
+impl RetryPolicy {
+    /// Returns true if a call rejected with `rejection_code` after `attempts` attempts has to be retried.
+    pub(crate) fn should_retry(&self, rejection_code: RejectionCode, attempts: u32) -> bool {
+        (self.retry_on)(rejection_code) && attempts < self.max_attempts
+    }
+}
+
+/// Returns true if `rejection_code` is the one of a transient error, e.g. an overloaded canister.
+pub fn is_transient_rejection(rejection_code: RejectionCode) -> bool {
+    rejection_code == RejectionCode::SysTransient
+}
+
+/// Fragments of the rejection messages of the responses exceeding the size limit of the inter-canister responses.
+const RESPONSE_TOO_LARGE_MESSAGES: [&str; 3] = [
+    "application payload size",
+    "payload too large",
+    "response size exceeds",
+];
+
+/// Returns true if the rejection `message` is the one of a response exceeding the size limit of the inter-canister responses.
+pub(crate) fn is_response_too_large_rejection(message: &str) -> bool {
+    let message = message.to_lowercase();
+    RESPONSE_TOO_LARGE_MESSAGES
+        .iter()
+        .any(|fragment| message.contains(fragment))
+}
+
+/// The default retry policy, which doesn't retry.
+pub const DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 1,
+    retry_on: is_transient_rejection,
+};
+
+/// Represents a payout to a raw script instead of an address.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct ScriptPayout {
+    pub script_pubkey: Vec<u8>,
+    pub amount: Satoshi,
 }
 
 /// Arguments used to call multi_transfer_from_args in the agent.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MultiTransferArgs {
     pub key_name: String,
+    /// The ECDSA public keys of the addresses that can be spent from.
     pub ecdsa_pub_key_addresses: BTreeMap<Address, EcdsaPubKey>,
+    /// The unspent UTXOs of the addresses that can be spent from.
     pub utxos_state_addresses: BTreeMap<Address, UtxosState>,
     pub payouts: BTreeMap<Address, Satoshi>,
     pub change_address: Address,
     pub fee: Fee,
+    /// The percentile used for `Fee::Standard`.
+    pub standard_fee_percentile: u8,
+    /// The percentiles associated with confirming the transaction within a given number of blocks, used for `Fee::TargetBlocks`.
+    pub target_blocks_percentiles: BTreeMap<u8, u8>,
+    /// If true, a percentile fee exceeding the 100th percentile is clamped to it instead of being an error.
+    pub clamp_percentile: bool,
+    /// The maximum fee in millisatoshis/byte of percentile fees, including `Fee::StandardTimes`, `None` if there isn't any.
+    pub max_fee_per_byte: Option<MillisatoshiPerByte>,
+    /// How the signatures of the inputs rejected because of a transient error are retried.
+    pub sign_retry_config: RetryConfig,
+    /// The maximum number of inputs signed concurrently, a single input being signed at a time if 0.
+    pub sign_concurrency: u32,
     pub min_confirmations: u32,
     pub replaceable: bool,
     pub network: Network,
+    pub queued_payout_ids: Vec<QueueId>,
+    /// Outpoints to spend, in this order. When empty, the UTXOs to spend are selected automatically.
+    pub inputs: Vec<OutPoint>,
+    /// Payouts to raw scripts, added after the address payouts in this order.
+    pub script_payouts: Vec<ScriptPayout>,
+    /// Allows the script payouts to use non-standard scripts, which most nodes don't relay.
+    pub allow_nonstandard: bool,
+    /// The maximum fee in satoshis of the transaction, which fails with `MultiTransferError::FeeTooHigh` above it.
+    pub max_fee: Option<Satoshi>,
+    /// The maximum weight of the signed transaction in weight units.
+    pub max_tx_weight: u64,
+    /// The maximum number of inputs of the transaction, each of them requiring a signature.
+    pub max_inputs: u32,
+    /// Restricts which managed addresses the spent UTXOs may come from.
+    pub selection_scope: SelectionScope,
+    /// Fee percentiles used for percentile fees instead of retrieving them, e.g. the ones cached by the Bitcoin agent.
+    pub current_fees: Option<Vec<MillisatoshiPerByte>>,
+    /// The fee in millisatoshis/byte used instead of a percentile fee when the management canister doesn't have any fee data.
+    pub fallback_fee_per_byte: Option<MillisatoshiPerByte>,
+    pub cycles_cost_config: CyclesCostConfig,
+    pub bitcoin_api_target: BitcoinApiTarget,
+    pub log_sink: Arc<dyn LogSink>,
+    /// Timestamps the transaction.
+    pub time_source: Arc<dyn TimeSource>,
+}
+
+// The log sink and the time source are compared by address, as they don't implement `PartialEq`.
+impl PartialEq for MultiTransferArgs {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_name == other.key_name
+            && self.ecdsa_pub_key_addresses == other.ecdsa_pub_key_addresses
+            && self.utxos_state_addresses == other.utxos_state_addresses
+            && self.payouts == other.payouts
+            && self.change_address == other.change_address
+            && self.fee == other.fee
+            && self.standard_fee_percentile == other.standard_fee_percentile
+            && self.target_blocks_percentiles == other.target_blocks_percentiles
+            && self.clamp_percentile == other.clamp_percentile
+            && self.max_fee_per_byte == other.max_fee_per_byte
+            && self.sign_retry_config == other.sign_retry_config
+            && self.sign_concurrency == other.sign_concurrency
+            && self.min_confirmations == other.min_confirmations
+            && self.replaceable == other.replaceable
+            && self.network == other.network
+            && self.queued_payout_ids == other.queued_payout_ids
+            && self.inputs == other.inputs
+            && self.script_payouts == other.script_payouts
+            && self.allow_nonstandard == other.allow_nonstandard
+            && self.max_fee == other.max_fee
+            && self.max_tx_weight == other.max_tx_weight
+            && self.max_inputs == other.max_inputs
+            && self.selection_scope == other.selection_scope
+            && self.current_fees == other.current_fees
+            && self.fallback_fee_per_byte == other.fallback_fee_per_byte
+            && self.cycles_cost_config == other.cycles_cost_config
+            && self.bitcoin_api_target == other.bitcoin_api_target
+            && Arc::ptr_eq(&self.log_sink, &other.log_sink)
+            && Arc::ptr_eq(&self.time_source, &other.time_source)
+    }
+}
+
+impl Eq for MultiTransferArgs {}
+
+/// Restricts which managed addresses the UTXOs spent by a transaction may come from, as spending UTXOs of several addresses links them on-chain.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum SelectionScope {
+    SingleAddressOnly,   // only spend UTXOs of a single address
+    PreferSingleAddress, // only spend UTXOs of several addresses if no single address covers the transaction
+    Any,                 // spend UTXOs of any address
 }
 
 /// Errors when processing a `multi_transfer` request.
-#[derive(CandidType, Debug)]
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
 pub enum MultiTransferError {
     FeeTooLow,
-    InvalidPercentile,
+    /// The `fee` of the transaction exceeds its `max_fee`.
+    FeeTooHigh {
+        fee: Satoshi,
+        max_fee: Satoshi,
+    },
+    /// The denominator of `Fee::StandardTimes` is 0.
+    InvalidFeeMultiplier,
+    /// The `requested` percentile exceeds the 100th percentile, `available` being the number of fee percentiles returned by the management canister.
+    InvalidPercentile {
+        requested: u8,
+        available: u32,
+    },
+    /// The management canister doesn't have any fee data and `fallback_fee_per_byte` is `None`.
+    NoFeeData,
     InsufficientBalance,
     MinConfirmationsTooHigh,
-    ManagementCanisterReject(RejectionCode, String),
+    InputNotSpendable,
+    DuplicateInput,
+    DustOutput,
+    ScriptTooLong,
+    NonStandardScript,
+    /// No single address covers the transaction or the given inputs belong to several addresses while using `SelectionScope::SingleAddressOnly`.
+    SingleAddressSelectionFailed,
+    /// The transaction exceeds `max_tx_weight` or `max_inputs`, the payouts should be split into several transactions.
+    TransactionTooLarge {
+        weight: u64,
+        inputs: u32,
+    },
+    /// The derivation path of a spent address, as read from the state, isn't valid.
+    InvalidDerivationPath(DerivationPathError),
+    /// The management canister rejected the call to `method`.
+    ManagementCanisterReject {
+        method: ManagementCanisterMethod,
+        rejection_code: RejectionCode,
+        message: String,
+    },
+    /// The management canister rejected the signature of some inputs, in the order of the inputs.
+    SigningFailed {
+        failed_inputs: Vec<InputSigningFailure>,
+    },
+}
+
+/// The rejection of the signature of the input at index `input` of a transaction.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct InputSigningFailure {
+    pub input: u32,
+    pub method: ManagementCanisterMethod,
+    pub rejection_code: RejectionCode,
+    pub message: String,
+}
+
+impl MultiTransferError {
+    /// Returns the error associated with the rejection of a call to the management canister `method`.
+    pub(crate) fn from_reject(
+        method: ManagementCanisterMethod,
+        ManagementCanisterReject(rejection_code, message): ManagementCanisterReject,
+    ) -> Self {
+        MultiTransferError::ManagementCanisterReject {
+            method,
+            rejection_code,
+            message,
+        }
+    }
+
+    /// Returns true if the management canister rejected a call because of a transient error, hence if the transfer may succeed when retried.
+    /// Failed signatures are retryable if all of them were rejected because of a transient error.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MultiTransferError::ManagementCanisterReject { rejection_code, .. } => {
+                *rejection_code == RejectionCode::SysTransient
+            }
+            MultiTransferError::SigningFailed { failed_inputs } => failed_inputs
+                .iter()
+                .all(|failed_input| failed_input.rejection_code == RejectionCode::SysTransient),
+            _ => false,
+        }
+    }
 }
 
 impl From<GetCurrentFeeError> for MultiTransferError {
     fn from(get_current_fee_error: GetCurrentFeeError) -> Self {
         match get_current_fee_error {
-            GetCurrentFeeError::InvalidPercentile => MultiTransferError::InvalidPercentile,
+            GetCurrentFeeError::InvalidPercentile {
+                requested,
+                available,
+            } => MultiTransferError::InvalidPercentile {
+                requested,
+                available,
+            },
+            GetCurrentFeeError::NoFeeData => MultiTransferError::NoFeeData,
             GetCurrentFeeError::ManagementCanisterReject(rejection_code, message) => {
-                MultiTransferError::ManagementCanisterReject(rejection_code, message)
+                MultiTransferError::from_reject(
+                    ManagementCanisterMethod::BitcoinGetCurrentFeePercentiles,
+                    ManagementCanisterReject(rejection_code, message),
+                )
             }
         }
     }
 }
 
-impl From<ManagementCanisterReject> for MultiTransferError {
-    fn from(ManagementCanisterReject(rejection_code, error): ManagementCanisterReject) -> Self {
-        MultiTransferError::ManagementCanisterReject(rejection_code, error)
+/// Error of any operation of the library, classified by its broad category, e.g. to return a single `Result<T, BtcLibError>` from a flow calling several operations.
+/// Every error of the library converts into it with `From`, and back with `TryFrom`, the original error being kept as `ErrorDetail`.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum BtcLibError {
+    /// The request was rejected, by the management canister or by the rate limits of the Bitcoin agent, and may succeed when retried.
+    Rejected(ErrorDetail),
+    /// The arguments or the settings of the request are invalid.
+    InvalidInput(ErrorDetail),
+    /// The address or the Bitcoin agent isn't managed.
+    NotTracked(ErrorDetail),
+    /// The UTXOs of the managed addresses don't cover the transfer.
+    InsufficientFunds(ErrorDetail),
+    /// The state of the Bitcoin agent or the responses of the management canister are inconsistent.
+    Internal(ErrorDetail),
+}
+
+/// The original error of a `BtcLibError`, one variant per error of the library.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorDetail {
+    BitcoinAddress(BitcoinAddressError),
+    DerivationPath(DerivationPathError),
+    AddressNotTracked(AddressNotTracked),
+    RateLimited(RateLimited),
+    UnsupportedNetwork(UnsupportedNetwork),
+    RegtestUnavailable(RegtestUnavailable),
+    MinConfirmationsTooHigh(MinConfirmationsTooHigh),
+    StandardFeePercentileTooHigh(StandardFeePercentileTooHigh),
+    InvalidPercentile(InvalidPercentile),
+    AddAddressWithParameters(AddAddressWithParametersError),
+    StateRestore(StateRestoreError),
+    IntegrityIssue(IntegrityIssue),
+    StateImport(StateImportError),
+    AgentRegistry(AgentRegistryError),
+    ApplyMultiTransferResult(ApplyMultiTransferResultError),
+    GetUtxos(GetUtxosError),
+    Signature(SignatureError),
+    ManagementCanisterReject(ManagementCanisterReject),
+    InvalidCyclesCostConfig(InvalidCyclesCostConfig),
+    Build(BuildError),
+    GetCurrentFee(GetCurrentFeeError),
+    SignMessage(SignMessageError),
+    MultiTransfer(MultiTransferError),
+    ParseNetwork(ParseNetworkError),
+    ParseAddressType(ParseAddressTypeError),
+    NetChangeOverflow(NetChangeOverflow),
+}
+
+impl BtcLibError {
+    /// Returns the error classified by the category of `detail`.
+    pub(crate) fn new(detail: ErrorDetail) -> Self {
+        let category: fn(ErrorDetail) -> BtcLibError = match &detail {
+            ErrorDetail::RateLimited(_) | ErrorDetail::ManagementCanisterReject(_) => {
+                BtcLibError::Rejected
+            }
+            ErrorDetail::BitcoinAddress(_)
+            | ErrorDetail::DerivationPath(_)
+            | ErrorDetail::UnsupportedNetwork(_)
+            | ErrorDetail::RegtestUnavailable(_)
+            | ErrorDetail::MinConfirmationsTooHigh(_)
+            | ErrorDetail::StandardFeePercentileTooHigh(_)
+            | ErrorDetail::InvalidPercentile(_)
+            | ErrorDetail::AddAddressWithParameters(_)
+            | ErrorDetail::StateImport(_)
+            | ErrorDetail::Signature(_)
+            | ErrorDetail::InvalidCyclesCostConfig(_)
+            | ErrorDetail::Build(_)
+            | ErrorDetail::ParseNetwork(_)
+            | ErrorDetail::ParseAddressType(_) => BtcLibError::InvalidInput,
+            ErrorDetail::AddressNotTracked(_) => BtcLibError::NotTracked,
+            ErrorDetail::StateRestore(_)
+            | ErrorDetail::IntegrityIssue(_)
+            | ErrorDetail::NetChangeOverflow(_) => BtcLibError::Internal,
+            ErrorDetail::AgentRegistry(error) => match error {
+                AgentRegistryError::UnknownAgent(_) => BtcLibError::NotTracked,
+                AgentRegistryError::DuplicateAddress { .. } => BtcLibError::InvalidInput,
+                AgentRegistryError::InvalidState { .. } => BtcLibError::Internal,
+            },
+            ErrorDetail::ApplyMultiTransferResult(error) => match error {
+                ApplyMultiTransferResultError::InvalidAddress(_) => BtcLibError::InvalidInput,
+                ApplyMultiTransferResultError::AddressNotTracked(_) => BtcLibError::NotTracked,
+            },
+            ErrorDetail::GetUtxos(error) => match error {
+                GetUtxosError::ManagementCanisterReject(..)
+                | GetUtxosError::ResponseTooLarge { .. } => BtcLibError::Rejected,
+                GetUtxosError::MinConfirmationsTooHigh => BtcLibError::InvalidInput,
+                GetUtxosError::AddressNotTracked => BtcLibError::NotTracked,
+                GetUtxosError::InvalidResponse { .. } => BtcLibError::Internal,
+            },
+            ErrorDetail::GetCurrentFee(error) => match error {
+                GetCurrentFeeError::ManagementCanisterReject(..) => BtcLibError::Rejected,
+                GetCurrentFeeError::InvalidPercentile { .. } => BtcLibError::InvalidInput,
+                GetCurrentFeeError::NoFeeData => BtcLibError::Internal,
+            },
+            ErrorDetail::SignMessage(error) => match error {
+                SignMessageError::ManagementCanisterReject(..) => BtcLibError::Rejected,
+                SignMessageError::UnsupportedAddressType => BtcLibError::InvalidInput,
+                SignMessageError::InvalidDerivationPath(_) => BtcLibError::Internal,
+            },
+            ErrorDetail::MultiTransfer(error) => match error {
+                MultiTransferError::ManagementCanisterReject { .. }
+                | MultiTransferError::SigningFailed { .. } => BtcLibError::Rejected,
+                MultiTransferError::FeeTooLow
+                | MultiTransferError::FeeTooHigh { .. }
+                | MultiTransferError::InvalidFeeMultiplier
+                | MultiTransferError::InvalidPercentile { .. }
+                | MultiTransferError::MinConfirmationsTooHigh
+                | MultiTransferError::InputNotSpendable
+                | MultiTransferError::DuplicateInput
+                | MultiTransferError::DustOutput
+                | MultiTransferError::ScriptTooLong
+                | MultiTransferError::NonStandardScript
+                | MultiTransferError::TransactionTooLarge { .. } => BtcLibError::InvalidInput,
+                MultiTransferError::InsufficientBalance
+                | MultiTransferError::SingleAddressSelectionFailed => {
+                    BtcLibError::InsufficientFunds
+                }
+                MultiTransferError::NoFeeData | MultiTransferError::InvalidDerivationPath(_) => {
+                    BtcLibError::Internal
+                }
+            },
+        };
+        category(detail)
+    }
+
+    /// Returns the original error.
+    pub fn detail(&self) -> &ErrorDetail {
+        match self {
+            BtcLibError::Rejected(detail)
+            | BtcLibError::InvalidInput(detail)
+            | BtcLibError::NotTracked(detail)
+            | BtcLibError::InsufficientFunds(detail)
+            | BtcLibError::Internal(detail) => detail,
+        }
+    }
+
+    /// Returns the original error, e.g. to match on it.
+    pub fn into_detail(self) -> ErrorDetail {
+        match self {
+            BtcLibError::Rejected(detail)
+            | BtcLibError::InvalidInput(detail)
+            | BtcLibError::NotTracked(detail)
+            | BtcLibError::InsufficientFunds(detail)
+            | BtcLibError::Internal(detail) => detail,
+        }
     }
 }
 
@@ -436,6 +2267,290 @@ pub struct BuiltTransaction {
     pub transaction: Transaction,
     pub mock_signed_transaction_size: u64,
     pub spending_utxos_addresses: BTreeMap<Address, Vec<Utxo>>,
+    pub spending_addresses: Vec<Address>,
+    pub spending_values: Vec<Satoshi>,
     pub spending_ecdsa_pub_keys: Vec<EcdsaPubKey>,
     pub fee: Satoshi,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        is_transient_rejection, AddressType, BalanceUpdate, BlockHeadersArgs, CurrentFeeArgs,
+        CurrentFeesArgs, FeeSuggestions, FeeSuggestionsArgs, InitializationParametersArgs,
+        MultiTransferArgs, MultiTransferResult, NetChangeOverflow, Network, OutPoint,
+        ParseAddressTypeError, ParseNetworkError, RegtestUnavailable, RetryPolicy, SignMessageArgs,
+        SignedMessage, TransactionID, TransactionInfo, UnsupportedNetwork, Utxo, UtxosArgs,
+        UtxosResult, UtxosState, UtxosUpdate, WithCost, DEFAULT_RETRY_POLICY,
+    };
+    use bitcoin::{blockdata::constants::genesis_block, Txid};
+    use candid::{decode_one, encode_one};
+    use ic_cdk::api::management_canister::bitcoin::{
+        self as management_canister_bitcoin, BitcoinNetwork,
+    };
+
+    /// Check that the conversions between `Network`, `bitcoin::Network` and the `BitcoinNetwork` of the management canister round trip for every network, the signet being the only network not served by the Bitcoin API of the IC.
+    #[test]
+    fn check_network_conversions() {
+        for network in [
+            Network::Mainnet,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            let bitcoin_network = bitcoin::Network::from(network);
+            assert_eq!(Network::try_from(bitcoin_network), Ok(network));
+            match BitcoinNetwork::try_from(network) {
+                Ok(management_canister_network) => {
+                    assert_eq!(Network::try_from(management_canister_network), Ok(network))
+                }
+                Err(unsupported_network) => {
+                    assert_eq!(network, Network::Signet);
+                    assert_eq!(unsupported_network, UnsupportedNetwork(Network::Signet));
+                }
+            }
+        }
+
+        for bitcoin_network in [
+            bitcoin::Network::Bitcoin,
+            bitcoin::Network::Testnet,
+            bitcoin::Network::Signet,
+            bitcoin::Network::Regtest,
+        ] {
+            let network: Result<Network, RegtestUnavailable> = bitcoin_network.try_into();
+            assert_eq!(bitcoin::Network::from(network.unwrap()), bitcoin_network);
+        }
+
+        for management_canister_network in [
+            BitcoinNetwork::Mainnet,
+            BitcoinNetwork::Testnet,
+            BitcoinNetwork::Regtest,
+        ] {
+            let network = Network::try_from(management_canister_network).unwrap();
+            assert_eq!(
+                BitcoinNetwork::try_from(network),
+                Ok(management_canister_network)
+            );
+        }
+    }
+
+    /// Check that the UTXOs convert from and to the ones of the management canister API of `ic-cdk`, which are Candid-encoded alike, so that the replies of the management canister and the saved states are decoded into either.
+    #[test]
+    fn check_utxo_conversions() {
+        let utxo = Utxo {
+            outpoint: OutPoint {
+                txid: vec![1; 32],
+                vout: 2,
+            },
+            value: 3,
+            height: 4,
+        };
+        let management_canister_utxo = management_canister_bitcoin::Utxo::from(utxo.clone());
+        assert_eq!(management_canister_utxo.outpoint.txid, vec![1; 32]);
+        assert_eq!(Utxo::from(management_canister_utxo.clone()), utxo);
+
+        assert_eq!(
+            decode_one::<Utxo>(&encode_one(&management_canister_utxo).unwrap()).unwrap(),
+            utxo
+        );
+        assert_eq!(
+            decode_one::<management_canister_bitcoin::Utxo>(&encode_one(&utxo).unwrap()).unwrap(),
+            management_canister_utxo
+        );
+    }
+
+    /// Check that the arguments and the results of the free functions can be logged, cloned and compared, e.g. to assert on them in the tests of canisters.
+    #[test]
+    fn check_args_and_results_traits() {
+        fn assert_traits<T: std::fmt::Debug + Clone + PartialEq + Eq>() {}
+        assert_traits::<UtxosArgs>();
+        assert_traits::<UtxosResult>();
+        assert_traits::<BlockHeadersArgs>();
+        assert_traits::<CurrentFeesArgs>();
+        assert_traits::<CurrentFeeArgs>();
+        assert_traits::<FeeSuggestionsArgs>();
+        assert_traits::<FeeSuggestions>();
+        assert_traits::<InitializationParametersArgs>();
+        assert_traits::<SignMessageArgs>();
+        assert_traits::<SignedMessage>();
+        assert_traits::<MultiTransferArgs>();
+        assert_traits::<MultiTransferResult>();
+        assert_traits::<TransactionInfo>();
+        assert_traits::<WithCost<Vec<Utxo>>>();
+
+        // The retry policies are equal if they hold the same function.
+        assert_eq!(
+            DEFAULT_RETRY_POLICY,
+            RetryPolicy {
+                max_attempts: 1,
+                retry_on: is_transient_rejection,
+            }
+        );
+        assert_ne!(
+            DEFAULT_RETRY_POLICY,
+            RetryPolicy {
+                max_attempts: 1,
+                retry_on: |_| true,
+            }
+        );
+    }
+
+    /// Check that every network and address type is parsed back from its name, whatever its case, and that unknown names are rejected with the valid ones.
+    #[test]
+    fn check_parse_names() {
+        assert_eq!(Network::ALL.len(), 4);
+        for network in Network::ALL {
+            let name = network.to_string();
+            assert_eq!(name.parse::<Network>(), Ok(*network));
+            assert_eq!(name.to_uppercase().parse::<Network>(), Ok(*network));
+        }
+        assert_eq!(Network::Mainnet.to_string(), "mainnet");
+        assert_eq!("TestNet".parse::<Network>(), Ok(Network::Testnet));
+        assert_eq!(
+            "bitcoin".parse::<Network>(),
+            Err(ParseNetworkError(String::from("bitcoin")))
+        );
+        assert_eq!(
+            "bitcoin".parse::<Network>().unwrap_err().to_string(),
+            "unknown network \"bitcoin\", expected one of: mainnet, testnet, signet, regtest"
+        );
+
+        assert_eq!(AddressType::ALL.len(), 4);
+        for address_type in AddressType::ALL {
+            let name = address_type.to_string();
+            assert_eq!(name.parse::<AddressType>(), Ok(*address_type));
+            assert_eq!(
+                name.to_uppercase().parse::<AddressType>(),
+                Ok(*address_type)
+            );
+        }
+        assert_eq!(AddressType::P2wpkh.to_string(), "p2wpkh");
+        assert_eq!("P2PKH".parse::<AddressType>(), Ok(AddressType::P2pkh));
+        for name in ["", "p2wsh", "p2pkh "] {
+            assert_eq!(
+                name.parse::<AddressType>(),
+                Err(ParseAddressTypeError(name.to_string()))
+            );
+        }
+        assert_eq!(
+            "p2wsh".parse::<AddressType>().unwrap_err().to_string(),
+            "unknown address type \"p2wsh\", expected one of: p2pkh, p2sh, p2wpkh, p2tr"
+        );
+    }
+
+    /// Check the byte order of the txid of the coinbase transaction of the genesis block, displayed reversed as by block explorers.
+    #[test]
+    fn check_transaction_id_byte_order() {
+        let txid = genesis_block(bitcoin::Network::Bitcoin).txdata[0].txid();
+        let transaction_id = TransactionID::from(txid);
+        let displayed_txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert_eq!(transaction_id.to_string(), displayed_txid);
+        assert_eq!(transaction_id.to_string(), txid.to_string());
+        assert_eq!(
+            hex::encode(transaction_id.as_bytes()),
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a"
+        );
+        assert_eq!(displayed_txid.parse::<TransactionID>(), Ok(transaction_id));
+        assert_eq!(Txid::from(transaction_id), txid);
+        assert!("4a5e1e4b".parse::<TransactionID>().is_err());
+
+        // The txid is Candid-encoded as its bytes in the internal order.
+        let bytes = encode_one(transaction_id).unwrap();
+        assert_eq!(
+            decode_one::<Vec<u8>>(&bytes).unwrap(),
+            transaction_id.as_bytes()
+        );
+        assert_eq!(decode_one::<TransactionID>(&bytes).unwrap(), transaction_id);
+        assert!(decode_one::<TransactionID>(&encode_one(vec![0_u8; 31]).unwrap()).is_err());
+    }
+
+    /// Check that the balance update of a UTXOs update counts its UTXOs and that its net change is checked.
+    #[test]
+    fn check_balance_update_from_utxos_update() {
+        let utxo = |vout: u32, value: u64| Utxo {
+            outpoint: OutPoint {
+                txid: vec![1; 32],
+                vout,
+            },
+            value,
+            height: 1,
+        };
+        let balance_update = BalanceUpdate::from(UtxosUpdate {
+            added_utxos: vec![utxo(0, 10_000), utxo(1, 20_000)],
+            removed_utxos: vec![utxo(2, 50_000)],
+        });
+        assert_eq!(
+            balance_update,
+            BalanceUpdate {
+                added_balance: 30_000,
+                removed_balance: 50_000,
+                net_change: -20_000,
+                added_utxo_count: 2,
+                removed_utxo_count: 1,
+                added_outpoints: vec![utxo(0, 0).outpoint, utxo(1, 0).outpoint],
+                removed_outpoints: vec![utxo(2, 0).outpoint],
+            }
+        );
+        assert_eq!(
+            BalanceUpdate::from(UtxosUpdate::new()),
+            BalanceUpdate::new()
+        );
+
+        assert_eq!(
+            BalanceUpdate::get_net_change(i64::MAX as u64, 0),
+            Ok(i64::MAX)
+        );
+        assert_eq!(
+            BalanceUpdate::get_net_change(0, i64::MAX as u64),
+            Ok(-i64::MAX)
+        );
+        assert_eq!(
+            BalanceUpdate::get_net_change(i64::MAX as u64 + 1, 0),
+            Err(NetChangeOverflow)
+        );
+        assert_eq!(
+            BalanceUpdate::get_net_change(0, u64::MAX),
+            Err(NetChangeOverflow)
+        );
+        // The net change of the balance update saturates instead.
+        let balance_update = BalanceUpdate::from(UtxosUpdate {
+            added_utxos: vec![],
+            removed_utxos: vec![utxo(0, u64::MAX)],
+        });
+        assert_eq!(balance_update.net_change, i64::MIN);
+    }
+
+    /// Check that the summaries of a UTXOs state are the ones of its seen state and that it is only quiescent when up to date without cached spent or generated UTXOs.
+    #[test]
+    fn check_utxos_state_accessors() {
+        let utxo = |vout: u32, value: u64| Utxo {
+            outpoint: OutPoint {
+                txid: vec![1; 32],
+                vout,
+            },
+            value,
+            height: 1,
+        };
+        let mut utxos_state = UtxosState::new(1);
+        assert!(utxos_state.is_quiescent());
+        assert!(utxos_state.is_empty());
+        assert_eq!((utxos_state.len(), utxos_state.value()), (0, 0));
+
+        utxos_state.unseen_state = vec![utxo(0, 10_000), utxo(1, 20_000)];
+        assert!(!utxos_state.is_quiescent());
+        assert!(utxos_state.is_empty());
+
+        utxos_state.seen_state = utxos_state.unseen_state.clone();
+        assert!(utxos_state.is_quiescent());
+        assert!(!utxos_state.is_empty());
+        assert_eq!((utxos_state.len(), utxos_state.value()), (2, 30_000));
+
+        utxos_state.spent_state = vec![utxo(0, 0).outpoint];
+        assert!(!utxos_state.is_quiescent());
+        utxos_state.spent_state.clear();
+        utxos_state.generated_state = vec![utxo(2, 5_000)];
+        assert!(!utxos_state.is_quiescent());
+        // The cached UTXOs aren't part of the summaries.
+        assert_eq!((utxos_state.len(), utxos_state.value()), (2, 30_000));
+    }
+}
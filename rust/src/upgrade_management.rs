@@ -1,10 +1,42 @@
+//! Saving and restoring the Bitcoin agent state, e.g. across canister upgrades.
+
 use crate::{
+    address_management::{
+        derive_ecdsa_public_key_and_address_from_extended_path,
+        get_btc_public_key_from_ecdsa_public_key, get_types_address_type,
+    },
+    ecdsa::get_key_name_from_network,
+    fee_cache::FeeCache,
+    legacy::LegacyBitcoinAgentStateV0,
+    logging::NoopLogSink,
+    payout_queue::PayoutQueue,
+    rate_limit::RateLimiter,
     types::{from_bitcoin_network_to_types_network, from_types_network_to_bitcoin_network},
-    AddressUsingPrimitives, BitcoinAgent, BitcoinAgentState, EcdsaPubKey, ManagementCanister,
-    UtxosState,
+    AddressUsingPrimitives, BitcoinAgent, BitcoinAgentState, BitcoinAgentStateV0,
+    BitcoinAgentStateV1, CyclesCostConfig, EcdsaPubKey, FeeSmoothing, IntegrityIssue, KeyRotation,
+    ManagementCanister, ManagementCanisterFactory, MemoryStats, MillisatoshiPerByte, OutPoint,
+    PayoutQueueState, RateLimitState, RateLimitedOp, RetryConfig, StateDiff, StateImportError,
+    StateRestoreError, StateRestoreOptions, Utxo, UtxosState, BITCOIN_AGENT_STATE_VERSION,
+    DEFAULT_CYCLES_COST_CEILING, DEFAULT_FEE_CACHE_MAX_AGE, DEFAULT_RETRY_CONFIG,
+    DEFAULT_RETRY_POLICY, DEFAULT_SIGN_CONCURRENCY, DEFAULT_STANDARD_FEE_PERCENTILE,
+    DEFAULT_TARGET_BLOCKS_PERCENTILES, MIN_CONFIRMATIONS_UPPER_BOUND,
+};
+use bitcoin::{
+    address,
+    hashes::{sha256, Hash, HashEngine},
+    Address, AddressType,
+};
+use candid::{ser::IDLBuilder, CandidType, Decode, Encode};
+#[cfg(feature = "serde")]
+use serde::{de, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    io,
+    mem::size_of,
+    str::FromStr,
+    sync::Arc,
 };
-use bitcoin::{Address, Network};
-use std::{collections::BTreeMap, str::FromStr};
 
 /// Returns the Bitcoin agent state.
 pub(crate) fn get_state<C: ManagementCanister>(
@@ -18,33 +50,495 @@ pub(crate) fn get_state<C: ManagementCanister>(
         })
         .collect();
 
+    let mut unseen_same_as_seen_addresses = BTreeSet::new();
     let utxos_state_addresses: BTreeMap<AddressUsingPrimitives, UtxosState> = bitcoin_agent
         .utxos_state_addresses
         .iter()
-        .map(|(address, utxos_state)| (get_address_using_primitives(address), utxos_state.clone()))
+        .map(|(address, utxos_state)| {
+            let address_using_primitives = get_address_using_primitives(address);
+            let unseen_state = if is_unseen_same_as_seen(utxos_state) {
+                unseen_same_as_seen_addresses.insert(address_using_primitives.clone());
+                vec![]
+            } else {
+                utxos_state.unseen_state.clone()
+            };
+            (
+                address_using_primitives,
+                UtxosState {
+                    seen_state: utxos_state.seen_state.clone(),
+                    unseen_state,
+                    min_confirmations: utxos_state.min_confirmations,
+                    spent_state: utxos_state.spent_state.clone(),
+                    generated_state: utxos_state.generated_state.clone(),
+                },
+            )
+        })
         .collect();
 
     BitcoinAgentState {
+        ecdsa_pub_key_addresses,
+        utxos_state_addresses,
+        unseen_same_as_seen_addresses,
+        ..get_state_without_addresses(bitcoin_agent)
+    }
+}
+
+/// Returns whether the unseen UTXOs state of `utxos_state` is stored as the same as its seen one, which is the case after `update_state` if there is any UTXO.
+fn is_unseen_same_as_seen(utxos_state: &UtxosState) -> bool {
+    !utxos_state.seen_state.is_empty() && utxos_state.unseen_state == utxos_state.seen_state
+}
+
+/// Returns the Bitcoin agent state without its managed addresses, whose maps are empty.
+pub(crate) fn get_state_without_addresses<C: ManagementCanister>(
+    bitcoin_agent: &BitcoinAgent<C>,
+) -> BitcoinAgentState {
+    BitcoinAgentState {
+        version: BITCOIN_AGENT_STATE_VERSION,
         network: from_bitcoin_network_to_types_network(
             bitcoin_agent.management_canister.get_network(),
         ),
         main_address_type: bitcoin_agent.main_address_type,
-        ecdsa_pub_key_addresses,
-        utxos_state_addresses,
+        ecdsa_pub_key_addresses: BTreeMap::default(),
+        utxos_state_addresses: BTreeMap::default(),
         min_confirmations: bitcoin_agent.min_confirmations,
         ecdsa_pub_key: bitcoin_agent.management_canister.get_ecdsa_public_key(),
+        ecdsa_key_name: bitcoin_agent.management_canister.get_ecdsa_key_name(),
+        payout_queue: bitcoin_agent.payout_queue.get_state(),
+        fee_cache_max_age: bitcoin_agent.fee_cache.max_age,
+        standard_fee_percentile: bitcoin_agent.standard_fee_percentile,
+        target_blocks_percentiles: bitcoin_agent.target_blocks_percentiles.clone(),
+        max_fee_per_byte: bitcoin_agent.max_fee_per_byte,
+        sign_retry_config: bitcoin_agent.sign_retry_config,
+        fee_smoothing: bitcoin_agent.fee_cache.fee_smoothing,
+        fee_history: bitcoin_agent
+            .fee_cache
+            .fee_history
+            .iter()
+            .cloned()
+            .collect(),
+        key_rotations: bitcoin_agent.key_rotations.clone(),
+        cycles_cost_config: bitcoin_agent.management_canister.get_cycles_cost_config(),
+        sign_concurrency: bitcoin_agent.sign_concurrency,
+        unseen_same_as_seen_addresses: BTreeSet::default(),
+        rate_limits: bitcoin_agent.rate_limiter.borrow().get_state(),
     }
 }
 
-/// Returns the associated Bitcoin agent with the given `bitcoin_agent_state`.
-pub(crate) fn from_state<C: ManagementCanister>(
+/// Borrows the addresses of a Bitcoin agent and their UTXOs states with the layout of `BitcoinAgentState`, whose encoding is the same.
+/// Its fields have to follow the ones of `BitcoinAgentState`.
+#[derive(CandidType)]
+struct BitcoinAgentStateRef<'a> {
+    version: u32,
+    network: crate::Network,
+    main_address_type: crate::AddressType,
+    ecdsa_pub_key_addresses: Vec<(AddressUsingPrimitives, &'a EcdsaPubKey)>,
+    utxos_state_addresses: Vec<(AddressUsingPrimitives, UtxosStateRef<'a>)>,
+    min_confirmations: u32,
+    ecdsa_pub_key: EcdsaPubKey,
+    ecdsa_key_name: String,
+    payout_queue: PayoutQueueState,
+    fee_cache_max_age: u32,
+    standard_fee_percentile: u8,
+    target_blocks_percentiles: &'a BTreeMap<u8, u8>,
+    max_fee_per_byte: Option<MillisatoshiPerByte>,
+    sign_retry_config: RetryConfig,
+    fee_smoothing: Option<FeeSmoothing>,
+    fee_history: Vec<&'a Vec<MillisatoshiPerByte>>,
+    key_rotations: &'a Vec<KeyRotation>,
+    cycles_cost_config: CyclesCostConfig,
+    sign_concurrency: u32,
+    unseen_same_as_seen_addresses: Vec<AddressUsingPrimitives>,
+    rate_limits: Option<BTreeMap<RateLimitedOp, RateLimitState>>,
+}
+
+/// Borrows a UTXOs state with the layout of `UtxosState`, its unseen UTXOs state being empty if it's the same as its seen one.
+#[derive(CandidType)]
+struct UtxosStateRef<'a> {
+    seen_state: &'a [Utxo],
+    unseen_state: &'a [Utxo],
+    min_confirmations: u32,
+    spent_state: &'a [OutPoint],
+    generated_state: &'a [Utxo],
+}
+
+/// Returns the entries of `address_map` keyed by their `AddressUsingPrimitives`, sorted like the maps of `BitcoinAgentState`.
+fn get_sorted_address_entries<T>(
+    address_map: &BTreeMap<Address, T>,
+) -> Vec<(AddressUsingPrimitives, &T)> {
+    let mut address_entries: Vec<(AddressUsingPrimitives, &T)> = address_map
+        .iter()
+        .map(|(address, value)| (get_address_using_primitives(address), value))
+        .collect();
+    address_entries.sort_unstable_by(|(address, _), (other_address, _)| address.cmp(other_address));
+    address_entries
+}
+
+/// Writes the Bitcoin agent state encoded with Candid to `writer`, the bytes being the ones of `get_state(bitcoin_agent).encode()`.
+/// The state is encoded from references to the Bitcoin agent, hence without cloning its ECDSA public keys and UTXOs states.
+pub(crate) fn write_state<C: ManagementCanister, W: io::Write>(
+    bitcoin_agent: &BitcoinAgent<C>,
+    writer: W,
+) -> Result<(), io::Error> {
+    let mut unseen_same_as_seen_addresses = vec![];
+    let utxos_state_addresses = get_sorted_address_entries(&bitcoin_agent.utxos_state_addresses)
+        .into_iter()
+        .map(|(address_using_primitives, utxos_state)| {
+            let unseen_state: &[Utxo] = if is_unseen_same_as_seen(utxos_state) {
+                unseen_same_as_seen_addresses.push(address_using_primitives.clone());
+                &[]
+            } else {
+                &utxos_state.unseen_state
+            };
+            (
+                address_using_primitives,
+                UtxosStateRef {
+                    seen_state: &utxos_state.seen_state,
+                    unseen_state,
+                    min_confirmations: utxos_state.min_confirmations,
+                    spent_state: &utxos_state.spent_state,
+                    generated_state: &utxos_state.generated_state,
+                },
+            )
+        })
+        .collect();
+    let bitcoin_agent_state_ref = BitcoinAgentStateRef {
+        version: BITCOIN_AGENT_STATE_VERSION,
+        network: from_bitcoin_network_to_types_network(
+            bitcoin_agent.management_canister.get_network(),
+        ),
+        main_address_type: bitcoin_agent.main_address_type,
+        ecdsa_pub_key_addresses: get_sorted_address_entries(&bitcoin_agent.ecdsa_pub_key_addresses),
+        utxos_state_addresses,
+        min_confirmations: bitcoin_agent.min_confirmations,
+        ecdsa_pub_key: bitcoin_agent.management_canister.get_ecdsa_public_key(),
+        ecdsa_key_name: bitcoin_agent.management_canister.get_ecdsa_key_name(),
+        payout_queue: bitcoin_agent.payout_queue.get_state(),
+        fee_cache_max_age: bitcoin_agent.fee_cache.max_age,
+        standard_fee_percentile: bitcoin_agent.standard_fee_percentile,
+        target_blocks_percentiles: &bitcoin_agent.target_blocks_percentiles,
+        max_fee_per_byte: bitcoin_agent.max_fee_per_byte,
+        sign_retry_config: bitcoin_agent.sign_retry_config,
+        fee_smoothing: bitcoin_agent.fee_cache.fee_smoothing,
+        fee_history: bitcoin_agent.fee_cache.fee_history.iter().collect(),
+        key_rotations: &bitcoin_agent.key_rotations,
+        cycles_cost_config: bitcoin_agent.management_canister.get_cycles_cost_config(),
+        sign_concurrency: bitcoin_agent.sign_concurrency,
+        unseen_same_as_seen_addresses,
+        rate_limits: bitcoin_agent.rate_limiter.borrow().get_state(),
+    };
+    IDLBuilder::new()
+        .arg(&bitcoin_agent_state_ref)
+        .and_then(|idl_builder| idl_builder.serialize(writer))
+        .map_err(io::Error::other)
+}
+
+/// Returns the Bitcoin agent associated with the state encoded with Candid read from `reader`, e.g. written by `write_state`.
+/// Fails with an `io::ErrorKind::InvalidData` error if the state can't be decoded or restored.
+pub(crate) fn read_state<C: ManagementCanisterFactory, R: io::Read>(
+    mut reader: R,
+    expected_network: crate::Network,
+) -> Result<BitcoinAgent<C>, io::Error> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    let bitcoin_agent_state = BitcoinAgentState::decode(&bytes)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    drop(bytes);
+    from_state(
+        bitcoin_agent_state,
+        StateRestoreOptions::new(expected_network),
+    )
+    .map_err(|error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid Bitcoin agent state: {:?}.", error),
+        )
+    })
+}
+
+/// Returns the associated Bitcoin agent with the given `bitcoin_agent_state` if the latter is valid, restored according to `state_restore_options`.
+/// A state of another network than the expected one is only restored if `allow_network_change` is set, its addresses being relabelled with the expected network.
+pub(crate) fn from_state<C: ManagementCanisterFactory>(
     bitcoin_agent_state: BitcoinAgentState,
+    state_restore_options: StateRestoreOptions,
+) -> Result<BitcoinAgent<C>, StateRestoreError> {
+    validate_state(&bitcoin_agent_state)?;
+    let expected_network = state_restore_options.expected_network;
+    let bitcoin_agent_state = if expected_network == bitcoin_agent_state.network {
+        bitcoin_agent_state
+    } else if state_restore_options.allow_network_change {
+        override_state_network(bitcoin_agent_state, expected_network)
+    } else {
+        return Err(StateRestoreError::NetworkMismatch {
+            expected: expected_network,
+            found: bitcoin_agent_state.network,
+        });
+    };
+    let bitcoin_agent = from_state_unchecked(bitcoin_agent_state);
+    if state_restore_options.verify_integrity {
+        if let Some(integrity_issue) = get_integrity_issues(&bitcoin_agent).next() {
+            return Err(StateRestoreError::IntegrityIssue(integrity_issue));
+        }
+    }
+    Ok(bitcoin_agent)
+}
+
+/// Returns the associated Bitcoin agent with the given `bitcoin_agent_state` if the latter is valid, its UTXOs states being reset to empty ones with the same minimum number of confirmations.
+pub(crate) fn from_state_addresses_only<C: ManagementCanisterFactory>(
+    mut bitcoin_agent_state: BitcoinAgentState,
+    expected_network: crate::Network,
+) -> Result<BitcoinAgent<C>, StateRestoreError> {
+    for utxos_state in bitcoin_agent_state.utxos_state_addresses.values_mut() {
+        *utxos_state = UtxosState::new(utxos_state.min_confirmations);
+    }
+    bitcoin_agent_state.unseen_same_as_seen_addresses.clear();
+    from_state(
+        bitcoin_agent_state,
+        StateRestoreOptions::new(expected_network),
+    )
+}
+
+/// Returns the given valid `bitcoin_agent_state` with its network replaced by `network`, its addresses being relabelled with the latter.
+fn override_state_network(
+    mut bitcoin_agent_state: BitcoinAgentState,
+    network: crate::Network,
+) -> BitcoinAgentState {
+    let relabel = |address_using_primitives: AddressUsingPrimitives| {
+        let mut address = get_address(address_using_primitives).expect("Malformed state address.");
+        address.network = from_types_network_to_bitcoin_network(network);
+        get_address_using_primitives(&address)
+    };
+    bitcoin_agent_state.network = network;
+    bitcoin_agent_state.ecdsa_pub_key_addresses = bitcoin_agent_state
+        .ecdsa_pub_key_addresses
+        .into_iter()
+        .map(|(address_using_primitives, ecdsa_pub_key)| {
+            (relabel(address_using_primitives), ecdsa_pub_key)
+        })
+        .collect();
+    bitcoin_agent_state.utxos_state_addresses = bitcoin_agent_state
+        .utxos_state_addresses
+        .into_iter()
+        .map(|(address_using_primitives, utxos_state)| {
+            (relabel(address_using_primitives), utxos_state)
+        })
+        .collect();
+    bitcoin_agent_state.unseen_same_as_seen_addresses = bitcoin_agent_state
+        .unseen_same_as_seen_addresses
+        .into_iter()
+        .map(relabel)
+        .collect();
+    for (address_using_primitives, _) in
+        bitcoin_agent_state.payout_queue.queued_payouts.values_mut()
+    {
+        *address_using_primitives = relabel(address_using_primitives.clone());
+    }
+    for key_rotation in &mut bitcoin_agent_state.key_rotations {
+        key_rotation.rekeyed_addresses = key_rotation
+            .rekeyed_addresses
+            .iter()
+            .map(|(previous_address, address)| {
+                (relabel(previous_address.clone()), relabel(address.clone()))
+            })
+            .collect();
+    }
+    bitcoin_agent_state
+}
+
+/// Checks that restoring a Bitcoin agent from `bitcoin_agent_state` won't panic and that its managed addresses are derived from its ECDSA public key.
+fn validate_state(bitcoin_agent_state: &BitcoinAgentState) -> Result<(), StateRestoreError> {
+    if bitcoin_agent_state.version != BITCOIN_AGENT_STATE_VERSION {
+        return Err(StateRestoreError::UnknownVersion(
+            bitcoin_agent_state.version,
+        ));
+    }
+    if bitcoin_agent_state.min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
+        return Err(StateRestoreError::MinConfirmationsTooHigh);
+    }
+    let network = bitcoin_agent_state.network;
+    let ecdsa_pub_key_addresses =
+        parse_state_addresses(bitcoin_agent_state.ecdsa_pub_key_addresses.iter(), network)?;
+    parse_state_addresses(bitcoin_agent_state.utxos_state_addresses.iter(), network)?;
+    for address_using_primitives in &bitcoin_agent_state.unseen_same_as_seen_addresses {
+        parse_state_address(address_using_primitives, network)?;
+        match bitcoin_agent_state
+            .utxos_state_addresses
+            .get(address_using_primitives)
+        {
+            Some(utxos_state) if utxos_state.unseen_state.is_empty() => (),
+            _ => {
+                return Err(StateRestoreError::InvalidCompactedAddress(
+                    address_using_primitives.0.clone(),
+                ))
+            }
+        }
+    }
+    for (address_using_primitives, _) in bitcoin_agent_state.payout_queue.queued_payouts.values() {
+        parse_state_address(address_using_primitives, network)?;
+    }
+    for key_rotation in &bitcoin_agent_state.key_rotations {
+        for (previous_address, address) in &key_rotation.rekeyed_addresses {
+            parse_state_address(previous_address, network)?;
+            parse_state_address(address, network)?;
+        }
+    }
+
+    if ecdsa_pub_key_addresses.is_empty() {
+        return Ok(());
+    }
+    let ecdsa_pub_key = &bitcoin_agent_state.ecdsa_pub_key;
+    if get_btc_public_key_from_ecdsa_public_key(ecdsa_pub_key).is_err() {
+        return Err(StateRestoreError::InvalidEcdsaPubKey);
+    }
+    for (address, address_ecdsa_pub_key) in ecdsa_pub_key_addresses {
+        validate_address_ecdsa_pub_key(&address, address_ecdsa_pub_key, ecdsa_pub_key)?;
+    }
+    Ok(())
+}
+
+/// Returns the addresses of the given state entries, failing if multiple entries are associated with the same address.
+fn parse_state_addresses<'a, T>(
+    entries: impl Iterator<Item = (&'a AddressUsingPrimitives, &'a T)>,
+    network: crate::Network,
+) -> Result<BTreeMap<Address, &'a T>, StateRestoreError> {
+    let mut addresses = BTreeMap::new();
+    for (address_using_primitives, value) in entries {
+        let address = parse_state_address(address_using_primitives, network)?;
+        if addresses.insert(address, value).is_some() {
+            return Err(StateRestoreError::DuplicateAddress(
+                address_using_primitives.0.clone(),
+            ));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Returns the `bitcoin::Address` associated with a given `AddressUsingPrimitives` of a state of the given network.
+fn parse_state_address(
+    address_using_primitives: &AddressUsingPrimitives,
+    network: crate::Network,
+) -> Result<Address, StateRestoreError> {
+    let (address_string, address_network) = address_using_primitives;
+    let address = Address::from_str(address_string)
+        .map_err(|_| StateRestoreError::InvalidAddress(address_string.clone()))?;
+    if *address_network != network
+        || !address.is_valid_for_network(from_types_network_to_bitcoin_network(network))
+    {
+        return Err(StateRestoreError::AddressNetworkMismatch(
+            address_string.clone(),
+        ));
+    }
+    get_address(address_using_primitives.clone())
+        .map_err(|_| StateRestoreError::InvalidAddress(address_string.clone()))
+}
+
+/// Checks that `address_ecdsa_pub_key` is derived from `ecdsa_pub_key` and derives `address`.
+fn validate_address_ecdsa_pub_key(
+    address: &Address,
+    address_ecdsa_pub_key: &EcdsaPubKey,
+    ecdsa_pub_key: &EcdsaPubKey,
+) -> Result<(), StateRestoreError> {
+    match get_address_integrity_issue(address, address_ecdsa_pub_key, ecdsa_pub_key) {
+        Some(_) => Err(StateRestoreError::EcdsaPubKeyMismatch(address.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Returns the issue of `address` if `address_ecdsa_pub_key` isn't derived from `ecdsa_pub_key` at its derivation path or doesn't derive `address`.
+fn get_address_integrity_issue(
+    address: &Address,
+    address_ecdsa_pub_key: &EcdsaPubKey,
+    ecdsa_pub_key: &EcdsaPubKey,
+) -> Option<IntegrityIssue> {
+    let address_type = match address.address_type() {
+        Some(
+            address_type @ (AddressType::P2pkh
+            | AddressType::P2sh
+            | AddressType::P2wpkh
+            | AddressType::P2tr),
+        ) => get_types_address_type(&address_type),
+        _ => return Some(IntegrityIssue::AddressMismatch(address.to_string())),
+    };
+    let derivation_path = match address_ecdsa_pub_key
+        .derivation_path
+        .strip_prefix(&ecdsa_pub_key.derivation_path[..])
+    {
+        Some(derivation_path) => derivation_path,
+        None => return Some(IntegrityIssue::DerivationPathMismatch(address.to_string())),
+    };
+    let (derived_ecdsa_pub_key, derived_address) =
+        derive_ecdsa_public_key_and_address_from_extended_path(
+            derivation_path,
+            &address_type,
+            &address.network,
+            ecdsa_pub_key,
+        );
+    // The chain code isn't compared, as the one of the root key may be stored empty while its derivation pads it with zeros.
+    if derived_ecdsa_pub_key.public_key != address_ecdsa_pub_key.public_key
+        || derived_ecdsa_pub_key.derivation_path != address_ecdsa_pub_key.derivation_path
+    {
+        Some(IntegrityIssue::EcdsaPubKeyMismatch(address.to_string()))
+    } else if derived_address != *address {
+        Some(IntegrityIssue::AddressMismatch(address.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Returns the integrity issues of the managed addresses of the Bitcoin agent, lazily so that the first one is found without checking the others.
+/// Watch-only addresses, which don't have any ECDSA public key, aren't re-derived.
+fn get_integrity_issues<C: ManagementCanister>(
+    bitcoin_agent: &BitcoinAgent<C>,
+) -> Box<dyn Iterator<Item = IntegrityIssue> + '_> {
+    if bitcoin_agent.ecdsa_pub_key_addresses.is_empty() {
+        return Box::new(std::iter::empty());
+    }
+    let ecdsa_pub_key = bitcoin_agent.management_canister.get_ecdsa_public_key();
+    if get_btc_public_key_from_ecdsa_public_key(&ecdsa_pub_key).is_err() {
+        return Box::new(std::iter::once(IntegrityIssue::InvalidEcdsaPubKey));
+    }
+    Box::new(bitcoin_agent.ecdsa_pub_key_addresses.iter().flat_map(
+        move |(address, address_ecdsa_pub_key)| {
+            let missing_utxos_state = (!bitcoin_agent.utxos_state_addresses.contains_key(address))
+                .then(|| IntegrityIssue::MissingUtxosState(address.to_string()));
+            get_address_integrity_issue(address, address_ecdsa_pub_key, &ecdsa_pub_key)
+                .into_iter()
+                .chain(missing_utxos_state)
+        },
+    ))
+}
+
+/// Returns the integrity issues of the managed addresses of the Bitcoin agent, re-derived from its ECDSA public key at the derivation paths of their ECDSA public keys.
+pub(crate) fn verify_state_integrity<C: ManagementCanister>(
+    bitcoin_agent: &BitcoinAgent<C>,
+) -> Vec<IntegrityIssue> {
+    get_integrity_issues(bitcoin_agent).collect()
+}
+
+/// Returns the associated Bitcoin agent with the given `bitcoin_agent_state` without validating it.
+/// The unseen UTXOs states of the `unseen_same_as_seen_addresses` are restored as copies of their seen ones.
+/// Panics if the version of `bitcoin_agent_state` isn't the current one, states of previous versions having to be migrated first.
+pub(crate) fn from_state_unchecked<C: ManagementCanisterFactory>(
+    mut bitcoin_agent_state: BitcoinAgentState,
 ) -> BitcoinAgent<C> {
+    assert_eq!(
+        bitcoin_agent_state.version, BITCOIN_AGENT_STATE_VERSION,
+        "Unknown Bitcoin agent state version."
+    );
+    for address_using_primitives in &bitcoin_agent_state.unseen_same_as_seen_addresses {
+        let utxos_state = bitcoin_agent_state
+            .utxos_state_addresses
+            .get_mut(address_using_primitives)
+            .expect("Compacted address without UTXOs state.");
+        utxos_state.unseen_state = utxos_state.seen_state.clone();
+    }
     let ecdsa_pub_key_addresses: BTreeMap<Address, EcdsaPubKey> = bitcoin_agent_state
         .ecdsa_pub_key_addresses
         .into_iter()
         .map(|(address_using_primitives, ecdsa_pub_key)| {
-            (get_address(address_using_primitives), ecdsa_pub_key)
+            (
+                get_address(address_using_primitives).expect("Malformed state address."),
+                ecdsa_pub_key,
+            )
         })
         .collect();
 
@@ -52,20 +546,568 @@ pub(crate) fn from_state<C: ManagementCanister>(
         .utxos_state_addresses
         .into_iter()
         .map(|(address_using_primitives, utxos_state)| {
-            (get_address(address_using_primitives), utxos_state)
+            (
+                get_address(address_using_primitives).expect("Malformed state address."),
+                utxos_state,
+            )
         })
         .collect();
 
-    let management_canister = C::new_using_ecdsa_public_key(
+    let mut management_canister = C::new_using_ecdsa_public_key(
         bitcoin_agent_state.network,
         bitcoin_agent_state.ecdsa_pub_key,
+        Some(bitcoin_agent_state.ecdsa_key_name),
     );
+    management_canister.set_cycles_cost_config(bitcoin_agent_state.cycles_cost_config);
+    let time_source = management_canister.get_time_source();
     BitcoinAgent {
         management_canister,
         main_address_type: bitcoin_agent_state.main_address_type,
         ecdsa_pub_key_addresses,
         min_confirmations: bitcoin_agent_state.min_confirmations,
         utxos_state_addresses,
+        payout_queue: PayoutQueue::from_state(bitcoin_agent_state.payout_queue),
+        fee_cache: FeeCache::from_state(
+            bitcoin_agent_state.fee_cache_max_age,
+            bitcoin_agent_state.fee_smoothing,
+            bitcoin_agent_state.fee_history,
+        ),
+        standard_fee_percentile: bitcoin_agent_state.standard_fee_percentile,
+        target_blocks_percentiles: bitcoin_agent_state.target_blocks_percentiles,
+        max_fee_per_byte: bitcoin_agent_state.max_fee_per_byte,
+        sign_retry_config: bitcoin_agent_state.sign_retry_config,
+        sign_concurrency: bitcoin_agent_state.sign_concurrency,
+        key_rotations: bitcoin_agent_state.key_rotations,
+        rate_limiter: RefCell::new(RateLimiter::from_state(bitcoin_agent_state.rate_limits)),
+        poller: RefCell::default(),
+        retry_policy: DEFAULT_RETRY_POLICY,
+        allow_zero_value_utxos: false,
+        log_sink: Arc::new(NoopLogSink),
+        time_source,
+        cycles_cost_ceiling: DEFAULT_CYCLES_COST_CEILING,
+        #[cfg(feature = "stable-memory")]
+        stable_storage: None,
+    }
+}
+
+/// Migrates a Bitcoin agent state saved by an early revision to the unversioned layout, its missing settings getting their default values.
+fn migrate_legacy_v0_to_v0(bitcoin_agent_state: LegacyBitcoinAgentStateV0) -> BitcoinAgentStateV0 {
+    let network = from_types_network_to_bitcoin_network(bitcoin_agent_state.network);
+    BitcoinAgentStateV0 {
+        network: bitcoin_agent_state.network,
+        main_address_type: bitcoin_agent_state.main_address_type,
+        ecdsa_pub_key_addresses: bitcoin_agent_state.ecdsa_pub_key_addresses,
+        utxos_state_addresses: bitcoin_agent_state
+            .utxos_state_addresses
+            .into_iter()
+            .map(|(address, utxos_state)| {
+                (
+                    address,
+                    UtxosState {
+                        seen_state: utxos_state.seen_state,
+                        unseen_state: utxos_state.unseen_state,
+                        min_confirmations: utxos_state.min_confirmations,
+                        spent_state: utxos_state.spent_state.unwrap_or_default(),
+                        generated_state: utxos_state.generated_state.unwrap_or_default(),
+                    },
+                )
+            })
+            .collect(),
+        min_confirmations: bitcoin_agent_state.min_confirmations,
+        ecdsa_pub_key: bitcoin_agent_state.ecdsa_pub_key,
+        ecdsa_key_name: get_key_name_from_network(network),
+        payout_queue: PayoutQueue::default().get_state(),
+        fee_cache_max_age: DEFAULT_FEE_CACHE_MAX_AGE,
+        standard_fee_percentile: DEFAULT_STANDARD_FEE_PERCENTILE,
+        target_blocks_percentiles: BTreeMap::from(DEFAULT_TARGET_BLOCKS_PERCENTILES),
+        max_fee_per_byte: None,
+        sign_retry_config: DEFAULT_RETRY_CONFIG,
+        fee_smoothing: None,
+        fee_history: vec![],
+        key_rotations: vec![],
+        cycles_cost_config: CyclesCostConfig::for_network(network),
+        sign_concurrency: DEFAULT_SIGN_CONCURRENCY,
+    }
+}
+
+/// Migrates an unversioned Bitcoin agent state to the version 1, whose layout only adds the version.
+fn migrate_v0_to_v1(bitcoin_agent_state: BitcoinAgentStateV0) -> BitcoinAgentStateV1 {
+    BitcoinAgentStateV1 {
+        version: 1,
+        network: bitcoin_agent_state.network,
+        main_address_type: bitcoin_agent_state.main_address_type,
+        ecdsa_pub_key_addresses: bitcoin_agent_state.ecdsa_pub_key_addresses,
+        utxos_state_addresses: bitcoin_agent_state.utxos_state_addresses,
+        min_confirmations: bitcoin_agent_state.min_confirmations,
+        ecdsa_pub_key: bitcoin_agent_state.ecdsa_pub_key,
+        ecdsa_key_name: bitcoin_agent_state.ecdsa_key_name,
+        payout_queue: bitcoin_agent_state.payout_queue,
+        fee_cache_max_age: bitcoin_agent_state.fee_cache_max_age,
+        standard_fee_percentile: bitcoin_agent_state.standard_fee_percentile,
+        target_blocks_percentiles: bitcoin_agent_state.target_blocks_percentiles,
+        max_fee_per_byte: bitcoin_agent_state.max_fee_per_byte,
+        sign_retry_config: bitcoin_agent_state.sign_retry_config,
+        fee_smoothing: bitcoin_agent_state.fee_smoothing,
+        fee_history: bitcoin_agent_state.fee_history,
+        key_rotations: bitcoin_agent_state.key_rotations,
+        cycles_cost_config: bitcoin_agent_state.cycles_cost_config,
+        sign_concurrency: bitcoin_agent_state.sign_concurrency,
+    }
+}
+
+/// Migrates a Bitcoin agent state of the version 1 to the version 2, whose unseen UTXOs states are all stored as is.
+fn migrate_v1_to_v2(bitcoin_agent_state: BitcoinAgentStateV1) -> BitcoinAgentState {
+    BitcoinAgentState {
+        version: 2,
+        network: bitcoin_agent_state.network,
+        main_address_type: bitcoin_agent_state.main_address_type,
+        ecdsa_pub_key_addresses: bitcoin_agent_state.ecdsa_pub_key_addresses,
+        utxos_state_addresses: bitcoin_agent_state.utxos_state_addresses,
+        min_confirmations: bitcoin_agent_state.min_confirmations,
+        ecdsa_pub_key: bitcoin_agent_state.ecdsa_pub_key,
+        ecdsa_key_name: bitcoin_agent_state.ecdsa_key_name,
+        payout_queue: bitcoin_agent_state.payout_queue,
+        fee_cache_max_age: bitcoin_agent_state.fee_cache_max_age,
+        standard_fee_percentile: bitcoin_agent_state.standard_fee_percentile,
+        target_blocks_percentiles: bitcoin_agent_state.target_blocks_percentiles,
+        max_fee_per_byte: bitcoin_agent_state.max_fee_per_byte,
+        sign_retry_config: bitcoin_agent_state.sign_retry_config,
+        fee_smoothing: bitcoin_agent_state.fee_smoothing,
+        fee_history: bitcoin_agent_state.fee_history,
+        key_rotations: bitcoin_agent_state.key_rotations,
+        cycles_cost_config: bitcoin_agent_state.cycles_cost_config,
+        sign_concurrency: bitcoin_agent_state.sign_concurrency,
+        unseen_same_as_seen_addresses: BTreeSet::default(),
+        rate_limits: None,
+    }
+}
+
+/// Migrates a Bitcoin agent state decoded with the current layout from the version 2 to the version 3, which only adds the optional rate limits.
+/// States of the current version are returned as is.
+pub(crate) fn migrate_v2_to_v3(bitcoin_agent_state: BitcoinAgentState) -> BitcoinAgentState {
+    match bitcoin_agent_state.version {
+        2 => BitcoinAgentState {
+            version: 3,
+            ..bitcoin_agent_state
+        },
+        _ => bitcoin_agent_state,
+    }
+}
+
+impl From<BitcoinAgentStateV0> for BitcoinAgentState {
+    fn from(bitcoin_agent_state: BitcoinAgentStateV0) -> Self {
+        migrate_v2_to_v3(migrate_v1_to_v2(migrate_v0_to_v1(bitcoin_agent_state)))
+    }
+}
+
+impl From<LegacyBitcoinAgentStateV0> for BitcoinAgentState {
+    fn from(bitcoin_agent_state: LegacyBitcoinAgentStateV0) -> Self {
+        BitcoinAgentState::from(migrate_legacy_v0_to_v0(bitcoin_agent_state))
+    }
+}
+
+impl From<BitcoinAgentStateV1> for BitcoinAgentState {
+    fn from(bitcoin_agent_state: BitcoinAgentStateV1) -> Self {
+        migrate_v2_to_v3(migrate_v1_to_v2(bitcoin_agent_state))
+    }
+}
+
+/// The magic starting the bytes of an exported state.
+const STATE_BYTES_MAGIC: [u8; 4] = *b"BTCA";
+
+/// The number of bytes of the header of an exported state: the magic, the little-endian version and the SHA-256 checksum.
+const STATE_BYTES_HEADER_LENGTH: usize = STATE_BYTES_MAGIC.len() + 4 + 32;
+
+/// Returns the SHA-256 checksum of an exported state, covering its magic, its version and its encoded state.
+fn get_state_bytes_checksum(magic_and_version: &[u8], encoded_state: &[u8]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(magic_and_version);
+    engine.input(encoded_state);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Returns the state of the Bitcoin agent as self-describing bytes: the magic, the version of the state as a little-endian `u32`, the SHA-256 checksum of the other bytes and the state encoded with Candid.
+pub(crate) fn export_state_bytes<C: ManagementCanister>(
+    bitcoin_agent: &BitcoinAgent<C>,
+) -> Vec<u8> {
+    let encoded_state = get_state(bitcoin_agent).encode();
+    let mut bytes = Vec::with_capacity(STATE_BYTES_HEADER_LENGTH + encoded_state.len());
+    bytes.extend_from_slice(&STATE_BYTES_MAGIC);
+    bytes.extend_from_slice(&BITCOIN_AGENT_STATE_VERSION.to_le_bytes());
+    let checksum = get_state_bytes_checksum(&bytes, &encoded_state);
+    bytes.extend_from_slice(&checksum);
+    bytes.extend_from_slice(&encoded_state);
+    bytes
+}
+
+/// Returns the Bitcoin agent associated with the given bytes exported with `export_state_bytes`.
+/// The magic, the version and the checksum are verified before decoding the state, which is then validated like with `from_state`.
+pub(crate) fn import_state_bytes<C: ManagementCanisterFactory>(
+    bytes: &[u8],
+    expected_network: crate::Network,
+) -> Result<BitcoinAgent<C>, StateImportError> {
+    if bytes.len() < STATE_BYTES_HEADER_LENGTH {
+        return Err(StateImportError::Truncated);
+    }
+    let (magic_and_version, rest) = bytes.split_at(STATE_BYTES_MAGIC.len() + 4);
+    let (checksum, encoded_state) = rest.split_at(32);
+    let (magic, version) = magic_and_version.split_at(STATE_BYTES_MAGIC.len());
+    if magic != STATE_BYTES_MAGIC {
+        return Err(StateImportError::InvalidMagic);
+    }
+    let version = u32::from_le_bytes(version.try_into().unwrap());
+    // Exported states of previous versions are migrated when decoded.
+    if version == 0 || version > BITCOIN_AGENT_STATE_VERSION {
+        return Err(StateImportError::UnknownVersion(version));
+    }
+    if get_state_bytes_checksum(magic_and_version, encoded_state) != checksum {
+        return Err(StateImportError::ChecksumMismatch);
+    }
+    let bitcoin_agent_state = BitcoinAgentState::decode(encoded_state)
+        .map_err(|error| StateImportError::DecodeFailed(error.to_string()))?;
+    from_state(
+        bitcoin_agent_state,
+        StateRestoreOptions::new(expected_network),
+    )
+    .map_err(StateImportError::InvalidState)
+}
+
+/// Returns the memory used by the addresses of the Bitcoin agent and their cached UTXOs.
+pub(crate) fn get_memory_stats<C: ManagementCanister>(
+    bitcoin_agent: &BitcoinAgent<C>,
+) -> MemoryStats {
+    let ecdsa_pub_keys_bytes: usize = bitcoin_agent
+        .ecdsa_pub_key_addresses
+        .values()
+        .map(|ecdsa_pub_key| {
+            size_of::<Address>()
+                + size_of::<EcdsaPubKey>()
+                + ecdsa_pub_key.public_key.len()
+                + ecdsa_pub_key.chain_code.len()
+                + ecdsa_pub_key
+                    .derivation_path
+                    .iter()
+                    .map(|element| size_of::<Vec<u8>>() + element.len())
+                    .sum::<usize>()
+        })
+        .sum();
+    let utxo_bytes = |utxo: &Utxo| size_of::<Utxo>() + utxo.outpoint.txid.len();
+    let (mut utxos_cached, mut spent_entries, mut generated_entries) = (0, 0, 0);
+    let mut utxos_states_bytes = 0;
+    for utxos_state in bitcoin_agent.utxos_state_addresses.values() {
+        utxos_cached += utxos_state.seen_state.len() + utxos_state.unseen_state.len();
+        spent_entries += utxos_state.spent_state.len();
+        generated_entries += utxos_state.generated_state.len();
+        utxos_states_bytes += size_of::<Address>()
+            + size_of::<UtxosState>()
+            + utxos_state
+                .seen_state
+                .iter()
+                .chain(&utxos_state.unseen_state)
+                .chain(&utxos_state.generated_state)
+                .map(utxo_bytes)
+                .sum::<usize>()
+            + utxos_state
+                .spent_state
+                .iter()
+                .map(|outpoint| size_of::<OutPoint>() + outpoint.txid.len())
+                .sum::<usize>();
+    }
+    MemoryStats {
+        addresses: bitcoin_agent.utxos_state_addresses.len() as u64,
+        utxos_cached: utxos_cached as u64,
+        spent_entries: spent_entries as u64,
+        generated_entries: generated_entries as u64,
+        approx_bytes: (ecdsa_pub_keys_bytes + utxos_states_bytes) as u64,
+    }
+}
+
+/// Counts the bytes written to it, to measure an encoding without storing it.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0 += bytes.len();
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The number of bytes of the pages in which `save_state_chunked` writes the state, the size of a WebAssembly page of the stable memory.
+pub const STATE_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Buffers the bytes written to it into pages of `STATE_PAGE_SIZE` bytes, each full page being passed to `write_page` with its offset.
+struct PageWriter<F: FnMut(u64, &[u8])> {
+    write_page: F,
+    offset: u64,
+    page: Vec<u8>,
+}
+
+impl<F: FnMut(u64, &[u8])> PageWriter<F> {
+    /// Passes the buffered bytes to `write_page`, if any.
+    fn write_buffered_page(&mut self) {
+        if !self.page.is_empty() {
+            (self.write_page)(self.offset, &self.page);
+            self.offset += self.page.len() as u64;
+            self.page.clear();
+        }
+    }
+}
+
+impl<F: FnMut(u64, &[u8])> io::Write for PageWriter<F> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let length = bytes.len().min(STATE_PAGE_SIZE as usize - self.page.len());
+        self.page.extend_from_slice(&bytes[..length]);
+        if self.page.len() == STATE_PAGE_SIZE as usize {
+            self.write_buffered_page();
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffered_page();
+        Ok(())
+    }
+}
+
+/// Writes `bitcoin_agent_state` encoded with Candid, preceded by its length as a little-endian `u64`, by pages of `STATE_PAGE_SIZE` bytes, the last one being shorter.
+/// `write_page` is called with the offset of each page and its bytes, e.g. to write them with `stable64_write` or to an `ic-stable-structures` memory, which it has to grow if needed.
+/// The state is streamed into the pages, hence isn't encoded at once, unlike with `storage::stable_save((bitcoin_agent_state,))`.
+pub fn save_state_chunked(
+    bitcoin_agent_state: &BitcoinAgentState,
+    write_page: impl FnMut(u64, &[u8]),
+) {
+    let mut page_writer = PageWriter {
+        write_page,
+        offset: 0,
+        page: Vec::with_capacity(STATE_PAGE_SIZE as usize),
+    };
+    let length = bitcoin_agent_state.estimated_encoded_size() as u64;
+    io::Write::write_all(&mut page_writer, &length.to_le_bytes()).unwrap();
+    IDLBuilder::new()
+        .arg(bitcoin_agent_state)
+        .unwrap()
+        .serialize(&mut page_writer)
+        .unwrap();
+    page_writer.write_buffered_page();
+}
+
+/// Returns the Bitcoin agent state written with `save_state_chunked`, states of previous versions being migrated like with `BitcoinAgentState::decode`.
+/// `read_page` is called with an offset and a buffer of at most `STATE_PAGE_SIZE` bytes to fill with the written bytes at this offset, e.g. with `stable64_read`.
+pub fn load_state_chunked(
+    mut read_page: impl FnMut(u64, &mut [u8]),
+) -> Result<BitcoinAgentState, candid::Error> {
+    let mut length_bytes = [0; size_of::<u64>()];
+    read_page(0, &mut length_bytes);
+    let length = u64::from_le_bytes(length_bytes);
+    let mut bytes = vec![0; length as usize];
+    for (index, page) in bytes.chunks_mut(STATE_PAGE_SIZE as usize).enumerate() {
+        read_page(
+            length_bytes.len() as u64 + index as u64 * STATE_PAGE_SIZE,
+            page,
+        );
+    }
+    BitcoinAgentState::decode(&bytes)
+}
+
+impl BitcoinAgentState {
+    /// Encodes the Bitcoin agent state, e.g. to store it in the stable memory before an upgrade.
+    pub fn encode(&self) -> Vec<u8> {
+        Encode!(self).unwrap()
+    }
+
+    /// Returns the number of bytes of the Bitcoin agent state encoded with Candid, e.g. by `storage::stable_save((bitcoin_agent_state,))`.
+    /// It's computed by encoding the state into a byte counter, e.g. to check before an upgrade that the state fits in the stable memory.
+    pub fn estimated_encoded_size(&self) -> usize {
+        let mut byte_counter = ByteCounter(0);
+        IDLBuilder::new()
+            .arg(self)
+            .unwrap()
+            .serialize(&mut byte_counter)
+            .unwrap();
+        byte_counter.0
+    }
+
+    /// Decodes a Bitcoin agent state of any known version, migrating it to the current version.
+    /// The current layout is tried first, then the previous ones from the most recent to the oldest.
+    /// Fails with the error of the current layout if none of them matches, or if the state is of a newer version.
+    pub fn decode(bytes: &[u8]) -> Result<Self, candid::Error> {
+        match Decode!(bytes, BitcoinAgentState) {
+            Ok(bitcoin_agent_state)
+                if bitcoin_agent_state.version > BITCOIN_AGENT_STATE_VERSION =>
+            {
+                Err(candid::Error::msg(format!(
+                    "Unknown Bitcoin agent state version {}.",
+                    bitcoin_agent_state.version
+                )))
+            }
+            Ok(bitcoin_agent_state) => Ok(migrate_v2_to_v3(bitcoin_agent_state)),
+            Err(error) => Decode!(bytes, BitcoinAgentStateV1)
+                .map(BitcoinAgentState::from)
+                .or_else(|_| Decode!(bytes, BitcoinAgentStateV0).map(BitcoinAgentState::from))
+                .or_else(|_| Self::from_legacy_v0(bytes))
+                .map_err(|_| error),
+        }
+    }
+
+    /// Decodes a Bitcoin agent state saved by an early revision of the library with the layout of `LegacyBitcoinAgentStateV0`, migrating it to the current version.
+    /// The settings missing from this layout, e.g. the fee ones, get their default values.
+    pub fn from_legacy_v0(bytes: &[u8]) -> Result<Self, StateRestoreError> {
+        Decode!(bytes, LegacyBitcoinAgentStateV0)
+            .map(BitcoinAgentState::from)
+            .map_err(|error| StateRestoreError::DecodeFailed(error.to_string()))
+    }
+
+    /// Returns the differences between this state and `other`, e.g. to find out what differs between a pre-upgrade and a post-upgrade state.
+    /// The unseen UTXOs states stored as the same as the seen ones are compared as such.
+    pub fn diff(&self, other: &BitcoinAgentState) -> StateDiff {
+        let addresses = self.get_addresses();
+        let other_addresses = other.get_addresses();
+        let mut changed_min_confirmations = BTreeMap::new();
+        let mut utxo_count_deltas = BTreeMap::new();
+        for (address, utxos_state) in &self.utxos_state_addresses {
+            let other_utxos_state = match other.utxos_state_addresses.get(address) {
+                Some(other_utxos_state) => other_utxos_state,
+                None => continue,
+            };
+            if utxos_state.min_confirmations != other_utxos_state.min_confirmations {
+                changed_min_confirmations.insert(
+                    address.clone(),
+                    (
+                        utxos_state.min_confirmations,
+                        other_utxos_state.min_confirmations,
+                    ),
+                );
+            }
+            let utxo_count_delta = other.get_unseen_utxo_count(address, other_utxos_state) as i64
+                - self.get_unseen_utxo_count(address, utxos_state) as i64;
+            if utxo_count_delta != 0 {
+                utxo_count_deltas.insert(address.clone(), utxo_count_delta);
+            }
+        }
+        let changed_fields = [
+            ("version", self.version != other.version),
+            ("network", self.network != other.network),
+            (
+                "main_address_type",
+                self.main_address_type != other.main_address_type,
+            ),
+            (
+                "min_confirmations",
+                self.min_confirmations != other.min_confirmations,
+            ),
+            ("ecdsa_pub_key", self.ecdsa_pub_key != other.ecdsa_pub_key),
+            (
+                "ecdsa_key_name",
+                self.ecdsa_key_name != other.ecdsa_key_name,
+            ),
+            ("payout_queue", self.payout_queue != other.payout_queue),
+            (
+                "fee_cache_max_age",
+                self.fee_cache_max_age != other.fee_cache_max_age,
+            ),
+            (
+                "standard_fee_percentile",
+                self.standard_fee_percentile != other.standard_fee_percentile,
+            ),
+            (
+                "target_blocks_percentiles",
+                self.target_blocks_percentiles != other.target_blocks_percentiles,
+            ),
+            (
+                "max_fee_per_byte",
+                self.max_fee_per_byte != other.max_fee_per_byte,
+            ),
+            (
+                "sign_retry_config",
+                self.sign_retry_config != other.sign_retry_config,
+            ),
+            ("fee_smoothing", self.fee_smoothing != other.fee_smoothing),
+            ("fee_history", self.fee_history != other.fee_history),
+            ("key_rotations", self.key_rotations != other.key_rotations),
+            ("rate_limits", self.rate_limits != other.rate_limits),
+            (
+                "cycles_cost_config",
+                self.cycles_cost_config != other.cycles_cost_config,
+            ),
+            (
+                "sign_concurrency",
+                self.sign_concurrency != other.sign_concurrency,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(field, _)| String::from(field))
+        .collect();
+        StateDiff {
+            added_addresses: other_addresses
+                .difference(&addresses)
+                .map(|address| (*address).clone())
+                .collect(),
+            removed_addresses: addresses
+                .difference(&other_addresses)
+                .map(|address| (*address).clone())
+                .collect(),
+            changed_min_confirmations,
+            utxo_count_deltas,
+            changed_fields,
+        }
+    }
+
+    /// Returns the addresses of the state, including the watch-only ones.
+    fn get_addresses(&self) -> BTreeSet<&AddressUsingPrimitives> {
+        self.ecdsa_pub_key_addresses
+            .keys()
+            .chain(self.utxos_state_addresses.keys())
+            .collect()
+    }
+
+    /// Returns the number of UTXOs of the unseen UTXOs state of `address`, which is the one of its seen UTXOs state if it's stored as the same.
+    fn get_unseen_utxo_count(
+        &self,
+        address: &AddressUsingPrimitives,
+        utxos_state: &UtxosState,
+    ) -> usize {
+        if self.unseen_same_as_seen_addresses.contains(address) {
+            utxos_state.seen_state.len()
+        } else {
+            utxos_state.unseen_state.len()
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl BitcoinAgentState {
+    /// Encodes the Bitcoin agent state in CBOR, e.g. for off-chain backups.
+    /// The encoding is stable: the bytes start with the self-described CBOR tag 55799, structs are maps keyed by their field names, unit enum variants are their names, `None` is null and byte vectors are arrays of integers.
+    /// Addresses are encoded as their `AddressUsingPrimitives`, i.e. `[address, network]` arrays, and the `version` field identifies the layout of the state.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut serializer = serde_cbor::Serializer::new(&mut bytes);
+        serializer.self_describe().unwrap();
+        self.serialize(&mut serializer).unwrap();
+        bytes
+    }
+
+    /// Decodes a Bitcoin agent state encoded with `to_cbor`, states of previous versions being migrated to the current version.
+    /// Fails if the bytes aren't a Bitcoin agent state or if the state is of a newer version.
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, serde_cbor::Error> {
+        let bitcoin_agent_state = match serde_cbor::from_slice::<BitcoinAgentState>(bytes) {
+            Ok(bitcoin_agent_state) => bitcoin_agent_state,
+            Err(error) => serde_cbor::from_slice::<BitcoinAgentStateV1>(bytes)
+                .map(BitcoinAgentState::from)
+                .map_err(|_| error)?,
+        };
+        if bitcoin_agent_state.version > BITCOIN_AGENT_STATE_VERSION {
+            return Err(de::Error::custom(format!(
+                "Unknown Bitcoin agent state version {}.",
+                bitcoin_agent_state.version
+            )));
+        }
+        Ok(migrate_v2_to_v3(bitcoin_agent_state))
     }
 }
 
@@ -78,20 +1120,33 @@ pub(crate) fn get_address_using_primitives(address: &Address) -> AddressUsingPri
 }
 
 /// Returns the `bitcoin::Address` associated with a given `AddressUsingPrimitives`.
-pub(crate) fn get_address((address_string, address_network): AddressUsingPrimitives) -> Address {
-    let mut address = Address::from_str(&address_string).unwrap();
-    address.network = if cfg!(all(not(test), locally)) {
-        Network::Regtest
-    } else {
-        from_types_network_to_bitcoin_network(address_network)
-    };
-    address
+/// Fails if the address string is malformed, e.g. if it comes from a corrupted state or result.
+pub(crate) fn get_address(
+    (address_string, address_network): AddressUsingPrimitives,
+) -> Result<Address, address::Error> {
+    // The network is the one of the primitives rather than the one of the prefix, shared by the testnet and the regtest.
+    let mut address = Address::from_str(&address_string)?.assume_checked();
+    address.network = from_types_network_to_bitcoin_network(address_network);
+    Ok(address)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{agent, canister_mock::ManagementCanisterMock, AddressType, Network};
+    #[cfg(feature = "serde")]
+    use crate::Satoshi;
+    use crate::{
+        agent,
+        canister_common::{SIGN_13_NODE_COST_CYCLES, SIGN_34_NODE_COST_CYCLES},
+        canister_mock::{
+            get_balance_update, get_init_balance_update, get_init_utxos, ManagementCanisterMock,
+        },
+        AddressType, BalanceUpdate, DerivationPath, Fee, FeeRequest, Network, UtxosResult,
+    };
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
 
     /// Check that `get_state` and `from_state` return respectively the Bitcoin agent state and the Bitcoin agent associated with the former Bitcoin agent state.
     #[test]
@@ -102,8 +1157,1285 @@ mod tests {
 
         let pre_upgrade_state = pre_upgrade_bitcoin_agent.get_state();
         let post_upgrade_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
-            BitcoinAgent::from_state(pre_upgrade_state.clone());
+            BitcoinAgent::from_state(pre_upgrade_state.clone(), Network::Regtest).unwrap();
+
+        let post_upgrade_state = post_upgrade_bitcoin_agent.get_state();
+        assert_eq!(
+            post_upgrade_state,
+            pre_upgrade_state,
+            "{:?}",
+            pre_upgrade_state.diff(&post_upgrade_state)
+        );
+    }
+
+    /// Check that calling `initialize` on a Bitcoin agent restored with `from_state` keeps its derived addresses and their UTXOs states.
+    #[test]
+    fn check_initialize_after_upgrade() {
+        let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> = BitcoinAgent::new(
+            ManagementCanisterMock::new(Network::Regtest, None),
+            &AddressType::P2pkh,
+            0,
+        )
+        .unwrap();
+        assert!(!bitcoin_agent.is_initialized());
+        let ecdsa_pub_key = get_populated_state().ecdsa_pub_key;
+        assert!(bitcoin_agent.initialize(ecdsa_pub_key.clone()));
+        assert!(bitcoin_agent.is_initialized());
+        let derived_address = bitcoin_agent.add_address(&[vec![1]]).unwrap();
+        let main_address = bitcoin_agent.get_main_address();
+        // The mock created without an ECDSA public key doesn't fund the main address.
+        bitcoin_agent
+            .management_canister
+            .chain()
+            .utxos_addresses
+            .insert(main_address.clone(), get_init_utxos());
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, &main_address, 0),
+            get_init_balance_update()
+        );
+        assert!(!bitcoin_agent.initialize(ecdsa_pub_key.clone()));
+
+        let state = bitcoin_agent.get_state();
+        let mut restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(state.clone(), Network::Regtest).unwrap();
+        assert!(restored_bitcoin_agent.is_initialized());
+        assert!(!restored_bitcoin_agent.initialize(ecdsa_pub_key));
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert!(restored_bitcoin_agent
+            .list_addresses()
+            .contains(&&derived_address));
+        assert_eq!(
+            get_balance_update(&mut restored_bitcoin_agent, &main_address, 0),
+            BalanceUpdate::new()
+        );
+    }
+
+    /// Check that the ECDSA key name defaults to the one of the network, flows into the arguments structures and is restored from the state.
+    #[test]
+    fn check_ecdsa_key_name() {
+        let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        assert_eq!(
+            bitcoin_agent.get_initialization_parameters_args().key_name,
+            "dfx_test_key"
+        );
+
+        let bitcoin_agent = BitcoinAgent::new(
+            ManagementCanisterMock::new(Network::Mainnet, Some(String::from("test_key_1"))),
+            &AddressType::P2pkh,
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            bitcoin_agent.get_initialization_parameters_args().key_name,
+            "test_key_1"
+        );
+        let change_address = &Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")
+            .unwrap()
+            .assume_checked();
+        let multi_transfer_args = bitcoin_agent.get_multi_transfer_args(
+            &BTreeMap::new(),
+            change_address,
+            Fee::Standard,
+            0,
+            false,
+        );
+        assert_eq!(multi_transfer_args.key_name, "test_key_1");
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Mainnet).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent
+                .get_initialization_parameters_args()
+                .key_name,
+            "test_key_1"
+        );
+    }
+
+    /// Check that the cycles cost configuration defaults to the one of the network, flows into the arguments structures and is restored from the state.
+    #[test]
+    fn check_cycles_cost_config() {
+        let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        assert_eq!(
+            bitcoin_agent
+                .get_current_fees_args()
+                .unwrap()
+                .cycles_cost_config
+                .sign_with_ecdsa,
+            SIGN_13_NODE_COST_CYCLES
+        );
+
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Mainnet, &AddressType::P2pkh);
+        let cycles_cost_config = bitcoin_agent.management_canister.get_cycles_cost_config();
+        assert_eq!(cycles_cost_config.sign_with_ecdsa, SIGN_34_NODE_COST_CYCLES);
+        assert_eq!(
+            cycles_cost_config.sign_with_schnorr,
+            SIGN_34_NODE_COST_CYCLES
+        );
+
+        let cycles_cost_config = CyclesCostConfig {
+            get_utxos: 1,
+            get_fees: 2,
+            send_tx: 3,
+            send_tx_per_byte: 4,
+            sign_with_ecdsa: 5,
+            sign_with_schnorr: 6,
+        };
+        bitcoin_agent
+            .management_canister
+            .set_cycles_cost_config(cycles_cost_config);
+        let main_address = &bitcoin_agent.get_main_address();
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_args(main_address, 0)
+                .unwrap()
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+        assert_eq!(
+            bitcoin_agent
+                .get_current_fees_args()
+                .unwrap()
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+        assert_eq!(
+            bitcoin_agent
+                .get_current_fee_args(FeeRequest::Standard)
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+        assert_eq!(
+            bitcoin_agent.get_fee_suggestions_args().cycles_cost_config,
+            cycles_cost_config
+        );
+        assert_eq!(
+            bitcoin_agent
+                .get_sign_message_args(main_address, b"message")
+                .unwrap()
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+        assert_eq!(
+            bitcoin_agent
+                .get_flush_args(Fee::Standard, main_address)
+                .cycles_cost_config,
+            cycles_cost_config
+        );
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(bitcoin_agent.get_state(), Network::Mainnet).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent
+                .management_canister
+                .get_cycles_cost_config(),
+            cycles_cost_config
+        );
+    }
+
+    /// Returns the unversioned layout of the given Bitcoin agent state.
+    fn get_state_v0(bitcoin_agent_state: BitcoinAgentState) -> BitcoinAgentStateV0 {
+        BitcoinAgentStateV0 {
+            network: bitcoin_agent_state.network,
+            main_address_type: bitcoin_agent_state.main_address_type,
+            ecdsa_pub_key_addresses: bitcoin_agent_state.ecdsa_pub_key_addresses,
+            utxos_state_addresses: bitcoin_agent_state.utxos_state_addresses,
+            min_confirmations: bitcoin_agent_state.min_confirmations,
+            ecdsa_pub_key: bitcoin_agent_state.ecdsa_pub_key,
+            ecdsa_key_name: bitcoin_agent_state.ecdsa_key_name,
+            payout_queue: bitcoin_agent_state.payout_queue,
+            fee_cache_max_age: bitcoin_agent_state.fee_cache_max_age,
+            standard_fee_percentile: bitcoin_agent_state.standard_fee_percentile,
+            target_blocks_percentiles: bitcoin_agent_state.target_blocks_percentiles,
+            max_fee_per_byte: bitcoin_agent_state.max_fee_per_byte,
+            sign_retry_config: bitcoin_agent_state.sign_retry_config,
+            fee_smoothing: bitcoin_agent_state.fee_smoothing,
+            fee_history: bitcoin_agent_state.fee_history,
+            key_rotations: bitcoin_agent_state.key_rotations,
+            cycles_cost_config: bitcoin_agent_state.cycles_cost_config,
+            sign_concurrency: bitcoin_agent_state.sign_concurrency,
+        }
+    }
+
+    /// Returns the layout of the version 1 of the given Bitcoin agent state, whose unseen UTXOs states mustn't be compacted.
+    fn get_state_v1(bitcoin_agent_state: BitcoinAgentState) -> BitcoinAgentStateV1 {
+        BitcoinAgentStateV1 {
+            version: 1,
+            network: bitcoin_agent_state.network,
+            main_address_type: bitcoin_agent_state.main_address_type,
+            ecdsa_pub_key_addresses: bitcoin_agent_state.ecdsa_pub_key_addresses,
+            utxos_state_addresses: bitcoin_agent_state.utxos_state_addresses,
+            min_confirmations: bitcoin_agent_state.min_confirmations,
+            ecdsa_pub_key: bitcoin_agent_state.ecdsa_pub_key,
+            ecdsa_key_name: bitcoin_agent_state.ecdsa_key_name,
+            payout_queue: bitcoin_agent_state.payout_queue,
+            fee_cache_max_age: bitcoin_agent_state.fee_cache_max_age,
+            standard_fee_percentile: bitcoin_agent_state.standard_fee_percentile,
+            target_blocks_percentiles: bitcoin_agent_state.target_blocks_percentiles,
+            max_fee_per_byte: bitcoin_agent_state.max_fee_per_byte,
+            sign_retry_config: bitcoin_agent_state.sign_retry_config,
+            fee_smoothing: bitcoin_agent_state.fee_smoothing,
+            fee_history: bitcoin_agent_state.fee_history,
+            key_rotations: bitcoin_agent_state.key_rotations,
+            cycles_cost_config: bitcoin_agent_state.cycles_cost_config,
+            sign_concurrency: bitcoin_agent_state.sign_concurrency,
+        }
+    }
+
+    /// Check that the encoded states are decoded whatever their version, the ones of previous versions being migrated to the current version.
+    #[test]
+    fn check_state_versions() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.queue_payout(&bitcoin_agent.get_main_address(), 10_000);
+        let state = bitcoin_agent.get_state();
+        assert_eq!(state.version, BITCOIN_AGENT_STATE_VERSION);
+        assert_eq!(BitcoinAgentState::decode(&state.encode()).unwrap(), state);
+
+        // The bytes stored by the releases preceding the versioning of the state.
+        let state_v0_bytes = Encode!(&get_state_v0(state.clone())).unwrap();
+        assert!(Decode!(&state_v0_bytes, BitcoinAgentState).is_err());
+        let migrated_state = BitcoinAgentState::decode(&state_v0_bytes).unwrap();
+        assert_eq!(migrated_state, state);
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(
+                Decode!(&state_v0_bytes, BitcoinAgentStateV0).unwrap(),
+                Network::Regtest,
+            )
+            .unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert_eq!(restored_bitcoin_agent.queued_total(), 10_000);
+
+        // The bytes stored by the releases of the version 1 of the state.
+        let state_v1_bytes = Encode!(&get_state_v1(state.clone())).unwrap();
+        assert!(Decode!(&state_v1_bytes, BitcoinAgentState).is_err());
+        assert_eq!(BitcoinAgentState::decode(&state_v1_bytes).unwrap(), state);
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(
+                Decode!(&state_v1_bytes, BitcoinAgentStateV1).unwrap(),
+                Network::Regtest,
+            )
+            .unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+
+        // The bytes stored by the releases of the version 2 of the state, which are decoded with the current layout.
+        let state_v2_bytes = BitcoinAgentState {
+            version: 2,
+            ..state.clone()
+        }
+        .encode();
+        assert_eq!(BitcoinAgentState::decode(&state_v2_bytes).unwrap(), state);
+
+        // States of newer versions and invalid bytes aren't decoded.
+        let newer_state = BitcoinAgentState {
+            version: BITCOIN_AGENT_STATE_VERSION + 1,
+            ..state
+        };
+        assert!(BitcoinAgentState::decode(&newer_state.encode()).is_err());
+        assert!(BitcoinAgentState::decode(&[0; 8]).is_err());
+    }
+
+    /// Check that the states saved by early revisions of the library, with and without the spent and generated UTXOs states, are restored into functioning Bitcoin agents.
+    #[test]
+    fn check_legacy_states() {
+        let fixtures: [&[u8]; 2] = [
+            include_bytes!("../fixtures/bitcoin_agent_state_legacy_v0.bin"),
+            include_bytes!("../fixtures/bitcoin_agent_state_legacy_v0_without_spent_state.bin"),
+        ];
+        for fixture in fixtures {
+            let state = BitcoinAgentState::from_legacy_v0(fixture).unwrap();
+            assert_eq!(state.version, BITCOIN_AGENT_STATE_VERSION);
+            assert_eq!(state.network, Network::Testnet);
+            assert_eq!(state.ecdsa_key_name, "test_key_1");
+            assert_eq!(state.payout_queue, PayoutQueue::default().get_state());
+            assert_eq!(BitcoinAgentState::decode(fixture).unwrap(), state);
+
+            let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+                BitcoinAgent::from_state(state.clone(), Network::Testnet).unwrap();
+            let main_address = bitcoin_agent.get_main_address();
+            assert_eq!(
+                main_address.to_string(),
+                "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r"
+            );
+            assert_eq!(
+                bitcoin_agent.utxos_state_addresses[&main_address].seen_state,
+                get_init_utxos()
+            );
+            // The seen UTXOs state already holds the UTXO of the main address, hence no balance update.
+            assert_eq!(
+                get_balance_update(&mut bitcoin_agent, &main_address, 0),
+                BalanceUpdate::new()
+            );
+        }
+
+        assert!(matches!(
+            BitcoinAgentState::from_legacy_v0(&[0; 8]),
+            Err(StateRestoreError::DecodeFailed(_))
+        ));
+    }
+
+    /// Returns the state of a Bitcoin agent managing multiple addresses, whose every field is populated.
+    fn get_populated_state() -> BitcoinAgentState {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = bitcoin_agent.get_main_address();
+        let p2wpkh_address = bitcoin_agent
+            .add_address_with_parameters(&[vec![2]], &AddressType::P2wpkh, 1)
+            .unwrap();
+        bitcoin_agent.queue_payout(&main_address, 10_000);
+        let main_address = get_address_using_primitives(&main_address);
+        let p2wpkh_address = get_address_using_primitives(&p2wpkh_address);
+        let mut state = bitcoin_agent.get_state();
+        let utxos_state = state.utxos_state_addresses.get_mut(&main_address).unwrap();
+        utxos_state.seen_state = get_init_utxos();
+        utxos_state.unseen_state = [get_init_utxos(), vec![get_synthetic_utxo(0)]].concat();
+        utxos_state.spent_state = vec![get_init_utxos()[0].outpoint.clone()];
+        utxos_state.generated_state = get_init_utxos();
+        // The unseen UTXOs state of the P2WPKH address is the same as its seen one.
+        state
+            .utxos_state_addresses
+            .get_mut(&p2wpkh_address)
+            .unwrap()
+            .seen_state = get_init_utxos();
+        BitcoinAgentState {
+            max_fee_per_byte: Some(100_000),
+            fee_smoothing: Some(FeeSmoothing { window: 3 }),
+            fee_history: vec![vec![1_000, 2_000], vec![3_000, 4_000]],
+            key_rotations: vec![KeyRotation {
+                previous_ecdsa_pub_key: state.ecdsa_pub_key.clone(),
+                rekeyed_addresses: BTreeMap::from([(main_address.clone(), main_address)]),
+            }],
+            unseen_same_as_seen_addresses: BTreeSet::from([p2wpkh_address]),
+            rate_limits: Some(BTreeMap::from([(
+                RateLimitedOp::GetUtxos,
+                RateLimitState {
+                    max_calls_per_window: 10,
+                    window_len: 6,
+                    window_start: 100,
+                    calls_in_window: 3,
+                },
+            )])),
+            ..state
+        }
+    }
+
+    /// Check that the unseen UTXOs states which are the same as the seen ones are stored once, the Bitcoin agents restored from the states captured before and after `update_state` behaving like the original one.
+    #[test]
+    fn check_state_compaction() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = bitcoin_agent.get_main_address();
+        bitcoin_agent.apply_utxos(UtxosResult {
+            address: main_address.clone(),
+            utxos: (0..100).map(get_synthetic_utxo).collect(),
+            tip_height: 100,
+        });
+        let check_restored_bitcoin_agent = |bitcoin_agent: &BitcoinAgent<
+            ManagementCanisterMock,
+        >| {
+            let state = bitcoin_agent.get_state();
+            let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+                BitcoinAgent::from_state(
+                    BitcoinAgentState::decode(&state.encode()).unwrap(),
+                    Network::Regtest,
+                )
+                .unwrap();
+            assert_eq!(restored_bitcoin_agent.get_state(), state);
+            assert_eq!(
+                restored_bitcoin_agent.utxos_state_addresses,
+                bitcoin_agent.utxos_state_addresses
+            );
+            // The UTXOs of the updates are sorted, as their order depends on the iteration order of a `HashSet`.
+            let get_sorted_updates = |bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>| {
+                let mut utxos_update = bitcoin_agent.peek_utxos_update(&main_address).unwrap();
+                utxos_update.added_utxos.sort();
+                utxos_update.removed_utxos.sort();
+                let mut balance_update = bitcoin_agent.peek_balance_update(&main_address).unwrap();
+                balance_update.added_outpoints.sort();
+                balance_update.removed_outpoints.sort();
+                (utxos_update, balance_update)
+            };
+            assert_eq!(
+                get_sorted_updates(&restored_bitcoin_agent),
+                get_sorted_updates(bitcoin_agent)
+            );
+            state
+        };
+
+        // Before `update_state`, the unseen UTXOs state differs from the seen one.
+        let state = check_restored_bitcoin_agent(&bitcoin_agent);
+        assert!(state.unseen_same_as_seen_addresses.is_empty());
+        let main_address_using_primitives = get_address_using_primitives(&main_address);
+        let corrupted_state = BitcoinAgentState {
+            unseen_same_as_seen_addresses: BTreeSet::from([main_address_using_primitives.clone()]),
+            ..state
+        };
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::InvalidCompactedAddress(main_address_using_primitives.0.clone())
+        );
+
+        // After `update_state`, the unseen UTXOs state is only stored as the same as the seen one.
+        bitcoin_agent.update_state(&main_address).unwrap();
+        let state = check_restored_bitcoin_agent(&bitcoin_agent);
+        assert_eq!(
+            state.unseen_same_as_seen_addresses,
+            BTreeSet::from([main_address_using_primitives.clone()])
+        );
+        let utxos_state = &state.utxos_state_addresses[&main_address_using_primitives];
+        assert_eq!(utxos_state.seen_state.len(), 100);
+        assert!(utxos_state.unseen_state.is_empty());
+        let mut bytes = vec![];
+        bitcoin_agent.write_state(&mut bytes).unwrap();
+        assert_eq!(bytes, state.encode());
+
+        // The uncompacted state is restored as well, but is much larger.
+        let mut uncompacted_state = BitcoinAgentState {
+            unseen_same_as_seen_addresses: BTreeSet::default(),
+            ..state.clone()
+        };
+        for utxos_state in uncompacted_state.utxos_state_addresses.values_mut() {
+            utxos_state.unseen_state = utxos_state.seen_state.clone();
+        }
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(uncompacted_state.clone(), Network::Regtest).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert!(state.encode().len() * 3 / 2 < uncompacted_state.encode().len());
+    }
+
+    /// Check that restoring only the addresses of a state clears their UTXOs states, their balances being recovered as added once their UTXOs are fetched again.
+    #[test]
+    fn check_addresses_only_restoration() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = bitcoin_agent.get_main_address();
+        bitcoin_agent
+            .add_address_with_parameters(&[vec![1]], &AddressType::P2wpkh, 1)
+            .unwrap();
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, &main_address, 0),
+            get_init_balance_update()
+        );
+        // The caches of the main address are stale.
+        let utxos_state = bitcoin_agent
+            .utxos_state_addresses
+            .get_mut(&main_address)
+            .unwrap();
+        utxos_state.spent_state = vec![get_init_utxos()[0].outpoint.clone()];
+        utxos_state.generated_state = vec![get_synthetic_utxo(0)];
+        let state = bitcoin_agent.get_state();
+
+        let mut restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state_addresses_only(state, Network::Regtest).unwrap();
+        assert_eq!(
+            restored_bitcoin_agent.ecdsa_pub_key_addresses,
+            bitcoin_agent.ecdsa_pub_key_addresses
+        );
+        for (address, utxos_state) in &bitcoin_agent.utxos_state_addresses {
+            assert_eq!(
+                restored_bitcoin_agent.utxos_state_addresses[address],
+                UtxosState::new(utxos_state.min_confirmations)
+            );
+        }
+
+        assert_eq!(
+            get_balance_update(&mut restored_bitcoin_agent, &main_address, 0),
+            get_init_balance_update()
+        );
+        assert_eq!(
+            restored_bitcoin_agent.get_balance_update(&main_address),
+            Ok(BalanceUpdate::new())
+        );
+    }
+
+    /// Check that the diff of two states reports their added and removed addresses, the changed minimum numbers of confirmations, the UTXO count deltas and the other changed fields.
+    #[test]
+    fn check_state_diff() {
+        let state = get_populated_state();
+        assert!(state.diff(&state).is_empty());
+
+        let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(state.clone(), Network::Regtest).unwrap();
+        let main_address = get_address_using_primitives(&bitcoin_agent.get_main_address());
+        let added_address =
+            get_address_using_primitives(&bitcoin_agent.add_address(&[vec![5]]).unwrap());
+        let p2wpkh_address = state
+            .unseen_same_as_seen_addresses
+            .iter()
+            .next()
+            .unwrap()
+            .clone();
+        let mut other_state = bitcoin_agent.get_state();
+        other_state.ecdsa_pub_key_addresses.remove(&p2wpkh_address);
+        other_state.utxos_state_addresses.remove(&p2wpkh_address);
+        other_state
+            .unseen_same_as_seen_addresses
+            .remove(&p2wpkh_address);
+        let min_confirmations = state.utxos_state_addresses[&main_address].min_confirmations;
+        let main_utxos_state = other_state
+            .utxos_state_addresses
+            .get_mut(&main_address)
+            .unwrap();
+        main_utxos_state.min_confirmations = min_confirmations + 1;
+        main_utxos_state.unseen_state.clear();
+        other_state.fee_history.clear();
+        other_state.sign_concurrency += 1;
+        assert_eq!(
+            state.diff(&other_state),
+            StateDiff {
+                added_addresses: vec![added_address.clone()],
+                removed_addresses: vec![p2wpkh_address.clone()],
+                changed_min_confirmations: BTreeMap::from([(
+                    main_address.clone(),
+                    (min_confirmations, min_confirmations + 1)
+                )]),
+                utxo_count_deltas: BTreeMap::from([(main_address.clone(), -2)]),
+                changed_fields: vec![
+                    String::from("fee_history"),
+                    String::from("sign_concurrency")
+                ],
+            }
+        );
+        let reverse_diff = other_state.diff(&state);
+        assert_eq!(reverse_diff.added_addresses, vec![p2wpkh_address.clone()]);
+        assert_eq!(reverse_diff.removed_addresses, vec![added_address]);
+        assert_eq!(
+            reverse_diff.utxo_count_deltas,
+            BTreeMap::from([(main_address, 2)])
+        );
+
+        // An unseen UTXOs state stored as the same as the seen one doesn't differ from its copy.
+        let mut uncompacted_state = state.clone();
+        uncompacted_state.unseen_same_as_seen_addresses.clear();
+        let p2wpkh_utxos_state = uncompacted_state
+            .utxos_state_addresses
+            .get_mut(&p2wpkh_address)
+            .unwrap();
+        p2wpkh_utxos_state.unseen_state = p2wpkh_utxos_state.seen_state.clone();
+        assert!(state.diff(&uncompacted_state).is_empty());
+    }
+
+    /// Check that a fully populated state is preserved when passed across the Candid boundary.
+    #[test]
+    fn check_state_candid_encoding() {
+        let state = get_populated_state();
+        let decoded_state: BitcoinAgentState =
+            candid::decode_one(&candid::encode_one(&state).unwrap()).unwrap();
+        assert_eq!(decoded_state, state);
+    }
+
+    /// Returns the error of `import_state_bytes` for the given bytes.
+    fn get_state_import_error(bytes: &[u8]) -> StateImportError {
+        BitcoinAgent::<ManagementCanisterMock>::import_state_bytes(bytes, Network::Regtest)
+            .err()
+            .unwrap()
+    }
+
+    /// Check that exported state bytes are imported back, and that corrupting a single byte of each region is reported by the corresponding error.
+    #[test]
+    fn check_state_bytes() {
+        let state = get_populated_state();
+        let bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(state.clone(), Network::Regtest).unwrap();
+        let bytes = bitcoin_agent.export_state_bytes();
+        assert_eq!(bytes[..4], *b"BTCA");
+        assert_eq!(
+            BitcoinAgent::<ManagementCanisterMock>::import_state_bytes(&bytes, Network::Regtest)
+                .unwrap()
+                .get_state(),
+            state
+        );
+        assert_eq!(
+            BitcoinAgent::<ManagementCanisterMock>::import_state_bytes(&bytes, Network::Testnet)
+                .err()
+                .unwrap(),
+            StateImportError::InvalidState(StateRestoreError::NetworkMismatch {
+                expected: Network::Testnet,
+                found: Network::Regtest,
+            })
+        );
+
+        let corrupt = |index: usize| {
+            let mut corrupted_bytes = bytes.clone();
+            corrupted_bytes[index] ^= 0xff;
+            corrupted_bytes
+        };
+        for index in 0..4 {
+            assert_eq!(
+                get_state_import_error(&corrupt(index)),
+                StateImportError::InvalidMagic
+            );
+        }
+        for index in 4..8 {
+            let corrupted_bytes = corrupt(index);
+            let version = u32::from_le_bytes(corrupted_bytes[4..8].try_into().unwrap());
+            assert_eq!(
+                get_state_import_error(&corrupted_bytes),
+                StateImportError::UnknownVersion(version)
+            );
+        }
+        for index in [8, 20, 39, 40, bytes.len() / 2, bytes.len() - 1] {
+            assert_eq!(
+                get_state_import_error(&corrupt(index)),
+                StateImportError::ChecksumMismatch
+            );
+        }
+        assert_eq!(
+            get_state_import_error(&bytes[..STATE_BYTES_HEADER_LENGTH - 1]),
+            StateImportError::Truncated
+        );
+
+        // A corrupted encoded state whose checksum is recomputed fails to decode.
+        let mut corrupted_bytes = bytes[..8].to_vec();
+        let encoded_state = b"DIDL corrupted";
+        corrupted_bytes.extend_from_slice(&get_state_bytes_checksum(&bytes[..8], encoded_state));
+        corrupted_bytes.extend_from_slice(encoded_state);
+        assert!(matches!(
+            get_state_import_error(&corrupted_bytes),
+            StateImportError::DecodeFailed(_)
+        ));
+
+        // A decoded state which can't be restored is rejected.
+        let corrupted_state = BitcoinAgentState {
+            min_confirmations: MIN_CONFIRMATIONS_UPPER_BOUND + 1,
+            ..state
+        };
+        let encoded_state = corrupted_state.encode();
+        let mut corrupted_bytes = bytes[..8].to_vec();
+        corrupted_bytes.extend_from_slice(&get_state_bytes_checksum(&bytes[..8], &encoded_state));
+        corrupted_bytes.extend_from_slice(&encoded_state);
+        assert_eq!(
+            get_state_import_error(&corrupted_bytes),
+            StateImportError::InvalidState(StateRestoreError::MinConfirmationsTooHigh)
+        );
+    }
+
+    /// Check that a fully populated state is preserved when encoded in CBOR.
+    #[test]
+    #[cfg(feature = "serde")]
+    fn check_state_cbor_encoding() {
+        let state = get_populated_state();
+        assert_eq!(
+            BitcoinAgentState::from_cbor(&state.to_cbor()).unwrap(),
+            state
+        );
+
+        let newer_state = BitcoinAgentState {
+            version: BITCOIN_AGENT_STATE_VERSION + 1,
+            ..state
+        };
+        assert!(BitcoinAgentState::from_cbor(&newer_state.to_cbor()).is_err());
+        assert!(BitcoinAgentState::from_cbor(&[0; 8]).is_err());
+    }
+
+    /// Returns a state of fixed values, whose CBOR encoding is `fixtures/bitcoin_agent_state.cbor`.
+    #[cfg(feature = "serde")]
+    fn get_fixture_state() -> BitcoinAgentState {
+        let ecdsa_pub_key = |public_key_byte: u8, derivation_path: Vec<Vec<u8>>| EcdsaPubKey {
+            public_key: vec![public_key_byte; 33],
+            chain_code: vec![1; 32],
+            derivation_path: DerivationPath::new(derivation_path).unwrap(),
+        };
+        let utxo = |txid_byte: u8, value: Satoshi| Utxo {
+            outpoint: OutPoint {
+                txid: vec![txid_byte; 32],
+                vout: 0,
+            },
+            value,
+            height: 100,
+        };
+        let main_address = (
+            String::from("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"),
+            Network::Testnet,
+        );
+        let p2wpkh_address = (
+            String::from("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"),
+            Network::Testnet,
+        );
+        BitcoinAgentState {
+            version: BITCOIN_AGENT_STATE_VERSION,
+            network: Network::Testnet,
+            main_address_type: AddressType::P2pkh,
+            ecdsa_pub_key_addresses: BTreeMap::from([
+                (main_address.clone(), ecdsa_pub_key(2, vec![vec![0]])),
+                (
+                    p2wpkh_address.clone(),
+                    ecdsa_pub_key(3, vec![vec![0], vec![1]]),
+                ),
+            ]),
+            utxos_state_addresses: BTreeMap::from([
+                (
+                    main_address.clone(),
+                    UtxosState {
+                        seen_state: vec![utxo(1, 250_000)],
+                        unseen_state: vec![utxo(1, 250_000), utxo(2, 50_000)],
+                        min_confirmations: 0,
+                        spent_state: vec![utxo(1, 250_000).outpoint],
+                        generated_state: vec![utxo(3, 200_000)],
+                    },
+                ),
+                (p2wpkh_address.clone(), UtxosState::new(1)),
+            ]),
+            min_confirmations: 0,
+            ecdsa_pub_key: ecdsa_pub_key(2, vec![vec![0]]),
+            ecdsa_key_name: String::from("dfx_test_key"),
+            payout_queue: PayoutQueueState {
+                next_queue_id: 1,
+                queued_payouts: BTreeMap::from([(0, (p2wpkh_address.clone(), 10_000))]),
+            },
+            fee_cache_max_age: 1,
+            standard_fee_percentile: 50,
+            target_blocks_percentiles: BTreeMap::from([(1, 75), (6, 50)]),
+            max_fee_per_byte: Some(100_000),
+            sign_retry_config: RetryConfig { max_attempts: 3 },
+            fee_smoothing: Some(FeeSmoothing { window: 3 }),
+            fee_history: vec![vec![1_000, 2_000]],
+            key_rotations: vec![KeyRotation {
+                previous_ecdsa_pub_key: ecdsa_pub_key(4, vec![vec![0]]),
+                rekeyed_addresses: BTreeMap::from([(main_address, p2wpkh_address)]),
+            }],
+            cycles_cost_config: CyclesCostConfig {
+                get_utxos: 10_000_000_000,
+                get_fees: 100_000_000,
+                send_tx: 5_000_000_000,
+                send_tx_per_byte: 20_000_000,
+                sign_with_ecdsa: 10_000_000_000,
+                sign_with_schnorr: 10_000_000_000,
+            },
+            sign_concurrency: 8,
+            unseen_same_as_seen_addresses: BTreeSet::default(),
+            rate_limits: None,
+        }
+    }
+
+    /// Check that the CBOR encoding of the state doesn't change accidentally, and that the fixtures of the previous versions are still decoded.
+    #[test]
+    #[cfg(feature = "serde")]
+    fn check_state_cbor_fixture() {
+        let fixture = include_bytes!("../fixtures/bitcoin_agent_state.cbor");
+        let state = get_fixture_state();
+        assert_eq!(state.to_cbor(), fixture);
+        assert_eq!(BitcoinAgentState::from_cbor(fixture).unwrap(), state);
+
+        let fixture_v1 = include_bytes!("../fixtures/bitcoin_agent_state_v1.cbor");
+        assert_eq!(BitcoinAgentState::from_cbor(fixture_v1).unwrap(), state);
+
+        // The fixture of the version 2 lacks the rate limits.
+        let fixture_v2 = include_bytes!("../fixtures/bitcoin_agent_state_v2.cbor");
+        assert_eq!(BitcoinAgentState::from_cbor(fixture_v2).unwrap(), state);
+    }
+
+    /// Returns a synthetic UTXO whose transaction identifier is derived from `index`.
+    fn get_synthetic_utxo(index: u32) -> Utxo {
+        Utxo {
+            outpoint: OutPoint {
+                txid: index.to_le_bytes().repeat(8),
+                vout: index,
+            },
+            value: 10_000,
+            height: 100,
+        }
+    }
+
+    /// Check that the estimated encoded size of small and large states is their encoded length and that the memory footprint follows the cached UTXOs.
+    #[test]
+    fn check_memory_footprint() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let state = bitcoin_agent.get_state();
+        assert_eq!(state.estimated_encoded_size(), state.encode().len());
+        let memory_stats = bitcoin_agent.memory_footprint();
+        assert_eq!(
+            memory_stats,
+            MemoryStats {
+                addresses: 1,
+                utxos_cached: 0,
+                spent_entries: 0,
+                generated_entries: 0,
+                approx_bytes: memory_stats.approx_bytes,
+            }
+        );
+
+        for index in 0..10 {
+            bitcoin_agent.add_address(&[vec![index]]).unwrap();
+        }
+        for (index, utxos_state) in bitcoin_agent.utxos_state_addresses.values_mut().enumerate() {
+            let utxos: Vec<Utxo> = (0..1_000)
+                .map(|utxo_index| get_synthetic_utxo(index as u32 * 1_000 + utxo_index))
+                .collect();
+            utxos_state.unseen_state = utxos[..900].to_vec();
+            utxos_state.spent_state = utxos[..100]
+                .iter()
+                .map(|utxo| utxo.outpoint.clone())
+                .collect();
+            utxos_state.generated_state = utxos[..10].to_vec();
+            utxos_state.seen_state = utxos;
+        }
+        let state = bitcoin_agent.get_state();
+        let encoded_size = state.encode().len();
+        assert_eq!(state.estimated_encoded_size(), encoded_size);
+        let memory_stats = bitcoin_agent.memory_footprint();
+        assert_eq!(
+            memory_stats,
+            MemoryStats {
+                addresses: 11,
+                utxos_cached: 11 * 1_900,
+                spent_entries: 11 * 100,
+                generated_entries: 11 * 10,
+                approx_bytes: memory_stats.approx_bytes,
+            }
+        );
+        // The heap and the encoding of a UTXO have the same order of magnitude.
+        let approx_bytes = memory_stats.approx_bytes as usize;
+        assert!(encoded_size / 4 < approx_bytes && approx_bytes < encoded_size * 4);
+    }
+
+    thread_local! {
+        static ALLOCATED_BYTES: Cell<isize> = const { Cell::new(0) };
+        static PEAK_ALLOCATED_BYTES: Cell<isize> = const { Cell::new(0) };
+    }
+
+    /// Counts the bytes allocated by each thread, to compare the peak allocations of the ways to encode a state.
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED_BYTES.try_with(|allocated_bytes| {
+                allocated_bytes.set(allocated_bytes.get() + layout.size() as isize);
+                let _ = PEAK_ALLOCATED_BYTES.try_with(|peak_allocated_bytes| {
+                    peak_allocated_bytes.set(peak_allocated_bytes.get().max(allocated_bytes.get()))
+                });
+            });
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let _ = ALLOCATED_BYTES.try_with(|allocated_bytes| {
+                allocated_bytes.set(allocated_bytes.get() - layout.size() as isize)
+            });
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING_ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Returns the peak number of bytes allocated by the current thread while calling `f`, on top of the ones allocated before.
+    fn get_peak_allocation(f: impl FnOnce()) -> isize {
+        let allocated_bytes = ALLOCATED_BYTES.with(Cell::get);
+        PEAK_ALLOCATED_BYTES.with(|peak_allocated_bytes| peak_allocated_bytes.set(allocated_bytes));
+        f();
+        PEAK_ALLOCATED_BYTES.with(Cell::get) - allocated_bytes
+    }
+
+    /// Check that `write_state` writes the bytes of the encoded `get_state` of a large state, read back by `read_state`, with a lower peak allocation.
+    #[test]
+    fn check_streaming_state_encoding() {
+        let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(get_populated_state(), Network::Regtest).unwrap();
+        for index in 0..20 {
+            bitcoin_agent
+                .add_address_with_parameters(&[vec![index, 2]], &AddressType::P2wpkh, 0)
+                .unwrap();
+            bitcoin_agent
+                .add_address_with_parameters(&[vec![index, 1]], &AddressType::P2pkh, 0)
+                .unwrap();
+        }
+        for (index, utxos_state) in bitcoin_agent.utxos_state_addresses.values_mut().enumerate() {
+            utxos_state.seen_state = (0..500)
+                .map(|utxo_index| get_synthetic_utxo(index as u32 * 500 + utxo_index))
+                .collect();
+        }
+
+        let mut bytes = vec![];
+        bitcoin_agent.write_state(&mut bytes).unwrap();
+        let state = bitcoin_agent.get_state();
+        assert_eq!(bytes, state.encode());
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::read_state(&bytes[..], Network::Regtest).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert_eq!(
+            BitcoinAgent::<ManagementCanisterMock>::read_state(
+                &bytes[..bytes.len() - 1],
+                Network::Regtest
+            )
+            .err()
+            .unwrap()
+            .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let streaming_peak_allocation = get_peak_allocation(|| {
+            bitcoin_agent.write_state(io::sink()).unwrap();
+        });
+        let cloning_peak_allocation = get_peak_allocation(|| {
+            bitcoin_agent.get_state().encode();
+        });
+        // The cloned UTXOs states take at least as much memory as their encoding.
+        assert!(streaming_peak_allocation + bytes.len() as isize / 2 < cloning_peak_allocation);
+    }
+
+    /// Check that a multi-megabyte state is written by pages and read back from an in-memory page store.
+    #[test]
+    fn check_chunked_state() {
+        let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(get_populated_state(), Network::Regtest).unwrap();
+        for index in 0..20 {
+            bitcoin_agent.add_address(&[vec![index, 3]]).unwrap();
+        }
+        for (index, utxos_state) in bitcoin_agent.utxos_state_addresses.values_mut().enumerate() {
+            utxos_state.seen_state = (0..2_500)
+                .map(|utxo_index| get_synthetic_utxo(index as u32 * 2_500 + utxo_index))
+                .collect();
+        }
+        let state = bitcoin_agent.get_state();
+
+        let mut pages: Vec<(u64, Vec<u8>)> = vec![];
+        save_state_chunked(&state, |offset, page| pages.push((offset, page.to_vec())));
+        let (last_page, full_pages) = pages.split_last().unwrap();
+        assert!(full_pages.len() >= 32);
+        for (index, (offset, page)) in full_pages.iter().enumerate() {
+            assert_eq!(*offset, index as u64 * STATE_PAGE_SIZE);
+            assert_eq!(page.len() as u64, STATE_PAGE_SIZE);
+        }
+        assert_eq!(last_page.0, full_pages.len() as u64 * STATE_PAGE_SIZE);
+        assert!(!last_page.1.is_empty() && last_page.1.len() as u64 <= STATE_PAGE_SIZE);
+
+        let page_store: Vec<u8> = pages.into_iter().flat_map(|(_, page)| page).collect();
+        assert_eq!(page_store.len(), size_of::<u64>() + state.encode().len());
+        let read_page = |offset: u64, buffer: &mut [u8]| {
+            buffer.copy_from_slice(&page_store[offset as usize..offset as usize + buffer.len()])
+        };
+        assert_eq!(load_state_chunked(read_page).unwrap(), state);
+
+        // A blank page store doesn't hold any state.
+        assert!(load_state_chunked(|_, buffer| buffer.fill(0)).is_err());
+    }
+
+    /// Check that `from_state_unchecked` panics on states of unknown versions.
+    #[test]
+    #[should_panic(expected = "Unknown Bitcoin agent state version.")]
+    #[allow(deprecated)]
+    fn check_unknown_state_version() {
+        let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let state = BitcoinAgentState {
+            version: BITCOIN_AGENT_STATE_VERSION + 1,
+            ..bitcoin_agent.get_state()
+        };
+        let _: BitcoinAgent<ManagementCanisterMock> = BitcoinAgent::from_state_unchecked(state);
+    }
+
+    /// Returns the error of restoring a Bitcoin agent from the given state.
+    fn get_state_restore_error(bitcoin_agent_state: BitcoinAgentState) -> StateRestoreError {
+        BitcoinAgent::<ManagementCanisterMock>::from_state(bitcoin_agent_state, Network::Regtest)
+            .err()
+            .unwrap()
+    }
+
+    /// Check that `from_state` returns an error for each corruption mode of the state.
+    #[test]
+    fn check_state_validation() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = get_address_using_primitives(&bitcoin_agent.get_main_address());
+        let p2wpkh_address = get_address_using_primitives(
+            &bitcoin_agent
+                .add_address_with_parameters(&[vec![2]], &AddressType::P2wpkh, 0)
+                .unwrap(),
+        );
+        let state = bitcoin_agent.get_state();
+        let main_ecdsa_pub_key = state.ecdsa_pub_key_addresses[&main_address].clone();
+        let p2wpkh_ecdsa_pub_key = state.ecdsa_pub_key_addresses[&p2wpkh_address].clone();
+
+        // Unparsable address.
+        let mut corrupted_state = state.clone();
+        corrupted_state.ecdsa_pub_key_addresses.insert(
+            (String::from("invalid"), Network::Regtest),
+            main_ecdsa_pub_key.clone(),
+        );
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::InvalidAddress(String::from("invalid"))
+        );
+
+        // Address labelled with another network than the one of the state.
+        let mut corrupted_state = state.clone();
+        let utxos_state = corrupted_state
+            .utxos_state_addresses
+            .remove(&main_address)
+            .unwrap();
+        corrupted_state
+            .utxos_state_addresses
+            .insert((main_address.0.clone(), Network::Mainnet), utxos_state);
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::AddressNetworkMismatch(main_address.0.clone())
+        );
+
+        // Mainnet address in a Regtest state.
+        let mainnet_address = String::from("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2");
+        let mut corrupted_state = state.clone();
+        corrupted_state
+            .payout_queue
+            .queued_payouts
+            .insert(0, ((mainnet_address.clone(), Network::Regtest), 10_000));
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::AddressNetworkMismatch(mainnet_address)
+        );
+
+        // The uppercase spelling of a Bech32 address is the same address, sorted before the lowercase one.
+        let uppercase_p2wpkh_address = p2wpkh_address.0.to_uppercase();
+        let mut corrupted_state = state.clone();
+        corrupted_state.ecdsa_pub_key_addresses.insert(
+            (uppercase_p2wpkh_address, Network::Regtest),
+            p2wpkh_ecdsa_pub_key,
+        );
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::DuplicateAddress(p2wpkh_address.0.clone())
+        );
+
+        let corrupted_state = BitcoinAgentState {
+            min_confirmations: MIN_CONFIRMATIONS_UPPER_BOUND + 1,
+            ..state.clone()
+        };
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::MinConfirmationsTooHigh
+        );
+
+        // ECDSA public key not deriving its address.
+        let mut corrupted_state = state.clone();
+        corrupted_state
+            .ecdsa_pub_key_addresses
+            .insert(p2wpkh_address.clone(), main_ecdsa_pub_key);
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::EcdsaPubKeyMismatch(p2wpkh_address.0)
+        );
+
+        let mut corrupted_state = state.clone();
+        corrupted_state.ecdsa_pub_key.public_key = vec![0; 33];
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::InvalidEcdsaPubKey
+        );
+
+        let corrupted_state = BitcoinAgentState {
+            version: BITCOIN_AGENT_STATE_VERSION + 1,
+            ..state.clone()
+        };
+        assert_eq!(
+            get_state_restore_error(corrupted_state),
+            StateRestoreError::UnknownVersion(BITCOIN_AGENT_STATE_VERSION + 1)
+        );
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(state.clone(), Network::Regtest).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+    }
+
+    /// Check that `verify_state_integrity` reports a corrupted derivation path, skipping watch-only addresses, and that `from_state_with_options` fails fast with `verify_integrity`.
+    #[test]
+    fn check_state_integrity() {
+        let mut state = get_populated_state();
+        state.utxos_state_addresses.insert(
+            (
+                String::from("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"),
+                Network::Regtest,
+            ),
+            UtxosState::new(0),
+        );
+        let verify_integrity_options = StateRestoreOptions {
+            verify_integrity: true,
+            ..StateRestoreOptions::new(Network::Regtest)
+        };
+        let mut bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state_with_options(state.clone(), verify_integrity_options).unwrap();
+        assert_eq!(bitcoin_agent.list_watch_only_addresses().len(), 1);
+        assert!(bitcoin_agent.verify_state_integrity().is_empty());
+
+        let main_address = bitcoin_agent.get_main_address();
+        let p2wpkh_address = bitcoin_agent
+            .ecdsa_pub_key_addresses
+            .keys()
+            .find(|address| **address != main_address)
+            .unwrap()
+            .clone();
+        bitcoin_agent
+            .ecdsa_pub_key_addresses
+            .get_mut(&p2wpkh_address)
+            .unwrap()
+            .derivation_path = DerivationPath::new(vec![vec![3]]).unwrap();
+        assert_eq!(
+            bitcoin_agent.verify_state_integrity(),
+            vec![IntegrityIssue::EcdsaPubKeyMismatch(
+                p2wpkh_address.to_string()
+            )]
+        );
+
+        // An address without UTXOs state is only reported when verifying the integrity.
+        let mut corrupted_state = state;
+        let p2wpkh_address_using_primitives = get_address_using_primitives(&p2wpkh_address);
+        corrupted_state
+            .utxos_state_addresses
+            .remove(&p2wpkh_address_using_primitives);
+        corrupted_state
+            .unseen_same_as_seen_addresses
+            .remove(&p2wpkh_address_using_primitives);
+        assert!(BitcoinAgent::<ManagementCanisterMock>::from_state(
+            corrupted_state.clone(),
+            Network::Regtest
+        )
+        .is_ok());
+        assert_eq!(
+            BitcoinAgent::<ManagementCanisterMock>::from_state_with_options(
+                corrupted_state,
+                verify_integrity_options
+            )
+            .err()
+            .unwrap(),
+            StateRestoreError::IntegrityIssue(IntegrityIssue::MissingUtxosState(
+                p2wpkh_address.to_string()
+            ))
+        );
+    }
+
+    /// Check that a state of another network than the expected one is only restored when allowing the network change, e.g. a testnet state restored as a regtest Bitcoin agent, its addresses being relabelled.
+    #[test]
+    fn check_network_change() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Testnet, &AddressType::P2wpkh);
+        let p2pkh_address = bitcoin_agent
+            .add_address_with_parameters(&[vec![1]], &AddressType::P2pkh, 0)
+            .unwrap();
+        bitcoin_agent.queue_payout(&p2pkh_address, 10_000);
+        let state = bitcoin_agent.get_state();
+
+        let restored_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(state.clone(), Network::Testnet).unwrap();
+        assert_eq!(restored_bitcoin_agent.get_state(), state);
+        assert!(restored_bitcoin_agent
+            .get_main_address()
+            .to_string()
+            .starts_with("tb1"));
+        for expected_network in [Network::Mainnet, Network::Regtest] {
+            assert_eq!(
+                BitcoinAgent::<ManagementCanisterMock>::from_state(state.clone(), expected_network)
+                    .err()
+                    .unwrap(),
+                StateRestoreError::NetworkMismatch {
+                    expected: expected_network,
+                    found: Network::Testnet,
+                }
+            );
+        }
+
+        let regtest_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state_with_options(
+                state.clone(),
+                StateRestoreOptions::new(Network::Regtest).allow_network_change(),
+            )
+            .unwrap();
+        assert_eq!(regtest_bitcoin_agent.network(), Network::Regtest);
+        let regtest_state = regtest_bitcoin_agent.get_state();
+        assert_eq!(regtest_state.network, Network::Regtest);
+        // The Bech32 addresses are relabelled with the regtest prefix, while the legacy ones are spelled the same on both networks.
+        assert!(regtest_bitcoin_agent
+            .get_main_address()
+            .to_string()
+            .starts_with("bcrt1"));
+        assert!(regtest_state
+            .utxos_state_addresses
+            .contains_key(&(p2pkh_address.to_string(), Network::Regtest)));
+        assert!(regtest_state
+            .ecdsa_pub_key_addresses
+            .keys()
+            .chain(regtest_state.utxos_state_addresses.keys())
+            .all(|(_, network)| *network == Network::Regtest));
+        assert_eq!(
+            regtest_state.payout_queue.queued_payouts[&0],
+            ((p2pkh_address.to_string(), Network::Regtest), 10_000)
+        );
+        assert_eq!(regtest_state.ecdsa_pub_key, state.ecdsa_pub_key);
+        assert_eq!(regtest_state.ecdsa_key_name, state.ecdsa_key_name);
+
+        // The relabelled state is restored on the regtest without allowing a network change.
+        let restored_regtest_bitcoin_agent: BitcoinAgent<ManagementCanisterMock> =
+            BitcoinAgent::from_state(regtest_state.clone(), Network::Regtest).unwrap();
+        assert_eq!(restored_regtest_bitcoin_agent.get_state(), regtest_state);
+    }
+
+    /// Check that `from_state` returns an error instead of panicking on malformed address strings wherever the state stores addresses.
+    #[test]
+    fn check_malformed_state_addresses() {
+        let bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2wpkh);
+        let main_address = get_address_using_primitives(&bitcoin_agent.get_main_address());
+        let state = bitcoin_agent.get_state();
+        let main_ecdsa_pub_key = state.ecdsa_pub_key_addresses[&main_address].clone();
+
+        let malformed_addresses = [
+            // Truncated address.
+            main_address.0[..main_address.0.len() - 1].to_string(),
+            // Invalid checksum.
+            main_address.0.replace('q', "p"),
+            // Mixed case Bech32 address.
+            main_address.0[..4].to_uppercase() + &main_address.0[4..],
+            String::new(),
+        ];
+        for malformed_address in malformed_addresses {
+            let address_using_primitives = (malformed_address.clone(), Network::Regtest);
+            assert!(get_address(address_using_primitives.clone()).is_err());
+            let invalid_address_error = StateRestoreError::InvalidAddress(malformed_address);
+
+            let mut corrupted_state = state.clone();
+            corrupted_state
+                .ecdsa_pub_key_addresses
+                .insert(address_using_primitives.clone(), main_ecdsa_pub_key.clone());
+            assert_eq!(
+                get_state_restore_error(corrupted_state),
+                invalid_address_error
+            );
+
+            let mut corrupted_state = state.clone();
+            corrupted_state
+                .utxos_state_addresses
+                .insert(address_using_primitives.clone(), UtxosState::new(0));
+            assert_eq!(
+                get_state_restore_error(corrupted_state),
+                invalid_address_error
+            );
+
+            let mut corrupted_state = state.clone();
+            corrupted_state
+                .payout_queue
+                .queued_payouts
+                .insert(0, (address_using_primitives, 10_000));
+            assert_eq!(
+                get_state_restore_error(corrupted_state),
+                invalid_address_error
+            );
+        }
+    }
+
+    /// Check that the ECDSA public keys of states storing derivation paths as `Vec<Vec<u8>>` remain readable.
+    #[test]
+    fn check_derivation_path_migration() {
+        #[derive(CandidType)]
+        struct PreviousEcdsaPubKey {
+            public_key: Vec<u8>,
+            chain_code: Vec<u8>,
+            derivation_path: Vec<Vec<u8>>,
+        }
 
-        assert_eq!(post_upgrade_bitcoin_agent.get_state(), pre_upgrade_state)
+        let previous_ecdsa_pub_key = PreviousEcdsaPubKey {
+            public_key: vec![2; 33],
+            chain_code: vec![1; 32],
+            derivation_path: vec![vec![0], vec![1, 2, 3, 4, 5]],
+        };
+        let ecdsa_pub_key =
+            Decode!(&Encode!(&previous_ecdsa_pub_key).unwrap(), EcdsaPubKey).unwrap();
+        assert_eq!(
+            ecdsa_pub_key,
+            EcdsaPubKey {
+                public_key: previous_ecdsa_pub_key.public_key,
+                chain_code: previous_ecdsa_pub_key.chain_code,
+                derivation_path: DerivationPath::new(previous_ecdsa_pub_key.derivation_path)
+                    .unwrap(),
+            }
+        );
     }
 }
@@ -1,64 +1,200 @@
 use crate::{
     agent::BitcoinAgent,
-    canister_common::{ManagementCanister, GET_UTXOS_COST_CYCLES},
-    types::{from_bitcoin_network_to_ic_btc_types_network, GetUtxosResponse},
-    AddressNotTracked, BalanceUpdate, GetUtxosError, Satoshi, Utxo, UtxosUpdate,
-    MIN_CONFIRMATIONS_UPPER_BOUND,
+    canister_common::{
+        call_with_payment, get_cycles_spent, ManagementCanister, GET_BALANCE_COST_CYCLES,
+    },
+    types::{
+        from_bitcoin_network_to_management_canister_network, GetBalanceRequest, GetUtxosResponse,
+    },
+    AddressNotTracked, BalanceUpdate, BitcoinApiTarget, CyclesCostConfig, GetUtxosError,
+    ManagementCanisterMethod, ManagementCanisterReject, OutPoint, Satoshi, TransactionID, Utxo,
+    UtxosUpdate, WithCost, MIN_CONFIRMATIONS_UPPER_BOUND,
 };
-use bitcoin::{Address, Network};
-use ic_btc_types::{
-    GetUtxosRequest,
-    UtxosFilter::{MinConfirmations, Page},
+use bitcoin::{hashes::hex, Address, Network};
+use ic_cdk::api::management_canister::bitcoin::{
+    self as management_canister_bitcoin, GetUtxosRequest,
+    UtxoFilter::{MinConfirmations, Page},
 };
-use ic_cdk::{api::call::call_with_payment, export::Principal};
+use std::{collections::HashSet, str::FromStr};
 
-/// Returns the actual UTXOs of the given Bitcoin `address` according to `min_confirmations`.
+/// Returns the actual UTXOs of the given Bitcoin `address` according to `min_confirmations` and the cycles spent to retrieve all their pages from `bitcoin_api_target`.
 pub(crate) async fn get_utxos(
     network: Network,
     address: &Address,
     min_confirmations: u32,
-) -> Result<GetUtxosResponse, GetUtxosError> {
+    cycles_cost_config: CyclesCostConfig,
+    bitcoin_api_target: &BitcoinApiTarget,
+) -> Result<WithCost<GetUtxosResponse>, GetUtxosError> {
     if min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
         return Err(GetUtxosError::MinConfirmationsTooHigh);
     }
-    let mut filter = Some(MinConfirmations(min_confirmations));
-    let mut utxos = vec![];
-    let tip_height;
-    loop {
-        let res: Result<(ic_btc_types::GetUtxosResponse,), _> = call_with_payment(
-            Principal::management_canister(),
-            "bitcoin_get_utxos",
+    let mut utxos_pages = UtxosPages::new(None);
+    let mut cycles_spent = 0;
+    while !utxos_pages.is_complete() {
+        let filter = utxos_pages
+            .take_next_page()
+            .map_or(MinConfirmations(min_confirmations), Page);
+        let res: Result<(management_canister_bitcoin::GetUtxosResponse,), _> = call_with_payment(
+            bitcoin_api_target.get_principal(),
+            ManagementCanisterMethod::BitcoinGetUtxos,
             (GetUtxosRequest {
                 address: address.to_string(),
-                network: from_bitcoin_network_to_ic_btc_types_network(network),
-                filter,
+                network: from_bitcoin_network_to_management_canister_network(network)?,
+                filter: Some(filter),
             },),
-            GET_UTXOS_COST_CYCLES,
+            cycles_cost_config.get_utxos,
         )
         .await;
 
         match res {
-            Ok((mut get_utxos_response,)) => {
-                utxos.append(&mut get_utxos_response.utxos);
-                if get_utxos_response.next_page.is_none() {
-                    tip_height = get_utxos_response.tip_height;
-                    break;
-                } else {
-                    filter = get_utxos_response.next_page.map(Page);
-                }
+            Ok((get_utxos_response,)) => {
+                cycles_spent += get_cycles_spent(
+                    ManagementCanisterMethod::BitcoinGetUtxos,
+                    cycles_cost_config.get_utxos,
+                );
+                utxos_pages.push_page(
+                    get_utxos_response
+                        .utxos
+                        .into_iter()
+                        .map(Utxo::from)
+                        .collect(),
+                    get_utxos_response.tip_height,
+                    get_utxos_response.next_page,
+                );
             }
 
-            // The call to `get_utxos` was rejected for a given reason (e.g., not enough cycles were attached to the call).
+            // The call to `get_utxos` was rejected for a given reason (e.g., not enough cycles were attached to the call, or the page exceeds the size limit of the responses).
             Err((rejection_code, message)) => {
-                return Err(GetUtxosError::ManagementCanisterReject(
-                    rejection_code,
-                    message,
-                ))
+                return Err(ManagementCanisterReject(rejection_code, message).into())
             }
         }
     }
 
-    Ok(GetUtxosResponse { utxos, tip_height })
+    Ok(WithCost {
+        result: utxos_pages.into_response(),
+        cycles_spent,
+        attempts: 1,
+    })
+}
+
+/// Returns the balance of the given Bitcoin `address` according to `min_confirmations` and the cycles spent to retrieve it from `bitcoin_api_target` with `bitcoin_get_balance`.
+/// Unlike `get_utxos`, it works for the addresses with too many UTXOs for them to be returned.
+pub(crate) async fn get_balance(
+    network: Network,
+    address: &Address,
+    min_confirmations: u32,
+    bitcoin_api_target: &BitcoinApiTarget,
+) -> Result<WithCost<Satoshi>, GetUtxosError> {
+    if min_confirmations > MIN_CONFIRMATIONS_UPPER_BOUND {
+        return Err(GetUtxosError::MinConfirmationsTooHigh);
+    }
+    let res: Result<(Satoshi,), _> = call_with_payment(
+        bitcoin_api_target.get_principal(),
+        ManagementCanisterMethod::BitcoinGetBalance,
+        (GetBalanceRequest {
+            address: address.to_string(),
+            network: from_bitcoin_network_to_management_canister_network(network)?,
+            min_confirmations: Some(min_confirmations),
+        },),
+        GET_BALANCE_COST_CYCLES,
+    )
+    .await;
+
+    match res {
+        Ok((balance,)) => Ok(WithCost {
+            result: balance,
+            cycles_spent: get_cycles_spent(
+                ManagementCanisterMethod::BitcoinGetBalance,
+                GET_BALANCE_COST_CYCLES,
+            ),
+            attempts: 1,
+        }),
+
+        // The call to `get_balance` was rejected for a given reason (e.g., not enough cycles were attached to the call).
+        Err((rejection_code, message)) => Err(GetUtxosError::ManagementCanisterReject(
+            rejection_code,
+            message,
+        )),
+    }
+}
+
+/// Assembles the UTXOs of the pages of a `get_utxos` call, each page but the last one giving the token `P` of the next one.
+/// At most `max_pages` pages, and at least one, are retrieved if set, the UTXOs being truncated if there are more.
+pub(crate) struct UtxosPages<P> {
+    utxos: Vec<Utxo>,
+    tip_height: u32,
+    /// The token of the next page to retrieve, `None` for the first page.
+    next_page: Option<P>,
+    pages_count: usize,
+    max_pages: Option<usize>,
+    complete: bool,
+    truncated: bool,
+}
+
+impl<P> UtxosPages<P> {
+    pub(crate) fn new(max_pages: Option<usize>) -> Self {
+        Self {
+            utxos: vec![],
+            tip_height: 0,
+            next_page: None,
+            pages_count: 0,
+            max_pages,
+            complete: false,
+            truncated: false,
+        }
+    }
+
+    /// Returns whether all the pages, or `max_pages` of them, were retrieved.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Returns the token of the next page to retrieve, `None` for the first page.
+    pub(crate) fn take_next_page(&mut self) -> Option<P> {
+        self.next_page.take()
+    }
+
+    /// Appends the UTXOs of the retrieved page, the tip height being the one of the last page.
+    pub(crate) fn push_page(
+        &mut self,
+        mut utxos: Vec<Utxo>,
+        tip_height: u32,
+        next_page: Option<P>,
+    ) {
+        self.utxos.append(&mut utxos);
+        self.tip_height = tip_height;
+        self.pages_count += 1;
+        match next_page {
+            None => self.complete = true,
+            Some(_)
+                if self
+                    .max_pages
+                    .is_some_and(|max_pages| self.pages_count >= max_pages) =>
+            {
+                self.complete = true;
+                self.truncated = true;
+            }
+            next_page => self.next_page = next_page,
+        }
+    }
+
+    #[cfg(any(test, feature = "mock"))]
+    pub(crate) fn get_pages_count(&self) -> usize {
+        self.pages_count
+    }
+
+    /// Returns whether pages were left unretrieved because of `max_pages`.
+    #[cfg(test)]
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub(crate) fn into_response(self) -> GetUtxosResponse {
+        GetUtxosResponse {
+            utxos: self.utxos,
+            tip_height: self.tip_height,
+        }
+    }
 }
 
 /// Returns the difference between the current UTXO state and the last seen state for this address.
@@ -115,6 +251,80 @@ pub(crate) fn get_balance_from_utxos(utxos: &[Utxo]) -> Satoshi {
     utxos.iter().map(|utxo| utxo.value).sum()
 }
 
+/// The number of satoshis that will ever exist, which the UTXOs of an address can't exceed.
+const MAX_SATOSHIS: Satoshi = 21_000_000 * 100_000_000;
+
+/// Returns the hexadecimal of the txid bytes in the reversed order, the one shown by block explorers and `bitcoin-cli`, the bytes being stored in the internal order.
+pub(crate) fn to_explorer_hex(txid: &[u8]) -> String {
+    txid.iter()
+        .rev()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the outpoint of the output `vout` of the transaction whose txid `txid_hex` is displayed as by block explorers, e.g. to match it against the outpoints of the UTXOs.
+/// Fails if `txid_hex` isn't the hexadecimal of 32 bytes.
+pub fn outpoint_from_explorer_hex(txid_hex: &str, vout: u32) -> Result<OutPoint, hex::Error> {
+    let transaction_id = TransactionID::from_str(txid_hex)?;
+    Ok(OutPoint {
+        txid: transaction_id.as_bytes().to_vec(),
+        vout,
+    })
+}
+
+/// Returns the outpoint as `txid:vout`, the txid being displayed as by block explorers.
+pub fn outpoint_to_explorer_string(outpoint: &OutPoint) -> String {
+    format!("{}:{}", to_explorer_hex(&outpoint.txid), outpoint.vout)
+}
+
+/// Returns the txid of the transaction which generated the UTXO, displayed as by block explorers.
+pub fn utxo_txid_hex(utxo: &Utxo) -> String {
+    to_explorer_hex(&utxo.outpoint.txid)
+}
+
+/// Checks that the UTXOs returned by `bitcoin_get_utxos` are consistent before they are applied, e.g. against a buggy local replica.
+/// The outpoints have to be unique, the heights can't exceed the tip height, the values can't be zero unless `allow_zero_value_utxos` and can't sum to more than `MAX_SATOSHIS`.
+pub(crate) fn validate_get_utxos_response(
+    get_utxos_response: &GetUtxosResponse,
+    allow_zero_value_utxos: bool,
+) -> Result<(), GetUtxosError> {
+    let invalid_response = |reason: String| Err(GetUtxosError::InvalidResponse { reason });
+    let mut outpoints = HashSet::new();
+    let mut total_value: Satoshi = 0;
+    for utxo in &get_utxos_response.utxos {
+        if !outpoints.insert(&utxo.outpoint) {
+            return invalid_response(format!(
+                "The outpoint {} is duplicated.",
+                outpoint_to_explorer_string(&utxo.outpoint)
+            ));
+        }
+        if utxo.height > get_utxos_response.tip_height {
+            return invalid_response(format!(
+                "The UTXO {} has the height {} above the tip height {}.",
+                outpoint_to_explorer_string(&utxo.outpoint),
+                utxo.height,
+                get_utxos_response.tip_height
+            ));
+        }
+        if utxo.value == 0 && !allow_zero_value_utxos {
+            return invalid_response(format!(
+                "The UTXO {} has a zero value.",
+                outpoint_to_explorer_string(&utxo.outpoint)
+            ));
+        }
+        total_value = match total_value.checked_add(utxo.value) {
+            Some(total_value) if total_value <= MAX_SATOSHIS => total_value,
+            _ => {
+                return invalid_response(format!(
+                    "The UTXOs sum to more than {} satoshis.",
+                    MAX_SATOSHIS
+                ))
+            }
+        };
+    }
+    Ok(())
+}
+
 /// Returns the difference between the current balance state and the last seen state for this address.
 /// The last seen state for an address is updated to the current unseen state by calling `update_state` or implicitly when invoking `get_balance_update`.
 /// If there are no changes to the balance since the last call, the returned `BalanceUpdate` will be identical.
@@ -127,7 +337,7 @@ pub(crate) fn peek_balance_update<C: ManagementCanister>(
 }
 
 /// Returns the difference in the balance of an address controlled by the `BitcoinAgent` between the current state and the seen state when the function was last called, considering only transactions with the specified number of confirmations.
-/// The returned `BalanceUpdate` contains the information on how much balance was added and subtracted in total. If the function is called for the first time, the current balance of the address is returned.
+/// The returned `BalanceUpdate` contains the information on how much balance was added and subtracted in total, and the outpoints of the UTXOs composing it. If the function is called for the first time, the current balance of the address is returned.
 /// It is equivalent to calling `get_utxos_update` and summing up the balances in the returned UTXOs.
 pub(crate) fn get_balance_update<C: ManagementCanister>(
     bitcoin_agent: &mut BitcoinAgent<C>,
@@ -154,10 +364,14 @@ pub(crate) mod tests {
         agent::tests::MOCK_AGENT,
         canister_mock,
         canister_mock::{
-            get_init_balance_update, get_init_utxos, get_init_utxos_update, ManagementCanisterMock,
+            get_balance_update, get_init_balance_update, get_init_utxos, get_init_utxos_update,
+            get_outpoint_ic_type, mine_block, ManagementCanisterMock,
         },
-        AddressType, BalanceUpdate, Network, OutPoint,
+        AddressType, BalanceUpdate, Fee, Network, OutPoint,
     };
+    use bitcoin::{absolute::LockTime, consensus::serialize, hashes::Hash, Transaction, TxOut};
+    use ic_cdk::api::call::RejectionCode;
+    use std::{collections::BTreeMap, str::FromStr};
 
     /// Check that `get_utxos` returns the correct address' UTXOs according to `min_confirmations`.
     #[test]
@@ -221,11 +435,12 @@ pub(crate) mod tests {
         };
         bitcoin_agent
             .management_canister
+            .chain()
             .utxos_addresses
             .get_mut(&bitcoin_agent.get_main_address())
             .unwrap()
             .push(added_utxo.clone());
-        bitcoin_agent.management_canister.tip_height += 1;
+        bitcoin_agent.management_canister.chain().tip_height += 1;
 
         assert_eq!(
             update_state(&mut bitcoin_agent, canister_bitcoin_address),
@@ -330,15 +545,638 @@ pub(crate) mod tests {
         );
     }
 
+    /// Check that the UTXOs funded with `fund_address` are confirmed at the tip height and gain confirmations as the tip moves.
+    #[test]
+    fn check_fund_address() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let tip_height = bitcoin_agent
+            .get_management_canister_mock()
+            .get_tip_height();
+        let management_canister = bitcoin_agent.get_management_canister_mock_mut();
+        let outpoints = [
+            management_canister.fund_address(main_address, 10_000),
+            management_canister.fund_address(main_address, 10_000),
+        ];
+        assert_ne!(outpoints[0], outpoints[1]);
+
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, 1).added_balance,
+            get_init_balance_update().added_balance + 20_000
+        );
+
+        let management_canister = bitcoin_agent.get_management_canister_mock_mut();
+        management_canister.mine_blocks(3);
+        assert_eq!(management_canister.get_tip_height(), tip_height + 3);
+        management_canister.set_tip_height(tip_height + 9);
+        let utxos = canister_mock::get_utxos(&bitcoin_agent, main_address, 10);
+        assert_eq!(utxos.len(), 3);
+        assert!(outpoints
+            .iter()
+            .all(|outpoint| utxos.iter().any(|utxo| utxo.outpoint == *outpoint)));
+        assert!(canister_mock::get_utxos(&bitcoin_agent, main_address, 11).is_empty());
+    }
+
+    /// Check that the pages exceeding the size limit of the responses are reported as `GetUtxosError::ResponseTooLarge`, the balance being retrieved with `bitcoin_get_balance` instead.
+    #[test]
+    fn check_response_too_large() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let management_canister = bitcoin_agent.get_management_canister_mock_mut();
+        for value in [10_000, 20_000, 30_000] {
+            management_canister.send_to_address(main_address, value);
+        }
+        mine_block(management_canister);
+        let utxos_count = get_init_utxos().len() + 3;
+        let balance =
+            get_balance_from_utxos(&canister_mock::get_utxos(&bitcoin_agent, main_address, 0));
+
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .set_max_response_utxos(Some(utxos_count - 1));
+        assert!(matches!(
+            bitcoin_agent.get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap()),
+            Err(GetUtxosError::ResponseTooLarge { message }) if message.contains("application payload size")
+        ));
+        assert_eq!(
+            bitcoin_agent
+                .get_balance_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap()),
+            Ok(balance)
+        );
+
+        // Smaller pages fit in the responses.
+        bitcoin_agent
+            .get_management_canister_mock_mut()
+            .set_page_size(Some(utxos_count - 1));
+        assert_eq!(
+            bitcoin_agent
+                .get_utxos_from_args_test(bitcoin_agent.get_utxos_args(main_address, 0).unwrap())
+                .unwrap()
+                .result
+                .utxos
+                .len(),
+            utxos_count
+        );
+
+        // Only the rejections of oversized responses are reported as such.
+        assert!(matches!(
+            GetUtxosError::from(ManagementCanisterReject(
+                RejectionCode::CanisterError,
+                "Canister violated contract: ic0.msg_reply_data_append: application payload size (2100000) cannot be larger than 2097152".to_string()
+            )),
+            GetUtxosError::ResponseTooLarge { .. }
+        ));
+        assert_eq!(
+            GetUtxosError::from(ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                "Not enough cycles.".to_string()
+            )),
+            GetUtxosError::ManagementCanisterReject(
+                RejectionCode::CanisterReject,
+                "Not enough cycles.".to_string()
+            )
+        );
+    }
+
+    /// Check that the transactions sent with `send_to_address` are pending until `mine_block` confirms their outputs at the tip height, as UTXOs of the paid addresses.
+    #[test]
+    fn check_send_to_address() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, 1),
+            get_init_balance_update()
+        );
+        let tip_height = bitcoin_agent
+            .get_management_canister_mock()
+            .get_tip_height();
+        let management_canister = bitcoin_agent.get_management_canister_mock_mut();
+        let txids = [
+            management_canister.send_to_address(main_address, 10_000),
+            management_canister.send_to_address(main_address, 20_000),
+        ];
+        assert_ne!(txids[0], txids[1]);
+        let pending_transactions = management_canister.get_pending_transactions();
+        assert_eq!(
+            pending_transactions
+                .iter()
+                .map(Transaction::txid)
+                .collect::<Vec<_>>(),
+            txids
+        );
+        assert!(pending_transactions
+            .iter()
+            .all(|transaction| transaction.is_coin_base()));
+        // The outputs aren't confirmed yet.
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, 1),
+            BalanceUpdate::new()
+        );
+
+        mine_block(&mut bitcoin_agent.management_canister);
+        let utxos = canister_mock::get_utxos(&bitcoin_agent, main_address, 1);
+        for (txid, value) in txids.into_iter().zip([10_000, 20_000]) {
+            assert!(utxos.contains(&Utxo {
+                outpoint: get_outpoint_ic_type(bitcoin::OutPoint::new(txid, 0)),
+                value,
+                height: tip_height,
+            }));
+        }
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, 1).added_balance,
+            30_000
+        );
+    }
+
+    /// Returns a mock Bitcoin agent whose main address has 7 UTXOs with 1 confirmation, returned by pages of 2 UTXOs, along with these UTXOs.
+    fn new_paginated_mock() -> (BitcoinAgent<ManagementCanisterMock>, GetUtxosResponse) {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let management_canister = bitcoin_agent.get_management_canister_mock_mut();
+        for value in 1..=6 {
+            management_canister.fund_address(main_address, value * 10_000);
+        }
+        let get_utxos_response = management_canister.internal_get_utxos(main_address, 1);
+        assert_eq!(get_utxos_response.utxos.len(), 7);
+        management_canister.set_page_size(Some(2));
+        (bitcoin_agent, get_utxos_response)
+    }
+
+    /// Check that the UTXOs assembled from several pages are the ones returned in a single page.
+    #[tokio::test]
+    async fn check_get_utxos_pages() {
+        let (mut bitcoin_agent, get_utxos_response) = new_paginated_mock();
+        let main_address = &bitcoin_agent.get_main_address();
+        let management_canister = bitcoin_agent.get_management_canister_mock();
+
+        let utxos_pages = management_canister.get_utxos_pages(main_address, 1, None);
+        assert_eq!(utxos_pages.get_pages_count(), 4);
+        assert!(!utxos_pages.is_truncated());
+        assert_eq!(utxos_pages.into_response(), get_utxos_response);
+        assert_eq!(
+            management_canister
+                .get_utxos(main_address, 1)
+                .await
+                .unwrap(),
+            get_utxos_response
+        );
+
+        // Each page is paid for.
+        let utxos_args = bitcoin_agent.get_utxos_args(main_address, 1).unwrap();
+        let get_utxos_cost = utxos_args.cycles_cost_config.get_utxos;
+        let utxos_result = bitcoin_agent.get_utxos_from_args_test(utxos_args).unwrap();
+        assert_eq!(utxos_result.cycles_spent, 4 * get_utxos_cost);
+        assert_eq!(utxos_result.result.utxos, get_utxos_response.utxos);
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, 1).added_balance,
+            get_init_balance_update().added_balance + 210_000
+        );
+    }
+
+    /// Check that at most `max_pages` pages are retrieved, the UTXOs being reported as truncated only if pages are left.
+    #[test]
+    fn check_get_utxos_max_pages() {
+        let (bitcoin_agent, get_utxos_response) = new_paginated_mock();
+        let main_address = &bitcoin_agent.get_main_address();
+        let management_canister = bitcoin_agent.get_management_canister_mock();
+
+        for max_pages in 1..=3 {
+            let utxos_pages = management_canister.get_utxos_pages(main_address, 1, Some(max_pages));
+            assert_eq!(utxos_pages.get_pages_count(), max_pages);
+            assert!(utxos_pages.is_truncated());
+            let truncated_response = utxos_pages.into_response();
+            assert_eq!(
+                truncated_response.utxos,
+                get_utxos_response.utxos[..2 * max_pages]
+            );
+            assert_eq!(truncated_response.tip_height, get_utxos_response.tip_height);
+        }
+
+        for max_pages in 4..=5 {
+            let utxos_pages = management_canister.get_utxos_pages(main_address, 1, Some(max_pages));
+            assert_eq!(utxos_pages.get_pages_count(), 4);
+            assert!(!utxos_pages.is_truncated());
+            assert_eq!(utxos_pages.into_response(), get_utxos_response);
+        }
+    }
+
+    /// Returns the UTXOs of the main address of a new mock retrieved with `min_confirmations = 0`, `added_utxo` being added to the ones of the mock beforehand.
+    fn get_utxos_with_added_utxo(
+        added_utxo: Utxo,
+        allow_zero_value_utxos: bool,
+    ) -> Result<Vec<Utxo>, GetUtxosError> {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        bitcoin_agent.set_allow_zero_value_utxos(allow_zero_value_utxos);
+        let main_address = bitcoin_agent.get_main_address();
+        bitcoin_agent
+            .management_canister
+            .chain()
+            .utxos_addresses
+            .get_mut(&main_address)
+            .unwrap()
+            .push(added_utxo);
+        let utxos_args = bitcoin_agent.get_utxos_args(&main_address, 0).unwrap();
+        bitcoin_agent
+            .get_utxos_from_args_test(utxos_args)
+            .map(|utxos_result| utxos_result.result.utxos)
+    }
+
+    /// Check that the malformed responses of `bitcoin_get_utxos` are rejected instead of being returned.
+    #[test]
+    fn check_invalid_get_utxos_responses() {
+        // The initial UTXO of the mock is at its initial tip height.
+        let init_utxo = get_init_utxos()[0].clone();
+        let tip_height = init_utxo.height;
+        let new_utxo = |value, height| Utxo {
+            outpoint: OutPoint {
+                txid: vec![1; 32],
+                vout: 0,
+            },
+            value,
+            height,
+        };
+        let is_invalid_response = |result: Result<Vec<Utxo>, GetUtxosError>| {
+            matches!(result, Err(GetUtxosError::InvalidResponse { .. }))
+        };
+
+        assert_eq!(
+            get_utxos_with_added_utxo(new_utxo(1_000, tip_height), false),
+            Ok(vec![init_utxo.clone(), new_utxo(1_000, tip_height)])
+        );
+
+        // The duplicated outpoint is rejected even with another height.
+        assert_eq!(
+            get_utxos_with_added_utxo(
+                Utxo {
+                    height: tip_height - 1,
+                    ..init_utxo.clone()
+                },
+                false
+            ),
+            Err(GetUtxosError::InvalidResponse {
+                reason: format!("The outpoint {}:0 is duplicated.", "0".repeat(64))
+            })
+        );
+
+        assert!(is_invalid_response(get_utxos_with_added_utxo(
+            new_utxo(1_000, tip_height + 1),
+            false
+        )));
+
+        assert!(is_invalid_response(get_utxos_with_added_utxo(
+            new_utxo(0, tip_height),
+            false
+        )));
+        assert_eq!(
+            get_utxos_with_added_utxo(new_utxo(0, tip_height), true),
+            Ok(vec![init_utxo, new_utxo(0, tip_height)])
+        );
+
+        // The sum of the values exceeds the supply, or even overflows.
+        assert!(is_invalid_response(get_utxos_with_added_utxo(
+            new_utxo(MAX_SATOSHIS, tip_height),
+            false
+        )));
+        assert!(is_invalid_response(get_utxos_with_added_utxo(
+            new_utxo(Satoshi::MAX, tip_height),
+            false
+        )));
+    }
+
+    /// Check that two Bitcoin agents whose mocks share a chain see the payments they make to each other once mined, whatever the mock mining them.
+    #[tokio::test]
+    async fn check_shared_mock_chain() {
+        let bitcoin_agent_a = &mut agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let bitcoin_agent_b = &mut canister_mock::new_mock_sharing(
+            bitcoin_agent_a.get_management_canister_mock().get_chain(),
+            &Network::Regtest,
+            &AddressType::P2tr,
+        );
+        let min_confirmations = 1;
+        let address_a = &bitcoin_agent_a.get_main_address();
+        let address_b = &bitcoin_agent_b.get_main_address();
+        assert_ne!(address_a, address_b);
+        assert_eq!(
+            get_balance_update(bitcoin_agent_b, address_b, min_confirmations),
+            BalanceUpdate::new()
+        );
+
+        // Agent A pays agent B.
+        get_balance_update(bitcoin_agent_a, address_a, min_confirmations);
+        canister_mock::multi_transfer(
+            bitcoin_agent_a,
+            &BTreeMap::from([(address_b.clone(), 100_000)]),
+            address_a,
+            Fee::Constant(1_000),
+            min_confirmations,
+            false,
+        )
+        .await;
+        assert_eq!(
+            bitcoin_agent_b
+                .get_management_canister_mock()
+                .get_pending_transactions()
+                .len(),
+            1
+        );
+        assert_eq!(
+            get_balance_update(bitcoin_agent_b, address_b, min_confirmations),
+            BalanceUpdate::new()
+        );
+        assert_eq!(
+            canister_mock::get_balance(bitcoin_agent_b, address_b, 0),
+            100_000
+        );
+
+        // The payment is mined through the mock of agent B.
+        mine_block(bitcoin_agent_b.get_management_canister_mock_mut());
+        assert_eq!(
+            bitcoin_agent_a
+                .get_management_canister_mock()
+                .get_tip_height(),
+            bitcoin_agent_b
+                .get_management_canister_mock()
+                .get_tip_height()
+        );
+        assert_eq!(
+            get_balance_update(bitcoin_agent_b, address_b, min_confirmations).added_balance,
+            100_000
+        );
+
+        // Agent B pays agent A back.
+        canister_mock::multi_transfer(
+            bitcoin_agent_b,
+            &BTreeMap::from([(address_a.clone(), 30_000)]),
+            address_b,
+            Fee::Constant(1_000),
+            min_confirmations,
+            false,
+        )
+        .await;
+        mine_block(bitcoin_agent_a.get_management_canister_mock_mut());
+        assert!(
+            canister_mock::get_utxos(bitcoin_agent_a, address_a, min_confirmations)
+                .iter()
+                .any(|utxo| utxo.value == 30_000)
+        );
+    }
+
+    /// Sends a transaction without inputs paying `value` satoshis to `address` to the mock, e.g. to simulate a received payment.
+    fn send_payment(
+        bitcoin_agent: &BitcoinAgent<ManagementCanisterMock>,
+        address: &Address,
+        value: Satoshi,
+    ) -> Transaction {
+        let transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value,
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let management_canister = &bitcoin_agent.management_canister;
+        management_canister
+            .internal_send_transaction(
+                serialize(&transaction),
+                management_canister.get_network(),
+                &management_canister.get_cycles_cost_config(),
+            )
+            .unwrap();
+        transaction
+    }
+
+    /// Check that the balance confirmed in a block invalidated by a reorganization is removed from the next balance update, and added back once the transaction is mined again.
+    #[test]
+    fn check_balance_update_after_invalidated_blocks() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let min_confirmations = 1;
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, min_confirmations),
+            get_init_balance_update()
+        );
+
+        let transaction = send_payment(&bitcoin_agent, main_address, 42_000);
+        let payment_outpoint = get_outpoint_ic_type(bitcoin::OutPoint::new(transaction.txid(), 0));
+        mine_block(&mut bitcoin_agent.management_canister);
+        let tip_height = bitcoin_agent.management_canister.get_tip_height();
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, min_confirmations),
+            BalanceUpdate {
+                added_balance: 42_000,
+                removed_balance: 0,
+                net_change: 42_000,
+                added_utxo_count: 1,
+                removed_utxo_count: 0,
+                added_outpoints: vec![payment_outpoint.clone()],
+                removed_outpoints: vec![]
+            }
+        );
+
+        bitcoin_agent.management_canister.invalidate_blocks(1);
+        assert_eq!(
+            bitcoin_agent.management_canister.get_tip_height(),
+            tip_height - 1
+        );
+        assert_eq!(
+            bitcoin_agent.management_canister.get_pending_transactions(),
+            vec![transaction]
+        );
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, min_confirmations),
+            BalanceUpdate {
+                added_balance: 0,
+                removed_balance: 42_000,
+                net_change: -42_000,
+                added_utxo_count: 0,
+                removed_utxo_count: 1,
+                added_outpoints: vec![],
+                removed_outpoints: vec![payment_outpoint.clone()]
+            }
+        );
+
+        mine_block(&mut bitcoin_agent.management_canister);
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, min_confirmations),
+            BalanceUpdate {
+                added_balance: 42_000,
+                removed_balance: 0,
+                net_change: 42_000,
+                added_utxo_count: 1,
+                removed_utxo_count: 0,
+                added_outpoints: vec![payment_outpoint],
+                removed_outpoints: vec![]
+            }
+        );
+    }
+
+    /// Check that a payment replaced by another one in a fork is reported as removed, the replacing payment being added.
+    #[test]
+    fn check_balance_update_after_fork_and_replace() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let min_confirmations = 1;
+        get_balance_update(&mut bitcoin_agent, main_address, min_confirmations);
+
+        let replaced_transaction = send_payment(&bitcoin_agent, main_address, 42_000);
+        mine_block(&mut bitcoin_agent.management_canister);
+        let tip_height = bitcoin_agent.management_canister.get_tip_height();
+        get_balance_update(&mut bitcoin_agent, main_address, min_confirmations);
+
+        let alternative_transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 21_000,
+                script_pubkey: main_address.script_pubkey(),
+            }],
+        };
+        let alternative_outpoint =
+            get_outpoint_ic_type(bitcoin::OutPoint::new(alternative_transaction.txid(), 0));
+        bitcoin_agent
+            .management_canister
+            .fork_and_replace(1, vec![alternative_transaction]);
+        assert_eq!(
+            bitcoin_agent.management_canister.get_tip_height(),
+            tip_height
+        );
+        assert!(bitcoin_agent
+            .management_canister
+            .get_pending_transactions()
+            .is_empty());
+        assert_eq!(
+            get_balance_update(&mut bitcoin_agent, main_address, min_confirmations),
+            BalanceUpdate {
+                added_balance: 21_000,
+                removed_balance: 42_000,
+                net_change: -21_000,
+                added_utxo_count: 1,
+                removed_utxo_count: 1,
+                added_outpoints: vec![alternative_outpoint],
+                removed_outpoints: vec![get_outpoint_ic_type(bitcoin::OutPoint::new(
+                    replaced_transaction.txid(),
+                    0
+                ))]
+            }
+        );
+    }
+
+    /// Check that the outpoints of a balance update are the ones of the funding transactions, peeking at it returning the same outpoints without advancing the seen state.
+    #[test]
+    fn check_balance_update_outpoints() {
+        let mut bitcoin_agent = agent::tests::new_mock(&Network::Regtest, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let min_confirmations = 1;
+        get_balance_update(&mut bitcoin_agent, main_address, min_confirmations);
+
+        let funded_outpoints: HashSet<OutPoint> = [10_000, 20_000]
+            .into_iter()
+            .map(|value| {
+                bitcoin_agent
+                    .management_canister
+                    .fund_address(main_address, value)
+            })
+            .collect();
+        let utxos_args = bitcoin_agent
+            .get_utxos_args(main_address, min_confirmations)
+            .unwrap();
+        let utxos_result = bitcoin_agent
+            .get_utxos_from_args_test(utxos_args)
+            .unwrap()
+            .result;
+        bitcoin_agent.apply_utxos(utxos_result);
+
+        let peeked_balance_update = bitcoin_agent.peek_balance_update(main_address).unwrap();
+        assert_eq!(peeked_balance_update.added_balance, 30_000);
+        assert_eq!(
+            peeked_balance_update
+                .added_outpoints
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>(),
+            funded_outpoints
+        );
+        assert!(peeked_balance_update.removed_outpoints.is_empty());
+        // The UTXOs update order, and so the outpoints one, may differ between two computations of it.
+        let sort_outpoints = |mut balance_update: BalanceUpdate| {
+            balance_update.added_outpoints.sort();
+            balance_update
+        };
+        assert_eq!(
+            sort_outpoints(bitcoin_agent.get_balance_update(main_address).unwrap()),
+            sort_outpoints(peeked_balance_update)
+        );
+        assert_eq!(
+            bitcoin_agent.peek_balance_update(main_address).unwrap(),
+            BalanceUpdate::new()
+        );
+    }
+
+    /// Check that invalidating the block of a transfer restores the spent UTXOs and removes the generated ones, the transfer becoming pending again.
+    #[tokio::test]
+    async fn check_invalidated_transfer() {
+        let bitcoin_agent = &mut agent::tests::new_mock(&Network::Testnet, &AddressType::P2pkh);
+        let main_address = &bitcoin_agent.get_main_address();
+        let payee_address = &Address::from_str("mh83WVoSsTGJAB3aiHJLpmYQCkwtnQ6o76")
+            .unwrap()
+            .assume_checked();
+        get_balance_update(bitcoin_agent, main_address, 0);
+        canister_mock::multi_transfer(
+            bitcoin_agent,
+            &BTreeMap::from([(payee_address.clone(), 100_000)]),
+            main_address,
+            Fee::Constant(1_000),
+            0,
+            false,
+        )
+        .await;
+        let transaction = bitcoin_agent.management_canister.get_pending_transactions()[0].clone();
+        mine_block(&mut bitcoin_agent.management_canister);
+        let management_canister = &bitcoin_agent.management_canister;
+        assert!(management_canister
+            .internal_get_utxos(main_address, 1)
+            .utxos
+            .iter()
+            .all(|utxo| utxo.outpoint.txid == transaction.txid().to_byte_array().to_vec()));
+        assert_eq!(
+            management_canister
+                .internal_get_utxos(payee_address, 1)
+                .utxos
+                .len(),
+            1
+        );
+
+        bitcoin_agent.management_canister.invalidate_blocks(1);
+        let management_canister = &bitcoin_agent.management_canister;
+        assert_eq!(
+            management_canister
+                .internal_get_utxos(main_address, 1)
+                .utxos,
+            get_init_utxos()
+        );
+        assert!(management_canister
+            .internal_get_utxos(payee_address, 1)
+            .utxos
+            .is_empty());
+        assert_eq!(
+            management_canister.get_pending_transactions(),
+            vec![transaction]
+        );
+    }
+
     /// Apply update following the same pattern a canister developer will use.
     pub(crate) fn apply_utxos_pattern(
         bitcoin_agent: &mut BitcoinAgent<ManagementCanisterMock>,
         address: &Address,
     ) {
-        let utxos_args = bitcoin_agent.get_utxos_args(address, 0);
+        let utxos_args = bitcoin_agent.get_utxos_args(address, 0).unwrap();
         let utxos_result = bitcoin_agent
             .get_utxos_from_args_test(utxos_args)
-            .expect("Error while getting UTXOs result.");
+            .expect("Error while getting UTXOs result.")
+            .result;
         let _utxos_update = bitcoin_agent.apply_utxos(utxos_result);
     }
 
@@ -347,9 +1185,9 @@ pub(crate) mod tests {
     fn test_thread_local_peek_utxos_update() {
         // Build args.
         let address = MOCK_AGENT.with(|a| a.borrow().get_main_address());
-        let args = MOCK_AGENT.with(|a| a.borrow().get_utxos_args(&address, 1));
+        let args = MOCK_AGENT.with(|a| a.borrow().get_utxos_args(&address, 1).unwrap());
         let utxos = MOCK_AGENT.with(|a| a.borrow().get_utxos_from_args_test(args));
-        let utxos = utxos.expect("Error while getting UTXOs result.");
+        let utxos = utxos.expect("Error while getting UTXOs result.").result;
 
         // Update agent state.
         let result = MOCK_AGENT.with(|a| a.borrow_mut().apply_utxos(utxos));
@@ -363,4 +1201,44 @@ pub(crate) mod tests {
         let utxos_update = get_init_utxos_update();
         assert_eq!(utxos_update, result);
     }
+
+    /// Check the txid helpers against the first transaction between two people, paying Hal Finney at block 170, whose txid is displayed reversed by block explorers.
+    #[test]
+    fn check_txid_byte_order_helpers() {
+        let explorer_txid = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+        let internal_txid =
+            ::hex::decode("169e1e83e930853391bc6f35f605c6754cfead57cf8387639d3b4096c54f18f4")
+                .unwrap();
+
+        let outpoint = outpoint_from_explorer_hex(explorer_txid, 1).unwrap();
+        assert_eq!(
+            outpoint,
+            OutPoint {
+                txid: internal_txid.clone(),
+                vout: 1
+            }
+        );
+        assert_eq!(
+            outpoint_to_explorer_string(&outpoint),
+            format!("{}:1", explorer_txid)
+        );
+        // The explorer order is also the one of `bitcoin::Txid`.
+        assert_eq!(
+            bitcoin::Txid::from_str(explorer_txid)
+                .unwrap()
+                .to_byte_array()
+                .to_vec(),
+            internal_txid
+        );
+
+        let utxo = Utxo {
+            outpoint,
+            value: 1_000_000_000,
+            height: 170,
+        };
+        assert_eq!(utxo_txid_hex(&utxo), explorer_txid);
+
+        assert!(outpoint_from_explorer_hex(&explorer_txid[2..], 0).is_err());
+        assert!(outpoint_from_explorer_hex("not a txid", 0).is_err());
+    }
 }